@@ -0,0 +1,31 @@
+//! Serial input cut into commands as the firmwares' receive tasks cut it,
+//! with their 64-byte `LineReader`. Whatever arrives, the parser has to give a
+//! command or `Unknown` without panicking, and a calibration it takes from
+//! `SCAL` has to read back the same from the `Calibration:` line echoing
+//! it, as the host tools read it.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sphere_mapping_core::calibration::Calibration;
+use sphere_mapping_core::command::{parse_command, LineReader, SerialCommand};
+
+/// The receive buffer's size in both firmwares.
+const COMMAND_LEN: usize = 64;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = LineReader::<COMMAND_LEN>::new();
+    // The last line is parsed even if nothing ends it.
+    for &byte in data.iter().chain(b"\n") {
+        if let Some(line) = reader.push(byte) {
+            check(line);
+        }
+    }
+});
+
+fn check(command: &[u8]) {
+    if let SerialCommand::SetCalibration(calibration) = parse_command(command) {
+        let echo = calibration.to_string();
+        assert_eq!(Calibration::parse(&echo), Some(calibration), "{echo}");
+    }
+}
@@ -0,0 +1,35 @@
+//! Arbitrary bytes through the host's stream decoder, in chunks of a size
+//! taken from the first byte, as reads off a port would split them. It
+//! mustn't panic, the spans it gives have to follow on from each other
+//! from the first byte, with a dropped packet's span as long as what it
+//! says was dropped, and decoding with and without spans has to give the
+//! same packets.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sphere_mapping_core::stream::{Decoder, Packet};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk, bytes)) = data.split_first() else {
+        return;
+    };
+    let chunk = chunk as usize + 1;
+    let (mut decoder, mut spanned) = (Decoder::new(), Decoder::new());
+    let (mut packets, mut spans) = (Vec::new(), Vec::new());
+    for bytes in bytes.chunks(chunk) {
+        decoder.push(bytes, &mut packets);
+        spanned.push_spans(bytes, &mut spans);
+    }
+    let mut end = 0;
+    for span in &spans {
+        assert_eq!(span.start, end, "spans don't follow on");
+        end += span.len as u64;
+        if let Some(Packet::Corrupt(dropped)) = span.packet {
+            assert_eq!(dropped, span.len, "dropped bytes miscounted");
+        }
+    }
+    assert!(end <= bytes.len() as u64, "spans past the end");
+    let from_spans: Vec<Packet> = spans.into_iter().filter_map(|span| span.packet).collect();
+    assert_eq!(packets, from_spans);
+});
@@ -0,0 +1,47 @@
+//! Well-formed frames of any kind and payload, the payload's length taken
+//! from the byte before it, back to back through the stream decoder. Each
+//! has to come back as it went in, even with the sync bytes inside its
+//! payload: as a sample if it is a sample frame of the right length, as
+//! the frame itself otherwise, and nothing dropped.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sphere_mapping_core::frame::{crc16, KIND_SAMPLE, KIND_SAMPLE_GYRO, SYNC};
+use sphere_mapping_core::stream::{Decoder, Packet};
+
+fuzz_target!(|data: &[u8]| {
+    let mut frames = Vec::new();
+    let mut stream = Vec::new();
+    let mut rest = data;
+    while let [kind, len, tail @ ..] = rest {
+        let len = (*len as usize).min(tail.len());
+        let (payload, tail) = tail.split_at(len);
+        let mut header = vec![*kind, len as u8];
+        header.extend_from_slice(payload);
+        stream.extend_from_slice(&SYNC);
+        stream.extend_from_slice(&header);
+        stream.extend_from_slice(&crc16(&header).to_le_bytes());
+        frames.push((*kind, payload));
+        rest = tail;
+    }
+    let mut packets = Vec::new();
+    Decoder::new().push(&stream, &mut packets);
+    assert_eq!(packets.len(), frames.len(), "frames lost or split");
+    for (packet, (kind, payload)) in packets.iter().zip(frames) {
+        match packet {
+            Packet::Sample(sample) => {
+                assert!(matches!(kind, KIND_SAMPLE | KIND_SAMPLE_GYRO));
+                assert_eq!(sample.gyro.is_some(), kind == KIND_SAMPLE_GYRO);
+                assert_eq!(sample.timestamp_us.to_le_bytes(), payload[..8]);
+            }
+            Packet::Frame {
+                kind: decoded,
+                payload: decoded_payload,
+            } => {
+                assert_eq!((*decoded, decoded_payload.as_slice()), (kind, payload));
+            }
+            packet => panic!("{packet:?} for a kind {kind} frame"),
+        }
+    }
+});
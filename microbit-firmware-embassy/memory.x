@@ -0,0 +1,8 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* The last 16K of flash hold the settings and calibration records, and
+     the 32K before them the sphere map's chunks. */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 464K
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
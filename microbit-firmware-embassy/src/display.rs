@@ -0,0 +1,123 @@
+//! LED matrix driven by an async task. `run` multiplexes the rows forever,
+//! dimming each pixel by keeping its column on for only part of the row
+//! time, so the rest of the firmware only has to call [`show`] when the
+//! picture changes.
+//!
+//! Frames submitted while the current one is still within its hold time are
+//! parked and swapped in by the task once the hold time has elapsed.
+
+use core::cell::RefCell;
+use embassy_nrf::gpio::Output;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+
+use sphere_mapping_core::led::{Frame, MAX_BRIGHTNESS};
+
+/// Time each row is lit for, split into one slot per brightness level.
+const ROW_US: u64 = 1_800;
+const SLOT_US: u64 = ROW_US / MAX_BRIGHTNESS as u64;
+
+struct State {
+    current: Frame,
+    pending: Option<Frame>,
+    shown_at: Instant,
+    hold_ms: u16,
+    brightness: u8,
+}
+
+impl State {
+    fn present(&mut self, frame: Frame) {
+        self.current = frame;
+        self.shown_at = Instant::now();
+    }
+
+    fn holding(&self) -> bool {
+        self.shown_at.elapsed() < Duration::from_millis(self.hold_ms as u64)
+    }
+
+    /// The current frame scaled by the brightness level.
+    fn lit(&self) -> Frame {
+        let mut scaled = self.current;
+        for pixel in scaled.iter_mut().flatten() {
+            if *pixel > 0 {
+                *pixel =
+                    (*pixel as u16 * self.brightness as u16).div_ceil(MAX_BRIGHTNESS as u16) as u8;
+            }
+        }
+        scaled
+    }
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    current: [[0; 5]; 5],
+    pending: None,
+    shown_at: Instant::MIN,
+    hold_ms: 0,
+    brightness: MAX_BRIGHTNESS,
+}));
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.lock(|state| f(&mut state.borrow_mut()))
+}
+
+/// Shows `frame` once the current frame's hold time has elapsed, skipping
+/// the update entirely when it is already on the matrix.
+pub fn show(frame: Frame) {
+    with_state(|state| {
+        if state.current == frame {
+            state.pending = None;
+        } else if state.holding() {
+            state.pending = Some(frame);
+        } else {
+            state.present(frame);
+        }
+    });
+}
+
+/// Sets the minimum time each frame stays on the matrix; 0 shows every
+/// frame as soon as it is submitted.
+pub fn set_hold_ms(hold_ms: u16) {
+    with_state(|state| state.hold_ms = hold_ms);
+}
+
+/// Sets a fixed brightness from 1 to `MAX_BRIGHTNESS`.
+pub fn set_brightness(brightness: u8) {
+    with_state(|state| state.brightness = brightness.clamp(1, MAX_BRIGHTNESS));
+}
+
+/// Multiplexes the matrix. Rows are active high and columns active low.
+#[embassy_executor::task]
+pub async fn run(mut rows: [Output<'static>; 5], mut cols: [Output<'static>; 5]) {
+    loop {
+        let frame = with_state(|state| {
+            if !state.holding() {
+                if let Some(frame) = state.pending.take() {
+                    state.present(frame);
+                }
+            }
+            state.lit()
+        });
+
+        for (row, pixels) in rows.iter_mut().zip(frame.iter()) {
+            for (col, &pixel) in cols.iter_mut().zip(pixels.iter()) {
+                if pixel > 0 {
+                    col.set_low();
+                }
+            }
+            row.set_high();
+            for level in 1..=MAX_BRIGHTNESS {
+                Timer::after_micros(SLOT_US).await;
+                for (col, &pixel) in cols.iter_mut().zip(pixels.iter()) {
+                    if pixel == level {
+                        col.set_high();
+                    }
+                }
+            }
+            row.set_low();
+            for col in cols.iter_mut() {
+                col.set_high();
+            }
+        }
+    }
+}
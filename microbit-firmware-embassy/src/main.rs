@@ -0,0 +1,649 @@
+//! Embassy port of the sphere mapping firmware. It shares the protocol,
+//! calibration and display logic with the RTIC firmware through
+//! `sphere_mapping_core`, but waits on the sensor and UART with `.await`
+//! instead of polling them from timer interrupts.
+//!
+//! Ambient light brightness and the external displays are only available
+//! in the RTIC firmware; `SBRT 0` leaves the matrix at full brightness.
+
+#![no_main]
+#![no_std]
+
+mod display;
+
+use core::f32::consts::PI;
+use core::fmt::Write;
+use embassy_executor::Spawner;
+use embassy_nrf::gpio::{AnyPin, Input, Level, Output, OutputDrive, Pull};
+use embassy_nrf::nvmc::Nvmc;
+use embassy_nrf::peripherals::{TWISPI0, UARTE0, WDT};
+use embassy_nrf::twim::{self, Twim};
+use embassy_nrf::uarte::{self, Uarte, UarteRx};
+use embassy_nrf::wdt::{self, Watchdog, WatchdogHandle};
+use embassy_nrf::{bind_interrupts, Peri};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{with_timeout, Delay, Duration, Instant, Timer};
+use heapless::String;
+use lsm303agr::interface::I2cInterface;
+use lsm303agr::mode::{MagContinuous, MagOneShot};
+use lsm303agr::{
+    AccelMode, AccelOutputDataRate, AccelScale, Interrupt, Lsm303agr, MagMode, MagOutputDataRate,
+};
+use panic_rtt_target as _;
+use rtt_target::{rprintln, rtt_init_print};
+
+use sphere_mapping_core::boot_record::BootRecord;
+use sphere_mapping_core::calibration::{
+    Calibration, Measurement, TiltGame, CURSOR_BLINK_MS, DONE_FRAME, DONE_MS,
+    PRECOMPUTED_CALIBRATION,
+};
+use sphere_mapping_core::command::{parse_command, LineReader, SerialCommand};
+use sphere_mapping_core::compass::Compass;
+use sphere_mapping_core::config;
+use sphere_mapping_core::device::{Acceleration, Sample};
+use sphere_mapping_core::events::{self, EventBus, FieldWatch};
+use sphere_mapping_core::led::{render_arrow, render_digit};
+use sphere_mapping_core::mode::{AppMode, Buttons, ModeAction, ModeEvent};
+use sphere_mapping_core::reset::ResetReason;
+use sphere_mapping_core::settings::{Settings, AUTO_BRIGHTNESS};
+use sphere_mapping_core::storage::{Storage, STORAGE_START};
+use sphere_mapping_core::survey::Survey;
+
+/// Both sensors run at the configured sample rate, which the config has
+/// already checked they support.
+const SAMPLE_PERIOD: Duration = Duration::from_millis(1_000 / config::SAMPLE_RATE_HZ as u64);
+const ACCEL_ODR: AccelOutputDataRate =
+    AccelOutputDataRate::from_hertz(config::SAMPLE_RATE_HZ as u16).unwrap();
+const MAG_ODR: MagOutputDataRate =
+    MagOutputDataRate::from_hertz(config::SAMPLE_RATE_HZ as u16).unwrap();
+const ACCEL_SCALE: AccelScale = match config::ACCEL_SCALE_G {
+    4 => AccelScale::G4,
+    8 => AccelScale::G8,
+    16 => AccelScale::G16,
+    _ => AccelScale::G2,
+};
+
+const SPLASH_STEPS: u32 = 16;
+const SPLASH_STEP_MS: u64 = 40;
+const SPLASH_VERSION_MS: u64 = 600;
+
+/// How often the buttons are polled, which doubles as their debounce
+/// interval.
+const BUTTON_POLL_MS: u64 = 10;
+
+bind_interrupts!(struct Irqs {
+    TWISPI0 => twim::InterruptHandler<TWISPI0>;
+    UARTE0 => uarte::InterruptHandler<UARTE0>;
+});
+
+type I2c = Twim<'static, TWISPI0>;
+type Sensor = Lsm303agr<I2cInterface<I2c>, MagContinuous>;
+type SensorError = lsm303agr::Error<twim::Error>;
+
+/// Failed reads in a row before the sensor is set up again.
+const MAX_FAILURES: u32 = 3;
+
+/// Time without a complete sample before the sensor is taken to have
+/// stopped producing data and is set up again.
+const DATA_TIMEOUT: Duration = Duration::from_millis(5 * 1_000 / config::SAMPLE_RATE_HZ as u64);
+
+#[derive(Debug)]
+enum ReadError {
+    Sensor(SensorError),
+    /// No complete sample within `DATA_TIMEOUT`.
+    Timeout,
+}
+
+impl From<SensorError> for ReadError {
+    fn from(e: SensorError) -> ReadError {
+        ReadError::Sensor(e)
+    }
+}
+
+/// Events the bus holds for outputs that haven't caught up yet.
+const EVENT_QUEUE_LEN: usize = 8;
+
+/// Things that happen outside the sampling loop, handled between samples.
+#[derive(Clone, Copy)]
+enum Event {
+    Mode(ModeEvent),
+    Command(SerialCommand),
+}
+
+static EVENTS: Channel<CriticalSectionRawMutex, Event, 4> = Channel::new();
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    rtt_init_print!();
+    let p = embassy_nrf::init(Default::default());
+
+    // The main loop feeds the watchdog after every sample, so if it wedges
+    // the board resets instead of freezing.
+    let reset_reason = take_reset_reason();
+    rprintln!("Reset: {}", reset_reason);
+    let mut config = wdt::Config::default();
+    config.timeout_ticks = config::WATCHDOG_TIMEOUT_MS * 32_768 / 1_000;
+    config.action_during_debug_halt = wdt::HaltConfig::PAUSE;
+    let mut watchdog = match Watchdog::try_new::<1>(p.WDT, config) {
+        Ok((_, [handle])) => handle,
+        // Still running from before a soft reset, with the same handle.
+        Err(_) => unsafe { WatchdogHandle::steal::<WDT>(0) },
+    };
+
+    // Initialize serial uart; incoming commands are read by their own task.
+    let mut config = uarte::Config::default();
+    config.baudrate = baudrate(config::BAUD_RATE);
+    let serial = Uarte::new(p.UARTE0, p.P1_08, p.P0_06, Irqs, config);
+    let (mut tx, rx) = serial.split();
+    spawner.must_spawn(receive(rx));
+
+    // Initialize LED display
+    let rows: [Peri<AnyPin>; 5] = [
+        p.P0_21.into(),
+        p.P0_22.into(),
+        p.P0_15.into(),
+        p.P0_24.into(),
+        p.P0_19.into(),
+    ];
+    let cols: [Peri<AnyPin>; 5] = [
+        p.P0_28.into(),
+        p.P0_11.into(),
+        p.P0_31.into(),
+        p.P1_05.into(),
+        p.P0_30.into(),
+    ];
+    let rows = rows.map(|pin| Output::new(pin, Level::Low, OutputDrive::Standard));
+    let cols = cols.map(|pin| Output::new(pin, Level::High, OutputDrive::Standard));
+    spawner.must_spawn(display::run(rows, cols));
+
+    spawner.must_spawn(buttons(
+        Input::new(p.P0_14, Pull::None),
+        Input::new(p.P0_23, Pull::None),
+    ));
+
+    // Restore persisted settings.
+    let mut storage = Storage::new(Nvmc::new(p.NVMC), STORAGE_START).with_supply_check(supply_ok);
+    let mut settings = Settings::load(&mut storage);
+    apply_settings(settings);
+    // Counted like the RTIC firmware does, but this one doesn't add its own
+    // uptime to the total.
+    let boot_record = BootRecord::count_boot(&mut storage);
+    if let Err(e) = boot_record.save(&mut storage) {
+        rprintln!("Failed to save boot count: {:?}", e);
+    }
+
+    // Initialize LSM303AGR sensor
+    let mut config = twim::Config::default();
+    config.frequency = twim::Frequency::K100;
+    let i2c = Twim::new(p.TWISPI0, Irqs, p.P0_16, p.P0_08, config, &mut []);
+    let mut sensor = start_sensor(i2c, &mut watchdog).await;
+    let mut drdy = Input::new(p.P0_25, Pull::None);
+
+    // Restore the stored calibration, falling back to precomputed constants.
+    let stored_calibration = Calibration::load(&mut storage);
+    let mut calibrated = stored_calibration.is_some();
+    let mut calibration = stored_calibration.unwrap_or(PRECOMPUTED_CALIBRATION);
+    rprintln!("{}", calibration);
+    let mut line = String::<384>::new();
+    write_version(&mut line);
+    write!(line, "Reset: {}\r\n", reset_reason).ok();
+    write!(line, "{}\r\n", boot_record).ok();
+    write!(line, "{}\r\n", calibration).ok();
+    if !calibrated {
+        write!(line, "Warning: no stored calibration, using defaults\r\n").ok();
+    }
+    write!(line, "{}\r\n", settings).ok();
+    tx.write(line.as_bytes()).await.ok();
+
+    play_boot_splash(settings).await;
+
+    let mut compass = Compass::new();
+    let mut watch = FieldWatch::new();
+    let mut survey = Survey::new();
+    let mut bus = EventBus::<EVENT_QUEUE_LEN>::new();
+    let mut serial_events = bus.subscribe();
+    let mut mode = AppMode::default();
+    let mut failures = 0;
+    let mut samples_since_report = 0;
+    loop {
+        watchdog.pet();
+
+        // Hand whatever was published last time round to the serial port.
+        while let Some(event) = bus.next(&mut serial_events) {
+            line.clear();
+            event.write_line(&mut line).ok();
+            if !line.is_empty() {
+                tx.write(line.as_bytes()).await.ok();
+            }
+        }
+
+        let sample = match read_sample(&mut sensor, &mut drdy).await {
+            Ok(sample) => {
+                failures = 0;
+                sample
+            }
+            Err(ReadError::Timeout) => {
+                rprintln!("Sensor timed out");
+                failures = 0;
+                bus.publish_error(&"sensor stopped producing data, restarting sensor");
+                sensor = start_sensor(sensor.destroy(), &mut watchdog).await;
+                bus.publish(events::Event::SensorRecovered);
+                continue;
+            }
+            Err(ReadError::Sensor(e)) => {
+                // Retry on the next edge; if that keeps failing, or the
+                // sensor no longer identifies itself because a brownout or
+                // glitch reset it, set it up from scratch.
+                rprintln!("Sensor read failed: {:?}", e);
+                failures += 1;
+                if failures >= MAX_FAILURES || !identifies(&mut sensor).await {
+                    failures = 0;
+                    bus.publish_error(&"sensor read failed, restarting sensor");
+                    sensor = start_sensor(sensor.destroy(), &mut watchdog).await;
+                    bus.publish(events::Event::SensorRecovered);
+                }
+                continue;
+            }
+        };
+        let heading = compass.update(sample, &calibration, calibrated, mode, &settings, None);
+        watch.check(&heading.field, &calibration, &mut bus);
+
+        // Send every `report_every`th sample over serial, unless the mode
+        // keeps quiet.
+        samples_since_report += 1;
+        if samples_since_report >= settings.report_every && mode.sends_samples() {
+            samples_since_report = 0;
+            line.clear();
+            settings
+                .output_format
+                .write_sample(
+                    &mut line,
+                    &heading.field,
+                    &sample.accel,
+                    sample.timestamp_us,
+                    None,
+                )
+                .ok();
+            tx.write(line.as_bytes()).await.ok();
+        }
+        // In the survey mode the field goes out averaged, with where it was
+        // measured, instead.
+        if mode == AppMode::Survey {
+            if let Some(point) = survey.add(&heading.field, sample.timestamp_us) {
+                line.clear();
+                write!(line, "{}\r\n", point).ok();
+                tx.write(line.as_bytes()).await.ok();
+            }
+        }
+
+        // Update LED display to point at magnetic North, or show the heading
+        // or level.
+        display::show(heading.frame);
+
+        while let Ok(event) = EVENTS.try_receive() {
+            let event = match event {
+                Event::Mode(event) => event,
+                Event::Command(SerialCommand::ManualCal) => {
+                    ModeEvent::Command(AppMode::Calibrating)
+                }
+                Event::Command(SerialCommand::SetAppMode(mode)) => ModeEvent::Command(mode),
+                Event::Command(SerialCommand::StopCal) => ModeEvent::StopCalibration,
+                Event::Command(command) => {
+                    // The power mode and sleep time are only saved, for the
+                    // RTIC firmware; this one has no battery or high-rate
+                    // mode and never powers down.
+                    if !settings.apply(&command) {
+                        match command {
+                            // Burst capture is RTIC firmware only.
+                            SerialCommand::Capture(_)
+                            | SerialCommand::Disarm
+                            | SerialCommand::SetPreTrigger(_)
+                            | SerialCommand::SetPostTrigger(_) => {
+                                line.clear();
+                                write!(line, "Warning: burst capture not supported\r\n").ok();
+                                tx.write(line.as_bytes()).await.ok();
+                                continue;
+                            }
+                            // So are benchmark runs.
+                            SerialCommand::Benchmark(_) => {
+                                line.clear();
+                                write!(line, "Warning: benchmark not supported\r\n").ok();
+                                tx.write(line.as_bytes()).await.ok();
+                                continue;
+                            }
+                            // And the sphere map.
+                            SerialCommand::Map(_) => {
+                                line.clear();
+                                write!(line, "Warning: sphere map not supported\r\n").ok();
+                                tx.write(line.as_bytes()).await.ok();
+                                continue;
+                            }
+                            SerialCommand::SetCalibration(new_calibration) => {
+                                calibration = new_calibration;
+                                calibrated = true;
+                                if let Err(e) = calibration.save(&mut storage) {
+                                    rprintln!("Failed to save calibration: {:?}", e);
+                                }
+                                rprintln!("New calibration: {:?}", calibration);
+                                bus.publish(events::Event::CalibrationApplied(calibration));
+                                continue;
+                            }
+                            SerialCommand::SetPosition(position) => {
+                                survey.set_position(position);
+                                line.clear();
+                                write!(line, "Position: {}\r\n", position).ok();
+                                tx.write(line.as_bytes()).await.ok();
+                                continue;
+                            }
+                            SerialCommand::Unknown => {
+                                rprintln!("Unknown command");
+                                continue;
+                            }
+                            // Turned into mode events above, or applied to the
+                            // settings.
+                            _ => unreachable!(),
+                        }
+                    }
+                    apply_settings(settings);
+                    if let Err(e) = settings.save(&mut storage) {
+                        rprintln!("Failed to save settings: {:?}", e);
+                    }
+                    line.clear();
+                    write!(line, "{}\r\n", settings).ok();
+                    tx.write(line.as_bytes()).await.ok();
+                    continue;
+                }
+            };
+
+            let (next, action) = mode.handle(event);
+            if next != mode {
+                mode = next;
+                rprintln!("{}", mode);
+                line.clear();
+                write!(line, "{}\r\n", mode).ok();
+                tx.write(line.as_bytes()).await.ok();
+            }
+            match action {
+                // The game runs to the end below before another event is
+                // handled, so there is never one to stop.
+                ModeAction::None | ModeAction::StopCalibration => {}
+                // Button A cycles through the display modes, which are
+                // saved like any other setting.
+                ModeAction::NextDisplayMode => {
+                    settings.display_mode = settings.display_mode.next();
+                    if let Err(e) = settings.save(&mut storage) {
+                        rprintln!("Failed to save settings: {:?}", e);
+                    }
+                    line.clear();
+                    write!(line, "{}\r\n", settings).ok();
+                    tx.write(line.as_bytes()).await.ok();
+                }
+                // The game runs here rather than in a task of its own, so
+                // sampling pauses until it is over either way.
+                ModeAction::StartCalibration => {
+                    let result = calc_calibration(&mut sensor, &mut drdy, &mut watchdog).await;
+                    mode = mode.handle(ModeEvent::CalibrationDone).0;
+                    line.clear();
+                    write!(line, "{}\r\n", mode).ok();
+                    tx.write(line.as_bytes()).await.ok();
+                    match result {
+                        Ok(new_calibration) => {
+                            calibration = new_calibration;
+                            calibrated = true;
+                            if let Err(e) = calibration.save(&mut storage) {
+                                rprintln!("Failed to save calibration: {:?}", e);
+                            }
+                            rprintln!("New calibration: {:?}", calibration);
+                            bus.publish(events::Event::CalibrationApplied(calibration));
+                        }
+                        Err(e) => {
+                            rprintln!("Calibration abandoned: {:?}", e);
+                            bus.publish_error(&"calibration abandoned after a sensor error");
+                        }
+                    }
+                }
+                ModeAction::NextWaypoint => {
+                    let position = survey.next_waypoint();
+                    line.clear();
+                    write!(line, "Position: {}\r\n", position).ok();
+                    tx.write(line.as_bytes()).await.ok();
+                }
+            }
+        }
+    }
+}
+
+/// The UARTE setting for a baud rate from the config.
+const fn baudrate(bps: u32) -> uarte::Baudrate {
+    match bps {
+        9_600 => uarte::Baudrate::BAUD9600,
+        19_200 => uarte::Baudrate::BAUD19200,
+        38_400 => uarte::Baudrate::BAUD38400,
+        57_600 => uarte::Baudrate::BAUD57600,
+        115_200 => uarte::Baudrate::BAUD115200,
+        230_400 => uarte::Baudrate::BAUD230400,
+        460_800 => uarte::Baudrate::BAUD460800,
+        921_600 => uarte::Baudrate::BAUD921600,
+        1_000_000 => uarte::Baudrate::BAUD1M,
+        _ => panic!("unsupported baud rate"),
+    }
+}
+
+/// Writes the boot banner's `Version:` line: the firmware's version, and
+/// the commit it was built from when known.
+fn write_version(out: &mut impl core::fmt::Write) {
+    write!(out, "Version: {}", env!("CARGO_PKG_VERSION")).ok();
+    if let Some(hash) = config::GIT_HASH {
+        write!(out, " ({})", hash).ok();
+    }
+    write!(out, "\r\n").ok();
+}
+
+/// Reads and clears the reason for the last reset.
+fn take_reset_reason() -> ResetReason {
+    let resetreas = embassy_nrf::pac::POWER.resetreas();
+    let bits = resetreas.read().0;
+    resetreas.write_value(embassy_nrf::pac::power::regs::Resetreas(bits));
+    ResetReason::from_resetreas(bits)
+}
+
+/// Whether the supply is high enough to write flash, from the power-fail
+/// comparator as in the RTIC firmware's `supply.rs`: above 2.2 V within
+/// three checks 1 ms apart.
+fn supply_ok() -> bool {
+    let power = embassy_nrf::pac::POWER;
+    (0..3).any(|attempt| {
+        if attempt > 0 {
+            cortex_m::asm::delay(64_000);
+        }
+        power.events_pofwarn().write_value(0);
+        power.pofcon().write(|w| {
+            w.set_pof(true);
+            w.set_threshold(embassy_nrf::pac::power::vals::Threshold::V22);
+        });
+        // 25 us for the comparator to settle.
+        cortex_m::asm::delay(1_600);
+        let warned = power.events_pofwarn().read() != 0;
+        power.pofcon().write(|w| w.set_pof(false));
+        power.events_pofwarn().write_value(0);
+        !warned
+    })
+}
+
+fn apply_settings(settings: Settings) {
+    display::set_hold_ms(settings.display_hold_ms);
+    if settings.brightness == AUTO_BRIGHTNESS {
+        display::set_brightness(sphere_mapping_core::led::MAX_BRIGHTNESS);
+    } else {
+        display::set_brightness(settings.brightness);
+    }
+}
+
+/// Collects incoming bytes into lines and hands each one to the main loop.
+#[embassy_executor::task]
+async fn receive(mut rx: UarteRx<'static, UARTE0>) {
+    let mut buffer = LineReader::<64>::new();
+    let mut byte = [0u8];
+    loop {
+        if rx.read(&mut byte).await.is_err() {
+            continue;
+        }
+        if let Some(line) = buffer.push(byte[0]) {
+            rprintln!("Received: {:?}", core::str::from_utf8(line));
+            EVENTS.send(Event::Command(parse_command(line))).await;
+        }
+    }
+}
+
+/// Polls buttons A and B together, so that holding both can be told apart
+/// from pressing either, and reports what they mean for the mode.
+#[embassy_executor::task]
+async fn buttons(button_a: Input<'static>, button_b: Input<'static>) {
+    let mut buttons = Buttons::new();
+    loop {
+        if let Some(event) = buttons.update(button_a.is_low(), button_b.is_low()) {
+            EVENTS.send(Event::Mode(event)).await;
+        }
+        Timer::after_millis(BUTTON_POLL_MS).await;
+    }
+}
+
+/// Sets the sensor up with both sensors running at the configured rate and
+/// signalling new data on the shared interrupt line on P0.25, retrying once a
+/// second until it answers.
+async fn start_sensor(mut i2c: I2c, watchdog: &mut WatchdogHandle) -> Sensor {
+    loop {
+        watchdog.pet();
+        let mut sensor = Lsm303agr::new_with_i2c(i2c);
+        match configure_sensor(&mut sensor).await {
+            Ok(()) => match sensor.into_mag_continuous().await {
+                Ok(mut sensor) => {
+                    let routed = match sensor.acc_enable_interrupt(Interrupt::DataReady1).await {
+                        Ok(()) => sensor.mag_enable_int().await,
+                        Err(e) => Err(e),
+                    };
+                    match routed {
+                        Ok(()) => return sensor,
+                        Err(e) => {
+                            rprintln!("Sensor setup failed: {:?}", e);
+                            i2c = sensor.destroy();
+                        }
+                    }
+                }
+                Err(e) => {
+                    rprintln!("Sensor setup failed: {:?}", e.error);
+                    i2c = e.dev.destroy();
+                }
+            },
+            Err(e) => {
+                rprintln!("Sensor setup failed: {:?}", e);
+                i2c = sensor.destroy();
+            }
+        }
+        Timer::after_secs(1).await;
+    }
+}
+
+async fn configure_sensor(
+    sensor: &mut Lsm303agr<I2cInterface<I2c>, MagOneShot>,
+) -> Result<(), SensorError> {
+    sensor.init().await?;
+    sensor
+        .set_accel_mode_and_odr(&mut Delay, AccelMode::Normal, ACCEL_ODR)
+        .await?;
+    sensor.set_accel_scale(ACCEL_SCALE).await?;
+    sensor
+        .set_mag_mode_and_odr(&mut Delay, MagMode::LowPower, MAG_ODR)
+        .await
+}
+
+/// Whether the LSM303AGR still answers with the WHO_AM_I values of both its
+/// halves.
+async fn identifies(sensor: &mut Sensor) -> bool {
+    matches!(sensor.accelerometer_id().await, Ok(id) if id.is_correct())
+        && matches!(sensor.magnetometer_id().await, Ok(id) if id.is_correct())
+}
+
+/// Waits until both sensors have produced a new sample, and returns the
+/// field in nT and the acceleration in mg, timestamped when each was read.
+/// Whichever one is ready is read straight away, which also releases the
+/// data-ready line so the next edge can be seen. Gives up after
+/// `DATA_TIMEOUT`.
+async fn read_sample(sensor: &mut Sensor, drdy: &mut Input<'_>) -> Result<Sample, ReadError> {
+    let deadline = Instant::now() + DATA_TIMEOUT;
+    let mut mag = None;
+    let mut accel = None;
+    loop {
+        if sensor.mag_status().await?.xyz_new_data() {
+            let field = sensor.magnetic_field().await?;
+            let field = Measurement {
+                x: field.x_nt(),
+                y: field.y_nt(),
+                z: field.z_nt(),
+            };
+            mag = Some((field, Instant::now().as_micros()));
+        }
+        if sensor.accel_status().await?.xyz_new_data() {
+            let acceleration = sensor.acceleration().await?;
+            let acceleration = Acceleration {
+                x: acceleration.x_mg(),
+                y: acceleration.y_mg(),
+                z: acceleration.z_mg(),
+            };
+            accel = Some((acceleration, Instant::now().as_micros()));
+        }
+        if let (Some((field, field_at)), Some((accel, accel_at))) = (mag, accel) {
+            return Ok(Sample::pair(field, field_at, accel, accel_at));
+        }
+        if Instant::now() >= deadline {
+            return Err(ReadError::Timeout);
+        }
+        // An edge that arrives between the status reads and this wait is
+        // lost, so don't wait longer than one sample period for it.
+        with_timeout(SAMPLE_PERIOD, drdy.wait_for_any_edge())
+            .await
+            .ok();
+    }
+}
+
+/// Runs the tilt-to-fill calibration game on the matrix. The game can take
+/// as long as the player likes, so it feeds the watchdog itself.
+async fn calc_calibration(
+    sensor: &mut Sensor,
+    drdy: &mut Input<'_>,
+    watchdog: &mut WatchdogHandle,
+) -> Result<Calibration, ReadError> {
+    let mut game = TiltGame::default();
+    while !game.is_done() {
+        watchdog.pet();
+        let sample = read_sample(sensor, drdy).await?;
+        if game.tilt(sample.accel.x, sample.accel.y) {
+            game.record(sample.field);
+        }
+        display::show(game.frame());
+        Timer::after_millis(CURSOR_BLINK_MS as u64).await;
+    }
+
+    display::show(DONE_FRAME);
+    Timer::after_millis(DONE_MS as u64).await;
+    Ok(game.calibration())
+}
+
+/// Sweeps the arrow once around the compass rose, then optionally flashes the
+/// firmware's major version so a reset is visible on the board itself.
+async fn play_boot_splash(settings: Settings) {
+    display::set_hold_ms(0);
+    for step in 0..SPLASH_STEPS {
+        let theta = PI / 2. - 2. * PI * step as f32 / SPLASH_STEPS as f32;
+        display::show(settings.rotation.apply(render_arrow(theta)));
+        Timer::after_millis(SPLASH_STEP_MS).await;
+    }
+    if cfg!(feature = "version-splash") {
+        let major = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0);
+        display::show(settings.rotation.apply(render_digit(major)));
+        Timer::after_millis(SPLASH_VERSION_MS).await;
+    }
+    display::show([[0; 5]; 5]);
+    display::set_hold_ms(settings.display_hold_ms);
+}
@@ -6,6 +6,11 @@
 //! build script becomes required. Additionally, by requesting that
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! a rebuild of the application with new memory settings is ensured after updating `memory.x`.
+//!
+//! The layout is copied from `memory-default.x`, with the `ble` feature
+//! from `memory-ble.x`, which leaves room for the SoftDevice, or with the
+//! `v1` feature from `memory-v1.x`, for the nRF51822. None of them is
+//! called `memory.x`, which the linker would find in the crate root first.
 
 use std::env;
 use std::fs::File;
@@ -18,13 +23,24 @@ fn main() {
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(
+            match (
+                env::var_os("CARGO_FEATURE_V1"),
+                env::var_os("CARGO_FEATURE_BLE"),
+            ) {
+                (Some(_), _) => include_bytes!("memory-v1.x").as_slice(),
+                (None, Some(_)) => include_bytes!("memory-ble.x").as_slice(),
+                (None, None) => include_bytes!("memory-default.x").as_slice(),
+            },
+        )
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
     // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
-    // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
+    // any file in the project changes. By specifying the memory
+    // layouts here, we ensure the build script is only re-run when
+    // one of them is changed.
+    println!("cargo:rerun-if-changed=memory-default.x");
+    println!("cargo:rerun-if-changed=memory-ble.x");
+    println!("cargo:rerun-if-changed=memory-v1.x");
 }
@@ -0,0 +1,8 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* The S113 SoftDevice takes the first 112K of flash and the start of
+     RAM; its RAM depends on its configuration, with room to spare here. */
+  FLASH : ORIGIN = 0x0001C000, LENGTH = 256K
+  RAM : ORIGIN = 0x20003000, LENGTH = 116K
+}
@@ -0,0 +1,7 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 256K
+  /* All of the nRF52833's RAM; the burst capture buffer alone takes 47K. */
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
@@ -0,0 +1,14 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* The nRF51822's 256K, less the 48K of records and sphere map chunks at
+     the top. */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 208K
+  RAM : ORIGIN = 0x20000000, LENGTH = 16K
+}
+
+/* The RTIC app's handlers carry the nRF52833's interrupt names (see
+   src/board/v1.rs); put them in the nRF51822's vectors. */
+UART0 = UARTE0_UART0;
+TIMER0 = TIMER4;
+SWI0 = SWI0_EGU0;
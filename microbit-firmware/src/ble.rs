@@ -0,0 +1,200 @@
+//! Bluetooth LE advertising of the compass's readout, with the `ble`
+//! feature, so phones and gateways can read it without connecting. The
+//! board advertises every 250 ms, named `sphere-mapping`, and brings the
+//! advertisement up to date every second. Its manufacturer data is 8 bytes,
+//! little-endian:
+//!
+//! - company ID `0xFFFF`, which the Bluetooth SIG keeps for testing, the
+//!   board having none registered (2 bytes);
+//! - the format, 1 (1 byte);
+//! - the heading in whole degrees clockwise from North, turned by the
+//!   declination setting, as the OLED shows it (2 bytes);
+//! - the field strength in tenths of a µT (2 bytes);
+//! - flags: bit 0 is set when a stored calibration is in use rather than
+//!   the defaults (1 byte).
+//!
+//! Until the first sample the advertisement has no manufacturer data.
+//!
+//! The advertisement is connectable, with the Nordic UART Service's UUID in
+//! the scan response. A central that connects gets the serial link over it
+//! (see [`nus`](crate::nus)) and the compass's readings as a service of
+//! their own (see [`compass_service`](crate::compass_service)); the board
+//! stops advertising until it disconnects, taking only one connection at a
+//! time.
+
+use core::cell::Cell;
+use critical_section::Mutex;
+use embassy_futures::join::join;
+use embassy_futures::select::select;
+use nrf_softdevice::ble::advertisement_builder::{
+    AdvertisementDataType, Flag, LegacyAdvertisementBuilder, LegacyAdvertisementPayload,
+    ServiceList,
+};
+use nrf_softdevice::ble::gatt_server::{self, RegisterError, WriteOp};
+use nrf_softdevice::ble::peripheral::{self, AdvertiseError, ConnectableAdvertisement};
+use nrf_softdevice::ble::{Connection, DeferredWriteReply};
+use nrf_softdevice::Softdevice;
+use rtt_target::rprintln;
+
+use crate::calibration::{Calibration, Measurement};
+use crate::command::SerialCommand;
+use crate::external::readout;
+use crate::settings::Settings;
+use crate::{compass_service, nus};
+
+pub const NAME: &str = "sphere-mapping";
+const COMPANY_ID: u16 = 0xffff;
+const FORMAT: u8 = 1;
+const CALIBRATED: u8 = 1 << 0;
+/// Advertisements sent, 250 ms apart, before the data is brought up to
+/// date.
+const EVENTS_PER_UPDATE: u8 = 4;
+
+type ManufacturerData = [u8; 8];
+
+/// The scan response, naming the service a connection offers.
+static SCAN_DATA: LegacyAdvertisementPayload = LegacyAdvertisementBuilder::new()
+    .services_128(ServiceList::Complete, &[nus::SERVICE_UUID])
+    .build();
+
+/// The manufacturer data for the latest sample.
+static LATEST: Mutex<Cell<Option<ManufacturerData>>> = Mutex::new(Cell::new(None));
+
+/// Brings the advertisement and the compass service up to date with a
+/// sample's calibrated `field` and `raw` one, and the `calibration` in use.
+pub fn update(
+    field: &Measurement,
+    raw: &Measurement,
+    calibration: &Calibration,
+    calibrated: bool,
+    settings: &Settings,
+) {
+    let (heading, field_tenths) = readout(field, settings.declination);
+    let mut data = [0; 8];
+    data[..2].copy_from_slice(&COMPANY_ID.to_le_bytes());
+    data[2] = FORMAT;
+    data[3..5].copy_from_slice(&(heading as u16).to_le_bytes());
+    data[5..7].copy_from_slice(&(field_tenths.min(u16::MAX as u32) as u16).to_le_bytes());
+    data[7] = if calibrated { CALIBRATED } else { 0 };
+    critical_section::with(|cs| LATEST.borrow(cs).set(Some(data)));
+    compass_service::update(
+        heading as u16,
+        raw,
+        calibration,
+        calibrated,
+        settings.power.sample_rate_hz(),
+    );
+}
+
+/// What a write to one of the services asks for.
+pub enum Event {
+    /// A value was written to the Nordic UART Service's RX.
+    Written,
+    /// A command, as from serial.
+    Command(SerialCommand),
+    /// Notifications have gone.
+    Sent,
+}
+
+/// The services a connection is offered.
+pub struct Server {
+    nus: nus::Service,
+    compass: compass_service::Service,
+}
+
+impl Server {
+    pub fn new(sd: &mut Softdevice) -> Result<Server, RegisterError> {
+        Ok(Server {
+            nus: nus::Service::new(sd)?,
+            compass: compass_service::Service::new(sd)?,
+        })
+    }
+
+    /// Serves `conn` until it is lost, handing the commands written to
+    /// `on_command`.
+    async fn serve(
+        &self,
+        sd: &Softdevice,
+        conn: &Connection,
+        on_command: &mut impl FnMut(SerialCommand),
+    ) {
+        select(
+            gatt_server::run(conn, self, |event| match event {
+                Event::Written => self.nus.commands(&mut *on_command),
+                Event::Command(command) => on_command(command),
+                Event::Sent => self.nus.sent(),
+            }),
+            join(self.nus.send(conn), self.compass.send(sd, conn)),
+        )
+        .await;
+        self.nus.disconnected();
+        self.compass.disconnected();
+    }
+}
+
+impl gatt_server::Server for Server {
+    type Event = Event;
+
+    fn on_write(
+        &self,
+        _conn: &Connection,
+        handle: u16,
+        _op: WriteOp,
+        _offset: usize,
+        data: &[u8],
+    ) -> Option<Event> {
+        self.nus
+            .on_write(handle, data)
+            .or_else(|| self.compass.on_write(handle, data))
+    }
+
+    fn on_deferred_write(
+        &self,
+        handle: u16,
+        _op: WriteOp,
+        _offset: usize,
+        data: &[u8],
+        reply: DeferredWriteReply,
+    ) -> Option<Event> {
+        self.compass.on_deferred_write(handle, data, reply)
+    }
+
+    fn on_notify_tx_complete(&self, _conn: &Connection, _count: u8) -> Option<Event> {
+        Some(Event::Sent)
+    }
+}
+
+/// Advertises the latest readout, and serves each connection made with
+/// `server`, handing the commands written to it to `on_command`, until the
+/// SoftDevice won't advertise.
+pub async fn advertise(
+    sd: &Softdevice,
+    server: &Server,
+    mut on_command: impl FnMut(SerialCommand),
+) {
+    let config = peripheral::Config {
+        max_events: Some(EVENTS_PER_UPDATE),
+        ..Default::default()
+    };
+    loop {
+        let mut builder =
+            LegacyAdvertisementBuilder::new().flags(&[Flag::GeneralDiscovery, Flag::LE_Only]);
+        if let Some(data) = critical_section::with(|cs| LATEST.borrow(cs).get()) {
+            builder = builder.raw(AdvertisementDataType::MANUFACTURER_SPECIFIC_DATA, &data);
+        }
+        let adv_data = builder.full_name(NAME).build();
+        let adv = ConnectableAdvertisement::ScannableUndirected {
+            adv_data: &adv_data,
+            scan_data: &SCAN_DATA,
+        };
+        match peripheral::advertise_connectable(sd, adv, &config).await {
+            Ok(conn) => server.serve(sd, &conn, &mut on_command).await,
+            // The advertisement's events have all been sent.
+            Err(AdvertiseError::Timeout) => {}
+            Err(e) => {
+                rprintln!("Advertising stopped: {:?}", e);
+                return;
+            }
+        }
+    }
+}
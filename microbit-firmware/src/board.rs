@@ -0,0 +1,49 @@
+//! The board the firmware runs on, and what about it differs from one
+//! micro:bit to the next: which peripherals drive the serial link, the
+//! internal I2C bus and the clock, which pins the matrix rows, SDA and the
+//! LSM303AGR's interrupt line are on, and how much flash there is. The
+//! micro:bit v2 is the default; the `v1` feature builds for the v1.5.
+//!
+//! |                     | v2 (nRF52833)               | v1.5 (nRF51822)            |
+//! |---------------------|-----------------------------|----------------------------|
+//! | CPU                 | 64 MHz                      | 16 MHz                     |
+//! | Serial              | UARTE0, in EasyDMA chunks   | UART0, a byte at a time    |
+//! | Internal I2C        | TWIM0, SDA on P0.16         | TWI0, SDA on P0.30         |
+//! | LSM303AGR interrupt | P0.25, both sensors         | P0.28, the accelerometer's |
+//! | Matrix              | 5 rows of 5                 | 3 rows of 9                |
+//! | Clock               | TIMER4                      | TIMER0                     |
+//! | Delays              | TIMER0, or SysTick with BLE | counted CPU cycles         |
+//! | Flash               | 512K, the HAL's NVMC driver | 256K, [`crate::nvmc`]      |
+//! | Light sensing       | SAADC                       | ADC                        |
+//!
+//! [`Parts::new`] splits the BSP's `Board` into the parts the firmware
+//! uses, under the same names on either board. The timers common to both
+//! (TIMER1 and TIMER2 for the matrix, RTC1 for the tick) are handed out as
+//! they are. `pac` is the PAC the RTIC app is built against, which on the
+//! v1 gives the interrupts the app binds their v2 names.
+
+#[cfg(all(feature = "v1", feature = "v2"))]
+compile_error!("the `v1` and `v2` features pick different boards; enable one");
+#[cfg(not(any(feature = "v1", feature = "v2")))]
+compile_error!("pick a board with the `v1` or `v2` feature");
+#[cfg(all(
+    feature = "v1",
+    any(
+        feature = "ble",
+        feature = "max7219",
+        feature = "oled",
+        feature = "mmc5983ma",
+        feature = "lsm6ds"
+    )
+))]
+compile_error!("`ble`, `max7219`, `oled`, `mmc5983ma` and `lsm6ds` need the micro:bit v2");
+
+#[cfg(feature = "v1")]
+mod v1;
+#[cfg(feature = "v2")]
+mod v2;
+
+#[cfg(feature = "v1")]
+pub use v1::*;
+#[cfg(feature = "v2")]
+pub use v2::*;
@@ -0,0 +1,190 @@
+//! The micro:bit v1.5's nRF51822.
+
+use embedded_hal::delay::DelayNs;
+use microbit::board::{Board, Buttons};
+use microbit::hal::clocks::Clocks;
+use microbit::hal::gpio::{Floating, Input, Pin};
+use microbit::hal::twi::{self, Twi};
+use microbit::hal::uart::{Parity, Uart};
+use microbit::hal::Timer;
+use microbit::pac::{ADC, GPIO, GPIOTE, NVMC, POWER, PPI, RTC1, TWI0, WDT};
+use microbit::pac::{TIMER0, TIMER1, TIMER2};
+use sphere_mapping_core::config;
+
+use crate::nvmc::Nvmc;
+use crate::serial_setup;
+use crate::uart::{self, UartPort};
+
+pub use microbit::pac::gpio::RegisterBlock as Port;
+
+/// The nRF51822's PAC, but with the interrupts the RTIC app binds named as
+/// on the nRF52833, so the one app serves both boards: RTIC 2 needs every
+/// bound interrupt to exist whichever tasks are compiled in. The handlers
+/// RTIC defines under the v2's names are put in the v1's vectors by
+/// `memory-v1.x`.
+pub mod pac {
+    pub use self::Interrupt as interrupt;
+    pub use microbit::pac::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+    pub enum Interrupt {
+        /// UART0.
+        UARTE0_UART0 = 2,
+        GPIOTE = 6,
+        /// TIMER0, the v1's clock.
+        TIMER4 = 8,
+        TIMER1 = 9,
+        TIMER2 = 10,
+        RTC1 = 17,
+        /// SWI0, RTIC's dispatcher.
+        SWI0_EGU0 = 20,
+    }
+
+    unsafe impl cortex_m::interrupt::InterruptNumber for Interrupt {
+        fn number(self) -> u16 {
+            self as u16
+        }
+    }
+}
+
+/// CPU cycles in a microsecond.
+pub const CYCLES_PER_US: u32 = 16;
+/// Bytes of flash, the records and chunks taking the top of it.
+pub const FLASH_LEN: u32 = 0x4_0000;
+/// SDA of the internal I2C bus, P0.30.
+pub const SDA_PIN: usize = 30;
+/// The accelerometer's INT1, which its data-ready and motion interrupts
+/// are raised on. The magnetometer's is P0.29, which isn't watched: it runs
+/// at the accelerometer's rate, so `sample` finds its data on the next edge
+/// at worst.
+pub const INT_PIN: usize = 28;
+/// The matrix rows, P0.13, P0.14 and P0.15, which light their LEDs when
+/// high.
+pub const ROW_PINS: [usize; 3] = [13, 14, 15];
+/// Columns whose pins double as ADC inputs, with the inputs: COL1 (P0.04,
+/// AIN5), COL2 (P0.05, AIN6) and COL3 (P0.06, AIN7).
+pub const SENSE_COLUMNS: [(usize, u8); 3] = [(4, 5), (5, 6), (6, 7)];
+
+/// TIMER2 is only 16 bits, so the hold timer counts at 31.25 kHz, which
+/// lasts a little over 2 s.
+const HOLD_PRESCALER: u8 = 9;
+const MAX_HOLD_MS: u16 = 2_000;
+
+pub type Serial = UartPort;
+pub type I2c = Twi<TWI0>;
+pub type I2cError = twi::Error;
+pub type I2cPins = twi::Pins;
+pub type ClockTimer = TIMER0;
+pub type Adc = ADC;
+pub type Flash = Nvmc;
+
+/// Delays counted in CPU cycles, there being no timer to spare for them:
+/// TIMER0 is the clock, and TIMER1 and TIMER2 the matrix's.
+pub struct Delay;
+
+impl DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        cortex_m::asm::delay(ns.div_ceil(1_000).saturating_mul(CYCLES_PER_US));
+    }
+}
+
+/// The peripherals and pins the firmware uses.
+pub struct Parts {
+    pub serial: Serial,
+    pub i2c: I2c,
+    /// The LSM303AGR's interrupt line, as a floating input.
+    pub int_pin: Pin<Input<Floating>>,
+    pub buttons: Buttons,
+    pub display_pins: microbit::gpio::DisplayPins,
+    pub display_timer: TIMER1,
+    pub hold_timer: TIMER2,
+    pub clock_timer: ClockTimer,
+    pub tick_rtc: RTC1,
+    pub delay: Delay,
+    pub adc: Adc,
+    pub nvmc: NVMC,
+    pub gpiote: GPIOTE,
+    pub ppi: PPI,
+    pub wdt: WDT,
+    pub power: POWER,
+}
+
+impl Parts {
+    /// Sets up the serial link and the internal I2C bus, and starts the
+    /// low-frequency clock the tick counts.
+    pub fn new(board: Board) -> Parts {
+        Clocks::new(board.CLOCK).start_lfclk();
+        let serial = Uart::new(
+            board.UART0,
+            board.uart.into(),
+            Parity::EXCLUDED,
+            serial_setup::baudrate(config::BAUD_RATE),
+        );
+        Parts {
+            serial: UartPort::new(serial),
+            i2c: i2c(board.TWI0, board.i2c.into()),
+            int_pin: board.pins.p0_28.into_floating_input().degrade(),
+            buttons: board.buttons,
+            display_pins: board.display_pins,
+            display_timer: board.TIMER1,
+            hold_timer: board.TIMER2,
+            clock_timer: board.TIMER0,
+            tick_rtc: board.RTC1,
+            delay: Delay,
+            adc: board.ADC,
+            nvmc: board.NVMC,
+            gpiote: board.GPIOTE,
+            ppi: board.PPI,
+            wdt: board.WDT,
+            power: board.POWER,
+        }
+    }
+}
+
+/// The GPIO port, for pins driven through its registers.
+pub fn port() -> &'static Port {
+    unsafe { &*GPIO::ptr() }
+}
+
+/// The internal I2C bus at 100 kHz.
+pub fn i2c(twi: TWI0, pins: I2cPins) -> I2c {
+    Twi::new(twi, pins, twi::Frequency::K100)
+}
+
+/// Disables the internal I2C bus's peripheral and hands its pins back, for
+/// driving them by hand until [`i2c`] takes them again.
+pub fn release_i2c(i2c: I2c) -> (TWI0, I2cPins) {
+    let (twi, pins) = i2c.free();
+    twi.enable.write(|w| w.enable().disabled());
+    (twi, pins)
+}
+
+/// TIMER2 as a one-shot timer for the frame hold, counting at 31.25 kHz.
+pub fn hold_timer(timer: TIMER2) -> Timer<TIMER2> {
+    let timer = Timer::one_shot(timer);
+    let registers = unsafe { &*TIMER2::ptr() };
+    registers.bitmode.write(|w| w.bitmode()._16bit());
+    registers
+        .prescaler
+        .write(|w| unsafe { w.prescaler().bits(HOLD_PRESCALER) });
+    timer
+}
+
+/// The hold timer's count for `hold_ms`, cut to the 2 s it can time.
+pub fn hold_ticks(hold_ms: u16) -> u32 {
+    hold_ms.min(MAX_HOLD_MS) as u32 * 125 / 4
+}
+
+/// The flash from `start`, `len` bytes of it.
+pub fn flash(nvmc: NVMC, start: u32, len: usize) -> Flash {
+    Nvmc::new(nvmc, unsafe {
+        core::slice::from_raw_parts_mut(start as *mut u8, len)
+    })
+}
+
+/// Writes `bytes` to the serial port without the interrupt, for the panic
+/// handler.
+pub fn write_polled(bytes: &[u8]) {
+    uart::write_polled(bytes);
+}
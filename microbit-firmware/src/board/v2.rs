@@ -0,0 +1,165 @@
+//! The micro:bit v2's nRF52833.
+
+#[cfg(any(feature = "oled", feature = "mmc5983ma", feature = "lsm6ds"))]
+use microbit::board::I2CExternalPins;
+use microbit::board::{Board, Buttons};
+#[cfg(not(feature = "ble"))]
+use microbit::hal::clocks::Clocks;
+#[cfg(feature = "max7219")]
+use microbit::hal::gpio::{p0, p1, Disconnected};
+use microbit::hal::gpio::{Floating, Input, Pin};
+use microbit::hal::nvmc::Nvmc;
+use microbit::hal::twim::{self, Twim};
+use microbit::hal::uarte::{Parity, Uarte};
+use microbit::hal::Timer;
+#[cfg(not(feature = "ble"))]
+use microbit::pac::TIMER0;
+use microbit::pac::{GPIOTE, NVMC, P0, POWER, PPI, RTC1, SAADC, TWIM0, UARTE0, WDT};
+use microbit::pac::{TIMER1, TIMER2, TIMER4};
+use sphere_mapping_core::config;
+
+use crate::serial_setup::{self, UartePort};
+
+pub use microbit::pac;
+pub use microbit::pac::p0::RegisterBlock as Port;
+
+/// CPU cycles in a microsecond.
+pub const CYCLES_PER_US: u32 = 64;
+/// Bytes of flash, the records and chunks taking the top of it.
+pub const FLASH_LEN: u32 = 0x8_0000;
+/// SDA of the internal I2C bus, P0.16.
+pub const SDA_PIN: usize = 16;
+/// The LSM303AGR's interrupt line, which both its sensors raise.
+pub const INT_PIN: usize = 25;
+/// The matrix rows, P0.21, P0.22, P0.15, P0.24 and P0.19, which light
+/// their LEDs when high.
+pub const ROW_PINS: [usize; 5] = [21, 22, 15, 24, 19];
+/// Columns whose pins double as SAADC inputs, with the inputs: COL1 (P0.28,
+/// AIN4), COL3 (P0.31, AIN7) and COL5 (P0.30, AIN6).
+pub const SENSE_COLUMNS: [(usize, u8); 3] = [(28, 4), (31, 7), (30, 6)];
+
+pub type Serial = UartePort<UARTE0>;
+pub type I2c = Twim<TWIM0>;
+pub type I2cError = twim::Error;
+pub type I2cPins = twim::Pins;
+pub type ClockTimer = TIMER4;
+pub type Adc = SAADC;
+pub type Flash = Nvmc<NVMC>;
+#[cfg(not(feature = "ble"))]
+pub type Delay = Timer<TIMER0>;
+/// The SoftDevice takes TIMER0, so delays count SysTick instead.
+#[cfg(feature = "ble")]
+pub type Delay = microbit::hal::Delay;
+
+/// The peripherals and pins the firmware uses.
+pub struct Parts {
+    pub serial: Serial,
+    pub i2c: I2c,
+    /// The LSM303AGR's interrupt line, as a floating input.
+    pub int_pin: Pin<Input<Floating>>,
+    pub buttons: Buttons,
+    pub display_pins: microbit::gpio::DisplayPins,
+    pub display_timer: TIMER1,
+    pub hold_timer: TIMER2,
+    pub clock_timer: ClockTimer,
+    pub tick_rtc: RTC1,
+    pub delay: Delay,
+    pub adc: Adc,
+    pub nvmc: NVMC,
+    pub gpiote: GPIOTE,
+    pub ppi: PPI,
+    pub wdt: WDT,
+    pub power: POWER,
+    /// SCK, MOSI and CS for the MAX7219, which the Board only hands out
+    /// loose.
+    #[cfg(feature = "max7219")]
+    pub matrix_pins: (
+        p0::P0_17<Disconnected>,
+        p0::P0_13<Disconnected>,
+        p1::P1_02<Disconnected>,
+    ),
+    #[cfg(any(feature = "oled", feature = "mmc5983ma", feature = "lsm6ds"))]
+    pub i2c_external: I2CExternalPins,
+}
+
+impl Parts {
+    /// Sets up the serial link, the internal I2C bus and the delay, and
+    /// starts the low-frequency clock the tick counts, which the SoftDevice
+    /// starts for itself with `ble`.
+    pub fn new(board: Board) -> Parts {
+        #[cfg(not(feature = "ble"))]
+        Clocks::new(board.CLOCK).start_lfclk();
+        let serial = Uarte::new(
+            board.UARTE0,
+            board.uart.into(),
+            Parity::EXCLUDED,
+            serial_setup::baudrate(config::BAUD_RATE),
+        );
+        Parts {
+            serial: UartePort::new(serial),
+            i2c: i2c(board.TWIM0, board.i2c_internal.into()),
+            int_pin: board.pins.p0_25.into_floating_input().degrade(),
+            buttons: board.buttons,
+            display_pins: board.display_pins,
+            display_timer: board.TIMER1,
+            hold_timer: board.TIMER2,
+            clock_timer: board.TIMER4,
+            tick_rtc: board.RTC1,
+            #[cfg(not(feature = "ble"))]
+            delay: Timer::new(board.TIMER0),
+            #[cfg(feature = "ble")]
+            delay: microbit::hal::Delay::new(board.SYST),
+            adc: board.ADC,
+            nvmc: board.NVMC,
+            gpiote: board.GPIOTE,
+            ppi: board.PPI,
+            wdt: board.WDT,
+            power: board.POWER,
+            #[cfg(feature = "max7219")]
+            matrix_pins: (board.pins.p0_17, board.pins.p0_13, board.edge.e16),
+            #[cfg(any(feature = "oled", feature = "mmc5983ma", feature = "lsm6ds"))]
+            i2c_external: board.i2c_external,
+        }
+    }
+}
+
+/// The GPIO port, for pins driven through its registers.
+pub fn port() -> &'static Port {
+    unsafe { &*P0::ptr() }
+}
+
+/// The internal I2C bus at 100 kHz.
+pub fn i2c(twim: TWIM0, pins: I2cPins) -> I2c {
+    Twim::new(twim, pins, twim::Frequency::K100)
+}
+
+/// Disables the internal I2C bus's peripheral and hands its pins back, for
+/// driving them by hand until [`i2c`] takes them again.
+pub fn release_i2c(i2c: I2c) -> (TWIM0, I2cPins) {
+    let (twim, pins) = i2c.free();
+    twim.enable.write(|w| w.enable().disabled());
+    (twim, pins)
+}
+
+/// TIMER2 as a one-shot timer for the frame hold, counting microseconds.
+pub fn hold_timer(timer: TIMER2) -> Timer<TIMER2> {
+    Timer::one_shot(timer)
+}
+
+/// The hold timer's count for `hold_ms`.
+pub fn hold_ticks(hold_ms: u16) -> u32 {
+    hold_ms as u32 * 1_000
+}
+
+/// The flash from `start`, `len` bytes of it.
+pub fn flash(nvmc: NVMC, start: u32, len: usize) -> Flash {
+    Nvmc::new(nvmc, unsafe {
+        core::slice::from_raw_parts_mut(start as *mut u8, len)
+    })
+}
+
+/// Writes `bytes` to the serial port without the interrupt, for the panic
+/// handler.
+pub fn write_polled(bytes: &[u8]) {
+    serial_setup::write_polled::<UARTE0>(bytes);
+}
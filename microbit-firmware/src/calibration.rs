@@ -1,294 +1,91 @@
-//! Translated from <https://github.com/lancaster-university/codal-microbit-v2/blob/006abf5566774fbcf674c0c7df27e8a9d20013de/source/MicroBitCompassCalibrator.cpp>
-
-use core::fmt::Debug;
-use embedded_hal::delay::DelayNs;
-use embedded_hal::i2c::I2c;
-use libm::{fabsf, sqrtf};
-use lsm303agr::interface::I2cInterface;
-use lsm303agr::mode::MagContinuous;
-use lsm303agr::{Lsm303agr, MagneticField};
-use microbit::display::blocking::Display;
-
-const PERIMETER_POINTS: usize = 25;
-const PIXEL1_THRESHOLD: i32 = 200;
-const PIXEL2_THRESHOLD: i32 = 600;
-const CALIBRATION_INCREMENT: i32 = 200;
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Measurement {
-    pub x: i32,
-    pub y: i32,
-    pub z: i32,
+//! Runs the tilt-to-fill calibration game from `sphere_mapping_core` on the
+//! LSM303AGR and the onboard matrix. `sample` hands the game each sample as
+//! it reads it, and `calibrate` shows the next frame whenever `tick` finds
+//! one due, so the game holds neither the sensor nor the matrix in between
+//! and everything else keeps running.
+
+use sphere_mapping_core::calibration::{TiltGame, CURSOR_BLINK_MS, DONE_FRAME, DONE_MS};
+use sphere_mapping_core::device::Sample;
+use sphere_mapping_core::led::Frame;
+use sphere_mapping_core::mode::AppMode;
+
+pub use sphere_mapping_core::calibration::{Calibration, Measurement, PRECOMPUTED_CALIBRATION};
+
+/// How long the game can go on before it is abandoned, in microseconds.
+pub const TIMEOUT_US: u64 = 120_000_000;
+
+/// A game under way.
+pub struct Game {
+    game: TiltGame,
+    /// The mode to go back to if the game is abandoned.
+    previous: AppMode,
+    started_us: u64,
+    next_frame_us: u64,
+    /// Whether the tick is up, to be shown until the next frame is due.
+    finishing: bool,
+    stopped: bool,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Calibration {
-    pub center: Measurement,
-    pub scale: Measurement,
-    pub radius: u32,
-}
-
-
-impl core::fmt::Display for Calibration {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(
-            f,
-            "Calibration: {}, {}, {}, {}, {}, {}, {}",
-            self.center.x,
-            self.center.y,
-            self.center.z,
-            self.scale.x,
-            self.scale.y,
-            self.scale.z,
-            self.radius
-        )
-    }
+/// What to do for the game's next frame.
+pub enum Step {
+    Show(Frame),
+    /// The game is complete and its tick has been shown.
+    Done(Calibration),
+    /// The game was stopped or ran out of time; go back to `previous`.
+    Abandoned {
+        previous: AppMode,
+        timed_out: bool,
+    },
 }
 
-impl Default for Calibration {
-    fn default() -> Calibration {
-        Calibration {
-            center: Measurement { x: 0, y: 0, z: 0 },
-            scale: Measurement {
-                x: 1024,
-                y: 1024,
-                z: 1024,
-            },
-            radius: 0,
+impl Game {
+    /// Starts a game at `now_us`, in place of `previous`.
+    pub fn new(previous: AppMode, now_us: u64) -> Game {
+        Game {
+            game: TiltGame::default(),
+            previous,
+            started_us: now_us,
+            next_frame_us: now_us,
+            finishing: false,
+            stopped: false,
         }
     }
-}
-
-pub fn calc_calibration<I, T>(
-    sensor: &mut Lsm303agr<I2cInterface<I>, MagContinuous>,
-    display: &mut Display,
-    timer: &mut T,
-) -> Calibration
-where
-    T: DelayNs,
-    I: I2c,
-{
-    let data = get_data(sensor, display, timer);
-    return calibrate(&data);
-}
-
-fn get_data<I, T>(
-    sensor: &mut Lsm303agr<I2cInterface<I>, MagContinuous>,
-    display: &mut Display,
-    timer: &mut T,
-) -> [Measurement; 25]
-where
-    T: DelayNs,
-    I: I2c,
-{
-    let mut leds = [
-        [0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0],
-        [0, 0, 0, 0, 0],
-    ];
-    let mut cursor = (2, 2);
-    let mut data = [Measurement { x: 0, y: 0, z: 0 }; PERIMETER_POINTS];
-    let mut samples = 0;
 
-    while samples < PERIMETER_POINTS {
-        while !sensor.accel_status().unwrap().xyz_new_data() {}
-        let accel_data = sensor.acceleration().unwrap();
-        let x = accel_data.x_mg();
-        let y = accel_data.y_mg();
-        if x < -PIXEL2_THRESHOLD {
-            cursor.1 = 0;
-        } else if x < -PIXEL1_THRESHOLD {
-            cursor.1 = 1;
-        } else if x > PIXEL2_THRESHOLD {
-            cursor.1 = 4;
-        } else if x > PIXEL1_THRESHOLD {
-            cursor.1 = 3;
-        } else {
-            cursor.1 = 2;
+    /// Moves the cursor for a sample, filling the pixel under it with the
+    /// field if it was empty.
+    pub fn feed(&mut self, sample: &Sample) {
+        if self.game.tilt(sample.accel.x, sample.accel.y) {
+            self.game.record(sample.field);
         }
-
-        if y < -PIXEL2_THRESHOLD {
-            cursor.0 = 0;
-        } else if y < -PIXEL1_THRESHOLD {
-            cursor.0 = 1;
-        } else if y > PIXEL2_THRESHOLD {
-            cursor.0 = 4;
-        } else if y > PIXEL1_THRESHOLD {
-            cursor.0 = 3;
-        } else {
-            cursor.0 = 2;
-        }
-
-        // Turn the y axis properly
-        cursor.0 = 4 - cursor.0;
-
-        if leds[cursor.0][cursor.1] != 1 {
-            leds[cursor.0][cursor.1] = 1;
-            while !sensor.mag_status().unwrap().xyz_new_data() {}
-            let mag_data_raw = sensor.magnetic_field().unwrap();
-            let mag_data = Measurement {
-                x: mag_data_raw.x_nt(),
-                y: mag_data_raw.y_nt(),
-                z: mag_data_raw.z_nt(),
-            };
-            let mag_data = measurement_to_enu(mag_data);
-            data[samples] = mag_data;
-            samples += 1;
-        }
-        display.show(timer, leds, 200);
     }
-    return data;
-}
-
-fn difference_square(a: Measurement, b: Measurement) -> f32 {
-    let dx = (a.x - b.x) as f32;
-    let dy = (a.y - b.y) as f32;
-    let dz = (a.z - b.z) as f32;
-
-    (dx * dx) + (dy * dy) + (dz * dz)
-}
 
-fn measure_score(center: Measurement, data: &[Measurement]) -> f32 {
-    let mut min_d = difference_square(center, data[0]);
-    let mut max_d = min_d;
-
-    for point in data[1..].iter() {
-        let d = difference_square(center, *point);
-        if d < min_d {
-            min_d = d;
-        }
-
-        if d > max_d {
-            max_d = d;
-        }
+    /// Abandons the game at its next frame.
+    pub fn stop(&mut self) {
+        self.stopped = true;
     }
 
-    max_d - min_d
-}
-
-fn calibrate(data: &[Measurement]) -> Calibration {
-    // Approximate a center for the data
-    let mut center = Measurement { x: 0, y: 0, z: 0 };
-    let mut best = center;
-
-    for point in data {
-        center.x += point.x;
-        center.y += point.y;
-        center.z += point.z;
+    pub fn frame_due(&self, now_us: u64) -> bool {
+        now_us >= self.next_frame_us
     }
 
-    center.x = center.x / data.len() as i32;
-    center.y = center.y / data.len() as i32;
-    center.z = center.z / data.len() as i32;
-
-    let mut current = center;
-    let mut score = measure_score(current, data);
-
-    // Calculate a fixpoint position
-    loop {
-        for x in [-CALIBRATION_INCREMENT, 0, CALIBRATION_INCREMENT] {
-            for y in [-CALIBRATION_INCREMENT, 0, CALIBRATION_INCREMENT] {
-                for z in [-CALIBRATION_INCREMENT, 0, CALIBRATION_INCREMENT] {
-                    let mut attempt = current;
-                    attempt.x += x;
-                    attempt.y += y;
-                    attempt.z += z;
-
-                    let attempt_score = measure_score(attempt, data);
-                    if attempt_score < score {
-                        score = attempt_score;
-                        best = attempt;
-                    }
-                }
-            }
+    /// The game's next frame, or how it ended, at `now_us`.
+    pub fn step(&mut self, now_us: u64) -> Step {
+        if self.finishing {
+            return Step::Done(self.game.calibration());
         }
-
-        if best == current {
-            break;
+        let timed_out = now_us - self.started_us >= TIMEOUT_US;
+        if self.stopped || timed_out {
+            return Step::Abandoned {
+                previous: self.previous,
+                timed_out,
+            };
         }
-
-        current = best;
-    }
-
-    spherify(current, data)
-}
-
-fn spherify(center: Measurement, data: &[Measurement]) -> Calibration {
-    let mut radius = 0;
-    for point in data {
-        let d = sqrtf(difference_square(center, *point)) as u32;
-        if d > radius {
-            radius = d;
+        if self.game.is_done() {
+            self.finishing = true;
+            self.next_frame_us = now_us + DONE_MS as u64 * 1000;
+            return Step::Show(DONE_FRAME);
         }
-    }
-
-    let mut scale: f32 = 0.0;
-    let mut weight_x = 0.0;
-    let mut weight_y = 0.0;
-    let mut weight_z = 0.0;
-
-    for point in data {
-        let d = sqrtf(difference_square(center, *point));
-        let s = (radius as f32 / d) - 1.0;
-        scale = scale.max(s);
-
-        let dx = point.x - center.x;
-        let dy = point.y - center.y;
-        let dz = point.z - center.z;
-
-        weight_x += s * fabsf(dx as f32 / d);
-        weight_y += s * fabsf(dy as f32 / d);
-        weight_z += s * fabsf(dz as f32 / d);
-    }
-
-    let wmag = sqrtf((weight_x * weight_x) + (weight_y * weight_y) + (weight_z * weight_z));
-    let scale_x = 1.0 + scale * (weight_x / wmag);
-    let scale_y = 1.0 + scale * (weight_y / wmag);
-    let scale_z = 1.0 + scale * (weight_z / wmag);
-
-    Calibration {
-        center,
-        radius,
-        scale: Measurement {
-            x: (1024.0 * scale_x) as i32,
-            y: (1024.0 * scale_y) as i32,
-            z: (1024.0 * scale_z) as i32,
-        },
-    }
-}
-
-pub fn calibrated_measurement(
-    measurement: MagneticField,
-    calibration: &Calibration,
-) -> Measurement {
-    let measurement = Measurement {
-        x: measurement.x_nt(),
-        y: measurement.y_nt(),
-        z: measurement.z_nt(),
-    };
-    let mut out = measurement_to_enu(measurement);
-    out = Measurement {
-        x: ((out.x - calibration.center.x) * calibration.scale.x) >> 10,
-        y: ((out.y - calibration.center.y) * calibration.scale.y) >> 10,
-        z: ((out.z - calibration.center.z) * calibration.scale.z) >> 10,
-    };
-    enu_to_cartesian(out)
-}
-
-fn measurement_to_enu(measurement: Measurement) -> Measurement {
-    Measurement {
-        x: -measurement.y,
-        y: -measurement.x,
-        z: measurement.z,
-    }
-}
-
-fn enu_to_cartesian(measurement: Measurement) -> Measurement {
-    Measurement {
-        x: -measurement.y,
-        y: measurement.x,
-        z: measurement.z,
+        self.next_frame_us = now_us + CURSOR_BLINK_MS as u64 * 1000;
+        Step::Show(self.game.frame())
     }
 }
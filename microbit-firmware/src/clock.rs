@@ -0,0 +1,117 @@
+//! Monotonic microsecond clock for timestamps, filters and timeouts. TIMER4
+//! (TIMER0 on the micro:bit v1, see `board.rs`) free-runs at 1 MHz and
+//! keeps counting while the CPU sleeps; it wraps every 71 minutes, so its
+//! interrupt counts wraps into the top half of a 64-bit count that won't
+//! wrap in the board's lifetime.
+//!
+//! The interrupt handler itself is an RTIC task in `main.rs` that calls
+//! [`wrapped`].
+//!
+//! A hardware event can also latch the count through PPI, with no CPU
+//! involved, by triggering [`event_capture_task`]; [`last_event`] then says
+//! when it happened, however late the firmware gets round to asking.
+//!
+//! [`wake_at`] has the timer's interrupt go off at a given time as well, to
+//! wake a CPU sleeping until a deadline.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use microbit::pac::timer0::TASKS_CAPTURE;
+
+use crate::board::ClockTimer;
+
+/// 16 MHz divided by 2^4.
+const PRESCALER: u8 = 4;
+/// Raised when the count wraps to 0.
+const WRAP_CHANNEL: usize = 0;
+/// Used to read the count.
+const CAPTURE_CHANNEL: usize = 1;
+/// Latched by hardware events through PPI.
+const EVENT_CHANNEL: usize = 2;
+/// Raised at the time given to [`wake_at`].
+const WAKE_CHANNEL: usize = 3;
+
+/// Wraps counted so far.
+static WRAPS: AtomicU32 = AtomicU32::new(0);
+
+/// Starts the clock from 0. Takes the timer to make sure nothing else uses
+/// it.
+pub fn start(timer: ClockTimer) {
+    timer.tasks_stop.write(|w| unsafe { w.bits(1) });
+    timer.mode.write(|w| w.mode().timer());
+    timer.bitmode.write(|w| w.bitmode()._32bit());
+    timer
+        .prescaler
+        .write(|w| unsafe { w.prescaler().bits(PRESCALER) });
+    timer.shorts.reset();
+    timer.cc[WRAP_CHANNEL].write(|w| unsafe { w.bits(0) });
+    timer.events_compare[WRAP_CHANNEL].reset();
+    timer.intenset.write(|w| w.compare0().set());
+    timer.tasks_clear.write(|w| unsafe { w.bits(1) });
+    timer.tasks_start.write(|w| unsafe { w.bits(1) });
+}
+
+fn timer() -> &'static microbit::pac::timer0::RegisterBlock {
+    unsafe { &*ClockTimer::ptr() }
+}
+
+/// Microseconds since [`start`].
+pub fn now() -> u64 {
+    critical_section::with(|_| {
+        let timer = timer();
+        timer.tasks_capture[CAPTURE_CHANNEL].write(|w| unsafe { w.bits(1) });
+        let low = timer.cc[CAPTURE_CHANNEL].read().bits();
+        let mut wraps = WRAPS.load(Ordering::Relaxed);
+        // A wrap that happened before the capture but that the interrupt
+        // hasn't counted yet, because interrupts are masked.
+        if timer.events_compare[WRAP_CHANNEL].read().bits() != 0 && low < u32::MAX / 2 {
+            wraps += 1;
+        }
+        (wraps as u64) << 32 | low as u64
+    })
+}
+
+/// Counts a wrap, and clears a [`wake_at`] that has gone off; call from the
+/// timer's interrupt.
+pub fn wrapped() {
+    critical_section::with(|_| {
+        let timer = timer();
+        if timer.events_compare[WRAP_CHANNEL].read().bits() != 0 {
+            timer.events_compare[WRAP_CHANNEL].reset();
+            // Not `fetch_add`, which the v1's Cortex-M0 lacks; the critical
+            // section makes it atomic anyway.
+            WRAPS.store(WRAPS.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        }
+        if timer.events_compare[WAKE_CHANNEL].read().bits() != 0 {
+            timer.events_compare[WAKE_CHANNEL].reset();
+            timer.intenclr.write(|w| w.compare3().clear());
+        }
+    });
+}
+
+/// Has the timer's interrupt go off at `at_us`, less than 71 minutes from
+/// now, replacing any earlier one. Does nothing useful if `at_us` has
+/// already passed, so check the time again before sleeping.
+#[cfg(feature = "ble")]
+pub fn wake_at(at_us: u64) {
+    critical_section::with(|_| {
+        let timer = timer();
+        timer.events_compare[WAKE_CHANNEL].reset();
+        timer.cc[WAKE_CHANNEL].write(|w| unsafe { w.bits(at_us as u32) });
+        timer.intenset.write(|w| w.compare3().set());
+    });
+}
+
+/// The task that latches the count for [`last_event`], for a PPI channel to
+/// trigger.
+pub fn event_capture_task() -> &'static TASKS_CAPTURE {
+    &timer().tasks_capture[EVENT_CHANNEL]
+}
+
+/// When [`event_capture_task`] was last triggered, in microseconds since
+/// [`start`]. Only the low 32 bits are latched, so the event must be less
+/// than 71 minutes old.
+pub fn last_event() -> u64 {
+    let latched = timer().cc[EVENT_CHANNEL].read().bits();
+    let now = now();
+    now - (now as u32).wrapping_sub(latched) as u64
+}
@@ -0,0 +1,296 @@
+//! A GATT service of the compass's readings, with the `ble` feature, so
+//! standard Bluetooth LE tools and phone apps can read the board without
+//! speaking its serial protocol. Its UUID is
+//! `5C3A0001-7D1E-4F4A-9B8E-2F6D1A3C5E70`, and its characteristics' differ
+//! from it in the third byte from the end. Values are little-endian:
+//!
+//! - heading (`…0002…`, read, notify): whole degrees clockwise from North,
+//!   turned by the declination setting, as a `u16`;
+//! - field (`…0003…`, read, notify): the magnetometer's reading before
+//!   calibration, x, y and z in nT as `i32`s, as `SCAP` records it;
+//! - calibration (`…0004…`, read): flags, bit 0 set when a stored
+//!   calibration is in use rather than the defaults, then the calibration
+//!   in use in the order a `Calibration:` line gives it: the center's x, y
+//!   and z and the scale's as `i32`s and the radius as a `u32` (29 bytes);
+//! - sample rate (`…0005…`, read, write): samples a second, as a `u16`.
+//!   Writing the rate of one of the power modes switches to it and saves
+//!   it, as `SPWR` does; any other value is refused with the "Out of
+//!   Range" error, `0xFF`;
+//! - configuration (`…0006…`, write): a settings command or `SCAL` with the
+//!   seven numbers of a calibration, as text as it would be sent over
+//!   serial, with or without its line ending. It is checked, applied and
+//!   saved as over serial, so a board out of reach can be set up from a
+//!   phone. Anything else, or a value out of range, is refused with the
+//!   "Out of Range" error. A calibration is longer than the 20 bytes a
+//!   write carries at the smallest MTU, so the client has to have agreed to
+//!   a larger one, as phones do.
+//!
+//! Heading and field are notified with each sample to a client that has
+//! subscribed. While the connection is behind, a sample's notifications are
+//! skipped, the next one's taking their place.
+
+use core::cell::Cell;
+use core::convert::TryFrom;
+use critical_section::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
+use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Properties};
+use nrf_softdevice::ble::gatt_server::{self, RegisterError};
+use nrf_softdevice::ble::{Connection, DeferredWriteReply, GattError, Uuid};
+use nrf_softdevice::Softdevice;
+use rtt_target::rprintln;
+
+use crate::ble::Event;
+use crate::calibration::{Calibration, Measurement};
+use crate::command::{parse_command, SerialCommand};
+use crate::settings::PowerMode;
+
+/// The service's UUID, 5C3A0001-7D1E-4F4A-9B8E-2F6D1A3C5E70, little-endian.
+pub const SERVICE_UUID: [u8; 16] = [
+    0x70, 0x5e, 0x3c, 0x1a, 0x6d, 0x2f, 0x8e, 0x9b, 0x4a, 0x4f, 0x1e, 0x7d, 0x01, 0x00, 0x3a, 0x5c,
+];
+const HEADING_UUID: u8 = 0x02;
+const FIELD_UUID: u8 = 0x03;
+const CALIBRATION_UUID: u8 = 0x04;
+const SAMPLE_RATE_UUID: u8 = 0x05;
+const CONFIGURATION_UUID: u8 = 0x06;
+/// Longest configuration written, as a command line over serial.
+const CONFIGURATION_LEN: u16 = 64;
+const CALIBRATED: u8 = 1 << 0;
+
+/// The characteristics' values for a sample.
+#[derive(Clone, Copy)]
+struct Values {
+    heading: [u8; 2],
+    field: [u8; 12],
+    calibration: [u8; 29],
+    sample_rate: [u8; 2],
+}
+
+/// The values for the latest sample.
+static LATEST: Mutex<Cell<Option<Values>>> = Mutex::new(Cell::new(None));
+/// A sample has come.
+static UPDATED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn uuid(kind: u8) -> Uuid {
+    let mut uuid = SERVICE_UUID;
+    uuid[12] = kind;
+    Uuid::new_128(&uuid)
+}
+
+/// Brings the values up to date with a sample: its `heading` and `raw`
+/// field, the `calibration` in use and whether it is a stored one, and the
+/// sample rate.
+pub fn update(
+    heading: u16,
+    raw: &Measurement,
+    calibration: &Calibration,
+    calibrated: bool,
+    sample_rate_hz: u32,
+) {
+    let mut field = [0; 12];
+    for (bytes, axis) in field.chunks_exact_mut(4).zip([raw.x, raw.y, raw.z]) {
+        bytes.copy_from_slice(&axis.to_le_bytes());
+    }
+    let mut status = [0; 29];
+    status[0] = if calibrated { CALIBRATED } else { 0 };
+    let Calibration {
+        center,
+        scale,
+        radius,
+    } = calibration;
+    let numbers = [center.x, center.y, center.z, scale.x, scale.y, scale.z];
+    for (bytes, number) in status[1..25].chunks_exact_mut(4).zip(numbers) {
+        bytes.copy_from_slice(&number.to_le_bytes());
+    }
+    status[25..].copy_from_slice(&radius.to_le_bytes());
+    let values = Values {
+        heading: heading.to_le_bytes(),
+        field,
+        calibration: status,
+        sample_rate: (sample_rate_hz as u16).to_le_bytes(),
+    };
+    critical_section::with(|cs| LATEST.borrow(cs).set(Some(values)));
+    UPDATED.signal(());
+}
+
+/// The power mode that samples at `hz`, preferring the normal one where
+/// two do.
+fn power_mode(hz: u32) -> Option<PowerMode> {
+    [PowerMode::Normal, PowerMode::HighRate, PowerMode::Battery]
+        .iter()
+        .copied()
+        .find(|mode| mode.sample_rate_hz() == hz)
+}
+
+/// The command written to the configuration, if it is one that may be:
+/// a setting or a calibration to take.
+fn configuration(data: &[u8]) -> Option<SerialCommand> {
+    let end = data
+        .iter()
+        .rposition(|&byte| byte != b'\r' && byte != b'\n')
+        .map_or(0, |last| last + 1);
+    match parse_command(&data[..end]) {
+        command @ (SerialCommand::SetCalibration(_)
+        | SerialCommand::SetRotation(_)
+        | SerialCommand::SetDisplayHold(_)
+        | SerialCommand::SetBrightness(_)
+        | SerialCommand::SetDisplayMode(_)
+        | SerialCommand::SetOutputFormat(_)
+        | SerialCommand::SetReportEvery(_)
+        | SerialCommand::SetSmoothing(_)
+        | SerialCommand::SetDeclination(_)
+        | SerialCommand::SetPowerMode(_)
+        | SerialCommand::SetSleepAfter(_)) => Some(command),
+        _ => None,
+    }
+}
+
+/// The service's characteristics, as registered with the SoftDevice.
+pub struct Service {
+    heading: u16,
+    heading_cccd: u16,
+    field: u16,
+    field_cccd: u16,
+    calibration: u16,
+    sample_rate: u16,
+    configuration: u16,
+    /// Whether the client has subscribed to the heading and the field.
+    notify_heading: Cell<bool>,
+    notify_field: Cell<bool>,
+}
+
+impl Service {
+    pub fn new(sd: &mut Softdevice) -> Result<Service, RegisterError> {
+        let mut service = ServiceBuilder::new(sd, Uuid::new_128(&SERVICE_UUID))?;
+        let read_notify = Properties::new().read().notify();
+        let heading = service
+            .add_characteristic(
+                uuid(HEADING_UUID),
+                Attribute::new([0u8; 2]),
+                Metadata::new(read_notify),
+            )?
+            .build();
+        let field = service
+            .add_characteristic(
+                uuid(FIELD_UUID),
+                Attribute::new([0u8; 12]),
+                Metadata::new(read_notify),
+            )?
+            .build();
+        let calibration = service
+            .add_characteristic(
+                uuid(CALIBRATION_UUID),
+                Attribute::new([0u8; 29]),
+                Metadata::new(Properties::new().read()),
+            )?
+            .build();
+        let sample_rate = service
+            .add_characteristic(
+                uuid(SAMPLE_RATE_UUID),
+                Attribute::new((PowerMode::Normal.sample_rate_hz() as u16).to_le_bytes())
+                    .deferred_write(),
+                Metadata::new(Properties::new().read().write()),
+            )?
+            .build();
+        let configuration = service
+            .add_characteristic(
+                uuid(CONFIGURATION_UUID),
+                Attribute::new([0u8; 0])
+                    .variable_len(CONFIGURATION_LEN)
+                    .deferred_write(),
+                Metadata::new(Properties::new().write()),
+            )?
+            .build();
+        service.build();
+        Ok(Service {
+            heading: heading.value_handle,
+            heading_cccd: heading.cccd_handle,
+            field: field.value_handle,
+            field_cccd: field.cccd_handle,
+            calibration: calibration.value_handle,
+            sample_rate: sample_rate.value_handle,
+            configuration: configuration.value_handle,
+            notify_heading: Cell::new(false),
+            notify_field: Cell::new(false),
+        })
+    }
+
+    /// Handles a write to one of the service's descriptors, if it is one.
+    pub fn on_write(&self, handle: u16, data: &[u8]) -> Option<Event> {
+        let notify = data.first().is_some_and(|bits| bits & 1 != 0);
+        if handle == self.heading_cccd {
+            self.notify_heading.set(notify);
+        } else if handle == self.field_cccd {
+            self.notify_field.set(notify);
+        }
+        None
+    }
+
+    /// Handles a write to the sample rate or the configuration, if it is
+    /// one, taking it if it is valid.
+    pub fn on_deferred_write(
+        &self,
+        handle: u16,
+        data: &[u8],
+        reply: DeferredWriteReply,
+    ) -> Option<Event> {
+        if handle == self.configuration {
+            rprintln!("Configured over BLE: {:?}", core::str::from_utf8(data));
+            let Some(command) = configuration(data) else {
+                reply.reply(Err(GattError::ATTERR_CPS_OUT_OF_RANGE)).ok();
+                return None;
+            };
+            reply.reply(Ok(data)).ok();
+            return Some(Event::Command(command));
+        }
+        if handle != self.sample_rate {
+            reply.reply(Err(GattError::ATTERR_WRITE_NOT_PERMITTED)).ok();
+            return None;
+        }
+        let Ok(&hz) = <&[u8; 2]>::try_from(data) else {
+            reply
+                .reply(Err(GattError::ATTERR_INVALID_ATT_VAL_LENGTH))
+                .ok();
+            return None;
+        };
+        let Some(mode) = power_mode(u16::from_le_bytes(hz) as u32) else {
+            reply.reply(Err(GattError::ATTERR_CPS_OUT_OF_RANGE)).ok();
+            return None;
+        };
+        reply.reply(Ok(data)).ok();
+        Some(Event::Command(SerialCommand::SetPowerMode(mode)))
+    }
+
+    /// Keeps the values up to date with each sample, as it comes, and
+    /// notifies the client of those it has subscribed to, until the
+    /// connection is lost.
+    pub async fn send(&self, sd: &Softdevice, conn: &Connection) {
+        loop {
+            if let Some(values) = critical_section::with(|cs| LATEST.borrow(cs).get()) {
+                let (heading, field) = (self.notify_heading.get(), self.notify_field.get());
+                update_value(sd, conn, self.heading, &values.heading, heading);
+                update_value(sd, conn, self.field, &values.field, field);
+                gatt_server::set_value(sd, self.calibration, &values.calibration).ok();
+                gatt_server::set_value(sd, self.sample_rate, &values.sample_rate).ok();
+            }
+            UPDATED.wait().await;
+        }
+    }
+
+    /// Forgets the connection's subscriptions.
+    pub fn disconnected(&self) {
+        self.notify_heading.set(false);
+        self.notify_field.set(false);
+    }
+}
+
+/// Sets `handle`'s value to `value`, and notifies the client of it if it
+/// has `subscribed`.
+fn update_value(sd: &Softdevice, conn: &Connection, handle: u16, value: &[u8], subscribed: bool) {
+    gatt_server::set_value(sd, handle, value).ok();
+    if subscribed {
+        gatt_server::notify_value(conn, handle, value).ok();
+    }
+}
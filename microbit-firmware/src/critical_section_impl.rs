@@ -0,0 +1,27 @@
+//! The critical sections `critical-section` users (RTT, the interrupt-safe
+//! statics) take, masking every interrupt as cortex-m's
+//! `critical-section-single-core` feature would. Only without `ble`: the
+//! SoftDevice's interrupts must never be masked, so with it
+//! `nrf-softdevice`'s implementation, which only disables the firmware's
+//! own interrupts, is used instead. Being a Cargo feature, cortex-m's
+//! couldn't be left out of a `ble` build.
+
+use cortex_m::interrupt;
+use cortex_m::register::primask;
+
+struct SingleCore;
+critical_section::set_impl!(SingleCore);
+
+unsafe impl critical_section::Impl for SingleCore {
+    unsafe fn acquire() -> bool {
+        let was_active = primask::read().is_active();
+        interrupt::disable();
+        was_active
+    }
+
+    unsafe fn release(was_active: bool) {
+        if was_active {
+            interrupt::enable();
+        }
+    }
+}
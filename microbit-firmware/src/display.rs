@@ -0,0 +1,249 @@
+//! Interrupt-driven LED matrix. TIMER1 multiplexes the rows in the
+//! background, so a frame stays lit until it is replaced and the sampling
+//! task only has to touch the display when the picture changes.
+//!
+//! TIMER2 enforces a minimum hold time per frame: frames submitted while the
+//! current one is still being held are parked and swapped in from the TIMER2
+//! interrupt, so display persistence does not depend on the sampling rate.
+//!
+//! The interrupt handlers themselves are RTIC tasks in `main.rs` that call
+//! [`refresh`] and [`hold_elapsed`].
+//!
+//! Frames are scaled by a global brightness level before they are lit, which
+//! can either be fixed or follow the ambient light measured by the matrix.
+//!
+//! A dark frame needs no multiplexing, so TIMER1 is stopped once the matrix
+//! has gone dark and started again by the next frame with anything lit,
+//! which keeps it from waking the CPU in sleep and battery modes.
+
+use core::cell::RefCell;
+use cortex_m::asm::delay;
+use critical_section::Mutex;
+use microbit::display::nonblocking::{Display, GreyscaleImage};
+use microbit::gpio::DisplayPins;
+use microbit::hal::Timer;
+use microbit::pac::{TIMER1, TIMER2};
+use sphere_mapping_core::config::AMBIENT_SMOOTHING;
+
+use crate::board::{self, CYCLES_PER_US};
+use crate::device::CompassDisplay;
+use crate::led::{Frame, MAX_BRIGHTNESS};
+use crate::light_sensor::{LightSensor, LIGHT_MAX};
+
+/// `show_polled` checks TIMER1 once a microsecond.
+const POLLS_PER_MS: u32 = 1_000;
+/// The board's matrix rows, which light their LEDs when high.
+const ROW_PINS: u32 = row_mask();
+
+const fn row_mask() -> u32 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < board::ROW_PINS.len() {
+        mask |= 1 << board::ROW_PINS[i];
+        i += 1;
+    }
+    mask
+}
+
+struct State {
+    display: Display<TIMER1>,
+    hold_timer: Timer<TIMER2>,
+    hold_ms: u16,
+    holding: bool,
+    brightness: u8,
+    ambient: u16,
+    current: Option<Frame>,
+    pending: Option<Frame>,
+    /// The frame on the matrix has nothing lit.
+    dark: bool,
+    /// TIMER1 is stopped.
+    paused: bool,
+}
+
+impl State {
+    fn present(&mut self, frame: Frame) {
+        self.light(frame);
+        self.current = Some(frame);
+        self.holding = self.hold_ms > 0;
+        if self.holding {
+            self.hold_timer.start(board::hold_ticks(self.hold_ms));
+        }
+    }
+
+    fn light(&mut self, frame: Frame) {
+        let mut scaled = frame;
+        for pixel in scaled.iter_mut().flatten() {
+            if *pixel > 0 {
+                *pixel = (*pixel as u16 * self.brightness as u16)
+                    .div_ceil(MAX_BRIGHTNESS as u16) as u8;
+            }
+        }
+        self.dark = scaled.iter().flatten().all(|&pixel| pixel == 0);
+        self.display.show(&GreyscaleImage::new(&scaled));
+        if !self.dark {
+            self.resume();
+        }
+    }
+
+    fn pause(&mut self) {
+        row_timer().tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.paused = true;
+    }
+
+    fn resume(&mut self) {
+        if self.paused {
+            row_timer().tasks_start.write(|w| unsafe { w.bits(1) });
+            self.paused = false;
+        }
+    }
+
+    fn set_brightness(&mut self, brightness: u8) {
+        let brightness = brightness.clamp(1, MAX_BRIGHTNESS);
+        if brightness != self.brightness {
+            self.brightness = brightness;
+            if let Some(frame) = self.current {
+                self.light(frame);
+            }
+        }
+    }
+}
+
+static STATE: Mutex<RefCell<Option<State>>> = Mutex::new(RefCell::new(None));
+
+/// Handle to the shared display state owned by the interrupt handlers.
+pub struct LedDisplay;
+
+impl LedDisplay {
+    pub fn new(timer: TIMER1, hold_timer: TIMER2, pins: DisplayPins, hold_ms: u16) -> LedDisplay {
+        let mut hold_timer = board::hold_timer(hold_timer);
+        hold_timer.enable_interrupt();
+        let state = State {
+            display: Display::new(timer, pins),
+            hold_timer,
+            hold_ms,
+            holding: false,
+            brightness: MAX_BRIGHTNESS,
+            ambient: LIGHT_MAX / 2,
+            current: None,
+            pending: None,
+            dark: false,
+            paused: false,
+        };
+        critical_section::with(|cs| STATE.borrow(cs).replace(Some(state)));
+        LedDisplay
+    }
+
+    /// Shows `frame` once the current frame's hold time has elapsed, skipping
+    /// the update entirely when it is already on the matrix.
+    pub fn show(&mut self, frame: Frame) {
+        with_state(|state| {
+            if state.current == Some(frame) {
+                state.pending = None;
+            } else if state.holding {
+                state.pending = Some(frame);
+            } else {
+                state.present(frame);
+            }
+        });
+    }
+
+    /// Sets the minimum time each frame stays on the matrix; 0 shows every
+    /// frame as soon as it is submitted.
+    pub fn set_hold_ms(&mut self, hold_ms: u16) {
+        with_state(|state| state.hold_ms = hold_ms);
+    }
+
+    /// Sets a fixed brightness from 1 to `MAX_BRIGHTNESS`.
+    pub fn set_brightness(&mut self, brightness: u8) {
+        with_state(|state| state.set_brightness(brightness));
+    }
+
+    /// Samples the ambient light through the matrix and adjusts the
+    /// brightness to match: dim in the dark, full brightness outdoors.
+    pub fn adapt_to_ambient(&mut self, sensor: &mut LightSensor) {
+        with_state(|state| {
+            let reading = sensor.measure() as u32;
+            let ambient = (state.ambient as u32 * (256 - AMBIENT_SMOOTHING)
+                + reading * AMBIENT_SMOOTHING)
+                / 256;
+            state.ambient = ambient as u16;
+            let brightness = 1 + ambient * (MAX_BRIGHTNESS as u32 - 1) / LIGHT_MAX as u32;
+            state.set_brightness(brightness as u8);
+        });
+    }
+}
+
+impl CompassDisplay for LedDisplay {
+    fn show(&mut self, frame: Frame) {
+        LedDisplay::show(self, frame);
+    }
+}
+
+fn with_state<F: FnOnce(&mut State)>(f: F) {
+    critical_section::with(|cs| {
+        if let Some(state) = STATE.borrow(cs).borrow_mut().as_mut() {
+            f(state);
+        }
+    });
+}
+
+fn row_timer() -> &'static microbit::pac::timer0::RegisterBlock {
+    unsafe { &*TIMER1::ptr() }
+}
+
+/// Lights the next row of the matrix; call from the TIMER1 interrupt.
+pub fn refresh() {
+    with_state(|state| {
+        // Only switching rows is sure to turn the last row's LEDs off.
+        let switching_rows = row_timer().events_compare[0].read().bits() != 0;
+        state.display.handle_display_event();
+        if state.dark && switching_rows {
+            state.pause();
+        }
+    });
+}
+
+/// Ends the current frame's hold time and shows any parked frame; call from
+/// the TIMER2 interrupt.
+pub fn hold_elapsed() {
+    with_state(|state| {
+        state.hold_timer.reset_event();
+        state.holding = false;
+        if let Some(frame) = state.pending.take() {
+            state.present(frame);
+        }
+    });
+}
+
+/// Turns the matrix off for good, dropping any parked frame and stopping
+/// TIMER1 with every row low, which is where the pins then stay in System
+/// OFF.
+pub fn switch_off() {
+    with_state(|state| {
+        state.current = None;
+        state.pending = None;
+        state.holding = false;
+        state.pause();
+    });
+    board::port().outclr.write(|w| unsafe { w.bits(ROW_PINS) });
+}
+
+/// Shows `frame` at full brightness for about `ms` milliseconds by polling
+/// TIMER1 instead of waiting for its interrupt. For the panic handler, which
+/// runs with interrupts disabled, possibly in the middle of another update.
+pub fn show_polled(frame: Frame, ms: u32) {
+    critical_section::with(|cs| {
+        let Ok(mut state) = STATE.borrow(cs).try_borrow_mut() else {
+            return;
+        };
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+        state.display.show(&GreyscaleImage::new(&frame));
+        state.resume();
+        for _ in 0..ms * POLLS_PER_MS {
+            state.display.handle_display_event();
+            delay(CYCLES_PER_US);
+        }
+    });
+}
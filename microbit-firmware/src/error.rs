@@ -0,0 +1,59 @@
+//! Firmware errors, one variant per failure class. Each class has its own
+//! recovery path (see `sensor.rs` for the LSM303AGR ones); none of them
+//! stops the firmware.
+
+use core::fmt;
+#[cfg(feature = "mmc5983ma")]
+use microbit::hal::twim;
+
+use crate::board::I2cError;
+use crate::storage::StorageError;
+
+pub type SensorError = lsm303agr::Error<I2cError>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Setting up the LSM303AGR failed.
+    SensorInit(SensorError),
+    /// Reading the accelerometer failed.
+    Accel(SensorError),
+    /// Reading the magnetometer failed.
+    Mag(SensorError),
+    /// Reading the MMC5983MA on the edge connector failed.
+    #[cfg(feature = "mmc5983ma")]
+    ExternalMag(twim::Error),
+    /// A peripheral was holding SDA low.
+    BusStuck,
+    /// The sensor is stopped after a failed restart.
+    SensorStopped,
+    /// The sensor stopped producing data without reporting an error.
+    SensorTimeout,
+    /// Setting the accelerometer up to wake the board on movement failed.
+    WakeSetup(I2cError),
+    /// Writing a settings or calibration record, or the sphere map, to
+    /// flash failed.
+    Storage(StorageError),
+}
+
+impl From<StorageError> for Error {
+    fn from(e: StorageError) -> Error {
+        Error::Storage(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::SensorInit(e) => write!(f, "sensor setup failed ({:?})", e),
+            Error::Accel(e) => write!(f, "accelerometer read failed ({:?})", e),
+            Error::Mag(e) => write!(f, "magnetometer read failed ({:?})", e),
+            #[cfg(feature = "mmc5983ma")]
+            Error::ExternalMag(e) => write!(f, "MMC5983MA read failed ({:?})", e),
+            Error::BusStuck => write!(f, "I2C bus stuck with SDA low"),
+            Error::SensorStopped => write!(f, "sensor not running"),
+            Error::SensorTimeout => write!(f, "sensor stopped producing data"),
+            Error::WakeSetup(e) => write!(f, "wake-on-motion setup failed ({:?})", e),
+            Error::Storage(e) => write!(f, "flash write failed ({:?})", e),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Optional displays on the edge connector that mirror the onboard matrix.
+//! Each one is compiled in by its own feature and attached at boot; without
+//! any of those features this is an empty shell.
+
+#[cfg(feature = "oled")]
+use core::fmt::Write;
+#[cfg(feature = "max7219")]
+use microbit::hal::gpio::{p0, p1, Disconnected, Level, Output, Pin, PushPull};
+#[cfg(feature = "max7219")]
+use microbit::hal::spim::{self, Spim};
+#[cfg(feature = "oled")]
+use microbit::hal::twim::Twim;
+#[cfg(feature = "max7219")]
+use microbit::pac::SPIM2;
+#[cfg(feature = "oled")]
+use microbit::pac::TWIM1;
+#[cfg(any(feature = "max7219", feature = "oled"))]
+use rtt_target::rprintln;
+#[cfg(all(any(feature = "oled", feature = "ble"), feature = "fixed-point"))]
+use sphere_mapping_core::fixed;
+#[cfg(all(any(feature = "oled", feature = "ble"), not(feature = "fixed-point")))]
+use sphere_mapping_core::math::{roundf, sqrtf};
+
+use crate::calibration::Measurement;
+use crate::events::Event;
+#[cfg(feature = "oled")]
+use crate::i2c_bus::{ExternalBus, SharedI2c};
+#[cfg(feature = "max7219")]
+use crate::led::Trail;
+use crate::led::View;
+#[cfg(all(any(feature = "oled", feature = "ble"), not(feature = "fixed-point")))]
+use crate::led::{heading_from_theta, theta_from_field};
+#[cfg(feature = "max7219")]
+use crate::max7219::Max7219;
+use crate::settings::Settings;
+#[cfg(feature = "oled")]
+use crate::ssd1306::{self, Ssd1306};
+
+#[cfg(feature = "max7219")]
+type Matrix = Max7219<Spim<SPIM2>, Pin<Output<PushPull>>>;
+
+pub struct ExternalDisplays {
+    #[cfg(feature = "max7219")]
+    matrix: Option<(Matrix, Trail<8>)>,
+    #[cfg(feature = "oled")]
+    oled: Option<Ssd1306<SharedI2c<'static, Twim<TWIM1>>>>,
+    #[cfg(feature = "oled")]
+    anomaly: bool,
+}
+
+impl ExternalDisplays {
+    pub fn new() -> ExternalDisplays {
+        ExternalDisplays {
+            #[cfg(feature = "max7219")]
+            matrix: None,
+            #[cfg(feature = "oled")]
+            oled: None,
+            #[cfg(feature = "oled")]
+            anomaly: false,
+        }
+    }
+
+    /// Attaches a MAX7219 8x8 matrix: SCK on P13, DIN on P15 and CS on P16.
+    ///
+    /// SPIM2 is the Board's unused `SPI2` instance; it is stolen here because
+    /// the Board only hands it out together with pins we don't want.
+    #[cfg(feature = "max7219")]
+    pub fn attach_matrix(
+        &mut self,
+        sck: p0::P0_17<Disconnected>,
+        mosi: p0::P0_13<Disconnected>,
+        cs: p1::P1_02<Disconnected>,
+    ) {
+        let spim2 = unsafe { microbit::pac::Peripherals::steal().SPIM2 };
+        let pins = spim::Pins {
+            sck: Some(sck.into_push_pull_output(Level::Low).degrade()),
+            mosi: Some(mosi.into_push_pull_output(Level::Low).degrade()),
+            miso: None,
+        };
+        let spi = Spim::new(spim2, pins, spim::Frequency::M1, spim::MODE_0, 0);
+        let cs = cs.into_push_pull_output(Level::High).degrade();
+        self.matrix = match Max7219::new(spi, cs) {
+            Ok(matrix) => Some((matrix, Trail::new())),
+            Err(e) => {
+                rprintln!("MAX7219 setup failed: {:?}", e);
+                None
+            }
+        };
+    }
+
+    /// Attaches an SSD1306 OLED on the edge connector's I2C bus.
+    #[cfg(feature = "oled")]
+    pub fn attach_oled(&mut self, bus: &'static ExternalBus) {
+        self.oled = match Ssd1306::new(SharedI2c::new(bus)) {
+            Ok(oled) => Some(oled),
+            Err(e) => {
+                rprintln!("OLED not found: {:?}", e);
+                None
+            }
+        };
+    }
+
+    /// Mirrors a fixed brightness from 1 to `MAX_BRIGHTNESS`.
+    pub fn set_brightness(&mut self, _brightness: u8) {
+        #[cfg(feature = "max7219")]
+        if let Some((matrix, _)) = self.matrix.as_mut() {
+            if let Err(e) = matrix.set_brightness(_brightness) {
+                rprintln!("MAX7219 stopped responding: {:?}", e);
+                self.matrix = None;
+            }
+        }
+    }
+
+    /// Follows the events the displays care about: the OLED warns of a
+    /// magnetic anomaly until it clears.
+    pub fn notify(&mut self, _event: &Event) {
+        #[cfg(feature = "oled")]
+        match _event {
+            Event::AnomalyDetected(_) => self.anomaly = true,
+            Event::AnomalyCleared => self.anomaly = false,
+            _ => {}
+        }
+    }
+
+    /// Shows the same view as the onboard matrix, plus the numeric readout
+    /// for `field` on the OLED, with the heading corrected for declination.
+    pub fn show(
+        &mut self,
+        _view: &View,
+        _settings: &Settings,
+        _field: &Measurement,
+        _calibrated: bool,
+    ) {
+        #[cfg(feature = "max7219")]
+        if let Some((matrix, trail)) = self.matrix.as_mut() {
+            if let Err(e) = matrix.show(&_settings.rotation.apply(trail.follow(_view))) {
+                rprintln!("MAX7219 stopped responding: {:?}", e);
+                self.matrix = None;
+            }
+        }
+
+        #[cfg(feature = "oled")]
+        if let Some(oled) = self.oled.as_mut() {
+            let status = if self.anomaly {
+                "Magnet nearby?"
+            } else if _calibrated {
+                "Cal stored"
+            } else {
+                "Cal default"
+            };
+            if let Err(e) = show_readout(oled, _field, _settings.declination, status) {
+                rprintln!("OLED stopped responding: {:?}", e);
+                self.oled = None;
+            }
+        }
+    }
+}
+
+/// Writes the numeric heading, field strength and a status line to the
+/// OLED. The heading is in degrees clockwise from magnetic North.
+#[cfg(feature = "oled")]
+fn show_readout<I2C: embedded_hal::i2c::I2c>(
+    oled: &mut Ssd1306<I2C>,
+    field: &Measurement,
+    declination: i16,
+    status: &str,
+) -> Result<(), I2C::Error> {
+    let (heading, field_tenths) = readout(field, declination);
+    let mut line = heapless::String::<{ ssd1306::LINE_LEN }>::new();
+    oled.write_line(0, "Sphere mapping")?;
+    write!(line, "Heading {:>3} deg", heading).ok();
+    oled.write_line(2, &line)?;
+    line.clear();
+    write!(line, "Field {}.{} uT", field_tenths / 10, field_tenths % 10).ok();
+    oled.write_line(4, &line)?;
+    oled.write_line(6, status)
+}
+
+/// The heading in whole degrees clockwise from North, turned by
+/// `declination` in tenths of a degree, and the field strength in tenths of
+/// a µT, as the OLED shows them and Bluetooth advertises them.
+#[cfg(all(any(feature = "oled", feature = "ble"), not(feature = "fixed-point")))]
+pub fn readout(field: &Measurement, declination: i16) -> (u32, u32) {
+    let theta = theta_from_field(field.x, field.y);
+    let (x, y, z) = (field.x as f32, field.y as f32, field.z as f32);
+    let field = sqrtf(x * x + y * y + z * z);
+    let heading = roundf(heading_from_theta(theta) + declination as f32 / 10.) as i32;
+    let heading = heading.rem_euclid(360) as u32;
+    (heading, roundf(field / 100.) as u32)
+}
+
+/// The heading in whole degrees clockwise from North, turned by
+/// `declination` in tenths of a degree, and the field strength in tenths of
+/// a µT, without going through a float angle.
+#[cfg(all(any(feature = "oled", feature = "ble"), feature = "fixed-point"))]
+pub fn readout(field: &Measurement, declination: i16) -> (u32, u32) {
+    let heading = fixed::true_heading(field, declination);
+    (heading, (fixed::magnitude(field) + 50) / 100)
+}
@@ -0,0 +1,86 @@
+//! Optional gyroscope on the edge connector, for host-side fusion that
+//! stays stable through fast motion. The gyro is sampled from the 100 Hz
+//! tick, and each sensor sample reports the average rate since the last
+//! one, so the host can integrate over the whole interval rather than
+//! trusting a single instantaneous reading. Without the `lsm6ds` feature
+//! this is an empty shell.
+
+#[cfg(feature = "lsm6ds")]
+use microbit::hal::twim::{self, Twim};
+#[cfg(feature = "lsm6ds")]
+use microbit::pac::TWIM1;
+#[cfg(feature = "lsm6ds")]
+use rtt_target::rprintln;
+
+#[cfg(feature = "lsm6ds")]
+use crate::i2c_bus::{ExternalBus, SharedI2c};
+#[cfg(feature = "lsm6ds")]
+use crate::lsm6ds::{Lsm6ds, SetupError, MDPS_PER_COUNT};
+
+#[cfg(feature = "lsm6ds")]
+type Imu = Lsm6ds<SharedI2c<'static, Twim<TWIM1>>>;
+
+pub struct Gyro {
+    #[cfg(feature = "lsm6ds")]
+    imu: Option<Imu>,
+    #[cfg(feature = "lsm6ds")]
+    sum: [i32; 3],
+    #[cfg(feature = "lsm6ds")]
+    count: i32,
+}
+
+impl Gyro {
+    pub fn new() -> Gyro {
+        Gyro {
+            #[cfg(feature = "lsm6ds")]
+            imu: None,
+            #[cfg(feature = "lsm6ds")]
+            sum: [0; 3],
+            #[cfg(feature = "lsm6ds")]
+            count: 0,
+        }
+    }
+
+    /// Attaches an LSM6DS-family IMU on the edge connector's I2C bus.
+    #[cfg(feature = "lsm6ds")]
+    pub fn attach(&mut self, bus: &'static ExternalBus) -> Result<(), SetupError<twim::Error>> {
+        self.imu = Some(Lsm6ds::new(SharedI2c::new(bus))?);
+        Ok(())
+    }
+
+    /// Adds the latest reading, if there is a new one, to the running
+    /// average. Call at least as often as the gyro's 104 Hz output rate
+    /// allows; readings in between are skipped, not queued.
+    pub fn poll(&mut self) {
+        #[cfg(feature = "lsm6ds")]
+        if let Some(imu) = self.imu.as_mut() {
+            match imu.read() {
+                Ok(Some(rate)) => {
+                    for (sum, rate) in self.sum.iter_mut().zip(rate) {
+                        *sum += rate as i32;
+                    }
+                    self.count += 1;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    rprintln!("Gyro stopped responding: {:?}", e);
+                    self.imu = None;
+                }
+            }
+        }
+    }
+
+    /// Returns the average rate in degrees per second around the gyro's X,
+    /// Y and Z axes since the last call, and starts a new average.
+    pub fn take_average(&mut self) -> Option<[f32; 3]> {
+        #[cfg(feature = "lsm6ds")]
+        if self.count > 0 {
+            let scale = MDPS_PER_COUNT / 1000. / self.count as f32;
+            let average = self.sum.map(|sum| sum as f32 * scale);
+            self.sum = [0; 3];
+            self.count = 0;
+            return Some(average);
+        }
+        None
+    }
+}
@@ -0,0 +1,52 @@
+//! Sharing one I2C bus between several drivers. Each `SharedI2c` only
+//! borrows the bus inside a critical section for the duration of a
+//! transaction, so drivers can own their handles independently, even from
+//! different tasks.
+
+use core::cell::RefCell;
+use critical_section::Mutex;
+use embedded_hal::i2c::{ErrorType, I2c, Operation};
+use microbit::board::I2CExternalPins;
+use microbit::hal::twim::{self, Twim};
+use microbit::pac::twim0::frequency::FREQUENCY_A;
+use microbit::pac::TWIM1;
+
+pub type ExternalBus = Mutex<RefCell<Twim<TWIM1>>>;
+
+/// Sets up the edge connector's I2C pins (SCL on P19, SDA on P20) as a bus
+/// for the external devices to share. TWIM1 isn't part of the Board, so it
+/// is stolen.
+pub fn external(pins: I2CExternalPins) -> &'static ExternalBus {
+    let twim1 = unsafe { microbit::pac::Peripherals::steal().TWIM1 };
+    let twim = Twim::new(twim1, twim::Pins::from(pins), FREQUENCY_A::K400);
+    cortex_m::singleton!(: ExternalBus = Mutex::new(RefCell::new(twim))).unwrap()
+}
+
+pub struct SharedI2c<'a, I2C> {
+    bus: &'a Mutex<RefCell<I2C>>,
+}
+
+impl<'a, I2C> SharedI2c<'a, I2C> {
+    pub fn new(bus: &'a Mutex<RefCell<I2C>>) -> SharedI2c<'a, I2C> {
+        SharedI2c { bus }
+    }
+}
+
+impl<I2C: ErrorType> ErrorType for SharedI2c<'_, I2C> {
+    type Error = I2C::Error;
+}
+
+impl<I2C: I2c> I2c for SharedI2c<'_, I2C> {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        critical_section::with(|cs| {
+            self.bus
+                .borrow(cs)
+                .borrow_mut()
+                .transaction(address, operations)
+        })
+    }
+}
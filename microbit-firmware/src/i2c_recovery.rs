@@ -0,0 +1,61 @@
+//! Recovery for an I2C bus that a peripheral has wedged. If a transfer is cut
+//! short (by a reset, or by noise on SCL) the peripheral can be left in the
+//! middle of sending a byte, holding SDA low and waiting for clocks the
+//! TWIM (or the v1's TWI) will never send. Clocking SCL by hand until SDA
+//! is released and then sending a STOP puts every peripheral on the bus
+//! back into idle.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+use microbit::hal::gpio::{Level, OpenDrainConfig};
+
+use crate::board::{self, I2c, I2cPins};
+
+/// A byte plus the ACK bit: the most clocks a peripheral can be waiting for.
+const CLEAR_CLOCKS: u32 = 9;
+/// Half an SCL period at 100 kHz.
+const HALF_PERIOD_US: u32 = 5;
+
+/// Whether the line on GPIO pin `sda` is low while the bus should be idle.
+/// Reading the input register leaves the TWIM's hold on the pin untouched.
+pub fn sda_held_low(sda: usize) -> bool {
+    board::port().in_.read().bits() & (1 << sda) == 0
+}
+
+/// Checks whether SDA is stuck low and, if so, clears the bus with the
+/// GPIOs before handing it back to a freshly set up peripheral. Returns the
+/// new bus and whether SDA was stuck.
+pub fn clear_bus<D: DelayNs>(i2c: I2c, delay: &mut D) -> (I2c, bool) {
+    let (twim, pins) = board::release_i2c(i2c);
+
+    let mut sda = pins
+        .sda
+        .into_open_drain_input_output(OpenDrainConfig::Standard0Disconnect1, Level::High);
+    let mut scl = pins
+        .scl
+        .into_open_drain_input_output(OpenDrainConfig::Standard0Disconnect1, Level::High);
+    delay.delay_us(HALF_PERIOD_US);
+    let stuck = sda.is_low().unwrap_or(false);
+    if stuck {
+        for _ in 0..CLEAR_CLOCKS {
+            if sda.is_high().unwrap_or(false) {
+                break;
+            }
+            scl.set_low().ok();
+            delay.delay_us(HALF_PERIOD_US);
+            scl.set_high().ok();
+            delay.delay_us(HALF_PERIOD_US);
+        }
+        // STOP: SDA rises while SCL is high.
+        sda.set_low().ok();
+        delay.delay_us(HALF_PERIOD_US);
+        sda.set_high().ok();
+        delay.delay_us(HALF_PERIOD_US);
+    }
+
+    let pins = I2cPins {
+        scl: scl.into_floating_input(),
+        sda: sda.into_floating_input(),
+    };
+    (board::i2c(twim, pins), stuck)
+}
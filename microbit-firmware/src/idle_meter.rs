@@ -0,0 +1,48 @@
+//! Measures how much of the time the CPU spends awake, against asleep in
+//! `idle`. The clock keeps counting while the CPU is stopped, unlike the
+//! cycle counter, so the time spent in each WFI can be read off it directly.
+
+use crate::clock;
+
+pub struct IdleMeter {
+    window_start: u64,
+    slept_us: u64,
+}
+
+impl IdleMeter {
+    pub fn new() -> IdleMeter {
+        IdleMeter {
+            window_start: clock::now(),
+            slept_us: 0,
+        }
+    }
+
+    /// Sleeps until the next interrupt. Interrupts are masked around the
+    /// WFI so the wake-up time is taken before the handler that woke the CPU
+    /// runs, and its work isn't counted as idle. This masks them with
+    /// PRIMASK rather than taking a critical section: with `ble` those only
+    /// disable the firmware's interrupts in the NVIC, and a disabled
+    /// interrupt can't wake the WFI.
+    pub fn sleep(&mut self) {
+        cortex_m::interrupt::free(|_| {
+            let start = clock::now();
+            cortex_m::asm::wfi();
+            self.slept_us += clock::now() - start;
+        });
+    }
+
+    /// Returns the share of the last window spent awake, in tenths of a
+    /// percent, once `window_us` has passed, and starts the next window.
+    pub fn take_load(&mut self, window_us: u64) -> Option<u16> {
+        let now = clock::now();
+        let elapsed_us = now - self.window_start;
+        if elapsed_us < window_us {
+            return None;
+        }
+        let awake_us = elapsed_us.saturating_sub(self.slept_us);
+        let load = (awake_us * 1000 / elapsed_us) as u16;
+        self.window_start = now;
+        self.slept_us = 0;
+        Some(load)
+    }
+}
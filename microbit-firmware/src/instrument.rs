@@ -0,0 +1,177 @@
+//! Optional instrumentation, for measuring before optimizing and after
+//! adding features: the stack's high-water mark, and how long each phase of
+//! handling a sample takes. Every 10 s `sample` reports both as a
+//! `Timing: ...` line. Without the `instrument` feature this is an empty
+//! shell.
+//!
+//! The stack is painted with a known pattern at boot, and the high-water
+//! mark is the deepest word that no longer holds it. Phases are timed on
+//! the microsecond clock.
+
+use core::fmt;
+#[cfg(feature = "instrument")]
+use sphere_mapping_core::config::SAMPLE_RATE_HZ;
+
+#[cfg(feature = "instrument")]
+use crate::clock;
+
+/// What `sample` spends its time on, in the order it happens.
+#[derive(Clone, Copy)]
+pub enum Phase {
+    /// From the end of one sample to the data-ready interrupt of the next,
+    /// including time spent in other tasks and asleep.
+    Wait,
+    /// Reading the sensors over I2C, including reads that find only one
+    /// sensor ready.
+    Read,
+    /// Calibrating, smoothing and rendering.
+    Math,
+    /// Formatting the sample into the serial queue; sending it happens
+    /// later, in `transmit`.
+    Serial,
+    /// Updating the matrix and the external displays.
+    Display,
+}
+
+#[cfg(feature = "instrument")]
+const PHASES: usize = 5;
+#[cfg(feature = "instrument")]
+const PHASE_NAMES: [&str; PHASES] = ["wait", "read", "math", "serial", "display"];
+/// Samples between reports (about 10 s).
+#[cfg(feature = "instrument")]
+const REPORT_SAMPLES: u32 = 10 * SAMPLE_RATE_HZ;
+
+/// Pattern the unused stack is filled with.
+#[cfg(feature = "instrument")]
+const PAINT: u32 = 0xCCCC_CCCC;
+/// Bytes just below the stack pointer that `paint_stack` leaves alone, for
+/// its own calls.
+#[cfg(feature = "instrument")]
+const PAINT_MARGIN: usize = 64;
+
+#[cfg(feature = "instrument")]
+extern "C" {
+    /// Top of the stack, from cortex-m-rt's linker script.
+    static _stack_start: u32;
+    /// Lowest address the stack can grow down to.
+    static _stack_end: u32;
+}
+
+/// Fills the stack below the current stack pointer with `PAINT`. Call first
+/// thing in `init`, while interrupts are still disabled.
+pub fn paint_stack() {
+    #[cfg(feature = "instrument")]
+    unsafe {
+        let bottom = core::ptr::addr_of!(_stack_end) as *mut u32;
+        let top = (cortex_m::register::msp::read() as usize - PAINT_MARGIN) as *mut u32;
+        let mut word = bottom;
+        while word < top {
+            word.write_volatile(PAINT);
+            word = word.add(1);
+        }
+    }
+}
+
+/// Bytes of stack used at the deepest point since `paint_stack`, and the
+/// stack's size.
+#[cfg(feature = "instrument")]
+fn stack_usage() -> (usize, usize) {
+    unsafe {
+        let bottom = core::ptr::addr_of!(_stack_end);
+        let top = core::ptr::addr_of!(_stack_start);
+        let mut word = bottom;
+        while word < top && word.read_volatile() == PAINT {
+            word = word.add(1);
+        }
+        (top as usize - word as usize, top as usize - bottom as usize)
+    }
+}
+
+/// Per-phase timing over the current report window.
+pub struct Instrument {
+    #[cfg(feature = "instrument")]
+    last_us: Option<u64>,
+    #[cfg(feature = "instrument")]
+    total_us: [u32; PHASES],
+    #[cfg(feature = "instrument")]
+    max_us: [u32; PHASES],
+    /// Time in each phase so far for the sample in progress.
+    #[cfg(feature = "instrument")]
+    sample_us: [u32; PHASES],
+    #[cfg(feature = "instrument")]
+    samples: u32,
+}
+
+impl Instrument {
+    pub const fn new() -> Instrument {
+        Instrument {
+            #[cfg(feature = "instrument")]
+            last_us: None,
+            #[cfg(feature = "instrument")]
+            total_us: [0; PHASES],
+            #[cfg(feature = "instrument")]
+            max_us: [0; PHASES],
+            #[cfg(feature = "instrument")]
+            sample_us: [0; PHASES],
+            #[cfg(feature = "instrument")]
+            samples: 0,
+        }
+    }
+
+    /// Charges the time since the previous lap to `phase`.
+    pub fn lap(&mut self, _phase: Phase) {
+        #[cfg(feature = "instrument")]
+        {
+            let now = clock::now();
+            // The first lap after boot has nothing to measure from.
+            if let Some(last) = self.last_us {
+                self.sample_us[_phase as usize] += (now - last) as u32;
+            }
+            self.last_us = Some(now);
+        }
+    }
+
+    /// Closes the books on a sample that went all the way to the displays.
+    /// Once a window's worth of samples has been seen, writes the report
+    /// line to `out` and returns true.
+    pub fn end_sample(&mut self, _out: &mut dyn fmt::Write) -> bool {
+        #[cfg(feature = "instrument")]
+        {
+            for phase in 0..PHASES {
+                self.total_us[phase] += self.sample_us[phase];
+                self.max_us[phase] = self.max_us[phase].max(self.sample_us[phase]);
+            }
+            self.sample_us = [0; PHASES];
+            self.samples += 1;
+            if self.samples >= REPORT_SAMPLES {
+                self.write_report(_out).ok();
+                self.total_us = [0; PHASES];
+                self.max_us = [0; PHASES];
+                self.samples = 0;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// `Timing: wait 98000/99500, read ..., display 300/650 us, stack
+    /// 2048/120000 bytes`, each phase as average/maximum per sample.
+    #[cfg(feature = "instrument")]
+    fn write_report(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "Timing:")?;
+        let phases = PHASE_NAMES.iter().zip(self.total_us).zip(self.max_us);
+        for (phase, ((name, total), max)) in phases.enumerate() {
+            let separator = if phase == 0 { "" } else { "," };
+            write!(
+                out,
+                "{} {} {}/{}",
+                separator,
+                name,
+                total / self.samples,
+                max
+            )?;
+        }
+        let (used, size) = stack_usage();
+        write!(out, " us, stack {}/{} bytes\r\n", used, size)
+    }
+}
@@ -0,0 +1,135 @@
+//! Ambient light sensing with the LED matrix itself. A reverse-biased LED
+//! behaves like a tiny photodiode: after charging its junction capacitance,
+//! the voltage left on a floating column after a fixed interval drops faster
+//! the brighter the surroundings are.
+//!
+//! The v2 reads the columns with its SAADC and the v1 with its older ADC,
+//! both scaled so that full scale is the supply.
+
+#[cfg(feature = "v2")]
+use core::sync::atomic::{compiler_fence, Ordering};
+use cortex_m::asm::delay;
+
+use crate::board::{self, Adc, CYCLES_PER_US, ROW_PINS, SENSE_COLUMNS};
+
+const CHARGE_CYCLES: u32 = CYCLES_PER_US * 100;
+const DISCHARGE_CYCLES: u32 = CYCLES_PER_US * 2_000;
+const ADC_MAX: u32 = 1023;
+
+pub const LIGHT_MAX: u16 = 255;
+
+pub struct LightSensor {
+    adc: Adc,
+}
+
+impl LightSensor {
+    #[cfg(feature = "v2")]
+    pub fn new(adc: Adc) -> LightSensor {
+        adc.resolution.write(|w| w.val()._10bit());
+        adc.samplerate.write(|w| w.mode().task());
+        adc.ch[0].config.write(|w| {
+            w.refsel().vdd1_4();
+            w.gain().gain1_4();
+            w.tacq()._3us();
+            w.mode().se();
+            w.resp().bypass();
+            w.resn().bypass();
+            w
+        });
+        adc.ch[0].pseln.write(|w| w.pseln().nc());
+        LightSensor { adc }
+    }
+
+    #[cfg(feature = "v1")]
+    pub fn new(adc: Adc) -> LightSensor {
+        adc.config.write(|w| {
+            w.res()._10bit();
+            w.inpsel().analog_input_one_third_prescaling();
+            w.refsel().supply_one_third_prescaling();
+            w
+        });
+        LightSensor { adc }
+    }
+
+    /// Returns the ambient light level from 0 (dark) to `LIGHT_MAX`.
+    ///
+    /// This takes over the matrix pins for a couple of milliseconds, so it
+    /// must run with the display interrupt masked; the next display refresh
+    /// drives the rows and columns again.
+    pub fn measure(&mut self) -> u16 {
+        let p0 = board::port();
+
+        // Reverse-bias the LEDs: rows (anodes) low, columns (cathodes) high.
+        for row in ROW_PINS {
+            p0.outclr.write(|w| unsafe { w.bits(1 << row) });
+        }
+        for (col, _) in SENSE_COLUMNS {
+            p0.outset.write(|w| unsafe { w.bits(1 << col) });
+        }
+        delay(CHARGE_CYCLES);
+
+        // Float the sensing columns and let the photocurrent discharge them.
+        for (col, _) in SENSE_COLUMNS {
+            p0.pin_cnf[col].write(|w| w.dir().input().input().disconnect());
+        }
+        delay(DISCHARGE_CYCLES);
+
+        self.adc.enable.write(|w| w.enable().enabled());
+        let remaining: u32 = SENSE_COLUMNS
+            .iter()
+            .map(|(_, ain)| self.sample(*ain))
+            .sum();
+        self.adc.enable.write(|w| w.enable().disabled());
+
+        for (col, _) in SENSE_COLUMNS {
+            p0.pin_cnf[col].write(|w| {
+                w.dir().output();
+                w.input().disconnect();
+                w.pull().disabled();
+                w.drive().s0s1();
+                w
+            });
+        }
+
+        let full_scale = SENSE_COLUMNS.len() as u32 * ADC_MAX;
+        ((full_scale - remaining.min(full_scale)) * LIGHT_MAX as u32 / full_scale) as u16
+    }
+
+    #[cfg(feature = "v2")]
+    fn sample(&mut self, ain: u8) -> u32 {
+        self.adc.ch[0].pselp.write(|w| match ain {
+            4 => w.pselp().analog_input4(),
+            6 => w.pselp().analog_input6(),
+            _ => w.pselp().analog_input7(),
+        });
+
+        let mut value: i16 = 0;
+        self.adc
+            .result
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(&mut value as *mut i16 as u32) });
+        self.adc.result.maxcnt.write(|w| unsafe { w.maxcnt().bits(1) });
+        compiler_fence(Ordering::SeqCst);
+
+        self.adc.tasks_start.write(|w| unsafe { w.bits(1) });
+        self.adc.tasks_sample.write(|w| unsafe { w.bits(1) });
+        while self.adc.events_end.read().bits() == 0 {}
+        self.adc.events_end.reset();
+        compiler_fence(Ordering::SeqCst);
+
+        value.max(0) as u32
+    }
+
+    #[cfg(feature = "v1")]
+    fn sample(&mut self, ain: u8) -> u32 {
+        self.adc.config.modify(|_, w| match ain {
+            5 => w.psel().analog_input5(),
+            6 => w.psel().analog_input6(),
+            _ => w.psel().analog_input7(),
+        });
+        self.adc.tasks_start.write(|w| unsafe { w.bits(1) });
+        while self.adc.events_end.read().bits() == 0 {}
+        self.adc.events_end.reset();
+        self.adc.result.read().result().bits() as u32
+    }
+}
@@ -0,0 +1,83 @@
+//! Gyroscope half of an ST LSM6DS3, LSM6DS3TR-C or LSM6DSOX IMU on I2C.
+//! The accelerometer is left off; the LSM303AGR already provides one. The
+//! gyro runs at 104 Hz over ±500 dps.
+
+use embedded_hal::i2c::I2c;
+
+/// SDO/SA0 low or high.
+const ADDRESSES: [u8; 2] = [0x6a, 0x6b];
+/// LSM6DS3, LSM6DS3TR-C and LSM6DSOX.
+const WHO_AM_I_VALUES: [u8; 3] = [0x69, 0x6a, 0x6c];
+
+const REG_WHO_AM_I: u8 = 0x0f;
+const REG_CTRL2_G: u8 = 0x11;
+const REG_CTRL3_C: u8 = 0x12;
+const REG_STATUS: u8 = 0x1e;
+const REG_OUTX_L_G: u8 = 0x22;
+
+const ODR_104HZ: u8 = 0b0100 << 4;
+const FS_500DPS: u8 = 0b01 << 2;
+/// Block data update, so the low and high bytes of a reading always match.
+const BDU: u8 = 1 << 6;
+const IF_INC: u8 = 1 << 2;
+const GDA: u8 = 1 << 1;
+
+/// Sensitivity at ±500 dps: 17.5 mdps per count.
+pub const MDPS_PER_COUNT: f32 = 17.5;
+
+#[derive(Debug)]
+pub enum SetupError<E> {
+    Bus(E),
+    /// Nothing at either address identified as a supported IMU.
+    NotFound,
+}
+
+pub struct Lsm6ds<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C: I2c> Lsm6ds<I2C> {
+    pub fn new(mut i2c: I2C) -> Result<Lsm6ds<I2C>, SetupError<I2C::Error>> {
+        let mut found = None;
+        for address in ADDRESSES {
+            let mut id = [0];
+            if i2c.write_read(address, &[REG_WHO_AM_I], &mut id).is_ok()
+                && WHO_AM_I_VALUES.contains(&id[0])
+            {
+                found = Some(address);
+                break;
+            }
+        }
+        let address = found.ok_or(SetupError::NotFound)?;
+        let mut imu = Lsm6ds { i2c, address };
+        imu.write_register(REG_CTRL3_C, BDU | IF_INC)
+            .map_err(SetupError::Bus)?;
+        imu.write_register(REG_CTRL2_G, ODR_104HZ | FS_500DPS)
+            .map_err(SetupError::Bus)?;
+        Ok(imu)
+    }
+
+    /// Returns the angular rate in counts (see `MDPS_PER_COUNT`) around the
+    /// IMU's X, Y and Z axes, if a new sample is ready.
+    pub fn read(&mut self) -> Result<Option<[i16; 3]>, I2C::Error> {
+        let mut status = [0];
+        self.i2c
+            .write_read(self.address, &[REG_STATUS], &mut status)?;
+        if status[0] & GDA == 0 {
+            return Ok(None);
+        }
+        let mut out = [0u8; 6];
+        self.i2c
+            .write_read(self.address, &[REG_OUTX_L_G], &mut out)?;
+        Ok(Some([
+            i16::from_le_bytes([out[0], out[1]]),
+            i16::from_le_bytes([out[2], out[3]]),
+            i16::from_le_bytes([out[4], out[5]]),
+        ]))
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), I2C::Error> {
+        self.i2c.write(self.address, &[register, value])
+    }
+}
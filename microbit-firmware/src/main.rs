@@ -1,156 +1,1677 @@
 #![no_main]
 #![no_std]
 
+#[cfg(feature = "ble")]
+mod ble;
+mod board;
 mod calibration;
-mod led;
+mod clock;
+#[cfg(feature = "ble")]
+mod compass_service;
+#[cfg(not(feature = "ble"))]
+mod critical_section_impl;
+mod display;
+mod error;
+mod external;
+mod gyro;
+#[cfg(any(feature = "oled", feature = "mmc5983ma", feature = "lsm6ds"))]
+mod i2c_bus;
+mod i2c_recovery;
+mod idle_meter;
+mod instrument;
+mod light_sensor;
+#[cfg(feature = "lsm6ds")]
+mod lsm6ds;
+#[cfg(feature = "max7219")]
+mod max7219;
+#[cfg(feature = "mmc5983ma")]
+mod mmc5983ma;
+#[cfg(feature = "ble")]
+mod nus;
+#[cfg(feature = "v1")]
+mod nvmc;
+mod panic;
+mod sensor;
 mod serial_setup;
+#[cfg(feature = "ble")]
+mod softdevice;
+#[cfg(feature = "oled")]
+mod ssd1306;
+mod supply;
+mod system_off;
+mod ticker;
+#[cfg(feature = "v1")]
+mod uart;
+mod watchdog;
 
-use core::fmt::Write;
-use cortex_m_rt::entry;
-use embedded_hal_nb::serial::Read;
-use heapless::Vec;
-use libm::atan2f;
-use lsm303agr::{AccelMode, AccelOutputDataRate, Lsm303agr};
-use lsm303agr::{MagMode, MagOutputDataRate};
-use microbit::display::blocking::Display;
-use microbit::hal::twim;
-use microbit::hal::uarte::{self, Baudrate, Parity};
-use microbit::hal::Timer;
-use microbit::pac::twim0::frequency::FREQUENCY_A;
-use panic_rtt_target as _;
-use rtt_target::{rprintln, rtt_init_print};
-
-use serial_setup::UartePort;
-
-use crate::calibration::{calc_calibration, calibrated_measurement, Calibration, Measurement};
-use crate::led::{dir_from_theta, direction_to_led};
-
-const CALIBRATION: Calibration = Calibration {
-    center: Measurement {
-        x: 20962,
-        y: 34322,
-        z: -23924,
-    },
-    scale: Measurement {
-        x: 1203,
-        y: 1177,
-        z: 1133,
-    },
-    radius: 48098,
+use core::f32::consts::PI;
+use embedded_hal::delay::DelayNs;
+use sphere_mapping_core::{
+    benchmark, boot_record, capture, command, compass, config, device, events, frame, led, mode,
+    motion, panic_log, reset, settings, sphere_map, status, storage, survey,
 };
 
-enum SerialCommand {
-    ManualCal,
-    Unknown,
-}
+use crate::calibration::{Calibration, PRECOMPUTED_CALIBRATION};
+use crate::display::LedDisplay;
+use crate::led::{render_arrow, render_digit};
+use crate::settings::Settings;
+
+/// Period of the RTC1 tick that polls the buttons, in microseconds.
+const TICK_US: u32 = 10_000;
+/// The tick in battery mode, which still debounces the buttons well enough.
+const BATTERY_TICK_US: u32 = 100_000;
+
+const US_PER_MINUTE: u64 = 60_000_000;
+
+/// Time without a sample before `sample` is run anyway, in microseconds, so
+/// a sensor that has stopped raising its data-ready line still gets
+/// recovered.
+const SAMPLE_TIMEOUT_US: u64 = 1_000_000;
+/// The same in battery mode, where samples come once a second.
+const BATTERY_SAMPLE_TIMEOUT_US: u64 = 5_000_000;
+
+/// How often a status frame is sent, in microseconds.
+const STATUS_INTERVAL_US: u64 = 1_000_000;
+const BATTERY_STATUS_INTERVAL_US: u64 = 60_000_000;
+
+/// How often the total uptime is saved, in seconds. Each save erases a
+/// flash page, good for 10,000 erases, which lasts about 7 years at this
+/// rate.
+const UPTIME_SAVE_INTERVAL_S: u32 = 6 * 60 * 60;
+
+/// Room left in the output queue for the live output while the sphere map
+/// is exported, enough for a few samples' lines.
+const EXPORT_HEADROOM: usize = 256;
+
+/// Events the bus holds for outputs that haven't caught up yet.
+const EVENT_QUEUE_LEN: usize = 8;
+
+const SPLASH_STEPS: u32 = 16;
+const SPLASH_STEP_MS: u32 = 40;
+const SPLASH_VERSION_MS: u32 = 600;
+
+/// The firmware is split into RTIC tasks so that a slow job never stalls
+/// the others:
+///
+/// - `refresh_display` and `hold_elapsed` (TIMER1/TIMER2) keep the matrix
+///   multiplexed and enforce the frame hold time. They and `clock_wrapped`
+///   run at priority 3, since the SoftDevice keeps the level priority 4
+///   maps to for itself, and the v1 has no priority 5.
+/// - `clock_wrapped` (TIMER4) extends the microsecond clock to 64 bits.
+/// - `receive` (UARTE0) collects command bytes as they arrive.
+/// - `sample` (GPIOTE) reads the LSM303AGR when its data-ready line fires,
+///   and updates the displays whenever both sensors have a new sample.
+/// - `tick` (RTC1) watches the buttons, nudges `sample` if the sensor
+///   has gone quiet, and has `calibrate` draw the calibration game's next
+///   frame when it is due.
+/// - `splash`, `mode_event`, `command`, `calibrate`, `dispatch`,
+///   `transmit`, `power_off`, `configure_capture`, `end_capture`,
+///   `dump_capture`, `run_benchmark`, `map_command`, `export_map` and
+///   `move_survey` are software tasks for the longer jobs: the boot
+///   animation, switching modes, applying serial commands, the calibration
+///   game, handing published events to the outputs, draining queued serial
+///   output, powering down when the board is left alone, arming, recording
+///   and dumping burst captures, starting benchmark runs, answering sphere
+///   map commands, exporting the map and moving the survey's position.
+/// - `bluetooth`, with the `ble` feature, starts the SoftDevice, advertises
+///   the readout, and serves the Nordic UART Service, carrying the serial
+///   link, and the compass's readings as GATT characteristics.
+/// - `idle` feeds the watchdog and sleeps whenever nothing else needs the
+///   CPU, sending a status frame with how much of the time it was awake.
+///
+/// The interrupts are named as on the v2; on the v1, `board::pac` maps them
+/// onto the nRF51822's (UART0 for UARTE0, TIMER0 for TIMER4).
+#[rtic::app(device = crate::board::pac, peripherals = true, dispatchers = [SWI0_EGU0])]
+mod app {
+    use core::fmt::Write;
+    use embedded_hal::digital::InputPin;
+    use embedded_hal_nb::serial::Read;
+    use microbit::gpio::{BTN_A, BTN_B};
+    use microbit::hal::gpio::{Floating, Input, Pin};
+    use microbit::hal::gpiote::Gpiote;
+    use microbit::hal::ppi::{self, ConfigurablePpi, Ppi};
+    use microbit::hal::wdt::{handles::Hdl0, WatchdogHandle};
+    use microbit::Board;
+    use rtt_target::{rprintln, rtt_init_print};
+
+    use super::*;
+    use crate::benchmark::{Benchmark, Stage, StageTimer};
+    use crate::boot_record::BootRecord;
+    use crate::calibration::{Game, Step};
+    use crate::capture::{Burst, Capture, Recorded, MAX_DUMP_LINE_LEN};
+    use crate::command::{parse_command, LineReader, MapCommand, SerialCommand};
+    use crate::compass::Compass;
+    use crate::display;
+    use crate::error::Error;
+    use crate::events::{Event, EventBus, FieldWatch, Subscriber};
+    use crate::external::ExternalDisplays;
+    use crate::frame::{Frame, CELL_FRAME_LEN};
+    use crate::gyro::Gyro;
+    use crate::idle_meter::IdleMeter;
+    use crate::instrument::{self, Instrument, Phase};
+    use crate::led::Celebration;
+    use crate::light_sensor::LightSensor;
+    use crate::mode::{AppMode, Buttons, ModeAction, ModeEvent};
+    use crate::motion::StillTimer;
+    use crate::panic_log::PanicLog;
+    use crate::reset::ResetReason;
+    use crate::sensor::{Failure, Reading, Recovery, Sensor};
+    use crate::serial_setup::TxQueue;
+    use crate::settings::{OutputFormat, PowerMode, AUTO_BRIGHTNESS};
+    use crate::sphere_map::{SphereMap, MAP_RAM_BYTES};
+    use crate::status::Status;
+    use crate::storage::{self, Storage, CHUNK_STORAGE_LEN, STORAGE_LEN};
+    use crate::survey::{Position, Survey};
+    use crate::ticker::Ticker;
+    use crate::{board, supply, system_off, watchdog};
+
+    #[cfg(not(feature = "ble"))]
+    type FlashStorage = Storage<board::Flash>;
+    #[cfg(feature = "ble")]
+    type FlashStorage = Storage<softdevice::Flash>;
+    type Delay = board::Delay;
+
+    #[shared]
+    struct Shared {
+        sensor: Sensor,
+        display: LedDisplay,
+        external: ExternalDisplays,
+        gyro: Gyro,
+        delay: Delay,
+        storage: FlashStorage,
+        /// As counted at boot, before this boot's uptime.
+        boot_record: BootRecord,
+        settings: Settings,
+        calibration: Calibration,
+        calibrated: bool,
+        compass: Compass,
+        sphere_map: SphereMap,
+        survey: Survey,
+        app_mode: AppMode,
+        events: EventBus<EVENT_QUEUE_LEN>,
+        /// Full samples handled since the last status frame.
+        sample_count: u32,
+        /// Largest gap between the two halves of a sample since the last
+        /// status frame, in microseconds.
+        max_skew_us: u32,
+        #[lock_free]
+        last_sample_us: u64,
+        serial: board::Serial,
+        tx_queue: TxQueue,
+        /// Lives in `init`'s static, being too large for the stack.
+        capture: &'static mut Capture,
+        benchmark: Benchmark,
+        watchdog: WatchdogHandle<Hdl0>,
+        /// The calibration game, while one is under way.
+        game: Option<Game>,
+    }
 
-#[entry]
-fn main() -> ! {
-    rtt_init_print!();
-    let board = microbit::Board::take().unwrap();
-
-    // Initialize serial uart.
-    let mut serial = {
-        // Set up UARTE for microbit v2 using UartePort wrapper
-        let serial = uarte::Uarte::new(
-            board.UARTE0,
-            board.uart.into(),
-            Parity::EXCLUDED,
-            Baudrate::BAUD115200,
+    #[local]
+    struct Local {
+        drdy: Gpiote,
+        drdy_pin: Pin<Input<Floating>>,
+        ticker: Ticker,
+        light_sensor: LightSensor,
+        button_a: BTN_A,
+        button_b: BTN_B,
+        rx_buffer: LineReader<64>,
+        idle_meter: IdleMeter,
+        reset_reason: ResetReason,
+        serial_events: Subscriber,
+        display_events: Subscriber,
+    }
+
+    #[init(local = [capture: Capture = Capture::new()])]
+    fn init(cx: init::Context) -> (Shared, Local) {
+        instrument::paint_stack();
+        rtt_init_print!();
+        let board = board::Parts::new(Board::new(cx.device, cx.core));
+        let reset_reason = watchdog::take_reset_reason(&board.power);
+        let watchdog = watchdog::start(board.wdt);
+        rprintln!("Reset: {}", reset_reason);
+
+        let mut serial = board.serial;
+        serial.listen();
+
+        let mut delay = board.delay;
+        let ticker = Ticker::new(board.tick_rtc, TICK_US);
+        clock::start(board.clock_timer);
+        let idle_meter = IdleMeter::new();
+
+        // Restore persisted settings.
+        // The chunks and the records after them.
+        let chunk_start = storage::chunk_storage_start(board::FLASH_LEN);
+        let flash = board::flash(board.nvmc, chunk_start, CHUNK_STORAGE_LEN + STORAGE_LEN);
+        #[cfg(feature = "ble")]
+        let flash = softdevice::Flash::new(flash, chunk_start);
+        let mut storage =
+            Storage::new(flash, CHUNK_STORAGE_LEN as u32).with_supply_check(supply::ok);
+        let settings = Settings::load(&mut storage);
+        let boot_record = BootRecord::count_boot(&mut storage);
+        let boot_saved = boot_record.save(&mut storage);
+        let panic_log = PanicLog::take(&mut storage);
+
+        // Initialize LED display
+        let mut display = LedDisplay::new(
+            board.display_timer,
+            board.hold_timer,
+            board.display_pins,
+            settings.display_hold_ms,
         );
-        UartePort::new(serial)
-    };
-
-    // Iniitalize I2C peripheral for communication with LSM303AGR
-    let i2c = { twim::Twim::new(board.TWIM0, board.i2c_internal.into(), FREQUENCY_A::K100) };
-
-    // Initialize timer peripherals
-    let mut timer0 = Timer::new(board.TIMER0);
-
-    // Initialize LED display
-    let mut display = Display::new(board.display_pins);
-
-    // Initialize LSM303AGR sensor
-    let mut sensor = Lsm303agr::new_with_i2c(i2c);
-    sensor.init().unwrap();
-
-    // Configure the sensor
-    sensor
-        .set_accel_mode_and_odr(&mut timer0, AccelMode::Normal, AccelOutputDataRate::Hz10)
-        .unwrap();
-    sensor
-        .set_mag_mode_and_odr(&mut timer0, MagMode::LowPower, MagOutputDataRate::Hz10)
-        .unwrap();
-    let mut sensor = sensor.into_mag_continuous().ok().unwrap();
-
-    // Set initial calibration using precomputed constants.
-    let mut calibration = CALIBRATION.clone();
-    rprintln!("{}", calibration);
-    rprintln!("Calibration done, entering busy loop");
-    write!(serial, "{}\r\n", calibration).unwrap();
-    let mut buffer = Vec::<u8, 32>::new();
-
-    // Main loop
-    loop {
-        // Read magnetometer data.
-        while !sensor.mag_status().unwrap().xyz_new_data() {}
-        let data = sensor.magnetic_field().unwrap();
-        let data = calibrated_measurement(data, &calibration);
-
-        // Read accelerometer data.
-        while !sensor.accel_status().unwrap().xyz_new_data() {}
-        let accel_data = sensor.acceleration().unwrap();
-
-        let ax = accel_data.x_mg();
-        let ay = accel_data.y_mg();
-        let az = accel_data.z_mg();
-
-        let gx = data.x as f32;
-        let gy = data.y as f32;
-        let gz = data.z as f32;
-
-        // Send sensor data over serial.
-        write!(
-            serial,
-            "Measurement: {gx:.2}, {gy:.2}, {gz:.2}, {ax:.2}, {ay:.2}, {az:.2}\r\n"
+        let light_sensor = LightSensor::new(board.adc);
+
+        #[cfg(any(feature = "oled", feature = "mmc5983ma", feature = "lsm6ds"))]
+        let external_bus = i2c_bus::external(board.i2c_external);
+        #[allow(unused_mut)]
+        let mut external = ExternalDisplays::new();
+        #[cfg(feature = "max7219")]
+        {
+            let (sck, mosi, cs) = board.matrix_pins;
+            external.attach_matrix(sck, mosi, cs);
+        }
+        #[cfg(feature = "oled")]
+        external.attach_oled(external_bus);
+
+        if settings.brightness != AUTO_BRIGHTNESS {
+            display.set_brightness(settings.brightness);
+            external.set_brightness(settings.brightness);
+        }
+
+        // Initialize LSM303AGR sensor. If it doesn't come up, `sample` keeps
+        // trying to restart it.
+        let mut sensor = Sensor::new(board.i2c);
+        sensor.set_power(settings.power);
+        let sensor_result = sensor.restart(&mut delay);
+        #[cfg(feature = "mmc5983ma")]
+        let external_mag_result = sensor.attach_mag(external_bus, &mut delay);
+        #[allow(unused_mut)]
+        let mut gyro = Gyro::new();
+        #[cfg(feature = "lsm6ds")]
+        let gyro_result = gyro.attach(external_bus);
+
+        // The sensor signals new data on its interrupt line; raise GPIOTE on
+        // every edge, so `sample` only runs when there is something to read.
+        // PPI also latches the clock on every edge, so a sample's timestamp
+        // doesn't depend on how long `sample` took to run.
+        let drdy_pin = board.int_pin;
+        let drdy = Gpiote::new(board.gpiote);
+        drdy.channel0()
+            .input_pin(&drdy_pin)
+            .toggle()
+            .enable_interrupt();
+        let mut ppi = ppi::Parts::new(board.ppi).ppi0;
+        ppi.set_event_endpoint(drdy.channel0().event());
+        ppi.set_task_endpoint(clock::event_capture_task());
+        ppi.enable();
+        // A sample may already be waiting, in which case the line won't
+        // change again until it has been read.
+        rtic::pend(microbit::pac::Interrupt::GPIOTE);
+
+        // Restore the stored calibration, falling back to precomputed constants.
+        let stored_calibration = Calibration::load(&mut storage);
+        let calibrated = stored_calibration.is_some();
+        let calibration = stored_calibration.unwrap_or(PRECOMPUTED_CALIBRATION);
+        rprintln!("{}", calibration);
+        let mut events = EventBus::new();
+        let serial_events = events.subscribe();
+        let display_events = events.subscribe();
+        let mut tx_queue = TxQueue::new();
+        write_version(&mut tx_queue);
+        write!(tx_queue, "Reset: {}\r\n", reset_reason).ok();
+        write!(tx_queue, "{}\r\n", boot_record).ok();
+        if let Err(e) = boot_saved {
+            report(&mut events, &Error::from(e));
+        }
+        if let Some(log) = panic_log {
+            rprintln!("Last panic: {}", log);
+            write!(tx_queue, "Panic: {}\r\n", log).ok();
+        }
+        write!(tx_queue, "{}\r\n", calibration).ok();
+        if !calibrated {
+            write!(
+                tx_queue,
+                "Warning: no stored calibration, using defaults\r\n"
+            )
+            .ok();
+        }
+        write!(tx_queue, "{}\r\n", settings).ok();
+        match sensor_result {
+            Ok(false) => {}
+            Ok(true) => report(
+                &mut events,
+                &Failure {
+                    error: Error::BusStuck,
+                    recovery: Recovery::BusCleared,
+                },
+            ),
+            Err(e) => report(&mut events, &e),
+        }
+        #[cfg(feature = "mmc5983ma")]
+        if let Err(e) = external_mag_result {
+            rprintln!("MMC5983MA setup failed: {:?}", e);
+            write!(
+                tx_queue,
+                "Warning: MMC5983MA not found, using the LSM303AGR magnetometer\r\n"
+            )
+            .ok();
+        }
+        #[cfg(feature = "lsm6ds")]
+        if let Err(e) = gyro_result {
+            rprintln!("Gyro setup failed: {:?}", e);
+            write!(tx_queue, "Warning: LSM6DS gyro not found\r\n").ok();
+        }
+
+        splash::spawn().ok();
+        dispatch::spawn().ok();
+        transmit::spawn().ok();
+        #[cfg(feature = "ble")]
+        bluetooth::spawn().ok();
+
+        (
+            Shared {
+                sensor,
+                display,
+                external,
+                gyro,
+                delay,
+                storage,
+                boot_record,
+                settings,
+                calibration,
+                calibrated,
+                compass: Compass::new(),
+                sphere_map: SphereMap::new(),
+                survey: Survey::new(),
+                app_mode: AppMode::default(),
+                events,
+                sample_count: 0,
+                max_skew_us: 0,
+                last_sample_us: 0,
+                serial,
+                tx_queue,
+                capture: cx.local.capture,
+                benchmark: Benchmark::new(),
+                watchdog,
+                game: None,
+            },
+            Local {
+                drdy,
+                drdy_pin,
+                ticker,
+                light_sensor,
+                button_a: board.buttons.button_a,
+                button_b: board.buttons.button_b,
+                rx_buffer: LineReader::new(),
+                idle_meter,
+                reset_reason,
+                serial_events,
+                display_events,
+            },
         )
-        .unwrap();
-
-        // Read any incoming serial data.
-        while let Ok(byte) = serial.read() {
-            if byte == b'\r' || byte == b'\n' || buffer.len() >= buffer.capacity() {
-                rprintln!("Received: {:?}", core::str::from_utf8(&buffer).unwrap());
-                let res = parse_command(&buffer);
-                match res {
-                    SerialCommand::ManualCal => {
-                        calibration = calc_calibration(&mut sensor, &mut display, &mut timer0);
-                        rprintln!("New calibration: {:?}", calibration);
-                        write!(serial, "{}\r\n", calibration).unwrap();
+    }
+
+    /// Feeds the watchdog between interrupts. Any task that never yields
+    /// keeps this from running, and the watchdog resets the board. Every
+    /// second, or every minute in battery mode, sends a status frame with
+    /// the share of the time spent awake and of the sphere map covered,
+    /// unless the board is asleep, and every few hours saves the total
+    /// uptime.
+    #[idle(
+        shared = [
+            watchdog,
+            app_mode,
+            calibrated,
+            settings,
+            storage,
+            boot_record,
+            events,
+            sample_count,
+            max_skew_us,
+            tx_queue,
+            sphere_map,
+        ],
+        local = [idle_meter, reset_reason]
+    )]
+    fn idle(mut cx: idle::Context) -> ! {
+        let boot_record = cx.shared.boot_record.lock(|boot_record| *boot_record);
+        let mut next_save_s = UPTIME_SAVE_INTERVAL_S;
+        loop {
+            cx.shared.watchdog.lock(|watchdog| watchdog.pet());
+            cx.local.idle_meter.sleep();
+            let window_us = match cx.shared.settings.lock(|settings| settings.power) {
+                PowerMode::Normal | PowerMode::HighRate => STATUS_INTERVAL_US,
+                PowerMode::Battery => BATTERY_STATUS_INTERVAL_US,
+            };
+            let Some(load_permille) = cx.local.idle_meter.take_load(window_us) else {
+                continue;
+            };
+            let uptime_s = (clock::now() / 1_000_000) as u32;
+            #[cfg(not(feature = "ble"))]
+            let ble_dropped = 0;
+            #[cfg(feature = "ble")]
+            let ble_dropped = nus::take_dropped();
+            let status = Status {
+                uptime_s,
+                load_permille,
+                samples: cx.shared.sample_count.lock(core::mem::take),
+                max_skew_us: cx.shared.max_skew_us.lock(core::mem::take),
+                dropped: cx.shared.tx_queue.lock(|tx_queue| tx_queue.take_dropped()) + ble_dropped,
+                mode: cx.shared.app_mode.lock(|app_mode| *app_mode),
+                calibrated: cx.shared.calibrated.lock(|calibrated| *calibrated),
+                boots: boot_record.boots,
+                total_uptime_s: boot_record.after(uptime_s).uptime_s,
+                reset: *cx.local.reset_reason,
+                coverage_permille: cx
+                    .shared
+                    .sphere_map
+                    .lock(|sphere_map| sphere_map.coverage().permille()),
+                map_bytes: MAP_RAM_BYTES as u32,
+            };
+            rprintln!("{}", status);
+            if uptime_s >= next_save_s {
+                next_save_s = uptime_s + UPTIME_SAVE_INTERVAL_S;
+                let saved = cx
+                    .shared
+                    .storage
+                    .lock(|storage| boot_record.after(uptime_s).save(storage));
+                if let Err(e) = saved {
+                    cx.shared
+                        .events
+                        .lock(|events| report(events, &Error::from(e)));
+                    dispatch::spawn().ok();
+                }
+            }
+            if status.mode != AppMode::Sleep {
+                cx.shared
+                    .tx_queue
+                    .lock(|tx_queue| write!(tx_queue, "{}\r\n", status).ok());
+                transmit::spawn().ok();
+            }
+        }
+    }
+
+    #[task(binds = TIMER1, priority = 3)]
+    fn refresh_display(_: refresh_display::Context) {
+        display::refresh();
+    }
+
+    #[task(binds = TIMER2, priority = 3)]
+    fn hold_elapsed(_: hold_elapsed::Context) {
+        display::hold_elapsed();
+    }
+
+    #[task(binds = TIMER4, priority = 3)]
+    fn clock_wrapped(_: clock_wrapped::Context) {
+        clock::wrapped();
+    }
+
+    /// Collects incoming bytes into lines and hands each one to `command`,
+    /// and has `transmit` send the next chunk of output once one has gone.
+    /// A line that arrives while `command` is still busy with the last one
+    /// is dropped with a warning.
+    #[task(
+        binds = UARTE0_UART0,
+        priority = 3,
+        shared = [serial, tx_queue],
+        local = [rx_buffer]
+    )]
+    fn receive(mut cx: receive::Context) {
+        let buffer = cx.local.rx_buffer;
+        let mut dropped = 0;
+        cx.shared.serial.lock(|serial| {
+            if serial.end_transmit() {
+                transmit::spawn().ok();
+            }
+            while let Ok(byte) = serial.read() {
+                if let Some(line) = buffer.push(byte) {
+                    rprintln!("Received: {:?}", core::str::from_utf8(line));
+                    if command::spawn(parse_command(line)).is_err() {
+                        dropped += 1;
                     }
-                    SerialCommand::Unknown => {
-                        rprintln!("Unknown command");
+                }
+            }
+        });
+        if dropped > 0 {
+            cx.shared
+                .tx_queue
+                .lock(|tx_queue| write_dropped(tx_queue, dropped));
+            transmit::spawn().ok();
+        }
+    }
+
+    /// Polls the buttons; the tick doubles as their debounce interval. It
+    /// slows down in battery mode, to wake the CPU less often. Also has
+    /// `calibrate` show the calibration game's next frame when it is due.
+    #[task(
+        binds = RTC1,
+        priority = 2,
+        shared = [gyro, settings, last_sample_us, game],
+        local = [
+            ticker,
+            button_a,
+            button_b,
+            buttons: Buttons = Buttons::new(),
+            power: PowerMode = PowerMode::Normal,
+        ]
+    )]
+    fn tick(mut cx: tick::Context) {
+        cx.local.ticker.reset_event();
+        let power = cx.shared.settings.lock(|settings| settings.power);
+        if power != *cx.local.power {
+            *cx.local.power = power;
+            cx.local.ticker.start(match power {
+                PowerMode::Normal | PowerMode::HighRate => TICK_US,
+                PowerMode::Battery => BATTERY_TICK_US,
+            });
+        }
+
+        let a_pressed = cx.local.button_a.is_low().unwrap();
+        let b_pressed = cx.local.button_b.is_low().unwrap();
+        if let Some(event) = cx.local.buttons.update(a_pressed, b_pressed) {
+            mode_event::spawn(event).ok();
+        }
+
+        let now = clock::now();
+        let last_sample_us = cx.shared.last_sample_us;
+        let timeout_us = match power {
+            PowerMode::Normal | PowerMode::HighRate => SAMPLE_TIMEOUT_US,
+            PowerMode::Battery => BATTERY_SAMPLE_TIMEOUT_US,
+        };
+        if now - *last_sample_us >= timeout_us {
+            *last_sample_us = now;
+            rtic::pend(microbit::pac::Interrupt::GPIOTE);
+        }
+        let frame_due = cx
+            .shared
+            .game
+            .lock(|game| game.as_ref().is_some_and(|game| game.frame_due(now)));
+        if frame_due {
+            calibrate::spawn().ok();
+        }
+
+        cx.shared.gyro.lock(|gyro| gyro.poll());
+    }
+
+    /// Runs whenever the LSM303AGR's data-ready line changes. Once both
+    /// sensors have produced a new sample, adds it to the sphere map,
+    /// reports it over serial and updates the displays, or during the
+    /// calibration game only hands it to the game.
+    #[task(
+        binds = GPIOTE,
+        priority = 2,
+        shared = [
+            sensor,
+            delay,
+            display,
+            external,
+            settings,
+            calibration,
+            calibrated,
+            compass,
+            sphere_map,
+            survey,
+            app_mode,
+            events,
+            sample_count,
+            max_skew_us,
+            gyro,
+            last_sample_us,
+            tx_queue,
+            capture,
+            benchmark,
+            game,
+        ],
+        local = [
+            drdy,
+            drdy_pin,
+            watch: FieldWatch = FieldWatch::new(),
+            instrument: Instrument = Instrument::new(),
+            light_sensor,
+            ambient_countdown: u32 = 0,
+            samples_since_report: u8 = 0,
+            still: StillTimer = StillTimer::new(),
+            celebration: Celebration = Celebration::new(),
+        ]
+    )]
+    fn sample(mut cx: sample::Context) {
+        cx.local.drdy.channel0().reset_events();
+        // The line only falls once the data is read, so while it is high the
+        // edge latched last is the one that announced new data. Otherwise,
+        // as when `tick` runs `sample` because the sensor went quiet, fall
+        // back to the time of reading.
+        let ready_at = if cx.local.drdy_pin.is_high().unwrap() {
+            clock::last_event()
+        } else {
+            clock::now()
+        };
+        let instrument = cx.local.instrument;
+        instrument.lap(Phase::Wait);
+        let mut stages = StageTimer::new(clock::now());
+
+        // Pick up whichever sensors have new data, and wait for the other.
+        let reading = (&mut cx.shared.sensor, &mut cx.shared.delay)
+            .lock(|sensor, delay| sensor.poll(delay, ready_at));
+        instrument.lap(Phase::Read);
+        stages.lap(Stage::Read, clock::now());
+        let reading = match reading {
+            Ok(Some(reading)) => reading,
+            Ok(None) => return,
+            Err(failure) => {
+                if failure.recovery == Recovery::Retry {
+                    rprintln!("{}", failure);
+                } else {
+                    cx.shared
+                        .events
+                        .lock(|events| report_failure(events, &failure));
+                    dispatch::spawn().ok();
+                }
+                return;
+            }
+        };
+        *cx.shared.last_sample_us = clock::now();
+        // The calibration game takes the samples while it runs, and draws on
+        // the matrix itself.
+        let playing = cx.shared.game.lock(|game| match game {
+            Some(game) => {
+                if let Reading::Full(sample) = &reading {
+                    game.feed(sample);
+                }
+                true
+            }
+            None => false,
+        });
+        if playing {
+            return;
+        }
+        let settings = cx.shared.settings.lock(|settings| *settings);
+
+        // Power down once the board has been left alone for long enough.
+        // Timing starts over either way, so a failed attempt is only
+        // retried after another full wait.
+        let accel = match &reading {
+            Reading::Full(sample) => sample.accel,
+            Reading::AccelOnly(accel) => *accel,
+        };
+        let still_us = cx.local.still.update(&accel, clock::now());
+        if settings.sleep_after_min != 0
+            && still_us >= settings.sleep_after_min as u64 * US_PER_MINUTE
+        {
+            cx.local.still.reset();
+            power_off::spawn().ok();
+        }
+        let sample = match reading {
+            Reading::Full(sample) => sample,
+            Reading::AccelOnly(_) if settings.power == PowerMode::Battery => return,
+            Reading::AccelOnly(accel) => {
+                let frame = cx
+                    .shared
+                    .compass
+                    .lock(|compass| compass.level(accel, settings.rotation));
+                cx.shared.display.lock(|display| display.show(frame));
+                return;
+            }
+        };
+
+        // While a burst is being recorded, keep the raw sample and skip the
+        // rest, so nothing holds up the next one. One waiting for its
+        // trigger only keeps a copy.
+        let (recorded, dumping) = cx
+            .shared
+            .capture
+            .lock(|capture| (capture.record(&sample), capture.is_dumping()));
+        match recorded {
+            Recorded::Ignored | Recorded::BeforeTrigger => {}
+            Recorded::InBurst => {
+                cx.shared.sample_count.lock(|count| *count += 1);
+                return;
+            }
+            Recorded::Complete => {
+                cx.shared.sample_count.lock(|count| *count += 1);
+                end_capture::spawn().ok();
+                return;
+            }
+        }
+
+        let calibration = cx.shared.calibration.lock(|calibration| *calibration);
+        let calibrated = cx.shared.calibrated.lock(|calibrated| *calibrated);
+        let app_mode = cx.shared.app_mode.lock(|app_mode| *app_mode);
+        let (heading, map_update, completed) = (&mut cx.shared.compass, &mut cx.shared.sphere_map)
+            .lock(|compass, sphere_map| {
+                let heading = compass.update(
+                    sample,
+                    &calibration,
+                    calibrated,
+                    app_mode,
+                    &settings,
+                    Some(sphere_map),
+                );
+                let index = sphere_map.add(&sample.accel, &heading.field, &sample.field);
+                let map_update = index.and_then(|index| sphere_map.live_update(index));
+                let completed = index.and_then(|index| sphere_map.completed_by(index));
+                (heading, map_update, completed)
+            });
+        let watch = cx.local.watch;
+        cx.shared.events.lock(|events| {
+            watch.check(&heading.field, &calibration, events);
+            if let Some(coverage) = completed {
+                events.publish(Event::SphereMapComplete(coverage));
+            }
+        });
+        if completed.is_some() {
+            cx.local.celebration.start(sample.timestamp_us);
+            map_command::spawn(MapCommand::Fit).ok();
+        }
+        dispatch::spawn().ok();
+        instrument.lap(Phase::Math);
+        stages.lap(Stage::Math, clock::now());
+
+        // Send every `report_every`th sample over serial, with the gyro's
+        // average rate since the last one sent if there is a gyro, unless
+        // the mode keeps quiet or a burst is being dumped.
+        let samples_since_report = cx.local.samples_since_report;
+        *samples_since_report += 1;
+        if *samples_since_report >= settings.report_every && app_mode.sends_samples() && !dumping {
+            *samples_since_report = 0;
+            let gyro = cx.shared.gyro.lock(|gyro| gyro.take_average());
+            cx.shared.tx_queue.lock(|tx_queue| {
+                if settings.output_format == OutputFormat::Binary {
+                    let frame =
+                        Frame::sample(&heading.field, &sample.accel, sample.timestamp_us, gyro);
+                    tx_queue.write_bytes(frame.as_bytes()).ok();
+                    return;
+                }
+                settings
+                    .output_format
+                    .write_sample(
+                        tx_queue,
+                        &heading.field,
+                        &sample.accel,
+                        sample.timestamp_us,
+                        gyro,
+                    )
+                    .ok();
+            });
+            transmit::spawn().ok();
+        }
+        // In the survey mode the field goes out averaged, with where it was
+        // measured, instead.
+        if app_mode == AppMode::Survey && !dumping {
+            let point = cx
+                .shared
+                .survey
+                .lock(|survey| survey.add(&heading.field, sample.timestamp_us));
+            if let Some(point) = point {
+                cx.shared
+                    .tx_queue
+                    .lock(|tx_queue| write!(tx_queue, "{}\r\n", point).ok());
+                transmit::spawn().ok();
+            }
+        }
+        // A cell that changed enough goes out whatever the output format,
+        // unless a burst is being dumped.
+        if let Some(frame) = map_update.filter(|_| !dumping) {
+            cx.shared
+                .tx_queue
+                .lock(|tx_queue| tx_queue.write_bytes(frame.as_bytes()).ok());
+            transmit::spawn().ok();
+        }
+        instrument.lap(Phase::Serial);
+        stages.lap(Stage::Output, clock::now());
+
+        // Update LED display to point at magnetic North, or show the heading
+        // or level, periodically following the ambient light when brightness
+        // is automatic, unless the sphere map has just been completed. In
+        // battery mode the matrix goes dark and the external displays are
+        // left as they are.
+        let light_sensor = cx.local.light_sensor;
+        let ambient_countdown = cx.local.ambient_countdown;
+        let celebration = cx.local.celebration;
+        let battery = settings.power == PowerMode::Battery;
+        cx.shared.display.lock(|display| {
+            if settings.brightness == AUTO_BRIGHTNESS && !battery {
+                if *ambient_countdown == 0 {
+                    display.adapt_to_ambient(light_sensor);
+                    // About once a second.
+                    *ambient_countdown = settings.power.sample_rate_hz();
+                }
+                *ambient_countdown -= 1;
+            }
+            // The celebration's frames are symmetric but for the tick,
+            // which should read the right way up.
+            let frame = celebration
+                .frame(sample.timestamp_us)
+                .filter(|_| !battery)
+                .map_or(heading.frame, |frame| settings.rotation.apply(frame));
+            display.show(frame);
+        });
+        if !battery {
+            cx.shared.external.lock(|external| {
+                external.show(&heading.view, &settings, &heading.field, calibrated);
+            });
+        }
+        #[cfg(feature = "ble")]
+        ble::update(
+            &heading.field,
+            &sample.field,
+            &calibration,
+            calibrated,
+            &settings,
+        );
+        instrument.lap(Phase::Display);
+        stages.lap(Stage::Display, clock::now());
+        let report = cx
+            .shared
+            .benchmark
+            .lock(|benchmark| benchmark.record(&stages, clock::now()));
+        if let Some(report) = report {
+            cx.shared
+                .tx_queue
+                .lock(|tx_queue| write!(tx_queue, "{}\r\n", report).ok());
+            transmit::spawn().ok();
+        }
+        cx.shared.sample_count.lock(|count| *count += 1);
+        cx.shared
+            .max_skew_us
+            .lock(|max_skew_us| *max_skew_us = (*max_skew_us).max(sample.skew_us));
+
+        if cx
+            .shared
+            .tx_queue
+            .lock(|tx_queue| instrument.end_sample(tx_queue))
+        {
+            transmit::spawn().ok();
+        }
+    }
+
+    /// Plays the boot animation. Holding the display keeps `sample` from
+    /// drawing over it until it finishes.
+    #[task(priority = 1, shared = [display, delay, settings])]
+    async fn splash(mut cx: splash::Context) {
+        let settings = cx.shared.settings.lock(|settings| *settings);
+        (cx.shared.display, cx.shared.delay).lock(|display, delay| {
+            play_boot_splash(display, delay, settings);
+        });
+    }
+
+    /// Moves the application mode on for `event`, doing whatever the
+    /// transition needs and reporting the new mode.
+    #[task(priority = 1, shared = [app_mode, settings, tx_queue, game])]
+    async fn mode_event(mut cx: mode_event::Context, event: ModeEvent) {
+        let (old, (new, action)) = cx
+            .shared
+            .app_mode
+            .lock(|app_mode| (*app_mode, app_mode.handle(event)));
+        match action {
+            ModeAction::None => {}
+            // Button A cycles through the display modes, which are saved
+            // like any other setting.
+            ModeAction::NextDisplayMode => {
+                let mode = cx.shared.settings.lock(|settings| {
+                    settings.display_mode = settings.display_mode.next();
+                    settings.display_mode
+                });
+                if command::spawn(SerialCommand::SetDisplayMode(mode)).is_err() {
+                    cx.shared
+                        .tx_queue
+                        .lock(|tx_queue| write_dropped(tx_queue, 1));
+                    transmit::spawn().ok();
+                }
+            }
+            // Only switch once the game is really starting, so the mode
+            // never claims a calibration that isn't running.
+            ModeAction::StartCalibration => {
+                let started = cx.shared.game.lock(|game| {
+                    if game.is_some() {
+                        return false;
                     }
+                    *game = Some(Game::new(old, clock::now()));
+                    true
+                });
+                if !started {
+                    return;
                 }
-                buffer.clear();
-                continue;
             }
-            buffer.push(byte).unwrap();
+            // `calibrate` goes back to the mode before the game at the next
+            // frame.
+            ModeAction::StopCalibration => {
+                cx.shared.game.lock(|game| {
+                    if let Some(game) = game {
+                        game.stop();
+                    }
+                });
+            }
+            ModeAction::NextWaypoint => {
+                move_survey::spawn(None).ok();
+            }
+        }
+        if new == old {
+            return;
         }
+        cx.shared.app_mode.lock(|app_mode| *app_mode = new);
+        rprintln!("{}", new);
+        cx.shared
+            .tx_queue
+            .lock(|tx_queue| write!(tx_queue, "{}\r\n", new).ok());
+        transmit::spawn().ok();
+    }
 
-        // Get angle of the magnetic field.
-        // Figure out the direction based on theta
-        let theta = atan2f(gy, gx);
-        let dir = dir_from_theta(theta);
+    #[task(
+        priority = 1,
+        shared = [sensor, delay, display, external, storage, settings, events, tx_queue]
+    )]
+    async fn command(mut cx: command::Context, command: SerialCommand) {
+        let old_power = cx.shared.settings.lock(|settings| settings.power);
+        let settings = cx.shared.settings.lock(|settings| {
+            if settings.apply(&command) {
+                return Some(*settings);
+            }
+            match command {
+                SerialCommand::ManualCal => {
+                    mode_event::spawn(ModeEvent::Command(AppMode::Calibrating)).ok();
+                }
+                SerialCommand::StopCal => {
+                    mode_event::spawn(ModeEvent::StopCalibration).ok();
+                }
+                SerialCommand::SetAppMode(mode) => {
+                    mode_event::spawn(ModeEvent::Command(mode)).ok();
+                }
+                SerialCommand::Capture(_)
+                | SerialCommand::Disarm
+                | SerialCommand::SetPreTrigger(_)
+                | SerialCommand::SetPostTrigger(_) => {
+                    configure_capture::spawn(command).ok();
+                }
+                SerialCommand::Benchmark(seconds) => {
+                    run_benchmark::spawn(seconds).ok();
+                }
+                SerialCommand::SetPosition(position) => {
+                    move_survey::spawn(Some(position)).ok();
+                }
+                SerialCommand::SetCalibration(calibration) => {
+                    set_calibration::spawn(calibration).ok();
+                }
+                SerialCommand::Map(command) => {
+                    map_command::spawn(command).ok();
+                }
+                SerialCommand::Unknown => rprintln!("Unknown command"),
+                // The settings, applied above.
+                _ => {}
+            }
+            None
+        });
+        let Some(settings) = settings else {
+            return;
+        };
+
+        // The sensor only takes new rates when it is set up again.
+        if settings.power != old_power {
+            let restarted = (&mut cx.shared.sensor, &mut cx.shared.delay).lock(|sensor, delay| {
+                sensor.set_power(settings.power);
+                sensor.restart(delay)
+            });
+            if let Err(e) = restarted {
+                cx.shared.events.lock(|events| report(events, &e));
+                dispatch::spawn().ok();
+            }
+        }
+
+        cx.shared.display.lock(|display| {
+            display.set_hold_ms(settings.display_hold_ms);
+            if settings.brightness != AUTO_BRIGHTNESS {
+                display.set_brightness(settings.brightness);
+            }
+        });
+        if settings.brightness != AUTO_BRIGHTNESS {
+            cx.shared
+                .external
+                .lock(|external| external.set_brightness(settings.brightness));
+        }
+        let saved = cx
+            .shared
+            .storage
+            .lock(|storage| settings.save(storage).map_err(Error::from));
+        if let Err(e) = saved {
+            cx.shared.events.lock(|events| report(events, &e));
+            dispatch::spawn().ok();
+        }
+        cx.shared
+            .tx_queue
+            .lock(|tx_queue| write!(tx_queue, "{}\r\n", settings).ok());
+        transmit::spawn().ok();
+    }
+
+    /// Shows the calibration game's next frame. Once the game is complete,
+    /// stores and publishes the result and hands back to the compass; if it
+    /// was stopped or ran out of time, goes back to the mode before it.
+    #[task(
+        priority = 1,
+        shared = [display, storage, calibration, calibrated, app_mode, events, tx_queue, game]
+    )]
+    async fn calibrate(mut cx: calibrate::Context) {
+        let now = clock::now();
+        let step = cx.shared.game.lock(|game| {
+            let step = game.as_mut()?.step(now);
+            if !matches!(step, Step::Show(_)) {
+                *game = None;
+            }
+            Some(step)
+        });
+        let (event, calibration) = match step {
+            None => return,
+            Some(Step::Show(frame)) => {
+                cx.shared.display.lock(|display| display.show(frame));
+                return;
+            }
+            Some(Step::Done(calibration)) => (ModeEvent::CalibrationDone, Some(calibration)),
+            Some(Step::Abandoned {
+                previous,
+                timed_out,
+            }) => {
+                let reason = if timed_out {
+                    "calibration timed out"
+                } else {
+                    "calibration stopped"
+                };
+                cx.shared.events.lock(|events| report(events, &reason));
+                dispatch::spawn().ok();
+                (ModeEvent::CalibrationAbandoned(previous), None)
+            }
+        };
+        // Switched here rather than through `mode_event`, which a button
+        // pressed during the game may already have queued.
+        let mode = cx.shared.app_mode.lock(|app_mode| {
+            *app_mode = app_mode.handle(event).0;
+            *app_mode
+        });
+        cx.shared
+            .tx_queue
+            .lock(|tx_queue| write!(tx_queue, "{}\r\n", mode).ok());
+        transmit::spawn().ok();
+        let Some(calibration) = calibration else {
+            return;
+        };
+        cx.shared.calibration.lock(|c| *c = calibration);
+        cx.shared.calibrated.lock(|calibrated| *calibrated = true);
+        let saved = cx
+            .shared
+            .storage
+            .lock(|storage| calibration.save(storage).map_err(Error::from));
+        rprintln!("New calibration: {:?}", calibration);
+        cx.shared.events.lock(|events| {
+            if let Err(e) = saved {
+                report(events, &e);
+            }
+            events.publish(Event::CalibrationApplied(calibration));
+        });
+        dispatch::spawn().ok();
+    }
+
+    /// Enables the SoftDevice, holding the storage so that no flash
+    /// operation is under way, then handles its events, advertises the
+    /// readout and serves the GATT services to a central that connects,
+    /// handing the commands it writes to `command`, or dropping them with a
+    /// warning while `command` is busy.
+    #[cfg(feature = "ble")]
+    #[task(priority = 1, shared = [storage, tx_queue])]
+    async fn bluetooth(mut cx: bluetooth::Context) {
+        let sd = cx.shared.storage.lock(|_| softdevice::enable());
+        let server = match ble::Server::new(sd) {
+            Ok(server) => server,
+            Err(e) => {
+                rprintln!("Registering the GATT services failed: {:?}", e);
+                return;
+            }
+        };
+        let sd = &*sd;
+        let advertise = ble::advertise(sd, &server, |command| {
+            if command::spawn(command).is_err() {
+                cx.shared
+                    .tx_queue
+                    .lock(|tx_queue| write_dropped(tx_queue, 1));
+                transmit::spawn().ok();
+            }
+        });
+        embassy_futures::join::join(sd.run_ble(), advertise).await;
+    }
+
+    /// Hands the events published since it last ran to each output that
+    /// follows them: the serial port and the external displays.
+    #[task(
+        priority = 1,
+        shared = [events, external, tx_queue],
+        local = [serial_events, display_events]
+    )]
+    async fn dispatch(mut cx: dispatch::Context) {
+        let serial_events = cx.local.serial_events;
+        while let Some(event) = cx.shared.events.lock(|events| events.next(serial_events)) {
+            cx.shared
+                .tx_queue
+                .lock(|tx_queue| event.write_line(tx_queue).ok());
+        }
+        transmit::spawn().ok();
+
+        let display_events = cx.local.display_events;
+        while let Some(event) = cx.shared.events.lock(|events| events.next(display_events)) {
+            cx.shared.external.lock(|external| external.notify(&event));
+        }
+    }
+
+    /// Powers the board down until it is moved: saves the total uptime,
+    /// says so over serial, turns the matrix off, leaves the accelerometer
+    /// watching for movement and enters System OFF. Holding the sensor
+    /// keeps `sample` from setting it back up for sampling in the meantime.
+    /// The external displays and sensors on the edge connector are left as
+    /// they are.
+    #[task(
+        priority = 1,
+        shared = [sensor, storage, boot_record, events, serial, tx_queue]
+    )]
+    async fn power_off(mut cx: power_off::Context) {
+        rprintln!("Powering off until moved");
+        // Waking up is a new boot, so this one's uptime ends here.
+        let boot_record = cx.shared.boot_record.lock(|boot_record| *boot_record);
+        let uptime_s = (clock::now() / 1_000_000) as u32;
+        let saved = cx
+            .shared
+            .storage
+            .lock(|storage| boot_record.after(uptime_s).save(storage));
+        if let Err(e) = saved {
+            rprintln!("Failed to save the uptime: {:?}", e);
+        }
+        cx.shared
+            .tx_queue
+            .lock(|tx_queue| write!(tx_queue, "Power: off until moved\r\n").ok());
+        // The UART stops with everything else, so drain it here rather than
+        // leaving it to `transmit`.
+        (&mut cx.shared.serial, &mut cx.shared.tx_queue)
+            .lock(|serial, tx_queue| serial.flush(tx_queue));
+        let armed: Result<(), Error> = cx.shared.sensor.lock(|sensor| {
+            display::switch_off();
+            sensor.arm_wake_on_motion()?;
+            system_off::enter()
+        });
+        if let Err(e) = armed {
+            cx.shared.events.lock(|events| report(events, &e));
+            dispatch::spawn().ok();
+        }
+    }
+
+    /// Applies the capture commands: sets how much of a triggered burst is
+    /// kept either side of the trigger, stops waiting for one, or sets the
+    /// sensor up for 100 Hz, whatever the power mode, and starts a burst or
+    /// waits for its trigger, unless one is already under way.
+    #[task(priority = 1, shared = [sensor, delay, settings, capture, events, tx_queue])]
+    async fn configure_capture(mut cx: configure_capture::Context, command: SerialCommand) {
+        let burst = match command {
+            SerialCommand::Capture(burst) => burst,
+            SerialCommand::Disarm => {
+                // `end_capture` puts the sensor back.
+                if cx.shared.capture.lock(|capture| capture.disarm()) {
+                    end_capture::spawn().ok();
+                }
+                cx.shared
+                    .tx_queue
+                    .lock(|tx_queue| write!(tx_queue, "Capture: disarmed\r\n").ok());
+                transmit::spawn().ok();
+                return;
+            }
+            SerialCommand::SetPreTrigger(_) | SerialCommand::SetPostTrigger(_) => {
+                let (pre, post) = cx.shared.capture.lock(|capture| {
+                    let (pre, post) = capture.window();
+                    match command {
+                        SerialCommand::SetPreTrigger(samples) => capture.set_window(samples, post),
+                        SerialCommand::SetPostTrigger(samples) => capture.set_window(pre, samples),
+                        _ => {}
+                    }
+                    capture.window()
+                });
+                cx.shared.tx_queue.lock(|tx_queue| {
+                    write!(tx_queue, "Capture: pre={}, post={}\r\n", pre, post).ok()
+                });
+                transmit::spawn().ok();
+                return;
+            }
+            _ => return,
+        };
+        if cx.shared.capture.lock(|capture| capture.is_busy()) {
+            cx.shared.tx_queue.lock(|tx_queue| {
+                write!(tx_queue, "Warning: a capture is already under way\r\n").ok()
+            });
+            transmit::spawn().ok();
+            return;
+        }
+        // Set up before recording starts, so every sample is at the high
+        // rate.
+        if cx.shared.settings.lock(|settings| settings.power) != PowerMode::HighRate {
+            let restarted = (&mut cx.shared.sensor, &mut cx.shared.delay).lock(|sensor, delay| {
+                sensor.set_power(PowerMode::HighRate);
+                sensor.restart(delay)
+            });
+            if let Err(e) = restarted {
+                cx.shared.events.lock(|events| report(events, &e));
+                dispatch::spawn().ok();
+            }
+        }
+        let (pre, post) = cx.shared.capture.lock(|capture| {
+            capture.begin(burst);
+            capture.window()
+        });
+        cx.shared.tx_queue.lock(|tx_queue| match burst {
+            Burst::Timed(seconds) => {
+                write!(tx_queue, "Capture: recording, seconds={}\r\n", seconds).ok()
+            }
+            Burst::Triggered(trigger) => write!(
+                tx_queue,
+                "Capture: armed, {}, pre={}, post={}\r\n",
+                trigger, pre, post
+            )
+            .ok(),
+        });
+        transmit::spawn().ok();
+    }
+
+    /// Puts the sensor back to the power mode's rates once a burst has been
+    /// recorded, and starts dumping it, or once one has stopped waiting for
+    /// its trigger.
+    #[task(priority = 1, shared = [sensor, delay, settings, events])]
+    async fn end_capture(mut cx: end_capture::Context) {
+        let power = cx.shared.settings.lock(|settings| settings.power);
+        if power != PowerMode::HighRate {
+            let restarted = (&mut cx.shared.sensor, &mut cx.shared.delay).lock(|sensor, delay| {
+                sensor.set_power(power);
+                sensor.restart(delay)
+            });
+            if let Err(e) = restarted {
+                cx.shared.events.lock(|events| report(events, &e));
+                dispatch::spawn().ok();
+            }
+        }
+        dump_capture::spawn().ok();
+    }
+
+    /// Applies the `SMAP` commands, acknowledging each with a `Map:` line:
+    /// starts or stops collecting samples, resets the map, reports how much
+    /// of it has been covered, after setting the samples a cell needs if
+    /// given, starts exporting it or the difference between the saved maps,
+    /// turns sending cells as they change on or off, lists its anomalies,
+    /// saves it to flash, loads it back or merges a saved map in, or
+    /// refits the calibration from its samples and applies the refit.
+    /// Samples wait while the map is saved, since the CPU stops while flash
+    /// is erased, but not while the calibration is refitted.
+    #[task(
+        priority = 1,
+        shared = [sphere_map, storage, calibration, calibrated, events, tx_queue]
+    )]
+    async fn map_command(mut cx: map_command::Context, command: MapCommand) {
+        let cells = cx
+            .shared
+            .sphere_map
+            .lock(|sphere_map| sphere_map.cells().len());
+        match command {
+            MapCommand::Start | MapCommand::Stop => {
+                let collecting = command == MapCommand::Start;
+                cx.shared
+                    .sphere_map
+                    .lock(|sphere_map| sphere_map.set_collecting(collecting));
+                cx.shared.tx_queue.lock(|tx_queue| {
+                    write!(
+                        tx_queue,
+                        "Map: {}, cells={}\r\n",
+                        if collecting { "started" } else { "stopped" },
+                        cells
+                    )
+                    .ok()
+                });
+            }
+            MapCommand::Reset => {
+                cx.shared.sphere_map.lock(|sphere_map| sphere_map.clear());
+                cx.shared
+                    .tx_queue
+                    .lock(|tx_queue| write!(tx_queue, "Map: reset, cells={}\r\n", cells).ok());
+            }
+            MapCommand::Coverage(samples) => {
+                let coverage = cx.shared.sphere_map.lock(|sphere_map| {
+                    if let Some(samples) = samples {
+                        sphere_map.set_covered_samples(samples as u32);
+                    }
+                    sphere_map.coverage()
+                });
+                cx.shared
+                    .tx_queue
+                    .lock(|tx_queue| write!(tx_queue, "{}\r\n", coverage).ok());
+            }
+            MapCommand::Export => {
+                cx.shared
+                    .sphere_map
+                    .lock(|sphere_map| sphere_map.start_export());
+                cx.shared
+                    .tx_queue
+                    .lock(|tx_queue| write!(tx_queue, "Map: exporting, cells={}\r\n", cells).ok());
+                export_map::spawn().ok();
+                return;
+            }
+            MapCommand::Diff => {
+                let started = (&mut cx.shared.sphere_map, &mut cx.shared.storage)
+                    .lock(|sphere_map, storage| sphere_map.start_difference(storage));
+                cx.shared.tx_queue.lock(|tx_queue| {
+                    if started {
+                        write!(tx_queue, "Map: diffing, cells={}\r\n", cells).ok();
+                    } else {
+                        write!(tx_queue, "Warning: sphere maps not saved in both slots\r\n").ok();
+                    }
+                });
+                if started {
+                    export_map::spawn().ok();
+                    return;
+                }
+            }
+            MapCommand::Live(live) => {
+                cx.shared
+                    .sphere_map
+                    .lock(|sphere_map| sphere_map.set_live(live));
+                cx.shared.tx_queue.lock(|tx_queue| {
+                    write!(
+                        tx_queue,
+                        "Map: live, updates={}\r\n",
+                        if live { "on" } else { "off" }
+                    )
+                    .ok()
+                });
+            }
+            MapCommand::Anomalies => {
+                let anomalies = cx
+                    .shared
+                    .sphere_map
+                    .lock(|sphere_map| sphere_map.anomalies());
+                cx.shared.tx_queue.lock(|tx_queue| {
+                    write!(tx_queue, "{}\r\n", anomalies).ok();
+                    for anomaly in anomalies.worst() {
+                        write!(tx_queue, "{}\r\n", anomaly).ok();
+                    }
+                });
+            }
+            MapCommand::Fit => {
+                let current = cx.shared.calibration.lock(|calibration| *calibration);
+                let refit = cx
+                    .shared
+                    .sphere_map
+                    .lock(|sphere_map| sphere_map.refit().clone());
+                let refitted = refit.fit(&current);
+                if let Ok(refitted) = refitted {
+                    cx.shared
+                        .sphere_map
+                        .lock(|sphere_map| sphere_map.refit().offer(refitted));
+                }
+                cx.shared.tx_queue.lock(|tx_queue| {
+                    match refitted {
+                        Ok(refitted) => write!(tx_queue, "{}\r\n", refitted),
+                        Err(directions) => write!(
+                            tx_queue,
+                            "Warning: too few field directions to refit, cells={}\r\n",
+                            directions
+                        ),
+                    }
+                    .ok()
+                });
+            }
+            MapCommand::Apply => {
+                let Some(refitted) = cx
+                    .shared
+                    .sphere_map
+                    .lock(|sphere_map| sphere_map.refit().accept())
+                else {
+                    cx.shared.tx_queue.lock(|tx_queue| {
+                        write!(tx_queue, "Warning: no calibration refit offered\r\n").ok()
+                    });
+                    transmit::spawn().ok();
+                    return;
+                };
+                let calibration = refitted.calibration;
+                cx.shared.calibration.lock(|c| *c = calibration);
+                cx.shared.calibrated.lock(|calibrated| *calibrated = true);
+                let saved = cx
+                    .shared
+                    .storage
+                    .lock(|storage| calibration.save(storage).map_err(Error::from));
+                cx.shared.tx_queue.lock(|tx_queue| {
+                    write!(tx_queue, "Map: applied, cells={}\r\n", refitted.cells).ok()
+                });
+                cx.shared.events.lock(|events| {
+                    if let Err(e) = saved {
+                        report(events, &e);
+                    }
+                    events.publish(Event::CalibrationApplied(calibration));
+                });
+                dispatch::spawn().ok();
+            }
+            MapCommand::Save(slot) => {
+                let saved = (&mut cx.shared.sphere_map, &mut cx.shared.storage).lock(
+                    |sphere_map, storage| sphere_map.save(storage, slot).map_err(Error::from),
+                );
+                match saved {
+                    Ok(()) => cx.shared.tx_queue.lock(|tx_queue| {
+                        write!(tx_queue, "Map: saved, slot={}, cells={}\r\n", slot, cells).ok()
+                    }),
+                    Err(e) => {
+                        cx.shared.events.lock(|events| report(events, &e));
+                        dispatch::spawn().ok()
+                    }
+                };
+            }
+            MapCommand::Load(slot) => {
+                let loaded = (&mut cx.shared.sphere_map, &mut cx.shared.storage)
+                    .lock(|sphere_map, storage| sphere_map.load(storage, slot));
+                cx.shared.tx_queue.lock(|tx_queue| {
+                    if loaded {
+                        write!(tx_queue, "Map: loaded, slot={}, cells={}\r\n", slot, cells).ok();
+                    } else {
+                        write!(tx_queue, "Warning: no saved sphere map, slot={}\r\n", slot).ok();
+                    }
+                });
+            }
+            MapCommand::Merge(slot) => {
+                // Merging a saved map twice would count its samples twice.
+                let merged = (&mut cx.shared.sphere_map, &mut cx.shared.storage).lock(
+                    |sphere_map, storage| {
+                        if sphere_map.holds_saved(slot) {
+                            return None;
+                        }
+                        Some(sphere_map.merge(storage, slot))
+                    },
+                );
+                cx.shared.tx_queue.lock(|tx_queue| {
+                    match merged {
+                        Some(true) => {
+                            write!(tx_queue, "Map: merged, slot={}, cells={}\r\n", slot, cells)
+                        }
+                        Some(false) => {
+                            write!(tx_queue, "Warning: no saved sphere map, slot={}\r\n", slot)
+                        }
+                        None => write!(
+                            tx_queue,
+                            "Warning: saved sphere map already merged, slot={}\r\n",
+                            slot
+                        ),
+                    }
+                    .ok()
+                });
+            }
+        }
+        transmit::spawn().ok();
+    }
+
+    /// Applies and saves a calibration sent over serial, which goes back out
+    /// as a `Calibration:` line like any other.
+    #[task(priority = 1, shared = [calibration, calibrated, storage, events])]
+    async fn set_calibration(mut cx: set_calibration::Context, calibration: Calibration) {
+        cx.shared.calibration.lock(|c| *c = calibration);
+        cx.shared.calibrated.lock(|calibrated| *calibrated = true);
+        let saved = cx
+            .shared
+            .storage
+            .lock(|storage| calibration.save(storage).map_err(Error::from));
+        rprintln!("New calibration: {:?}", calibration);
+        cx.shared.events.lock(|events| {
+            if let Err(e) = saved {
+                report(events, &e);
+            }
+            events.publish(Event::CalibrationApplied(calibration));
+        });
+        dispatch::spawn().ok();
+    }
 
-        // Update LED display to point at magnetic North.
-        display.show(&mut timer0, direction_to_led(dir), 100);
+    /// Moves the survey to `position`, or on to its next waypoint, and
+    /// reports where it is.
+    #[task(priority = 1, shared = [survey, tx_queue])]
+    async fn move_survey(mut cx: move_survey::Context, position: Option<Position>) {
+        let position = cx.shared.survey.lock(|survey| match position {
+            Some(position) => {
+                survey.set_position(position);
+                position
+            }
+            None => survey.next_waypoint(),
+        });
+        cx.shared
+            .tx_queue
+            .lock(|tx_queue| write!(tx_queue, "Position: {}\r\n", position).ok());
+        transmit::spawn().ok();
     }
+
+    /// Starts a benchmark run of `seconds`, over which `sample` times each
+    /// stage of handling samples as it goes and then reports, replacing any
+    /// run already going.
+    #[task(priority = 1, shared = [benchmark, tx_queue])]
+    async fn run_benchmark(mut cx: run_benchmark::Context, seconds: u8) {
+        cx.shared
+            .benchmark
+            .lock(|benchmark| benchmark.start(seconds, clock::now()));
+        cx.shared
+            .tx_queue
+            .lock(|tx_queue| write!(tx_queue, "Benchmark: running, seconds={}\r\n", seconds).ok());
+        transmit::spawn().ok();
+    }
+
+    /// Queues as much of a recorded burst as fits, only ever whole lines,
+    /// and has `transmit` send it. `transmit` runs this again as the queue
+    /// drains, until the whole burst has gone.
+    #[task(priority = 1, shared = [capture, tx_queue])]
+    async fn dump_capture(mut cx: dump_capture::Context) {
+        let queued = (&mut cx.shared.capture, &mut cx.shared.tx_queue).lock(|capture, tx_queue| {
+            let mut queued = false;
+            while tx_queue.space() >= MAX_DUMP_LINE_LEN && capture.write_next(tx_queue) == Ok(true)
+            {
+                queued = true;
+            }
+            queued
+        });
+        // Only when something was queued, so the two tasks don't keep
+        // running each other while the queue is full.
+        if queued {
+            transmit::spawn().ok();
+        }
+    }
+
+    /// Queues as many cells of the sphere map, or of the difference between
+    /// the saved maps, being exported as fit while leaving room for the
+    /// live output, and the closing line after the last, and has `transmit`
+    /// send them. Like `dump_capture`, `transmit` runs this again as the
+    /// queue drains.
+    #[task(priority = 1, shared = [sphere_map, storage, tx_queue])]
+    async fn export_map(mut cx: export_map::Context) {
+        let queued = (
+            &mut cx.shared.sphere_map,
+            &mut cx.shared.storage,
+            &mut cx.shared.tx_queue,
+        )
+            .lock(|sphere_map, storage, tx_queue| {
+                let mut queued = false;
+                while sphere_map.is_exporting()
+                    && tx_queue.space() >= CELL_FRAME_LEN + EXPORT_HEADROOM
+                {
+                    match sphere_map.export_next(storage) {
+                        Some(frame) => tx_queue.write_bytes(frame.as_bytes()).ok(),
+                        None => write!(tx_queue, "Map: done\r\n").ok(),
+                    };
+                    queued = true;
+                }
+                queued
+            });
+        if queued {
+            transmit::spawn().ok();
+        }
+    }
+
+    /// Starts sending the next chunk of queued output, if the UART isn't
+    /// busy with one. EasyDMA sends it in the background, and `receive`
+    /// runs this again once it has gone, until the queue is empty. Tops the
+    /// queue up with the next part of a burst capture being dumped, or of
+    /// the sphere map being exported.
+    #[task(priority = 1, shared = [serial, tx_queue, capture, sphere_map])]
+    async fn transmit(mut cx: transmit::Context) {
+        (&mut cx.shared.serial, &mut cx.shared.tx_queue).lock(|serial, tx_queue| {
+            let _chunk = serial.transmit(tx_queue);
+            #[cfg(feature = "ble")]
+            nus::mirror(_chunk);
+        });
+        if cx.shared.capture.lock(|capture| capture.is_dumping()) {
+            dump_capture::spawn().ok();
+        }
+        if cx
+            .shared
+            .sphere_map
+            .lock(|sphere_map| sphere_map.is_exporting())
+        {
+            export_map::spawn().ok();
+        }
+    }
+}
+
+/// Writes the boot banner's `Version:` line: the firmware's version, and
+/// the commit it was built from when known.
+fn write_version(out: &mut impl core::fmt::Write) {
+    write!(out, "Version: {}", env!("CARGO_PKG_VERSION")).ok();
+    if let Some(hash) = config::GIT_HASH {
+        write!(out, " ({})", hash).ok();
+    }
+    write!(out, "\r\n").ok();
+}
+
+/// Warns that `count` commands were dropped because `command` was still
+/// handling an earlier one; spawn `transmit` afterwards to send it.
+fn write_dropped(out: &mut impl core::fmt::Write, count: u32) {
+    write!(out, "Warning: busy with a command, dropped={}\r\n", count).ok();
 }
 
-fn parse_command(command: &[u8]) -> SerialCommand {
-    if command == b"SCAL" {
-        rprintln!("Manual calibration requested");
-        return SerialCommand::ManualCal;
+/// Reports `error` over RTT, and publishes it for the outputs that follow
+/// errors; spawn `dispatch` afterwards to hand it on.
+fn report(events: &mut events::EventBus<EVENT_QUEUE_LEN>, error: &dyn core::fmt::Display) {
+    rtt_target::rprintln!("{}", error);
+    events.publish_error(error);
+}
+
+/// Reports a sensor failure like `report`, followed by a recovery event if
+/// the sensor was set up again.
+fn report_failure(events: &mut events::EventBus<EVENT_QUEUE_LEN>, failure: &sensor::Failure) {
+    report(events, failure);
+    if failure.recovered() {
+        events.publish(events::Event::SensorRecovered);
+    }
+}
+
+/// Sweeps the arrow once around the compass rose, then optionally flashes the
+/// firmware's major version so a reset is visible on the board itself.
+fn play_boot_splash<T: DelayNs>(display: &mut LedDisplay, timer: &mut T, settings: Settings) {
+    display.set_hold_ms(0);
+    for step in 0..SPLASH_STEPS {
+        let theta = PI / 2. - 2. * PI * step as f32 / SPLASH_STEPS as f32;
+        display.show(settings.rotation.apply(render_arrow(theta)));
+        timer.delay_ms(SPLASH_STEP_MS);
+    }
+    if cfg!(feature = "version-splash") {
+        let major = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0);
+        display.show(settings.rotation.apply(render_digit(major)));
+        timer.delay_ms(SPLASH_VERSION_MS);
     }
-    SerialCommand::Unknown
+    display.show([[0; 5]; 5]);
+    display.set_hold_ms(settings.display_hold_ms);
 }
@@ -0,0 +1,78 @@
+//! Driver for a MAX7219-based 8x8 LED matrix wired to the edge connector's
+//! SPI pins, used as a larger alternative to the onboard compass display.
+
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+
+use crate::led::{Grid, MAX_BRIGHTNESS};
+
+pub const SIZE: usize = 8;
+
+const REG_ROW0: u8 = 0x01;
+const REG_DECODE_MODE: u8 = 0x09;
+const REG_INTENSITY: u8 = 0x0a;
+const REG_SCAN_LIMIT: u8 = 0x0b;
+const REG_SHUTDOWN: u8 = 0x0c;
+const REG_DISPLAY_TEST: u8 = 0x0f;
+const MAX_INTENSITY: u8 = 0x0f;
+
+pub struct Max7219<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+    current: Option<Grid<SIZE>>,
+}
+
+impl<SPI, CS> Max7219<SPI, CS>
+where
+    SPI: SpiBus,
+    CS: OutputPin,
+{
+    pub fn new(spi: SPI, cs: CS) -> Result<Max7219<SPI, CS>, SPI::Error> {
+        let mut matrix = Max7219 {
+            spi,
+            cs,
+            current: None,
+        };
+        matrix.write_register(REG_DISPLAY_TEST, 0)?;
+        matrix.write_register(REG_DECODE_MODE, 0)?;
+        matrix.write_register(REG_SCAN_LIMIT, SIZE as u8 - 1)?;
+        matrix.write_register(REG_INTENSITY, MAX_INTENSITY)?;
+        matrix.show(&[[0; SIZE]; SIZE])?;
+        matrix.write_register(REG_SHUTDOWN, 1)?;
+        Ok(matrix)
+    }
+
+    /// Shows `frame`, skipping the update when it is already displayed. The
+    /// MAX7219 has no per-pixel brightness, so every non-zero pixel is lit.
+    pub fn show(&mut self, frame: &Grid<SIZE>) -> Result<(), SPI::Error> {
+        if self.current.as_ref() == Some(frame) {
+            return Ok(());
+        }
+        for (row, line) in frame.iter().enumerate() {
+            let bits = line
+                .iter()
+                .fold(0u8, |bits, pixel| (bits << 1) | (*pixel > 0) as u8);
+            self.write_register(REG_ROW0 + row as u8, bits)?;
+        }
+        self.current = Some(*frame);
+        Ok(())
+    }
+
+    /// Maps a brightness from 1 to `MAX_BRIGHTNESS` onto the chip's global
+    /// intensity register.
+    pub fn set_brightness(&mut self, brightness: u8) -> Result<(), SPI::Error> {
+        let brightness = brightness.min(MAX_BRIGHTNESS) as u16;
+        let intensity = brightness * MAX_INTENSITY as u16 / MAX_BRIGHTNESS as u16;
+        self.write_register(REG_INTENSITY, intensity as u8)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), SPI::Error> {
+        self.cs.set_low().ok();
+        let result = self
+            .spi
+            .write(&[register, value])
+            .and_then(|_| self.spi.flush());
+        self.cs.set_high().ok();
+        result
+    }
+}
@@ -0,0 +1,105 @@
+//! Driver for a MEMSIC MMC5983MA magnetometer on I2C, as a lower-noise
+//! alternative to the LSM303AGR's magnetometer. It runs in continuous mode
+//! at the configured sample rate, matching the LSM303AGR, with periodic set/reset pulses to
+//! cancel the offset drift of its sensing elements.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use sphere_mapping_core::config;
+
+use crate::calibration::Measurement;
+
+const ADDRESS: u8 = 0x30;
+const PRODUCT_ID: u8 = 0x30;
+
+const REG_XOUT0: u8 = 0x00;
+const REG_STATUS: u8 = 0x08;
+const REG_CONTROL0: u8 = 0x09;
+const REG_CONTROL1: u8 = 0x0a;
+const REG_CONTROL2: u8 = 0x0b;
+const REG_PRODUCT_ID: u8 = 0x2f;
+
+const MEAS_M_DONE: u8 = 1 << 0;
+const AUTO_SR_EN: u8 = 1 << 5;
+const SW_RST: u8 = 1 << 7;
+const CM_FREQ: u8 = match config::SAMPLE_RATE_HZ {
+    50 => 0b100,
+    100 => 0b101,
+    _ => 0b010,
+};
+const CMM_EN: u8 = 1 << 3;
+/// Set pulse every 100 measurements.
+const PRD_SET_100: u8 = 0b011 << 4;
+const EN_PRD_SET: u8 = 1 << 7;
+
+const RESET_MS: u32 = 10;
+/// 18-bit output at zero field.
+const NULL_FIELD: i32 = 1 << 17;
+/// 16384 counts per gauss, and 100000 nT per gauss: 3125/512 nT per count.
+const NT_PER_COUNT_NUM: i32 = 3_125;
+const NT_PER_COUNT_DEN: i32 = 512;
+
+#[derive(Debug)]
+pub enum SetupError<E> {
+    Bus(E),
+    /// Something else answered at the MMC5983MA's address.
+    UnknownDevice(u8),
+}
+
+pub struct Mmc5983ma<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C: I2c> Mmc5983ma<I2C> {
+    pub fn new<D: DelayNs>(
+        i2c: I2C,
+        delay: &mut D,
+    ) -> Result<Mmc5983ma<I2C>, SetupError<I2C::Error>> {
+        let mut mag = Mmc5983ma { i2c };
+        let mut id = [0];
+        mag.i2c
+            .write_read(ADDRESS, &[REG_PRODUCT_ID], &mut id)
+            .map_err(SetupError::Bus)?;
+        if id[0] != PRODUCT_ID {
+            return Err(SetupError::UnknownDevice(id[0]));
+        }
+        mag.write_register(REG_CONTROL1, SW_RST)
+            .map_err(SetupError::Bus)?;
+        delay.delay_ms(RESET_MS);
+        mag.write_register(REG_CONTROL0, AUTO_SR_EN)
+            .map_err(SetupError::Bus)?;
+        mag.write_register(REG_CONTROL2, EN_PRD_SET | PRD_SET_100 | CMM_EN | CM_FREQ)
+            .map_err(SetupError::Bus)?;
+        Ok(mag)
+    }
+
+    /// Returns the field in nT, in the sensor's own axes, if a new
+    /// measurement has finished since the last call.
+    pub fn read(&mut self) -> Result<Option<Measurement>, I2C::Error> {
+        let mut status = [0];
+        self.i2c.write_read(ADDRESS, &[REG_STATUS], &mut status)?;
+        if status[0] & MEAS_M_DONE == 0 {
+            return Ok(None);
+        }
+        // X, Y and Z high and middle bytes, then the two low bits of each.
+        let mut out = [0u8; 7];
+        self.i2c.write_read(ADDRESS, &[REG_XOUT0], &mut out)?;
+        self.write_register(REG_STATUS, MEAS_M_DONE)?;
+        let axis = |high: usize, low_shift: u8| {
+            let raw = (out[high] as i32) << 10
+                | (out[high + 1] as i32) << 2
+                | (out[6] >> low_shift & 0b11) as i32;
+            (raw - NULL_FIELD) * NT_PER_COUNT_NUM / NT_PER_COUNT_DEN
+        };
+        Ok(Some(Measurement {
+            x: axis(0, 6),
+            y: axis(2, 4),
+            z: axis(4, 2),
+        }))
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), I2C::Error> {
+        self.i2c.write(ADDRESS, &[register, value])
+    }
+}
@@ -0,0 +1,209 @@
+//! The Nordic UART Service, with the `ble` feature: the serial link carried
+//! over a Bluetooth LE connection, so the board can be used with no cable
+//! at all. Whatever the board sends over serial is also notified on the TX
+//! characteristic to a client that has subscribed to it, and lines written
+//! to the RX characteristic are taken as commands, as those from serial
+//! are. The bytes are the same either way, so the host's decoder reads
+//! them unchanged.
+//!
+//! Output is copied as the UARTE sends it, a chunk at a time, and notified
+//! in pieces as long as the connection's MTU allows. Unless the central
+//! agrees to a large MTU and a short connection interval, the connection is
+//! slower than the serial port, so while [`QUEUE_LEN`] bytes are waiting,
+//! chunks are dropped whole and counted with the serial port's in the
+//! `Status:` line's `dropped`.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use critical_section::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use heapless::{Deque, Vec};
+use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
+use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Properties};
+use nrf_softdevice::ble::gatt_server::{self, NotifyValueError, RegisterError};
+use nrf_softdevice::ble::{Connection, Uuid};
+use nrf_softdevice::{RawError, Softdevice};
+use rtt_target::rprintln;
+
+use crate::ble::Event;
+use crate::command::{parse_command, LineReader, SerialCommand};
+
+/// The service's UUID, 6E400001-B5A3-F393-E0A9-E50E24DCCA9E, little-endian.
+/// The characteristics' differ from it in the third byte from the end.
+pub const SERVICE_UUID: [u8; 16] = [
+    0x9e, 0xca, 0xdc, 0x24, 0x0e, 0xe5, 0xa9, 0xe0, 0x93, 0xf3, 0xa3, 0xb5, 0x01, 0x00, 0x40, 0x6e,
+];
+const RX_UUID: u8 = 0x02;
+const TX_UUID: u8 = 0x03;
+/// The ATT MTU the SoftDevice agrees to at most, which leaves 244 bytes for
+/// a notification's value.
+pub const ATT_MTU: u16 = 247;
+/// Longest value written or notified.
+const VALUE_LEN: usize = ATT_MTU as usize - 3;
+/// Bytes of output waiting to be notified.
+const QUEUE_LEN: usize = 1024;
+/// Longest command line, as over serial.
+const LINE_LEN: usize = 64;
+
+/// Output waiting to be notified, while a client is subscribed.
+static QUEUE: Mutex<RefCell<Deque<u8, QUEUE_LEN>>> = Mutex::new(RefCell::new(Deque::new()));
+static SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+/// Output has been queued.
+static QUEUED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// The SoftDevice has sent notifications, making room for more.
+static SENT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn uuid(kind: u8) -> Uuid {
+    let mut uuid = SERVICE_UUID;
+    uuid[12] = kind;
+    Uuid::new_128(&uuid)
+}
+
+/// Queues a chunk of output for the subscribed client, if there is one.
+pub fn mirror(chunk: &[u8]) {
+    if chunk.is_empty() || !SUBSCRIBED.load(Ordering::Acquire) {
+        return;
+    }
+    let queued = critical_section::with(|cs| {
+        let mut queue = QUEUE.borrow(cs).borrow_mut();
+        if chunk.len() > queue.capacity() - queue.len() {
+            return false;
+        }
+        for &byte in chunk {
+            queue.push_back(byte).ok();
+        }
+        true
+    });
+    match queued {
+        true => QUEUED.signal(()),
+        false => {
+            DROPPED.fetch_add(chunk.len() as u32, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the bytes dropped since the last call.
+pub fn take_dropped() -> u32 {
+    DROPPED.swap(0, Ordering::Relaxed)
+}
+
+/// The service's characteristics, as registered with the SoftDevice.
+pub struct Service {
+    rx: u16,
+    tx: u16,
+    tx_cccd: u16,
+    /// The value last written to RX.
+    written: RefCell<Vec<u8, VALUE_LEN>>,
+    /// The command line being written.
+    line: RefCell<LineReader<LINE_LEN>>,
+}
+
+impl Service {
+    pub fn new(sd: &mut Softdevice) -> Result<Service, RegisterError> {
+        let mut service = ServiceBuilder::new(sd, Uuid::new_128(&SERVICE_UUID))?;
+        let rx = service
+            .add_characteristic(
+                uuid(RX_UUID),
+                Attribute::new([0u8; 0]).variable_len(VALUE_LEN as u16),
+                Metadata::new(Properties::new().write().write_without_response()),
+            )?
+            .build();
+        let tx = service
+            .add_characteristic(
+                uuid(TX_UUID),
+                Attribute::new([0u8; 0]).variable_len(VALUE_LEN as u16),
+                Metadata::new(Properties::new().notify()),
+            )?
+            .build();
+        service.build();
+        Ok(Service {
+            rx: rx.value_handle,
+            tx: tx.value_handle,
+            tx_cccd: tx.cccd_handle,
+            written: RefCell::new(Vec::new()),
+            line: RefCell::new(LineReader::new()),
+        })
+    }
+
+    /// Handles a write to one of the service's attributes, if it is one.
+    pub fn on_write(&self, handle: u16, data: &[u8]) -> Option<Event> {
+        if handle == self.tx_cccd {
+            let notify = data.first().is_some_and(|bits| bits & 1 != 0);
+            SUBSCRIBED.store(notify, Ordering::Release);
+            return None;
+        }
+        if handle != self.rx {
+            return None;
+        }
+        let mut written = self.written.borrow_mut();
+        written.clear();
+        written.extend_from_slice(data).ok()?;
+        Some(Event::Written)
+    }
+
+    /// Hands each command line finished by what was last written to
+    /// `on_command`. Lines end as over serial, and one too long for the
+    /// buffer is cut short.
+    pub fn commands(&self, mut on_command: impl FnMut(SerialCommand)) {
+        let mut reader = self.line.borrow_mut();
+        for &byte in self.written.borrow().iter() {
+            if let Some(line) = reader.push(byte) {
+                rprintln!("Received over BLE: {:?}", core::str::from_utf8(line));
+                on_command(parse_command(line));
+            }
+        }
+    }
+
+    /// Says that notifications have gone, making room for more.
+    pub fn sent(&self) {
+        SENT.signal(());
+    }
+
+    /// Notifies the queued output, as it comes, until the connection is
+    /// lost.
+    pub async fn send(&self, conn: &Connection) {
+        let mut piece = [0u8; VALUE_LEN];
+        loop {
+            QUEUED.wait().await;
+            loop {
+                let len = usize::from(conn.att_mtu() - 3).min(piece.len());
+                let len = critical_section::with(|cs| {
+                    let mut queue = QUEUE.borrow(cs).borrow_mut();
+                    let mut taken = 0;
+                    while taken < len {
+                        let Some(byte) = queue.pop_front() else {
+                            break;
+                        };
+                        piece[taken] = byte;
+                        taken += 1;
+                    }
+                    taken
+                });
+                if len == 0 {
+                    break;
+                }
+                loop {
+                    match gatt_server::notify_value(conn, self.tx, &piece[..len]) {
+                        Ok(()) => break,
+                        // The SoftDevice's buffers are full until some go.
+                        Err(NotifyValueError::Raw(RawError::Resources)) => SENT.wait().await,
+                        Err(NotifyValueError::Disconnected) => return,
+                        Err(e) => {
+                            rprintln!("Notifying failed: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Forgets the connection's subscription, output and unfinished line.
+    pub fn disconnected(&self) {
+        SUBSCRIBED.store(false, Ordering::Release);
+        critical_section::with(|cs| QUEUE.borrow(cs).borrow_mut().clear());
+        self.line.borrow_mut().clear();
+    }
+}
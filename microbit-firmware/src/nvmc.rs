@@ -0,0 +1,86 @@
+//! The nRF51822's NVMC, as the `NorFlash` that `Storage` writes through on
+//! the micro:bit v1, where the HAL has no driver for it. It takes the same
+//! arguments as the HAL's `Nvmc` on the v2, but erases the nRF51's 1K
+//! pages, four to each of `Storage`'s.
+
+use core::convert::TryInto;
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+use microbit::pac::NVMC;
+
+const PAGE_SIZE: usize = 1024;
+const WORD_SIZE: usize = 4;
+
+pub struct Nvmc {
+    nvmc: NVMC,
+    storage: &'static mut [u8],
+}
+
+impl Nvmc {
+    /// Takes over `storage`, which must start and end on a page boundary.
+    pub fn new(nvmc: NVMC, storage: &'static mut [u8]) -> Nvmc {
+        assert!((storage.as_ptr() as usize).is_multiple_of(PAGE_SIZE));
+        assert!(storage.len().is_multiple_of(PAGE_SIZE));
+        Nvmc { nvmc, storage }
+    }
+}
+
+fn wait_ready(nvmc: &NVMC) {
+    while nvmc.ready.read().ready().is_busy() {}
+}
+
+impl ErrorType for Nvmc {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for Nvmc {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        wait_ready(&self.nvmc);
+        bytes.copy_from_slice(&self.storage[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.storage.len()
+    }
+}
+
+impl NorFlash for Nvmc {
+    const WRITE_SIZE: usize = WORD_SIZE;
+    const ERASE_SIZE: usize = PAGE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.nvmc.config.write(|w| w.wen().een());
+        for page in self.storage[from as usize..to as usize].chunks_exact_mut(PAGE_SIZE) {
+            let address = page.as_mut_ptr() as u32;
+            self.nvmc.erasepage().write(|w| unsafe { w.bits(address) });
+            wait_ready(&self.nvmc);
+        }
+        self.nvmc.config.write(|w| w.wen().ren());
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        self.nvmc.config.write(|w| w.wen().wen());
+        for (target, word) in self.storage[offset..offset + bytes.len()]
+            .chunks_exact_mut(WORD_SIZE)
+            .zip(bytes.chunks_exact(WORD_SIZE))
+        {
+            wait_ready(&self.nvmc);
+            let word = u32::from_ne_bytes(word.try_into().unwrap());
+            unsafe { core::ptr::write_volatile(target.as_mut_ptr() as *mut u32, word) };
+            cortex_m::asm::dmb();
+        }
+        wait_ready(&self.nvmc);
+        self.nvmc.config.write(|w| w.wen().ren());
+        Ok(())
+    }
+}
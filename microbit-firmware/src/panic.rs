@@ -0,0 +1,56 @@
+//! Panic handler. Rather than freezing invisibly, the firmware shows a sad
+//! face on the matrix, sends the panic message over RTT and serial (as a
+//! `Panic: ...` line), optionally saves it to flash to be reported on the
+//! next boot, and resets. Once the SoftDevice is enabled, with the `ble`
+//! feature, the message isn't saved: the flash is the SoftDevice's, and it
+//! can't be called with interrupts masked.
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use rtt_target::rprintln;
+use sphere_mapping_core::config::PANIC_HOLD_MS;
+use sphere_mapping_core::led::SAD_FACE;
+use sphere_mapping_core::panic_log::PanicLog;
+
+use crate::board::write_polled;
+use crate::display;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    let mut log = PanicLog::new();
+    write!(log, "{}", info.message()).ok();
+    if let Some(location) = info.location() {
+        write!(log, " at {}:{}", location.file(), location.line()).ok();
+    }
+    rprintln!("Panic: {}", log);
+    write_polled(b"Panic: ");
+    write_polled(log.as_str().as_bytes());
+    write_polled(b"\r\n");
+
+    #[cfg(feature = "panic-log")]
+    save(&log);
+
+    display::show_polled(SAD_FACE, PANIC_HOLD_MS);
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+#[cfg(feature = "panic-log")]
+fn save(log: &PanicLog) {
+    use sphere_mapping_core::storage::{storage_start, Storage, STORAGE_LEN};
+
+    use crate::board;
+
+    #[cfg(feature = "ble")]
+    if crate::softdevice::enabled() {
+        return;
+    }
+    // Whoever owned the NVMC is never going to run again.
+    let nvmc = unsafe { microbit::pac::Peripherals::steal() }.NVMC;
+    let flash = board::flash(nvmc, storage_start(board::FLASH_LEN), STORAGE_LEN);
+    let mut storage = Storage::new(flash, 0).with_supply_check(crate::supply::ok);
+    if let Err(e) = log.save(&mut storage) {
+        rprintln!("Failed to save panic message: {:?}", e);
+    }
+}
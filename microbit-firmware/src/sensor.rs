@@ -0,0 +1,545 @@
+//! The LSM303AGR, as the `MagSource` and `AccelSource` the compass logic
+//! runs on, and its recovery paths:
+//!
+//! - a failed read is retried on the next data-ready edge, unless SDA is
+//!   being held low, in which case the bus is cleared and the sensor set up
+//!   again straight away;
+//! - after `MAX_FAILURES` failures in a row the sensor is set up from
+//!   scratch, and if that fails too it stays stopped until a later `poll`
+//!   manages to restart it;
+//! - a failed read after which the LSM303AGR no longer answers with its
+//!   WHO_AM_I values (a brownout or glitch on the internal rail) sets it up
+//!   from scratch straight away, as its configuration is likely lost;
+//! - a sensor that stops producing data without reporting an error is set
+//!   up again once five sample periods have passed without a complete
+//!   reading;
+//! - a magnetometer that keeps failing while the accelerometer works is
+//!   dropped, leaving an accelerometer-only sensor that gives the
+//!   magnetometer another chance every `MAG_RETRY_SAMPLES` samples.
+//!
+//! In battery mode the accelerometer slows to 1 Hz and the magnetometer to
+//! its slowest 10 Hz, so a full reading, paced by the accelerometer, comes
+//! once a second. In high-rate mode both run at 100 Hz.
+//!
+//! Before the board powers down, the sensor can be left watching for
+//! movement on its own, raising the same interrupt line to wake the board.
+//! The `lsm303agr` driver has no API for the accelerometer's motion
+//! interrupt, so its registers are written directly.
+//!
+//! With the `mmc5983ma` feature, the field can come from an MMC5983MA on
+//! the edge connector instead, still paced by the LSM303AGR's data-ready
+//! line.
+
+use core::fmt;
+use embedded_hal::delay::DelayNs;
+use lsm303agr::interface::I2cInterface;
+use lsm303agr::mode::{MagContinuous, MagOneShot};
+use lsm303agr::{
+    AccelMode, AccelOutputDataRate, AccelScale, Interrupt, Lsm303agr, MagMode, MagOutputDataRate,
+};
+#[cfg(feature = "mmc5983ma")]
+use microbit::hal::twim::{self, Twim};
+use sphere_mapping_core::calibration::Measurement;
+use sphere_mapping_core::config;
+use sphere_mapping_core::device::{Acceleration, Sample, SampleSource};
+use sphere_mapping_core::settings::PowerMode;
+
+use crate::board::{self, I2c, I2cError};
+use crate::clock;
+use crate::error::{Error, SensorError};
+#[cfg(feature = "mmc5983ma")]
+use crate::i2c_bus::{ExternalBus, SharedI2c};
+use crate::i2c_recovery::{clear_bus, sda_held_low};
+#[cfg(feature = "mmc5983ma")]
+use crate::mmc5983ma::{Mmc5983ma, SetupError};
+
+const MAX_FAILURES: u8 = 3;
+/// Sample periods without a complete reading before the sensor is taken to
+/// have stopped.
+const TIMEOUT_PERIODS: u64 = 5;
+/// Samples between attempts to bring back a dropped magnetometer (about
+/// 10 s).
+const MAG_RETRY_SAMPLES: u32 = 10 * config::SAMPLE_RATE_HZ;
+/// Both halves run at the configured sample rate, which the config has
+/// already checked they support.
+const ACCEL_ODR: AccelOutputDataRate =
+    AccelOutputDataRate::from_hertz(config::SAMPLE_RATE_HZ as u16).unwrap();
+const MAG_ODR: MagOutputDataRate =
+    MagOutputDataRate::from_hertz(config::SAMPLE_RATE_HZ as u16).unwrap();
+const ACCEL_SCALE: AccelScale = match config::ACCEL_SCALE_G {
+    4 => AccelScale::G4,
+    8 => AccelScale::G8,
+    16 => AccelScale::G16,
+    _ => AccelScale::G2,
+};
+
+const ACCEL_ADDRESS: u8 = 0x19;
+const MAG_ADDRESS: u8 = 0x1E;
+const CTRL_REG1_A: u8 = 0x20;
+const CTRL_REG2_A: u8 = 0x21;
+const CTRL_REG3_A: u8 = 0x22;
+const CTRL_REG4_A: u8 = 0x23;
+const CTRL_REG5_A: u8 = 0x24;
+const REFERENCE_A: u8 = 0x26;
+const INT1_CFG_A: u8 = 0x30;
+const INT1_SRC_A: u8 = 0x31;
+const INT1_THS_A: u8 = 0x32;
+const INT1_DURATION_A: u8 = 0x33;
+const CFG_REG_A_M: u8 = 0x60;
+const CFG_REG_C_M: u8 = 0x62;
+/// 10 Hz, low-power mode, all axes on.
+const WAKE_CTRL_REG1_A: u8 = 0x2F;
+/// High-pass filter on the motion interrupt, so it sees changes rather
+/// than gravity.
+const WAKE_CTRL_REG2_A: u8 = 0x01;
+/// Motion interrupt on INT1, in place of data-ready.
+const WAKE_CTRL_REG3_A: u8 = 0x40;
+/// Latch the motion interrupt until INT1_SRC_A is read.
+const WAKE_CTRL_REG5_A: u8 = 0x08;
+/// 80 mg, in 16 mg steps at 2 g full scale.
+const WAKE_THRESHOLD: u8 = 5;
+/// Any axis going high.
+const WAKE_INT1_CFG_A: u8 = 0x2A;
+/// Magnetometer idle.
+const MAG_IDLE: u8 = 0x03;
+
+type Lsm<MODE> = Lsm303agr<I2cInterface<I2c>, MODE>;
+#[cfg(feature = "mmc5983ma")]
+type ExternalMag = Mmc5983ma<SharedI2c<'static, Twim<microbit::pac::TWIM1>>>;
+
+enum State {
+    Running(Lsm<MagContinuous>),
+    Stopped(I2c),
+}
+
+/// A complete sample from every sensor in use: the field in nT and the
+/// acceleration in mg.
+pub enum Reading {
+    Full(Sample),
+    AccelOnly(Acceleration),
+}
+
+/// What `poll` did about a failure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    Retry,
+    Restarted,
+    BusCleared,
+    Stopped,
+    AccelOnly,
+    Reconfigured,
+}
+
+pub struct Failure {
+    pub error: Error,
+    pub recovery: Recovery,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let recovery = match self.recovery {
+            Recovery::Retry => "retrying",
+            Recovery::Restarted => "sensor restarted",
+            Recovery::BusCleared => "bus cleared and sensor restarted",
+            Recovery::Stopped => "sensor stopped",
+            Recovery::AccelOnly => "continuing without the magnetometer",
+            Recovery::Reconfigured => "sensor lost its identity, configuration restored",
+        };
+        write!(f, "{}, {}", self.error, recovery)
+    }
+}
+
+impl Failure {
+    /// Whether the sensor was set up again and is running.
+    pub fn recovered(&self) -> bool {
+        matches!(
+            self.recovery,
+            Recovery::Restarted | Recovery::BusCleared | Recovery::Reconfigured
+        )
+    }
+}
+
+pub struct Sensor {
+    /// Only `None` while `restart` is swapping the driver out.
+    state: Option<State>,
+    power: PowerMode,
+    failures: u8,
+    mag_failures: u8,
+    mag_available: bool,
+    mag_retry_countdown: u32,
+    /// When the last complete reading was taken, or the sensor set up.
+    last_reading_at: u64,
+    /// The half of a pair picked up so far, with when it became ready.
+    mag: Option<(Measurement, u64)>,
+    accel: Option<(Acceleration, u64)>,
+    #[cfg(feature = "mmc5983ma")]
+    external_mag: Option<ExternalMag>,
+}
+
+impl Sensor {
+    /// Takes over the sensor's bus. The sensor starts out stopped; call
+    /// `restart` to set it up.
+    pub fn new(i2c: I2c) -> Sensor {
+        Sensor {
+            state: Some(State::Stopped(i2c)),
+            power: PowerMode::Normal,
+            failures: 0,
+            mag_failures: 0,
+            mag_available: true,
+            mag_retry_countdown: 0,
+            last_reading_at: 0,
+            mag: None,
+            accel: None,
+            #[cfg(feature = "mmc5983ma")]
+            external_mag: None,
+        }
+    }
+
+    /// Takes the field from an MMC5983MA on the edge connector's I2C bus
+    /// instead of the LSM303AGR. If none answers, the LSM303AGR's
+    /// magnetometer stays in use.
+    #[cfg(feature = "mmc5983ma")]
+    pub fn attach_mag<D: DelayNs>(
+        &mut self,
+        bus: &'static ExternalBus,
+        delay: &mut D,
+    ) -> Result<(), SetupError<twim::Error>> {
+        self.external_mag = Some(Mmc5983ma::new(SharedI2c::new(bus), delay)?);
+        Ok(())
+    }
+
+    /// Sets the sensor up from scratch, with both sensors running at the
+    /// power mode's rates and signalling new data on the shared interrupt
+    /// line. The bus is cleared first if SDA is stuck; returns whether it
+    /// was.
+    pub fn restart<D: DelayNs>(&mut self, delay: &mut D) -> Result<bool, Error> {
+        let i2c = match self.state.take() {
+            Some(State::Running(lsm)) => lsm.destroy(),
+            Some(State::Stopped(i2c)) => i2c,
+            None => return Err(Error::SensorStopped),
+        };
+        let (i2c, bus_was_stuck) = clear_bus(i2c, delay);
+        self.failures = 0;
+        self.mag_failures = 0;
+        self.mag_available = true;
+        self.mag = None;
+        self.accel = None;
+        self.last_reading_at = clock::now();
+        match start(i2c, delay, self.power) {
+            Ok(lsm) => {
+                self.state = Some(State::Running(lsm));
+                Ok(bus_was_stuck)
+            }
+            Err((e, i2c)) => {
+                self.state = Some(State::Stopped(i2c));
+                Err(Error::SensorInit(e))
+            }
+        }
+    }
+
+    /// Leaves the LSM303AGR doing nothing but watching for movement: the
+    /// magnetometer idle, and the accelerometer at 10 Hz in low-power mode,
+    /// holding its interrupt line high once any axis changes by more than
+    /// about 80 mg. The sensor is stopped afterwards, and `restart` sets it
+    /// up for sampling again.
+    pub fn arm_wake_on_motion(&mut self) -> Result<(), Error> {
+        let mut i2c = match self.state.take() {
+            Some(State::Running(lsm)) => lsm.destroy(),
+            Some(State::Stopped(i2c)) => i2c,
+            None => return Err(Error::SensorStopped),
+        };
+        let armed = arm_wake(&mut i2c);
+        self.state = Some(State::Stopped(i2c));
+        armed.map_err(Error::WakeSetup)
+    }
+
+    /// Switches the rates to those of `power` at the next `restart`.
+    pub fn set_power(&mut self, power: PowerMode) {
+        self.power = power;
+    }
+
+    fn data_timeout_us(&self) -> u64 {
+        TIMEOUT_PERIODS * 1_000_000 / self.power.sample_rate_hz() as u64
+    }
+
+    /// Picks up whichever sensors have new data, taking it to have become
+    /// ready at `ready_at`. Returns a reading once every sensor in use has
+    /// produced one, and `None` while waiting for the rest.
+    pub fn poll<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+        ready_at: u64,
+    ) -> Result<Option<Reading>, Failure> {
+        let lsm = match self.state.as_mut() {
+            Some(State::Running(lsm)) => lsm,
+            _ => {
+                return match self.restart(delay) {
+                    Ok(false) => Ok(None),
+                    Ok(true) => Err(Failure {
+                        error: Error::BusStuck,
+                        recovery: Recovery::BusCleared,
+                    }),
+                    Err(error) => Err(Failure {
+                        error,
+                        recovery: Recovery::Stopped,
+                    }),
+                }
+            }
+        };
+
+        match read_accel(lsm) {
+            Ok(Some(accel)) => self.accel = Some((accel, ready_at)),
+            Ok(None) => {}
+            Err(e) => return Err(self.fail(Error::Accel(e), delay)),
+        }
+        if self.mag_available {
+            match self.read_new_field() {
+                Ok(Some(mag)) => self.mag = Some((mag, ready_at)),
+                Ok(None) => {}
+                Err(e) => return Err(self.fail_mag(e, delay)),
+            }
+        }
+
+        let reading = match (self.mag, self.accel) {
+            (Some((mag, mag_at)), Some((accel, accel_at))) if self.mag_available => {
+                self.mag_failures = 0;
+                Reading::Full(Sample::pair(mag, mag_at, accel, accel_at))
+            }
+            (_, Some((accel, _))) if !self.mag_available => {
+                self.retry_mag_later();
+                Reading::AccelOnly(accel)
+            }
+            _ if clock::now() - self.last_reading_at >= self.data_timeout_us() => {
+                return Err(self.reset(Error::SensorTimeout, delay));
+            }
+            _ => return Ok(None),
+        };
+        self.failures = 0;
+        self.last_reading_at = clock::now();
+        self.mag = None;
+        self.accel = None;
+        Ok(Some(reading))
+    }
+
+    /// Reads the magnetometer in use, if it has a new sample.
+    fn read_new_field(&mut self) -> Result<Option<Measurement>, Error> {
+        #[cfg(feature = "mmc5983ma")]
+        if let Some(mag) = self.external_mag.as_mut() {
+            return mag.read().map_err(Error::ExternalMag);
+        }
+        read_mag(self.running()?).map_err(Error::Mag)
+    }
+
+    fn running(&mut self) -> Result<&mut Lsm<MagContinuous>, Error> {
+        match self.state.as_mut() {
+            Some(State::Running(lsm)) => Ok(lsm),
+            _ => Err(Error::SensorStopped),
+        }
+    }
+
+    fn fail<D: DelayNs>(&mut self, error: Error, delay: &mut D) -> Failure {
+        self.failures += 1;
+        if self.failures >= MAX_FAILURES || sda_held_low(board::SDA_PIN) {
+            return self.reset(error, delay);
+        }
+        if !self.identifies() {
+            return self.reconfigure(error, delay);
+        }
+        Failure {
+            error,
+            recovery: Recovery::Retry,
+        }
+    }
+
+    /// Whether the LSM303AGR still answers with the WHO_AM_I values of both
+    /// its halves.
+    fn identifies(&mut self) -> bool {
+        let Ok(lsm) = self.running() else {
+            return false;
+        };
+        matches!(lsm.accelerometer_id(), Ok(id) if id.is_correct())
+            && matches!(lsm.magnetometer_id(), Ok(id) if id.is_correct())
+    }
+
+    /// Sets the sensor up from scratch after it stopped identifying itself.
+    fn reconfigure<D: DelayNs>(&mut self, error: Error, delay: &mut D) -> Failure {
+        let mut failure = self.reset(error, delay);
+        if failure.recovery == Recovery::Restarted {
+            failure.recovery = Recovery::Reconfigured;
+        }
+        failure
+    }
+
+    /// Sets the sensor up from scratch after `error`, and says how that
+    /// went.
+    pub fn reset<D: DelayNs>(&mut self, error: Error, delay: &mut D) -> Failure {
+        let recovery = match self.restart(delay) {
+            Ok(false) => Recovery::Restarted,
+            Ok(true) => Recovery::BusCleared,
+            Err(_) => Recovery::Stopped,
+        };
+        Failure { error, recovery }
+    }
+
+    fn fail_mag<D: DelayNs>(&mut self, error: Error, delay: &mut D) -> Failure {
+        // Not the magnetometer's fault if the whole sensor has reset.
+        if !self.identifies() {
+            return self.reconfigure(error, delay);
+        }
+        self.mag_failures += 1;
+        let recovery = if self.mag_failures < MAX_FAILURES {
+            Recovery::Retry
+        } else {
+            self.mag_available = false;
+            self.mag = None;
+            self.mag_retry_countdown = MAG_RETRY_SAMPLES;
+            Recovery::AccelOnly
+        };
+        Failure { error, recovery }
+    }
+
+    /// Counts down to the next magnetometer attempt. A single failure is
+    /// then enough to drop it again.
+    fn retry_mag_later(&mut self) {
+        self.mag_retry_countdown = self.mag_retry_countdown.saturating_sub(1);
+        if self.mag_retry_countdown == 0 {
+            self.mag_available = true;
+            self.mag_failures = MAX_FAILURES - 1;
+        }
+    }
+}
+
+impl SampleSource for Sensor {
+    type Error = Error;
+
+    /// Blocks until both sensors have a new sample, reading each as soon as
+    /// it is ready, for at most five sample periods.
+    fn read_sample(&mut self) -> Result<Sample, Error> {
+        self.mag = None;
+        self.accel = None;
+        let started = clock::now();
+        loop {
+            let now = clock::now();
+            if now - started >= self.data_timeout_us() {
+                return Err(Error::SensorTimeout);
+            }
+            if let Some(accel) = read_accel(self.running()?).map_err(Error::Accel)? {
+                self.accel = Some((accel, now));
+            }
+            if let Some(mag) = self.read_new_field()? {
+                self.mag = Some((mag, now));
+            }
+            if let (Some((mag, mag_at)), Some((accel, accel_at))) = (self.mag, self.accel) {
+                self.mag = None;
+                self.accel = None;
+                self.last_reading_at = now;
+                return Ok(Sample::pair(mag, mag_at, accel, accel_at));
+            }
+        }
+    }
+}
+
+fn start<D: DelayNs>(
+    mut i2c: I2c,
+    delay: &mut D,
+    power: PowerMode,
+) -> Result<Lsm<MagContinuous>, (SensorError, I2c)> {
+    if let Err(e) = disarm_wake(&mut i2c) {
+        return Err((SensorError::Comm(e), i2c));
+    }
+    let mut lsm = Lsm303agr::new_with_i2c(i2c);
+    if let Err(e) = configure(&mut lsm, delay, power) {
+        return Err((e, lsm.destroy()));
+    }
+    let mut lsm = lsm
+        .into_mag_continuous()
+        .map_err(|e| (e.error, e.dev.destroy()))?;
+    match lsm
+        .acc_enable_interrupt(Interrupt::DataReady1)
+        .and_then(|()| lsm.mag_enable_int())
+    {
+        Ok(()) => Ok(lsm),
+        Err(e) => Err((e, lsm.destroy())),
+    }
+}
+
+fn arm_wake(i2c: &mut I2c) -> Result<(), I2cError> {
+    write_register(i2c, MAG_ADDRESS, CFG_REG_C_M, 0)?;
+    write_register(i2c, MAG_ADDRESS, CFG_REG_A_M, MAG_IDLE)?;
+    write_register(i2c, ACCEL_ADDRESS, CTRL_REG1_A, WAKE_CTRL_REG1_A)?;
+    write_register(i2c, ACCEL_ADDRESS, CTRL_REG2_A, WAKE_CTRL_REG2_A)?;
+    write_register(i2c, ACCEL_ADDRESS, CTRL_REG4_A, 0)?;
+    write_register(i2c, ACCEL_ADDRESS, CTRL_REG5_A, WAKE_CTRL_REG5_A)?;
+    write_register(i2c, ACCEL_ADDRESS, INT1_THS_A, WAKE_THRESHOLD)?;
+    write_register(i2c, ACCEL_ADDRESS, INT1_DURATION_A, 0)?;
+    // Reading the reference sets the high-pass filter to the current
+    // orientation, so the board's resting tilt doesn't wake it straight
+    // away.
+    read_register(i2c, ACCEL_ADDRESS, REFERENCE_A)?;
+    write_register(i2c, ACCEL_ADDRESS, INT1_CFG_A, WAKE_INT1_CFG_A)?;
+    write_register(i2c, ACCEL_ADDRESS, CTRL_REG3_A, WAKE_CTRL_REG3_A)?;
+    // Clears anything latched while the interrupt was being set up.
+    read_register(i2c, ACCEL_ADDRESS, INT1_SRC_A)?;
+    Ok(())
+}
+
+/// Undoes `arm_wake`, where `configure` doesn't already, and releases the
+/// latched interrupt that woke the board.
+fn disarm_wake(i2c: &mut I2c) -> Result<(), I2cError> {
+    write_register(i2c, ACCEL_ADDRESS, INT1_CFG_A, 0)?;
+    write_register(i2c, ACCEL_ADDRESS, CTRL_REG2_A, 0)?;
+    write_register(i2c, ACCEL_ADDRESS, CTRL_REG5_A, 0)?;
+    read_register(i2c, ACCEL_ADDRESS, INT1_SRC_A)?;
+    Ok(())
+}
+
+fn write_register(i2c: &mut I2c, address: u8, register: u8, value: u8) -> Result<(), I2cError> {
+    i2c.write(address, &[register, value])
+}
+
+fn read_register(i2c: &mut I2c, address: u8, register: u8) -> Result<u8, I2cError> {
+    let mut value = [0];
+    i2c.write_then_read(address, &[register], &mut value)?;
+    Ok(value[0])
+}
+
+fn configure<D: DelayNs>(
+    lsm: &mut Lsm<MagOneShot>,
+    delay: &mut D,
+    power: PowerMode,
+) -> Result<(), SensorError> {
+    let (accel_odr, mag_odr) = match power {
+        PowerMode::Normal => (ACCEL_ODR, MAG_ODR),
+        PowerMode::Battery => (AccelOutputDataRate::Hz1, MagOutputDataRate::Hz10),
+        PowerMode::HighRate => (AccelOutputDataRate::Hz100, MagOutputDataRate::Hz100),
+    };
+    lsm.init()?;
+    lsm.set_accel_mode_and_odr(delay, AccelMode::Normal, accel_odr)?;
+    lsm.set_accel_scale(ACCEL_SCALE)?;
+    lsm.set_mag_mode_and_odr(delay, MagMode::LowPower, mag_odr)?;
+    Ok(())
+}
+
+fn read_accel(lsm: &mut Lsm<MagContinuous>) -> Result<Option<Acceleration>, SensorError> {
+    if !lsm.accel_status()?.xyz_new_data() {
+        return Ok(None);
+    }
+    let accel = lsm.acceleration()?;
+    Ok(Some(Acceleration {
+        x: accel.x_mg(),
+        y: accel.y_mg(),
+        z: accel.z_mg(),
+    }))
+}
+
+fn read_mag(lsm: &mut Lsm<MagContinuous>) -> Result<Option<Measurement>, SensorError> {
+    if !lsm.mag_status()?.xyz_new_data() {
+        return Ok(None);
+    }
+    let field = lsm.magnetic_field()?;
+    Ok(Some(Measurement {
+        x: field.x_nt(),
+        y: field.y_nt(),
+        z: field.z_nt(),
+    }))
+}
@@ -1,15 +1,76 @@
+//! The UARTE: bytes received one at a time through the interrupt, and
+//! output queued and sent in the background in EasyDMA transfers of up to
+//! `TX_CHUNK_LEN` bytes, so the CPU is only interrupted once per chunk
+//! rather than once per byte. That is what keeps up with 100 Hz samples.
+//!
+//! The micro:bit v1 has a plain UART instead, driven by `uart.rs` behind
+//! the same interface, with the `TxQueue` here.
+
 use core::fmt;
+#[cfg(feature = "v2")]
 use core::ptr::addr_of_mut;
+#[cfg(feature = "v2")]
+use core::sync::atomic::{compiler_fence, Ordering};
+#[cfg(feature = "v2")]
 use embedded_hal_nb::nb;
-use embedded_hal_nb::serial::{Error as SerialError, ErrorType, Read, Write};
-use embedded_io::{Read as EmbeddedIoRead, ReadReady, Write as EmbeddedIoWrite};
-use microbit::hal::uarte::{Instance, Uarte, UarteRx, UarteTx};
+use embedded_hal_nb::serial::Error as SerialError;
+#[cfg(feature = "v2")]
+use embedded_hal_nb::serial::{ErrorType, Read};
+#[cfg(feature = "v2")]
+use embedded_io::{Read as EmbeddedIoRead, ReadReady};
+use heapless::Deque;
+#[cfg(feature = "v1")]
+use microbit::hal::uart::Baudrate;
+#[cfg(feature = "v2")]
+use microbit::hal::uarte::{Baudrate, Instance, Uarte, UarteRx, UarteTx};
+
+/// Bytes waiting in `TxQueue`: a quarter of a second of `Measurement:`
+/// lines at 100 Hz, which is plenty once the link keeps up.
+#[cfg(feature = "v2")]
+const TX_QUEUE_LEN: usize = 2048;
+/// Half that on the v1, whose 16K of RAM has to leave room for the stack.
+#[cfg(feature = "v1")]
+const TX_QUEUE_LEN: usize = 1024;
+/// Bytes sent per EasyDMA transfer.
+#[cfg(feature = "v2")]
+const TX_CHUNK_LEN: usize = 64;
+
+/// The UART's or UARTE's setting for a baud rate from the config.
+pub const fn baudrate(bps: u32) -> Baudrate {
+    match bps {
+        9_600 => Baudrate::BAUD9600,
+        19_200 => Baudrate::BAUD19200,
+        38_400 => Baudrate::BAUD38400,
+        57_600 => Baudrate::BAUD57600,
+        115_200 => Baudrate::BAUD115200,
+        230_400 => Baudrate::BAUD230400,
+        460_800 => Baudrate::BAUD460800,
+        921_600 => Baudrate::BAUD921600,
+        1_000_000 => Baudrate::BAUD1M,
+        _ => panic!("unsupported baud rate"),
+    }
+}
 
+#[cfg(feature = "v2")]
 static mut TX_BUF: [u8; 1] = [0; 1];
+#[cfg(feature = "v2")]
 static mut RX_BUF: [u8; 1] = [0; 1];
+/// EasyDMA can only read from RAM.
+#[cfg(feature = "v2")]
+static mut TX_CHUNK: [u8; TX_CHUNK_LEN] = [0; TX_CHUNK_LEN];
 
-pub struct UartePort<T: Instance>(UarteTx<T>, UarteRx<T>);
+/// The UARTE, split. Output doesn't go through the HAL's transmitter, which
+/// sends a byte per transfer; it is only kept so the transmitter stays set
+/// up.
+#[cfg(feature = "v2")]
+pub struct UartePort<T: Instance> {
+    _tx: UarteTx<T>,
+    rx: UarteRx<T>,
+    /// A chunk is being sent.
+    sending: bool,
+}
 
+#[cfg(feature = "v2")]
 impl<T: Instance> UartePort<T> {
     pub fn new(serial: Uarte<T>) -> UartePort<T> {
         let (tx, rx) = serial
@@ -17,56 +78,200 @@ impl<T: Instance> UartePort<T> {
                 &mut *addr_of_mut!(RX_BUF)
             })
             .unwrap();
-        UartePort(tx, rx)
+        UartePort {
+            _tx: tx,
+            rx,
+            sending: false,
+        }
     }
-}
 
-#[derive(Debug)]
-pub enum Error {
-    Other,
+    /// Starts receiving in the background, raising the UARTE interrupt as
+    /// each byte arrives and as each chunk of output has been sent. The
+    /// interrupt handler should then `read` until it would block, which
+    /// also starts the next reception, and call `end_transmit`.
+    pub fn listen(&mut self) {
+        let uarte = unsafe { &*T::ptr() };
+        uarte.intenset.write(|w| w.endrx().set().endtx().set());
+        self.read().ok();
+    }
+
+    /// Starts sending the next chunk of `queue` in the background, unless a
+    /// chunk is still being sent. Returns the chunk, empty if none was
+    /// started.
+    pub fn transmit(&mut self, queue: &mut TxQueue) -> &[u8] {
+        if self.sending {
+            return &[];
+        }
+        let chunk = unsafe { &mut *addr_of_mut!(TX_CHUNK) };
+        let mut len = 0;
+        while len < chunk.len() {
+            let Some(byte) = queue.pop() else {
+                break;
+            };
+            chunk[len] = byte;
+            len += 1;
+        }
+        if len == 0 {
+            return &[];
+        }
+        let uarte = unsafe { &*T::ptr() };
+        // The chunk must be in memory before EasyDMA reads it.
+        compiler_fence(Ordering::SeqCst);
+        uarte
+            .txd
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(chunk.as_ptr() as u32) });
+        uarte
+            .txd
+            .maxcnt
+            .write(|w| unsafe { w.maxcnt().bits(len as _) });
+        uarte.events_endtx.reset();
+        uarte.tasks_starttx.write(|w| unsafe { w.bits(1) });
+        self.sending = true;
+        &chunk[..len]
+    }
+
+    /// Finishes the chunk being sent, if it has all gone out; call from the
+    /// UARTE interrupt. Returns whether it had, and so whether to
+    /// `transmit` the next one.
+    pub fn end_transmit(&mut self) -> bool {
+        let uarte = unsafe { &*T::ptr() };
+        if !self.sending || uarte.events_endtx.read().bits() == 0 {
+            return false;
+        }
+        uarte.events_endtx.reset();
+        uarte.events_txstarted.reset();
+        // Lets the transmitter power down until the next chunk.
+        uarte.tasks_stoptx.write(|w| unsafe { w.bits(1) });
+        compiler_fence(Ordering::SeqCst);
+        self.sending = false;
+        true
+    }
+
+    /// Sends everything in `queue`, waiting for it to go out. For when the
+    /// interrupt can't be waited for, as before powering down.
+    pub fn flush(&mut self, queue: &mut TxQueue) {
+        let uarte = unsafe { &*T::ptr() };
+        loop {
+            self.transmit(queue);
+            if !self.sending {
+                return;
+            }
+            while uarte.events_endtx.read().bits() == 0 {}
+            self.end_transmit();
+        }
+    }
 }
 
-impl SerialError for Error {
-    fn kind(&self) -> embedded_hal_nb::serial::ErrorKind {
-        embedded_hal_nb::serial::ErrorKind::Other
+/// Writes `bytes` straight through the UARTE registers, waiting for each
+/// transfer to end. For the panic handler, which can't reach the
+/// `UartePort`; does nothing if the UARTE hasn't been set up.
+#[cfg(feature = "v2")]
+pub fn write_polled<T: Instance>(bytes: &[u8]) {
+    let uarte = unsafe { &*T::ptr() };
+    if uarte.enable.read().enable().is_disabled() {
+        return;
     }
+    // Let a transfer that was already under way finish first.
+    if uarte.events_txstarted.read().bits() == 1 {
+        while uarte.events_endtx.read().bits() == 0 {}
+    }
+    // EasyDMA can only read from RAM, so go through a buffer on the stack.
+    let mut buffer = [0u8; 32];
+    for chunk in bytes.chunks(buffer.len()) {
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        uarte.events_endtx.reset();
+        uarte
+            .txd
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(buffer.as_ptr() as u32) });
+        uarte
+            .txd
+            .maxcnt
+            .write(|w| unsafe { w.maxcnt().bits(chunk.len() as _) });
+        uarte.tasks_starttx.write(|w| unsafe { w.bits(1) });
+        while uarte.events_endtx.read().bits() == 0 {}
+    }
+    uarte.events_txstarted.reset();
 }
 
-impl<T: Instance> ErrorType for UartePort<T> {
-    type Error = Error;
+/// Outgoing bytes waiting for the transmit task, so formatting a line never
+/// waits for the UART. Output that doesn't fit is dropped, and counted.
+pub struct TxQueue {
+    bytes: Deque<u8, TX_QUEUE_LEN>,
+    dropped: u32,
 }
 
-impl<T: Instance> fmt::Write for UartePort<T> {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        for byte in s.bytes() {
-            nb::block!(self.write(byte)).map_err(|_| fmt::Error)?;
+impl TxQueue {
+    pub const fn new() -> TxQueue {
+        TxQueue {
+            bytes: Deque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        self.bytes.pop_front()
+    }
+
+    /// Bytes that can be queued before output starts being dropped.
+    pub fn space(&self) -> usize {
+        self.bytes.capacity() - self.bytes.len()
+    }
+
+    /// Queues `bytes` whole, or drops them if they don't fit.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() > self.space() {
+            self.dropped = self.dropped.saturating_add(bytes.len() as u32);
+            return Err(());
+        }
+        for &byte in bytes {
+            self.bytes.push_back(byte).ok();
         }
         Ok(())
     }
+
+    /// Returns the bytes dropped since the last call.
+    pub fn take_dropped(&mut self) -> u32 {
+        core::mem::take(&mut self.dropped)
+    }
 }
 
-impl<T: Instance> Write<u8> for UartePort<T> {
-    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
-        self.0
-            .write(&[word])
-            .map_err(|_| nb::Error::Other(Error::Other))?;
-        Ok(())
+impl fmt::Write for TxQueue {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes()).map_err(|()| fmt::Error)
     }
+}
 
-    fn flush(&mut self) -> nb::Result<(), Self::Error> {
-        self.0.flush().map_err(|_| nb::Error::Other(Error::Other))
+#[derive(Debug)]
+pub enum Error {
+    Other,
+}
+
+impl SerialError for Error {
+    fn kind(&self) -> embedded_hal_nb::serial::ErrorKind {
+        embedded_hal_nb::serial::ErrorKind::Other
     }
 }
 
+#[cfg(feature = "v2")]
+impl<T: Instance> ErrorType for UartePort<T> {
+    type Error = Error;
+}
+
+#[cfg(feature = "v2")]
 impl<T: Instance> Read<u8> for UartePort<T> {
     fn read(&mut self) -> nb::Result<u8, Self::Error> {
         let mut buffer = [0u8; 1];
-        let res = self.1.read_ready().map_err(|_| nb::Error::Other(Error::Other))?;
+        let res = self
+            .rx
+            .read_ready()
+            .map_err(|_| nb::Error::Other(Error::Other))?;
         if !res {
             return Err(nb::Error::WouldBlock);
         }
 
-        match self.1.read(&mut buffer) {
+        match self.rx.read(&mut buffer) {
             Ok(1) => Ok(buffer[0]),
             Ok(_) => Err(nb::Error::WouldBlock),
             Err(_) => Err(nb::Error::Other(Error::Other)),
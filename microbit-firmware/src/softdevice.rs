@@ -0,0 +1,234 @@
+//! Nordic's S113 SoftDevice, the Bluetooth LE stack behind the `ble`
+//! feature. It lives in flash and RAM of its own below the firmware's (see
+//! `memory-ble.x`), and once enabled takes over peripherals the firmware
+//! otherwise drives itself: TIMER0, so the delay counts SysTick instead, and
+//! the power and flash controllers, so flash writes and erases, the supply
+//! check before them and powering off go through its API from then on.
+//!
+//! It can't be enabled in `init`, where interrupts are masked and its calls
+//! would fault, so the `bluetooth` task enables it while holding the
+//! storage, which keeps a flash operation from being cut in two. Until then
+//! [`Flash`] drives the flash controller directly.
+//!
+//! The SoftDevice keeps interrupt priorities 0, 1 and 4 to itself, which is
+//! why no task runs at RTIC priority 4. `nrf-softdevice`'s event loop only
+//! handles its Bluetooth events, leaving the SoC ones (a flash operation
+//! done, the supply low) to [`wait_for_event`], which sleeps until the
+//! SoftDevice's SD_EVT interrupt says one has come.
+
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use microbit::hal::nvmc::{Nvmc, NvmcError};
+use microbit::pac::NVMC;
+use nrf_softdevice::{raw, Softdevice};
+
+use crate::{clock, nus};
+
+const PAGE_SIZE: u32 = 4096;
+/// Words copied to an aligned buffer and written at a time.
+const WRITE_WORDS: usize = 64;
+/// How long a flash operation may take before it is given up on, in
+/// microseconds, well inside the watchdog's timeout. A page erase takes
+/// about 85 ms, longer when it has to fit between radio events.
+const FLASH_TIMEOUT_US: u64 = 1_000_000;
+
+/// Bytes of the SoftDevice's RAM for the GATT attribute table, where the
+/// values are kept, which the UART service's two 244-byte ones mostly fill
+/// at the default size.
+const ATTR_TAB_SIZE: u32 = 0x800;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// SoC events taken from the SoftDevice but not waited for yet, a bit for
+/// each.
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+/// Enables the SoftDevice, with the low-frequency clock from the internal
+/// RC oscillator, the micro:bit having no crystal for it, room for the
+/// Nordic UART Service's long notifications, and an attribute table with
+/// room for the services' characteristics.
+pub fn enable() -> &'static mut Softdevice {
+    let config = nrf_softdevice::Config {
+        clock: Some(raw::nrf_clock_lf_cfg_t {
+            source: raw::NRF_CLOCK_LF_SRC_RC as u8,
+            rc_ctiv: 16,
+            rc_temp_ctiv: 2,
+            accuracy: raw::NRF_CLOCK_LF_ACCURACY_500_PPM as u8,
+        }),
+        conn_gatt: Some(raw::ble_gatt_conn_cfg_t {
+            att_mtu: nus::ATT_MTU,
+        }),
+        gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
+            attr_tab_size: ATTR_TAB_SIZE,
+        }),
+        ..Default::default()
+    };
+    let sd = Softdevice::enable(&config);
+    ENABLED.store(true, Ordering::Release);
+    sd
+}
+
+/// Whether the SoftDevice has been enabled, after which the peripherals it
+/// owns must be left to it.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Sleeps until the SoftDevice's SoC event `success` or `failure` comes, and
+/// says whether it was `success`. Gives up after `timeout_us`. Events are
+/// kept until they are waited for, so others that come meanwhile aren't
+/// lost; the SD_EVT interrupt or the clock at the deadline wakes the CPU.
+pub fn wait_for_event(success: u32, failure: Option<u32>, timeout_us: u64) -> bool {
+    let deadline = clock::now() + timeout_us;
+    clock::wake_at(deadline);
+    loop {
+        if take(success) {
+            return true;
+        }
+        if failure.is_some_and(take) {
+            return false;
+        }
+        if clock::now() >= deadline {
+            return false;
+        }
+        // Returns at once if an interrupt has come since it was last
+        // called, so an event or the deadline can't slip in before it.
+        unsafe { raw::sd_app_evt_wait() };
+    }
+}
+
+/// Forgets any `event` that has already come, so that the next
+/// [`wait_for_event`] is for one raised after this; call before starting
+/// whatever raises it.
+pub fn discard(event: u32) {
+    take(event);
+}
+
+/// Whether `event` has come since it was last taken, taking it.
+fn take(event: u32) -> bool {
+    let mut next = 0;
+    while unsafe { raw::sd_evt_get(&mut next) } == raw::NRF_SUCCESS {
+        PENDING.fetch_or(1 << next, Ordering::Relaxed);
+    }
+    PENDING.fetch_and(!(1 << event), Ordering::Relaxed) & 1 << event != 0
+}
+
+#[derive(Debug)]
+pub enum FlashError {
+    Nvmc(NvmcError),
+    /// The SoftDevice refused or failed the operation.
+    SoftDevice,
+}
+
+impl NorFlashError for FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            FlashError::Nvmc(e) => e.kind(),
+            FlashError::SoftDevice => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// The flash controller's driver, handing writes and erases to the
+/// SoftDevice once it is enabled. Reads are from memory either way.
+pub struct Flash {
+    nvmc: Nvmc<NVMC>,
+    /// Address of the start of `nvmc`'s pages, which the SoftDevice wants
+    /// absolute addresses for.
+    start: u32,
+}
+
+impl Flash {
+    pub fn new(nvmc: Nvmc<NVMC>, start: u32) -> Flash {
+        Flash { nvmc, start }
+    }
+
+    fn check(&self, offset: u32, len: usize, align: u32) -> Result<(), FlashError> {
+        if offset as usize + len > self.nvmc.capacity() {
+            return Err(FlashError::Nvmc(NvmcError::OutOfBounds));
+        }
+        if !offset.is_multiple_of(align) || !(len as u32).is_multiple_of(align) {
+            return Err(FlashError::Nvmc(NvmcError::Unaligned));
+        }
+        Ok(())
+    }
+
+    /// Starts an operation with `start`, retrying while the SoftDevice is
+    /// busy with another, and waits for it to finish.
+    fn run(start: impl Fn() -> u32) -> Result<(), FlashError> {
+        discard(raw::NRF_SOC_EVTS_NRF_EVT_FLASH_OPERATION_SUCCESS);
+        discard(raw::NRF_SOC_EVTS_NRF_EVT_FLASH_OPERATION_ERROR);
+        loop {
+            match start() {
+                raw::NRF_SUCCESS => break,
+                raw::NRF_ERROR_BUSY => continue,
+                _ => return Err(FlashError::SoftDevice),
+            }
+        }
+        match wait_for_event(
+            raw::NRF_SOC_EVTS_NRF_EVT_FLASH_OPERATION_SUCCESS,
+            Some(raw::NRF_SOC_EVTS_NRF_EVT_FLASH_OPERATION_ERROR),
+            FLASH_TIMEOUT_US,
+        ) {
+            true => Ok(()),
+            false => Err(FlashError::SoftDevice),
+        }
+    }
+}
+
+impl ErrorType for Flash {
+    type Error = FlashError;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        self.nvmc.read(offset, bytes).map_err(FlashError::Nvmc)
+    }
+
+    fn capacity(&self) -> usize {
+        self.nvmc.capacity()
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = PAGE_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        if !enabled() {
+            return self.nvmc.erase(from, to).map_err(FlashError::Nvmc);
+        }
+        self.check(from, to.saturating_sub(from) as usize, PAGE_SIZE)?;
+        for page in (self.start + from) / PAGE_SIZE..(self.start + to) / PAGE_SIZE {
+            Flash::run(|| unsafe { raw::sd_flash_page_erase(page) })?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        if !enabled() {
+            return self.nvmc.write(offset, bytes).map_err(FlashError::Nvmc);
+        }
+        self.check(offset, bytes.len(), 4)?;
+        let mut address = self.start + offset;
+        for chunk in bytes.chunks(WRITE_WORDS * 4) {
+            // The SoftDevice reads the words as they are written, so they
+            // have to be aligned.
+            let mut words = [0u32; WRITE_WORDS];
+            for (word, bytes) in words.iter_mut().zip(chunk.chunks_exact(4)) {
+                *word = u32::from_ne_bytes(bytes.try_into().unwrap());
+            }
+            let len = (chunk.len() / 4) as u32;
+            Flash::run(|| unsafe {
+                raw::sd_flash_write(address as *mut u32, words.as_ptr(), len)
+            })?;
+            address += chunk.len() as u32;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! Text-only driver for a 128x64 SSD1306 OLED on I2C. The screen is treated
+//! as eight lines of 21 characters, one per 8-pixel page, and a line is only
+//! sent over the bus when its text changes.
+
+use embedded_hal::i2c::I2c;
+
+pub const ADDRESS: u8 = 0x3c;
+pub const LINES: usize = 8;
+pub const LINE_LEN: usize = WIDTH / GLYPH_WIDTH;
+
+const WIDTH: usize = 128;
+const GLYPH_WIDTH: usize = 6;
+
+const CONTROL_COMMAND: u8 = 0x00;
+const CONTROL_DATA: u8 = 0x40;
+
+const INIT_SEQUENCE: [u8; 25] = [
+    0xae, // display off
+    0xd5, 0x80, // clock divide ratio
+    0xa8, 0x3f, // multiplex ratio: 64 rows
+    0xd3, 0x00, // no display offset
+    0x40, // start line 0
+    0x8d, 0x14, // enable charge pump
+    0x20, 0x02, // page addressing mode
+    0xa1, // mirror columns
+    0xc8, // scan rows from the bottom
+    0xda, 0x12, // alternative COM pin configuration
+    0x81, 0xcf, // contrast
+    0xd9, 0xf1, // pre-charge period
+    0xdb, 0x40, // VCOMH deselect level
+    0xa4, // follow RAM contents
+    0xa6, // normal, not inverted
+    0xaf, // display on
+];
+
+pub struct Ssd1306<I2C> {
+    i2c: I2C,
+    lines: [[u8; LINE_LEN]; LINES],
+}
+
+impl<I2C: I2c> Ssd1306<I2C> {
+    pub fn new(i2c: I2C) -> Result<Ssd1306<I2C>, I2C::Error> {
+        let mut oled = Ssd1306 {
+            i2c,
+            lines: [[0; LINE_LEN]; LINES],
+        };
+        oled.command(&INIT_SEQUENCE)?;
+        for line in 0..LINES {
+            oled.write_line(line, "")?;
+        }
+        Ok(oled)
+    }
+
+    /// Shows `text` on `line`, padded with spaces or cut to `LINE_LEN`.
+    /// Lowercase letters are shown as uppercase.
+    pub fn write_line(&mut self, line: usize, text: &str) -> Result<(), I2C::Error> {
+        let mut chars = [b' '; LINE_LEN];
+        for (c, byte) in chars.iter_mut().zip(text.bytes()) {
+            *c = byte.to_ascii_uppercase();
+        }
+        if self.lines[line] == chars {
+            return Ok(());
+        }
+
+        let mut data = [0; WIDTH + 1];
+        data[0] = CONTROL_DATA;
+        for (cell, c) in data[1..].chunks_mut(GLYPH_WIDTH).zip(chars.iter()) {
+            cell[..5].copy_from_slice(&glyph(*c));
+        }
+        self.command(&[0xb0 + line as u8, 0x00, 0x10])?;
+        self.i2c.write(ADDRESS, &data)?;
+        self.lines[line] = chars;
+        Ok(())
+    }
+
+    fn command(&mut self, bytes: &[u8]) -> Result<(), I2C::Error> {
+        let mut buffer = [0; INIT_SEQUENCE.len() + 1];
+        buffer[0] = CONTROL_COMMAND;
+        buffer[1..=bytes.len()].copy_from_slice(bytes);
+        self.i2c.write(ADDRESS, &buffer[..=bytes.len()])
+    }
+}
+
+/// Returns the 5x7 glyph for `c`, one byte per column with bit 0 at the top.
+/// Characters outside the font are blank.
+fn glyph(c: u8) -> [u8; 5] {
+    match c {
+        b'0'..=b'9' => DIGITS[(c - b'0') as usize],
+        b'A'..=b'Z' => LETTERS[(c - b'A') as usize],
+        b'-' => [0x08, 0x08, 0x08, 0x08, 0x08],
+        b'.' => [0x00, 0x60, 0x60, 0x00, 0x00],
+        b':' => [0x00, 0x36, 0x36, 0x00, 0x00],
+        _ => [0; 5],
+    }
+}
+
+const DIGITS: [[u8; 5]; 10] = [
+    [0x3e, 0x51, 0x49, 0x45, 0x3e],
+    [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x42, 0x61, 0x51, 0x49, 0x46],
+    [0x21, 0x41, 0x45, 0x4b, 0x31],
+    [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39],
+    [0x3c, 0x4a, 0x49, 0x49, 0x30],
+    [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36],
+    [0x06, 0x49, 0x49, 0x29, 0x1e],
+];
+
+const LETTERS: [[u8; 5]; 26] = [
+    [0x7e, 0x11, 0x11, 0x11, 0x7e],
+    [0x7f, 0x49, 0x49, 0x49, 0x36],
+    [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x22, 0x1c],
+    [0x7f, 0x49, 0x49, 0x49, 0x41],
+    [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x49, 0x49, 0x7a],
+    [0x7f, 0x08, 0x08, 0x08, 0x7f],
+    [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01],
+    [0x7f, 0x08, 0x14, 0x22, 0x41],
+    [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f],
+    [0x7f, 0x04, 0x08, 0x10, 0x7f],
+    [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06],
+    [0x3e, 0x41, 0x51, 0x21, 0x5e],
+    [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x46, 0x49, 0x49, 0x49, 0x31],
+    [0x01, 0x01, 0x7f, 0x01, 0x01],
+    [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f],
+    [0x3f, 0x40, 0x38, 0x40, 0x3f],
+    [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x07, 0x08, 0x70, 0x08, 0x07],
+    [0x61, 0x51, 0x49, 0x45, 0x43],
+];
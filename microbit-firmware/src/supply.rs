@@ -0,0 +1,70 @@
+//! The supply check flash writes go through: the power-fail comparator, switched on only for the check. Erasing a page draws several
+//! mA for about 85 ms, which a flat battery may not hold up through.
+//!
+//! The comparator raises POFWARN on enabling if the supply is already below
+//! its threshold, so a check is: enable, give it a moment, and see whether
+//! the event fired. Once the SoftDevice is enabled, with the `ble` feature,
+//! it owns the comparator, and passes the event on as one of its own.
+
+use cortex_m::asm::delay;
+use microbit::pac::POWER;
+
+use crate::board::CYCLES_PER_US;
+
+/// Checks before giving up, so a brief dip, such as from the matrix
+/// lighting up, doesn't cost a write.
+const ATTEMPTS: u32 = 3;
+/// 1 ms between checks.
+const RETRY_CYCLES: u32 = CYCLES_PER_US * 1_000;
+/// 25 us for the comparator to settle.
+const SETTLE_CYCLES: u32 = CYCLES_PER_US * 25;
+/// The same, plus time for the SoftDevice to pass the event on, in
+/// microseconds.
+#[cfg(feature = "ble")]
+const SOFTDEVICE_SETTLE_US: u64 = 100;
+
+/// Whether the supply is above 2.2 V, well clear of the 1.7 V flash needs;
+/// on the v1, whose comparator has no 2.2 V setting, above 2.1 V.
+pub fn ok() -> bool {
+    (0..ATTEMPTS).any(|attempt| {
+        if attempt > 0 {
+            delay(RETRY_CYCLES);
+        }
+        above_threshold()
+    })
+}
+
+fn above_threshold() -> bool {
+    #[cfg(feature = "ble")]
+    if crate::softdevice::enabled() {
+        return softdevice_above_threshold();
+    }
+    let power = unsafe { &*POWER::ptr() };
+    power.events_pofwarn.reset();
+    #[cfg(feature = "v2")]
+    power.pofcon.write(|w| w.pof().enabled().threshold().v22());
+    #[cfg(feature = "v1")]
+    power.pofcon.write(|w| w.pof().enabled().threshold().v21());
+    delay(SETTLE_CYCLES);
+    let warned = power.events_pofwarn.read().bits() != 0;
+    power.pofcon.write(|w| w.pof().disabled());
+    power.events_pofwarn.reset();
+    !warned
+}
+
+#[cfg(feature = "ble")]
+fn softdevice_above_threshold() -> bool {
+    use nrf_softdevice::raw;
+    crate::softdevice::discard(raw::NRF_SOC_EVTS_NRF_EVT_POWER_FAILURE_WARNING);
+    unsafe {
+        raw::sd_power_pof_threshold_set(raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V22 as u8);
+        raw::sd_power_pof_enable(1);
+    }
+    let warned = crate::softdevice::wait_for_event(
+        raw::NRF_SOC_EVTS_NRF_EVT_POWER_FAILURE_WARNING,
+        None,
+        SOFTDEVICE_SETTLE_US,
+    );
+    unsafe { raw::sd_power_pof_enable(0) };
+    !warned
+}
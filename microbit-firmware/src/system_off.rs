@@ -0,0 +1,50 @@
+//! System OFF, the nRF52833's (and nRF51822's) deepest sleep, where only
+//! the GPIO sense logic stays powered. The board comes back through a
+//! reset, reported as a wake from off, when the LSM303AGR raises its
+//! interrupt line.
+//!
+//! Everything that should stay quiet meanwhile (the LED matrix, the
+//! sensor) has to be dealt with beforehand: pins keep their levels, and
+//! the I2C devices keep running.
+//!
+//! Once the SoftDevice is enabled, with the `ble` feature, System OFF is
+//! entered through it.
+
+use microbit::pac::{GPIOTE, POWER};
+
+use crate::board::{self, INT_PIN as WAKE_PIN};
+
+/// Powers down until the wake pin goes high.
+pub fn enter() -> ! {
+    let gpiote = unsafe { &*GPIOTE::ptr() };
+    let p0 = board::port();
+    let power = unsafe { &*POWER::ptr() };
+    // GPIOTE would otherwise keep the pin to itself and wake nothing.
+    gpiote.intenclr.write(|w| unsafe { w.bits(u32::MAX) });
+    gpiote.config[0].reset();
+    p0.pin_cnf[WAKE_PIN].write(|w| {
+        w.dir()
+            .input()
+            .input()
+            .connect()
+            .pull()
+            .disabled()
+            .sense()
+            .high()
+    });
+    #[cfg(feature = "ble")]
+    if crate::softdevice::enabled() {
+        unsafe { nrf_softdevice::raw::sd_power_system_off() };
+        wait();
+    }
+    power.systemoff.write(|w| w.systemoff().enter());
+    wait()
+}
+
+/// Entering System OFF can take a moment, and a debugger keeps the CPU in
+/// an emulated one.
+fn wait() -> ! {
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
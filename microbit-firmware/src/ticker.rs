@@ -0,0 +1,45 @@
+//! The periodic tick `tick` runs on: RTC1's TICK event, counting the
+//! 32.768 kHz low-frequency clock, which every micro:bit has and which,
+//! unlike a TIMER, doesn't keep the high-frequency clock running between
+//! ticks. A period is rounded to a whole number of 30.5 us counts, close
+//! enough for polling buttons.
+
+use microbit::pac::RTC1;
+
+const LFCLK_HZ: u32 = 32_768;
+/// The largest the 12-bit prescaler goes: 125 ms.
+const MAX_PRESCALER: u32 = (1 << 12) - 1;
+
+pub struct Ticker {
+    rtc: RTC1,
+}
+
+impl Ticker {
+    /// Starts ticking every `period_us`, raising the RTC1 interrupt.
+    pub fn new(rtc: RTC1, period_us: u32) -> Ticker {
+        rtc.evtenset.write(|w| w.tick().set());
+        rtc.intenset.write(|w| w.tick().set());
+        let mut ticker = Ticker { rtc };
+        ticker.start(period_us);
+        ticker
+    }
+
+    /// Ticks every `period_us` from now on, up to 125 ms.
+    pub fn start(&mut self, period_us: u32) {
+        let counts = (period_us as u64 * LFCLK_HZ as u64 / 1_000_000) as u32;
+        let prescaler = counts.clamp(1, MAX_PRESCALER + 1) - 1;
+        // The prescaler can only be changed while the RTC is stopped.
+        self.rtc.tasks_stop.write(|w| unsafe { w.bits(1) });
+        self.rtc
+            .prescaler
+            .write(|w| unsafe { w.prescaler().bits(prescaler as u16) });
+        self.rtc.tasks_clear.write(|w| unsafe { w.bits(1) });
+        self.rtc.tasks_start.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// Clears the tick that raised the interrupt; call from the RTC1
+    /// interrupt.
+    pub fn reset_event(&mut self) {
+        self.rtc.events_tick.reset();
+    }
+}
@@ -0,0 +1,153 @@
+//! The nRF51822's UART, for the micro:bit v1, behind the same interface as
+//! the v2's `UartePort`. Without EasyDMA, a chunk of output is taken off
+//! the queue at a time as there, but then sent a byte per interrupt, each
+//! TXDRDY loading the next byte until the chunk has gone. At 115200 baud
+//! that is an interrupt every 87 us while output is waiting, which the
+//! 16 MHz CPU still keeps up with alongside 100 Hz samples.
+
+use cortex_m::asm::delay;
+use embedded_hal_nb::nb;
+use embedded_hal_nb::serial::{ErrorType, Read};
+use microbit::hal::uart::Uart;
+use microbit::pac::UART0;
+
+use crate::board::CYCLES_PER_US;
+use crate::serial_setup::{Error, TxQueue};
+
+/// Bytes taken off the queue at a time.
+const TX_CHUNK_LEN: usize = 64;
+/// Longer than a byte takes at 9600 baud, the slowest the config allows.
+const BYTE_US: u32 = 1_100;
+
+pub struct UartPort {
+    _uart: Uart<UART0>,
+    chunk: [u8; TX_CHUNK_LEN],
+    len: usize,
+    /// Bytes of the chunk loaded into TXD so far.
+    sent: usize,
+    /// A chunk is being sent.
+    sending: bool,
+}
+
+fn uart() -> &'static microbit::pac::uart0::RegisterBlock {
+    unsafe { &*UART0::ptr() }
+}
+
+impl UartPort {
+    pub fn new(uart: Uart<UART0>) -> UartPort {
+        // The transmitter is only started for each chunk.
+        self::uart().tasks_stoptx.write(|w| unsafe { w.bits(1) });
+        UartPort {
+            _uart: uart,
+            chunk: [0; TX_CHUNK_LEN],
+            len: 0,
+            sent: 0,
+            sending: false,
+        }
+    }
+
+    /// Raises the UART interrupt as each byte arrives and as each byte of
+    /// output has been sent. The interrupt handler should then `read` until
+    /// it would block, and call `end_transmit`.
+    pub fn listen(&mut self) {
+        uart().intenset.write(|w| w.rxdrdy().set().txdrdy().set());
+    }
+
+    /// Starts sending the next chunk of `queue` in the background, unless a
+    /// chunk is still being sent. Returns the chunk, empty if none was
+    /// started.
+    pub fn transmit(&mut self, queue: &mut TxQueue) -> &[u8] {
+        if self.sending {
+            return &[];
+        }
+        let mut len = 0;
+        while len < self.chunk.len() {
+            let Some(byte) = queue.pop() else {
+                break;
+            };
+            self.chunk[len] = byte;
+            len += 1;
+        }
+        if len == 0 {
+            return &[];
+        }
+        let uart = uart();
+        uart.events_txdrdy.reset();
+        uart.tasks_starttx.write(|w| unsafe { w.bits(1) });
+        uart.txd.write(|w| unsafe { w.txd().bits(self.chunk[0]) });
+        self.len = len;
+        self.sent = 1;
+        self.sending = true;
+        &self.chunk[..len]
+    }
+
+    /// Loads the chunk's next byte once the last has gone; call from the
+    /// UART interrupt. Returns whether the whole chunk had, and so whether
+    /// to `transmit` the next one.
+    pub fn end_transmit(&mut self) -> bool {
+        let uart = uart();
+        if !self.sending || uart.events_txdrdy.read().bits() == 0 {
+            return false;
+        }
+        uart.events_txdrdy.reset();
+        if self.sent < self.len {
+            uart.txd
+                .write(|w| unsafe { w.txd().bits(self.chunk[self.sent]) });
+            self.sent += 1;
+            return false;
+        }
+        // Lets the transmitter power down until the next chunk.
+        uart.tasks_stoptx.write(|w| unsafe { w.bits(1) });
+        self.sending = false;
+        true
+    }
+
+    /// Sends everything in `queue`, waiting for it to go out. For when the
+    /// interrupt can't be waited for, as before powering down.
+    pub fn flush(&mut self, queue: &mut TxQueue) {
+        loop {
+            self.transmit(queue);
+            if !self.sending {
+                return;
+            }
+            while self.sending {
+                while uart().events_txdrdy.read().bits() == 0 {}
+                self.end_transmit();
+            }
+        }
+    }
+}
+
+/// Writes `bytes` straight through the UART registers, waiting for each
+/// byte to go. For the panic handler, which can't reach the `UartPort`;
+/// does nothing if the UART hasn't been set up. A byte's time is left
+/// first for one a chunk had under way.
+pub fn write_polled(bytes: &[u8]) {
+    let uart = uart();
+    if uart.enable.read().enable().is_disabled() {
+        return;
+    }
+    delay(BYTE_US * CYCLES_PER_US);
+    uart.tasks_starttx.write(|w| unsafe { w.bits(1) });
+    for &byte in bytes {
+        uart.events_txdrdy.reset();
+        uart.txd.write(|w| unsafe { w.txd().bits(byte) });
+        while uart.events_txdrdy.read().bits() == 0 {}
+    }
+    uart.tasks_stoptx.write(|w| unsafe { w.bits(1) });
+}
+
+impl ErrorType for UartPort {
+    type Error = Error;
+}
+
+impl Read<u8> for UartPort {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let uart = uart();
+        if uart.events_rxdrdy.read().bits() == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        uart.events_rxdrdy.reset();
+        Ok(uart.rxd.read().rxd().bits())
+    }
+}
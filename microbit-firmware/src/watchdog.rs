@@ -0,0 +1,38 @@
+//! The hardware watchdog, and the reset reason it leaves behind. The idle
+//! task feeds the watchdog, so if any task wedges (a sensor wait that never
+//! ends, a UART write that never completes) idle stops running and the
+//! board resets instead of freezing.
+
+use microbit::hal::wdt::{count, handles::Hdl0, Watchdog, WatchdogHandle};
+use microbit::pac::{POWER, WDT};
+
+use sphere_mapping_core::config::WATCHDOG_TIMEOUT_MS;
+use sphere_mapping_core::reset::ResetReason;
+
+/// The watchdog counts the 32.768 kHz low-frequency clock.
+const TICKS_PER_MS: u32 = 32_768 / 1_000;
+
+/// Starts the watchdog, or takes it back over if it is still running from
+/// before a soft reset (it can't be stopped once started).
+pub fn start(wdt: WDT) -> WatchdogHandle<Hdl0> {
+    let parts = match Watchdog::try_new(wdt) {
+        Ok(mut watchdog) => {
+            watchdog.set_lfosc_ticks(WATCHDOG_TIMEOUT_MS * TICKS_PER_MS);
+            watchdog.run_during_sleep(true);
+            watchdog.run_during_debug_halt(false);
+            watchdog.activate::<count::One>()
+        }
+        Err(wdt) => match Watchdog::try_recover::<count::One>(wdt) {
+            Ok(parts) => parts,
+            Err(_) => panic!("watchdog running with other handles"),
+        },
+    };
+    parts.handles.0
+}
+
+/// Reads and clears the reason for the last reset.
+pub fn take_reset_reason(power: &POWER) -> ResetReason {
+    let bits = power.resetreas.read().bits();
+    power.resetreas.write(|w| unsafe { w.bits(bits) });
+    ResetReason::from_resetreas(bits)
+}
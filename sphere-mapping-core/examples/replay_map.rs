@@ -0,0 +1,106 @@
+//! Rebuilds the sphere map from a serial log, binned by any of the grids
+//! the firmware can be built with, so a resolution can be tried out on data
+//! already collected instead of flashing and mapping again.
+//!
+//! The log is what the board sent in its text or CSV output format; other
+//! lines are skipped. Samples are binned the way the firmware bins them:
+//! only while the board is close to still, by which way gravity points,
+//! with the strength of the logged calibrated field.
+//!
+//!     cargo run -p sphere-mapping-core --example replay_map -- <log> [--cells <n>] [--covered <n>]
+//!
+//! Prints one CSV row per cell, and how much of the sphere is covered to
+//! stderr.
+
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+
+use sphere_mapping_core::fixed;
+use sphere_mapping_core::gravity::{self, Grid, CELLS};
+use sphere_mapping_core::settings::LoggedSample;
+use sphere_mapping_core::sphere_map::Cell;
+
+/// Samples a cell needs to count as covered, as on the board by default.
+const DEFAULT_COVERED_SAMPLES: u32 = 10;
+
+const USAGE: &str = "usage: replay_map <log> [--cells 12|42|72|162|642] [--covered <samples>]";
+
+fn main() -> ExitCode {
+    let mut path = None;
+    let mut grid = gravity::GRID;
+    let mut covered_samples = DEFAULT_COVERED_SAMPLES;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cells" => match args
+                .next()
+                .and_then(|n| n.parse().ok())
+                .and_then(Grid::with_cells)
+            {
+                Some(cells) => grid = cells,
+                None => return usage(),
+            },
+            "--covered" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(samples) => covered_samples = samples,
+                None => return usage(),
+            },
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return usage(),
+        }
+    }
+    let Some(path) = path else {
+        return usage();
+    };
+    let log = match fs::read(&path) {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("{path}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut cells = vec![Cell::EMPTY; grid.cells()];
+    let (mut samples, mut still) = (0, 0);
+    for line in String::from_utf8_lossy(&log).lines() {
+        let Some(sample) = LoggedSample::parse(line) else {
+            continue;
+        };
+        samples += 1;
+        if let Some(down) = gravity::down(&sample.accel) {
+            cells[grid.nearest_cell(down)].add(fixed::magnitude(&sample.field));
+            still += 1;
+        }
+    }
+
+    println!("index,x,y,z,samples,mean_nt,std_dev_nt,min_nt,max_nt,std_error_nt");
+    for (index, cell) in cells.iter().enumerate() {
+        let [x, y, z] = grid.cell_direction(index);
+        let show = |value: Option<u32>| value.map_or(String::new(), |value| value.to_string());
+        println!(
+            "{index},{x:.6},{y:.6},{z:.6},{},{},{},{},{},{}",
+            cell.count,
+            show(cell.mean_nt()),
+            show(cell.std_dev_nt()),
+            show(cell.min_nt()),
+            show(cell.max_nt()),
+            cell.std_error_nt()
+                .map_or(String::new(), |error| format!("{error:.2}")),
+        );
+    }
+    let covered = cells
+        .iter()
+        .filter(|cell| cell.count >= covered_samples)
+        .count();
+    eprintln!(
+        "{samples} samples, {still} still; covered {covered} of {} cells ({}%), the board's grid has {CELLS}",
+        cells.len(),
+        covered * 100 / cells.len()
+    );
+    ExitCode::SUCCESS
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{USAGE}");
+    ExitCode::FAILURE
+}
@@ -0,0 +1,184 @@
+//! Benchmark runs, so a performance change between firmware versions shows
+//! up as numbers on the device itself: for a fixed interval, the firmware
+//! times each stage of handling a sample, then reports the shortest,
+//! average and longest time in each and the sample rate it achieved.
+//!
+//! Unlike the `instrument` feature's `Timing:` lines, this is in every
+//! build, and runs only when asked.
+
+use core::fmt;
+
+/// The stages of handling a sample, in the order they happen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// Reading the sensors.
+    Read,
+    /// Calibrating, smoothing and rendering.
+    Math,
+    /// Formatting the output.
+    Output,
+    /// Updating the displays.
+    Display,
+}
+
+/// Longest run, in seconds.
+pub const MAX_BENCHMARK_S: u8 = 60;
+
+const STAGES: usize = 4;
+const STAGE_NAMES: [&str; STAGES] = ["read", "math", "output", "display"];
+
+/// Times the stages of one sample.
+pub struct StageTimer {
+    last_us: u64,
+    stage_us: [u32; STAGES],
+}
+
+impl StageTimer {
+    /// Starts timing a sample whose handling began at `now_us`.
+    pub fn new(now_us: u64) -> StageTimer {
+        StageTimer {
+            last_us: now_us,
+            stage_us: [0; STAGES],
+        }
+    }
+
+    /// Charges the time since the previous lap to `stage`.
+    pub fn lap(&mut self, stage: Stage, now_us: u64) {
+        let elapsed_us = now_us.saturating_sub(self.last_us).min(u32::MAX as u64) as u32;
+        self.stage_us[stage as usize] += elapsed_us;
+        self.last_us = now_us;
+    }
+}
+
+/// Shortest, total and longest of one stage's times over a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Spread {
+    min_us: u32,
+    total_us: u64,
+    max_us: u32,
+}
+
+impl Spread {
+    const EMPTY: Spread = Spread {
+        min_us: u32::MAX,
+        total_us: 0,
+        max_us: 0,
+    };
+
+    fn add(&mut self, us: u32) {
+        self.min_us = self.min_us.min(us);
+        self.total_us += us as u64;
+        self.max_us = self.max_us.max(us);
+    }
+}
+
+/// A benchmark run in progress, if any.
+pub struct Benchmark {
+    /// When the run started and ends, in microseconds on the sample clock.
+    started_us: u64,
+    ends_us: Option<u64>,
+    samples: u32,
+    stages: [Spread; STAGES],
+    total: Spread,
+}
+
+impl Default for Benchmark {
+    fn default() -> Benchmark {
+        Benchmark::new()
+    }
+}
+
+impl Benchmark {
+    pub const fn new() -> Benchmark {
+        Benchmark {
+            started_us: 0,
+            ends_us: None,
+            samples: 0,
+            stages: [Spread::EMPTY; STAGES],
+            total: Spread::EMPTY,
+        }
+    }
+
+    /// Starts a run of `seconds` from `now_us`, abandoning any run already
+    /// in progress.
+    pub fn start(&mut self, seconds: u8, now_us: u64) {
+        *self = Benchmark {
+            started_us: now_us,
+            ends_us: Some(now_us + seconds as u64 * 1_000_000),
+            ..Benchmark::new()
+        };
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.ends_us.is_some()
+    }
+
+    /// Adds a sample that went through every stage, finished at `now_us`.
+    /// Returns the report once the run is over.
+    pub fn record(&mut self, timer: &StageTimer, now_us: u64) -> Option<Report> {
+        let ends_us = self.ends_us?;
+        for (spread, us) in self.stages.iter_mut().zip(timer.stage_us) {
+            spread.add(us);
+        }
+        self.total.add(timer.stage_us.iter().sum());
+        self.samples += 1;
+        if now_us < ends_us {
+            return None;
+        }
+        self.ends_us = None;
+        Some(Report {
+            elapsed_us: now_us - self.started_us,
+            samples: self.samples,
+            stages: self.stages,
+            total: self.total,
+        })
+    }
+}
+
+/// The results of a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Report {
+    elapsed_us: u64,
+    samples: u32,
+    stages: [Spread; STAGES],
+    total: Spread,
+}
+
+impl Report {
+    /// Samples a second achieved, in tenths.
+    fn rate_decihertz(&self) -> u64 {
+        self.samples as u64 * 10_000_000 / self.elapsed_us.max(1)
+    }
+}
+
+/// `Benchmark: samples=<n>, rate=<hz>, read=<min>/<mean>/<max>, math=...,
+/// output=..., display=..., total=...`, with the rate to a tenth of a
+/// hertz and each stage's times in microseconds.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rate = self.rate_decihertz();
+        write!(
+            f,
+            "Benchmark: samples={}, rate={}.{}",
+            self.samples,
+            rate / 10,
+            rate % 10
+        )?;
+        let samples = self.samples.max(1) as u64;
+        let spreads = STAGE_NAMES
+            .iter()
+            .zip(self.stages)
+            .chain(core::iter::once((&"total", self.total)));
+        for (name, spread) in spreads {
+            write!(
+                f,
+                ", {}={}/{}/{}",
+                name,
+                spread.min_us,
+                spread.total_us / samples,
+                spread.max_us
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! How many times the board has booted and how long it has run in all,
+//! kept in flash so a unit in the field can be triaged remotely: a count
+//! that climbs much faster than the total uptime points at resets.
+//!
+//! The total is only saved every few hours (and before powering down), as
+//! each save erases a flash page, so up to that much of a boot's uptime is
+//! lost when it ends in a reset.
+
+use core::fmt;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::storage::{Slot, Storage, StorageError};
+
+const BOOT_RECORD_VERSION: u16 = 1;
+const BOOT_RECORD_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BootRecord {
+    /// Boots so far, this one included.
+    pub boots: u32,
+    /// Seconds run over all boots, as of the last save.
+    pub uptime_s: u32,
+}
+
+impl BootRecord {
+    /// Loads the stored record and counts this boot in it, starting from
+    /// zero if there is none. Save it to keep the count.
+    pub fn count_boot<F: NorFlash + ReadNorFlash>(storage: &mut Storage<F>) -> BootRecord {
+        let mut bytes = [0u8; BOOT_RECORD_LEN];
+        let stored = match storage.load(Slot::Boot, BOOT_RECORD_VERSION, &mut bytes) {
+            Some(BOOT_RECORD_LEN) => BootRecord::from_bytes(&bytes),
+            _ => BootRecord::default(),
+        };
+        BootRecord {
+            boots: stored.boots.saturating_add(1),
+            ..stored
+        }
+    }
+
+    /// The record once this boot has run for `uptime_s`.
+    pub fn after(&self, uptime_s: u32) -> BootRecord {
+        BootRecord {
+            uptime_s: self.uptime_s.saturating_add(uptime_s),
+            ..*self
+        }
+    }
+
+    pub fn save<F: NorFlash + ReadNorFlash>(
+        &self,
+        storage: &mut Storage<F>,
+    ) -> Result<(), StorageError> {
+        storage.store(Slot::Boot, BOOT_RECORD_VERSION, &self.to_bytes())
+    }
+
+    fn to_bytes(self) -> [u8; BOOT_RECORD_LEN] {
+        let mut bytes = [0u8; BOOT_RECORD_LEN];
+        bytes[0..4].copy_from_slice(&self.boots.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.uptime_s.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; BOOT_RECORD_LEN]) -> BootRecord {
+        BootRecord {
+            boots: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            uptime_s: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        }
+    }
+}
+
+/// `Boot: count=<n>, total=<s>`, sent at boot after the reset reason.
+impl fmt::Display for BootRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Boot: count={}, total={}", self.boots, self.uptime_s)
+    }
+}
@@ -0,0 +1,437 @@
+//! Translated from <https://github.com/lancaster-university/codal-microbit-v2/blob/006abf5566774fbcf674c0c7df27e8a9d20013de/source/MicroBitCompassCalibrator.cpp>
+
+use embedded_hal::delay::DelayNs;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::config;
+use crate::device::{CompassDisplay, SampleSource};
+use crate::led::{Frame, MAX_BRIGHTNESS};
+use crate::math::{fabsf, sqrtf};
+use crate::storage::{Slot, Storage, StorageError};
+
+const PERIMETER_POINTS: usize = 25;
+const PIXEL1_THRESHOLD: i32 = 200;
+const PIXEL2_THRESHOLD: i32 = 600;
+const CALIBRATION_INCREMENT: i32 = 200;
+const CALIBRATION_VERSION: u16 = 1;
+const CALIBRATION_LEN: usize = 28;
+const FILLED_BRIGHTNESS: u8 = 3;
+/// How long each frame of the calibration game stays up, which also paces
+/// the cursor blink.
+pub const CURSOR_BLINK_MS: u32 = 150;
+/// How long `DONE_FRAME` is shown once the game is complete.
+pub const DONE_MS: u32 = 1000;
+pub const DONE_FRAME: Frame = [
+    [0, 0, 0, 0, 0],
+    [0, 0, 0, 0, MAX_BRIGHTNESS],
+    [0, 0, 0, MAX_BRIGHTNESS, 0],
+    [MAX_BRIGHTNESS, 0, MAX_BRIGHTNESS, 0, 0],
+    [0, MAX_BRIGHTNESS, 0, 0, 0],
+];
+
+/// Calibration used until a board has been calibrated itself, see
+/// [`config::CALIBRATION`].
+pub const PRECOMPUTED_CALIBRATION: Calibration = config::CALIBRATION;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub center: Measurement,
+    pub scale: Measurement,
+    pub radius: u32,
+}
+
+impl core::fmt::Display for Calibration {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Calibration: {}, {}, {}, {}, {}, {}, {}",
+            self.center.x,
+            self.center.y,
+            self.center.z,
+            self.scale.x,
+            self.scale.y,
+            self.scale.z,
+            self.radius
+        )
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Calibration {
+        Calibration {
+            center: Measurement { x: 0, y: 0, z: 0 },
+            scale: Measurement {
+                x: 1024,
+                y: 1024,
+                z: 1024,
+            },
+            radius: 0,
+        }
+    }
+}
+
+impl Calibration {
+    /// Reads a `Calibration:` line back, as the host tools get them.
+    pub fn parse(line: &str) -> Option<Calibration> {
+        crate::command::parse_calibration(line.strip_prefix("Calibration:")?.as_bytes())
+    }
+
+    /// Returns the calibration saved by the last successful calibration run,
+    /// if there is a valid one in flash.
+    pub fn load<F: NorFlash + ReadNorFlash>(storage: &mut Storage<F>) -> Option<Calibration> {
+        let mut bytes = [0u8; CALIBRATION_LEN];
+        match storage.load(Slot::Calibration, CALIBRATION_VERSION, &mut bytes) {
+            Some(CALIBRATION_LEN) => Some(Calibration::from_bytes(&bytes)),
+            _ => None,
+        }
+    }
+
+    pub fn save<F: NorFlash + ReadNorFlash>(
+        &self,
+        storage: &mut Storage<F>,
+    ) -> Result<(), StorageError> {
+        storage.store(Slot::Calibration, CALIBRATION_VERSION, &self.to_bytes())
+    }
+
+    fn to_bytes(self) -> [u8; CALIBRATION_LEN] {
+        let fields = [
+            self.center.x,
+            self.center.y,
+            self.center.z,
+            self.scale.x,
+            self.scale.y,
+            self.scale.z,
+            self.radius as i32,
+        ];
+        let mut bytes = [0u8; CALIBRATION_LEN];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; CALIBRATION_LEN]) -> Calibration {
+        let mut fields = [0i32; 7];
+        for (field, chunk) in fields.iter_mut().zip(bytes.chunks_exact(4)) {
+            *field = i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        Calibration {
+            center: Measurement {
+                x: fields[0],
+                y: fields[1],
+                z: fields[2],
+            },
+            scale: Measurement {
+                x: fields[3],
+                y: fields[4],
+                z: fields[5],
+            },
+            radius: fields[6] as u32,
+        }
+    }
+}
+
+/// The tilt-to-fill calibration game: a blinking cursor follows the board's
+/// tilt and every pixel it visits stays lit and contributes one magnetometer
+/// sample. Filling the whole matrix means the board was rotated through 25
+/// distinct orientations, which is what the sphere fit in [`calibrate`]
+/// needs.
+///
+/// [`play`] drives the game on a blocking sensor; an async firmware can drive
+/// it the same way: after each accelerometer reading passed to
+/// [`TiltGame::tilt`] it records a magnetometer sample if asked to, then
+/// shows [`TiltGame::frame`] for `CURSOR_BLINK_MS`.
+pub struct TiltGame {
+    leds: Frame,
+    cursor: (usize, usize),
+    cursor_on: bool,
+    data: [Measurement; PERIMETER_POINTS],
+    samples: usize,
+}
+
+impl Default for TiltGame {
+    fn default() -> TiltGame {
+        TiltGame {
+            leds: [[0; 5]; 5],
+            cursor: (2, 2),
+            cursor_on: true,
+            data: [Measurement { x: 0, y: 0, z: 0 }; PERIMETER_POINTS],
+            samples: 0,
+        }
+    }
+}
+
+impl TiltGame {
+    /// Moves the cursor for an accelerometer reading in mg. Returns `true`
+    /// when it lands on a pixel that hasn't been filled yet, in which case
+    /// the caller should pass a fresh magnetometer reading to `record`.
+    pub fn tilt(&mut self, x: i32, y: i32) -> bool {
+        let cursor = &mut self.cursor;
+        if x < -PIXEL2_THRESHOLD {
+            cursor.1 = 0;
+        } else if x < -PIXEL1_THRESHOLD {
+            cursor.1 = 1;
+        } else if x > PIXEL2_THRESHOLD {
+            cursor.1 = 4;
+        } else if x > PIXEL1_THRESHOLD {
+            cursor.1 = 3;
+        } else {
+            cursor.1 = 2;
+        }
+
+        if y < -PIXEL2_THRESHOLD {
+            cursor.0 = 0;
+        } else if y < -PIXEL1_THRESHOLD {
+            cursor.0 = 1;
+        } else if y > PIXEL2_THRESHOLD {
+            cursor.0 = 4;
+        } else if y > PIXEL1_THRESHOLD {
+            cursor.0 = 3;
+        } else {
+            cursor.0 = 2;
+        }
+
+        // Turn the y axis properly
+        cursor.0 = 4 - cursor.0;
+
+        self.leds[cursor.0][cursor.1] == 0
+    }
+
+    /// Fills the pixel under the cursor with a magnetometer reading in nT,
+    /// in the sensor's own axes.
+    pub fn record(&mut self, field: Measurement) {
+        let (row, col) = self.cursor;
+        if self.leds[row][col] != 0 || self.is_done() {
+            return;
+        }
+        self.leds[row][col] = FILLED_BRIGHTNESS;
+        self.data[self.samples] = measurement_to_enu(field);
+        self.samples += 1;
+    }
+
+    /// Returns the next frame to show, blinking the cursor on every call.
+    pub fn frame(&mut self) -> Frame {
+        let mut frame = self.leds;
+        if self.cursor_on {
+            frame[self.cursor.0][self.cursor.1] = MAX_BRIGHTNESS;
+        }
+        self.cursor_on = !self.cursor_on;
+        frame
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.samples == PERIMETER_POINTS
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        calibrate(&self.data)
+    }
+}
+
+/// Plays the game to completion on `sensor` and `display`, calling
+/// `frame_shown` after every frame (the game can take as long as the player
+/// likes, so this is where a watchdog gets fed). A sensor error abandons
+/// the game.
+pub fn play<S, D, T, F>(
+    sensor: &mut S,
+    display: &mut D,
+    delay: &mut T,
+    mut frame_shown: F,
+) -> Result<Calibration, S::Error>
+where
+    S: SampleSource,
+    D: CompassDisplay,
+    T: DelayNs,
+    F: FnMut(),
+{
+    let mut game = TiltGame::default();
+    while !game.is_done() {
+        let sample = sensor.read_sample()?;
+        if game.tilt(sample.accel.x, sample.accel.y) {
+            game.record(sample.field);
+        }
+        display.show(game.frame());
+        frame_shown();
+        delay.delay_ms(CURSOR_BLINK_MS);
+    }
+
+    display.show(DONE_FRAME);
+    delay.delay_ms(DONE_MS);
+    Ok(game.calibration())
+}
+
+fn difference_square(a: Measurement, b: Measurement) -> f32 {
+    let dx = (a.x - b.x) as f32;
+    let dy = (a.y - b.y) as f32;
+    let dz = (a.z - b.z) as f32;
+
+    (dx * dx) + (dy * dy) + (dz * dz)
+}
+
+fn measure_score(center: Measurement, data: &[Measurement]) -> f32 {
+    let mut min_d = difference_square(center, data[0]);
+    let mut max_d = min_d;
+
+    for point in data[1..].iter() {
+        let d = difference_square(center, *point);
+        if d < min_d {
+            min_d = d;
+        }
+
+        if d > max_d {
+            max_d = d;
+        }
+    }
+
+    max_d - min_d
+}
+
+/// Fits a sphere to magnetometer samples taken in as many orientations as
+/// possible, returning the hard-iron offset and per-axis soft-iron scale.
+pub fn calibrate(data: &[Measurement]) -> Calibration {
+    // Approximate a center for the data
+    let mut center = Measurement { x: 0, y: 0, z: 0 };
+    let mut best = center;
+
+    for point in data {
+        center.x += point.x;
+        center.y += point.y;
+        center.z += point.z;
+    }
+
+    center.x /= data.len() as i32;
+    center.y /= data.len() as i32;
+    center.z /= data.len() as i32;
+
+    let mut current = center;
+    let mut score = measure_score(current, data);
+
+    // Calculate a fixpoint position
+    loop {
+        for x in [-CALIBRATION_INCREMENT, 0, CALIBRATION_INCREMENT] {
+            for y in [-CALIBRATION_INCREMENT, 0, CALIBRATION_INCREMENT] {
+                for z in [-CALIBRATION_INCREMENT, 0, CALIBRATION_INCREMENT] {
+                    let mut attempt = current;
+                    attempt.x += x;
+                    attempt.y += y;
+                    attempt.z += z;
+
+                    let attempt_score = measure_score(attempt, data);
+                    if attempt_score < score {
+                        score = attempt_score;
+                        best = attempt;
+                    }
+                }
+            }
+        }
+
+        if best == current {
+            break;
+        }
+
+        current = best;
+    }
+
+    spherify(current, data)
+}
+
+fn spherify(center: Measurement, data: &[Measurement]) -> Calibration {
+    let mut radius = 0;
+    for point in data {
+        let d = sqrtf(difference_square(center, *point)) as u32;
+        if d > radius {
+            radius = d;
+        }
+    }
+
+    let mut scale: f32 = 0.0;
+    let mut weight_x = 0.0;
+    let mut weight_y = 0.0;
+    let mut weight_z = 0.0;
+
+    for point in data {
+        let d = sqrtf(difference_square(center, *point));
+        let s = (radius as f32 / d) - 1.0;
+        scale = scale.max(s);
+
+        let dx = point.x - center.x;
+        let dy = point.y - center.y;
+        let dz = point.z - center.z;
+
+        weight_x += s * fabsf(dx as f32 / d);
+        weight_y += s * fabsf(dy as f32 / d);
+        weight_z += s * fabsf(dz as f32 / d);
+    }
+
+    let wmag = sqrtf((weight_x * weight_x) + (weight_y * weight_y) + (weight_z * weight_z));
+    let scale_x = 1.0 + scale * (weight_x / wmag);
+    let scale_y = 1.0 + scale * (weight_y / wmag);
+    let scale_z = 1.0 + scale * (weight_z / wmag);
+
+    Calibration {
+        center,
+        radius,
+        scale: Measurement {
+            x: (1024.0 * scale_x) as i32,
+            y: (1024.0 * scale_y) as i32,
+            z: (1024.0 * scale_z) as i32,
+        },
+    }
+}
+
+/// Applies `calibration` to a magnetometer reading in nT, in the sensor's
+/// own axes, returning the corrected field in the compass's frame.
+pub fn calibrated_measurement(measurement: Measurement, calibration: &Calibration) -> Measurement {
+    let mut out = measurement_to_enu(measurement);
+    out = Measurement {
+        x: ((out.x - calibration.center.x) * calibration.scale.x) >> 10,
+        y: ((out.y - calibration.center.y) * calibration.scale.y) >> 10,
+        z: ((out.z - calibration.center.z) * calibration.scale.z) >> 10,
+    };
+    enu_to_cartesian(out)
+}
+
+/// Undoes [`calibrated_measurement`], giving back the raw reading to within
+/// the rounding of the scaling.
+pub fn uncalibrated_measurement(field: Measurement, calibration: &Calibration) -> Measurement {
+    let enu = cartesian_to_enu(field);
+    // Turning the sensor's axes into the fitting frame is its own inverse.
+    measurement_to_enu(Measurement {
+        x: (enu.x << 10) / calibration.scale.x + calibration.center.x,
+        y: (enu.y << 10) / calibration.scale.y + calibration.center.y,
+        z: (enu.z << 10) / calibration.scale.z + calibration.center.z,
+    })
+}
+
+/// Turns a reading in the sensor's own axes into the frame calibrations
+/// are fitted in.
+pub fn measurement_to_enu(measurement: Measurement) -> Measurement {
+    Measurement {
+        x: -measurement.y,
+        y: -measurement.x,
+        z: measurement.z,
+    }
+}
+
+fn enu_to_cartesian(measurement: Measurement) -> Measurement {
+    Measurement {
+        x: -measurement.y,
+        y: measurement.x,
+        z: measurement.z,
+    }
+}
+
+fn cartesian_to_enu(measurement: Measurement) -> Measurement {
+    Measurement {
+        x: measurement.y,
+        y: -measurement.x,
+        z: measurement.z,
+    }
+}
@@ -0,0 +1,546 @@
+//! Burst capture, for characterising transient magnetic events without a
+//! fast link: a few seconds of raw samples at the sensor's fastest rate are
+//! kept in RAM with nothing sent while they are taken, then dumped one line
+//! at a time once the burst is over.
+//!
+//! A burst either starts straight away and runs for a number of seconds, or
+//! waits like an oscilloscope's one-shot for the field to cross a threshold.
+//! While waiting, the latest samples go round a circular buffer, so the dump
+//! also shows what led up to the trigger.
+//!
+//! The dump is a `Capture: samples=<n>, rate=<hz>, start=<us>, pre=<n>`
+//! line, one `Captured: <us>, <x>, <y>, <z>, <ax>, <ay>, <az>` line per
+//! sample, with the time relative to the start, the uncalibrated field in nT
+//! and the acceleration in mg, and a closing `Capture: done`. The start is
+//! the first sample, or the one that set the trigger off, with the `pre`
+//! samples before it.
+
+use core::fmt::{self, Write};
+
+use crate::calibration::Measurement;
+use crate::device::Sample;
+use crate::settings::HIGH_RATE_HZ;
+
+/// Samples a second while capturing: the high-rate mode's, the fastest the
+/// magnetometer runs continuously.
+pub const CAPTURE_RATE_HZ: u32 = HIGH_RATE_HZ;
+/// Longest burst, in seconds: less with the `short-capture` feature, for
+/// boards with too little RAM for the full buffer.
+#[cfg(not(feature = "short-capture"))]
+pub const MAX_CAPTURE_S: u8 = 20;
+#[cfg(feature = "short-capture")]
+pub const MAX_CAPTURE_S: u8 = 2;
+/// Samples the buffer holds, 24 bytes each.
+pub const CAPTURE_LEN: usize = MAX_CAPTURE_S as usize * CAPTURE_RATE_HZ as usize;
+/// Most samples kept from before a trigger, and from it on; together they
+/// fill the buffer.
+pub const MAX_PRE_TRIGGER: u16 = CAPTURE_LEN as u16 / 2;
+pub const MAX_POST_TRIGGER: u16 = CAPTURE_LEN as u16 - MAX_PRE_TRIGGER;
+/// Room a dump line needs in the output queue, so a line is only started
+/// once it can be written whole.
+pub const MAX_DUMP_LINE_LEN: usize = 96;
+
+/// Samples kept from before a trigger, and from it on, until set otherwise:
+/// 1 s and 4 s, or as much of them as the buffer holds.
+const DEFAULT_PRE_TRIGGER: u16 = min(100, MAX_PRE_TRIGGER);
+const DEFAULT_POST_TRIGGER: u16 = min(400, MAX_POST_TRIGGER);
+
+const NT_PER_UT: u32 = 1000;
+
+const fn min(a: u16, b: u16) -> u16 {
+    if a < b {
+        a
+    } else {
+        b
+    }
+}
+
+/// What sets a waiting burst off, comparing each raw field with the one
+/// before.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// The field's magnitude rising above this many nT.
+    Magnitude(u32),
+    /// Any axis changing by more than this many nT from one sample to the
+    /// next.
+    Delta(u32),
+}
+
+impl Trigger {
+    /// A trigger on the magnitude rising above `threshold_ut` µT.
+    pub fn magnitude_ut(threshold_ut: u16) -> Trigger {
+        Trigger::Magnitude(threshold_ut as u32 * NT_PER_UT)
+    }
+
+    fn fires(self, previous: &Measurement, field: &Measurement) -> bool {
+        match self {
+            Trigger::Magnitude(threshold) => {
+                let limit = threshold as i64 * threshold as i64;
+                magnitude_squared(previous) <= limit && magnitude_squared(field) > limit
+            }
+            Trigger::Delta(threshold) => {
+                let threshold = threshold as u64;
+                previous.x.abs_diff(field.x) as u64 > threshold
+                    || previous.y.abs_diff(field.y) as u64 > threshold
+                    || previous.z.abs_diff(field.z) as u64 > threshold
+            }
+        }
+    }
+}
+
+/// `trigger=<magnitude|delta>, threshold=<nT>`.
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, threshold) = match self {
+            Trigger::Magnitude(threshold) => ("magnitude", threshold),
+            Trigger::Delta(threshold) => ("delta", threshold),
+        };
+        write!(f, "trigger={}, threshold={}", name, threshold)
+    }
+}
+
+fn magnitude_squared(field: &Measurement) -> i64 {
+    let (x, y, z) = (field.x as i64, field.y as i64, field.z as i64);
+    x * x + y * y + z * z
+}
+
+/// How a burst starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Burst {
+    /// Now, for this many seconds, at most [`MAX_CAPTURE_S`].
+    Timed(u8),
+    /// Once the trigger fires.
+    Triggered(Trigger),
+}
+
+/// What [`Capture::record`] did with a sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recorded {
+    /// Nothing; no burst is waiting or being recorded.
+    Ignored,
+    /// Kept in case a trigger follows; the sample should be handled as
+    /// usual.
+    BeforeTrigger,
+    /// Kept as part of a burst, which wants the sample to itself.
+    InBurst,
+    /// Kept as the last of a burst, which is ready to dump.
+    Complete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    /// The low half of the sample's timestamp, which is plenty to tell the
+    /// samples of one burst apart.
+    time_us: u32,
+    field: [i32; 3],
+    accel: [i16; 3],
+}
+
+const EMPTY: Entry = Entry {
+    time_us: 0,
+    field: [0; 3],
+    accel: [0; 3],
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Armed(Trigger),
+    Recording,
+    /// Dumping, with the next line to write: 0 is the header, then one per
+    /// sample, then the closing line.
+    Dumping(usize),
+}
+
+/// The buffer and where the burst has got to. Large, so keep it in a
+/// static rather than on the stack.
+///
+/// While armed, the samples from before the trigger go round the start of
+/// the buffer, with `head` the oldest once it has wrapped; those from the
+/// trigger on follow them in order.
+pub struct Capture {
+    entries: [Entry; CAPTURE_LEN],
+    len: usize,
+    /// Samples the burst being recorded stops at.
+    target: usize,
+    /// Where the samples from before the trigger go round: the size the
+    /// circular part was armed with, how many it holds, and the oldest.
+    ring_len: usize,
+    pre_len: usize,
+    head: usize,
+    previous: Option<Measurement>,
+    /// The timestamp the dump's times are relative to.
+    start_us: u64,
+    state: State,
+    pre_trigger: u16,
+    post_trigger: u16,
+}
+
+impl Default for Capture {
+    fn default() -> Capture {
+        Capture::new()
+    }
+}
+
+impl Capture {
+    pub const fn new() -> Capture {
+        Capture {
+            entries: [EMPTY; CAPTURE_LEN],
+            len: 0,
+            target: 0,
+            ring_len: 0,
+            pre_len: 0,
+            head: 0,
+            previous: None,
+            start_us: 0,
+            state: State::Idle,
+            pre_trigger: DEFAULT_PRE_TRIGGER,
+            post_trigger: DEFAULT_POST_TRIGGER,
+        }
+    }
+
+    /// Sets how many samples a triggered burst keeps from before the
+    /// trigger, and from it on, from the next one armed.
+    pub fn set_window(&mut self, pre_trigger: u16, post_trigger: u16) {
+        self.pre_trigger = pre_trigger.min(MAX_PRE_TRIGGER);
+        self.post_trigger = post_trigger.clamp(1, MAX_POST_TRIGGER);
+    }
+
+    /// Samples kept from before a trigger, and from it on.
+    pub fn window(&self) -> (u16, u16) {
+        (self.pre_trigger, self.post_trigger)
+    }
+
+    /// Starts recording a burst, or waiting for its trigger. Returns false,
+    /// leaving things as they are, while another burst is still waiting,
+    /// being recorded or being dumped.
+    pub fn begin(&mut self, burst: Burst) -> bool {
+        if self.is_busy() {
+            return false;
+        }
+        self.len = 0;
+        self.pre_len = 0;
+        self.head = 0;
+        self.previous = None;
+        match burst {
+            Burst::Timed(seconds) => {
+                self.ring_len = 0;
+                self.target = (seconds.min(MAX_CAPTURE_S) as u32 * CAPTURE_RATE_HZ) as usize;
+                self.state = State::Recording;
+            }
+            Burst::Triggered(trigger) => {
+                self.ring_len = self.pre_trigger as usize;
+                self.target = self.ring_len + self.post_trigger as usize;
+                self.state = State::Armed(trigger);
+            }
+        }
+        true
+    }
+
+    /// Stops waiting for a trigger. Returns false if nothing was waiting.
+    pub fn disarm(&mut self) -> bool {
+        if !self.is_armed() {
+            return false;
+        }
+        self.state = State::Idle;
+        true
+    }
+
+    pub fn is_armed(&self) -> bool {
+        matches!(self.state, State::Armed(_))
+    }
+
+    pub fn is_dumping(&self) -> bool {
+        matches!(self.state, State::Dumping(_))
+    }
+
+    /// Waiting, recording or dumping.
+    pub fn is_busy(&self) -> bool {
+        self.state != State::Idle
+    }
+
+    /// Keeps a sample if a burst is waiting or being recorded.
+    pub fn record(&mut self, sample: &Sample) -> Recorded {
+        let entry = Entry {
+            time_us: sample.timestamp_us as u32,
+            field: [sample.field.x, sample.field.y, sample.field.z],
+            accel: [sample.accel.x, sample.accel.y, sample.accel.z].map(clamp_i16),
+        };
+        match self.state {
+            State::Armed(trigger) => {
+                let fired = self
+                    .previous
+                    .is_some_and(|previous| trigger.fires(&previous, &sample.field));
+                self.previous = Some(sample.field);
+                if !fired {
+                    self.keep_before_trigger(entry);
+                    return Recorded::BeforeTrigger;
+                }
+                // The burst runs on from however much came before.
+                self.target = self.pre_len + self.post_trigger as usize;
+                self.start_us = sample.timestamp_us;
+                self.state = State::Recording;
+            }
+            State::Recording => {
+                if self.len == 0 {
+                    self.start_us = sample.timestamp_us;
+                }
+            }
+            State::Idle | State::Dumping(_) => return Recorded::Ignored,
+        }
+        self.entries[self.len] = entry;
+        self.len += 1;
+        if self.len < self.target {
+            return Recorded::InBurst;
+        }
+        self.state = State::Dumping(0);
+        Recorded::Complete
+    }
+
+    fn keep_before_trigger(&mut self, entry: Entry) {
+        if self.ring_len == 0 {
+            return;
+        }
+        if self.pre_len < self.ring_len {
+            self.entries[self.pre_len] = entry;
+            self.pre_len += 1;
+        } else {
+            self.entries[self.head] = entry;
+            self.head = (self.head + 1) % self.ring_len;
+        }
+        self.len = self.pre_len;
+    }
+
+    /// The `index`th sample of the burst, in the order they were taken.
+    fn entry(&self, index: usize) -> Option<&Entry> {
+        if index >= self.len {
+            return None;
+        }
+        if index < self.pre_len {
+            return Some(&self.entries[(self.head + index) % self.pre_len]);
+        }
+        Some(&self.entries[index])
+    }
+
+    /// Writes the next line of the dump, and returns false, writing
+    /// nothing, once there are none left or nothing is being dumped.
+    pub fn write_next<W: Write>(&mut self, out: &mut W) -> Result<bool, fmt::Error> {
+        let State::Dumping(next) = self.state else {
+            return Ok(false);
+        };
+        if next == 0 {
+            write!(
+                out,
+                "Capture: samples={}, rate={}, start={}, pre={}\r\n",
+                self.len, CAPTURE_RATE_HZ, self.start_us, self.pre_len
+            )?;
+        } else if let Some(entry) = self.entry(next - 1) {
+            let [x, y, z] = entry.field;
+            let [ax, ay, az] = entry.accel;
+            let offset_us = entry.time_us.wrapping_sub(self.start_us as u32) as i32;
+            write!(
+                out,
+                "Captured: {}, {}, {}, {}, {}, {}, {}\r\n",
+                offset_us, x, y, z, ax, ay, az
+            )?;
+        } else {
+            out.write_str("Capture: done\r\n")?;
+            self.state = State::Idle;
+            return Ok(true);
+        }
+        self.state = State::Dumping(next + 1);
+        Ok(true)
+    }
+}
+
+/// The field in a `Captured:` line of a dump, as a host reads it back.
+pub fn parse_captured(line: &str) -> Option<Measurement> {
+    let mut values = line
+        .strip_prefix("Captured:")?
+        .split(',')
+        .map(|value| value.trim().parse::<i64>().ok());
+    let _offset_us = values.next()??;
+    let [x, y, z] = [(); 3].map(|_| values.next().flatten().and_then(|v| i32::try_from(v).ok()));
+    Some(Measurement {
+        x: x?,
+        y: y?,
+        z: z?,
+    })
+}
+
+fn clamp_i16(value: i32) -> i16 {
+    value.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Acceleration;
+    use std::string::String;
+    use std::vec::Vec;
+
+    const PERIOD_US: u64 = 10_000;
+    const TRIGGER: Burst = Burst::Triggered(Trigger::Delta(100));
+
+    /// The `n`th sample of a run, with `x` as its field's first axis.
+    fn sample(n: u64, x: i32) -> Sample {
+        Sample {
+            field: Measurement { x, y: 0, z: 40_000 },
+            accel: Acceleration {
+                x: 0,
+                y: 0,
+                z: 1000,
+            },
+            timestamp_us: 1_000_000 + n * PERIOD_US,
+            skew_us: 0,
+        }
+    }
+
+    /// Every line of the dump.
+    fn dump(capture: &mut Capture) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        while capture.write_next(&mut line).unwrap() {
+            lines.push(core::mem::take(&mut line));
+        }
+        lines
+    }
+
+    /// The time and first field axis of each `Captured:` line.
+    fn captured(lines: &[String]) -> Vec<(i64, i32)> {
+        lines
+            .iter()
+            .filter(|line| line.starts_with("Captured:"))
+            .map(|line| {
+                let offset_us = line[9..].split(',').next().unwrap().trim().parse().unwrap();
+                (offset_us, parse_captured(line).unwrap().x)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dump_runs_oldest_first_after_wrapping() {
+        let mut capture = Capture::new();
+        capture.set_window(3, 2);
+        assert!(capture.begin(TRIGGER));
+        // Round the three-sample ring three and a bit times, never
+        // changing by enough to fire.
+        for n in 0..10 {
+            assert_eq!(
+                capture.record(&sample(n, n as i32)),
+                Recorded::BeforeTrigger
+            );
+        }
+        assert_eq!(capture.record(&sample(10, 1000)), Recorded::InBurst);
+        assert_eq!(capture.record(&sample(11, 1001)), Recorded::Complete);
+
+        let lines = dump(&mut capture);
+        assert_eq!(
+            lines[0],
+            "Capture: samples=5, rate=100, start=1100000, pre=3\r\n"
+        );
+        assert_eq!(
+            captured(&lines),
+            [
+                (-30_000, 7),
+                (-20_000, 8),
+                (-10_000, 9),
+                (0, 1000),
+                (10_000, 1001)
+            ]
+        );
+        assert_eq!(lines.last().unwrap(), "Capture: done\r\n");
+    }
+
+    #[test]
+    fn no_samples_kept_before_trigger() {
+        let mut capture = Capture::new();
+        capture.set_window(0, 2);
+        capture.begin(TRIGGER);
+        for n in 0..5 {
+            capture.record(&sample(n, 0));
+        }
+        assert_eq!(capture.record(&sample(5, 500)), Recorded::InBurst);
+        assert_eq!(capture.record(&sample(6, 500)), Recorded::Complete);
+
+        let lines = dump(&mut capture);
+        assert_eq!(
+            lines[0],
+            "Capture: samples=2, rate=100, start=1050000, pre=0\r\n"
+        );
+        assert_eq!(captured(&lines), [(0, 500), (10_000, 500)]);
+    }
+
+    #[test]
+    fn trigger_before_ring_fills() {
+        let mut capture = Capture::new();
+        capture.set_window(5, 2);
+        capture.begin(TRIGGER);
+        capture.record(&sample(0, 0));
+        capture.record(&sample(1, 1));
+        assert_eq!(capture.record(&sample(2, 500)), Recorded::InBurst);
+        assert_eq!(capture.record(&sample(3, 501)), Recorded::Complete);
+
+        let lines = dump(&mut capture);
+        assert_eq!(
+            lines[0],
+            "Capture: samples=4, rate=100, start=1020000, pre=2\r\n"
+        );
+        assert_eq!(
+            captured(&lines),
+            [(-20_000, 0), (-10_000, 1), (0, 500), (10_000, 501)]
+        );
+    }
+
+    #[test]
+    fn timed_burst_completes_then_dumps() {
+        let mut capture = Capture::new();
+        assert!(capture.begin(Burst::Timed(1)));
+        let samples = CAPTURE_RATE_HZ as u64;
+        for n in 0..samples - 1 {
+            assert_eq!(capture.record(&sample(n, 0)), Recorded::InBurst);
+        }
+        assert_eq!(capture.record(&sample(samples - 1, 0)), Recorded::Complete);
+        assert!(capture.is_dumping());
+        // Samples that come while dumping aren't kept.
+        assert_eq!(capture.record(&sample(samples, 0)), Recorded::Ignored);
+
+        let lines = dump(&mut capture);
+        assert_eq!(
+            lines[0],
+            "Capture: samples=100, rate=100, start=1000000, pre=0\r\n"
+        );
+        assert_eq!(lines.len(), 1 + samples as usize + 1);
+        assert_eq!(lines.last().unwrap(), "Capture: done\r\n");
+        assert!(!capture.is_busy());
+        assert!(!capture.write_next(&mut String::new()).unwrap());
+    }
+
+    #[test]
+    fn begin_refused_while_busy() {
+        let mut capture = Capture::new();
+        capture.set_window(0, 1);
+
+        // Armed.
+        assert!(capture.begin(TRIGGER));
+        assert!(!capture.begin(Burst::Timed(1)));
+        assert!(capture.disarm());
+        assert!(!capture.disarm());
+
+        // Recording.
+        assert!(capture.begin(Burst::Timed(1)));
+        capture.record(&sample(0, 0));
+        assert!(!capture.begin(TRIGGER));
+        for n in 1..CAPTURE_RATE_HZ as u64 {
+            capture.record(&sample(n, 0));
+        }
+
+        // Dumping, until the last line.
+        assert!(capture.is_dumping());
+        assert!(!capture.begin(TRIGGER));
+        capture.write_next(&mut String::new()).unwrap();
+        assert!(!capture.begin(TRIGGER));
+        dump(&mut capture);
+        assert!(capture.begin(TRIGGER));
+    }
+}
@@ -0,0 +1,576 @@
+//! The serial command protocol: one command per line, a four-letter name
+//! optionally followed by a number. The sphere map's commands form a family
+//! of their own, `SMAP <operation> [number]`, each acknowledged with a
+//! `Map: <operation>` line or a `Warning:` saying why it couldn't be done;
+//! the older four-letter forms of some of them still work.
+
+use crate::benchmark::MAX_BENCHMARK_S;
+use crate::calibration::{Calibration, Measurement};
+use crate::capture::{Burst, Trigger, MAX_CAPTURE_S, MAX_POST_TRIGGER, MAX_PRE_TRIGGER};
+use crate::led::{DisplayMode, Rotation, MAX_BRIGHTNESS};
+use crate::mode::AppMode;
+use crate::settings::{OutputFormat, PowerMode, MAX_DECLINATION};
+use crate::sphere_map::MAP_SLOTS;
+use crate::survey::{Position, MAX_LATITUDE_E7, MAX_LONGITUDE_E7};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SerialCommand {
+    /// `SCAL`: start the calibration game.
+    ManualCal,
+    /// `SCAL STOP`: stop the calibration game, going back to the mode before
+    /// it.
+    StopCal,
+    /// `SCAL <center x>,<center y>,<center z>,<scale x>,<scale y>,<scale
+    /// z>,<radius>`: apply and save a calibration fitted elsewhere, in the
+    /// order `Calibration:` lines give it.
+    SetCalibration(Calibration),
+    /// `SROT <degrees>`: rotate the LED output clockwise.
+    SetRotation(Rotation),
+    /// `SHLD <ms>`: minimum time each LED frame is held.
+    SetDisplayHold(u16),
+    /// `SBRT <level>`: fixed brightness 1-9, or 0 to follow ambient light.
+    SetBrightness(u8),
+    /// `SMOD <mode>`: 0 compass, 1 clock, 2 level.
+    SetDisplayMode(DisplayMode),
+    /// `SFMT <format>`: 0 for `Measurement:` lines, 1 for CSV, 2 for binary
+    /// frames.
+    SetOutputFormat(OutputFormat),
+    /// `SRPT <samples>`: send every nth sample, 1-255.
+    SetReportEvery(u8),
+    /// `SFLT <weight>`: field smoothing 0-255, 0 for none.
+    SetSmoothing(u8),
+    /// `SDEC <tenths>`: declination in tenths of a degree, East positive.
+    SetDeclination(i16),
+    /// `SAPP <mode>`: 0 compass, 1 calibrate, 2 streaming only, 3 magnitude,
+    /// 4 sleep, 5 sphere mapping, 6 magnetic survey.
+    SetAppMode(AppMode),
+    /// `SPWR <mode>`: 0 normal, 1 battery, 2 high-rate.
+    SetPowerMode(PowerMode),
+    /// `SIDL <minutes>`: power down after 1-255 minutes without movement,
+    /// or 0 never.
+    SetSleepAfter(u8),
+    /// `SCAP <seconds>`: record 1-20 s of raw samples at 100 Hz, then dump
+    /// them. `STRM <uT>`: the same once the field's magnitude rises above
+    /// 1-65535 µT. `STRD <nT>`: once any axis changes by more than 1-65535
+    /// nT from one sample to the next.
+    Capture(Burst),
+    /// `STRM 0` or `STRD 0`: stop waiting for a trigger.
+    Disarm,
+    /// `SPRE <samples>`: samples a triggered capture keeps from before the
+    /// trigger, 0-1000.
+    SetPreTrigger(u16),
+    /// `SPST <samples>`: samples it keeps from the trigger on, 1-1000.
+    SetPostTrigger(u16),
+    /// `SBEN <seconds>`: time each stage of handling samples for 1-60 s,
+    /// then report.
+    Benchmark(u8),
+    /// `SPOS <lat>,<lon>`: the survey's position in decimal degrees, North
+    /// and East positive. `SWPT <n>`: the survey's position as waypoint
+    /// 0-65535.
+    SetPosition(Position),
+    /// `SMAP <operation> [number]`: one of the sphere map's commands.
+    Map(MapCommand),
+    Unknown,
+}
+
+/// The sphere map's commands, by the operation that follows `SMAP`, with
+/// the older form where there is one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapCommand {
+    /// `START`: add samples to the map, as it does from boot.
+    Start,
+    /// `STOP`: stop adding samples to the map, keeping what it has.
+    Stop,
+    /// `RESET`: forget every sample.
+    Reset,
+    /// `COV`, or `SCOV`: report how much of the map has been covered.
+    /// `COV <samples>`: the same, after setting the samples a cell needs to
+    /// count as covered, 1-65535.
+    Coverage(Option<u16>),
+    /// `EXPORT`, or `SMAP` alone: send every cell as a binary frame.
+    Export,
+    /// `SAVE [slot]`, or `SMSV [slot]`: save the map to flash, in slot 0
+    /// or 1.
+    Save(u8),
+    /// `LOAD [slot]`, or `SMLD [slot]`: replace the map with the one saved
+    /// in a slot.
+    Load(u8),
+    /// `MERGE [slot]`, or `SMMG [slot]`: merge the map saved in a slot into
+    /// this one.
+    Merge(u8),
+    /// `LIVE <0|1>`, or `SMLV <0|1>`: stop or start sending cells as they
+    /// change.
+    Live(bool),
+    /// `DIFF`, or `SDIF`: send the difference from the map saved in slot 0
+    /// to the one in slot 1, a binary frame per cell.
+    Diff,
+    /// `ANOM`, or `SANM`: list the cells whose average strength stands out
+    /// from the rest.
+    Anomalies,
+    /// `FIT`: refit the calibration from the samples the map has seen, and
+    /// offer it, as happens when the map is complete.
+    Fit,
+    /// `APPLY`: apply and save the calibration on offer.
+    Apply,
+}
+
+/// Cuts serial input into command lines, as every receive loop does: a
+/// line ends at a `\r` or `\n`, or once `N` bytes have come without one,
+/// the byte that found it full being dropped. Empty lines, such as the
+/// `\n` of a `\r\n`, are skipped rather than parsed as `Unknown`.
+pub struct LineReader<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> LineReader<N> {
+    pub const fn new() -> LineReader<N> {
+        LineReader {
+            buffer: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Takes the next byte, giving the line it finishes, if any.
+    pub fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        if byte == b'\r' || byte == b'\n' || self.len >= N {
+            let len = core::mem::replace(&mut self.len, 0);
+            return Some(&self.buffer[..len]).filter(|line| !line.is_empty());
+        }
+        self.buffer[self.len] = byte;
+        self.len += 1;
+        None
+    }
+
+    /// Forgets the unfinished line.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for LineReader<N> {
+    fn default() -> LineReader<N> {
+        LineReader::new()
+    }
+}
+
+pub fn parse_command(command: &[u8]) -> SerialCommand {
+    if command == b"SCAL" {
+        return SerialCommand::ManualCal;
+    }
+    if command == b"SCAL STOP" {
+        return SerialCommand::StopCal;
+    }
+    if let Some(calibration) = command.strip_prefix(b"SCAL").and_then(parse_calibration) {
+        return SerialCommand::SetCalibration(calibration);
+    }
+    if let Some(map) = parse_map_command(command) {
+        return SerialCommand::Map(map);
+    }
+    if let Some(arg) = command.strip_prefix(b"SROT") {
+        if let Some(rotation) = parse_number(arg).and_then(Rotation::from_degrees) {
+            return SerialCommand::SetRotation(rotation);
+        }
+    }
+    if let Some(hold_ms) = command.strip_prefix(b"SHLD").and_then(parse_number) {
+        return SerialCommand::SetDisplayHold(hold_ms);
+    }
+    if let Some(brightness) = command.strip_prefix(b"SBRT").and_then(parse_number) {
+        if brightness <= MAX_BRIGHTNESS as u16 {
+            return SerialCommand::SetBrightness(brightness as u8);
+        }
+    }
+    if let Some(index) = command.strip_prefix(b"SMOD").and_then(parse_number) {
+        if let Some(mode) = u8::try_from(index).ok().and_then(DisplayMode::from_index) {
+            return SerialCommand::SetDisplayMode(mode);
+        }
+    }
+    if let Some(index) = command.strip_prefix(b"SFMT").and_then(parse_number) {
+        if let Some(format) = u8::try_from(index).ok().and_then(OutputFormat::from_index) {
+            return SerialCommand::SetOutputFormat(format);
+        }
+    }
+    if let Some(samples) = command.strip_prefix(b"SRPT").and_then(parse_number) {
+        if let Ok(samples @ 1..) = u8::try_from(samples) {
+            return SerialCommand::SetReportEvery(samples);
+        }
+    }
+    if let Some(weight) = command.strip_prefix(b"SFLT").and_then(parse_number) {
+        if let Ok(weight) = u8::try_from(weight) {
+            return SerialCommand::SetSmoothing(weight);
+        }
+    }
+    if let Some(tenths) = command.strip_prefix(b"SDEC").and_then(parse_signed) {
+        if (-MAX_DECLINATION..=MAX_DECLINATION).contains(&tenths) {
+            return SerialCommand::SetDeclination(tenths);
+        }
+    }
+    if let Some(index) = command.strip_prefix(b"SAPP").and_then(parse_number) {
+        if let Some(mode) = u8::try_from(index).ok().and_then(AppMode::from_index) {
+            return SerialCommand::SetAppMode(mode);
+        }
+    }
+    if let Some(index) = command.strip_prefix(b"SPWR").and_then(parse_number) {
+        if let Some(power) = u8::try_from(index).ok().and_then(PowerMode::from_index) {
+            return SerialCommand::SetPowerMode(power);
+        }
+    }
+    if let Some(minutes) = command.strip_prefix(b"SIDL").and_then(parse_number) {
+        if let Ok(minutes) = u8::try_from(minutes) {
+            return SerialCommand::SetSleepAfter(minutes);
+        }
+    }
+    if let Some(seconds) = command.strip_prefix(b"SCAP").and_then(parse_number) {
+        if let Ok(seconds @ 1..=MAX_CAPTURE_S) = u8::try_from(seconds) {
+            return SerialCommand::Capture(Burst::Timed(seconds));
+        }
+    }
+    if let Some(threshold_ut) = command.strip_prefix(b"STRM").and_then(parse_number) {
+        return match threshold_ut {
+            0 => SerialCommand::Disarm,
+            _ => SerialCommand::Capture(Burst::Triggered(Trigger::magnitude_ut(threshold_ut))),
+        };
+    }
+    if let Some(threshold_nt) = command.strip_prefix(b"STRD").and_then(parse_number) {
+        return match threshold_nt {
+            0 => SerialCommand::Disarm,
+            _ => SerialCommand::Capture(Burst::Triggered(Trigger::Delta(threshold_nt as u32))),
+        };
+    }
+    if let Some(samples) = command.strip_prefix(b"SPRE").and_then(parse_number) {
+        if samples <= MAX_PRE_TRIGGER {
+            return SerialCommand::SetPreTrigger(samples);
+        }
+    }
+    if let Some(samples @ 1..) = command.strip_prefix(b"SPST").and_then(parse_number) {
+        if samples <= MAX_POST_TRIGGER {
+            return SerialCommand::SetPostTrigger(samples);
+        }
+    }
+    if let Some(seconds) = command.strip_prefix(b"SBEN").and_then(parse_number) {
+        if let Ok(seconds @ 1..=MAX_BENCHMARK_S) = u8::try_from(seconds) {
+            return SerialCommand::Benchmark(seconds);
+        }
+    }
+    if let Some(position) = command.strip_prefix(b"SPOS").and_then(parse_coordinates) {
+        return SerialCommand::SetPosition(position);
+    }
+    if let Some(waypoint) = command.strip_prefix(b"SWPT").and_then(parse_number) {
+        return SerialCommand::SetPosition(Position::Waypoint(waypoint));
+    }
+    SerialCommand::Unknown
+}
+
+/// A sphere map command, from the `SMAP` family or one of the older forms.
+fn parse_map_command(command: &[u8]) -> Option<MapCommand> {
+    let (operation, arg): (&[u8], &[u8]) = match command.strip_prefix(b"SMAP") {
+        Some(rest) => {
+            let rest = rest.trim_ascii_start();
+            rest.split_at(rest.iter().position(|b| *b == b' ').unwrap_or(rest.len()))
+        }
+        None => {
+            let (name, arg) = command.split_at(command.len().min(4));
+            let operation: &[u8] = match name {
+                b"SCOV" => b"COV",
+                b"SMSV" => b"SAVE",
+                b"SMLD" => b"LOAD",
+                b"SMMG" => b"MERGE",
+                b"SMLV" => b"LIVE",
+                b"SDIF" => b"DIFF",
+                b"SANM" => b"ANOM",
+                _ => return None,
+            };
+            (operation, arg)
+        }
+    };
+    match operation {
+        b"" | b"EXPORT" if arg.is_empty() => Some(MapCommand::Export),
+        b"START" if arg.is_empty() => Some(MapCommand::Start),
+        b"STOP" if arg.is_empty() => Some(MapCommand::Stop),
+        b"RESET" if arg.is_empty() => Some(MapCommand::Reset),
+        b"COV" if arg.is_empty() => Some(MapCommand::Coverage(None)),
+        b"COV" => match parse_number(arg)? {
+            0 => None,
+            samples => Some(MapCommand::Coverage(Some(samples))),
+        },
+        b"SAVE" => parse_slot(arg).map(MapCommand::Save),
+        b"LOAD" => parse_slot(arg).map(MapCommand::Load),
+        b"MERGE" => parse_slot(arg).map(MapCommand::Merge),
+        b"LIVE" => match parse_number(arg)? {
+            0 => Some(MapCommand::Live(false)),
+            1 => Some(MapCommand::Live(true)),
+            _ => None,
+        },
+        b"DIFF" if arg.is_empty() => Some(MapCommand::Diff),
+        b"ANOM" if arg.is_empty() => Some(MapCommand::Anomalies),
+        b"FIT" if arg.is_empty() => Some(MapCommand::Fit),
+        b"APPLY" if arg.is_empty() => Some(MapCommand::Apply),
+        _ => None,
+    }
+}
+
+fn parse_number(arg: &[u8]) -> Option<u16> {
+    core::str::from_utf8(arg).ok()?.trim().parse().ok()
+}
+
+/// A saved sphere map's slot, 0 if left out.
+fn parse_slot(arg: &[u8]) -> Option<u8> {
+    if arg.is_empty() {
+        return Some(0);
+    }
+    u8::try_from(parse_number(arg)?)
+        .ok()
+        .filter(|slot| *slot < MAP_SLOTS)
+}
+
+/// The seven numbers of a `Calibration:` line, comma-separated. Scales
+/// are in 1/1024ths and have to be positive.
+pub(crate) fn parse_calibration(arg: &[u8]) -> Option<Calibration> {
+    let mut fields = [0i32; 7];
+    let mut parts = core::str::from_utf8(arg).ok()?.split(',');
+    for field in fields.iter_mut() {
+        *field = parts.next()?.trim().parse().ok()?;
+    }
+    if parts.next().is_some() || fields[3..6].iter().any(|scale| *scale <= 0) || fields[6] < 0 {
+        return None;
+    }
+    Some(Calibration {
+        center: Measurement {
+            x: fields[0],
+            y: fields[1],
+            z: fields[2],
+        },
+        scale: Measurement {
+            x: fields[3],
+            y: fields[4],
+            z: fields[5],
+        },
+        radius: fields[6] as u32,
+    })
+}
+
+/// `<lat>,<lon>` in decimal degrees, to seven decimal places.
+fn parse_coordinates(arg: &[u8]) -> Option<Position> {
+    let arg = core::str::from_utf8(arg).ok()?;
+    let (lat, lon) = arg.split_once(',')?;
+    let lat_e7 = parse_degrees_e7(lat.trim())?;
+    let lon_e7 = parse_degrees_e7(lon.trim())?;
+    if lat_e7.abs() > MAX_LATITUDE_E7 || lon_e7.abs() > MAX_LONGITUDE_E7 {
+        return None;
+    }
+    Some(Position::Coordinates { lat_e7, lon_e7 })
+}
+
+/// Decimal degrees in 10⁻⁷ degrees, without going through floats, which
+/// can't hold a longitude that finely.
+fn parse_degrees_e7(degrees: &str) -> Option<i32> {
+    let (negative, degrees) = match degrees.strip_prefix('-') {
+        Some(degrees) => (true, degrees),
+        None => (false, degrees),
+    };
+    let (whole, fraction) = degrees.split_once('.').unwrap_or((degrees, ""));
+    if fraction.len() > 7 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // Anything over 180 is out of range anyway, and would overflow.
+    let whole = whole.parse::<u8>().ok().filter(|whole| *whole <= 180)?;
+    let mut value = whole as i32 * 10_000_000;
+    let mut scale = 1_000_000;
+    for digit in fraction.bytes() {
+        value += (digit - b'0') as i32 * scale;
+        scale /= 10;
+    }
+    Some(if negative { -value } else { value })
+}
+
+fn parse_signed(arg: &[u8]) -> Option<i16> {
+    core::str::from_utf8(arg).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// The commands `input` makes, as a receive loop would parse them.
+    fn commands(input: &[u8]) -> Vec<SerialCommand> {
+        let mut reader = LineReader::<64>::new();
+        input
+            .iter()
+            .filter_map(|byte| reader.push(*byte).map(parse_command))
+            .collect()
+    }
+
+    #[test]
+    fn crlf_ends_one_command() {
+        assert_eq!(commands(b"SCAL\r\n"), [SerialCommand::ManualCal]);
+    }
+
+    #[test]
+    fn blank_lines_are_skipped() {
+        assert_eq!(
+            commands(b"\r\n\nSCAL\n\r\rSCAL STOP\r"),
+            [SerialCommand::ManualCal, SerialCommand::StopCal]
+        );
+    }
+
+    #[test]
+    fn unfinished_line_waits() {
+        assert_eq!(commands(b"SCAL"), []);
+    }
+
+    #[test]
+    fn full_buffer_ends_line() {
+        let mut reader = LineReader::<4>::new();
+        let lines: Vec<Vec<u8>> = b"SCALXSCAL\n"
+            .iter()
+            .filter_map(|byte| reader.push(*byte).map(<[u8]>::to_vec))
+            .collect();
+        // The X found the buffer full, and went with it.
+        assert_eq!(lines, [b"SCAL".to_vec(), b"SCAL".to_vec()]);
+    }
+
+    #[test]
+    fn clear_forgets_unfinished_line() {
+        let mut reader = LineReader::<64>::new();
+        for byte in b"SCA" {
+            reader.push(*byte);
+        }
+        reader.clear();
+        assert_eq!(reader.push(b'\n'), None);
+    }
+
+    fn map(command: &[u8]) -> SerialCommand {
+        parse_command(command)
+    }
+
+    #[test]
+    fn map_commands() {
+        use MapCommand::*;
+        let cases: [(&[u8], MapCommand); 21] = [
+            (b"SMAP", Export),
+            (b"SMAP EXPORT", Export),
+            (b"SMAP START", Start),
+            (b"SMAP STOP", Stop),
+            (b"SMAP RESET", Reset),
+            (b"SMAP COV", Coverage(None)),
+            (b"SMAP COV 25", Coverage(Some(25))),
+            (b"SMAP SAVE", Save(0)),
+            (b"SMAP SAVE 1", Save(1)),
+            (b"SMAP LOAD 1", Load(1)),
+            (b"SMAP MERGE", Merge(0)),
+            (b"SMAP LIVE 0", Live(false)),
+            (b"SMAP LIVE 1", Live(true)),
+            (b"SMAP DIFF", Diff),
+            (b"SMAP ANOM", Anomalies),
+            (b"SMAP FIT", Fit),
+            (b"SMAP APPLY", Apply),
+            // Extra spaces before the operation and the number are fine.
+            (b"SMAP  START", Start),
+            (b"SMAP COV  7", Coverage(Some(7))),
+            (b"SMAP LOAD", Load(0)),
+            (b"SMAP MERGE 1", Merge(1)),
+        ];
+        for (command, expected) in cases {
+            assert_eq!(map(command), SerialCommand::Map(expected), "{command:?}");
+        }
+    }
+
+    #[test]
+    fn older_map_commands() {
+        use MapCommand::*;
+        let cases: [(&[u8], MapCommand); 9] = [
+            (b"SCOV", Coverage(None)),
+            (b"SCOV 12", Coverage(Some(12))),
+            (b"SMSV", Save(0)),
+            (b"SMSV 1", Save(1)),
+            (b"SMLD 1", Load(1)),
+            (b"SMMG", Merge(0)),
+            (b"SMLV 1", Live(true)),
+            (b"SDIF", Diff),
+            (b"SANM", Anomalies),
+        ];
+        for (command, expected) in cases {
+            assert_eq!(map(command), SerialCommand::Map(expected), "{command:?}");
+        }
+    }
+
+    #[test]
+    fn bad_map_commands_are_unknown() {
+        let cases: [&[u8]; 14] = [
+            b"SMAP COV 0",
+            b"SMAP COV 65536",
+            b"SMAP SAVE 2",
+            b"SMAP LOAD -1",
+            b"SMAP LIVE 2",
+            b"SMAP START now",
+            b"SMAP FIT 1",
+            b"SMAP FITX",
+            b"SMAP SAVE 1 2",
+            b"SMAP NOPE",
+            b"smap start",
+            b"SCOVX",
+            b"SDIF 1",
+            b"SMLV",
+        ];
+        for command in cases {
+            assert_eq!(map(command), SerialCommand::Unknown, "{command:?}");
+        }
+    }
+
+    fn position(command: &[u8]) -> Option<(i32, i32)> {
+        match parse_command(command) {
+            SerialCommand::SetPosition(Position::Coordinates { lat_e7, lon_e7 }) => {
+                Some((lat_e7, lon_e7))
+            }
+            SerialCommand::Unknown => None,
+            other => panic!("{command:?} gave {other:?}"),
+        }
+    }
+
+    #[test]
+    fn coordinates() {
+        assert_eq!(
+            position(b"SPOS 51.5,-0.1275"),
+            Some((515_000_000, -1_275_000))
+        );
+        assert_eq!(
+            position(b"SPOS -33.8688197, 151.2092955"),
+            Some((-338_688_197, 1_512_092_955))
+        );
+        assert_eq!(position(b"SPOS -0.5,0"), Some((-5_000_000, 0)));
+        assert_eq!(position(b"SPOS 12,-7"), Some((120_000_000, -70_000_000)));
+        assert_eq!(
+            position(b"SPOS 90,-180"),
+            Some((MAX_LATITUDE_E7, -MAX_LONGITUDE_E7))
+        );
+    }
+
+    #[test]
+    fn bad_coordinates_are_unknown() {
+        let cases: [&[u8]; 10] = [
+            b"SPOS 90.0000001,0",
+            b"SPOS 0,180.5",
+            b"SPOS -91,0",
+            b"SPOS 256,0",
+            b"SPOS 1.12345678,0",
+            b"SPOS 1.5",
+            b"SPOS 1.5,2,3",
+            b"SPOS 1.x,2",
+            b"SPOS --1,2",
+            b"SPOS 1.5,2 N",
+        ];
+        for command in cases {
+            assert_eq!(position(command), None, "{command:?}");
+        }
+    }
+
+    #[test]
+    fn waypoints() {
+        assert_eq!(
+            parse_command(b"SWPT 42"),
+            SerialCommand::SetPosition(Position::Waypoint(42))
+        );
+        assert_eq!(parse_command(b"SWPT 65536"), SerialCommand::Unknown);
+    }
+}
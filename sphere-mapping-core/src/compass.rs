@@ -0,0 +1,153 @@
+//! The per-sample pipeline from a sensor reading to what the matrix shows.
+
+#[cfg(not(feature = "fixed-point"))]
+use libm::powf;
+
+use crate::calibration::{calibrated_measurement, Calibration, Measurement};
+use crate::config::SAMPLE_RATE_HZ;
+use crate::device::{Acceleration, Sample};
+use crate::fixed;
+use crate::led::{theta_from_field, Frame, Rotation, Trail, UncalibratedWarning, View};
+use crate::mode::AppMode;
+use crate::settings::{PowerMode, Settings};
+use crate::sphere_map::SphereMap;
+
+/// The state the pipeline keeps between samples.
+pub struct Compass {
+    smoothed: Option<Measurement>,
+    /// When `smoothed` was last updated, in microseconds.
+    smoothed_at: Option<u64>,
+    trail: Trail<5>,
+    warning: UncalibratedWarning,
+}
+
+/// Everything worked out from one sample.
+pub struct Heading {
+    /// The calibrated and smoothed field in nT, in the compass's frame.
+    pub field: Measurement,
+    pub view: View,
+    /// `view` rendered for the matrix, with the trail and rotation applied.
+    pub frame: Frame,
+}
+
+impl Heading {
+    /// Angle of the field in the board's plane, in radians. Worked out on
+    /// request, since the matrix only needs the field's octant.
+    pub fn theta(&self) -> f32 {
+        theta_from_field(self.field.x, self.field.y)
+    }
+}
+
+impl Default for Compass {
+    fn default() -> Compass {
+        Compass::new()
+    }
+}
+
+impl Compass {
+    pub const fn new() -> Compass {
+        Compass {
+            smoothed: None,
+            smoothed_at: None,
+            trail: Trail::new(),
+            warning: UncalibratedWarning::new(),
+        }
+    }
+
+    /// Applies `calibration` to a sample's field, smooths it, and works out
+    /// what to show for it and the acceleration in `mode` with `settings`.
+    /// Sphere mapping shows the way round `sphere_map`, or nothing without
+    /// one. Nothing is shown in battery mode.
+    pub fn update(
+        &mut self,
+        sample: Sample,
+        calibration: &Calibration,
+        calibrated: bool,
+        mode: AppMode,
+        settings: &Settings,
+        sphere_map: Option<&SphereMap>,
+    ) -> Heading {
+        let field = self.smooth(
+            calibrated_measurement(sample.field, calibration),
+            settings.smoothing,
+            sample.timestamp_us,
+        );
+        let accel = sample.accel;
+        let view = match mode {
+            _ if settings.power == PowerMode::Battery => View::Blank,
+            AppMode::Compass => {
+                settings
+                    .display_mode
+                    .view(&field, settings.declination, accel.x, accel.y, accel.z)
+            }
+            AppMode::Magnitude | AppMode::Survey => View::Magnitude(fixed::magnitude(&field)),
+            AppMode::Mapping => sphere_map.map_or(View::Blank, |map| map.guide(&accel).view()),
+            AppMode::Calibrating | AppMode::StreamingOnly | AppMode::Sleep => View::Blank,
+        };
+        let view = self.warning.apply(view, calibrated);
+        let frame = settings.rotation.apply(self.trail.follow(&view));
+        Heading { field, view, frame }
+    }
+
+    /// The frame for an accelerometer reading alone, for when the
+    /// magnetometer is out of action: only the level can be shown.
+    pub fn level(&mut self, accel: Acceleration, rotation: Rotation) -> Frame {
+        rotation.apply(self.trail.follow(&View::Level(accel.x, accel.y, accel.z)))
+    }
+
+    /// Blends `field` into the running average, keeping `smoothing`/256 of
+    /// the previous value per sample period at the configured rate. A longer
+    /// or shorter gap since the last sample keeps less or more of it, so the
+    /// filter's time constant holds when samples come late or are dropped.
+    fn smooth(&mut self, field: Measurement, smoothing: u8, timestamp_us: u64) -> Measurement {
+        let elapsed_us = self
+            .smoothed_at
+            .map(|last| timestamp_us.saturating_sub(last));
+        self.smoothed_at = Some(timestamp_us);
+        let weight = weight(smoothing, elapsed_us);
+        let blend =
+            |old: i32, new: i32| ((old as i64 * weight + new as i64 * (256 - weight)) / 256) as i32;
+        let smoothed = match self.smoothed {
+            Some(old) if smoothing > 0 => Measurement {
+                x: blend(old.x, field.x),
+                y: blend(old.y, field.y),
+                z: blend(old.z, field.z),
+            },
+            _ => field,
+        };
+        self.smoothed = Some(smoothed);
+        smoothed
+    }
+}
+
+/// `smoothing`/256 raised to the number of sample periods in `elapsed_us`,
+/// one without a previous sample, as a weight out of 256.
+#[cfg(not(feature = "fixed-point"))]
+fn weight(smoothing: u8, elapsed_us: Option<u64>) -> i64 {
+    let periods = match elapsed_us {
+        Some(elapsed) => elapsed as f32 * SAMPLE_RATE_HZ as f32 / 1_000_000.,
+        None => 1.,
+    };
+    (powf(smoothing as f32 / 256., periods) * 256.) as i64
+}
+
+/// `smoothing`/256 raised to the number of sample periods in `elapsed_us`,
+/// one without a previous sample, as a weight out of 256. The periods are
+/// rounded to a whole number, and the power taken by squaring in Q16.
+#[cfg(feature = "fixed-point")]
+fn weight(smoothing: u8, elapsed_us: Option<u64>) -> i64 {
+    let mut periods = match elapsed_us {
+        Some(elapsed) => (elapsed.saturating_mul(SAMPLE_RATE_HZ as u64) + 500_000) / 1_000_000,
+        None => 1,
+    };
+    let mut base = (smoothing as i64) << 8;
+    let mut power = 1 << 16;
+    while periods > 0 && power > 0 {
+        if periods & 1 == 1 {
+            power = (power * base) >> 16;
+        }
+        base = (base * base) >> 16;
+        periods >>= 1;
+    }
+    power >> 8
+}
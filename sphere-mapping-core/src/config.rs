@@ -0,0 +1,184 @@
+//! Build-time settings that differ between deployments, in one place. Each
+//! has a default here and can be overridden with the environment variable
+//! named in its doc comment when building, for example
+//! `SPHERE_SAMPLE_RATE_HZ=50 cargo build --release`. A value that doesn't
+//! parse, or that the hardware can't do, fails the build.
+
+use crate::calibration::{Calibration, Measurement};
+
+/// Serial baud rate. `SPHERE_BAUD_RATE`: 9600, 19200, 38400, 57600, 115200,
+/// 230400, 460800, 921600 or 1000000.
+pub const BAUD_RATE: u32 = env_u32(option_env!("SPHERE_BAUD_RATE"), 115_200);
+
+/// Output data rate of the accelerometer and magnetometer, and so the rate
+/// of `Measurement:` lines. `SPHERE_SAMPLE_RATE_HZ`: 10, 50 or 100, the
+/// rates both halves of the LSM303AGR share.
+pub const SAMPLE_RATE_HZ: u32 = env_u32(option_env!("SPHERE_SAMPLE_RATE_HZ"), 10);
+
+/// Full scale of the accelerometer in g. `SPHERE_ACCEL_SCALE_G`: 2, 4, 8 or
+/// 16. Readings are in mg whatever the scale.
+pub const ACCEL_SCALE_G: u32 = env_u32(option_env!("SPHERE_ACCEL_SCALE_G"), 2);
+
+/// How long the sad face stays up after a panic before the board resets, in
+/// milliseconds. `SPHERE_PANIC_HOLD_MS`.
+pub const PANIC_HOLD_MS: u32 = env_u32(option_env!("SPHERE_PANIC_HOLD_MS"), 2_000);
+
+/// Weight of a new ambient light reading in the smoothed level used for
+/// automatic brightness, in 1/256ths. `SPHERE_AMBIENT_SMOOTHING`: 1 to 256.
+pub const AMBIENT_SMOOTHING: u32 = env_u32(option_env!("SPHERE_AMBIENT_SMOOTHING"), 64);
+
+/// Time without a feed before the watchdog resets the board, in
+/// milliseconds. Long enough for the slowest stretch between feeds: the
+/// boot animation takes about 1.3 s. `SPHERE_WATCHDOG_TIMEOUT_MS`.
+pub const WATCHDOG_TIMEOUT_MS: u32 = env_u32(option_env!("SPHERE_WATCHDOG_TIMEOUT_MS"), 3_000);
+
+/// Cells in the sphere map. `SPHERE_MAP_CELLS`: 12, 42, 162 or 642 for a
+/// geodesic grid of nearly equal cells, or 72 for 30° bands of latitude and
+/// longitude.
+pub const SPHERE_MAP_CELLS: u32 = env_u32(option_env!("SPHERE_MAP_CELLS"), 162);
+
+/// RAM the sphere map may take, in bytes; a grid that needs more fails the
+/// build. The burst capture buffer and the rest of the firmware need most
+/// of the 128K, so raise it only after dropping something else.
+/// `SPHERE_MAP_RAM_BUDGET`: up to 32768.
+pub const SPHERE_MAP_RAM_BUDGET: u32 = env_u32(option_env!("SPHERE_MAP_RAM_BUDGET"), 16_384);
+
+/// Calibration used until a board has been calibrated itself, precomputed
+/// for the development board. `SPHERE_CALIBRATION`: the seven numbers of a
+/// `Calibration:` line, comma-separated.
+pub const CALIBRATION: Calibration = match option_env!("SPHERE_CALIBRATION") {
+    Some(value) => parse_calibration(value.as_bytes()),
+    None => Calibration {
+        center: Measurement {
+            x: 20962,
+            y: 34322,
+            z: -23924,
+        },
+        scale: Measurement {
+            x: 1203,
+            y: 1177,
+            z: 1133,
+        },
+        radius: 48098,
+    },
+};
+
+/// The commit the firmware was built from, given after its version in the
+/// boot banner's `Version:` line so a board can be matched to its source.
+/// `SPHERE_GIT_HASH`, which `cargo xtask` sets from `git`; left out, the
+/// banner gives the version alone.
+pub const GIT_HASH: Option<&str> = option_env!("SPHERE_GIT_HASH");
+
+const _: () = {
+    assert!(
+        matches!(
+            BAUD_RATE,
+            9_600 | 19_200 | 38_400 | 57_600 | 115_200 | 230_400 | 460_800 | 921_600 | 1_000_000
+        ),
+        "unsupported SPHERE_BAUD_RATE"
+    );
+    assert!(
+        matches!(SAMPLE_RATE_HZ, 10 | 50 | 100),
+        "SPHERE_SAMPLE_RATE_HZ must be 10, 50 or 100"
+    );
+    assert!(
+        matches!(ACCEL_SCALE_G, 2 | 4 | 8 | 16),
+        "SPHERE_ACCEL_SCALE_G must be 2, 4, 8 or 16"
+    );
+    assert!(
+        AMBIENT_SMOOTHING >= 1 && AMBIENT_SMOOTHING <= 256,
+        "SPHERE_AMBIENT_SMOOTHING must be 1 to 256"
+    );
+    assert!(
+        matches!(SPHERE_MAP_CELLS, 12 | 42 | 72 | 162 | 642),
+        "SPHERE_MAP_CELLS must be 12, 42, 72, 162 or 642"
+    );
+    assert!(
+        SPHERE_MAP_RAM_BUDGET <= 32_768,
+        "SPHERE_MAP_RAM_BUDGET can't be more than 32768"
+    );
+};
+
+const fn env_u32(value: Option<&str>, default: u32) -> u32 {
+    match value {
+        Some(value) => {
+            let bytes = value.as_bytes();
+            let number = parse_i32(bytes, 0, bytes.len());
+            assert!(number >= 0, "a SPHERE_* setting can't be negative");
+            number as u32
+        }
+        None => default,
+    }
+}
+
+const fn parse_calibration(bytes: &[u8]) -> Calibration {
+    let mut fields = [0; 7];
+    let mut field = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i <= bytes.len() {
+        if i == bytes.len() || bytes[i] == b',' {
+            assert!(
+                field < fields.len(),
+                "SPHERE_CALIBRATION has too many numbers"
+            );
+            fields[field] = parse_i32(bytes, start, i);
+            field += 1;
+            start = i + 1;
+        }
+        i += 1;
+    }
+    assert!(
+        field == fields.len(),
+        "SPHERE_CALIBRATION needs seven numbers"
+    );
+    assert!(
+        fields[6] >= 0,
+        "the radius in SPHERE_CALIBRATION can't be negative"
+    );
+    Calibration {
+        center: Measurement {
+            x: fields[0],
+            y: fields[1],
+            z: fields[2],
+        },
+        scale: Measurement {
+            x: fields[3],
+            y: fields[4],
+            z: fields[5],
+        },
+        radius: fields[6] as u32,
+    }
+}
+
+/// Parses a decimal integer from `bytes[start..end]`, ignoring spaces.
+const fn parse_i32(bytes: &[u8], start: usize, end: usize) -> i32 {
+    let mut negative = false;
+    let mut digits = 0;
+    let mut number: i32 = 0;
+    let mut i = start;
+    while i < end {
+        match bytes[i] {
+            b' ' => {}
+            b'-' if digits == 0 && !negative => negative = true,
+            digit @ b'0'..=b'9' => {
+                number = match number.checked_mul(10) {
+                    Some(n) => match n.checked_add((digit - b'0') as i32) {
+                        Some(n) => n,
+                        None => panic!("number in a SPHERE_* setting is too large"),
+                    },
+                    None => panic!("number in a SPHERE_* setting is too large"),
+                };
+                digits += 1;
+            }
+            _ => panic!("a SPHERE_* setting isn't a number"),
+        }
+        i += 1;
+    }
+    assert!(digits > 0, "a SPHERE_* setting is missing a number");
+    if negative {
+        -number
+    } else {
+        number
+    }
+}
@@ -0,0 +1,55 @@
+//! The hardware the compass logic runs on, as traits. Everything in this
+//! crate that needs a sensor or a display goes through these, so the same
+//! code drives the LSM303AGR and the 5x5 matrix, another sensor, or a
+//! simulation on the host.
+
+use crate::calibration::Measurement;
+use crate::led::Frame;
+
+/// An accelerometer reading in mg.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Acceleration {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+/// A magnetometer and an accelerometer reading taken together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// In nT, in the sensor's own axes.
+    pub field: Measurement,
+    pub accel: Acceleration,
+    /// When the sensor had the pair ready, in microseconds on a monotonic
+    /// clock.
+    pub timestamp_us: u64,
+    /// How far apart the two readings became ready, in microseconds.
+    pub skew_us: u32,
+}
+
+impl Sample {
+    /// Pairs a field and an acceleration that became ready at the given
+    /// times. The sample is as old as the later of the two.
+    pub fn pair(field: Measurement, field_us: u64, accel: Acceleration, accel_us: u64) -> Sample {
+        Sample {
+            field,
+            accel,
+            timestamp_us: field_us.max(accel_us),
+            skew_us: field_us.abs_diff(accel_us).min(u32::MAX as u64) as u32,
+        }
+    }
+}
+
+pub trait SampleSource {
+    type Error;
+
+    /// Waits until both the magnetometer and the accelerometer have a new
+    /// reading, picking each up as soon as it is ready rather than waiting
+    /// for one and then the other.
+    fn read_sample(&mut self) -> Result<Sample, Self::Error>;
+}
+
+/// A display that can show the 5x5 frames the compass renders.
+pub trait CompassDisplay {
+    fn show(&mut self, frame: Frame);
+}
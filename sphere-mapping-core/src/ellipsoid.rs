@@ -0,0 +1,413 @@
+//! Least-squares ellipsoid fitting, for calibrating from many more samples
+//! than the board's own calibration game takes, and fitting them properly:
+//! the game looks for the centre by stepping towards it and then stretches
+//! the axes from the spread of its 25 points.
+//!
+//! The firmware's [`Calibration`] removes an offset and scales each axis,
+//! so the fit is of an ellipsoid with its axes along the sensor's,
+//!
+//! `a x² + b y² + c z² + d x + e y + f z = 1`,
+//!
+//! solved for its six coefficients by linear least squares over the raw
+//! fields, in the frame calibrations are fitted in, after centring and
+//! scaling them so the equations stay well conditioned. Each axis is then
+//! scaled to the ellipsoid's mean radius, the geometric mean of its three,
+//! which keeps the field's strength where it was on average.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use libm::{cbrt, fabs, round, sqrt};
+
+use crate::calibration::{calibrated_measurement, measurement_to_enu, Calibration, Measurement};
+use crate::fixed;
+
+/// Fewest samples worth fitting; six would do for the equations, but not
+/// for the noise.
+pub const MIN_SAMPLES: usize = 50;
+/// Smallest determinant of the normalised samples' covariance that counts
+/// as spread through three dimensions: a sphere's is 1/27, and samples
+/// within 2% of the spread of a plane, as when the board was only turned
+/// about one axis, come to about 1e-4. Below that the equations are still
+/// solvable once the readings are rounded, but only by fitting the
+/// rounding.
+const MIN_VOLUME: f64 = 1e-4;
+
+/// Why a fit failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FitError {
+    TooFewSamples(usize),
+    /// The samples don't pin down an ellipsoid, as when the board was only
+    /// turned about one axis.
+    Degenerate,
+}
+
+impl core::fmt::Display for FitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FitError::TooFewSamples(samples) => write!(
+                f,
+                "{samples} samples are too few to fit, at least {MIN_SAMPLES} are needed"
+            ),
+            FitError::Degenerate => write!(
+                f,
+                "the samples don't fit an ellipsoid; turn the board through every orientation"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for FitError {}
+
+/// A fitted calibration and how well it fits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fit {
+    pub calibration: Calibration,
+    pub samples: usize,
+    /// The ellipsoid's radii along its axes, in nT.
+    pub radii: [f64; 3],
+    /// The RMS of the calibrated strengths' differences from their mean, in
+    /// nT, as the firmware would calibrate them.
+    pub residual_nt: f64,
+}
+
+/// Fits a calibration to raw fields in nT, in the sensor's own axes.
+pub fn fit(raw: &[Measurement]) -> Result<Fit, FitError> {
+    if raw.len() < MIN_SAMPLES {
+        return Err(FitError::TooFewSamples(raw.len()));
+    }
+    let points: Vec<[f64; 3]> = raw
+        .iter()
+        .map(|&point| {
+            let enu = measurement_to_enu(point);
+            [enu.x as f64, enu.y as f64, enu.z as f64]
+        })
+        .collect();
+    let count = points.len() as f64;
+    let mut mean = [0.; 3];
+    for point in &points {
+        for (mean, value) in mean.iter_mut().zip(point) {
+            *mean += value / count;
+        }
+    }
+    let spread = sqrt(
+        points
+            .iter()
+            .map(|point| (0..3).map(|i| squared(point[i] - mean[i])).sum::<f64>())
+            .sum::<f64>()
+            / count,
+    );
+    if spread == 0. {
+        return Err(FitError::Degenerate);
+    }
+    let normalised: Vec<[f64; 3]> = points
+        .iter()
+        .map(|point| [0, 1, 2].map(|i| (point[i] - mean[i]) / spread))
+        .collect();
+    if determinant(covariance(&normalised)) < MIN_VOLUME {
+        return Err(FitError::Degenerate);
+    }
+
+    // The normal equations of the fit over the normalised points.
+    let mut normal = [[0.; 7]; 6];
+    for &[x, y, z] in &normalised {
+        let row = [x * x, y * y, z * z, x, y, z];
+        for i in 0..6 {
+            for j in 0..6 {
+                normal[i][j] += row[i] * row[j];
+            }
+            normal[i][6] += row[i];
+        }
+    }
+    let [a, b, c, d, e, f] = solve(normal).ok_or(FitError::Degenerate)?;
+    if a <= 0. || b <= 0. || c <= 0. {
+        return Err(FitError::Degenerate);
+    }
+    let squares = [a, b, c];
+    let center = [d / (-2. * a), e / (-2. * b), f / (-2. * c)];
+    let g = 1. + d * d / (4. * a) + e * e / (4. * b) + f * f / (4. * c);
+    let radii = squares.map(|square| sqrt(g / square) * spread);
+    let radius = cbrt(radii[0] * radii[1] * radii[2]);
+    let calibration = Calibration {
+        center: Measurement {
+            x: round(mean[0] + center[0] * spread) as i32,
+            y: round(mean[1] + center[1] * spread) as i32,
+            z: round(mean[2] + center[2] * spread) as i32,
+        },
+        scale: Measurement {
+            x: round(1024. * radius / radii[0]) as i32,
+            y: round(1024. * radius / radii[1]) as i32,
+            z: round(1024. * radius / radii[2]) as i32,
+        },
+        radius: round(radius) as u32,
+    };
+    let scale = calibration.scale;
+    if !radius.is_finite() || scale.x <= 0 || scale.y <= 0 || scale.z <= 0 {
+        return Err(FitError::Degenerate);
+    }
+    Ok(Fit {
+        calibration,
+        samples: raw.len(),
+        radii,
+        residual_nt: residual(raw, &calibration),
+    })
+}
+
+/// The RMS of the calibrated strengths' differences from their mean.
+pub fn residual(raw: &[Measurement], calibration: &Calibration) -> f64 {
+    let strengths: Vec<f64> = raw
+        .iter()
+        .map(|&point| fixed::magnitude(&calibrated_measurement(point, calibration)) as f64)
+        .collect();
+    let mean = strengths.iter().sum::<f64>() / strengths.len() as f64;
+    sqrt(
+        strengths
+            .iter()
+            .map(|strength| squared(strength - mean))
+            .sum::<f64>()
+            / strengths.len() as f64,
+    )
+}
+
+fn squared(value: f64) -> f64 {
+    value * value
+}
+
+/// The covariance of points whose mean is the origin.
+fn covariance(points: &[[f64; 3]]) -> [[f64; 3]; 3] {
+    let mut covariance = [[0.; 3]; 3];
+    for point in points {
+        for i in 0..3 {
+            for j in 0..3 {
+                covariance[i][j] += point[i] * point[j] / points.len() as f64;
+            }
+        }
+    }
+    covariance
+}
+
+fn determinant(m: [[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+/// Solves six linear equations, each row its coefficients and then the
+/// right-hand side, by Gaussian elimination with partial pivoting. `None`
+/// if they have no single solution.
+fn solve(mut rows: [[f64; 7]; 6]) -> Option<[f64; 6]> {
+    for column in 0..6 {
+        let pivot =
+            (column..6).max_by(|&i, &j| fabs(rows[i][column]).total_cmp(&fabs(rows[j][column])))?;
+        if fabs(rows[pivot][column]) < 1e-12 {
+            return None;
+        }
+        rows.swap(column, pivot);
+        let pivot_row = rows[column];
+        for row in &mut rows[column + 1..] {
+            let factor = row[column] / pivot_row[column];
+            for (value, pivot_value) in row.iter_mut().zip(pivot_row).skip(column) {
+                *value -= factor * pivot_value;
+            }
+        }
+    }
+    let mut solution = [0.; 6];
+    for row in (0..6).rev() {
+        let known: f64 = (row + 1..6).map(|k| rows[row][k] * solution[k]).sum();
+        solution[row] = (rows[row][6] - known) / rows[row][row];
+    }
+    Some(solution)
+}
+
+/// The calibration as the Rust const the firmware's precomputed one is.
+pub fn rust_const(calibration: &Calibration) -> String {
+    let Calibration {
+        center,
+        scale,
+        radius,
+    } = calibration;
+    format!(
+        "pub const CALIBRATION: Calibration = Calibration {{
+    center: Measurement {{
+        x: {},
+        y: {},
+        z: {},
+    }},
+    scale: Measurement {{
+        x: {},
+        y: {},
+        z: {},
+    }},
+    radius: {radius},
+}};",
+        center.x, center.y, center.z, scale.x, scale.y, scale.z
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f64::consts::PI;
+    use libm::{cos, sin};
+
+    /// The hard-iron offset and soft-iron radii along the axes, in the
+    /// frame calibrations are fitted in, that the samples are made with.
+    const CENTER: [f64; 3] = [-12_300., 4_560., 21_000.];
+    const RADII: [f64; 3] = [52_000., 47_000., 44_000.];
+
+    /// `count` directions spread evenly over the sphere.
+    fn directions(count: usize) -> Vec<[f64; 3]> {
+        let golden = PI * (3. - sqrt(5.));
+        (0..count)
+            .map(|i| {
+                let z = 1. - 2. * (i as f64 + 0.5) / count as f64;
+                let r = sqrt(1. - z * z);
+                let longitude = golden * i as f64;
+                [r * cos(longitude), r * sin(longitude), z]
+            })
+            .collect()
+    }
+
+    /// Raw readings, in the sensor's axes, of a field of the same strength
+    /// from each of `directions`, with up to `noise` nT added to each axis.
+    fn readings(directions: &[[f64; 3]], noise: f64) -> Vec<Measurement> {
+        let mut state = 0x2545_f491_u32;
+        let mut jitter = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as f64 / u32::MAX as f64 * 2. - 1.) * noise
+        };
+        directions
+            .iter()
+            .map(|direction| {
+                let [x, y, z] = [0, 1, 2].map(|i| CENTER[i] + RADII[i] * direction[i] + jitter());
+                // The sensor's axes and the fitting frame swap the same
+                // way both ways.
+                measurement_to_enu(Measurement {
+                    x: round(x) as i32,
+                    y: round(y) as i32,
+                    z: round(z) as i32,
+                })
+            })
+            .collect()
+    }
+
+    fn assert_recovers(fit: &Fit, center_nt: f64, scale: f64, radius_nt: f64) {
+        let calibration = fit.calibration;
+        let center = [
+            calibration.center.x,
+            calibration.center.y,
+            calibration.center.z,
+        ];
+        let scales = [
+            calibration.scale.x,
+            calibration.scale.y,
+            calibration.scale.z,
+        ];
+        let radius = cbrt(RADII[0] * RADII[1] * RADII[2]);
+        for i in 0..3 {
+            assert!(
+                fabs(center[i] as f64 - CENTER[i]) <= center_nt,
+                "center {:?}",
+                center
+            );
+            assert!(
+                fabs(fit.radii[i] - RADII[i]) <= radius_nt,
+                "radii {:?}",
+                fit.radii
+            );
+            let expected = 1024. * radius / RADII[i];
+            assert!(
+                fabs(scales[i] as f64 - expected) <= scale,
+                "scales {:?}",
+                scales
+            );
+        }
+        assert!(fabs(calibration.radius as f64 - radius) <= radius_nt);
+    }
+
+    #[test]
+    fn recovers_the_offset_and_scales() {
+        let raw = readings(&directions(500), 0.);
+        let fit = fit(&raw).unwrap();
+        assert_eq!(fit.samples, 500);
+        assert_recovers(&fit, 2., 1., 2.);
+        assert!(fit.residual_nt < 50., "residual {}", fit.residual_nt);
+    }
+
+    #[test]
+    fn recovers_the_offset_and_scales_through_noise() {
+        let raw = readings(&directions(2000), 400.);
+        let fit = fit(&raw).unwrap();
+        assert_recovers(&fit, 100., 2., 100.);
+        assert!(fit.residual_nt < 400., "residual {}", fit.residual_nt);
+    }
+
+    #[test]
+    fn calibrated_strengths_agree() {
+        let raw = readings(&directions(300), 0.);
+        let calibration = fit(&raw).unwrap().calibration;
+        let radius = calibration.radius as f64;
+        for &point in &raw {
+            let strength = fixed::magnitude(&calibrated_measurement(point, &calibration)) as f64;
+            assert!(fabs(strength - radius) < 0.005 * radius, "{}", strength);
+        }
+    }
+
+    #[test]
+    fn recovers_from_half_the_sphere() {
+        let upper: Vec<[f64; 3]> = directions(1000)
+            .into_iter()
+            .filter(|direction| direction[2] > 0.)
+            .collect();
+        let fit = fit(&readings(&upper, 0.)).unwrap();
+        assert_recovers(&fit, 20., 2., 20.);
+    }
+
+    #[test]
+    fn too_few_samples() {
+        let raw = readings(&directions(MIN_SAMPLES - 1), 0.);
+        assert_eq!(fit(&raw), Err(FitError::TooFewSamples(MIN_SAMPLES - 1)));
+        assert_eq!(fit(&[]), Err(FitError::TooFewSamples(0)));
+    }
+
+    #[test]
+    fn the_same_point_over_and_over() {
+        let raw = [Measurement {
+            x: 1_000,
+            y: -2_000,
+            z: 30_000,
+        }; 100];
+        assert_eq!(fit(&raw), Err(FitError::Degenerate));
+    }
+
+    #[test]
+    fn turned_about_one_axis() {
+        // Level, turned only about Z: every point on one circle.
+        let circle: Vec<[f64; 3]> = (0..200)
+            .map(|i| {
+                let angle = 2. * PI * i as f64 / 200.;
+                [cos(angle), sin(angle), 0.]
+            })
+            .collect();
+        assert_eq!(fit(&readings(&circle, 0.)), Err(FitError::Degenerate));
+        assert_eq!(fit(&readings(&circle, 400.)), Err(FitError::Degenerate));
+    }
+
+    #[test]
+    fn coplanar_at_a_slant() {
+        // Turned about a tilted axis: still a circle, in a slanted plane.
+        let (tilt_cos, tilt_sin) = (cos(0.5), sin(0.5));
+        let circle: Vec<[f64; 3]> = (0..200)
+            .map(|i| {
+                let angle = 2. * PI * i as f64 / 200.;
+                let (x, y) = (cos(angle), sin(angle));
+                [x, y * tilt_cos, y * tilt_sin]
+            })
+            .collect();
+        let result = fit(&readings(&circle, 0.));
+        assert_eq!(result, Err(FitError::Degenerate), "{:?}", result);
+    }
+}
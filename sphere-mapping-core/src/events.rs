@@ -0,0 +1,228 @@
+//! A small publish/subscribe queue for things that happen between samples,
+//! so an output (the serial port, the external displays, later BLE) can
+//! follow them without each publisher knowing it exists.
+//!
+//! Publishers push onto one [`EventBus`]; every consumer keeps its own
+//! [`Subscriber`] cursor and drains the bus when it gets to run. The bus
+//! keeps the last `N` events, so a consumer that falls further behind skips
+//! ahead to the oldest one still held.
+
+use core::fmt::{self, Write};
+
+use crate::calibration::{Calibration, Measurement};
+use crate::fixed;
+use crate::led::{dir_from_field, Direction};
+use crate::sphere_map::Coverage;
+
+/// How far the calibrated field strength may stray from the calibration's
+/// radius before it counts as an anomaly, in percent. Generous, since the
+/// calibration's per-axis scaling leaves the corrected strength some way off
+/// the radius even in a clean field.
+const ANOMALY_PERCENT: u32 = 50;
+/// How far back inside that band it has to come before the anomaly is over,
+/// in percent, so a reading on the edge doesn't flap.
+const ANOMALY_CLEAR_PERCENT: u32 = 35;
+/// Longest error message an event carries; longer ones are cut short.
+pub const MESSAGE_LEN: usize = 96;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// The field moved into a new octant, so the arrow points a new way.
+    HeadingChanged(Direction),
+    /// A new calibration took effect.
+    CalibrationApplied(Calibration),
+    /// The field strength in nT is well away from the one the calibration
+    /// was fitted to, usually because of a magnet or steel nearby.
+    AnomalyDetected(u32),
+    /// The field strength is back to normal.
+    AnomalyCleared,
+    /// Something failed; the subsystem has already recovered as best it can.
+    Error(Message),
+    /// The sensor was set up again from scratch after a failure, and is
+    /// producing data with its full configuration.
+    SensorRecovered,
+    /// Every cell of the sphere map has been covered.
+    SphereMapComplete(Coverage),
+}
+
+impl Event {
+    /// Writes the serial line for this event, if it has one. Heading changes
+    /// don't: every sample already carries the field.
+    pub fn write_line<W: Write>(&self, out: &mut W) -> fmt::Result {
+        match self {
+            Event::HeadingChanged(_) => Ok(()),
+            Event::CalibrationApplied(calibration) => write!(out, "{}\r\n", calibration),
+            Event::AnomalyDetected(strength) => write!(out, "Anomaly: {} nT\r\n", strength),
+            Event::AnomalyCleared => write!(out, "Anomaly: cleared\r\n"),
+            Event::Error(message) => write!(out, "Warning: {}\r\n", message),
+            Event::SensorRecovered => write!(out, "Recovered: sensor set up again\r\n"),
+            Event::SphereMapComplete(coverage) => write!(
+                out,
+                "Map: complete, cells={}, samples={}\r\n",
+                coverage.cells, coverage.covered_samples
+            ),
+        }
+    }
+}
+
+/// An error's description, rendered when it is published so the event
+/// stays `Copy` whatever the error was.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Message {
+    text: [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl Message {
+    pub fn new(error: &dyn fmt::Display) -> Message {
+        let mut message = Message {
+            text: [0; MESSAGE_LEN],
+            len: 0,
+        };
+        write!(message, "{}", error).ok();
+        message
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are ever copied in.
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let end = self.len + c.len_utf8();
+            if end > MESSAGE_LEN {
+                break;
+            }
+            c.encode_utf8(&mut self.text[self.len..end]);
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// The last `N` events published.
+pub struct EventBus<const N: usize> {
+    events: [Option<Event>; N],
+    /// Events published since boot; the next one goes in slot
+    /// `published % N`.
+    published: u32,
+}
+
+/// One consumer's place in an [`EventBus`].
+pub struct Subscriber {
+    next: u32,
+}
+
+impl<const N: usize> Default for EventBus<N> {
+    fn default() -> EventBus<N> {
+        EventBus::new()
+    }
+}
+
+impl<const N: usize> EventBus<N> {
+    pub const fn new() -> EventBus<N> {
+        EventBus {
+            events: [None; N],
+            published: 0,
+        }
+    }
+
+    pub fn publish(&mut self, event: Event) {
+        self.events[self.published as usize % N] = Some(event);
+        self.published = self.published.wrapping_add(1);
+    }
+
+    /// Publishes `error` as an [`Event::Error`].
+    pub fn publish_error(&mut self, error: &dyn fmt::Display) {
+        self.publish(Event::Error(Message::new(error)));
+    }
+
+    /// A subscriber that sees every event published from now on.
+    pub fn subscribe(&self) -> Subscriber {
+        Subscriber {
+            next: self.published,
+        }
+    }
+
+    /// The next event `subscriber` hasn't seen yet, if any.
+    pub fn next(&self, subscriber: &mut Subscriber) -> Option<Event> {
+        let behind = self.published.wrapping_sub(subscriber.next);
+        if behind == 0 {
+            return None;
+        }
+        if behind > N as u32 {
+            subscriber.next = self.published.wrapping_sub(N as u32);
+        }
+        let event = self.events[subscriber.next as usize % N];
+        subscriber.next = subscriber.next.wrapping_add(1);
+        event
+    }
+}
+
+/// Watches the calibrated field from sample to sample and publishes the
+/// changes other outputs care about.
+pub struct FieldWatch {
+    direction: Option<Direction>,
+    anomaly: bool,
+}
+
+impl Default for FieldWatch {
+    fn default() -> FieldWatch {
+        FieldWatch::new()
+    }
+}
+
+impl FieldWatch {
+    pub const fn new() -> FieldWatch {
+        FieldWatch {
+            direction: None,
+            anomaly: false,
+        }
+    }
+
+    /// Publishes a [`Event::HeadingChanged`] when the calibrated `field`
+    /// changes octant, and an anomaly event when its strength strays from
+    /// `calibration`'s radius or comes back.
+    pub fn check<const N: usize>(
+        &mut self,
+        field: &Measurement,
+        calibration: &Calibration,
+        bus: &mut EventBus<N>,
+    ) {
+        let direction = dir_from_field(field.x, field.y);
+        if self.direction != Some(direction) {
+            self.direction = Some(direction);
+            bus.publish(Event::HeadingChanged(direction));
+        }
+
+        // A calibration without a radius has nothing to compare against.
+        let expected = calibration.radius;
+        if expected == 0 {
+            return;
+        }
+        let strength = fixed::magnitude(field);
+        let off_by = strength.abs_diff(expected) as u64 * 100;
+        if !self.anomaly && off_by > expected as u64 * ANOMALY_PERCENT as u64 {
+            self.anomaly = true;
+            bus.publish(Event::AnomalyDetected(strength));
+        } else if self.anomaly && off_by <= expected as u64 * ANOMALY_CLEAR_PERCENT as u64 {
+            self.anomaly = false;
+            bus.publish(Event::AnomalyCleared);
+        }
+    }
+}
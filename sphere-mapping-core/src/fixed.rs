@@ -0,0 +1,228 @@
+//! Q16.16 fixed-point versions of the per-sample heading and field strength
+//! math, used instead of libm's `atan2f` and `sqrtf` with the `fixed-point`
+//! feature. Applying a calibration is integer-only already (the scale is
+//! stored times 1024), so with these the path from a reading to a heading
+//! needs no floating-point library calls. Without the `libm` feature, the
+//! rest of the crate's float math is worked out with them too (see
+//! [`math`](crate::math)).
+
+use crate::calibration::Measurement;
+
+const FRAC_BITS: u32 = 16;
+
+/// `atan(2^-i)` for each CORDIC step, in Q16.16 radians.
+const ATAN_STEPS: [i64; 17] = [
+    51472, 30386, 16055, 8150, 4091, 2047, 1024, 512, 256, 128, 64, 32, 16, 8, 4, 2, 1,
+];
+
+/// The CORDIC steps' gain, 1/∏√(1 + 2^-2i), in Q16.16: starting from it
+/// leaves a rotated unit vector of length 1.
+const CORDIC_GAIN: i64 = 39_797;
+
+/// A Q16.16 fixed-point number: the value times 65536.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fixed(pub i32);
+
+impl Fixed {
+    pub const ONE: Fixed = Fixed(1 << FRAC_BITS);
+    pub const PI: Fixed = Fixed(205_887);
+
+    pub const fn from_int(n: i32) -> Fixed {
+        Fixed(n << FRAC_BITS)
+    }
+
+    /// The nearest fixed-point number to `value`, which has to be within
+    /// ±32768.
+    pub fn from_f32(value: f32) -> Fixed {
+        let scaled = value * Fixed::ONE.0 as f32;
+        Fixed((scaled + if scaled < 0. { -0.5 } else { 0.5 }) as i32)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / Fixed::ONE.0 as f32
+    }
+
+    /// Rounds to the nearest integer, with halves rounded up.
+    pub fn round(self) -> i32 {
+        (self.0 + (1 << (FRAC_BITS - 1))) >> FRAC_BITS
+    }
+}
+
+/// The angle of `(x, y)` in radians, from -π up to π, as `atan2f(y, x)`
+/// would return it. Accurate to about 2^-15 rad.
+pub fn atan2(y: i32, x: i32) -> Fixed {
+    if x == 0 && y == 0 {
+        return Fixed(0);
+    }
+    // CORDIC only converges in the right half-plane, so turn the left half
+    // round by π first. The 16 fractional bits added here keep precision on
+    // small fields, and i64 leaves room for the CORDIC gain.
+    let (mut x, mut y, mut angle) = if x < 0 {
+        let half_turn = if y < 0 { -Fixed::PI.0 } else { Fixed::PI.0 };
+        (-(x as i64), -(y as i64), half_turn as i64)
+    } else {
+        (x as i64, y as i64, 0)
+    };
+    x <<= FRAC_BITS;
+    y <<= FRAC_BITS;
+    for (i, step) in ATAN_STEPS.iter().enumerate() {
+        let (dx, dy) = (y >> i, x >> i);
+        if y > 0 {
+            x += dx;
+            y -= dy;
+            angle += step;
+        } else {
+            x -= dx;
+            y += dy;
+            angle -= step;
+        }
+    }
+    Fixed(angle as i32)
+}
+
+/// The sine and cosine of `angle` in radians, from -π up to π. Accurate to
+/// about 2^-15.
+pub fn sin_cos(angle: Fixed) -> (Fixed, Fixed) {
+    // CORDIC only converges within about ±1.74 rad, so turn the rest round
+    // by π first, which negates both.
+    let half_pi = Fixed::PI.0 as i64 / 2;
+    let (mut angle, sign) = match angle.0 as i64 {
+        angle if angle > half_pi => (angle - Fixed::PI.0 as i64, -1),
+        angle if angle < -half_pi => (angle + Fixed::PI.0 as i64, -1),
+        angle => (angle, 1),
+    };
+    let (mut x, mut y) = (CORDIC_GAIN << FRAC_BITS, 0i64);
+    for (i, step) in ATAN_STEPS.iter().enumerate() {
+        let (dx, dy) = (y >> i, x >> i);
+        if angle > 0 {
+            x -= dx;
+            y += dy;
+            angle -= step;
+        } else {
+            x += dx;
+            y -= dy;
+            angle += step;
+        }
+    }
+    let unscale = |value: i64| Fixed(((sign * value) >> FRAC_BITS) as i32);
+    (unscale(y), unscale(x))
+}
+
+/// Converts a field angle `theta` into a whole heading in degrees clockwise
+/// from magnetic North, from 0 to 359, like rounding
+/// [`heading_from_theta`](crate::led::heading_from_theta).
+pub fn heading_degrees(theta: Fixed) -> u32 {
+    Fixed(heading(theta) as i32).round() as u32 % 360
+}
+
+/// The heading of a calibrated field in whole degrees clockwise from North,
+/// turned by `declination` in tenths of a degree, East positive, from 0 to
+/// 359.
+pub fn true_heading(field: &Measurement, declination: i16) -> u32 {
+    let declination = ((declination as i64) << FRAC_BITS) / 10;
+    let heading = (heading(atan2(field.y, field.x)) + declination).rem_euclid(360 << FRAC_BITS);
+    Fixed(heading as i32).round() as u32 % 360
+}
+
+/// Converts a field angle `theta` into a heading in Q16.16 degrees
+/// clockwise from magnetic North, from 0 up to 360.
+fn heading(theta: Fixed) -> i64 {
+    let degrees = ((theta.0 as i64 * 180) << FRAC_BITS) / Fixed::PI.0 as i64;
+    (Fixed::from_int(90).0 as i64 - degrees).rem_euclid(360 << FRAC_BITS)
+}
+
+/// The strength of a field, in the same units as its components.
+pub fn magnitude(field: &Measurement) -> u32 {
+    let (x, y, z) = (field.x as i64, field.y as i64, field.z as i64);
+    isqrt((x * x + y * y + z * z) as u64) as u32
+}
+
+/// Integer square root, rounded down, one result bit at a time.
+fn isqrt(n: u64) -> u64 {
+    let mut rest = n;
+    let mut root = 0;
+    let mut bit = 1 << 62;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rest >= root + bit {
+            rest -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near(value: Fixed, expected: f32) -> bool {
+        (value.to_f32() - expected).abs() < 1e-3
+    }
+
+    #[test]
+    fn atan2_covers_every_quadrant() {
+        use core::f32::consts::PI;
+        assert!(near(atan2(0, 100), 0.));
+        assert!(near(atan2(100, 100), PI / 4.));
+        assert!(near(atan2(100, 0), PI / 2.));
+        assert!(near(atan2(100, -100), 3. * PI / 4.));
+        assert!(near(atan2(-100, -100), -3. * PI / 4.));
+        assert!(near(atan2(-100, 0), -PI / 2.));
+        assert_eq!(atan2(0, 0), Fixed(0));
+    }
+
+    #[test]
+    fn sin_cos_round_the_circle() {
+        use core::f32::consts::{FRAC_1_SQRT_2, PI};
+        for (angle, sin, cos) in [
+            (0., 0., 1.),
+            (PI / 6., 0.5, 0.866_025_4),
+            (PI / 2., 1., 0.),
+            (2. * PI / 3., 0.866_025_4, -0.5),
+            (-3. * PI / 4., -FRAC_1_SQRT_2, -FRAC_1_SQRT_2),
+            (-PI / 2., -1., 0.),
+            (PI - 1e-4, 0., -1.),
+        ] {
+            let (s, c) = sin_cos(Fixed::from_f32(angle));
+            assert!(near(s, sin) && near(c, cos), "{angle}: {s:?} {c:?}");
+        }
+    }
+
+    #[test]
+    fn headings_turn_clockwise_from_north() {
+        let field = |x, y| Measurement { x, y, z: 0 };
+        assert_eq!(true_heading(&field(0, 100), 0), 0);
+        assert_eq!(true_heading(&field(100, 0), 0), 90);
+        assert_eq!(true_heading(&field(0, -100), 0), 180);
+        assert_eq!(true_heading(&field(-100, 0), 0), 270);
+        assert_eq!(heading_degrees(atan2(100, 100)), 45);
+    }
+
+    #[test]
+    fn declination_turns_the_heading() {
+        let north = Measurement { x: 0, y: 100, z: 0 };
+        assert_eq!(true_heading(&north, 126), 13);
+        assert_eq!(true_heading(&north, -126), 347);
+        assert_eq!(true_heading(&north, 3600), 0);
+    }
+
+    #[test]
+    fn magnitude_is_rounded_down() {
+        assert_eq!(magnitude(&Measurement { x: 3, y: 4, z: 12 }), 13);
+        assert_eq!(magnitude(&Measurement { x: 1, y: 1, z: 1 }), 1);
+        assert_eq!(
+            magnitude(&Measurement {
+                x: -40_000,
+                y: 0,
+                z: 0
+            }),
+            40_000
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Binary frames, for output formats where text is too slow: a
+//! `Measurement:` line is about 70 bytes, a frame 32, so 100 Hz fits in
+//! 115200 baud with room to spare for everything else. Frames are sent
+//! between the text lines, which never contain the sync bytes.
+//!
+//! A frame is the sync bytes `A5 5A`, a kind, the payload length, the
+//! payload, and a CRC-16/CCITT-FALSE of the kind, length and payload, all
+//! little-endian:
+//!
+//! - kind 1, a sample: the timestamp in µs since boot (u64), the calibrated
+//!   field in nT (3 × i32), and the acceleration in mg (3 × i16);
+//! - kind 2, the same followed by the gyro's rate in deg/s (3 × f32);
+//! - kind 3, a sphere map cell: its index and the map's cell count (2 ×
+//!   u16), the unit vector through its middle in the board's axes (3 ×
+//!   f32), its sample count (u32), and the average, standard deviation,
+//!   smallest and largest field strength in nT (4 × u32, 0 until there
+//!   are enough samples), and the standard error of the average in nT
+//!   (f32, infinite before a second sample), so a fit can weight each
+//!   cell by how well its average is known;
+//! - kind 4, a cell of the difference between two saved sphere maps: the
+//!   same index, cell count and direction, the sample count and average
+//!   field strength in nT before and after (4 × u32), and how much the
+//!   average grew in nT (i32, 0 unless both have samples).
+
+use crate::calibration::Measurement;
+use crate::device::Acceleration;
+use crate::sphere_map::Cell;
+
+pub const SYNC: [u8; 2] = [0xA5, 0x5A];
+pub const KIND_SAMPLE: u8 = 1;
+pub const KIND_SAMPLE_GYRO: u8 = 2;
+pub const KIND_SPHERE_CELL: u8 = 3;
+pub const KIND_SPHERE_DIFF: u8 = 4;
+
+const HEADER_LEN: usize = 4;
+const CRC_LEN: usize = 2;
+const SAMPLE_LEN: usize = 8 + 3 * 4 + 3 * 2;
+const GYRO_LEN: usize = 3 * 4;
+const CELL_LEN: usize = 2 * 2 + 3 * 4 + 5 * 4 + 4;
+const DIFF_LEN: usize = 2 * 2 + 3 * 4 + 4 * 4 + 4;
+const MAX_PAYLOAD_LEN: usize = if CELL_LEN > SAMPLE_LEN + GYRO_LEN {
+    CELL_LEN
+} else {
+    SAMPLE_LEN + GYRO_LEN
+};
+const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN + CRC_LEN;
+/// A sphere map cell's frame, with its header and CRC; a difference
+/// cell's is no longer.
+pub const CELL_FRAME_LEN: usize = HEADER_LEN + CELL_LEN + CRC_LEN;
+const _: () = assert!(DIFF_LEN <= CELL_LEN);
+
+/// One encoded frame.
+pub struct Frame {
+    bytes: [u8; MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl Frame {
+    pub fn sample(
+        field: &Measurement,
+        accel: &Acceleration,
+        timestamp_us: u64,
+        gyro: Option<[f32; 3]>,
+    ) -> Frame {
+        let mut frame = match gyro {
+            Some(_) => Frame::start(KIND_SAMPLE_GYRO, SAMPLE_LEN + GYRO_LEN),
+            None => Frame::start(KIND_SAMPLE, SAMPLE_LEN),
+        };
+        frame.push(&timestamp_us.to_le_bytes());
+        for value in [field.x, field.y, field.z] {
+            frame.push(&value.to_le_bytes());
+        }
+        for value in [accel.x, accel.y, accel.z] {
+            let mg = value.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            frame.push(&mg.to_le_bytes());
+        }
+        if let Some(rate) = gyro {
+            for value in rate {
+                frame.push(&value.to_le_bytes());
+            }
+        }
+        frame.finish()
+    }
+
+    /// Cell `index` of a map of `cells`, through the middle of which runs
+    /// `direction`.
+    pub fn sphere_cell(index: u16, cells: u16, direction: [f32; 3], cell: &Cell) -> Frame {
+        let mut frame = Frame::start(KIND_SPHERE_CELL, CELL_LEN);
+        frame.push(&index.to_le_bytes());
+        frame.push(&cells.to_le_bytes());
+        for value in direction {
+            frame.push(&value.to_le_bytes());
+        }
+        frame.push(&cell.count.to_le_bytes());
+        for value in [
+            cell.mean_nt(),
+            cell.std_dev_nt(),
+            cell.min_nt(),
+            cell.max_nt(),
+        ] {
+            frame.push(&value.unwrap_or(0).to_le_bytes());
+        }
+        let std_error = cell.std_error_nt().unwrap_or(f32::INFINITY);
+        frame.push(&std_error.to_le_bytes());
+        frame.finish()
+    }
+
+    /// Cell `index` of the difference from the map `before` to `after`.
+    pub fn sphere_difference(
+        index: u16,
+        cells: u16,
+        direction: [f32; 3],
+        before: &Cell,
+        after: &Cell,
+    ) -> Frame {
+        let mut frame = Frame::start(KIND_SPHERE_DIFF, DIFF_LEN);
+        frame.push(&index.to_le_bytes());
+        frame.push(&cells.to_le_bytes());
+        for value in direction {
+            frame.push(&value.to_le_bytes());
+        }
+        for cell in [before, after] {
+            frame.push(&cell.count.to_le_bytes());
+            frame.push(&cell.mean_nt().unwrap_or(0).to_le_bytes());
+        }
+        let difference = match (before.mean_nt(), after.mean_nt()) {
+            (Some(before), Some(after)) => after as i64 - before as i64,
+            _ => 0,
+        };
+        let difference = difference.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        frame.push(&difference.to_le_bytes());
+        frame.finish()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn start(kind: u8, payload_len: usize) -> Frame {
+        let mut frame = Frame {
+            bytes: [0; MAX_FRAME_LEN],
+            len: 0,
+        };
+        frame.push(&SYNC);
+        frame.push(&[kind, payload_len as u8]);
+        frame
+    }
+
+    fn finish(mut self) -> Frame {
+        let crc = crc16(&self.bytes[SYNC.len()..self.len]);
+        self.push(&crc.to_le_bytes());
+        self
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+}
+
+/// CRC-16/CCITT-FALSE, computed bitwise like the storage CRC.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
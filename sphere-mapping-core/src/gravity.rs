@@ -0,0 +1,310 @@
+//! Which way the board is pointing, from the accelerometer: the direction
+//! gravity pulls in the board's axes, and which cell of the orientation
+//! grid it falls in. The sphere map bins its samples by these cells, its
+//! coverage, which also judges how completely a calibration has seen
+//! every orientation, counts them, and the mapping guide steers towards
+//! the next one, so all three agree on where the board points.
+//!
+//! The refit of the calibration (see [`crate::refit`]) bins the field's
+//! direction the same way, on a grid of its own.
+//!
+//! The grid's cells are centred on the vertices of a geodesic sphere (see
+//! [`crate::geodesic`]), a direction going to the nearest, so every cell
+//! covers about the same solid angle, or, with `SPHERE_MAP_CELLS=72`, are
+//! equal bands of latitude from the board's -Z to its +Z axis, each split
+//! into equal sectors of longitude around it, which oversample the poles.
+//! The firmware uses the one it was built with; host tools can pick any of
+//! them with [`Grid`], to bin logged samples more coarsely or finely.
+
+use core::f32::consts::{FRAC_PI_2, PI};
+
+use crate::config::SPHERE_MAP_CELLS;
+use crate::device::Acceleration;
+use crate::geodesic;
+use crate::math::{asinf, atan2f, cosf, fabsf, sinf, sqrtf};
+
+/// Cells in the orientation grid.
+pub const CELLS: usize = SPHERE_MAP_CELLS as usize;
+
+/// Bands of latitude and sectors of longitude in each band, 30° each, for
+/// the latitude and longitude grid.
+const LATITUDE_BANDS: usize = 6;
+const LONGITUDE_SECTORS: usize = 12;
+
+/// The geodesic grids' cell centres. Only the ones used are linked in.
+static GEODESIC_12: [[f32; 3]; 12] = geodesic::vertices();
+static GEODESIC_42: [[f32; 3]; 42] = geodesic::vertices();
+static GEODESIC_162: [[f32; 3]; 162] = geodesic::vertices();
+static GEODESIC_642: [[f32; 3]; 642] = geodesic::vertices();
+
+/// The grid the sphere map was built with.
+pub const GRID: Grid = match Grid::with_cells(CELLS) {
+    Some(grid) => grid,
+    None => panic!("SPHERE_MAP_CELLS must be 12, 42, 72, 162 or 642"),
+};
+
+/// An orientation grid.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Grid {
+    /// Cells centred on these vertices of a geodesic sphere.
+    Geodesic(&'static [[f32; 3]]),
+    /// Bands of latitude, each split into sectors of longitude.
+    LatLong,
+}
+
+impl Grid {
+    /// The grid with `cells` cells: 12, 42, 162 or 642 for a geodesic
+    /// grid, or 72 for latitude and longitude.
+    pub const fn with_cells(cells: usize) -> Option<Grid> {
+        match cells {
+            12 => Some(Grid::Geodesic(&GEODESIC_12)),
+            42 => Some(Grid::Geodesic(&GEODESIC_42)),
+            162 => Some(Grid::Geodesic(&GEODESIC_162)),
+            642 => Some(Grid::Geodesic(&GEODESIC_642)),
+            72 => Some(Grid::LatLong),
+            _ => None,
+        }
+    }
+
+    pub fn cells(self) -> usize {
+        match self {
+            Grid::Geodesic(vertices) => vertices.len(),
+            Grid::LatLong => LATITUDE_BANDS * LONGITUDE_SECTORS,
+        }
+    }
+
+    /// The cell a unit vector falls in.
+    pub fn nearest_cell(self, down: [f32; 3]) -> usize {
+        match self {
+            Grid::Geodesic(vertices) => nearest(vertices, down),
+            Grid::LatLong => lat_long_index(down),
+        }
+    }
+
+    /// The unit vector through the middle of a cell, in the board's axes.
+    pub fn cell_direction(self, index: usize) -> [f32; 3] {
+        match self {
+            Grid::Geodesic(vertices) => vertices[index],
+            Grid::LatLong => lat_long_direction(index),
+        }
+    }
+}
+
+/// Standard gravity, in mg.
+pub const GRAVITY_MG: f32 = 1000.;
+/// How far from 1 g the acceleration can be, in mg, for the board to count
+/// as still enough to tell which way is down.
+const STILL_TOLERANCE_MG: f32 = 150.;
+
+/// The unit vector `accel` points along, or `None` if it has no length.
+/// Works while the board is moving, when it is only roughly down.
+pub fn direction(accel: &Acceleration) -> Option<[f32; 3]> {
+    unit([accel.x as f32, accel.y as f32, accel.z as f32])
+}
+
+/// The unit vector along `vector`, or `None` if it has no length.
+pub fn unit(vector: [f32; 3]) -> Option<[f32; 3]> {
+    let norm = sqrtf(dot(vector, vector));
+    if norm == 0. {
+        return None;
+    }
+    Some(vector.map(|axis| axis / norm))
+}
+
+/// The unit vector gravity pulls along, or `None` if the acceleration is
+/// too far from 1 g to be gravity alone.
+pub fn down(accel: &Acceleration) -> Option<[f32; 3]> {
+    let (x, y, z) = (accel.x as f32, accel.y as f32, accel.z as f32);
+    let norm = sqrtf(x * x + y * y + z * z);
+    if fabsf(norm - GRAVITY_MG) > STILL_TOLERANCE_MG {
+        return None;
+    }
+    Some([x / norm, y / norm, z / norm])
+}
+
+/// The cell for the direction of gravity in `accel`, or `None` if the
+/// acceleration is too far from 1 g to be gravity alone.
+pub fn cell_index(accel: &Acceleration) -> Option<usize> {
+    down(accel).map(nearest_cell)
+}
+
+/// The dot product: for unit vectors, the cosine of the angle between
+/// them, 1 when they point the same way.
+pub fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// The cell of the sphere map's grid a unit vector falls in.
+pub fn nearest_cell(down: [f32; 3]) -> usize {
+    GRID.nearest_cell(down)
+}
+
+/// Which of `centres` a unit vector is nearest: the one most nearly in the
+/// same direction.
+pub fn nearest(centres: &[[f32; 3]], direction: [f32; 3]) -> usize {
+    (0..centres.len())
+        .max_by(|&a, &b| dot(centres[a], direction).total_cmp(&dot(centres[b], direction)))
+        .unwrap_or(0)
+}
+
+/// The unit vector through the middle of a cell of the sphere map's grid,
+/// in the board's axes.
+pub fn cell_direction(index: usize) -> [f32; 3] {
+    GRID.cell_direction(index)
+}
+
+fn lat_long_direction(index: usize) -> [f32; 3] {
+    let band = index / LONGITUDE_SECTORS;
+    let sector = index % LONGITUDE_SECTORS;
+    let latitude = (band as f32 + 0.5) * PI / LATITUDE_BANDS as f32 - FRAC_PI_2;
+    let longitude = (sector as f32 + 0.5) * 2. * PI / LONGITUDE_SECTORS as f32 - PI;
+    [
+        cosf(latitude) * cosf(longitude),
+        cosf(latitude) * sinf(longitude),
+        sinf(latitude),
+    ]
+}
+
+/// The angle, in [`crate::led::render_arrow`]'s convention, of the edge of
+/// the board to tip down to turn gravity from `down` towards `target`.
+pub fn tilt_towards(down: [f32; 3], target: [f32; 3]) -> f32 {
+    // Raising the board's left edge turns the acceleration towards +X,
+    // and its bottom edge towards +Y, as the level's bubble shows, so
+    // tipping the right edge down goes towards +X and the top edge
+    // towards +Y: the arrow points along the change still needed in
+    // the board's plane.
+    atan2f(target[1] - down[1], down[0] - target[0])
+}
+
+fn lat_long_index(down: [f32; 3]) -> usize {
+    let latitude = asinf(down[2].clamp(-1., 1.));
+    let longitude = atan2f(down[1], down[0]);
+    let band = bin(latitude + FRAC_PI_2, PI, LATITUDE_BANDS);
+    let sector = bin(longitude + PI, 2. * PI, LONGITUDE_SECTORS);
+    band * LONGITUDE_SECTORS + sector
+}
+
+/// Which of `bins` equal parts of `0..span` `value` falls in.
+fn bin(value: f32, span: f32, bins: usize) -> usize {
+    ((value / span * bins as f32) as usize).min(bins - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accel(x: i32, y: i32, z: i32) -> Acceleration {
+        Acceleration { x, y, z }
+    }
+
+    fn assert_near(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(fabsf(a - e) < 1e-3, "{:?} isn't {:?}", actual, expected);
+        }
+    }
+
+    /// The direction the arrow for `angle` points on the display, x right
+    /// and y up, as `render_arrow` draws it.
+    fn arrow(angle: f32) -> [f32; 2] {
+        [-cosf(angle), sinf(angle)]
+    }
+
+    #[test]
+    fn down_when_level() {
+        assert_near(down(&accel(0, 0, 1000)).unwrap(), [0., 0., 1.]);
+    }
+
+    #[test]
+    fn down_when_inverted() {
+        assert_near(down(&accel(0, 0, -1000)).unwrap(), [0., 0., -1.]);
+    }
+
+    #[test]
+    fn down_when_tilted() {
+        assert_near(down(&accel(500, 0, 866)).unwrap(), [0.5, 0., 0.866]);
+        let corner = down(&accel(-577, 577, -577)).unwrap();
+        assert_near(corner, [-0.577, 0.577, -0.577]);
+    }
+
+    #[test]
+    fn down_only_near_1g() {
+        assert!(down(&accel(0, 0, 0)).is_none());
+        assert!(down(&accel(0, 0, 800)).is_none());
+        assert!(down(&accel(1200, 0, 0)).is_none());
+        assert!(down(&accel(0, 0, 1140)).is_some());
+        assert!(cell_index(&accel(0, 0, 1500)).is_none());
+    }
+
+    #[test]
+    fn every_grid_finds_its_own_centres() {
+        for cells in [12, 42, 72, 162, 642] {
+            let grid = Grid::with_cells(cells).unwrap();
+            assert_eq!(grid.cells(), cells);
+            for index in 0..cells {
+                let centre = grid.cell_direction(index);
+                assert!(fabsf(dot(centre, centre) - 1.) < 1e-4);
+                assert_eq!(grid.nearest_cell(centre), index, "{} cells", cells);
+            }
+        }
+        assert!(Grid::with_cells(100).is_none());
+    }
+
+    #[test]
+    fn cell_index_agrees_with_nearest_cell() {
+        for index in 0..CELLS {
+            let centre = cell_direction(index);
+            let reading = centre.map(|axis| (axis * GRAVITY_MG) as i32);
+            let reading = accel(reading[0], reading[1], reading[2]);
+            assert_eq!(nearest_cell(centre), index);
+            assert_eq!(cell_index(&reading), Some(index));
+        }
+    }
+
+    #[test]
+    fn lat_long_bins_round_trip() {
+        for index in 0..LATITUDE_BANDS * LONGITUDE_SECTORS {
+            assert_eq!(lat_long_index(lat_long_direction(index)), index);
+        }
+        // The poles fall in the first and last bands, and the seam behind
+        // -X in the first and last sectors.
+        assert_eq!(lat_long_index([0., 0., -1.]) / LONGITUDE_SECTORS, 0);
+        assert_eq!(
+            lat_long_index([0., 0., 1.]) / LONGITUDE_SECTORS,
+            LATITUDE_BANDS - 1
+        );
+        assert_eq!(lat_long_index([-1., -1e-6, 0.]) % LONGITUDE_SECTORS, 0);
+        assert_eq!(
+            lat_long_index([-1., 0., 0.]) % LONGITUDE_SECTORS,
+            LONGITUDE_SECTORS - 1
+        );
+    }
+
+    #[test]
+    fn bins_clamp_at_the_end() {
+        assert_eq!(bin(0., PI, 6), 0);
+        assert_eq!(bin(PI, PI, 6), 5);
+        assert_eq!(bin(PI / 6. + 1e-3, PI, 6), 1);
+    }
+
+    #[test]
+    fn tilt_towards_the_target() {
+        let flat = [0., 0., 1.];
+        let right = arrow(tilt_towards(flat, [1., 0., 0.]));
+        assert!(right[0] > 0.99, "{:?}", right);
+        let left = arrow(tilt_towards(flat, [-1., 0., 0.]));
+        assert!(left[0] < -0.99, "{:?}", left);
+        let up = arrow(tilt_towards(flat, [0., 1., 0.]));
+        assert!(up[1] > 0.99, "{:?}", up);
+        let diagonal = arrow(tilt_towards(flat, [0.6, -0.6, 0.529]));
+        assert!(diagonal[0] > 0.7 && diagonal[1] < -0.7, "{:?}", diagonal);
+    }
+
+    #[test]
+    fn tilt_towards_the_target_from_it() {
+        let down = [0.6, 0., 0.8];
+        assert_eq!(tilt_towards(down, down), 0.);
+        // A hair away, it still points along what's left to go.
+        let near = arrow(tilt_towards(down, [0.6, 0.001, 0.8]));
+        assert!(near[1] > 0.99, "{:?}", near);
+    }
+}
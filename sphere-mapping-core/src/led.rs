@@ -0,0 +1,646 @@
+use core::f32::consts::PI;
+
+use crate::calibration::Measurement;
+use crate::config::SAMPLE_RATE_HZ;
+use crate::math::{atan2f, cosf, fabsf, roundf, sinf, sqrtf};
+
+/// A square LED frame, indexed `[row][col]` with row 0 at the top. Each
+/// pixel is a brightness from 0 (off) to `MAX_BRIGHTNESS`.
+pub type Grid<const N: usize> = [[u8; N]; N];
+
+/// A frame for the micro:bit's onboard 5x5 matrix.
+pub type Frame = Grid<5>;
+
+pub const MAX_BRIGHTNESS: u8 = 9;
+
+/// Main-loop iterations a pixel left behind by the needle stays lit, and the
+/// brightness it starts fading from.
+const TRAIL_TICKS: u8 = 8;
+const TRAIL_BRIGHTNESS: u8 = 4;
+
+/// While uncalibrated, the warning glyph replaces the heading for
+/// `UNCALIBRATED_WARNING_LEN` out of every `UNCALIBRATED_WARNING_PERIOD`
+/// samples (0.5 s every 3 s).
+const UNCALIBRATED_WARNING_PERIOD: u32 = 3 * SAMPLE_RATE_HZ;
+const UNCALIBRATED_WARNING_LEN: u32 = SAMPLE_RATE_HZ / 2;
+
+const BARB_ANGLE: f32 = 3. * PI / 4.;
+const LEVEL_THRESHOLD: f32 = 2. * PI / 180.;
+const LEVEL_STEP: f32 = 10. * PI / 180.;
+/// Field strength that lights the whole matrix in the magnitude view, in nT.
+const MAGNITUDE_FULL_SCALE_NT: u64 = 100_000;
+/// tan(π/8) as a ratio, for telling the octants apart without an `atan2`.
+const TAN_PI_8_NUM: i64 = 985;
+const TAN_PI_8_DEN: i64 = 2378;
+
+pub const WARNING: Frame = [
+    [0, 0, MAX_BRIGHTNESS, 0, 0],
+    [0, 0, MAX_BRIGHTNESS, 0, 0],
+    [0, 0, MAX_BRIGHTNESS, 0, 0],
+    [0, 0, 0, 0, 0],
+    [0, 0, MAX_BRIGHTNESS, 0, 0],
+];
+
+/// Shown in sphere mapping while the board is in a cell that still needs
+/// samples.
+pub const HOLD: Frame = [
+    [0, 0, 0, 0, 0],
+    [0, MAX_BRIGHTNESS, MAX_BRIGHTNESS, MAX_BRIGHTNESS, 0],
+    [0, MAX_BRIGHTNESS, 0, MAX_BRIGHTNESS, 0],
+    [0, MAX_BRIGHTNESS, MAX_BRIGHTNESS, MAX_BRIGHTNESS, 0],
+    [0, 0, 0, 0, 0],
+];
+
+/// Shown in sphere mapping once every cell has been covered.
+pub const TICK: Frame = [
+    [0, 0, 0, 0, 0],
+    [0, 0, 0, 0, MAX_BRIGHTNESS],
+    [0, 0, 0, MAX_BRIGHTNESS, 0],
+    [MAX_BRIGHTNESS, 0, MAX_BRIGHTNESS, 0, 0],
+    [0, MAX_BRIGHTNESS, 0, 0, 0],
+];
+
+/// The rings the celebration ripples out through, then the blank between
+/// ripples.
+const RIPPLE: [Frame; 4] = [
+    [
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 0, 0],
+        [0, 0, MAX_BRIGHTNESS, 0, 0],
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 0, 0],
+    ],
+    HOLD,
+    [
+        [MAX_BRIGHTNESS; 5],
+        [MAX_BRIGHTNESS, 0, 0, 0, MAX_BRIGHTNESS],
+        [MAX_BRIGHTNESS, 0, 0, 0, MAX_BRIGHTNESS],
+        [MAX_BRIGHTNESS, 0, 0, 0, MAX_BRIGHTNESS],
+        [MAX_BRIGHTNESS; 5],
+    ],
+    [[0; 5]; 5],
+];
+const RIPPLE_STEP_US: u64 = 150_000;
+const RIPPLES: u64 = 3;
+/// How long the tick stays up after the ripples.
+const CELEBRATION_TICK_US: u64 = 1_000_000;
+
+/// Shown while the firmware panics.
+pub const SAD_FACE: Frame = [
+    [0, MAX_BRIGHTNESS, 0, MAX_BRIGHTNESS, 0],
+    [0, 0, 0, 0, 0],
+    [0, 0, 0, 0, 0],
+    [0, MAX_BRIGHTNESS, MAX_BRIGHTNESS, MAX_BRIGHTNESS, 0],
+    [MAX_BRIGHTNESS, 0, 0, 0, MAX_BRIGHTNESS],
+];
+
+const DIGIT_FONT: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b011, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DisplayMode {
+    #[default]
+    Compass = 0,
+    Clock = 1,
+    Level = 2,
+}
+
+impl DisplayMode {
+    pub fn from_index(index: u8) -> Option<DisplayMode> {
+        match index {
+            0 => Some(DisplayMode::Compass),
+            1 => Some(DisplayMode::Clock),
+            2 => Some(DisplayMode::Level),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DisplayMode::Compass => "compass",
+            DisplayMode::Clock => "clock",
+            DisplayMode::Level => "level",
+        }
+    }
+
+    pub fn next(self) -> DisplayMode {
+        match self {
+            DisplayMode::Compass => DisplayMode::Clock,
+            DisplayMode::Clock => DisplayMode::Level,
+            DisplayMode::Level => DisplayMode::Compass,
+        }
+    }
+
+    /// Picks what this mode shows for a calibrated field and an
+    /// accelerometer reading in mg. Headings are turned by `declination`
+    /// (tenths of a degree, East positive) to point at true North. Only the
+    /// clock and a non-zero declination need the field's angle; otherwise
+    /// the arrow just needs its octant. With the `fixed-point` feature the
+    /// angle is worked out in whole degrees, without floats.
+    pub fn view(self, field: &Measurement, declination: i16, ax: i32, ay: i32, az: i32) -> View {
+        match self {
+            DisplayMode::Compass if declination == 0 => {
+                View::Arrow(dir_from_field(field.x, field.y).theta())
+            }
+            #[cfg(not(feature = "fixed-point"))]
+            DisplayMode::Compass => {
+                let declination = (declination as f32 / 10.).to_radians();
+                let theta = wrap_angle(theta_from_field(field.x, field.y) - declination);
+                View::Arrow(dir_from_theta(theta).theta())
+            }
+            #[cfg(feature = "fixed-point")]
+            DisplayMode::Compass => {
+                let heading = crate::fixed::true_heading(field, declination);
+                View::Arrow(dir_from_heading(heading).theta())
+            }
+            #[cfg(not(feature = "fixed-point"))]
+            DisplayMode::Clock => {
+                let declination = (declination as f32 / 10.).to_radians();
+                let theta = theta_from_field(field.x, field.y) - declination;
+                View::Clock(clock_hour(heading_from_theta(theta)))
+            }
+            #[cfg(feature = "fixed-point")]
+            DisplayMode::Clock => {
+                let heading = crate::fixed::true_heading(field, declination);
+                View::Clock(((heading + 15) / 30 % 12) as u8)
+            }
+            DisplayMode::Level => View::Level(ax, ay, az),
+        }
+    }
+}
+
+/// What the compass is showing, independent of the size of the matrix it
+/// ends up on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum View {
+    /// An arrow towards magnetic North for a field angle, see [`render_arrow`].
+    Arrow(f32),
+    /// A clock hand at an hour position (0 to 11) for the heading, see
+    /// [`render_clock`].
+    Clock(u8),
+    /// A spirit-level bubble for an accelerometer reading in mg.
+    Level(i32, i32, i32),
+    /// A fixed 5x5 picture, centered on larger matrices.
+    Glyph(Frame),
+    /// A bar for a field strength in nT, see [`render_magnitude`].
+    Magnitude(u32),
+    /// Nothing lit.
+    Blank,
+}
+
+impl View {
+    pub fn render<const N: usize>(&self) -> Grid<N> {
+        match *self {
+            View::Arrow(theta) => render_arrow(theta),
+            View::Clock(hour) => render_clock(hour),
+            View::Level(ax, ay, az) => render_level(ax, ay, az),
+            View::Glyph(frame) => center(&frame),
+            View::Magnitude(field) => render_magnitude(field),
+            View::Blank => [[0; N]; N],
+        }
+    }
+}
+
+/// Without a real calibration, periodically interrupts heading views with
+/// `WARNING` so the heading isn't mistaken for a trusted one.
+#[derive(Default)]
+pub struct UncalibratedWarning {
+    phase: u32,
+}
+
+impl UncalibratedWarning {
+    pub const fn new() -> UncalibratedWarning {
+        UncalibratedWarning { phase: 0 }
+    }
+
+    /// Returns the view to show for this sample instead of `view`.
+    pub fn apply(&mut self, view: View, calibrated: bool) -> View {
+        let show_warning = matches!(view, View::Arrow(_) | View::Clock(_))
+            && !calibrated
+            && self.phase < UNCALIBRATED_WARNING_LEN;
+        self.phase = (self.phase + 1) % UNCALIBRATED_WARNING_PERIOD;
+        if show_warning {
+            View::Glyph(WARNING)
+        } else {
+            view
+        }
+    }
+}
+
+/// The animation that takes over the matrix once the sphere map is
+/// complete: rings rippling out from the middle a few times, then a tick.
+/// It goes by the samples' timestamps, so it runs at the same speed
+/// whatever the sample rate, if more smoothly at a faster one.
+#[derive(Default)]
+pub struct Celebration {
+    started_us: Option<u64>,
+}
+
+impl Celebration {
+    pub const fn new() -> Celebration {
+        Celebration { started_us: None }
+    }
+
+    pub fn start(&mut self, now_us: u64) {
+        self.started_us = Some(now_us);
+    }
+
+    /// The frame to show at `now_us` instead of the usual one, until the
+    /// animation is over.
+    pub fn frame(&mut self, now_us: u64) -> Option<Frame> {
+        let elapsed_us = now_us.saturating_sub(self.started_us?);
+        let step = (elapsed_us / RIPPLE_STEP_US) as usize;
+        if step < RIPPLES as usize * RIPPLE.len() {
+            return Some(RIPPLE[step % RIPPLE.len()]);
+        }
+        if elapsed_us < RIPPLES * RIPPLE.len() as u64 * RIPPLE_STEP_US + CELEBRATION_TICK_US {
+            return Some(TICK);
+        }
+        self.started_us = None;
+        None
+    }
+}
+
+/// A fading trail of previous needle positions, so a glance shows whether
+/// and which way the board has been turning.
+pub struct Trail<const N: usize> {
+    fade: Grid<N>,
+}
+
+impl<const N: usize> Default for Trail<N> {
+    fn default() -> Trail<N> {
+        Trail::new()
+    }
+}
+
+impl<const N: usize> Trail<N> {
+    pub const fn new() -> Trail<N> {
+        Trail { fade: [[0; N]; N] }
+    }
+
+    /// Renders `view`, adding the trail for heading views. Any other view
+    /// clears the trail so it doesn't reappear later.
+    pub fn follow(&mut self, view: &View) -> Grid<N> {
+        let mut frame = view.render();
+        if !matches!(view, View::Arrow(_) | View::Clock(_)) {
+            self.fade = [[0; N]; N];
+            return frame;
+        }
+        for (pixel, fade) in frame
+            .iter_mut()
+            .flatten()
+            .zip(self.fade.iter_mut().flatten())
+        {
+            if *pixel > 0 {
+                *fade = TRAIL_TICKS;
+            } else {
+                *fade = fade.saturating_sub(1);
+                *pixel = (*fade * TRAIL_BRIGHTNESS).div_ceil(TRAIL_TICKS);
+            }
+        }
+        frame
+    }
+}
+
+/// Clockwise rotation applied to every rendered frame, for boards mounted
+/// sideways or upside down.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Rotation {
+    #[default]
+    Deg0 = 0,
+    Deg90 = 1,
+    Deg180 = 2,
+    Deg270 = 3,
+}
+
+impl Rotation {
+    pub fn from_index(index: u8) -> Option<Rotation> {
+        match index {
+            0 => Some(Rotation::Deg0),
+            1 => Some(Rotation::Deg90),
+            2 => Some(Rotation::Deg180),
+            3 => Some(Rotation::Deg270),
+            _ => None,
+        }
+    }
+
+    pub fn from_degrees(degrees: u16) -> Option<Rotation> {
+        if !degrees.is_multiple_of(90) {
+            return None;
+        }
+        Rotation::from_index((degrees / 90) as u8)
+    }
+
+    pub fn degrees(self) -> u16 {
+        self as u16 * 90
+    }
+
+    pub fn apply<const N: usize>(self, frame: Grid<N>) -> Grid<N> {
+        let mut out = frame;
+        for _ in 0..self as u8 {
+            out = rotate_clockwise(out);
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+}
+
+impl Direction {
+    /// Field angle at the middle of this direction's octant, in the same
+    /// convention as [`dir_from_theta`].
+    pub fn theta(&self) -> f32 {
+        match self {
+            Direction::East => 0.,
+            Direction::NorthEast => PI / 4.,
+            Direction::North => PI / 2.,
+            Direction::NorthWest => 3. * PI / 4.,
+            Direction::West => PI,
+            Direction::SouthWest => -3. * PI / 4.,
+            Direction::South => -PI / 2.,
+            Direction::SouthEast => -PI / 4.,
+        }
+    }
+}
+
+/// The octant of a calibrated field's horizontal components, the same as
+/// `dir_from_theta(atan2f(y, x))` but with only integer compares.
+pub fn dir_from_field(x: i32, y: i32) -> Direction {
+    let (x, y) = (x as i64, y as i64);
+    let (abs_x, abs_y) = (x.abs(), y.abs());
+    if abs_y * TAN_PI_8_DEN <= abs_x * TAN_PI_8_NUM {
+        if x < 0 {
+            Direction::West
+        } else {
+            Direction::East
+        }
+    } else if abs_x * TAN_PI_8_DEN < abs_y * TAN_PI_8_NUM {
+        if y < 0 {
+            Direction::South
+        } else {
+            Direction::North
+        }
+    } else {
+        match (x < 0, y < 0) {
+            (false, false) => Direction::NorthEast,
+            (true, false) => Direction::NorthWest,
+            (true, true) => Direction::SouthWest,
+            (false, true) => Direction::SouthEast,
+        }
+    }
+}
+
+/// The angle of a calibrated field's horizontal components in radians,
+/// `atan2f(y, x)`, worked out in fixed point with the `fixed-point` feature.
+pub fn theta_from_field(x: i32, y: i32) -> f32 {
+    #[cfg(not(feature = "fixed-point"))]
+    return atan2f(y as f32, x as f32);
+    #[cfg(feature = "fixed-point")]
+    return crate::fixed::atan2(y, x).to_f32();
+}
+
+/// The octant of a heading in whole degrees clockwise from North.
+#[cfg(feature = "fixed-point")]
+fn dir_from_heading(heading: u32) -> Direction {
+    match (heading + 22) / 45 % 8 {
+        0 => Direction::North,
+        1 => Direction::NorthEast,
+        2 => Direction::East,
+        3 => Direction::SouthEast,
+        4 => Direction::South,
+        5 => Direction::SouthWest,
+        6 => Direction::West,
+        _ => Direction::NorthWest,
+    }
+}
+
+pub fn dir_from_theta(theta: f32) -> Direction {
+    if theta < -7. * PI / 8. {
+        Direction::West
+    } else if theta < -5. * PI / 8. {
+        Direction::SouthWest
+    } else if theta < -3. * PI / 8. {
+        Direction::South
+    } else if theta < -PI / 8. {
+        Direction::SouthEast
+    } else if theta < PI / 8. {
+        Direction::East
+    } else if theta < 3. * PI / 8. {
+        Direction::NorthEast
+    } else if theta < 5. * PI / 8. {
+        Direction::North
+    } else if theta < 7. * PI / 8. {
+        Direction::NorthWest
+    } else {
+        Direction::West
+    }
+}
+
+/// Rasterizes an arrow pointing at magnetic North for a field angle `theta`
+/// (radians, as returned by `atan2f(y, x)` on the calibrated field).
+///
+/// The arrow points up when `theta` is `PI / 2` and rotates clockwise on the
+/// display as `theta` decreases, matching the board turning to face East.
+pub fn render_arrow<const N: usize>(theta: f32) -> Grid<N> {
+    let mut frame = [[0; N]; N];
+    let center = (N - 1) as f32 / 2.;
+
+    // Screen-space unit vector (x right, y up) the arrow points along.
+    let (ux, uy) = (-cosf(theta), sinf(theta));
+    let (sx, sy) = to_grid_step(ux, uy);
+
+    // Shaft: one-pixel steps from tail to tip.
+    for i in 0..N {
+        let t = i as f32 - center;
+        plot(&mut frame, center + sx * t, center - sy * t);
+    }
+
+    // Head: two barbs swept back from the tip.
+    let tip = (center + sx * center, center - sy * center);
+    for angle in [BARB_ANGLE, -BARB_ANGLE] {
+        let (bx, by) = rotate(ux, uy, angle);
+        let (bx, by) = to_grid_step(bx, by);
+        for s in 1..=(N - 1) / 2 {
+            let s = s as f32;
+            plot(&mut frame, tip.0 + bx * s, tip.1 - by * s);
+        }
+    }
+
+    frame
+}
+
+/// Converts a field angle `theta` into a heading in degrees clockwise from
+/// magnetic North, from 0 up to (but excluding) 360.
+pub fn heading_from_theta(theta: f32) -> f32 {
+    let heading = 90. - theta.to_degrees();
+    if heading < 0. {
+        heading + 360.
+    } else if heading >= 360. {
+        heading - 360.
+    } else {
+        heading
+    }
+}
+
+/// Returns the clock position (0 for 12 o'clock up to 11) nearest to a
+/// heading in degrees.
+pub fn clock_hour(heading: f32) -> u8 {
+    (roundf(heading / 30.) as u8) % 12
+}
+
+/// Renders a clock face: the center pixel plus a hand pointing at `hour`, so
+/// 12 o'clock means the board faces North and 3 o'clock means East.
+pub fn render_clock<const N: usize>(hour: u8) -> Grid<N> {
+    let mut frame = [[0; N]; N];
+    let center = (N - 1) as f32 / 2.;
+    let angle = (hour % 12) as f32 * PI / 6.;
+    let (sx, sy) = to_grid_step(sinf(angle), cosf(angle));
+    for s in 0..=(N - 1) / 2 {
+        let s = s as f32;
+        plot(&mut frame, center + sx * s, center - sy * s);
+    }
+    frame
+}
+
+/// Brings an angle back into -π to π after an offset of up to π.
+#[cfg(not(feature = "fixed-point"))]
+fn wrap_angle(theta: f32) -> f32 {
+    if theta > PI {
+        theta - 2. * PI
+    } else if theta < -PI {
+        theta + 2. * PI
+    } else {
+        theta
+    }
+}
+
+fn rotate_clockwise<const N: usize>(frame: Grid<N>) -> Grid<N> {
+    let mut out = [[0; N]; N];
+    for (row, line) in frame.iter().enumerate() {
+        for (col, pixel) in line.iter().enumerate() {
+            out[col][N - 1 - row] = *pixel;
+        }
+    }
+    out
+}
+
+/// Scales a direction so its larger component is exactly one pixel, which
+/// makes diagonal arrows reach the corners of the grid. Components that are
+/// only rounding noise are snapped to zero so straight arrows stay straight.
+fn to_grid_step(x: f32, y: f32) -> (f32, f32) {
+    let m = fabsf(x).max(fabsf(y));
+    let snap = |v: f32| if fabsf(v) < 1e-4 { 0. } else { v };
+    (snap(x / m), snap(y / m))
+}
+
+fn rotate(x: f32, y: f32, angle: f32) -> (f32, f32) {
+    let (s, c) = (sinf(angle), cosf(angle));
+    (x * c - y * s, x * s + y * c)
+}
+
+fn plot<const N: usize>(frame: &mut Grid<N>, col: f32, row: f32) {
+    let (col, row) = (roundf(col), roundf(row));
+    if (0. ..N as f32).contains(&col) && (0. ..N as f32).contains(&row) {
+        frame[row as usize][col as usize] = MAX_BRIGHTNESS;
+    }
+}
+
+/// Places a 5x5 frame in the middle of a larger grid.
+fn center<const N: usize>(frame: &Frame) -> Grid<N> {
+    let mut out = [[0; N]; N];
+    let offset = N.saturating_sub(5) / 2;
+    for (row, line) in frame.iter().enumerate().take(N) {
+        for (col, pixel) in line.iter().enumerate().take(N) {
+            out[row + offset][col + offset] = *pixel;
+        }
+    }
+    out
+}
+
+/// Renders a single decimal digit centered on the matrix; values above 9
+/// show their last digit.
+pub fn render_digit(digit: u8) -> Frame {
+    let mut frame = [[0; 5]; 5];
+    for (row, bits) in DIGIT_FONT[(digit % 10) as usize].iter().enumerate() {
+        for col in 0..3 {
+            if bits & (0b100 >> col) != 0 {
+                frame[row][col + 1] = MAX_BRIGHTNESS;
+            }
+        }
+    }
+    frame
+}
+
+/// Renders a field strength in nT as a bar filling the matrix from the
+/// bottom row up, left to right, full at `MAGNITUDE_FULL_SCALE_NT`. Any
+/// field at all lights at least one pixel.
+pub fn render_magnitude<const N: usize>(field: u32) -> Grid<N> {
+    let pixels = (N * N) as u64;
+    let lit = (field as u64 * pixels)
+        .div_ceil(MAGNITUDE_FULL_SCALE_NT)
+        .min(pixels) as usize;
+    let mut frame = [[0; N]; N];
+    for i in 0..lit {
+        frame[N - 1 - i / N][i % N] = MAX_BRIGHTNESS;
+    }
+    frame
+}
+
+/// Renders a spirit-level bubble for an accelerometer reading in mg.
+///
+/// The bubble drifts towards the raised edge of the board, one pixel per
+/// `LEVEL_STEP` of tilt beyond `LEVEL_THRESHOLD`, so the center pixel is only
+/// lit when both axes are level. On even-sized grids the bubble is 2x2.
+pub fn render_level<const N: usize>(ax: i32, ay: i32, az: i32) -> Grid<N> {
+    let (ax, ay, az) = (ax as f32, ay as f32, az as f32);
+    let tilt_x = atan2f(ax, sqrtf(ay * ay + az * az));
+    let tilt_y = atan2f(ay, sqrtf(ax * ax + az * az));
+
+    let center = (N - 1) as f32 / 2.;
+    let max_offset = (N - 1) / 2;
+    let col = center - bubble_offset(tilt_x, max_offset) as f32;
+    let row = center + bubble_offset(tilt_y, max_offset) as f32;
+
+    let mut frame = [[0; N]; N];
+    for (r, line) in frame.iter_mut().enumerate() {
+        for (c, pixel) in line.iter_mut().enumerate() {
+            if fabsf(r as f32 - row) <= 0.5 && fabsf(c as f32 - col) <= 0.5 {
+                *pixel = MAX_BRIGHTNESS;
+            }
+        }
+    }
+    frame
+}
+
+fn bubble_offset(tilt: f32, max_offset: usize) -> i32 {
+    let excess = fabsf(tilt) - LEVEL_THRESHOLD;
+    if excess <= 0. {
+        return 0;
+    }
+    let offset = ((excess / LEVEL_STEP) as i32 + 1).min(max_offset as i32);
+    if tilt < 0. {
+        -offset
+    } else {
+        offset
+    }
+}
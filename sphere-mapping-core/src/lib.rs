@@ -0,0 +1,57 @@
+//! Hardware-independent pieces of the compass, shared by the RTIC firmware in
+//! `microbit-firmware` and the async firmware in `microbit-firmware-embassy`:
+//! the top-level mode, the sample-to-display pipeline, the event bus its
+//! outputs follow, inactivity detection, its fixed-point math, LED
+//! rendering, the calibration fit and game, which way gravity points, the
+//! sphere map and the calibration's refit from its samples, settings and
+//! their flash records, the last panic message, the boot count and total
+//! uptime, the serial command protocol, burst capture, benchmark runs,
+//! binary sample frames, the status frame, reset reasons, magnetic surveys
+//! and the build-time configuration.
+//! The sensor and display they need are described by the traits in
+//! [`device`].
+//!
+//! With the `alloc` feature, it also has what hosts need and the boards
+//! don't: decoding the serial stream and the least-squares calibration fit,
+//! shared by the host tools in `sphere-mapping-host` and the browser page
+//! in `sphere-mapping-web`.
+
+#![no_std]
+
+#[cfg(not(any(feature = "libm", feature = "fixed-point")))]
+compile_error!("without the `libm` feature, the `fixed-point` one is needed for the heading math");
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+pub mod benchmark;
+pub mod boot_record;
+pub mod calibration;
+pub mod capture;
+pub mod command;
+pub mod compass;
+pub mod config;
+pub mod device;
+#[cfg(feature = "alloc")]
+pub mod ellipsoid;
+pub mod events;
+pub mod fixed;
+pub mod frame;
+pub mod geodesic;
+pub mod gravity;
+pub mod led;
+pub mod math;
+pub mod mode;
+pub mod motion;
+pub mod panic_log;
+pub mod refit;
+pub mod reset;
+pub mod settings;
+pub mod sphere_map;
+pub mod status;
+pub mod storage;
+#[cfg(feature = "alloc")]
+pub mod stream;
+pub mod survey;
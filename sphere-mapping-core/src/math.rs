@@ -0,0 +1,77 @@
+//! The float functions the crate needs beyond arithmetic: libm's, with the
+//! `libm` feature, or without it worked out with [`fixed`]'s CORDIC and
+//! Newton's method, so a firmware built with `fixed-point` and without
+//! `libm` links no floating-point library at all. Those are accurate to
+//! about 2^-15, plenty for drawing on the matrix and binning directions,
+//! and only take angles within a few turns of zero.
+
+#[cfg(feature = "libm")]
+pub use libm::{asinf, atan2f, cosf, fabsf, roundf, sinf, sqrtf};
+
+#[cfg(not(feature = "libm"))]
+pub use self::software::{asinf, atan2f, cosf, fabsf, roundf, sinf, sqrtf};
+
+#[cfg(not(feature = "libm"))]
+mod software {
+    use core::f32::consts::PI;
+
+    use crate::fixed::{self, Fixed};
+
+    pub fn fabsf(value: f32) -> f32 {
+        value.abs()
+    }
+
+    /// Rounds half away from zero.
+    pub fn roundf(value: f32) -> f32 {
+        // Anything this large is whole already.
+        if fabsf(value) >= (1 << 23) as f32 {
+            return value;
+        }
+        (value + if value < 0. { -0.5 } else { 0.5 }) as i32 as f32
+    }
+
+    pub fn sqrtf(value: f32) -> f32 {
+        if value < 0. {
+            return f32::NAN;
+        }
+        if value == 0. || value == f32::INFINITY {
+            return value;
+        }
+        // Halving the exponent is a guess within a few percent, which three
+        // steps of Newton's method take to the last bit.
+        let mut root = f32::from_bits((value.to_bits() >> 1) + 0x1fbd_1df5);
+        for _ in 0..3 {
+            root = 0.5 * (root + value / root);
+        }
+        root
+    }
+
+    pub fn atan2f(y: f32, x: f32) -> f32 {
+        let largest = fabsf(x).max(fabsf(y));
+        if largest == 0. {
+            return 0.;
+        }
+        // Only the direction matters, so scale it to fill CORDIC's inputs.
+        let scale = (1 << 24) as f32 / largest;
+        fixed::atan2((y * scale) as i32, (x * scale) as i32).to_f32()
+    }
+
+    pub fn asinf(value: f32) -> f32 {
+        let value = value.clamp(-1., 1.);
+        atan2f(value, sqrtf(1. - value * value))
+    }
+
+    pub fn sinf(angle: f32) -> f32 {
+        fixed::sin_cos(wrap(angle)).0.to_f32()
+    }
+
+    pub fn cosf(angle: f32) -> f32 {
+        fixed::sin_cos(wrap(angle)).1.to_f32()
+    }
+
+    /// `angle` brought into -π to π, as a fixed-point number.
+    fn wrap(angle: f32) -> Fixed {
+        let turns = roundf(angle / (2. * PI));
+        Fixed::from_f32(angle - turns * 2. * PI)
+    }
+}
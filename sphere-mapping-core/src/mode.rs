@@ -0,0 +1,298 @@
+//! The firmware's top-level mode, and how button presses and serial commands
+//! move it between modes. The firmware asks the mode what to do with each
+//! sample rather than keeping flags of its own, so adding a mode means adding
+//! a variant and its transitions here.
+
+/// What the firmware is doing as a whole. The display mode (compass, clock or
+/// level) picked with button A is a setting within `Compass`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AppMode {
+    /// The matrix shows the display mode's view, and samples are sent.
+    #[default]
+    Compass = 0,
+    /// The calibration game has the sensor's samples and the matrix to
+    /// itself.
+    Calibrating = 1,
+    /// Samples are sent with the matrix dark.
+    StreamingOnly = 2,
+    /// The matrix shows the field strength as a bar, and samples are sent.
+    Magnitude = 3,
+    /// The matrix is dark and nothing is sent until a button is pressed.
+    Sleep = 4,
+    /// The matrix guides the board round the sphere map's cells that still
+    /// need samples, and samples are sent.
+    Mapping = 5,
+    /// The matrix shows the field strength as a bar, and the field is
+    /// averaged and logged with the board's position instead of samples
+    /// being sent.
+    Survey = 6,
+}
+
+/// Something that can change the mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModeEvent {
+    ButtonA,
+    ButtonB,
+    /// Both buttons held together.
+    BothButtons,
+    /// A serial command asking for a mode.
+    Command(AppMode),
+    /// The calibration game finished, whether or not it produced a
+    /// calibration.
+    CalibrationDone,
+    /// A serial command asking to stop the calibration game.
+    StopCalibration,
+    /// The calibration game was stopped or ran out of time, and the mode
+    /// before it should come back.
+    CalibrationAbandoned(AppMode),
+}
+
+/// Work the firmware has to do for a transition, beyond switching modes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModeAction {
+    None,
+    /// Move on to the next display mode and save it.
+    NextDisplayMode,
+    /// Run the calibration game, then report `ModeEvent::CalibrationDone`.
+    StartCalibration,
+    /// Stop the calibration game, then report
+    /// `ModeEvent::CalibrationAbandoned` with the mode before it.
+    StopCalibration,
+    /// Move the survey on to its next waypoint.
+    NextWaypoint,
+}
+
+impl AppMode {
+    pub fn from_index(index: u8) -> Option<AppMode> {
+        match index {
+            0 => Some(AppMode::Compass),
+            1 => Some(AppMode::Calibrating),
+            2 => Some(AppMode::StreamingOnly),
+            3 => Some(AppMode::Magnitude),
+            4 => Some(AppMode::Sleep),
+            5 => Some(AppMode::Mapping),
+            6 => Some(AppMode::Survey),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AppMode::Compass => "compass",
+            AppMode::Calibrating => "calibrating",
+            AppMode::StreamingOnly => "streaming",
+            AppMode::Magnitude => "magnitude",
+            AppMode::Sleep => "sleep",
+            AppMode::Mapping => "mapping",
+            AppMode::Survey => "survey",
+        }
+    }
+
+    /// Returns the mode after `event`, and what has to be done to get there.
+    ///
+    /// - Button B, or asking for `Calibrating`, starts the calibration game
+    ///   from any mode but `Sleep`; when it finishes the compass comes back.
+    ///   In `Survey` button B steps on to the next waypoint instead.
+    /// - Button A cycles the display modes in `Compass`, and returns to
+    ///   `Compass` from `StreamingOnly`, `Magnitude`, `Mapping` and
+    ///   `Survey`.
+    /// - Both buttons together go to `Sleep`, and any press wakes it.
+    /// - Button A, or asking to stop it, stops the calibration game, and
+    ///   the mode before it comes back; nothing else interrupts it.
+    pub fn handle(self, event: ModeEvent) -> (AppMode, ModeAction) {
+        match (self, event) {
+            (AppMode::Calibrating, ModeEvent::CalibrationDone) => {
+                (AppMode::Compass, ModeAction::None)
+            }
+            (AppMode::Calibrating, ModeEvent::CalibrationAbandoned(previous)) => {
+                (previous, ModeAction::None)
+            }
+            (AppMode::Calibrating, ModeEvent::ButtonA | ModeEvent::StopCalibration) => {
+                (self, ModeAction::StopCalibration)
+            }
+            (AppMode::Calibrating, _)
+            | (
+                _,
+                ModeEvent::CalibrationDone
+                | ModeEvent::StopCalibration
+                | ModeEvent::CalibrationAbandoned(_),
+            ) => (self, ModeAction::None),
+            (AppMode::Sleep, ModeEvent::Command(mode)) if mode != AppMode::Calibrating => {
+                (mode, ModeAction::None)
+            }
+            (AppMode::Sleep, ModeEvent::Command(_)) => (self, ModeAction::None),
+            (AppMode::Sleep, _) => (AppMode::Compass, ModeAction::None),
+            (_, ModeEvent::BothButtons) => (AppMode::Sleep, ModeAction::None),
+            (AppMode::Survey, ModeEvent::ButtonB) => (self, ModeAction::NextWaypoint),
+            (_, ModeEvent::ButtonB) | (_, ModeEvent::Command(AppMode::Calibrating)) => {
+                (AppMode::Calibrating, ModeAction::StartCalibration)
+            }
+            (AppMode::Compass, ModeEvent::ButtonA) => (self, ModeAction::NextDisplayMode),
+            (_, ModeEvent::ButtonA) => (AppMode::Compass, ModeAction::None),
+            (_, ModeEvent::Command(mode)) => (mode, ModeAction::None),
+        }
+    }
+
+    /// Whether samples are sent over serial in this mode.
+    pub fn sends_samples(self) -> bool {
+        matches!(
+            self,
+            AppMode::Compass | AppMode::StreamingOnly | AppMode::Magnitude | AppMode::Mapping
+        )
+    }
+}
+
+impl core::fmt::Display for AppMode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Mode: {}", self.name())
+    }
+}
+
+/// Turns the two buttons' levels, polled regularly, into mode events. A
+/// single button acts when it is released, so that pressing both together
+/// counts only as `BothButtons`, which acts as soon as both are down.
+#[derive(Default)]
+pub struct Buttons {
+    a_was_pressed: bool,
+    b_was_pressed: bool,
+    chord: bool,
+}
+
+impl Buttons {
+    pub const fn new() -> Buttons {
+        Buttons {
+            a_was_pressed: false,
+            b_was_pressed: false,
+            chord: false,
+        }
+    }
+
+    pub fn update(&mut self, a_pressed: bool, b_pressed: bool) -> Option<ModeEvent> {
+        let event = if a_pressed && b_pressed && !self.chord {
+            self.chord = true;
+            Some(ModeEvent::BothButtons)
+        } else if self.a_was_pressed && !a_pressed && !self.chord {
+            Some(ModeEvent::ButtonA)
+        } else if self.b_was_pressed && !b_pressed && !self.chord {
+            Some(ModeEvent::ButtonB)
+        } else {
+            None
+        };
+        if !a_pressed && !b_pressed {
+            self.chord = false;
+        }
+        self.a_was_pressed = a_pressed;
+        self.b_was_pressed = b_pressed;
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [AppMode; 7] = [
+        AppMode::Compass,
+        AppMode::Calibrating,
+        AppMode::StreamingOnly,
+        AppMode::Magnitude,
+        AppMode::Sleep,
+        AppMode::Mapping,
+        AppMode::Survey,
+    ];
+
+    #[test]
+    fn transitions() {
+        use AppMode::*;
+        use ModeAction as Do;
+        use ModeEvent::*;
+        let table = [
+            (Compass, ButtonA, Compass, Do::NextDisplayMode),
+            (Compass, ButtonB, Calibrating, Do::StartCalibration),
+            (Compass, BothButtons, Sleep, Do::None),
+            (Compass, Command(Mapping), Mapping, Do::None),
+            (
+                Compass,
+                Command(Calibrating),
+                Calibrating,
+                Do::StartCalibration,
+            ),
+            (StreamingOnly, ButtonA, Compass, Do::None),
+            (Magnitude, ButtonB, Calibrating, Do::StartCalibration),
+            (Mapping, ButtonA, Compass, Do::None),
+            (Mapping, BothButtons, Sleep, Do::None),
+            (Survey, ButtonA, Compass, Do::None),
+            (Survey, ButtonB, Survey, Do::NextWaypoint),
+            (
+                Survey,
+                Command(Calibrating),
+                Calibrating,
+                Do::StartCalibration,
+            ),
+            (Sleep, ButtonA, Compass, Do::None),
+            (Sleep, ButtonB, Compass, Do::None),
+            (Sleep, BothButtons, Compass, Do::None),
+            (Sleep, Command(Survey), Survey, Do::None),
+            (Sleep, Command(Calibrating), Sleep, Do::None),
+            // Stopping the game by button or command waits for it to report
+            // that it was abandoned, on a timeout too.
+            (Calibrating, ButtonA, Calibrating, Do::StopCalibration),
+            (
+                Calibrating,
+                StopCalibration,
+                Calibrating,
+                Do::StopCalibration,
+            ),
+            (Calibrating, CalibrationAbandoned(Survey), Survey, Do::None),
+            (Calibrating, CalibrationAbandoned(Sleep), Sleep, Do::None),
+            (Calibrating, CalibrationDone, Compass, Do::None),
+            (Calibrating, ButtonB, Calibrating, Do::None),
+            (Calibrating, BothButtons, Calibrating, Do::None),
+            (Calibrating, Command(Mapping), Calibrating, Do::None),
+        ];
+        for (mode, event, next, action) in table {
+            assert_eq!(mode.handle(event), (next, action), "{mode:?} {event:?}");
+        }
+    }
+
+    #[test]
+    fn calibration_events_only_matter_while_calibrating() {
+        for mode in MODES
+            .into_iter()
+            .filter(|&mode| mode != AppMode::Calibrating)
+        {
+            for event in [
+                ModeEvent::CalibrationDone,
+                ModeEvent::StopCalibration,
+                ModeEvent::CalibrationAbandoned(AppMode::Mapping),
+            ] {
+                assert_eq!(
+                    mode.handle(event),
+                    (mode, ModeAction::None),
+                    "{mode:?} {event:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn from_index_matches_discriminant() {
+        for mode in MODES {
+            assert_eq!(AppMode::from_index(mode as u8), Some(mode));
+        }
+        assert_eq!(AppMode::from_index(MODES.len() as u8), None);
+    }
+
+    #[test]
+    fn buttons_act_on_release_and_chord_at_once() {
+        let mut buttons = Buttons::new();
+        assert_eq!(buttons.update(true, false), None);
+        assert_eq!(buttons.update(false, false), Some(ModeEvent::ButtonA));
+        assert_eq!(buttons.update(false, true), None);
+        assert_eq!(buttons.update(false, false), Some(ModeEvent::ButtonB));
+        assert_eq!(buttons.update(true, false), None);
+        assert_eq!(buttons.update(true, true), Some(ModeEvent::BothButtons));
+        assert_eq!(buttons.update(false, true), None);
+        assert_eq!(buttons.update(false, false), None);
+    }
+}
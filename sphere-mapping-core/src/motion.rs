@@ -0,0 +1,54 @@
+//! Telling when the board has been left alone, from the accelerometer, so
+//! the firmware can power down until it is picked up again.
+
+use crate::device::Acceleration;
+
+/// Change on any axis, in mg, that counts as the board being moved. Well
+/// above the noise of a board lying on a desk.
+const MOVED_MG: i32 = 60;
+
+/// Times how long the board has been still. Readings are compared with the
+/// one taken when it came to rest rather than with each other, so a slow
+/// tilt still counts as movement.
+pub struct StillTimer {
+    rest: Option<Acceleration>,
+    still_since_us: u64,
+}
+
+impl Default for StillTimer {
+    fn default() -> StillTimer {
+        StillTimer::new()
+    }
+}
+
+impl StillTimer {
+    pub const fn new() -> StillTimer {
+        StillTimer {
+            rest: None,
+            still_since_us: 0,
+        }
+    }
+
+    /// Starts timing again from the next reading.
+    pub fn reset(&mut self) {
+        self.rest = None;
+    }
+
+    /// Follows a reading taken at `timestamp_us`, and returns how long the
+    /// board has been still, in microseconds.
+    pub fn update(&mut self, accel: &Acceleration, timestamp_us: u64) -> u64 {
+        let moved = match self.rest {
+            Some(rest) => {
+                (accel.x - rest.x).abs() > MOVED_MG
+                    || (accel.y - rest.y).abs() > MOVED_MG
+                    || (accel.z - rest.z).abs() > MOVED_MG
+            }
+            None => true,
+        };
+        if moved {
+            self.rest = Some(*accel);
+            self.still_since_us = timestamp_us;
+        }
+        timestamp_us.saturating_sub(self.still_since_us)
+    }
+}
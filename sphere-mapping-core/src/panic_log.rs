@@ -0,0 +1,69 @@
+//! The message of the last panic, kept in flash so a unit that crashed in
+//! the field can say why on its next boot.
+
+use core::fmt;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::storage::{Slot, Storage, StorageError, MAX_PAYLOAD_LEN};
+
+const PANIC_LOG_VERSION: u16 = 1;
+
+/// A panic message, truncated to what fits in one record.
+pub struct PanicLog {
+    text: [u8; MAX_PAYLOAD_LEN],
+    len: usize,
+}
+
+impl PanicLog {
+    pub const fn new() -> PanicLog {
+        PanicLog {
+            text: [0; MAX_PAYLOAD_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or("")
+    }
+
+    /// Returns the stored message, if any, and erases it so it is only
+    /// reported once.
+    pub fn take<F: NorFlash + ReadNorFlash>(storage: &mut Storage<F>) -> Option<PanicLog> {
+        let mut log = PanicLog::new();
+        log.len = storage.load(Slot::Panic, PANIC_LOG_VERSION, &mut log.text)?;
+        storage.erase(Slot::Panic).ok();
+        Some(log)
+    }
+
+    pub fn save<F: NorFlash + ReadNorFlash>(
+        &self,
+        storage: &mut Storage<F>,
+    ) -> Result<(), StorageError> {
+        storage.store(Slot::Panic, PANIC_LOG_VERSION, &self.text[..self.len])
+    }
+}
+
+impl Default for PanicLog {
+    fn default() -> PanicLog {
+        PanicLog::new()
+    }
+}
+
+/// Appends as much of the text as fits, cutting it at a character boundary.
+impl fmt::Write for PanicLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(self.text.len() - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.text[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+impl fmt::Display for PanicLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -0,0 +1,191 @@
+//! Refitting the calibration from the samples the sphere map has seen, so
+//! a long mapping session also sharpens the calibration it relies on.
+//!
+//! The sphere map bins strengths by which way gravity points, which says
+//! nothing about the field's direction around the vertical, so the refit
+//! keeps its own bins: the raw field, averaged by which cell of a 42-cell
+//! geodesic grid the calibrated field points into. Each cell's average is
+//! one point for [`calibrate`], much less noisy than the single samples
+//! the calibration game takes. Once the map is complete the fit is run
+//! and offered, with how far the calibrated strengths of the averages
+//! spread under it and under the calibration in use, and only applied
+//! when asked. The fit takes a while, so it works on a copy, leaving the
+//! map free to take samples.
+
+use core::fmt;
+
+use crate::calibration::{
+    calibrate, calibrated_measurement, measurement_to_enu, Calibration, Measurement,
+};
+use crate::math::roundf;
+use crate::{fixed, geodesic, gravity};
+
+/// Cells the field's directions are binned into.
+const REFIT_CELLS: usize = 42;
+static VERTICES: [[f32; 3]; REFIT_CELLS] = geodesic::vertices();
+/// Samples a cell needs before its average is used.
+const MIN_CELL_SAMPLES: u32 = 10;
+/// Cells with enough samples needed for a fit, as many points as the
+/// calibration game collects.
+const MIN_FIT_CELLS: usize = 25;
+
+/// The running average of the raw field in one direction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FieldCell {
+    count: u32,
+    mean: [f32; 3],
+}
+
+impl FieldCell {
+    const EMPTY: FieldCell = FieldCell {
+        count: 0,
+        mean: [0.; 3],
+    };
+
+    fn add(&mut self, raw: &Measurement) {
+        if self.count == u32::MAX {
+            return;
+        }
+        self.count += 1;
+        let count = self.count as f32;
+        for (mean, value) in self.mean.iter_mut().zip([raw.x, raw.y, raw.z]) {
+            *mean += (value as f32 - *mean) / count;
+        }
+    }
+
+    fn average(&self) -> Measurement {
+        Measurement {
+            x: roundf(self.mean[0]) as i32,
+            y: roundf(self.mean[1]) as i32,
+            z: roundf(self.mean[2]) as i32,
+        }
+    }
+}
+
+/// A calibration fitted to the averages, with how well it and the one in
+/// use fit them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Refitted {
+    pub calibration: Calibration,
+    /// Cells whose averages were fitted.
+    pub cells: u16,
+    /// How far the averages' calibrated strengths spread, largest less
+    /// smallest, in nT, under the new calibration and the one in use.
+    pub spread_nt: u32,
+    pub current_spread_nt: u32,
+}
+
+/// `Map: refit, cells=<n>, spread=<nT>, current=<nT>, calibration=<center
+/// x>, <center y>, <center z>, <scale x>, <scale y>, <scale z>, <radius>`,
+/// with the calibration as `Calibration:` lines give it.
+impl fmt::Display for Refitted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Calibration {
+            center,
+            scale,
+            radius,
+        } = self.calibration;
+        write!(
+            f,
+            "Map: refit, cells={}, spread={}, current={}, calibration={}, {}, {}, {}, {}, {}, {}",
+            self.cells,
+            self.spread_nt,
+            self.current_spread_nt,
+            center.x,
+            center.y,
+            center.z,
+            scale.x,
+            scale.y,
+            scale.z,
+            radius
+        )
+    }
+}
+
+/// The raw field averaged by direction, and the fit on offer, if any.
+#[derive(Clone)]
+pub struct Refit {
+    cells: [FieldCell; REFIT_CELLS],
+    offer: Option<Refitted>,
+}
+
+impl Default for Refit {
+    fn default() -> Refit {
+        Refit::new()
+    }
+}
+
+impl Refit {
+    pub const fn new() -> Refit {
+        Refit {
+            cells: [FieldCell::EMPTY; REFIT_CELLS],
+            offer: None,
+        }
+    }
+
+    /// Forgets every sample, and the fit on offer.
+    pub fn clear(&mut self) {
+        *self = Refit::new();
+    }
+
+    /// Adds a raw field, in the sensor's own axes, to the cell its
+    /// calibrated counterpart points into.
+    pub fn add(&mut self, raw: &Measurement, calibrated: &Measurement) {
+        let vector = [calibrated.x, calibrated.y, calibrated.z].map(|axis| axis as f32);
+        if let Some(direction) = gravity::unit(vector) {
+            self.cells[gravity::nearest(&VERTICES, direction)].add(raw);
+        }
+    }
+
+    /// Fits a calibration to the cells with enough samples. Returns `Err`
+    /// with how many cells had enough if there were too few to fit.
+    pub fn fit(&self, current: &Calibration) -> Result<Refitted, u16> {
+        let mut points = [Measurement { x: 0, y: 0, z: 0 }; REFIT_CELLS];
+        let mut len = 0;
+        for cell in self
+            .cells
+            .iter()
+            .filter(|cell| cell.count >= MIN_CELL_SAMPLES)
+        {
+            points[len] = cell.average();
+            len += 1;
+        }
+        if len < MIN_FIT_CELLS {
+            return Err(len as u16);
+        }
+        let points = &points[..len];
+        // Fitted in the frame the calibration game fits in.
+        let mut enu = [Measurement { x: 0, y: 0, z: 0 }; REFIT_CELLS];
+        for (enu, point) in enu.iter_mut().zip(points) {
+            *enu = measurement_to_enu(*point);
+        }
+        let calibration = calibrate(&enu[..len]);
+        Ok(Refitted {
+            calibration,
+            cells: len as u16,
+            spread_nt: spread(points, &calibration),
+            current_spread_nt: spread(points, current),
+        })
+    }
+
+    /// Offers `refitted`, in place of any offered before.
+    pub fn offer(&mut self, refitted: Refitted) {
+        self.offer = Some(refitted);
+    }
+
+    /// Takes the fit on offer, if any, to be applied.
+    pub fn accept(&mut self) -> Option<Refitted> {
+        self.offer.take()
+    }
+}
+
+/// The largest calibrated strength of `points` less the smallest, in nT.
+fn spread(points: &[Measurement], calibration: &Calibration) -> u32 {
+    let strengths = points
+        .iter()
+        .map(|point| fixed::magnitude(&calibrated_measurement(*point, calibration)));
+    let (min, max) = strengths.fold((u32::MAX, 0), |(min, max), strength| {
+        (min.min(strength), max.max(strength))
+    });
+    max.saturating_sub(min)
+}
@@ -0,0 +1,53 @@
+//! Why the board last reset, decoded from the nRF52 `POWER.RESETREAS`
+//! register so both firmwares report it the same way.
+
+use core::fmt;
+
+const RESETPIN: u32 = 1 << 0;
+const DOG: u32 = 1 << 1;
+const SREQ: u32 = 1 << 2;
+const LOCKUP: u32 = 1 << 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetReason {
+    PowerOn,
+    ResetPin,
+    Watchdog,
+    SoftReset,
+    Lockup,
+    /// Woken from System OFF.
+    Wake,
+}
+
+impl ResetReason {
+    /// Decodes `RESETREAS`. The register accumulates until cleared, so when
+    /// several bits are set the most alarming one wins.
+    pub fn from_resetreas(bits: u32) -> ResetReason {
+        if bits & DOG != 0 {
+            ResetReason::Watchdog
+        } else if bits & LOCKUP != 0 {
+            ResetReason::Lockup
+        } else if bits & SREQ != 0 {
+            ResetReason::SoftReset
+        } else if bits & RESETPIN != 0 {
+            ResetReason::ResetPin
+        } else if bits != 0 {
+            ResetReason::Wake
+        } else {
+            ResetReason::PowerOn
+        }
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ResetReason::PowerOn => "power-on",
+            ResetReason::ResetPin => "reset button",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::SoftReset => "soft reset",
+            ResetReason::Lockup => "lockup",
+            ResetReason::Wake => "wake from off",
+        })
+    }
+}
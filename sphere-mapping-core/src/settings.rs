@@ -0,0 +1,342 @@
+use core::fmt::{self, Write};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::calibration::Measurement;
+use crate::command::SerialCommand;
+use crate::config;
+use crate::device::Acceleration;
+use crate::led::{DisplayMode, Rotation, MAX_BRIGHTNESS};
+use crate::math::roundf;
+use crate::storage::{Slot, Storage, StorageError};
+
+const SETTINGS_VERSION: u16 = 6;
+const SETTINGS_LEN: usize = 12;
+const DEFAULT_DISPLAY_HOLD_MS: u16 = 100;
+/// Declination limit, in tenths of a degree either way.
+pub const MAX_DECLINATION: i16 = 1800;
+
+/// User-adjustable settings that persist across resets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub rotation: Rotation,
+    /// Minimum time a frame stays on the LED matrix before it can be replaced.
+    pub display_hold_ms: u16,
+    /// Fixed LED brightness from 1 to 9, or `AUTO_BRIGHTNESS` to follow the
+    /// ambient light.
+    pub brightness: u8,
+    /// What the matrix shows, also cycled with button A.
+    pub display_mode: DisplayMode,
+    pub output_format: OutputFormat,
+    /// Samples per line sent over serial, at least 1.
+    pub report_every: u8,
+    /// Weight of the previous field in the smoothed field, in 1/256ths; 0
+    /// turns smoothing off.
+    pub smoothing: u8,
+    /// Magnetic declination in tenths of a degree, East positive, so
+    /// headings point at true North. Up to `MAX_DECLINATION` either way.
+    pub declination: i16,
+    pub power: PowerMode,
+    /// Minutes without movement before the board powers down until it is
+    /// moved again; 0 never powers down.
+    pub sleep_after_min: u8,
+}
+
+pub const AUTO_BRIGHTNESS: u8 = 0;
+
+/// How each sample is written to serial.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    /// `Measurement: gx, gy, gz, ax, ay, az`, preceded by `Gyro: x, y, z`
+    /// when there is a gyro, as the host app expects.
+    #[default]
+    Text = 0,
+    /// `gx,gy,gz,ax,ay,az`, plus `,x,y,z` when there is a gyro, for
+    /// spreadsheets and serial plotters.
+    Csv = 1,
+    /// A binary frame per sample (see `frame`), for high rates. Where only
+    /// text can be sent, as `Text`.
+    Binary = 2,
+}
+
+impl OutputFormat {
+    pub fn from_index(index: u8) -> Option<OutputFormat> {
+        match index {
+            0 => Some(OutputFormat::Text),
+            1 => Some(OutputFormat::Csv),
+            2 => Some(OutputFormat::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Binary => "binary",
+        }
+    }
+
+    /// Writes one sample: the calibrated field in nT, the acceleration in
+    /// mg, when it was acquired in microseconds since boot, and the gyro's
+    /// rate in deg/s if there is one. Binary frames aren't text, so callers
+    /// that can send them encode a `Frame` instead, and this writes
+    /// text for them.
+    pub fn write_sample<W: Write>(
+        self,
+        out: &mut W,
+        field: &Measurement,
+        accel: &Acceleration,
+        timestamp_us: u64,
+        gyro: Option<[f32; 3]>,
+    ) -> fmt::Result {
+        let (gx, gy, gz) = (field.x as f32, field.y as f32, field.z as f32);
+        let (ax, ay, az) = (accel.x, accel.y, accel.z);
+        match self {
+            OutputFormat::Text | OutputFormat::Binary => {
+                if let Some([rx, ry, rz]) = gyro {
+                    write!(out, "Gyro: {rx:.2}, {ry:.2}, {rz:.2}\r\n")?;
+                }
+                write!(
+                    out,
+                    "Measurement: {gx:.2}, {gy:.2}, {gz:.2}, {ax:.2}, {ay:.2}, {az:.2}, {timestamp_us}\r\n"
+                )
+            }
+            OutputFormat::Csv => {
+                write!(out, "{gx:.2},{gy:.2},{gz:.2},{ax},{ay},{az}")?;
+                if let Some([rx, ry, rz]) = gyro {
+                    write!(out, ",{rx:.2},{ry:.2},{rz:.2}")?;
+                }
+                write!(out, ",{timestamp_us}\r\n")
+            }
+        }
+    }
+}
+
+/// One sample as [`OutputFormat::write_sample`] wrote it, read back from a
+/// serial log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoggedSample {
+    pub field: Measurement,
+    pub accel: Acceleration,
+    pub timestamp_us: u64,
+    pub gyro: Option<[f32; 3]>,
+}
+
+impl LoggedSample {
+    /// Parses a `Measurement:` line or a CSV line, without its line ending.
+    /// Text lines carry the gyro's rate on the `Gyro:` line before them, so
+    /// theirs is left `None`. Anything else gives `None`.
+    pub fn parse(line: &str) -> Option<LoggedSample> {
+        let line = line.trim();
+        let (values, text) = match line.strip_prefix("Measurement:") {
+            Some(values) => (values, true),
+            None => (line, false),
+        };
+        let mut parts = values.split(',').map(str::trim);
+        let mut numbers = [0f32; 9];
+        let mut len = 0;
+        let mut timestamp_us = None;
+        for part in parts.by_ref() {
+            // The timestamp is the last field and the only one that can't
+            // round-trip through f32.
+            if let Ok(timestamp) = part.parse::<u64>() {
+                if len == 6 || len == 9 {
+                    timestamp_us = Some(timestamp);
+                    break;
+                }
+            }
+            if len == numbers.len() {
+                return None;
+            }
+            numbers[len] = part.parse().ok()?;
+            len += 1;
+        }
+        let timestamp_us = timestamp_us?;
+        if parts.next().is_some() || (text && len != 6) {
+            return None;
+        }
+        let whole = |value: f32| roundf(value) as i32;
+        Some(LoggedSample {
+            field: Measurement {
+                x: whole(numbers[0]),
+                y: whole(numbers[1]),
+                z: whole(numbers[2]),
+            },
+            accel: Acceleration {
+                x: whole(numbers[3]),
+                y: whole(numbers[4]),
+                z: whole(numbers[5]),
+            },
+            timestamp_us,
+            gyro: (len == 9).then(|| [numbers[6], numbers[7], numbers[8]]),
+        })
+    }
+}
+
+/// How much the firmware does, traded against battery life.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PowerMode {
+    #[default]
+    Normal = 0,
+    /// For logging from a battery for days: a sample a second, a dark
+    /// matrix, a status frame a minute and as much sleep as possible.
+    Battery = 1,
+    /// Both halves of the sensor at 100 Hz, whatever the configured rate,
+    /// for capturing fast changes. Pair it with binary output.
+    HighRate = 2,
+}
+
+/// Complete samples a second in battery mode.
+const BATTERY_RATE_HZ: u32 = 1;
+/// The same in high-rate mode.
+pub const HIGH_RATE_HZ: u32 = 100;
+
+impl PowerMode {
+    pub fn from_index(index: u8) -> Option<PowerMode> {
+        match index {
+            0 => Some(PowerMode::Normal),
+            1 => Some(PowerMode::Battery),
+            2 => Some(PowerMode::HighRate),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PowerMode::Normal => "normal",
+            PowerMode::Battery => "battery",
+            PowerMode::HighRate => "high-rate",
+        }
+    }
+
+    /// Complete samples a second the sensor is set up for.
+    pub fn sample_rate_hz(self) -> u32 {
+        match self {
+            PowerMode::Normal => config::SAMPLE_RATE_HZ,
+            PowerMode::Battery => BATTERY_RATE_HZ,
+            PowerMode::HighRate => HIGH_RATE_HZ,
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            rotation: Rotation::default(),
+            display_hold_ms: DEFAULT_DISPLAY_HOLD_MS,
+            brightness: AUTO_BRIGHTNESS,
+            display_mode: DisplayMode::default(),
+            output_format: OutputFormat::default(),
+            report_every: 1,
+            smoothing: 0,
+            declination: 0,
+            power: PowerMode::default(),
+            sleep_after_min: 0,
+        }
+    }
+}
+
+impl core::fmt::Display for Settings {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Settings: rotation={}, hold={}, brightness=",
+            self.rotation.degrees(),
+            self.display_hold_ms
+        )?;
+        match self.brightness {
+            AUTO_BRIGHTNESS => write!(f, "auto")?,
+            level => write!(f, "{}", level)?,
+        }
+        write!(
+            f,
+            ", mode={}, format={}, every={}, smoothing={}, declination={}{}.{}, power={}, sleep=",
+            self.display_mode.name(),
+            self.output_format.name(),
+            self.report_every,
+            self.smoothing,
+            if self.declination < 0 { "-" } else { "" },
+            self.declination.unsigned_abs() / 10,
+            self.declination.unsigned_abs() % 10,
+            self.power.name()
+        )?;
+        match self.sleep_after_min {
+            0 => write!(f, "off"),
+            minutes => write!(f, "{}", minutes),
+        }
+    }
+}
+
+impl Settings {
+    /// Restores the stored settings, falling back to defaults if none were
+    /// saved or the stored record is unreadable.
+    pub fn load<F: NorFlash + ReadNorFlash>(storage: &mut Storage<F>) -> Settings {
+        let mut bytes = [0u8; SETTINGS_LEN];
+        match storage.load(Slot::Settings, SETTINGS_VERSION, &mut bytes) {
+            Some(SETTINGS_LEN) => Settings::from_bytes(&bytes).unwrap_or_default(),
+            _ => Settings::default(),
+        }
+    }
+
+    /// Applies `command` if it sets one of the settings, saying whether it
+    /// did. Both firmwares and the simulator go through this, so they agree
+    /// on what each command changes.
+    pub fn apply(&mut self, command: &SerialCommand) -> bool {
+        match *command {
+            SerialCommand::SetRotation(rotation) => self.rotation = rotation,
+            SerialCommand::SetDisplayHold(hold_ms) => self.display_hold_ms = hold_ms,
+            SerialCommand::SetBrightness(brightness) => self.brightness = brightness,
+            SerialCommand::SetDisplayMode(mode) => self.display_mode = mode,
+            SerialCommand::SetOutputFormat(format) => self.output_format = format,
+            SerialCommand::SetReportEvery(samples) => self.report_every = samples,
+            SerialCommand::SetSmoothing(weight) => self.smoothing = weight,
+            SerialCommand::SetDeclination(tenths) => self.declination = tenths,
+            SerialCommand::SetPowerMode(power) => self.power = power,
+            SerialCommand::SetSleepAfter(minutes) => self.sleep_after_min = minutes,
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn save<F: NorFlash + ReadNorFlash>(
+        &self,
+        storage: &mut Storage<F>,
+    ) -> Result<(), StorageError> {
+        storage.store(Slot::Settings, SETTINGS_VERSION, &self.to_bytes())
+    }
+
+    fn to_bytes(self) -> [u8; SETTINGS_LEN] {
+        let hold = self.display_hold_ms.to_le_bytes();
+        let declination = self.declination.to_le_bytes();
+        [
+            self.rotation as u8,
+            hold[0],
+            hold[1],
+            self.brightness,
+            self.display_mode as u8,
+            self.output_format as u8,
+            self.report_every,
+            self.smoothing,
+            declination[0],
+            declination[1],
+            self.power as u8,
+            self.sleep_after_min,
+        ]
+    }
+
+    fn from_bytes(bytes: &[u8; SETTINGS_LEN]) -> Option<Settings> {
+        Some(Settings {
+            rotation: Rotation::from_index(bytes[0])?,
+            display_hold_ms: u16::from_le_bytes([bytes[1], bytes[2]]),
+            brightness: bytes[3].min(MAX_BRIGHTNESS),
+            display_mode: DisplayMode::from_index(bytes[4])?,
+            output_format: OutputFormat::from_index(bytes[5])?,
+            report_every: bytes[6].max(1),
+            smoothing: bytes[7],
+            declination: i16::from_le_bytes([bytes[8], bytes[9]])
+                .clamp(-MAX_DECLINATION, MAX_DECLINATION),
+            power: PowerMode::from_index(bytes[10])?,
+            sleep_after_min: bytes[11],
+        })
+    }
+}
@@ -0,0 +1,892 @@
+//! Sphere mapping: as the board is turned, the strength of the calibrated
+//! field is binned by which way gravity points relative to the board,
+//! building up a map of the field over every orientation. Each cell keeps
+//! the count, running mean and variance (by Welford's method, so in fixed
+//! memory however many samples it sees), smallest and largest strength,
+//! which together show both the field's structure and how noisy it is in
+//! each orientation.
+//!
+//! Orientations are binned by the cells of [`crate::gravity`]'s grid. Only
+//! readings taken while the board is close to still count, since otherwise
+//! the accelerometer measures more than gravity.
+//!
+//! How much of the sphere has been covered is the share of cells with at
+//! least a set number of samples, so a map, or a calibration, can be judged
+//! by how completely it has seen every orientation. The sample that covers
+//! the last cell is announced, for rigs that turn the board until the map
+//! is done.
+//!
+//! Once mapped, cells whose average strength stands out from the rest are
+//! flagged as anomalies: a fixed source of interference near the board,
+//! such as a screw or a speaker magnet, bends the field in the orientations
+//! that bring it closest to the sensor, so their directions help find it.
+//! The rest is taken as the median of the covered cells' averages, and a
+//! cell stands out once it is more than three robust standard deviations
+//! (from the median absolute deviation) away, and at least 500 nT, above
+//! the sensor's noise.
+//!
+//! In the sphere mapping mode the matrix guides the board towards the
+//! nearest cell that still needs samples, turning blind waving into a
+//! scan: an arrow points at the edge of the board to tip down, a ring asks
+//! for the board to be held where it is, and a tick shows the map is done.
+//!
+//! Samples are added from boot; collecting can be stopped, keeping the map
+//! as it is, and started again, and the map reset to start over. The raw
+//! fields they were calibrated from also go to [`crate::refit`], to
+//! refit the calibration once the map is complete.
+//!
+//! The map can be saved to flash and loaded back, to carry a long mapping
+//! session over a battery swap. The chunk pages below the flash records
+//! hold [`MAP_SLOTS`] saved maps, each chunk opening with the map's cell
+//! count so a map saved with another grid isn't loaded onto this one, then
+//! as many cells as fit. A saved map can also be merged into the one being
+//! collected, each cell's statistics combined as if it had seen both sets
+//! of samples, so coverage builds up over several short sessions.
+//!
+//! The whole map can be exported for host tools as one binary frame per
+//! cell (see [`crate::frame`]), between a `Map: exporting, cells=<n>` line
+//! and a closing `Map: done`. Samples keep being added while it goes out,
+//! so a cell sent early may have moved on by the end. The difference
+//! between the two saved maps goes out the same way, between
+//! `Map: diffing, cells=<n>` and `Map: done`, to show how the surroundings
+//! changed between them, after moving the board or changing its enclosure.
+//!
+//! With live updates on, a cell's frame is also sent as it changes
+//! meaningfully, so a host can paint the sphere as the board turns: each
+//! time its sample count doubles, which shrinks the uncertainty of its
+//! average by a factor of √2, and when it becomes covered.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::calibration::Measurement;
+use crate::config::SPHERE_MAP_RAM_BUDGET;
+use crate::device::Acceleration;
+use crate::fixed;
+use crate::frame::Frame;
+use crate::gravity::{self, cell_direction, cell_index, CELLS, GRAVITY_MG};
+use crate::led::{View, HOLD, TICK};
+use crate::math::{roundf, sqrtf};
+use crate::refit::Refit;
+use crate::storage::{Storage, StorageError, CHUNK_PAGES, MAX_CHUNK_LEN};
+
+/// Samples a cell needs to count as covered, until set otherwise.
+const DEFAULT_COVERED_SAMPLES: u32 = 10;
+
+/// Most anomalies listed, the furthest from the rest first; more are only
+/// counted.
+pub const MAX_ANOMALIES: usize = 16;
+/// How far a cell's average has to be from the median, in robust standard
+/// deviations and at least in nT, to count as an anomaly.
+const ANOMALY_DEVIATIONS: u32 = 3;
+const MIN_ANOMALY_NT: u32 = 500;
+/// Covered cells needed before any can stand out from the rest.
+const MIN_ANOMALY_CELLS: usize = 3;
+
+/// Maps that can be saved at once, each in its own chunks.
+pub const MAP_SLOTS: u8 = 2;
+/// The saved maps a difference goes from and to.
+const DIFF_BEFORE: u8 = 0;
+const DIFF_AFTER: u8 = 1;
+
+/// Bump when the saved layout changes; maps saved with another version are
+/// ignored.
+const MAP_VERSION: u16 = 1;
+/// A chunk's cell count, and one cell's count, mean, sum of squares,
+/// smallest and largest, as saved.
+const CHUNK_HEADER_LEN: usize = 4;
+const SAVED_CELL_LEN: usize = 20;
+const CELLS_PER_CHUNK: usize = (MAX_CHUNK_LEN - CHUNK_HEADER_LEN) / SAVED_CELL_LEN;
+const MAP_CHUNKS: usize = CELLS.div_ceil(CELLS_PER_CHUNK);
+/// Chunks set aside for each saved map.
+const SLOT_CHUNKS: usize = CHUNK_PAGES / MAP_SLOTS as usize;
+const _: () = assert!(
+    MAP_CHUNKS <= SLOT_CHUNKS,
+    "the sphere map doesn't fit its flash pages"
+);
+
+/// The field strengths seen in one cell.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Cell {
+    pub count: u32,
+    /// The running mean, in nT, and sum of squared differences from it, in
+    /// nT².
+    mean: f32,
+    m2: f32,
+    min_nt: u32,
+    max_nt: u32,
+}
+
+impl Cell {
+    /// A cell with no samples yet.
+    pub const EMPTY: Cell = Cell {
+        count: 0,
+        mean: 0.,
+        m2: 0.,
+        min_nt: u32::MAX,
+        max_nt: 0,
+    };
+
+    /// Adds one strength, in nT.
+    pub fn add(&mut self, strength_nt: u32) {
+        if self.count == u32::MAX {
+            return;
+        }
+        self.count += 1;
+        let value = strength_nt as f32;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (value - self.mean);
+        self.min_nt = self.min_nt.min(strength_nt);
+        self.max_nt = self.max_nt.max(strength_nt);
+    }
+
+    /// Combines `other`'s samples into this cell's, weighting each cell's
+    /// mean and variance by its count.
+    fn merge(&mut self, other: &Cell) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = *other;
+            return;
+        }
+        let (count, other_count) = (self.count as f32, other.count as f32);
+        let total = count + other_count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other_count / total;
+        self.m2 += other.m2 + delta * delta * count * other_count / total;
+        self.count = self.count.saturating_add(other.count);
+        self.min_nt = self.min_nt.min(other.min_nt);
+        self.max_nt = self.max_nt.max(other.max_nt);
+    }
+
+    fn to_bytes(self) -> [u8; SAVED_CELL_LEN] {
+        let mut bytes = [0u8; SAVED_CELL_LEN];
+        bytes[0..4].copy_from_slice(&self.count.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.mean.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.m2.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.min_nt.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.max_nt.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; SAVED_CELL_LEN]) -> Cell {
+        let word = |at: usize| bytes[at..at + 4].try_into().unwrap();
+        Cell {
+            count: u32::from_le_bytes(word(0)),
+            mean: f32::from_le_bytes(word(4)),
+            m2: f32::from_le_bytes(word(8)),
+            min_nt: u32::from_le_bytes(word(12)),
+            max_nt: u32::from_le_bytes(word(16)),
+        }
+    }
+
+    /// The average strength, in nT, or `None` before any sample.
+    pub fn mean_nt(&self) -> Option<u32> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(roundf(self.mean) as u32)
+    }
+
+    /// The strengths' sample variance, in nT², or `None` before a second
+    /// sample.
+    pub fn variance(&self) -> Option<f32> {
+        if self.count < 2 {
+            return None;
+        }
+        Some(self.m2 / (self.count - 1) as f32)
+    }
+
+    /// The strengths' standard deviation, in nT, or `None` before a second
+    /// sample.
+    pub fn std_dev_nt(&self) -> Option<u32> {
+        self.variance()
+            .map(|variance| roundf(sqrtf(variance)) as u32)
+    }
+
+    /// The standard error of the average strength, in nT: how far it is
+    /// likely to be from the strength the cell would average over many
+    /// more samples, shrinking with the square root of the count. `None`
+    /// before a second sample, when there is no telling.
+    pub fn std_error_nt(&self) -> Option<f32> {
+        self.variance()
+            .map(|variance| sqrtf(variance / self.count as f32))
+    }
+
+    /// The smallest strength, in nT, or `None` before any sample.
+    pub fn min_nt(&self) -> Option<u32> {
+        (self.count > 0).then_some(self.min_nt)
+    }
+
+    /// The largest strength, in nT, or `None` before any sample.
+    pub fn max_nt(&self) -> Option<u32> {
+        (self.count > 0).then_some(self.max_nt)
+    }
+}
+
+pub struct SphereMap {
+    cells: [Cell; CELLS],
+    /// Samples a cell needs to count as covered.
+    covered_samples: u32,
+    /// What is being exported, if anything.
+    export: Option<Export>,
+    /// One bit per slot whose saved map has been saved from, loaded into or
+    /// merged into this one since it was last cleared, so merging it again
+    /// would count its samples twice.
+    holds_saved: u8,
+    /// Whether samples are being added.
+    collecting: bool,
+    /// Whether to send cells as they change.
+    live: bool,
+    /// Whether every cell was covered when last checked, so completion is
+    /// only announced as it happens.
+    complete: bool,
+    /// The raw field averaged by direction, for refitting the calibration.
+    refit: Refit,
+}
+
+/// An export going out, with the next cell to send.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Export {
+    Cells(usize),
+    Difference(usize),
+}
+
+/// RAM the sphere map takes, in bytes, reported in the status frame.
+pub const MAP_RAM_BYTES: usize = core::mem::size_of::<SphereMap>();
+const _: () = assert!(
+    MAP_RAM_BYTES <= SPHERE_MAP_RAM_BUDGET as usize,
+    "the sphere map needs more RAM than SPHERE_MAP_RAM_BUDGET: pick fewer SPHERE_MAP_CELLS"
+);
+
+impl Default for SphereMap {
+    fn default() -> SphereMap {
+        SphereMap::new()
+    }
+}
+
+impl SphereMap {
+    pub const fn new() -> SphereMap {
+        SphereMap {
+            cells: [Cell::EMPTY; CELLS],
+            covered_samples: DEFAULT_COVERED_SAMPLES,
+            export: None,
+            holds_saved: 0,
+            collecting: true,
+            live: false,
+            complete: false,
+            refit: Refit::new(),
+        }
+    }
+
+    /// Forgets every sample.
+    pub fn clear(&mut self) {
+        self.cells = [Cell::EMPTY; CELLS];
+        self.holds_saved = 0;
+        self.complete = false;
+        self.refit.clear();
+    }
+
+    /// Starts or stops adding samples, keeping the map as it is.
+    pub fn set_collecting(&mut self, collecting: bool) {
+        self.collecting = collecting;
+    }
+
+    /// Adds the strength of a calibrated field to the cell for `accel`, and
+    /// returns which cell that was, or `None` if collecting is stopped or
+    /// the board was moving too much to tell. The raw field it was
+    /// calibrated from goes to the refit, moving or not.
+    pub fn add(
+        &mut self,
+        accel: &Acceleration,
+        field: &Measurement,
+        raw: &Measurement,
+    ) -> Option<usize> {
+        if !self.collecting {
+            return None;
+        }
+        self.refit.add(raw, field);
+        let index = cell_index(accel)?;
+        self.cells[index].add(fixed::magnitude(field));
+        Some(index)
+    }
+
+    pub fn cells(&self) -> &[Cell; CELLS] {
+        &self.cells
+    }
+
+    /// The refit of the calibration from the samples added since the map
+    /// was last cleared. Loading or merging a saved map doesn't add to it.
+    pub fn refit(&mut self) -> &mut Refit {
+        &mut self.refit
+    }
+
+    /// Turns sending cells as they change on or off.
+    pub fn set_live(&mut self, live: bool) {
+        self.live = live;
+    }
+
+    /// The frame for cell `index`, which has just had a sample added, if
+    /// live updates are on and it has changed enough to be worth sending.
+    pub fn live_update(&self, index: usize) -> Option<Frame> {
+        let cell = self.cells.get(index).filter(|_| self.live)?;
+        if !cell.count.is_power_of_two() && cell.count != self.covered_samples {
+            return None;
+        }
+        Some(Frame::sphere_cell(
+            index as u16,
+            CELLS as u16,
+            cell_direction(index),
+            cell,
+        ))
+    }
+
+    /// Sets how many samples a cell needs to count as covered, at least 1.
+    pub fn set_covered_samples(&mut self, samples: u32) {
+        self.covered_samples = samples.max(1);
+        self.complete = self.is_complete();
+    }
+
+    /// The coverage, if the sample just added to cell `index` covered the
+    /// last cell that still needed samples.
+    pub fn completed_by(&mut self, index: usize) -> Option<Coverage> {
+        let cell = self.cells.get(index)?;
+        if self.complete || cell.count != self.covered_samples {
+            return None;
+        }
+        self.complete = self.is_complete();
+        self.complete.then(|| self.coverage())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.cells
+            .iter()
+            .all(|cell| cell.count >= self.covered_samples)
+    }
+
+    /// Writes every cell to flash in `slot`, over any map saved there
+    /// before.
+    pub fn save<F: NorFlash + ReadNorFlash>(
+        &mut self,
+        storage: &mut Storage<F>,
+        slot: u8,
+    ) -> Result<(), StorageError> {
+        for (index, cells) in self.cells.chunks(CELLS_PER_CHUNK).enumerate() {
+            let len = CHUNK_HEADER_LEN + cells.len() * SAVED_CELL_LEN;
+            let mut chunk = storage.begin_chunk(chunk_index(slot, index), MAP_VERSION, len)?;
+            chunk.write(&(CELLS as u32).to_le_bytes())?;
+            for cell in cells {
+                chunk.write(&cell.to_bytes())?;
+            }
+            chunk.finish()?;
+        }
+        self.holds_saved |= 1 << slot;
+        Ok(())
+    }
+
+    /// Replaces every cell with the map saved in `slot`, and forgets the
+    /// raw fields gathered for a refit, which belonged to the cells
+    /// replaced. Returns false, leaving the map as it was, if there isn't
+    /// one for this grid or any of it is corrupt, or, leaving it empty, if
+    /// the flash fails while it is being read.
+    pub fn load<F: NorFlash + ReadNorFlash>(&mut self, storage: &mut Storage<F>, slot: u8) -> bool {
+        if !is_saved(storage, slot) {
+            return false;
+        }
+        // Another slot's samples may no longer be part of the map.
+        self.holds_saved = 0;
+        self.refit.clear();
+        self.read_saved(storage, slot, |cell, saved| *cell = saved)
+    }
+
+    /// Merges the map saved in `slot` into this one, cell by cell. Returns
+    /// false, leaving the map as it was, if there isn't one for this grid
+    /// or any of it is corrupt, or, leaving it empty, if the flash fails
+    /// while it is being read. Check [`SphereMap::holds_saved`] first.
+    pub fn merge<F: NorFlash + ReadNorFlash>(
+        &mut self,
+        storage: &mut Storage<F>,
+        slot: u8,
+    ) -> bool {
+        is_saved(storage, slot) && self.read_saved(storage, slot, |cell, saved| cell.merge(&saved))
+    }
+
+    /// Whether the map saved in `slot` is already part of this one, having
+    /// been saved from it, loaded or merged in.
+    pub fn holds_saved(&self, slot: u8) -> bool {
+        self.holds_saved & 1 << slot != 0
+    }
+
+    /// Hands each cell of the checked map in `slot` to `apply` with the
+    /// cell it belongs to.
+    fn read_saved<F: NorFlash + ReadNorFlash>(
+        &mut self,
+        storage: &mut Storage<F>,
+        slot: u8,
+        mut apply: impl FnMut(&mut Cell, Cell),
+    ) -> bool {
+        // The CRCs have been checked, so reading straight into the map
+        // only fails if the flash itself does, which leaves it empty.
+        for (index, cell) in self.cells.iter_mut().enumerate() {
+            let Some(saved) = read_saved_cell(storage, slot, index) else {
+                self.clear();
+                return false;
+            };
+            apply(cell, saved);
+        }
+        self.holds_saved |= 1 << slot;
+        self.complete = self.is_complete();
+        true
+    }
+
+    /// Starts exporting the map from its first cell, starting over if an
+    /// export is already going out.
+    pub fn start_export(&mut self) {
+        self.export = Some(Export::Cells(0));
+    }
+
+    /// Starts exporting the difference from the map saved in slot 0 to the
+    /// one in slot 1, starting over if an export is already going out.
+    /// Returns false, exporting nothing, unless both are readable maps for
+    /// this grid.
+    pub fn start_difference<F: NorFlash + ReadNorFlash>(
+        &mut self,
+        storage: &mut Storage<F>,
+    ) -> bool {
+        if !is_saved(storage, DIFF_BEFORE) || !is_saved(storage, DIFF_AFTER) {
+            return false;
+        }
+        self.export = Some(Export::Difference(0));
+        true
+    }
+
+    pub fn is_exporting(&self) -> bool {
+        self.export.is_some()
+    }
+
+    /// The frame for the next cell being exported, or `None` once every
+    /// cell has gone, or nothing is being exported.
+    pub fn export_next<F: NorFlash + ReadNorFlash>(
+        &mut self,
+        storage: &mut Storage<F>,
+    ) -> Option<Frame> {
+        let (Export::Cells(index) | Export::Difference(index)) = self.export?;
+        if index >= CELLS {
+            self.export = None;
+            return None;
+        }
+        let frame = match self.export? {
+            Export::Cells(_) => {
+                self.export = Some(Export::Cells(index + 1));
+                Frame::sphere_cell(
+                    index as u16,
+                    CELLS as u16,
+                    cell_direction(index),
+                    &self.cells[index],
+                )
+            }
+            Export::Difference(_) => {
+                self.export = Some(Export::Difference(index + 1));
+                let before = read_saved_cell(storage, DIFF_BEFORE, index).unwrap_or(Cell::EMPTY);
+                let after = read_saved_cell(storage, DIFF_AFTER, index).unwrap_or(Cell::EMPTY);
+                Frame::sphere_difference(
+                    index as u16,
+                    CELLS as u16,
+                    cell_direction(index),
+                    &before,
+                    &after,
+                )
+            }
+        };
+        Some(frame)
+    }
+
+    /// The covered cells whose average strength stands out from the rest.
+    pub fn anomalies(&self) -> Anomalies {
+        let mut anomalies = Anomalies {
+            found: 0,
+            covered: 0,
+            median_nt: 0,
+            threshold_nt: MIN_ANOMALY_NT,
+            worst: [Anomaly::NONE; MAX_ANOMALIES],
+            listed: 0,
+        };
+        let mut means = [0u32; CELLS];
+        let mut covered = 0;
+        for cell in self
+            .cells
+            .iter()
+            .filter(|cell| cell.count >= self.covered_samples)
+        {
+            if let Some(mean) = cell.mean_nt() {
+                means[covered] = mean;
+                covered += 1;
+            }
+        }
+        anomalies.covered = covered as u16;
+        if covered < MIN_ANOMALY_CELLS {
+            return anomalies;
+        }
+        let means = &mut means[..covered];
+        let median_nt = median(means);
+        for mean in means.iter_mut() {
+            *mean = mean.abs_diff(median_nt);
+        }
+        // 1.4826 times the median absolute deviation estimates the
+        // standard deviation of normally distributed values, while
+        // ignoring the outliers being looked for.
+        let spread = (median(means) as u64 * 14_826 / 10_000) as u32;
+        anomalies.median_nt = median_nt;
+        anomalies.threshold_nt = (ANOMALY_DEVIATIONS * spread).max(MIN_ANOMALY_NT);
+        for (index, cell) in self.cells.iter().enumerate() {
+            let Some(mean) = cell
+                .mean_nt()
+                .filter(|_| cell.count >= self.covered_samples)
+            else {
+                continue;
+            };
+            if mean.abs_diff(median_nt) > anomalies.threshold_nt {
+                anomalies.add(Anomaly {
+                    index: index as u16,
+                    direction: cell_direction(index),
+                    mean_nt: mean,
+                    deviation_nt: mean as i32 - median_nt as i32,
+                });
+            }
+        }
+        anomalies
+    }
+
+    /// How many cells have been covered.
+    pub fn coverage(&self) -> Coverage {
+        Coverage {
+            covered: self
+                .cells
+                .iter()
+                .filter(|cell| cell.count >= self.covered_samples)
+                .count() as u16,
+            cells: CELLS as u16,
+            covered_samples: self.covered_samples,
+        }
+    }
+
+    /// Which way to turn the board from `accel` to reach the nearest cell
+    /// with fewer samples than it needs to count as covered. Unlike adding
+    /// a sample, this works while the board is moving, going by which way
+    /// the acceleration points.
+    pub fn guide(&self, accel: &Acceleration) -> Guide {
+        let Some(down) = gravity::direction(accel) else {
+            return Guide::Hold;
+        };
+        let closeness = |index: usize| gravity::dot(cell_direction(index), down);
+        let Some(target) = (0..CELLS)
+            .filter(|&index| self.cells[index].count < self.covered_samples)
+            .max_by(|&a, &b| closeness(a).total_cmp(&closeness(b)))
+        else {
+            return Guide::Done;
+        };
+        if target == gravity::nearest_cell(down) {
+            return Guide::Hold;
+        }
+        Guide::Tilt(gravity::tilt_towards(down, cell_direction(target)))
+    }
+}
+
+/// The covered cells whose average strength stands out from the rest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Anomalies {
+    pub found: u16,
+    /// Cells covered, out of which they were found.
+    pub covered: u16,
+    /// The covered cells' median average strength, in nT.
+    pub median_nt: u32,
+    /// How far from the median a cell's average has to be, in nT.
+    pub threshold_nt: u32,
+    /// The ones furthest from the median, furthest first.
+    worst: [Anomaly; MAX_ANOMALIES],
+    listed: usize,
+}
+
+impl Anomalies {
+    /// The anomalies furthest from the median, at most
+    /// [`MAX_ANOMALIES`], furthest first.
+    pub fn worst(&self) -> &[Anomaly] {
+        &self.worst[..self.listed]
+    }
+
+    fn add(&mut self, anomaly: Anomaly) {
+        self.found += 1;
+        let distance = anomaly.deviation_nt.unsigned_abs();
+        let at = self.worst[..self.listed]
+            .iter()
+            .position(|worse| worse.deviation_nt.unsigned_abs() < distance)
+            .unwrap_or(self.listed);
+        if at == MAX_ANOMALIES {
+            return;
+        }
+        self.listed = (self.listed + 1).min(MAX_ANOMALIES);
+        self.worst.copy_within(at..self.listed - 1, at + 1);
+        self.worst[at] = anomaly;
+    }
+}
+
+/// `Map: anomalies, found=<n>, listed=<n>, covered=<n>, median=<nT>,
+/// threshold=<nT>`.
+impl core::fmt::Display for Anomalies {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Map: anomalies, found={}, listed={}, covered={}, median={}, threshold={}",
+            self.found, self.listed, self.covered, self.median_nt, self.threshold_nt
+        )
+    }
+}
+
+/// A cell whose average strength stands out from the rest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Anomaly {
+    pub index: u16,
+    /// The unit vector through the middle of the cell, in the board's axes.
+    pub direction: [f32; 3],
+    pub mean_nt: u32,
+    /// How far the average is above the median, or below it if negative,
+    /// in nT.
+    pub deviation_nt: i32,
+}
+
+impl Anomaly {
+    const NONE: Anomaly = Anomaly {
+        index: 0,
+        direction: [0.; 3],
+        mean_nt: 0,
+        deviation_nt: 0,
+    };
+}
+
+/// `Anomaly: cell=<n>, x=<mg>, y=<mg>, z=<mg>, mean=<nT>, deviation=<nT>`,
+/// with the direction as the acceleration the board reads, in mg, when
+/// held still in that orientation.
+impl core::fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [x, y, z] = self.direction.map(|axis| roundf(axis * GRAVITY_MG) as i32);
+        write!(
+            f,
+            "Anomaly: cell={}, x={}, y={}, z={}, mean={}, deviation={}",
+            self.index, x, y, z, self.mean_nt, self.deviation_nt
+        )
+    }
+}
+
+/// How much of the sphere has been covered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coverage {
+    pub covered: u16,
+    pub cells: u16,
+    /// Samples a cell needs to count as covered.
+    pub covered_samples: u32,
+}
+
+impl Coverage {
+    /// The share of cells covered, in tenths of a percent.
+    pub fn permille(&self) -> u16 {
+        (self.covered as u32 * 1000 / self.cells.max(1) as u32) as u16
+    }
+}
+
+/// `Map: coverage, covered=<n>, cells=<n>, percent=<%>, samples=<n>`, with
+/// the share to a tenth of a percent and the samples a cell needs.
+impl core::fmt::Display for Coverage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let permille = self.permille();
+        write!(
+            f,
+            "Map: coverage, covered={}, cells={}, percent={}.{}, samples={}",
+            self.covered,
+            self.cells,
+            permille / 10,
+            permille % 10,
+            self.covered_samples
+        )
+    }
+}
+
+/// Which way to turn the board next while mapping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Guide {
+    /// Tip the board down at the edge an arrow at this angle points to, in
+    /// [`crate::led::render_arrow`]'s convention.
+    Tilt(f32),
+    /// Hold still: the board is in a cell that still needs samples.
+    Hold,
+    /// Every cell has been covered.
+    Done,
+}
+
+impl Guide {
+    pub fn view(self) -> View {
+        match self {
+            Guide::Tilt(theta) => View::Arrow(theta),
+            Guide::Hold => View::Glyph(HOLD),
+            Guide::Done => View::Glyph(TICK),
+        }
+    }
+}
+
+/// Where chunk `index` of the map saved in `slot` goes.
+fn chunk_index(slot: u8, index: usize) -> usize {
+    slot as usize * SLOT_CHUNKS + index
+}
+
+/// Whether `slot` holds a readable map for this grid.
+fn is_saved<F: NorFlash + ReadNorFlash>(storage: &mut Storage<F>, slot: u8) -> bool {
+    if slot >= MAP_SLOTS {
+        return false;
+    }
+    (0..MAP_CHUNKS).all(|index| {
+        let cells = (CELLS - index * CELLS_PER_CHUNK).min(CELLS_PER_CHUNK);
+        let len = CHUNK_HEADER_LEN + cells * SAVED_CELL_LEN;
+        let chunk = chunk_index(slot, index);
+        let mut header = [0u8; CHUNK_HEADER_LEN];
+        storage.chunk_len(chunk, MAP_VERSION) == Some(len)
+            && storage.read_chunk(chunk, 0, &mut header).is_ok()
+            && u32::from_le_bytes(header) == CELLS as u32
+    })
+}
+
+/// Cell `index` of the map saved in `slot`, which [`is_saved`] has checked.
+fn read_saved_cell<F: NorFlash + ReadNorFlash>(
+    storage: &mut Storage<F>,
+    slot: u8,
+    index: usize,
+) -> Option<Cell> {
+    let offset = CHUNK_HEADER_LEN + index % CELLS_PER_CHUNK * SAVED_CELL_LEN;
+    let mut bytes = [0u8; SAVED_CELL_LEN];
+    storage
+        .read_chunk(
+            chunk_index(slot, index / CELLS_PER_CHUNK),
+            offset,
+            &mut bytes,
+        )
+        .ok()?;
+    Some(Cell::from_bytes(&bytes))
+}
+
+/// The middle value, or the lower of the middle two, reordering `values`.
+fn median(values: &mut [u32]) -> u32 {
+    values.sort_unstable();
+    values[(values.len() - 1) / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{corrupt_chunk, RamFlash, RECORDS_START};
+    use std::vec::Vec;
+
+    /// Strengths around 48 µT, spread by a few hundred nT, from a fixed
+    /// pseudo-random sequence.
+    fn strengths(seed: u32, count: usize) -> Vec<u32> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                48_000 + (state >> 22)
+            })
+            .collect()
+    }
+
+    fn cell_of(strengths: &[u32]) -> Cell {
+        let mut cell = Cell::EMPTY;
+        for strength in strengths {
+            cell.add(*strength);
+        }
+        cell
+    }
+
+    /// A map with a different handful of samples in every cell.
+    fn filled_map(seed: u32) -> SphereMap {
+        let mut map = SphereMap::new();
+        for (index, cell) in map.cells.iter_mut().enumerate() {
+            *cell = cell_of(&strengths(seed + index as u32, index % 5));
+        }
+        map
+    }
+
+    #[test]
+    fn merged_cells_match_one_cell_fed_everything() {
+        let (first, second) = (strengths(1, 37), strengths(2, 21));
+        let mut merged = cell_of(&first);
+        merged.merge(&cell_of(&second));
+        let whole = cell_of(&[first, second].concat());
+
+        assert_eq!(merged.count, whole.count);
+        assert_eq!(merged.min_nt(), whole.min_nt());
+        assert_eq!(merged.max_nt(), whole.max_nt());
+        assert!(
+            (merged.mean - whole.mean).abs() < 0.05,
+            "{merged:?} {whole:?}"
+        );
+        let (merged, whole) = (merged.variance().unwrap(), whole.variance().unwrap());
+        assert!((merged - whole).abs() < whole * 1e-4, "{merged} {whole}");
+    }
+
+    #[test]
+    fn merging_with_empty_cell_keeps_the_other() {
+        let cell = cell_of(&strengths(3, 10));
+        let mut empty = Cell::EMPTY;
+        empty.merge(&cell);
+        assert_eq!(empty, cell);
+        let mut merged = cell;
+        merged.merge(&Cell::EMPTY);
+        assert_eq!(merged, cell);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::new(&mut flash, RECORDS_START);
+        let mut saved = filled_map(10);
+        saved.save(&mut storage, 1).unwrap();
+        assert!(saved.holds_saved(1));
+
+        let mut loaded = SphereMap::new();
+        assert!(!loaded.load(&mut storage, 0));
+        assert!(loaded.load(&mut storage, 1));
+        assert_eq!(loaded.cells, saved.cells);
+        assert!(loaded.holds_saved(1) && !loaded.holds_saved(0));
+    }
+
+    #[test]
+    fn merge_from_flash_combines_each_cell() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::new(&mut flash, RECORDS_START);
+        let saved = filled_map(20);
+        filled_map(20).save(&mut storage, 0).unwrap();
+
+        let mut map = filled_map(30);
+        let mut expected = map.cells;
+        for (cell, saved) in expected.iter_mut().zip(&saved.cells) {
+            cell.merge(saved);
+        }
+        assert!(map.merge(&mut storage, 0));
+        assert_eq!(map.cells, expected);
+        assert!(map.holds_saved(0));
+    }
+
+    #[test]
+    fn corrupt_map_is_not_loaded() {
+        let mut flash = RamFlash::new();
+        filled_map(40)
+            .save(&mut Storage::new(&mut flash, RECORDS_START), 0)
+            .unwrap();
+        corrupt_chunk(&mut flash, chunk_index(0, MAP_CHUNKS - 1), CHUNK_HEADER_LEN);
+
+        let mut map = filled_map(50);
+        let before = map.cells;
+        let mut storage = Storage::new(&mut flash, RECORDS_START);
+        assert!(!map.load(&mut storage, 0));
+        assert!(!map.merge(&mut storage, 0));
+        assert_eq!(map.cells, before);
+    }
+}
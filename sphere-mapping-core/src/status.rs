@@ -0,0 +1,65 @@
+//! The periodic status frame, so the host can watch the firmware's health
+//! without a debugger: how busy the CPU was over the last window, how many
+//! samples it handled, and what mode it's in, along with the boot count,
+//! total uptime and why it last reset, for triaging units in the field, and
+//! how much of the sphere map has been covered and the RAM it takes.
+
+use crate::mode::AppMode;
+use crate::reset::ResetReason;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    /// Seconds since boot.
+    pub uptime_s: u32,
+    /// Share of the window the CPU spent awake, in tenths of a percent.
+    pub load_permille: u16,
+    /// Full samples handled over the window.
+    pub samples: u32,
+    /// Largest gap between the magnetometer and accelerometer halves of a
+    /// sample over the window, in microseconds.
+    pub max_skew_us: u32,
+    /// Bytes of output dropped over the window because the link couldn't
+    /// keep up.
+    pub dropped: u32,
+    pub mode: AppMode,
+    pub calibrated: bool,
+    pub boots: u32,
+    /// Seconds run over all boots, this one included.
+    pub total_uptime_s: u32,
+    pub reset: ResetReason,
+    /// Share of the sphere map's cells covered, in tenths of a percent.
+    pub coverage_permille: u16,
+    /// RAM the sphere map takes, in bytes, which grows with its cells.
+    pub map_bytes: u32,
+}
+
+/// `Status: uptime=<s>, load=<%>, idle=<%>, samples=<n>, skew=<us>,
+/// mode=<name>, calibrated=<yes|no>, boots=<n>, total=<s>, reset=<reason>,
+/// dropped=<bytes>, coverage=<%>, map=<bytes>`,
+/// with load, idle and coverage to a tenth of a percent.
+impl core::fmt::Display for Status {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let idle_permille = 1000 - self.load_permille.min(1000);
+        write!(
+            f,
+            "Status: uptime={}, load={}.{}, idle={}.{}, samples={}, skew={}, mode={}, calibrated={}, \
+             boots={}, total={}, reset={}, dropped={}, coverage={}.{}, map={}",
+            self.uptime_s,
+            self.load_permille / 10,
+            self.load_permille % 10,
+            idle_permille / 10,
+            idle_permille % 10,
+            self.samples,
+            self.max_skew_us,
+            self.mode.name(),
+            if self.calibrated { "yes" } else { "no" },
+            self.boots,
+            self.total_uptime_s,
+            self.reset,
+            self.dropped,
+            self.coverage_permille / 10,
+            self.coverage_permille % 10,
+            self.map_bytes
+        )
+    }
+}
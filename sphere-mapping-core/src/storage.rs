@@ -0,0 +1,535 @@
+//! Small versioned records kept in dedicated flash pages at the top of
+//! flash, outside the region the firmware images link into (see their
+//! `memory.x`), so they survive reflashing. Where that is depends on the
+//! chip: [`storage_start`] works it out from the size of its flash.
+//!
+//! Below the records, a few more pages hold data too large for a record,
+//! such as the sphere map, one chunk per page. A chunk is laid out like a
+//! record, but written a piece at a time, so it needn't be assembled in
+//! RAM first, and its header goes last, so a chunk cut short reads as
+//! empty.
+//!
+//! Erasing and writing are refused while the supply is too low to see them
+//! through, as checked by the firmware's own supply check, so a dying
+//! battery can't leave a record half erased.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+/// Address and size of the flash region reserved for records, on the
+/// micro:bit v2's nRF52833, with 512K of flash.
+pub const STORAGE_START: u32 = storage_start(0x8_0000);
+pub const STORAGE_LEN: usize = STORAGE_PAGES * PAGE_SIZE;
+
+/// Address and size of the flash region reserved for chunks, just below
+/// the records, on the nRF52833.
+pub const CHUNK_STORAGE_START: u32 = chunk_storage_start(0x8_0000);
+pub const CHUNK_STORAGE_LEN: usize = CHUNK_PAGES * PAGE_SIZE;
+pub const CHUNK_PAGES: usize = 8;
+
+const PAGE_SIZE: usize = 4096;
+const STORAGE_PAGES: usize = 4;
+
+/// Where the records start on a chip with `flash_len` bytes of flash: the
+/// last `STORAGE_LEN` of them.
+pub const fn storage_start(flash_len: u32) -> u32 {
+    flash_len - STORAGE_LEN as u32
+}
+
+/// Where the chunks start on a chip with `flash_len` bytes of flash.
+pub const fn chunk_storage_start(flash_len: u32) -> u32 {
+    storage_start(flash_len) - CHUNK_STORAGE_LEN as u32
+}
+
+const RECORD_MAGIC: u32 = 0x5350_4D52; // "SPMR"
+const HEADER_LEN: usize = 8;
+const CRC_LEN: usize = 4;
+const MAX_RECORD_LEN: usize = 128;
+pub const MAX_PAYLOAD_LEN: usize = MAX_RECORD_LEN - HEADER_LEN - CRC_LEN;
+
+const CHUNK_MAGIC: u32 = 0x5350_4D43; // "SPMC"
+/// The most a chunk holds.
+pub const MAX_CHUNK_LEN: usize = PAGE_SIZE - HEADER_LEN - CRC_LEN;
+/// Bytes read at a time while checking a chunk's CRC.
+const CHUNK_READ_LEN: usize = 64;
+
+/// Each slot owns one flash page and holds a single record.
+#[derive(Debug, Clone, Copy)]
+pub enum Slot {
+    Settings = 0,
+    Calibration = 1,
+    Panic = 2,
+    Boot = 3,
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    TooLarge,
+    Flash,
+    /// The supply was too low to write safely; nothing was changed.
+    LowSupply,
+}
+
+pub struct Storage<F> {
+    flash: F,
+    start: u32,
+    supply_ok: fn() -> bool,
+}
+
+impl<F: NorFlash + ReadNorFlash> Storage<F> {
+    /// Keeps records in `flash`, where `start` is the offset of
+    /// `STORAGE_START` within it: 0 for a driver that only covers the
+    /// records, `CHUNK_STORAGE_LEN` for one that covers the chunks and the
+    /// records, `STORAGE_START` for one that covers all of flash. Chunks
+    /// can't be kept with the first.
+    pub fn new(flash: F, start: u32) -> Storage<F> {
+        Storage {
+            flash,
+            start,
+            supply_ok: || true,
+        }
+    }
+
+    /// Checks `supply_ok` before every erase and write, and skips them when
+    /// it says the supply is marginal.
+    pub fn with_supply_check(self, supply_ok: fn() -> bool) -> Storage<F> {
+        Storage { supply_ok, ..self }
+    }
+
+    /// Reads the record in `slot` into `payload` and returns its length, or
+    /// `None` if the slot is empty, corrupt, or was written with another
+    /// `version`.
+    pub fn load(&mut self, slot: Slot, version: u16, payload: &mut [u8]) -> Option<usize> {
+        let mut record = [0u8; MAX_RECORD_LEN];
+        self.flash.read(self.slot_offset(slot), &mut record).ok()?;
+
+        let magic = u32::from_le_bytes(record[0..4].try_into().unwrap());
+        let stored_version = u16::from_le_bytes(record[4..6].try_into().unwrap());
+        let len = u16::from_le_bytes(record[6..8].try_into().unwrap()) as usize;
+        if magic != RECORD_MAGIC
+            || stored_version != version
+            || len > MAX_PAYLOAD_LEN
+            || len > payload.len()
+        {
+            return None;
+        }
+
+        let body = &record[..HEADER_LEN + len];
+        let crc = u32::from_le_bytes(record[body.len()..][..CRC_LEN].try_into().unwrap());
+        if crc != crc32(body) {
+            return None;
+        }
+
+        payload[..len].copy_from_slice(&body[HEADER_LEN..]);
+        Some(len)
+    }
+
+    /// Erases `slot` and writes `payload` as its new record.
+    pub fn store(&mut self, slot: Slot, version: u16, payload: &[u8]) -> Result<(), StorageError> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(StorageError::TooLarge);
+        }
+
+        let mut record = [0xffu8; MAX_RECORD_LEN];
+        let body_len = HEADER_LEN + payload.len();
+        record[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        record[4..6].copy_from_slice(&version.to_le_bytes());
+        record[6..8].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        record[HEADER_LEN..body_len].copy_from_slice(payload);
+        let crc = crc32(&record[..body_len]);
+        record[body_len..][..CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+
+        // Flash writes must be whole words.
+        let write_len = (body_len + CRC_LEN).next_multiple_of(4);
+        let offset = self.slot_offset(slot);
+        self.check_supply()?;
+        self.flash
+            .erase(offset, offset + PAGE_SIZE as u32)
+            .map_err(|_| StorageError::Flash)?;
+        // Leaves the slot empty rather than half written if the supply has
+        // dropped since.
+        self.check_supply()?;
+        self.flash
+            .write(offset, &record[..write_len])
+            .map_err(|_| StorageError::Flash)
+    }
+
+    /// Erases `slot`, leaving it empty.
+    pub fn erase(&mut self, slot: Slot) -> Result<(), StorageError> {
+        let offset = self.slot_offset(slot);
+        self.check_supply()?;
+        self.flash
+            .erase(offset, offset + PAGE_SIZE as u32)
+            .map_err(|_| StorageError::Flash)
+    }
+
+    /// Erases chunk `index` and starts writing `len` bytes into it, which
+    /// [`ChunkWriter::finish`] completes once they have all been written.
+    pub fn begin_chunk(
+        &mut self,
+        index: usize,
+        version: u16,
+        len: usize,
+    ) -> Result<ChunkWriter<'_, F>, StorageError> {
+        if len > MAX_CHUNK_LEN || !len.is_multiple_of(4) {
+            return Err(StorageError::TooLarge);
+        }
+        let offset = self.chunk_offset(index).ok_or(StorageError::Flash)?;
+        self.check_supply()?;
+        self.flash
+            .erase(offset, offset + PAGE_SIZE as u32)
+            .map_err(|_| StorageError::Flash)?;
+        let mut header = [0u8; HEADER_LEN];
+        header[0..4].copy_from_slice(&CHUNK_MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&version.to_le_bytes());
+        header[6..8].copy_from_slice(&(len as u16).to_le_bytes());
+        Ok(ChunkWriter {
+            crc: crc32_update(CRC32_INIT, &header),
+            storage: self,
+            offset,
+            header,
+            len,
+            written: 0,
+        })
+    }
+
+    /// The length of chunk `index`, or `None` if it is empty, corrupt, or
+    /// was written with another `version`.
+    pub fn chunk_len(&mut self, index: usize, version: u16) -> Option<usize> {
+        let offset = self.chunk_offset(index)?;
+        let mut header = [0u8; HEADER_LEN];
+        self.flash.read(offset, &mut header).ok()?;
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let stored_version = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        let len = u16::from_le_bytes(header[6..8].try_into().unwrap()) as usize;
+        if magic != CHUNK_MAGIC || stored_version != version || len > MAX_CHUNK_LEN {
+            return None;
+        }
+
+        let mut crc = crc32_update(CRC32_INIT, &header);
+        let mut bytes = [0u8; CHUNK_READ_LEN];
+        let mut read = 0;
+        while read < len {
+            let piece = &mut bytes[..(len - read).min(CHUNK_READ_LEN)];
+            self.read_chunk(index, read, piece).ok()?;
+            crc = crc32_update(crc, piece);
+            read += piece.len();
+        }
+        let mut stored_crc = [0u8; CRC_LEN];
+        self.flash
+            .read(offset + (HEADER_LEN + len) as u32, &mut stored_crc)
+            .ok()?;
+        (u32::from_le_bytes(stored_crc) == !crc).then_some(len)
+    }
+
+    /// Reads `bytes` from chunk `index`, starting `offset` bytes in. Check
+    /// the chunk with [`Storage::chunk_len`] first.
+    pub fn read_chunk(
+        &mut self,
+        index: usize,
+        offset: usize,
+        bytes: &mut [u8],
+    ) -> Result<(), StorageError> {
+        let start = self.chunk_offset(index).ok_or(StorageError::Flash)?;
+        self.flash
+            .read(start + (HEADER_LEN + offset) as u32, bytes)
+            .map_err(|_| StorageError::Flash)
+    }
+
+    fn check_supply(&self) -> Result<(), StorageError> {
+        if (self.supply_ok)() {
+            Ok(())
+        } else {
+            Err(StorageError::LowSupply)
+        }
+    }
+
+    fn slot_offset(&self, slot: Slot) -> u32 {
+        self.start + (slot as usize * PAGE_SIZE) as u32
+    }
+
+    fn chunk_offset(&self, index: usize) -> Option<u32> {
+        if index >= CHUNK_PAGES {
+            return None;
+        }
+        let start = self.start.checked_sub(CHUNK_STORAGE_LEN as u32)?;
+        Some(start + (index * PAGE_SIZE) as u32)
+    }
+}
+
+/// A chunk being written, from [`Storage::begin_chunk`].
+pub struct ChunkWriter<'a, F> {
+    storage: &'a mut Storage<F>,
+    offset: u32,
+    header: [u8; HEADER_LEN],
+    len: usize,
+    written: usize,
+    /// The CRC of the header and everything written so far.
+    crc: u32,
+}
+
+impl<F: NorFlash + ReadNorFlash> ChunkWriter<'_, F> {
+    /// Writes the next part of the chunk, a whole number of words long.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), StorageError> {
+        if self.written + bytes.len() > self.len || !bytes.len().is_multiple_of(4) {
+            return Err(StorageError::TooLarge);
+        }
+        self.storage.check_supply()?;
+        self.storage
+            .flash
+            .write(self.offset + (HEADER_LEN + self.written) as u32, bytes)
+            .map_err(|_| StorageError::Flash)?;
+        self.crc = crc32_update(self.crc, bytes);
+        self.written += bytes.len();
+        Ok(())
+    }
+
+    /// Writes the CRC, then the header that makes the chunk readable.
+    /// Fails, leaving the chunk unreadable, unless all of it was written.
+    pub fn finish(self) -> Result<(), StorageError> {
+        if self.written != self.len {
+            return Err(StorageError::TooLarge);
+        }
+        self.storage.check_supply()?;
+        let crc_offset = self.offset + (HEADER_LEN + self.len) as u32;
+        self.storage
+            .flash
+            .write(crc_offset, &(!self.crc).to_le_bytes())
+            .and_then(|_| self.storage.flash.write(self.offset, &self.header))
+            .map_err(|_| StorageError::Flash)
+    }
+}
+
+/// CRC-32 (IEEE 802.3), computed bitwise to avoid a lookup table in flash.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(CRC32_INIT, data)
+}
+
+const CRC32_INIT: u32 = 0xffff_ffff;
+
+/// Carries the CRC-32 on over `data` from `crc`, its value before the
+/// final inversion.
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use embedded_storage::nor_flash::{
+        check_erase, check_read, check_write, ErrorType, NorFlashErrorKind,
+    };
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Where the records start in a [`RamFlash`].
+    pub(crate) const RECORDS_START: u32 = CHUNK_STORAGE_LEN as u32;
+
+    /// The chunks and the records after them, in RAM, where writing can
+    /// only clear bits, as on the real thing.
+    pub(crate) struct RamFlash {
+        pub(crate) bytes: Vec<u8>,
+    }
+
+    impl RamFlash {
+        pub(crate) fn new() -> RamFlash {
+            RamFlash {
+                bytes: vec![0xff; CHUNK_STORAGE_LEN + STORAGE_LEN],
+            }
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            check_read(self, offset, bytes.len())?;
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.bytes.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = PAGE_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            check_erase(self, from, to)?;
+            self.bytes[from as usize..to as usize].fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            check_write(self, offset, bytes.len())?;
+            let offset = offset as usize;
+            for (old, new) in self.bytes[offset..offset + bytes.len()]
+                .iter_mut()
+                .zip(bytes)
+            {
+                *old &= new;
+            }
+            Ok(())
+        }
+    }
+
+    /// Flips a bit of chunk `index`'s contents, `at` bytes in.
+    pub(crate) fn corrupt_chunk(flash: &mut RamFlash, index: usize, at: usize) {
+        flash.bytes[index * PAGE_SIZE + HEADER_LEN + at] ^= 0x80;
+    }
+
+    const PAYLOAD: [u8; 5] = [1, 2, 3, 4, 5];
+
+    fn record_offset(slot: Slot) -> usize {
+        RECORDS_START as usize + slot as usize * PAGE_SIZE
+    }
+
+    /// Writes chunk 0 with `bytes`, finishing it if `finish`.
+    fn write_chunk(flash: &mut RamFlash, bytes: &[u8], finish: bool) {
+        let mut storage = Storage::new(flash, RECORDS_START);
+        let mut writer = storage.begin_chunk(0, 1, bytes.len()).unwrap();
+        writer.write(bytes).unwrap();
+        if finish {
+            writer.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn regions_sit_at_the_top_of_flash() {
+        assert_eq!(STORAGE_START, 0x7_C000);
+        assert_eq!(CHUNK_STORAGE_START, 0x7_4000);
+        // The micro:bit v1's nRF51822, with 256K.
+        assert_eq!(storage_start(0x4_0000), 0x3_C000);
+        assert_eq!(chunk_storage_start(0x4_0000), 0x3_4000);
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::new(&mut flash, RECORDS_START);
+        storage.store(Slot::Calibration, 3, &PAYLOAD).unwrap();
+        let mut payload = [0u8; MAX_PAYLOAD_LEN];
+        assert_eq!(storage.load(Slot::Calibration, 3, &mut payload), Some(5));
+        assert_eq!(payload[..5], PAYLOAD);
+        // The other slots are left alone.
+        assert_eq!(storage.load(Slot::Settings, 3, &mut payload), None);
+    }
+
+    #[test]
+    fn record_of_another_version_is_ignored() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::new(&mut flash, RECORDS_START);
+        storage.store(Slot::Settings, 3, &PAYLOAD).unwrap();
+        assert_eq!(storage.load(Slot::Settings, 4, &mut [0; 16]), None);
+    }
+
+    #[test]
+    fn corrupt_record_is_ignored() {
+        let mut flash = RamFlash::new();
+        Storage::new(&mut flash, RECORDS_START)
+            .store(Slot::Boot, 1, &PAYLOAD)
+            .unwrap();
+        flash.bytes[record_offset(Slot::Boot) + HEADER_LEN + 2] ^= 0x10;
+        let mut storage = Storage::new(&mut flash, RECORDS_START);
+        assert_eq!(storage.load(Slot::Boot, 1, &mut [0; 16]), None);
+    }
+
+    #[test]
+    fn oversized_record_is_refused() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::new(&mut flash, RECORDS_START);
+        assert!(matches!(
+            storage.store(Slot::Panic, 1, &[0; MAX_PAYLOAD_LEN + 1]),
+            Err(StorageError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn low_supply_leaves_slot_untouched() {
+        static SUPPLY_OK: AtomicBool = AtomicBool::new(true);
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::new(&mut flash, RECORDS_START)
+            .with_supply_check(|| SUPPLY_OK.load(Ordering::Relaxed));
+        storage.store(Slot::Settings, 1, &PAYLOAD).unwrap();
+        SUPPLY_OK.store(false, Ordering::Relaxed);
+        assert!(matches!(
+            storage.store(Slot::Settings, 1, &[9; 8]),
+            Err(StorageError::LowSupply)
+        ));
+        assert!(matches!(
+            storage.erase(Slot::Settings),
+            Err(StorageError::LowSupply)
+        ));
+        assert!(matches!(
+            storage.begin_chunk(0, 1, 8),
+            Err(StorageError::LowSupply)
+        ));
+        let mut payload = [0u8; 16];
+        assert_eq!(storage.load(Slot::Settings, 1, &mut payload), Some(5));
+        assert_eq!(payload[..5], PAYLOAD);
+    }
+
+    #[test]
+    fn chunk_round_trip() {
+        let bytes: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut flash = RamFlash::new();
+        write_chunk(&mut flash, &bytes, true);
+        let mut storage = Storage::new(&mut flash, RECORDS_START);
+        assert_eq!(storage.chunk_len(0, 1), Some(200));
+        assert_eq!(storage.chunk_len(0, 2), None);
+        let mut read = vec![0u8; 100];
+        storage.read_chunk(0, 100, &mut read).unwrap();
+        assert_eq!(read, bytes[100..]);
+    }
+
+    #[test]
+    fn unfinished_chunk_reads_as_empty() {
+        let mut flash = RamFlash::new();
+        write_chunk(&mut flash, &[7; 64], false);
+        assert_eq!(
+            Storage::new(&mut flash, RECORDS_START).chunk_len(0, 1),
+            None
+        );
+    }
+
+    #[test]
+    fn chunk_must_be_written_in_full() {
+        let mut flash = RamFlash::new();
+        let mut storage = Storage::new(&mut flash, RECORDS_START);
+        let mut writer = storage.begin_chunk(1, 1, 64).unwrap();
+        writer.write(&[7; 32]).unwrap();
+        assert!(matches!(writer.finish(), Err(StorageError::TooLarge)));
+        assert_eq!(storage.chunk_len(1, 1), None);
+    }
+
+    #[test]
+    fn corrupt_chunk_is_ignored() {
+        let mut flash = RamFlash::new();
+        write_chunk(&mut flash, &[7; 64], true);
+        corrupt_chunk(&mut flash, 0, 40);
+        assert_eq!(
+            Storage::new(&mut flash, RECORDS_START).chunk_len(0, 1),
+            None
+        );
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
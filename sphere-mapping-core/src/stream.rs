@@ -0,0 +1,451 @@
+//! Splitting what the board sends into text lines and binary frames (see
+//! [`frame`](crate::frame)), and decoding the samples in either, so a host
+//! reads the stream the same whichever output format the board is set to.
+//!
+//! Frames go out between lines and lines never hold the sync bytes, so
+//! whichever comes first, a newline or the sync bytes, says what the next
+//! packet is. A frame whose CRC doesn't match is dropped up to the next
+//! sync bytes within the length it gives, or whole if there are none, since
+//! a dropped byte could have been the start of another frame. A `Gyro:`
+//! line belongs to the `Measurement:` line after it. Whatever is dropped
+//! is counted, so a bad link shows.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::calibration::Measurement;
+use crate::device::Acceleration;
+use crate::frame::{crc16, KIND_SAMPLE, KIND_SAMPLE_GYRO, SYNC};
+use crate::settings::LoggedSample;
+
+/// The sync bytes, kind and payload length before a frame's payload, and
+/// the CRC after it.
+const HEADER_LEN: usize = 4;
+const CRC_LEN: usize = 2;
+/// Longest a line can get before it is taken for noise and dropped.
+const MAX_LINE_LEN: usize = 1024;
+
+/// One thing the board sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    /// A sample, from a `Measurement:` or CSV line or a sample frame.
+    Sample(LoggedSample),
+    /// Any other line, without its line ending.
+    Line(String),
+    /// Any other frame, with its CRC checked.
+    Frame { kind: u8, payload: Vec<u8> },
+    /// Bytes dropped for not decoding: a frame that failed its CRC, or a
+    /// line cut short by a frame or too long to be one.
+    Corrupt(usize),
+}
+
+/// Where in the stream one packet's bytes were, for tools that look at the
+/// bytes themselves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    /// Bytes into the stream the packet starts, from the first pushed.
+    pub start: u64,
+    pub len: usize,
+    /// The packet, or `None` for a blank line or a `Gyro:` line, which
+    /// goes with the sample after it.
+    pub packet: Option<Packet>,
+}
+
+/// Bytes received but not yet decoded.
+#[derive(Default)]
+pub struct Decoder {
+    pending: Vec<u8>,
+    /// Where in the stream `pending` starts.
+    offset: u64,
+    gyro: Option<[f32; 3]>,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// Adds `bytes` and appends every packet they complete to `packets`.
+    pub fn push(&mut self, bytes: &[u8], packets: &mut Vec<Packet>) {
+        self.decode(bytes, |_, _, packet| packets.extend(packet));
+    }
+
+    /// Adds `bytes` and appends where every packet they complete was, with
+    /// the packet, to `spans`; the spans follow on from each other, so
+    /// every byte is in one once it is decoded.
+    pub fn push_spans(&mut self, bytes: &[u8], spans: &mut Vec<Span>) {
+        self.decode(bytes, |start, len, packet| {
+            spans.push(Span { start, len, packet })
+        });
+    }
+
+    fn decode(&mut self, bytes: &[u8], mut emit: impl FnMut(u64, usize, Option<Packet>)) {
+        let mut pending = core::mem::take(&mut self.pending);
+        pending.extend_from_slice(bytes);
+        let mut start = 0;
+        while let Some((used, packet)) = self.next(&pending[start..]) {
+            emit(self.offset + start as u64, used, packet);
+            start += used;
+        }
+        pending.drain(..start);
+        self.offset += start as u64;
+        if pending.len() > MAX_LINE_LEN && find(&pending, &SYNC).is_none() {
+            emit(
+                self.offset,
+                pending.len(),
+                Some(Packet::Corrupt(pending.len())),
+            );
+            self.offset += pending.len() as u64;
+            pending.clear();
+        }
+        self.pending = pending;
+    }
+
+    /// The bytes the next packet in `bytes` takes and the packet, if it
+    /// decodes, or `None` if it isn't all there yet.
+    fn next(&mut self, bytes: &[u8]) -> Option<(usize, Option<Packet>)> {
+        let newline = bytes.iter().position(|&byte| byte == b'\n');
+        match find(bytes, &SYNC) {
+            Some(0) => self.frame(bytes),
+            Some(sync) if newline.is_none_or(|newline| sync < newline) => {
+                // A line cut short by a frame: drop what came of it.
+                Some((sync, Some(Packet::Corrupt(sync))))
+            }
+            _ => {
+                let newline = newline?;
+                let line = String::from_utf8_lossy(&bytes[..newline]);
+                Some((newline + 1, self.line(line.trim_end_matches('\r'))))
+            }
+        }
+    }
+
+    fn frame(&mut self, bytes: &[u8]) -> Option<(usize, Option<Packet>)> {
+        let (&kind, &len) = (bytes.get(2)?, bytes.get(3)?);
+        let end = HEADER_LEN + len as usize;
+        let crc = bytes.get(end..end + CRC_LEN)?;
+        if crc16(&bytes[SYNC.len()..end]) != u16::from_le_bytes([crc[0], crc[1]]) {
+            let skip = find(&bytes[1..end + CRC_LEN], &SYNC).map_or(end + CRC_LEN, |sync| sync + 1);
+            return Some((skip, Some(Packet::Corrupt(skip))));
+        }
+        let payload = &bytes[HEADER_LEN..end];
+        let packet = match decode_sample(kind, payload) {
+            Some(sample) => Packet::Sample(sample),
+            None => Packet::Frame {
+                kind,
+                payload: payload.to_vec(),
+            },
+        };
+        Some((end + CRC_LEN, Some(packet)))
+    }
+
+    fn line(&mut self, line: &str) -> Option<Packet> {
+        if line.is_empty() {
+            return None;
+        }
+        if let Some(gyro) = line.strip_prefix("Gyro:").and_then(parse_gyro) {
+            self.gyro = Some(gyro);
+            return None;
+        }
+        let gyro = self.gyro.take();
+        match LoggedSample::parse(line) {
+            Some(mut sample) => {
+                if line.starts_with("Measurement:") {
+                    sample.gyro = gyro;
+                }
+                Some(Packet::Sample(sample))
+            }
+            None => Some(Packet::Line(line.to_string())),
+        }
+    }
+}
+
+fn find(bytes: &[u8], needle: &[u8]) -> Option<usize> {
+    bytes
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn parse_gyro(values: &str) -> Option<[f32; 3]> {
+    let mut parts = values.split(',').map(|part| part.trim().parse().ok());
+    let gyro = [parts.next()??, parts.next()??, parts.next()??];
+    parts.next().is_none().then_some(gyro)
+}
+
+/// A sample frame's payload: the timestamp, field, acceleration and, for
+/// `KIND_SAMPLE_GYRO`, the gyro's rate.
+fn decode_sample(kind: u8, payload: &[u8]) -> Option<LoggedSample> {
+    let with_gyro = match kind {
+        KIND_SAMPLE => false,
+        KIND_SAMPLE_GYRO => true,
+        _ => return None,
+    };
+    let mut reader = Reader(payload);
+    let timestamp_us = u64::from_le_bytes(reader.take()?);
+    let [x, y, z] = [(); 3].map(|_| reader.take().map(i32::from_le_bytes));
+    let field = Measurement {
+        x: x?,
+        y: y?,
+        z: z?,
+    };
+    let [x, y, z] = [(); 3].map(|_| reader.take().map(i16::from_le_bytes));
+    let accel = Acceleration {
+        x: x? as i32,
+        y: y? as i32,
+        z: z? as i32,
+    };
+    let gyro = if with_gyro {
+        let [x, y, z] = [(); 3].map(|_| reader.take().map(f32::from_le_bytes));
+        Some([x?, y?, z?])
+    } else {
+        None
+    };
+    reader.0.is_empty().then_some(LoggedSample {
+        field,
+        accel,
+        timestamp_us,
+        gyro,
+    })
+}
+
+/// Reads little-endian values off the front of a payload.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (value, rest) = self.0.split_first_chunk()?;
+        self.0 = rest;
+        Some(*value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+
+    fn sample(timestamp_us: u64, gyro: Option<[f32; 3]>) -> LoggedSample {
+        LoggedSample {
+            field: Measurement {
+                x: 12_000,
+                y: -3_400,
+                z: 45_600,
+            },
+            accel: Acceleration {
+                x: 10,
+                y: -20,
+                z: 990,
+            },
+            timestamp_us,
+            gyro,
+        }
+    }
+
+    fn sample_frame(sample: &LoggedSample) -> Vec<u8> {
+        Frame::sample(
+            &sample.field,
+            &sample.accel,
+            sample.timestamp_us,
+            sample.gyro,
+        )
+        .as_bytes()
+        .to_vec()
+    }
+
+    fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = SYNC.to_vec();
+        bytes.push(kind);
+        bytes.push(payload.len() as u8);
+        bytes.extend_from_slice(payload);
+        let crc = crc16(&bytes[SYNC.len()..]);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// A bit of everything the board sends.
+    fn stream() -> Vec<u8> {
+        let mut bytes = b"Status: uptime=1, load=3.0%\r\n\r\n".to_vec();
+        bytes.extend(sample_frame(&sample(1_000, None)));
+        bytes.extend(b"Gyro: 0.5, -1.25, 2\r\nMeasurement: 12000,-3400,45600,10,-20,990,2000\r\n");
+        bytes.extend(sample_frame(&sample(3_000, Some([1., 2., 3.]))));
+        bytes.extend(frame(7, &[1, 2, 3]));
+        bytes.extend(b"12000,-3400,45600,10,-20,990,4000\n");
+        bytes
+    }
+
+    fn expected() -> Vec<Packet> {
+        Vec::from([
+            Packet::Line("Status: uptime=1, load=3.0%".to_string()),
+            Packet::Sample(sample(1_000, None)),
+            Packet::Sample(sample(2_000, Some([0.5, -1.25, 2.]))),
+            Packet::Sample(sample(3_000, Some([1., 2., 3.]))),
+            Packet::Frame {
+                kind: 7,
+                payload: Vec::from([1, 2, 3]),
+            },
+            Packet::Sample(sample(4_000, None)),
+        ])
+    }
+
+    fn decode_in(pieces: &[&[u8]]) -> Vec<Packet> {
+        let mut decoder = Decoder::new();
+        let mut packets = Vec::new();
+        for piece in pieces {
+            decoder.push(piece, &mut packets);
+        }
+        packets
+    }
+
+    fn corrupt(packets: &[Packet]) -> usize {
+        packets
+            .iter()
+            .map(|packet| match packet {
+                Packet::Corrupt(len) => *len,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    #[test]
+    fn decodes_lines_and_frames() {
+        assert_eq!(decode_in(&[&stream()]), expected());
+    }
+
+    #[test]
+    fn split_at_every_byte() {
+        let bytes = stream();
+        for split in 0..=bytes.len() {
+            let (head, tail) = bytes.split_at(split);
+            assert_eq!(decode_in(&[head, tail]), expected(), "split at {}", split);
+        }
+        let bytes: Vec<&[u8]> = bytes.chunks(1).collect();
+        assert_eq!(decode_in(&bytes), expected());
+    }
+
+    #[test]
+    fn corrupt_frame_then_a_good_one() {
+        let mut bad = sample_frame(&sample(1_000, None));
+        bad[10] ^= 0x40;
+        let mut bytes = bad.clone();
+        bytes.extend(sample_frame(&sample(2_000, None)));
+        assert_eq!(
+            decode_in(&[&bytes]),
+            [
+                Packet::Corrupt(bad.len()),
+                Packet::Sample(sample(2_000, None))
+            ]
+        );
+    }
+
+    #[test]
+    fn corrupt_frame_cut_short_by_another() {
+        // The bad frame's length runs on into the good one.
+        let mut bytes = frame(9, &[0; 20]);
+        bytes.truncate(12);
+        bytes.extend(sample_frame(&sample(2_000, None)));
+        assert_eq!(
+            decode_in(&[&bytes]),
+            [Packet::Corrupt(12), Packet::Sample(sample(2_000, None))]
+        );
+    }
+
+    #[test]
+    fn sync_bytes_in_a_bad_frames_payload() {
+        for at in 0..10 {
+            let mut payload = [0x11; 12];
+            payload[at..at + 2].copy_from_slice(&SYNC);
+            let mut bad = frame(9, &payload);
+            let last = bad.len() - 1;
+            bad[last] ^= 0xff;
+            let mut bytes = bad.clone();
+            bytes.extend(sample_frame(&sample(2_000, None)));
+            // Enough after it for whatever length the bytes after the
+            // stray sync bytes give.
+            bytes.extend(b"Hello\r\n".repeat(40));
+
+            let packets = decode_in(&[&bytes]);
+            let good = packets
+                .iter()
+                .position(|packet| !matches!(packet, Packet::Corrupt(_)))
+                .unwrap();
+            assert_eq!(corrupt(&packets[..good]), bad.len(), "sync at {}", at);
+            assert_eq!(packets[good], Packet::Sample(sample(2_000, None)));
+            assert_eq!(packets.len() - good - 1, 40);
+        }
+    }
+
+    #[test]
+    fn line_cut_short_by_a_frame() {
+        let mut bytes = b"Status: upt".to_vec();
+        bytes.extend(sample_frame(&sample(1_000, None)));
+        assert_eq!(
+            decode_in(&[&bytes]),
+            [Packet::Corrupt(11), Packet::Sample(sample(1_000, None))]
+        );
+    }
+
+    #[test]
+    fn too_long_for_a_line() {
+        let noise = [b'x'; MAX_LINE_LEN + 1];
+        assert_eq!(
+            decode_in(&[&noise[..MAX_LINE_LEN], &noise[MAX_LINE_LEN..], b"ok\n"]),
+            [
+                Packet::Corrupt(MAX_LINE_LEN + 1),
+                Packet::Line("ok".to_string())
+            ]
+        );
+        // Not while it could be waiting on the rest of a frame.
+        let mut long = frame(9, &[0x11; 200]);
+        long.truncate(150);
+        let mut bytes = noise.to_vec();
+        bytes.extend(&long);
+        assert_eq!(decode_in(&[&bytes]), [Packet::Corrupt(noise.len())]);
+    }
+
+    #[test]
+    fn gyro_goes_with_the_next_measurement_only() {
+        let packets = decode_in(&[b"Gyro: 1,2,3\r\nWarning: x\r\n\
+            Measurement: 12000,-3400,45600,10,-20,990,2000\r\n\
+            Gyro: 4,5,6\r\n12000,-3400,45600,10,-20,990,3000\r\n\
+            Measurement: 12000,-3400,45600,10,-20,990,4000\r\n\
+            Gyro: 7,8\r\n"]);
+        assert_eq!(
+            packets,
+            [
+                Packet::Line("Warning: x".to_string()),
+                Packet::Sample(sample(2_000, None)),
+                Packet::Sample(sample(3_000, None)),
+                Packet::Sample(sample(4_000, None)),
+                Packet::Line("Gyro: 7,8".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_cover_every_byte() {
+        let mut bytes = stream();
+        let mut bad = sample_frame(&sample(5_000, None));
+        bad[7] ^= 1;
+        bytes.extend(b"Status: cut");
+        bytes.extend(&bad);
+        bytes.extend(b"Gyro: 1,2,3\r\n");
+        bytes.extend(stream());
+        for piece in [1, 3, 7, 64, bytes.len()] {
+            let mut decoder = Decoder::new();
+            let mut spans = Vec::new();
+            for chunk in bytes.chunks(piece) {
+                decoder.push_spans(chunk, &mut spans);
+            }
+            let mut next = 0;
+            for span in &spans {
+                assert_eq!(span.start, next, "pieces of {}", piece);
+                if let Some(Packet::Corrupt(len)) = span.packet {
+                    assert_eq!(len, span.len);
+                }
+                next += span.len as u64;
+            }
+            assert_eq!(next, bytes.len() as u64, "pieces of {}", piece);
+            let packets: Vec<Packet> = spans.into_iter().filter_map(|span| span.packet).collect();
+            assert_eq!(packets, decode_in(&[&bytes]));
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Magnetic surveys: in the survey mode the calibrated field is averaged
+//! over a second at a time and logged with where the board was, building a
+//! geo-referenced map of the field for finding buried pipes, cables or
+//! ironwork by walking a grid over them.
+//!
+//! The position comes from outside: latitude and longitude sent by the host,
+//! which can relay a GPS receiver's fixes as they arrive, or a waypoint
+//! number for a grid pegged out by hand, stepped on with button B at each
+//! peg. Samples taken before there is a position are left out. A new
+//! waypoint starts a new average, so no point mixes readings from two pegs,
+//! while new coordinates, which a receiver sends every second or so as the
+//! board is walked along, only move where the average is logged.
+
+use core::fmt;
+
+use crate::calibration::Measurement;
+use crate::fixed;
+
+/// How long each logged point is averaged over, in microseconds.
+const SURVEY_WINDOW_US: u64 = 1_000_000;
+/// Degrees in the units positions are kept in, as GPS receivers give them.
+const E7: i32 = 10_000_000;
+pub const MAX_LATITUDE_E7: i32 = 90 * E7;
+pub const MAX_LONGITUDE_E7: i32 = 180 * E7;
+
+/// Where the board is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    /// Latitude and longitude in 10⁻⁷ degrees, North and East positive.
+    Coordinates { lat_e7: i32, lon_e7: i32 },
+    /// A numbered point of a grid set out by hand.
+    Waypoint(u16),
+}
+
+/// `lat=<degrees>, lon=<degrees>` to seven decimal places, or
+/// `waypoint=<n>`.
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Position::Coordinates { lat_e7, lon_e7 } => {
+                write!(f, "lat=")?;
+                write_degrees(f, *lat_e7)?;
+                write!(f, ", lon=")?;
+                write_degrees(f, *lon_e7)
+            }
+            Position::Waypoint(waypoint) => write!(f, "waypoint={}", waypoint),
+        }
+    }
+}
+
+fn write_degrees(f: &mut fmt::Formatter<'_>, degrees_e7: i32) -> fmt::Result {
+    let sign = if degrees_e7 < 0 { "-" } else { "" };
+    let magnitude = degrees_e7.unsigned_abs();
+    write!(
+        f,
+        "{}{}.{:07}",
+        sign,
+        magnitude / E7 as u32,
+        magnitude % E7 as u32
+    )
+}
+
+/// One averaged point of the survey.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurveyPoint {
+    pub position: Position,
+    /// The average calibrated field, in nT.
+    pub field: Measurement,
+    pub samples: u32,
+}
+
+/// `Survey: <position>, x=<nT>, y=<nT>, z=<nT>, strength=<nT>,
+/// samples=<n>`.
+impl fmt::Display for SurveyPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Survey: {}, x={}, y={}, z={}, strength={}, samples={}",
+            self.position,
+            self.field.x,
+            self.field.y,
+            self.field.z,
+            fixed::magnitude(&self.field),
+            self.samples
+        )
+    }
+}
+
+/// The survey's position and the average being built up there.
+#[derive(Default)]
+pub struct Survey {
+    position: Option<Position>,
+    sum: [i64; 3],
+    samples: u32,
+    started_us: u64,
+}
+
+impl Survey {
+    pub const fn new() -> Survey {
+        Survey {
+            position: None,
+            sum: [0; 3],
+            samples: 0,
+            started_us: 0,
+        }
+    }
+
+    /// Moves to `position`, dropping the average so far unless it only
+    /// updates the coordinates.
+    pub fn set_position(&mut self, position: Position) {
+        let moving = matches!(
+            (self.position, position),
+            (
+                Some(Position::Coordinates { .. }),
+                Position::Coordinates { .. }
+            )
+        );
+        if !moving {
+            self.samples = 0;
+        }
+        self.position = Some(position);
+    }
+
+    /// Moves to the waypoint after the current one, or to the first if the
+    /// position isn't a waypoint, and returns it.
+    pub fn next_waypoint(&mut self) -> Position {
+        let next = match self.position {
+            Some(Position::Waypoint(waypoint)) => Position::Waypoint(waypoint.wrapping_add(1)),
+            _ => Position::Waypoint(0),
+        };
+        self.set_position(next);
+        next
+    }
+
+    /// Adds a calibrated field taken at `timestamp_us`, and returns the
+    /// average once it has been built up over a whole window.
+    pub fn add(&mut self, field: &Measurement, timestamp_us: u64) -> Option<SurveyPoint> {
+        let position = self.position?;
+        if self.samples == 0 {
+            self.sum = [0; 3];
+            self.started_us = timestamp_us;
+        }
+        self.sum[0] += field.x as i64;
+        self.sum[1] += field.y as i64;
+        self.sum[2] += field.z as i64;
+        self.samples += 1;
+        if timestamp_us.saturating_sub(self.started_us) < SURVEY_WINDOW_US {
+            return None;
+        }
+        let samples = core::mem::take(&mut self.samples);
+        let average = |sum: i64| (sum / samples as i64) as i32;
+        Some(SurveyPoint {
+            position,
+            field: Measurement {
+                x: average(self.sum[0]),
+                y: average(self.sum[1]),
+                z: average(self.sum[2]),
+            },
+            samples,
+        })
+    }
+}
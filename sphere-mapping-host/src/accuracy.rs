@@ -0,0 +1,233 @@
+//! `accuracy`: how far the heading in a recorded log is from a reference
+//! heading taken at the same time, such as a turntable's angle or a
+//! phone's compass, so a change to the filter or the calibration can be
+//! measured rather than eyeballed.
+//!
+//! The log, a serial log or one of `log`'s CSV files, is read as a replay
+//! reads it (see [`read_log`](crate::source::read_log)), and the heading
+//! worked out from each sample as the other tools work it out (see
+//! [`Attitude`]). The reference is a CSV of a time in seconds and a
+//! heading in degrees clockwise from North on each line; lines that don't
+//! start with two numbers, such as a header, are skipped.
+//!
+//! Both are timed from their first entry, or `--offset` apart, and the
+//! lag is found as the shift of the reference, within `--max-lag` either
+//! way in steps of the log's sample interval, that brings the headings
+//! closest. At that lag each sample's heading is compared with the
+//! reference's, interpolated, and the report gives:
+//!
+//! - the bias, the mean difference, which a declination or the board
+//!   being turned on its mount shows as;
+//! - the RMS difference, with and without the bias;
+//! - the largest difference and the 95th percentile of them.
+//!
+//! A positive lag means the board's heading follows the reference's, as
+//! smoothing makes it.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use sphere_mapping_core::stream::Packet;
+
+use crate::attitude::Attitude;
+use crate::source::read_log;
+
+#[derive(Args)]
+pub struct AccuracyArgs {
+    /// A serial log, whatever the board sent saved to a file, or a `log`
+    /// CSV file.
+    log: PathBuf,
+    /// The reference: a CSV of time in s and heading in degrees.
+    reference: PathBuf,
+    /// Seconds into the reference the log's first sample was taken at.
+    #[arg(long, default_value_t = 0., allow_negative_numbers = true)]
+    offset: f64,
+    /// Longest lag looked for either way, in s.
+    #[arg(long, default_value_t = 2.)]
+    max_lag: f64,
+}
+
+pub fn run(args: AccuracyArgs) -> io::Result<()> {
+    let headings = log_headings(&args.log)?;
+    let reference = Reference::read(&args.reference)?;
+    let Some(report) = Report::new(&headings, &reference, args.offset, args.max_lag) else {
+        return Err(io::Error::other(
+            "the log and the reference don't overlap in time; try --offset",
+        ));
+    };
+    println!(
+        "{} against {}: {report}",
+        args.log.display(),
+        args.reference.display()
+    );
+    Ok(())
+}
+
+/// The heading of each sample in the log at `path`, in degrees, with the
+/// time since the first, in s. A reset ends the log there, since the
+/// times after it can't be lined up.
+fn log_headings(path: &Path) -> io::Result<Vec<(f64, f64)>> {
+    let mut headings = Vec::new();
+    let mut first_us = None;
+    let mut last_us = 0;
+    for packet in read_log(path)? {
+        let Packet::Sample(sample) = packet else {
+            continue;
+        };
+        if sample.timestamp_us < last_us {
+            eprintln!("The board reset; comparing up to the reset");
+            break;
+        }
+        last_us = sample.timestamp_us;
+        let first_us = *first_us.get_or_insert(sample.timestamp_us);
+        let t = (sample.timestamp_us - first_us) as f64 / 1e6;
+        headings.push((t, Attitude::of(&sample).heading_deg as f64));
+    }
+    if headings.len() < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: too few samples to compare", path.display()),
+        ));
+    }
+    Ok(headings)
+}
+
+/// A reference heading trace, timed from its first entry.
+struct Reference {
+    /// Time in s and heading in degrees, in time order.
+    headings: Vec<(f64, f64)>,
+}
+
+impl Reference {
+    fn read(path: &Path) -> io::Result<Reference> {
+        let text = fs::read_to_string(path)?;
+        let mut headings: Vec<(f64, f64)> = text
+            .lines()
+            .filter_map(|line| {
+                let mut cells = line.split(',').map(str::trim);
+                let t = cells.next()?.parse().ok()?;
+                let heading = cells.next()?.parse().ok()?;
+                Some((t, heading))
+            })
+            .collect();
+        headings.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let Some(&(first, _)) = headings.first() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: no headings", path.display()),
+            ));
+        };
+        for (t, _) in &mut headings {
+            *t -= first;
+        }
+        Ok(Reference { headings })
+    }
+
+    /// The heading at `t`, interpolated the short way round between the
+    /// entries either side, or `None` outside the trace.
+    fn at(&self, t: f64) -> Option<f64> {
+        let after = self.headings.partition_point(|&(at, _)| at < t);
+        let &(t1, h1) = self.headings.get(after)?;
+        if t1 == t {
+            return Some(h1);
+        }
+        let &(t0, h0) = self.headings.get(after.checked_sub(1)?)?;
+        let share = (t - t0) / (t1 - t0);
+        Some((h0 + difference(h1, h0) * share).rem_euclid(360.))
+    }
+}
+
+/// `a - b` in degrees, the short way round: from -180 to 180.
+fn difference(a: f64, b: f64) -> f64 {
+    (a - b + 180.).rem_euclid(360.) - 180.
+}
+
+/// How far the headings are from the reference's.
+struct Report {
+    compared: usize,
+    lag_s: f64,
+    bias_deg: f64,
+    rms_deg: f64,
+    unbiased_rms_deg: f64,
+    p95_deg: f64,
+    max_deg: f64,
+}
+
+impl Report {
+    fn new(
+        headings: &[(f64, f64)],
+        reference: &Reference,
+        offset_s: f64,
+        max_lag_s: f64,
+    ) -> Option<Report> {
+        let mut intervals: Vec<f64> = headings.windows(2).map(|w| w[1].0 - w[0].0).collect();
+        intervals.sort_by(f64::total_cmp);
+        let step = intervals[intervals.len() / 2].max(1e-3);
+        let steps = (max_lag_s / step).round() as i64;
+        // The lag whose differences vary least, and those differences.
+        // Nearest first, so of lags that fit as well the shortest wins, as
+        // one does for a steady turn, where any lag looks like a bias.
+        let mut step_indices: Vec<i64> = (-steps..=steps).collect();
+        step_indices.sort_by_key(|step_index| step_index.abs());
+        let (lag_s, differences) = step_indices
+            .into_iter()
+            .map(|step_index| {
+                let lag_s = step_index as f64 * step;
+                let differences: Vec<f64> = headings
+                    .iter()
+                    .filter_map(|&(t, heading)| {
+                        let reference = reference.at(t + offset_s - lag_s)?;
+                        Some(difference(heading, reference))
+                    })
+                    .collect();
+                (lag_s, differences)
+            })
+            .filter(|(_, differences)| differences.len() >= headings.len() / 2)
+            .min_by(|(_, a), (_, b)| variance(a).total_cmp(&variance(b)))?;
+        let n = differences.len() as f64;
+        let bias_deg = differences.iter().sum::<f64>() / n;
+        let unbiased: Vec<f64> = differences.iter().map(|d| d - bias_deg).collect();
+        let mut sizes: Vec<f64> = differences.iter().map(|d| d.abs()).collect();
+        sizes.sort_by(f64::total_cmp);
+        Some(Report {
+            compared: differences.len(),
+            lag_s,
+            bias_deg,
+            rms_deg: mean_square(&differences).sqrt(),
+            unbiased_rms_deg: mean_square(&unbiased).sqrt(),
+            p95_deg: sizes[((sizes.len() - 1) as f64 * 0.95) as usize],
+            max_deg: sizes[sizes.len() - 1],
+        })
+    }
+}
+
+fn mean_square(values: &[f64]) -> f64 {
+    values.iter().map(|v| v * v).sum::<f64>() / values.len().max(1) as f64
+}
+
+/// Found with the bias left out, so a declination doesn't pull the lag.
+fn variance(values: &[f64]) -> f64 {
+    let mean = values.iter().sum::<f64>() / values.len().max(1) as f64;
+    mean_square(values) - mean * mean
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} samples compared", self.compared)?;
+        writeln!(f, "Lag: {:.3} s", self.lag_s)?;
+        writeln!(f, "Bias: {:+.2}°", self.bias_deg)?;
+        writeln!(
+            f,
+            "RMS error: {:.2}°, {:.2}° without the bias",
+            self.rms_deg, self.unbiased_rms_deg
+        )?;
+        write!(
+            f,
+            "Largest error: {:.2}°, 95% within {:.2}°",
+            self.max_deg, self.p95_deg
+        )
+    }
+}
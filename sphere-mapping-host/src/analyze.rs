@@ -0,0 +1,277 @@
+//! `analyze`: how good a recorded log is, before anything is fitted to it.
+//! The log, a serial log or one of `log`'s CSV files, is read as a replay
+//! reads it (see [`read_log`](crate::source::read_log)) and summed up:
+//!
+//! - the interval between samples, the median of them, since the rate can
+//!   be changed on the board, and the gaps, intervals more than half again
+//!   as long, with how many samples they are missing;
+//! - a histogram of how far the other intervals are from it;
+//! - what was dropped: packets that didn't decode, such as frames failing
+//!   their CRC, and the bytes the board says it dropped in its `Status:`
+//!   lines (a CSV log has neither);
+//! - samples at the sensors' limits, whose fields or accelerations were cut
+//!   off and would pull a fit;
+//! - resets, timestamps going backwards, which split the log into runs.
+//!
+//! It ends with what, if anything, makes the log unfit to fit.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use clap::Args;
+use sphere_mapping_core::stream::Packet;
+use sphere_mapping_host::frame::parse_status;
+
+use crate::source::read_log;
+
+/// How much longer than the median an interval is before samples count as
+/// missing.
+const GAP_FACTOR: f64 = 1.5;
+/// The magnetometer's limit on each axis in nT: the LSM303AGR's 16 bits at
+/// 150 nT each. The calibration moves it a little, so samples within
+/// [`SATURATION_MARGIN`] of it count.
+const MAG_FULL_SCALE_NT: f64 = 32_767. * 150.;
+/// How near a sensor's limit, as a share of it, a sample counts as at it.
+const SATURATION_MARGIN: f64 = 0.03;
+/// The jitter histogram's bins: how far from the median interval, at most,
+/// in µs.
+const JITTER_BINS_US: [u64; 6] = [50, 200, 1_000, 5_000, 20_000, u64::MAX];
+/// Width of the histogram's longest bar.
+const BAR_WIDTH: usize = 40;
+/// Share of samples missing above which a log is called gappy.
+const MAX_MISSING: f64 = 0.01;
+
+#[derive(Args)]
+pub struct AnalyzeArgs {
+    /// A serial log, whatever the board sent saved to a file, or a `log`
+    /// CSV file.
+    log: PathBuf,
+    /// Full scale of the accelerometer the log was recorded with, in g.
+    #[arg(long, default_value_t = sphere_mapping_core::config::ACCEL_SCALE_G)]
+    accel_scale: u32,
+}
+
+pub fn run(args: AnalyzeArgs) -> io::Result<()> {
+    let packets = read_log(&args.log)?;
+    let report = Report::new(&packets, args.accel_scale);
+    println!("{}: {report}", args.log.display());
+    Ok(())
+}
+
+/// What a log holds, and what is wrong with it.
+struct Report {
+    samples: usize,
+    /// Board time covered, summed over the runs, in µs.
+    span_us: u64,
+    resets: usize,
+    median_us: Option<u64>,
+    gaps: usize,
+    missing: u64,
+    /// The longest gap, and the board time it ended at, in µs.
+    longest_gap: Option<(u64, u64)>,
+    /// How many of the intervals that aren't gaps fall in each of
+    /// [`JITTER_BINS_US`].
+    jitter: [usize; JITTER_BINS_US.len()],
+    corrupt: usize,
+    corrupt_bytes: usize,
+    board_dropped: u64,
+    field_saturated: usize,
+    accel_saturated: usize,
+}
+
+impl Report {
+    fn new(packets: &[Packet], accel_scale_g: u32) -> Report {
+        let mut report = Report {
+            samples: 0,
+            span_us: 0,
+            resets: 0,
+            median_us: None,
+            gaps: 0,
+            missing: 0,
+            longest_gap: None,
+            jitter: [0; JITTER_BINS_US.len()],
+            corrupt: 0,
+            corrupt_bytes: 0,
+            board_dropped: 0,
+            field_saturated: 0,
+            accel_saturated: 0,
+        };
+        let accel_limit = accel_scale_g as f64 * 1000. * (1. - SATURATION_MARGIN);
+        let field_limit = MAG_FULL_SCALE_NT * (1. - SATURATION_MARGIN);
+        // Each interval, with the time it ended at.
+        let mut intervals = Vec::new();
+        let mut last_us: Option<u64> = None;
+        for packet in packets {
+            match packet {
+                Packet::Sample(sample) => {
+                    report.samples += 1;
+                    let (field, accel) = (sample.field, sample.accel);
+                    if at_limit([field.x, field.y, field.z], field_limit) {
+                        report.field_saturated += 1;
+                    }
+                    if at_limit([accel.x, accel.y, accel.z], accel_limit) {
+                        report.accel_saturated += 1;
+                    }
+                    match last_us.replace(sample.timestamp_us) {
+                        Some(last) if sample.timestamp_us < last => report.resets += 1,
+                        Some(last) => {
+                            let interval = sample.timestamp_us - last;
+                            report.span_us += interval;
+                            intervals.push((interval, sample.timestamp_us));
+                        }
+                        None => {}
+                    }
+                }
+                Packet::Line(line) => {
+                    if let Some(fields) = line.strip_prefix("Status:") {
+                        report.board_dropped += parse_status(fields)
+                            .into_iter()
+                            .find(|(key, _)| key == "dropped")
+                            .and_then(|(_, value)| value.parse::<u64>().ok())
+                            .unwrap_or(0);
+                    }
+                }
+                Packet::Corrupt(bytes) => {
+                    report.corrupt += 1;
+                    report.corrupt_bytes += bytes;
+                }
+                Packet::Frame { .. } => {}
+            }
+        }
+        let mut sorted: Vec<u64> = intervals.iter().map(|(interval, _)| *interval).collect();
+        sorted.sort_unstable();
+        let Some(&median) = sorted.get(sorted.len() / 2).filter(|&&median| median > 0) else {
+            return report;
+        };
+        report.median_us = Some(median);
+        for (interval, at_us) in intervals {
+            if interval as f64 > median as f64 * GAP_FACTOR {
+                report.gaps += 1;
+                report.missing += (interval as f64 / median as f64).round() as u64 - 1;
+                if report
+                    .longest_gap
+                    .is_none_or(|(longest, _)| interval > longest)
+                {
+                    report.longest_gap = Some((interval, at_us));
+                }
+            } else {
+                let off = interval.abs_diff(median);
+                let bin = JITTER_BINS_US
+                    .iter()
+                    .position(|&most| off <= most)
+                    .expect("the last bin takes everything");
+                report.jitter[bin] += 1;
+            }
+        }
+        report
+    }
+
+    /// What makes the log unfit to fit, if anything.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.samples == 0 {
+            problems.push("there are no samples".into());
+            return problems;
+        }
+        let expected = self.samples as u64 + self.missing;
+        if self.missing as f64 > expected as f64 * MAX_MISSING {
+            problems.push(format!(
+                "{:.1}% of samples are missing",
+                self.missing as f64 * 100. / expected as f64
+            ));
+        }
+        if self.field_saturated > 0 {
+            problems.push(format!(
+                "{} samples have the field at the magnetometer's limit; keep magnets away",
+                self.field_saturated
+            ));
+        }
+        if self.accel_saturated > 0 {
+            problems.push(format!(
+                "{} samples have the acceleration at the accelerometer's limit, so their tilt is off; turn the board more gently",
+                self.accel_saturated
+            ));
+        }
+        if self.corrupt > 0 || self.board_dropped > 0 {
+            problems.push(
+                "data was lost on the way; a slower output format or rate, or a better cable, may help"
+                    .into(),
+            );
+        }
+        problems
+    }
+}
+
+/// Whether any of `axes` is at `limit` either way.
+fn at_limit(axes: [i32; 3], limit: f64) -> bool {
+    axes.iter().any(|axis| axis.unsigned_abs() as f64 >= limit)
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} samples over {:.1} s, in {} runs ({} resets)",
+            self.samples,
+            self.span_us as f64 / 1e6,
+            self.resets + 1,
+            self.resets
+        )?;
+        match self.median_us {
+            Some(median) => writeln!(
+                f,
+                "Interval: {:.3} ms median ({:.1} Hz)",
+                median as f64 / 1e3,
+                1e6 / median as f64
+            )?,
+            None => writeln!(f, "Interval: too few samples to tell")?,
+        }
+        write!(
+            f,
+            "Gaps: {}, about {} samples missing",
+            self.gaps, self.missing
+        )?;
+        match self.longest_gap {
+            Some((interval, at_us)) => writeln!(
+                f,
+                ", the longest {:.3} s, ending at {:.3} s",
+                interval as f64 / 1e6,
+                at_us as f64 / 1e6
+            )?,
+            None => writeln!(f)?,
+        }
+        if self.median_us.is_some() {
+            writeln!(f, "Jitter, off the median interval:")?;
+            let most = self.jitter.iter().copied().max().unwrap_or(0).max(1);
+            for (&bound, &count) in JITTER_BINS_US.iter().zip(&self.jitter) {
+                let label = match bound {
+                    u64::MAX => "more".to_string(),
+                    bound => format!("<= {bound} us"),
+                };
+                let bar = "#".repeat(count.div_ceil(most.div_ceil(BAR_WIDTH)));
+                let row = format!("  {label:>12} {count:>8}  {bar}");
+                writeln!(f, "{}", row.trim_end())?;
+            }
+        }
+        writeln!(
+            f,
+            "Dropped: {} packets that didn't decode ({} bytes); the board dropped {} bytes",
+            self.corrupt, self.corrupt_bytes, self.board_dropped
+        )?;
+        writeln!(
+            f,
+            "Saturated: {} fields, {} accelerations",
+            self.field_saturated, self.accel_saturated
+        )?;
+        let problems = self.problems();
+        if problems.is_empty() {
+            return write!(f, "Quality: good to fit");
+        }
+        write!(f, "Quality: not good to fit")?;
+        for problem in problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
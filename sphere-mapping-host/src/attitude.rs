@@ -0,0 +1,34 @@
+//! What the host tools work out from each sample: the field's strength and
+//! heading, worked out as the board does, and how the board is tilted.
+//!
+//! The heading is the angle of the calibrated field in the board's plane,
+//! in degrees clockwise from magnetic North, without tilt compensation,
+//! like the matrix's arrow. Pitch and roll are the tilts the level's bubble
+//! shows, in degrees from the acceleration: pitch is positive with the
+//! board's bottom edge raised, roll with its left edge raised, and both are
+//! 0 when it lies flat.
+
+use sphere_mapping_core::fixed;
+use sphere_mapping_core::led::{heading_from_theta, theta_from_field};
+use sphere_mapping_core::settings::LoggedSample;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attitude {
+    pub magnitude_nt: u32,
+    pub heading_deg: f32,
+    pub pitch_deg: f32,
+    pub roll_deg: f32,
+}
+
+impl Attitude {
+    pub fn of(sample: &LoggedSample) -> Attitude {
+        let field = &sample.field;
+        let [x, y, z] = [sample.accel.x, sample.accel.y, sample.accel.z].map(|axis| axis as f32);
+        Attitude {
+            magnitude_nt: fixed::magnitude(field),
+            heading_deg: heading_from_theta(theta_from_field(field.x, field.y)),
+            pitch_deg: y.atan2(x.hypot(z)).to_degrees(),
+            roll_deg: x.atan2(y.hypot(z)).to_degrees(),
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! The board over Bluetooth LE, built with the `ble` feature, for a board
+//! running the firmware's `ble` build with no cable to it: its serial link
+//! carried by the Nordic UART Service, read and written like the port, so
+//! every command works the same over it.
+//!
+//! The board is connected to by its address, as `bluetoothctl scan le`
+//! lists it under the name `sphere-mapping`, which is a random static one.
+//! On connecting, the ATT MTU is raised so notifications carry up to 244
+//! bytes, the service and its characteristics are found and notifications
+//! on TX are turned on; from then on each notification is bytes the board
+//! sent, and commands are written to RX without waiting for a response.
+//!
+//! It speaks ATT over the kernel's L2CAP socket, so it builds on Linux
+//! only, and there isn't a Bluetooth crate here; the few socket functions
+//! needed are declared below. BlueZ's `bluetoothd` needn't be stopped.
+
+use std::ffi::{c_long, c_void};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+/// The Nordic UART Service's UUID, 6E400001-B5A3-F393-E0A9-E50E24DCCA9E,
+/// little-endian as ATT has it. RX and TX differ in the third byte from the
+/// end.
+const SERVICE_UUID: [u8; 16] = [
+    0x9e, 0xca, 0xdc, 0x24, 0x0e, 0xe5, 0xa9, 0xe0, 0x93, 0xf3, 0xa3, 0xb5, 0x01, 0x00, 0x40, 0x6e,
+];
+const RX_UUID: u8 = 0x02;
+const TX_UUID: u8 = 0x03;
+/// The MTU asked for, the most the firmware agrees to.
+const MTU: u16 = 247;
+/// How long a read waits before timing out, as the serial port's does.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long the board may take to answer a request while connecting.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// ATT opcodes.
+const ERROR_RESPONSE: u8 = 0x01;
+const EXCHANGE_MTU_REQUEST: u8 = 0x02;
+const FIND_INFORMATION_REQUEST: u8 = 0x04;
+const FIND_BY_TYPE_VALUE_REQUEST: u8 = 0x06;
+const READ_BY_TYPE_REQUEST: u8 = 0x08;
+const WRITE_REQUEST: u8 = 0x12;
+const NOTIFICATION: u8 = 0x1b;
+const WRITE_COMMAND: u8 = 0x52;
+/// The error a search ends with once nothing more is found.
+const ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+
+// GATT attribute types.
+const PRIMARY_SERVICE: u16 = 0x2800;
+const CHARACTERISTIC: u16 = 0x2803;
+const CLIENT_CHARACTERISTIC_CONFIGURATION: u16 = 0x2902;
+
+/// A connection to the board's Nordic UART Service.
+pub struct Link {
+    socket: File,
+    /// The longest value a write can carry.
+    value_len: usize,
+    rx: u16,
+    tx: u16,
+    /// Bytes of the last notification that didn't fit the reader's buffer.
+    pending: Vec<u8>,
+}
+
+impl Link {
+    /// Connects to the board at `address`, written `AA:BB:CC:DD:EE:FF`.
+    pub fn connect(address: &str) -> io::Result<Link> {
+        let bdaddr = parse_address(address)
+            .ok_or_else(|| io::Error::other(format!("{address} isn't a Bluetooth address")))?;
+        let socket = open_socket(bdaddr)
+            .map_err(|err| io::Error::new(err.kind(), format!("{address}: {err}")))?;
+        let mut link = Link {
+            socket,
+            value_len: usize::from(MTU) - 3,
+            rx: 0,
+            tx: 0,
+            pending: Vec::new(),
+        };
+        link.set_up()
+            .map_err(|err| io::Error::new(err.kind(), format!("{address}: {err}")))?;
+        Ok(link)
+    }
+
+    /// Agrees the MTU, finds the service's characteristics and subscribes
+    /// to TX.
+    fn set_up(&mut self) -> io::Result<()> {
+        let mut request = vec![EXCHANGE_MTU_REQUEST];
+        request.extend(MTU.to_le_bytes());
+        let response = self.request(&request)?;
+        let mtu = response
+            .get(1..3)
+            .map_or(23, |mtu| u16::from_le_bytes([mtu[0], mtu[1]]));
+        self.value_len = usize::from(mtu.clamp(23, MTU)) - 3;
+
+        let mut request = vec![FIND_BY_TYPE_VALUE_REQUEST];
+        request.extend(1u16.to_le_bytes());
+        request.extend(0xffffu16.to_le_bytes());
+        request.extend(PRIMARY_SERVICE.to_le_bytes());
+        request.extend(SERVICE_UUID);
+        let response = self.request(&request)?;
+        let (start, end) = match response.get(1..5) {
+            Some(handles) if response[0] != ERROR_RESPONSE => (
+                u16::from_le_bytes([handles[0], handles[1]]),
+                u16::from_le_bytes([handles[2], handles[3]]),
+            ),
+            _ => return Err(io::Error::other("the board has no UART service")),
+        };
+
+        // Each characteristic declaration's entry is its handle, its
+        // properties, its value's handle and its UUID.
+        let mut handle = start;
+        while handle <= end {
+            let mut request = vec![READ_BY_TYPE_REQUEST];
+            request.extend(handle.to_le_bytes());
+            request.extend(end.to_le_bytes());
+            request.extend(CHARACTERISTIC.to_le_bytes());
+            let response = self.request(&request)?;
+            if response[0] == ERROR_RESPONSE {
+                break;
+            }
+            let Some(&len) = response.get(1).filter(|&&len| len >= 5) else {
+                break;
+            };
+            for entry in response[2..].chunks_exact(usize::from(len)) {
+                let declaration = u16::from_le_bytes([entry[0], entry[1]]);
+                let value = u16::from_le_bytes([entry[3], entry[4]]);
+                let uuid = &entry[5..];
+                if uuid.len() == 16 && uuid[..12] == SERVICE_UUID[..12] {
+                    match uuid[12] {
+                        RX_UUID => self.rx = value,
+                        TX_UUID => self.tx = value,
+                        _ => {}
+                    }
+                }
+                handle = declaration.saturating_add(1);
+            }
+            if handle == u16::MAX {
+                break;
+            }
+        }
+        if self.rx == 0 || self.tx == 0 {
+            return Err(io::Error::other("the board's UART service lacks RX or TX"));
+        }
+
+        // TX's configuration descriptor comes after its value.
+        let mut request = vec![FIND_INFORMATION_REQUEST];
+        request.extend((self.tx + 1).to_le_bytes());
+        request.extend(end.to_le_bytes());
+        let response = self.request(&request)?;
+        let cccd = match response.get(1) {
+            // Handles with 16-bit UUIDs.
+            Some(1) if response[0] != ERROR_RESPONSE => response[2..]
+                .chunks_exact(4)
+                .find(|entry| {
+                    u16::from_le_bytes([entry[2], entry[3]]) == CLIENT_CHARACTERISTIC_CONFIGURATION
+                })
+                .map(|entry| u16::from_le_bytes([entry[0], entry[1]])),
+            _ => None,
+        }
+        .ok_or_else(|| io::Error::other("the board's TX can't be subscribed to"))?;
+
+        let mut request = vec![WRITE_REQUEST];
+        request.extend(cccd.to_le_bytes());
+        request.extend(1u16.to_le_bytes());
+        let response = self.request(&request)?;
+        if response[0] == ERROR_RESPONSE {
+            return Err(io::Error::other("the board refused notifications"));
+        }
+        Ok(())
+    }
+
+    /// Sends `request` and returns its response. A search that found
+    /// nothing is answered with an error response, which is returned; any
+    /// other refusal is an error.
+    fn request(&mut self, request: &[u8]) -> io::Result<Vec<u8>> {
+        self.socket.write_all(request)?;
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let mut pdu = [0u8; MTU as usize];
+        while Instant::now() < deadline {
+            let len = match self.socket.read(&mut pdu) {
+                Ok(0) => return Err(disconnected()),
+                Ok(len) => len,
+                Err(err) if timed_out(&err) => continue,
+                Err(err) => return Err(err),
+            };
+            let response = &pdu[..len];
+            match response[0] {
+                NOTIFICATION => continue,
+                ERROR_RESPONSE
+                    if response.get(1) == Some(&request[0])
+                        && response.get(4) != Some(&ATTRIBUTE_NOT_FOUND) =>
+                {
+                    return Err(io::Error::other(format!(
+                        "the board refused request 0x{:02x} (error 0x{:02x})",
+                        request[0],
+                        response.get(4).copied().unwrap_or(0)
+                    )))
+                }
+                ERROR_RESPONSE => return Ok(response.to_vec()),
+                // Each response's opcode is its request's plus one.
+                opcode if opcode == request[0] + 1 => return Ok(response.to_vec()),
+                _ => continue,
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the board didn't answer",
+        ))
+    }
+}
+
+impl Read for Link {
+    /// Reads the bytes of the next notification, timing out after a short
+    /// while without one, as the serial port does.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            let mut pdu = [0u8; MTU as usize];
+            let deadline = Instant::now() + READ_TIMEOUT;
+            loop {
+                let len = match self.socket.read(&mut pdu) {
+                    Ok(0) => return Err(disconnected()),
+                    Ok(len) => len,
+                    Err(err) if timed_out(&err) => {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "no notification"))
+                    }
+                    Err(err) => return Err(err),
+                };
+                if len >= 3
+                    && pdu[0] == NOTIFICATION
+                    && u16::from_le_bytes([pdu[1], pdu[2]]) == self.tx
+                {
+                    self.pending.extend(&pdu[3..len]);
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "no notification"));
+                }
+            }
+        }
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Link {
+    /// Writes as much of `buf` to RX as one write command carries.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.value_len);
+        let mut pdu = vec![WRITE_COMMAND];
+        pdu.extend(self.rx.to_le_bytes());
+        pdu.extend(&buf[..len]);
+        self.socket.write_all(&pdu)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `AA:BB:CC:DD:EE:FF` as the kernel has it, lowest byte first.
+fn parse_address(address: &str) -> Option<[u8; 6]> {
+    let mut bdaddr = [0u8; 6];
+    let mut parts = address.split(':');
+    for byte in bdaddr.iter_mut().rev() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().is_none().then_some(bdaddr)
+}
+
+/// An L2CAP socket on the ATT channel, connected to the board at `bdaddr`,
+/// with reads that time out.
+fn open_socket(bdaddr: [u8; 6]) -> io::Result<File> {
+    let fd = unsafe {
+        ffi::socket(
+            ffi::AF_BLUETOOTH,
+            ffi::SOCK_SEQPACKET | ffi::SOCK_CLOEXEC,
+            ffi::BTPROTO_L2CAP,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let address = |bdaddr, bdaddr_type| ffi::sockaddr_l2 {
+        l2_family: ffi::AF_BLUETOOTH as u16,
+        l2_psm: 0,
+        l2_bdaddr: bdaddr,
+        l2_cid: ffi::ATT_CID.to_le(),
+        l2_bdaddr_type: bdaddr_type,
+    };
+    let len = mem::size_of::<ffi::sockaddr_l2>() as u32;
+    let local = address([0; 6], ffi::BDADDR_LE_PUBLIC);
+    if unsafe { ffi::bind(fd, &local, len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let remote = address(bdaddr, ffi::BDADDR_LE_RANDOM);
+    if unsafe { ffi::connect(fd, &remote, len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let timeout = ffi::timeval {
+        tv_sec: READ_TIMEOUT.as_secs() as c_long,
+        tv_usec: READ_TIMEOUT.subsec_micros() as c_long,
+    };
+    let set = unsafe {
+        ffi::setsockopt(
+            fd,
+            ffi::SOL_SOCKET,
+            ffi::SO_RCVTIMEO,
+            &timeout as *const ffi::timeval as *const c_void,
+            mem::size_of::<ffi::timeval>() as u32,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(File::from(socket))
+}
+
+fn timed_out(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "the board disconnected")
+}
+
+/// The parts of the socket API used above, as Linux has them.
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::ffi::{c_int, c_long, c_void};
+
+    pub const AF_BLUETOOTH: c_int = 31;
+    pub const SOCK_SEQPACKET: c_int = 5;
+    pub const SOCK_CLOEXEC: c_int = 0o2000000;
+    pub const BTPROTO_L2CAP: c_int = 0;
+    pub const SOL_SOCKET: c_int = 1;
+    pub const SO_RCVTIMEO: c_int = 20;
+    /// The fixed L2CAP channel ATT runs on.
+    pub const ATT_CID: u16 = 4;
+    pub const BDADDR_LE_PUBLIC: u8 = 1;
+    pub const BDADDR_LE_RANDOM: u8 = 2;
+
+    #[repr(C)]
+    pub struct sockaddr_l2 {
+        pub l2_family: u16,
+        pub l2_psm: u16,
+        pub l2_bdaddr: [u8; 6],
+        pub l2_cid: u16,
+        pub l2_bdaddr_type: u8,
+    }
+
+    #[repr(C)]
+    pub struct timeval {
+        pub tv_sec: c_long,
+        pub tv_usec: c_long,
+    }
+
+    extern "C" {
+        pub fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+        pub fn bind(fd: c_int, address: *const sockaddr_l2, len: u32) -> c_int;
+        pub fn connect(fd: c_int, address: *const sockaddr_l2, len: u32) -> c_int;
+        pub fn setsockopt(
+            fd: c_int,
+            level: c_int,
+            name: c_int,
+            value: *const c_void,
+            len: u32,
+        ) -> c_int;
+    }
+}
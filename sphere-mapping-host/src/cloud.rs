@@ -0,0 +1,491 @@
+//! A window showing raw fields as a turning 3D point cloud, with the
+//! ellipsoid fitted to them (see [`ellipsoid`]) drawn
+//! over it, so the distortions a calibration corrects can be seen while
+//! its samples are collected: hard iron moves the cloud off the origin,
+//! soft iron squashes it from a sphere into an ellipsoid.
+//!
+//! Raw fields come from a burst capture's dump, taken with the Capture
+//! button as with `fit`, and from the board's samples, which are calibrated,
+//! with the calibration undone. That takes the calibration the board is
+//! using, from its last `Calibration:` line or `--calibration`; until one
+//! is known, samples are left out. Everything is drawn in the frame
+//! calibrations are fitted in, East, North and Up from the origin.
+//!
+//! The fit is redone as fields arrive, and a plot below the cloud follows
+//! its residual and how far its centre is from the latest fit's as the
+//! fields add up (see [`Convergence`]). Once the last quarter of the fields
+//! has hardly moved the fit, it has settled, and collecting more won't
+//! change it much.
+//!
+//! The cloud turns on its own until dragged round by hand.
+
+use std::collections::VecDeque;
+use std::f32::consts::{FRAC_PI_2, TAU};
+use std::io;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use eframe::egui::{self, Align2, Color32, FontId, Painter, Pos2, Rect, Sense, Stroke};
+use egui_plot::{Legend, Line, Plot, PlotPoints};
+use sphere_mapping_core::calibration::{
+    measurement_to_enu, uncalibrated_measurement, Calibration, Measurement,
+};
+use sphere_mapping_core::capture::{parse_captured, MAX_CAPTURE_S};
+use sphere_mapping_core::ellipsoid::{self, Fit, FitError};
+use sphere_mapping_core::stream::Packet;
+
+use crate::gui::{self, Event};
+use crate::source::Source;
+
+/// Most fields kept, the oldest going first.
+pub const MAX_POINTS: usize = 20_000;
+/// Shortest time between refits as fields arrive.
+const REFIT_INTERVAL: Duration = Duration::from_millis(250);
+/// How fast the cloud turns on its own, in radians a second.
+const SPIN_RATE: f32 = 0.4;
+/// Fields there have to be before the fit can be said to have settled.
+const SETTLING_FIELDS: usize = 200;
+/// Share of the fields, the latest, whose effect on the fit says whether
+/// it has settled.
+const SETTLING_SHARE: f64 = 0.25;
+/// Most they can move the centre, as a share of the field's strength, and
+/// change the residual, as a share of itself, for the fit to have settled.
+const SETTLED_CENTRE: f64 = 0.01;
+const SETTLED_RESIDUAL: f64 = 0.05;
+/// Height of the plot of the fit settling.
+const CONVERGENCE_HEIGHT: f32 = 140.;
+/// Lines of latitude and longitude drawn on the ellipsoid.
+const PARALLELS: usize = 7;
+const MERIDIANS: usize = 12;
+/// Points along each of them.
+const SEGMENTS: usize = 48;
+
+const POINT_COLOR: Color32 = Color32::from_rgb(90, 170, 255);
+const ELLIPSOID_COLOR: Color32 = Color32::from_rgb(255, 170, 60);
+const AXIS_COLORS: [Color32; 3] = [
+    Color32::from_rgb(230, 80, 80),
+    Color32::from_rgb(80, 200, 80),
+    Color32::from_rgb(110, 130, 255),
+];
+
+pub struct CloudApp {
+    events: Receiver<Event>,
+    commands: Sender<String>,
+    live: bool,
+    /// Raw fields, in the sensor's own axes.
+    raw: VecDeque<Measurement>,
+    /// The calibration the board's samples have had applied.
+    calibration: Option<Calibration>,
+    fit: Option<Result<Fit, FitError>>,
+    fitted: Option<Instant>,
+    /// Whether there are fields the fit hasn't seen.
+    stale: bool,
+    /// Fields added since the last clear, including any since dropped.
+    added: usize,
+    convergence: Convergence,
+    orbit: Orbit,
+    status: String,
+}
+
+/// Which way the cloud is looked at.
+pub struct Orbit {
+    /// Turn about Up and tilt towards it of the view, in radians.
+    yaw: f32,
+    elevation: f32,
+    pub spin: bool,
+}
+
+/// Opens the window and shows the raw fields from `source` in it until it
+/// is closed, undoing `calibration` on its samples until the board reports
+/// another.
+pub fn run(name: String, source: Source, calibration: Option<Calibration>) -> io::Result<()> {
+    let live = matches!(source, Source::Live { .. });
+    let status = format!("Reading {name}");
+    gui::show(&name, source, move |reader| CloudApp {
+        events: reader.events,
+        commands: reader.commands,
+        live,
+        raw: VecDeque::new(),
+        calibration,
+        fit: None,
+        fitted: None,
+        stale: false,
+        added: 0,
+        convergence: Convergence::default(),
+        orbit: Orbit::default(),
+        status,
+    })
+}
+
+impl CloudApp {
+    fn collect(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                Event::Packet(Packet::Sample(sample)) => {
+                    if let Some(calibration) = &self.calibration {
+                        self.add(uncalibrated_measurement(sample.field, calibration));
+                    }
+                }
+                Event::Packet(Packet::Line(line)) => {
+                    if let Some(field) = parse_captured(&line) {
+                        self.add(field);
+                    } else if let Some(calibration) = Calibration::parse(&line) {
+                        self.calibration = Some(calibration);
+                    } else if line == "Capture: done" || line.starts_with("Warning:") {
+                        self.status = line;
+                    }
+                }
+                Event::Packet(_) => {}
+                Event::Ended => self.status = "Replay finished".into(),
+                Event::Failed(err) => self.status = format!("Stopped: {err}"),
+            }
+        }
+    }
+
+    fn add(&mut self, field: Measurement) {
+        if self.raw.len() == MAX_POINTS {
+            self.raw.pop_front();
+        }
+        self.raw.push_back(field);
+        self.added += 1;
+        self.stale = true;
+    }
+
+    fn refit(&mut self) {
+        if !self.stale || self.fitted.is_some_and(|at| at.elapsed() < REFIT_INTERVAL) {
+            return;
+        }
+        let fit = ellipsoid::fit(self.raw.make_contiguous());
+        if let Ok(fit) = fit {
+            self.convergence.push(self.added, fit);
+        }
+        self.fit = Some(fit);
+        self.fitted = Some(Instant::now());
+        self.stale = false;
+    }
+
+    fn controls(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            let capture = ui.add_enabled(
+                self.live,
+                egui::Button::new(format!("Capture {MAX_CAPTURE_S} s")),
+            );
+            if capture.clicked() {
+                self.status = match self.commands.send(format!("SCAP {MAX_CAPTURE_S}")) {
+                    Ok(()) => "Capturing: turn the board through every orientation".into(),
+                    Err(_) => "The board has gone".into(),
+                };
+            }
+            if ui.button("Clear").clicked() {
+                self.raw.clear();
+                self.fit = None;
+                self.stale = false;
+                self.added = 0;
+                self.convergence.clear();
+            }
+            ui.checkbox(&mut self.orbit.spin, "Spin");
+        });
+        let fit = match &self.fit {
+            Some(Ok(fit)) => {
+                let Calibration { center, .. } = fit.calibration;
+                let [rx, ry, rz] = fit.radii;
+                format!(
+                    "Centre {}, {}, {} nT, radii {rx:.0}, {ry:.0}, {rz:.0} nT, \
+                     residual {:.0} nT RMS",
+                    center.x, center.y, center.z, fit.residual_nt
+                )
+            }
+            Some(Err(err)) => format!("No fit: {err}"),
+            None => "No fit yet".into(),
+        };
+        ui.label(format!("{} fields. {fit}", self.raw.len()));
+        if let Some((settled, summary)) = self.convergence.summary() {
+            match settled {
+                true => ui.colored_label(Color32::LIGHT_GREEN, summary),
+                false => ui.label(summary),
+            };
+        }
+        match self.calibration {
+            Some(_) => ui.label(&self.status),
+            None => ui.label(format!(
+                "{}. Samples are left out until the board's calibration is known.",
+                self.status
+            )),
+        };
+    }
+}
+
+impl eframe::App for CloudApp {
+    fn ui(&mut self, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
+        self.collect();
+        self.refit();
+        egui::Panel::top("controls").show(ui, |ui| self.controls(ui));
+        egui::Panel::bottom("convergence").show(ui, |ui| self.convergence.plot(ui));
+        egui::CentralPanel::default().show(ui, |ui| {
+            let fit = self.fit.as_ref().and_then(|fit| fit.as_ref().ok());
+            self.orbit.draw(ui, self.raw.make_contiguous(), fit);
+        });
+    }
+}
+
+impl Default for Orbit {
+    fn default() -> Orbit {
+        Orbit {
+            yaw: 0.6,
+            elevation: 0.35,
+            spin: true,
+        }
+    }
+}
+
+impl Orbit {
+    /// Draws `raw` fields, with `fit` over them if there is one, filling
+    /// `ui`, and returns where they were drawn, to draw more over.
+    pub fn draw(
+        &mut self,
+        ui: &mut egui::Ui,
+        raw: &[Measurement],
+        fit: Option<&Fit>,
+    ) -> (Painter, View) {
+        let (response, painter) = ui.allocate_painter(ui.available_size(), Sense::drag());
+        if response.dragged() {
+            let delta = response.drag_delta();
+            self.spin = false;
+            self.yaw -= delta.x * 0.01;
+            self.elevation = (self.elevation + delta.y * 0.01).clamp(-FRAC_PI_2, FRAC_PI_2);
+        } else if self.spin {
+            self.yaw = (self.yaw + SPIN_RATE * ui.input(|input| input.stable_dt)) % TAU;
+            ui.ctx().request_repaint();
+        }
+
+        let points: Vec<[f32; 3]> = raw
+            .iter()
+            .map(|&field| {
+                let enu = measurement_to_enu(field);
+                [enu.x as f32, enu.y as f32, enu.z as f32]
+            })
+            .collect();
+        let center = match fit {
+            Some(fit) => {
+                let center = fit.calibration.center;
+                [center.x as f32, center.y as f32, center.z as f32]
+            }
+            None => mean(&points),
+        };
+        // Big enough for the cloud and the origin, so an offset shows.
+        let extent = points
+            .iter()
+            .chain([&[0.; 3]])
+            .map(|point| distance(point, &center))
+            .fold(1., f32::max);
+        let view = View {
+            rect: response.rect,
+            center,
+            scale: 0.45 * response.rect.width().min(response.rect.height()) / extent,
+            yaw: self.yaw,
+            elevation: self.elevation,
+        };
+
+        let axis_len = 0.3 * extent;
+        for (axis, (name, color)) in ["E", "N", "U"].into_iter().zip(AXIS_COLORS).enumerate() {
+            let mut end = [0.; 3];
+            end[axis] = axis_len;
+            let (end, _) = view.project(end);
+            painter.line_segment([view.project([0.; 3]).0, end], Stroke::new(1.5, color));
+            painter.text(
+                end,
+                Align2::CENTER_CENTER,
+                name,
+                FontId::proportional(13.),
+                color,
+            );
+        }
+
+        for point in &points {
+            let (pos, depth) = view.project(*point);
+            // Nearer points brighter, so the cloud reads as a solid.
+            let alpha = (150. + 100. * depth.clamp(-1., 1.)) as u8;
+            let color = Color32::from_rgba_unmultiplied(
+                POINT_COLOR.r(),
+                POINT_COLOR.g(),
+                POINT_COLOR.b(),
+                alpha,
+            );
+            painter.circle_filled(pos, 1.5, color);
+        }
+
+        if let Some(fit) = fit {
+            let stroke = Stroke::new(1., ELLIPSOID_COLOR.gamma_multiply(0.8));
+            let on_ellipsoid = |latitude: f32, longitude: f32| {
+                let [rx, ry, rz] = fit.radii.map(|radius| radius as f32);
+                let point = [
+                    center[0] + rx * latitude.cos() * longitude.cos(),
+                    center[1] + ry * latitude.cos() * longitude.sin(),
+                    center[2] + rz * latitude.sin(),
+                ];
+                view.project(point).0
+            };
+            for parallel in 1..=PARALLELS {
+                let latitude = (parallel as f32 / (PARALLELS + 1) as f32 - 0.5) * TAU / 2.;
+                let line = (0..=SEGMENTS)
+                    .map(|i| on_ellipsoid(latitude, i as f32 / SEGMENTS as f32 * TAU))
+                    .collect();
+                painter.line(line, stroke);
+            }
+            for meridian in 0..MERIDIANS {
+                let longitude = meridian as f32 / MERIDIANS as f32 * TAU;
+                let line = (0..=SEGMENTS)
+                    .map(|i| on_ellipsoid((i as f32 / SEGMENTS as f32 - 0.5) * TAU / 2., longitude))
+                    .collect();
+                painter.line(line, stroke);
+            }
+            // The hard-iron offset, from the origin to the centre.
+            painter.line_segment(
+                [view.project([0.; 3]).0, view.project(center).0],
+                Stroke::new(1.5, ELLIPSOID_COLOR),
+            );
+        }
+        (painter, view)
+    }
+}
+
+/// The fits made as fields arrived, to see the fit settle down and stop
+/// collecting once more fields no longer change it.
+#[derive(Default)]
+pub struct Convergence {
+    /// Each fit, with how many fields had been added when it was made.
+    fits: Vec<(usize, Fit)>,
+}
+
+/// How far a fit has moved over the last [`SETTLING_SHARE`] of its fields.
+struct Settling {
+    /// How far its centre moved, in nT.
+    centre_nt: f64,
+    /// How much its residual changed, as a share of the residual now.
+    residual: f64,
+}
+
+impl Convergence {
+    pub fn push(&mut self, added: usize, fit: Fit) {
+        self.fits.push((added, fit));
+    }
+
+    pub fn clear(&mut self) {
+        self.fits.clear();
+    }
+
+    /// How far the latest fit has moved from the one made when there were
+    /// a [`SETTLING_SHARE`] fewer fields, or `None` before there have been
+    /// enough fields to tell.
+    fn settling(&self) -> Option<Settling> {
+        let &(added, latest) = self.fits.last()?;
+        if added < SETTLING_FIELDS {
+            return None;
+        }
+        let before = (added as f64 * (1. - SETTLING_SHARE)) as usize;
+        let then = self.fits.iter().rev().find(|(at, _)| *at <= before)?.1;
+        Some(Settling {
+            centre_nt: centre_distance(&then, &latest),
+            residual: (latest.residual_nt - then.residual_nt).abs() / latest.residual_nt.max(1.),
+        })
+    }
+
+    /// Whether the fit has settled, and by how much it moved, to show
+    /// beside the plot.
+    pub fn summary(&self) -> Option<(bool, String)> {
+        let settling = self.settling()?;
+        let radius = self.fits.last()?.1.calibration.radius.max(1) as f64;
+        let settled =
+            settling.centre_nt / radius < SETTLED_CENTRE && settling.residual < SETTLED_RESIDUAL;
+        let moved = format!(
+            "The last {:.0}% of the fields moved the centre {:.0} nT and the residual {:.0}%",
+            SETTLING_SHARE * 100.,
+            settling.centre_nt,
+            settling.residual * 100.
+        );
+        Some(match settled {
+            true => (
+                true,
+                format!("{moved}: settled, so more fields won't change the fit much"),
+            ),
+            false => (false, format!("{moved}: still settling")),
+        })
+    }
+
+    /// Plots each fit's residual, and how far its centre is from the
+    /// latest's, against the fields there were.
+    pub fn plot(&self, ui: &mut egui::Ui) {
+        let Some(&(_, latest)) = self.fits.last() else {
+            ui.label("The fit's residual will be plotted here as it settles");
+            return;
+        };
+        let residual: PlotPoints = self
+            .fits
+            .iter()
+            .map(|(added, fit)| [*added as f64, fit.residual_nt])
+            .collect();
+        let centre: PlotPoints = self
+            .fits
+            .iter()
+            .map(|(added, fit)| [*added as f64, centre_distance(fit, &latest)])
+            .collect();
+        Plot::new("convergence")
+            .height(CONVERGENCE_HEIGHT)
+            .legend(Legend::default())
+            .x_axis_label("fields")
+            .y_axis_label("nT")
+            .show(ui, |plot_ui| {
+                plot_ui.line(Line::new("Residual RMS", residual));
+                plot_ui.line(Line::new("Centre from the latest", centre));
+            });
+    }
+}
+
+/// How far apart `a`'s and `b`'s centres are, in nT.
+fn centre_distance(a: &Fit, b: &Fit) -> f64 {
+    let (a, b) = (a.calibration.center, b.calibration.center);
+    [a.x - b.x, a.y - b.y, a.z - b.z]
+        .map(|axis| (axis as f64).powi(2))
+        .iter()
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// How the cloud is looked at: from a turn of `yaw` about Up and tilted
+/// `elevation` towards it, looking at `center`, `scale` pixels to the nT.
+pub struct View {
+    rect: Rect,
+    center: [f32; 3],
+    scale: f32,
+    yaw: f32,
+    elevation: f32,
+}
+
+impl View {
+    /// Where `point` lands in the window, and how near it is, from -1 at
+    /// the back of the view to 1 at the front.
+    pub fn project(&self, point: [f32; 3]) -> (Pos2, f32) {
+        let [x, y, z] = [0, 1, 2].map(|i| point[i] - self.center[i]);
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let across = x * cos_yaw - y * sin_yaw;
+        let away = x * sin_yaw + y * cos_yaw;
+        let (sin_el, cos_el) = self.elevation.sin_cos();
+        let up = z * cos_el + away * sin_el;
+        let depth = z * sin_el - away * cos_el;
+        let half = 0.5 * self.rect.width().min(self.rect.height());
+        let pos = self.rect.center() + egui::vec2(across, -up) * self.scale;
+        (pos, depth * self.scale / half)
+    }
+}
+
+fn mean(points: &[[f32; 3]]) -> [f32; 3] {
+    if points.is_empty() {
+        return [0.; 3];
+    }
+    let count = points.len() as f32;
+    [0, 1, 2].map(|i| points.iter().map(|point| point[i]).sum::<f32>() / count)
+}
+
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (0..3).map(|i| (a[i] - b[i]).powi(2)).sum::<f32>().sqrt()
+}
@@ -0,0 +1,85 @@
+//! A connection to the board for other applications, so they can take its
+//! samples and send it commands without running the CLI.
+//!
+//! [`Connection`] is a [`Stream`] of [`Frame`]s, read and decoded on a
+//! thread of its own, so it can be awaited on whatever executor the
+//! application has: `connection.next().await` with `futures`'
+//! `StreamExt`, or `tokio-stream`'s. The stream ends after the port fails,
+//! with the error. Commands are written straight to the port, being a few
+//! bytes each. Dropping the connection stops the thread and closes the
+//! port.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+
+use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures_core::Stream;
+use serialport::SerialPort;
+use sphere_mapping_core::stream::Decoder;
+
+use crate::frame::Frame;
+use crate::port;
+
+pub struct Connection {
+    name: String,
+    port: Box<dyn SerialPort>,
+    frames: UnboundedReceiver<io::Result<Frame>>,
+}
+
+impl Connection {
+    /// Opens `port`, or the board's port if it is left out (see
+    /// [`port::detect`]), at `baud`, which is the firmware's
+    /// [`BAUD_RATE`](sphere_mapping_core::config::BAUD_RATE) unless it was
+    /// built with another.
+    pub fn open(port: Option<&str>, baud: u32) -> io::Result<Connection> {
+        let (name, port) = port::connect(port.map(str::to_string), baud)?;
+        let reader = port.try_clone()?;
+        let (sender, frames) = mpsc::unbounded();
+        thread::spawn(move || read(reader, sender));
+        Ok(Connection { name, port, frames })
+    }
+
+    /// The name of the port the board is on.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sends a command, such as `SCAP 20`, without its line ending. What
+    /// the board makes of it comes back in the stream.
+    pub fn send_command(&mut self, command: &str) -> io::Result<()> {
+        self.port.write_all(format!("{command}\r").as_bytes())
+    }
+}
+
+impl Stream for Connection {
+    type Item = io::Result<Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.frames).poll_next(cx)
+    }
+}
+
+/// Decodes what arrives on `port` until it fails or the connection is
+/// dropped.
+fn read(mut port: Box<dyn SerialPort>, frames: UnboundedSender<io::Result<Frame>>) {
+    let mut decoder = Decoder::new();
+    let mut packets = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !frames.is_closed() {
+        match port.read(&mut buffer) {
+            Ok(len) => decoder.push(&buffer[..len], &mut packets),
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) => {
+                let _ = frames.unbounded_send(Err(err));
+                return;
+            }
+        }
+        for packet in packets.drain(..) {
+            if frames.unbounded_send(Ok(packet.into())).is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! Samples logged to CSV, one row each, in files named after when they were
+//! started, `<prefix>-<YYYYMMDD>-<HHMMSS>.csv` in UTC. A new file is started
+//! once the current one reaches a size or an age, so a log left running for
+//! days stays in pieces that are easy to open and to throw away.
+//!
+//! Each row has when the host received the sample, in UTC to the
+//! millisecond, beside the board's own timestamp, then the calibrated field
+//! in nT, the acceleration in mg and the gyro's rate in deg/s, left empty
+//! without a gyro:
+//!
+//! `host_time,timestamp_us,gx,gy,gz,ax,ay,az,rx,ry,rz`
+//!
+//! A session log of several boards starts each row with the board it came
+//! from (see [`CsvLog::session`]), the rows of all of them in the order
+//! they were received:
+//!
+//! `device,host_time,timestamp_us,gx,gy,gz,ax,ay,az,rx,ry,rz`
+//!
+//! Beside each CSV file is a metadata file of the same name ending
+//! `.meta`, so a recording can still be made sense of long after: which
+//! version of the host tool wrote it, from which port, and the lines the
+//! board describes itself with, the boot banner's `Version:`, `Reset:`,
+//! `Boot:`, `Calibration:` and `Settings:`, and the `Calibration:` and
+//! `Settings:` lines it sends again when they change. It starts with the
+//! latest of each known when the CSV file was started, and has each sent
+//! after added as it arrives, one to a line after when the host received
+//! it, in the rows' format, and in a session log the board's device:
+//!
+//! `<host_time> [<device>] <line>`
+//!
+//! The board sends its banner only when it boots, so one already running
+//! when the log starts has nothing to say until it is reset.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sphere_mapping_core::calibration::Measurement;
+use sphere_mapping_core::device::Acceleration;
+use sphere_mapping_core::settings::LoggedSample;
+
+pub const HEADER: &str = "host_time,timestamp_us,gx,gy,gz,ax,ay,az,rx,ry,rz";
+pub const SESSION_HEADER: &str = "device,host_time,timestamp_us,gx,gy,gz,ax,ay,az,rx,ry,rz";
+
+/// How the lines from the board that go in the metadata file start.
+const DESCRIPTIONS: [&str; 5] = ["Version:", "Reset:", "Boot:", "Calibration:", "Settings:"];
+
+/// When to start a new file; zero turns a limit off.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub max_age: Duration,
+}
+
+struct Current {
+    out: File,
+    meta: File,
+    bytes: u64,
+    started: Instant,
+}
+
+/// The file being written, opened with the first sample after the last
+/// one was closed.
+pub struct CsvLog {
+    dir: PathBuf,
+    prefix: String,
+    rotation: Rotation,
+    /// Whether rows start with the board they came from.
+    session: bool,
+    /// The latest line of each kind describing the host and the boards,
+    /// for the start of each metadata file.
+    descriptions: Vec<Description>,
+    current: Option<Current>,
+}
+
+struct Description {
+    received: SystemTime,
+    device: Option<String>,
+    line: String,
+}
+
+impl Description {
+    /// Whether `self` and `other` are the same kind of line from the same
+    /// board, the later replacing the earlier.
+    fn replaces(&self, other: &Description) -> bool {
+        let kind = |line: &str| line.split(':').next().unwrap_or_default().to_string();
+        self.device == other.device && kind(&self.line) == kind(&other.line)
+    }
+
+    /// The description's line of a metadata file.
+    fn row(&self) -> String {
+        match &self.device {
+            Some(device) => format!("{} {device} {}\n", row_time(self.received), self.line),
+            None => format!("{} {}\n", row_time(self.received), self.line),
+        }
+    }
+}
+
+impl CsvLog {
+    pub fn new(dir: PathBuf, prefix: String, rotation: Rotation) -> CsvLog {
+        CsvLog {
+            dir,
+            prefix,
+            rotation,
+            session: false,
+            descriptions: vec![Description {
+                received: SystemTime::now(),
+                device: None,
+                line: format!("Host: sphere-mapping-host {}", env!("CARGO_PKG_VERSION")),
+            }],
+            current: None,
+        }
+    }
+
+    /// A log of several boards, whose rows start with the board each came
+    /// from.
+    pub fn session(dir: PathBuf, prefix: String, rotation: Rotation) -> CsvLog {
+        CsvLog {
+            session: true,
+            ..CsvLog::new(dir, prefix, rotation)
+        }
+    }
+
+    /// Writes a row for `sample`, received now. Returns the path of the
+    /// file if it had to be started for it.
+    pub fn write(&mut self, sample: &LoggedSample) -> io::Result<Option<PathBuf>> {
+        self.write_row(None, sample)
+    }
+
+    /// Writes a row for `sample`, received now from `device`, to a session
+    /// log, like [`write`](CsvLog::write).
+    pub fn write_device(
+        &mut self,
+        device: &str,
+        sample: &LoggedSample,
+    ) -> io::Result<Option<PathBuf>> {
+        self.write_row(Some(device), sample)
+    }
+
+    /// Keeps `line` from the board, from `device` in a session log, for the
+    /// metadata file if it is one that describes the board.
+    pub fn line(&mut self, device: Option<&str>, line: &str) -> io::Result<()> {
+        match DESCRIPTIONS.iter().any(|start| line.starts_with(start)) {
+            true => self.describe(device, line),
+            false => Ok(()),
+        }
+    }
+
+    /// Keeps `line`, of the form `<kind>: <description>`, for the metadata
+    /// file, in place of any before it of the same kind from `device`.
+    pub fn describe(&mut self, device: Option<&str>, line: &str) -> io::Result<()> {
+        let description = Description {
+            received: SystemTime::now(),
+            device: device.map(str::to_string),
+            line: line.to_string(),
+        };
+        if let Some(current) = &mut self.current {
+            current.meta.write_all(description.row().as_bytes())?;
+        }
+        self.descriptions
+            .retain(|earlier| !description.replaces(earlier));
+        self.descriptions.push(description);
+        Ok(())
+    }
+
+    fn write_row(
+        &mut self,
+        device: Option<&str>,
+        sample: &LoggedSample,
+    ) -> io::Result<Option<PathBuf>> {
+        let now = SystemTime::now();
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|current| self.is_full(current))
+        {
+            self.current = None;
+        }
+        let mut started = None;
+        let current = match &mut self.current {
+            Some(current) => current,
+            None => {
+                let path = self
+                    .dir
+                    .join(format!("{}-{}.csv", self.prefix, file_time(now)));
+                let header = match self.session {
+                    true => SESSION_HEADER,
+                    false => HEADER,
+                };
+                let current = Current::create(&path, header, &self.descriptions)?;
+                started = Some(path);
+                self.current.insert(current)
+            }
+        };
+        let LoggedSample {
+            field,
+            accel,
+            timestamp_us,
+            gyro,
+        } = sample;
+        let mut row = match device {
+            Some(device) => format!("{device},"),
+            None => String::new(),
+        };
+        row += &format!(
+            "{},{timestamp_us},{},{},{},{},{},{}",
+            row_time(now),
+            field.x,
+            field.y,
+            field.z,
+            accel.x,
+            accel.y,
+            accel.z
+        );
+        match gyro {
+            Some([rx, ry, rz]) => row += &format!(",{rx:.2},{ry:.2},{rz:.2}\n"),
+            None => row += ",,,\n",
+        }
+        // Unbuffered, so a log cut off by unplugging the board or killing
+        // the tool loses nothing.
+        current.out.write_all(row.as_bytes())?;
+        current.bytes += row.len() as u64;
+        Ok(started)
+    }
+
+    fn is_full(&self, current: &Current) -> bool {
+        let Rotation { max_bytes, max_age } = self.rotation;
+        (max_bytes > 0 && current.bytes >= max_bytes)
+            || (!max_age.is_zero() && current.started.elapsed() >= max_age)
+    }
+}
+
+impl Current {
+    fn create(path: &Path, header: &str, descriptions: &[Description]) -> io::Result<Current> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut out = File::create(path)?;
+        writeln!(out, "{header}")?;
+        let mut meta = File::create(path.with_extension("meta"))?;
+        for description in descriptions {
+            meta.write_all(description.row().as_bytes())?;
+        }
+        Ok(Current {
+            out,
+            meta,
+            bytes: header.len() as u64 + 1,
+            started: Instant::now(),
+        })
+    }
+}
+
+/// The sample in a row of a log file, or `None` for the header or a row
+/// that doesn't parse.
+pub fn parse_row(row: &str) -> Option<LoggedSample> {
+    let mut cells = row.trim_end().split(',').skip(1);
+    let timestamp_us = cells.next()?.parse().ok()?;
+    let mut whole = || cells.next()?.parse::<i32>().ok();
+    let field = Measurement {
+        x: whole()?,
+        y: whole()?,
+        z: whole()?,
+    };
+    let accel = Acceleration {
+        x: whole()?,
+        y: whole()?,
+        z: whole()?,
+    };
+    let rates: Vec<&str> = cells.collect();
+    let gyro = match rates.as_slice() {
+        ["", "", ""] => None,
+        [x, y, z] => Some([x.parse().ok()?, y.parse().ok()?, z.parse().ok()?]),
+        _ => return None,
+    };
+    Some(LoggedSample {
+        field,
+        accel,
+        timestamp_us,
+        gyro,
+    })
+}
+
+/// `YYYYMMDD-HHMMSS`, in UTC.
+pub fn file_time(time: SystemTime) -> String {
+    let (date, [hour, minute, second], _) = utc(time);
+    format!(
+        "{:04}{:02}{:02}-{hour:02}{minute:02}{second:02}",
+        date[0], date[1], date[2]
+    )
+}
+
+/// ISO 8601 to the millisecond, in UTC.
+pub fn row_time(time: SystemTime) -> String {
+    let ([year, month, day], [hour, minute, second], millis) = utc(time);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{millis:03}Z")
+}
+
+/// The date, time of day and milliseconds of `time`, in UTC, by the
+/// proleptic Gregorian calendar (Howard Hinnant's `civil_from_days`).
+fn utc(time: SystemTime) -> ([u64; 3], [u64; 3], u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, of_day) = (seconds / 86_400, seconds % 86_400);
+    let clock = [of_day / 3600, of_day / 60 % 60, of_day % 60];
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    ([year, month, day], clock, since_epoch.subsec_millis())
+}
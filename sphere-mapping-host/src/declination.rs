@@ -0,0 +1,353 @@
+//! The declination where the board is, sent to it on connecting so its
+//! heading points at true North without anyone having to look it up.
+//!
+//! Where the board is comes from `--location`, a latitude and longitude or
+//! `ip` to look them up from this computer's public IP address, which
+//! places it to within a city or so: close enough, since the declination
+//! changes by about a degree over a hundred kilometres at most latitudes.
+//!
+//! The declination there, today, comes from the World Magnetic Model:
+//! NOAA's `WMM.COF`, the model's spherical harmonic coefficients and how
+//! they change each year, read from `--wmm`. The field is worked out at sea
+//! level from them as the model's technical report does it, in geocentric
+//! spherical coordinates on the WGS 84 ellipsoid, then turned back to the
+//! geodetic frame, and the declination is the angle of its horizontal part
+//! East of true North.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+
+/// The service the location is looked up from, by IP address, over plain
+/// HTTP.
+const LOOKUP_HOST: &str = "ip-api.com";
+const LOOKUP_PATH: &str = "/json/?fields=status,lat,lon";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// WGS 84's semi-major axis, in km.
+const WGS84_A: f64 = 6378.137;
+/// WGS 84's flattening.
+const WGS84_F: f64 = 1. / 298.257_223_563;
+/// The model's reference radius, in km.
+const MODEL_RADIUS: f64 = 6371.2;
+/// How many years after its epoch a model is meant to be used for.
+const MODEL_LIFE: f64 = 5.;
+
+/// Where the board is, to send it the declination there.
+#[derive(Args, Clone)]
+pub struct DeclinationArgs {
+    /// Where the board is, to send it the declination there on connecting:
+    /// `<lat>,<lon>` in decimal degrees, North and East positive, or `ip`
+    /// to look it up from this computer's IP address.
+    #[arg(long, value_parser = parse_location, allow_hyphen_values = true)]
+    pub location: Option<Location>,
+    /// The World Magnetic Model's coefficients, NOAA's `WMM.COF`.
+    #[arg(long, default_value = "WMM.COF")]
+    pub wmm: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Location {
+    At { lat_deg: f64, lon_deg: f64 },
+    Ip,
+}
+
+fn parse_location(text: &str) -> Result<Location, String> {
+    if text == "ip" {
+        return Ok(Location::Ip);
+    }
+    let (lat, lon) = text.split_once(',').ok_or("expected <lat>,<lon> or ip")?;
+    let lat_deg: f64 = lat.trim().parse().map_err(|_| "latitude isn't a number")?;
+    let lon_deg: f64 = lon.trim().parse().map_err(|_| "longitude isn't a number")?;
+    if !(-90. ..=90.).contains(&lat_deg) || !(-180. ..=180.).contains(&lon_deg) {
+        return Err("latitude is -90 to 90 and longitude -180 to 180".into());
+    }
+    Ok(Location::At { lat_deg, lon_deg })
+}
+
+impl DeclinationArgs {
+    /// The `SDEC` command setting the declination at `--location`, saying
+    /// what it is on stderr, or `None` without a location.
+    pub fn command(&self) -> io::Result<Option<String>> {
+        let Some(location) = self.location else {
+            return Ok(None);
+        };
+        let (lat_deg, lon_deg) = match location {
+            Location::At { lat_deg, lon_deg } => (lat_deg, lon_deg),
+            Location::Ip => locate()?,
+        };
+        let model = Model::read(&self.wmm)?;
+        let year = decimal_year(SystemTime::now());
+        if !(model.epoch..model.epoch + MODEL_LIFE).contains(&year) {
+            eprintln!(
+                "Warning: {} is for {:.0} to {:.0}; a newer one may be out",
+                model.name,
+                model.epoch,
+                model.epoch + MODEL_LIFE
+            );
+        }
+        let declination_deg = model.declination(lat_deg, lon_deg, 0., year);
+        let tenths = (declination_deg * 10.).round() as i16;
+        eprintln!(
+            "Declination at {lat_deg:.3}, {lon_deg:.3}: {declination_deg:+.1}° ({})",
+            model.name
+        );
+        Ok(Some(format!("SDEC {tenths}")))
+    }
+}
+
+/// This computer's latitude and longitude, looked up from its IP address.
+fn locate() -> io::Result<(f64, f64)> {
+    let address = (LOOKUP_HOST, 80)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("{LOOKUP_HOST} has no address")))?;
+    let mut stream = TcpStream::connect_timeout(&address, LOOKUP_TIMEOUT)?;
+    stream.set_read_timeout(Some(LOOKUP_TIMEOUT))?;
+    write!(
+        stream,
+        "GET {LOOKUP_PATH} HTTP/1.0\r\nHost: {LOOKUP_HOST}\r\nConnection: close\r\n\r\n"
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    let number = |name: &str| -> Option<f64> {
+        let after = body.split_once(&format!("\"{name}\":"))?.1;
+        let end = after.find([',', '}']).unwrap_or(after.len());
+        after[..end].trim().parse().ok()
+    };
+    match (number("lat"), number("lon")) {
+        (Some(lat_deg), Some(lon_deg)) => {
+            eprintln!("Located by IP address at {lat_deg:.3}, {lon_deg:.3}");
+            Ok((lat_deg, lon_deg))
+        }
+        _ => Err(io::Error::other(format!(
+            "couldn't locate this computer by its IP address; give --location <lat>,<lon> \
+             instead ({LOOKUP_HOST} replied {body:?})"
+        ))),
+    }
+}
+
+/// The year, with how far through it `time` is as a fraction, near enough:
+/// a day either way moves the field by a tiny fraction of a degree.
+fn decimal_year(time: SystemTime) -> f64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    1970. + since_epoch.as_secs_f64() / 86_400. / 365.2425
+}
+
+/// A spherical harmonic model of the main field, as the World Magnetic
+/// Model's coefficients file gives it.
+struct Model {
+    /// Such as `WMM-2025`.
+    name: String,
+    /// The year the coefficients are for.
+    epoch: f64,
+    /// The highest degree.
+    degree: usize,
+    /// `g`, `h` and their yearly changes in nT, by degree then order.
+    g: Vec<Vec<f64>>,
+    h: Vec<Vec<f64>>,
+    g_dot: Vec<Vec<f64>>,
+    h_dot: Vec<Vec<f64>>,
+}
+
+impl Model {
+    /// Reads a coefficients file: a line with the epoch and the model's
+    /// name, then `n m g h g_dot h_dot` on each line, to a line of 9s.
+    fn read(path: &Path) -> io::Result<Model> {
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {what}", path.display()),
+            )
+        };
+        let text = fs::read_to_string(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "{}: {err}; download the World Magnetic Model's WMM.COF from NOAA \
+                     and give it with --wmm",
+                    path.display()
+                ),
+            )
+        })?;
+        let mut lines = text.lines();
+        let mut header = lines.next().unwrap_or_default().split_whitespace();
+        let epoch: f64 = header
+            .next()
+            .and_then(|epoch| epoch.parse().ok())
+            .ok_or_else(|| invalid("no epoch on the first line"))?;
+        let name = header.next().unwrap_or("WMM").to_string();
+        let mut rows = Vec::new();
+        for line in lines {
+            if line.trim_start().starts_with("9999") {
+                break;
+            }
+            let cells: Vec<&str> = line.split_whitespace().collect();
+            let [n, m, g, h, g_dot, h_dot] = cells[..] else {
+                continue;
+            };
+            let (Ok(n), Ok(m)) = (n.parse::<usize>(), m.parse::<usize>()) else {
+                return Err(invalid(&format!("bad degree or order in {line:?}")));
+            };
+            let values: Vec<f64> = [g, h, g_dot, h_dot]
+                .iter()
+                .map(|value| value.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| invalid(&format!("bad coefficient in {line:?}")))?;
+            if m > n || n == 0 {
+                return Err(invalid(&format!("no such degree and order in {line:?}")));
+            }
+            rows.push((n, m, values));
+        }
+        let degree = rows
+            .iter()
+            .map(|(n, ..)| *n)
+            .max()
+            .ok_or_else(|| invalid("no coefficients"))?;
+        let zeros = vec![vec![0.; degree + 1]; degree + 1];
+        let mut model = Model {
+            name,
+            epoch,
+            degree,
+            g: zeros.clone(),
+            h: zeros.clone(),
+            g_dot: zeros.clone(),
+            h_dot: zeros,
+        };
+        for (n, m, values) in rows {
+            model.g[n][m] = values[0];
+            model.h[n][m] = values[1];
+            model.g_dot[n][m] = values[2];
+            model.h_dot[n][m] = values[3];
+        }
+        Ok(model)
+    }
+
+    /// The declination `height_km` above the ellipsoid at `lat_deg`,
+    /// `lon_deg` in `year`, in degrees, East positive.
+    fn declination(&self, lat_deg: f64, lon_deg: f64, height_km: f64, year: f64) -> f64 {
+        let lat = lat_deg.to_radians();
+        let lon = lon_deg.to_radians();
+        let dt = year - self.epoch;
+
+        // Geodetic to geocentric spherical.
+        let e2 = WGS84_F * (2. - WGS84_F);
+        let rc = WGS84_A / (1. - e2 * lat.sin().powi(2)).sqrt();
+        let p = (rc + height_km) * lat.cos();
+        let z = (rc * (1. - e2) + height_km) * lat.sin();
+        let r = p.hypot(z);
+        let lat_c = (z / r).asin();
+
+        // Schmidt semi-normalised associated Legendre functions of the
+        // colatitude, and their derivatives by it.
+        let (cos_t, sin_t) = (lat_c.sin(), lat_c.cos());
+        let size = self.degree + 1;
+        let mut legendre = vec![vec![0.; size]; size];
+        let mut d_legendre = vec![vec![0.; size]; size];
+        legendre[0][0] = 1.;
+        for n in 1..size {
+            for m in 0..=n {
+                if n == m {
+                    legendre[n][m] = sin_t * legendre[n - 1][m - 1];
+                    d_legendre[n][m] =
+                        sin_t * d_legendre[n - 1][m - 1] + cos_t * legendre[n - 1][m - 1];
+                } else {
+                    let (before, d_before) = match n {
+                        1 => (0., 0.),
+                        _ => (legendre[n - 2][m], d_legendre[n - 2][m]),
+                    };
+                    let k = match n {
+                        1 => 0.,
+                        _ => {
+                            (((n - 1) * (n - 1) - m * m) as f64)
+                                / ((2 * n - 1) * (2 * n - 3)) as f64
+                        }
+                    };
+                    legendre[n][m] = cos_t * legendre[n - 1][m] - k * before;
+                    d_legendre[n][m] =
+                        cos_t * d_legendre[n - 1][m] - sin_t * legendre[n - 1][m] - k * d_before;
+                }
+            }
+        }
+        // From Gauss's normalisation to Schmidt's.
+        let mut schmidt = vec![vec![0.; size]; size];
+        schmidt[0][0] = 1.;
+        for n in 1..size {
+            schmidt[n][0] = schmidt[n - 1][0] * (2 * n - 1) as f64 / n as f64;
+            for m in 1..=n {
+                let twice = if m == 1 { 2. } else { 1. };
+                schmidt[n][m] =
+                    schmidt[n][m - 1] * ((n - m + 1) as f64 * twice / (n + m) as f64).sqrt();
+            }
+        }
+
+        // North and East in the geocentric frame, and down.
+        let (mut north, mut east, mut down) = (0., 0., 0.);
+        for n in 1..size {
+            let scale = (MODEL_RADIUS / r).powi(n as i32 + 2);
+            for m in 0..=n {
+                let g = (self.g[n][m] + dt * self.g_dot[n][m]) * schmidt[n][m];
+                let h = (self.h[n][m] + dt * self.h_dot[n][m]) * schmidt[n][m];
+                let (sin_m, cos_m) = (m as f64 * lon).sin_cos();
+                let along = g * cos_m + h * sin_m;
+                north += scale * along * d_legendre[n][m];
+                east += scale * m as f64 * (g * sin_m - h * cos_m) * legendre[n][m];
+                down -= scale * (n + 1) as f64 * along * legendre[n][m];
+            }
+        }
+        // At the poles the East component's sine cancels out; the model's
+        // report takes the limit, but a board isn't used there.
+        east /= sin_t.max(1e-9);
+        // Back to the geodetic frame.
+        let tilt = lat_c - lat;
+        let north = north * tilt.cos() - down * tilt.sin();
+        east.atan2(north).to_degrees()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The WMM2025 coefficients, as NOAA's `WMM.COF` gives them.
+    fn wmm2025() -> Model {
+        Model::read(&Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/WMM2025.COF")).unwrap()
+    }
+
+    /// The declinations in the WMM2025 technical report's test values: the
+    /// year, the height in km, the latitude, the longitude, and the
+    /// declination to a hundredth of a degree.
+    const TEST_VALUES: [(f64, f64, f64, f64, f64); 12] = [
+        (2025.0, 0., 80., 0., 1.28),
+        (2025.0, 0., 0., 120., -0.16),
+        (2025.0, 0., -80., 240., 68.78),
+        (2025.0, 100., 80., 0., 0.85),
+        (2025.0, 100., 0., 120., -0.15),
+        (2025.0, 100., -80., 240., 68.21),
+        (2027.5, 0., 80., 0., 2.59),
+        (2027.5, 0., 0., 120., -0.24),
+        (2027.5, 0., -80., 240., 68.49),
+        (2027.5, 100., 80., 0., 2.16),
+        (2027.5, 100., 0., 120., -0.23),
+        (2027.5, 100., -80., 240., 67.93),
+    ];
+
+    #[test]
+    fn matches_wmm2025_test_values() {
+        let model = wmm2025();
+        assert_eq!(model.name, "WMM-2025");
+        assert_eq!(model.degree, 12);
+        for (year, height_km, lat_deg, lon_deg, expected) in TEST_VALUES {
+            let declination = model.declination(lat_deg, lon_deg, height_km, year);
+            assert!(
+                (declination - expected).abs() <= 0.01,
+                "{year} {height_km} km {lat_deg}, {lon_deg}: {declination:.3}, not {expected}"
+            );
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! `export`: a recorded log in a format other tools read, so a session can
+//! be fed to attitude estimation code and the benchmarks built around it.
+//! The log, a serial log or one of `log`'s CSV files, is read as a replay
+//! reads it (see [`read_log`](crate::source::read_log)), and each sample
+//! is written out in SI units:
+//!
+//! - `ros-csv`: a row per sample as `rostopic echo -p` writes a
+//!   `sensor_msgs/Imu` topic, with the `sensor_msgs/MagneticField` fields
+//!   alongside, stamped in nanoseconds: the gyro's rate in rad/s, the
+//!   acceleration in m/s² and the field in T. Without a gyro its columns
+//!   are left empty.
+//! - `json`: one object with the units and a `samples` array of
+//!   `{"t", "accel", "gyro", "mag"}`, the time in s, the acceleration in
+//!   m/s², the rate in rad/s, or `null` without a gyro, and the field in
+//!   µT.
+//!
+//! The axes are the board's, as logged, and the field is the calibrated
+//! one. A reset starts the board's clock over, so the time after one
+//! carries on from where the run before it ended, a sample interval
+//! later, and only ever goes forward.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{Args, ValueEnum};
+use sphere_mapping_core::settings::LoggedSample;
+use sphere_mapping_core::stream::Packet;
+
+use crate::source::read_log;
+
+/// Standard gravity, in m/s² per g.
+const STANDARD_GRAVITY: f64 = 9.806_65;
+
+const ROS_HEADER: &str = "%time,field.header.seq,field.header.stamp,field.header.frame_id,\
+field.angular_velocity.x,field.angular_velocity.y,field.angular_velocity.z,\
+field.linear_acceleration.x,field.linear_acceleration.y,field.linear_acceleration.z,\
+field.magnetic_field.x,field.magnetic_field.y,field.magnetic_field.z";
+
+/// The `frame_id` rows are given.
+const FRAME_ID: &str = "microbit";
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    /// A `rostopic echo -p` style CSV of `sensor_msgs/Imu` and
+    /// `sensor_msgs/MagneticField`.
+    RosCsv,
+    /// A JSON object of IMU and magnetometer samples.
+    Json,
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    /// A serial log, whatever the board sent saved to a file, or a `log`
+    /// CSV file.
+    log: PathBuf,
+    #[arg(long, value_enum, default_value_t = Format::RosCsv)]
+    format: Format,
+    /// File to write; stdout if left out.
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+pub fn run(args: ExportArgs) -> io::Result<()> {
+    let packets = read_log(&args.log)?;
+    let samples = continuous(packets.iter().filter_map(|packet| match packet {
+        Packet::Sample(sample) => Some(*sample),
+        _ => None,
+    }));
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    match args.format {
+        Format::RosCsv => write_ros_csv(&mut out, &samples)?,
+        Format::Json => write_json(&mut out, &samples)?,
+    }
+    out.flush()?;
+    if let Some(path) = &args.output {
+        eprintln!("Wrote {} samples to {}", samples.len(), path.display());
+    }
+    Ok(())
+}
+
+/// `samples` with the timestamps after each reset carried on from the run
+/// before it.
+fn continuous(samples: impl Iterator<Item = LoggedSample>) -> Vec<LoggedSample> {
+    let mut out: Vec<LoggedSample> = Vec::new();
+    // Added to the board's timestamps in the current run.
+    let mut offset_us = 0;
+    let mut interval_us = 0;
+    let mut last_us: Option<u64> = None;
+    for mut sample in samples {
+        match last_us {
+            Some(last_us) if sample.timestamp_us < last_us => {
+                let ended_us = out.last().map_or(0, |sample| sample.timestamp_us);
+                offset_us = ended_us + interval_us - sample.timestamp_us;
+            }
+            Some(last_us) => interval_us = sample.timestamp_us - last_us,
+            None => {}
+        }
+        last_us = Some(sample.timestamp_us);
+        sample.timestamp_us += offset_us;
+        out.push(sample);
+    }
+    out
+}
+
+/// The acceleration in m/s², the gyro's rate in rad/s if there is a gyro
+/// and the field in nT.
+fn si(sample: &LoggedSample) -> ([f64; 3], Option<[f64; 3]>, [f64; 3]) {
+    let accel = [sample.accel.x, sample.accel.y, sample.accel.z]
+        .map(|mg| mg as f64 / 1000. * STANDARD_GRAVITY);
+    let gyro = sample
+        .gyro
+        .map(|rates| rates.map(|rate| (rate as f64).to_radians()));
+    let field = [sample.field.x, sample.field.y, sample.field.z].map(|nt| nt as f64);
+    (accel, gyro, field)
+}
+
+fn write_ros_csv(out: &mut impl Write, samples: &[LoggedSample]) -> io::Result<()> {
+    writeln!(out, "{ROS_HEADER}")?;
+    for (seq, sample) in samples.iter().enumerate() {
+        let stamp_ns = sample.timestamp_us * 1000;
+        let (accel, gyro, field) = si(sample);
+        let gyro = match gyro {
+            Some([x, y, z]) => format!("{x},{y},{z}"),
+            None => ",,".into(),
+        };
+        let [ax, ay, az] = accel;
+        let [mx, my, mz] = field.map(|nt| nt / 1e9);
+        writeln!(
+            out,
+            "{stamp_ns},{seq},{stamp_ns},{FRAME_ID},{gyro},{ax},{ay},{az},{mx:e},{my:e},{mz:e}"
+        )?;
+    }
+    Ok(())
+}
+
+fn write_json(out: &mut impl Write, samples: &[LoggedSample]) -> io::Result<()> {
+    writeln!(out, "{{")?;
+    writeln!(
+        out,
+        r#"  "units": {{"t": "s", "accel": "m/s^2", "gyro": "rad/s", "mag": "uT"}},"#
+    )?;
+    write!(out, r#"  "samples": ["#)?;
+    for (i, sample) in samples.iter().enumerate() {
+        let (accel, gyro, field) = si(sample);
+        let mut row = String::new();
+        write!(
+            row,
+            r#"{{"t": {}, "accel": {}, "gyro": "#,
+            sample.timestamp_us as f64 / 1e6,
+            array(accel)
+        )
+        .ok();
+        match gyro {
+            Some(gyro) => row.push_str(&array(gyro)),
+            None => row.push_str("null"),
+        }
+        write!(row, r#", "mag": {}}}"#, array(field.map(|nt| nt / 1000.))).ok();
+        let separator = if i == 0 { "" } else { "," };
+        write!(out, "{separator}\n    {row}")?;
+    }
+    if !samples.is_empty() {
+        writeln!(out)?;
+        write!(out, "  ")?;
+    }
+    writeln!(out, "]")?;
+    writeln!(out, "}}")
+}
+
+/// `values` as a JSON array.
+fn array(values: [f64; 3]) -> String {
+    let [x, y, z] = values;
+    format!("[{x}, {y}, {z}]")
+}
@@ -0,0 +1,163 @@
+//! What the board sends, typed: the [`Packet`]s the stream decodes into,
+//! with the lines and frames an application would otherwise have to parse
+//! itself picked out.
+
+use sphere_mapping_core::calibration::{Calibration, Measurement};
+use sphere_mapping_core::capture::parse_captured;
+use sphere_mapping_core::frame::{KIND_SPHERE_CELL, KIND_SPHERE_DIFF};
+use sphere_mapping_core::settings::LoggedSample;
+use sphere_mapping_core::stream::Packet;
+
+/// One thing the board sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// A sample, whichever output format it came in.
+    Sample(LoggedSample),
+    /// The calibration the board is using, from a `Calibration:` line.
+    Calibration(Calibration),
+    /// A raw field from a burst capture's dump, from a `Captured:` line.
+    Captured(Measurement),
+    /// A `Status:` line's `key=value` fields, in order.
+    Status(Vec<(String, String)>),
+    /// A cell of the sphere map, from a kind 3 frame.
+    SphereCell(SphereCell),
+    /// A cell of the difference between two saved sphere maps, from a kind
+    /// 4 frame.
+    SphereDiff(SphereDiff),
+    /// Any other line, without its line ending.
+    Line(String),
+    /// Any other frame, or one too short for its kind, with its CRC
+    /// checked.
+    Other { kind: u8, payload: Vec<u8> },
+    /// Bytes dropped for not decoding.
+    Corrupt(usize),
+}
+
+impl From<Packet> for Frame {
+    fn from(packet: Packet) -> Frame {
+        match packet {
+            Packet::Sample(sample) => Frame::Sample(sample),
+            Packet::Line(line) => {
+                if let Some(field) = parse_captured(&line) {
+                    Frame::Captured(field)
+                } else if let Some(calibration) = Calibration::parse(&line) {
+                    Frame::Calibration(calibration)
+                } else if let Some(fields) = line.strip_prefix("Status:") {
+                    Frame::Status(parse_status(fields))
+                } else {
+                    Frame::Line(line)
+                }
+            }
+            Packet::Frame { kind, payload } => {
+                let typed = match kind {
+                    KIND_SPHERE_CELL => SphereCell::decode(&payload).map(Frame::SphereCell),
+                    KIND_SPHERE_DIFF => SphereDiff::decode(&payload).map(Frame::SphereDiff),
+                    _ => None,
+                };
+                typed.unwrap_or(Frame::Other { kind, payload })
+            }
+            Packet::Corrupt(bytes) => Frame::Corrupt(bytes),
+        }
+    }
+}
+
+/// One cell of the sphere map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SphereCell {
+    pub index: usize,
+    /// Cells in the whole map.
+    pub cells: usize,
+    /// The unit vector through the cell's middle, in the board's axes.
+    pub direction: [f32; 3],
+    pub count: u32,
+    /// The average, standard deviation, smallest and largest field
+    /// strength in nT, 0 until there are enough samples.
+    pub mean_nt: u32,
+    pub std_dev_nt: u32,
+    pub min_nt: u32,
+    pub max_nt: u32,
+    /// The standard error of the average in nT, infinite before a second
+    /// sample.
+    pub std_error_nt: f32,
+}
+
+impl SphereCell {
+    pub fn decode(payload: &[u8]) -> Option<SphereCell> {
+        let mut fields = Fields(payload);
+        Some(SphereCell {
+            index: fields.u16()? as usize,
+            cells: fields.u16()? as usize,
+            direction: [fields.f32()?, fields.f32()?, fields.f32()?],
+            count: fields.u32()?,
+            mean_nt: fields.u32()?,
+            std_dev_nt: fields.u32()?,
+            min_nt: fields.u32()?,
+            max_nt: fields.u32()?,
+            std_error_nt: fields.f32()?,
+        })
+    }
+}
+
+/// One cell of the difference from one saved sphere map to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SphereDiff {
+    pub index: usize,
+    pub cells: usize,
+    pub direction: [f32; 3],
+    /// The sample count and average field strength in nT before and after.
+    pub count_before: u32,
+    pub mean_before_nt: u32,
+    pub count_after: u32,
+    pub mean_after_nt: u32,
+    /// How much the average grew in nT, 0 unless both have samples.
+    pub difference_nt: i32,
+}
+
+impl SphereDiff {
+    pub fn decode(payload: &[u8]) -> Option<SphereDiff> {
+        let mut fields = Fields(payload);
+        Some(SphereDiff {
+            index: fields.u16()? as usize,
+            cells: fields.u16()? as usize,
+            direction: [fields.f32()?, fields.f32()?, fields.f32()?],
+            count_before: fields.u32()?,
+            mean_before_nt: fields.u32()?,
+            count_after: fields.u32()?,
+            mean_after_nt: fields.u32()?,
+            difference_nt: fields.u32()? as i32,
+        })
+    }
+}
+
+/// The `key=value` fields of a `Status:` line, after its prefix, or of a
+/// `Settings:` line.
+pub fn parse_status(fields: &str) -> Vec<(String, String)> {
+    fields
+        .split(',')
+        .filter_map(|field| field.trim().split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// A payload's little-endian fields, read from the front.
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (field, rest) = self.0.split_first_chunk()?;
+        self.0 = rest;
+        Some(*field)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.take().map(f32::from_le_bytes)
+    }
+}
@@ -0,0 +1,387 @@
+//! A window plotting the samples as they arrive: the calibrated field's
+//! three axes and strength, the heading and the board's pitch and roll
+//! (see [`attitude`](crate::attitude)), against the board's own time.
+//!
+//! The plots follow the latest samples, over a window that can be widened
+//! up to all the history kept. Pausing freezes them where they are, while
+//! samples keep being collected behind them, so they can be dragged and
+//! zoomed, with the mouse, scroll wheel or a box drawn with the right
+//! button, and double-clicked to fit. Export writes all the history kept to
+//! a CSV file. Above them is how the link is doing, its rates and what has
+//! been lost (see [`link`](crate::link)), in yellow once anything has.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use eframe::egui;
+use egui_plot::{Legend, Line, Plot, PlotPoints, Points};
+use sphere_mapping_core::settings::LoggedSample;
+use sphere_mapping_core::stream::Packet;
+
+use crate::attitude::Attitude;
+use crate::csv_log;
+use crate::link::LinkStats;
+use crate::source::Source;
+
+/// Most samples kept, ten minutes at the board's 100 Hz.
+pub const MAX_HISTORY: usize = 60_000;
+
+const EXPORT_HEADER: &str = "t_s,timestamp_us,gx,gy,gz,magnitude_nt,heading_deg,pitch_deg,roll_deg";
+
+/// What the reader thread passes on.
+pub enum Event {
+    Packet(Packet),
+    Ended,
+    Failed(io::Error),
+}
+
+/// The board's end of a window: what it has sent, and a way to send it
+/// commands.
+pub struct Reader {
+    pub events: Receiver<Event>,
+    pub commands: Sender<String>,
+    /// Kept up to date by the reader thread as it reads.
+    pub link: Arc<Mutex<LinkStats>>,
+}
+
+/// A sample and what was worked out from it, at `t_s` along the plots.
+struct Point {
+    t_s: f64,
+    sample: LoggedSample,
+    attitude: Attitude,
+}
+
+/// Keeps the plots' time running on when the board's timestamps go
+/// backwards, as after it resets.
+#[derive(Default)]
+struct Timeline {
+    offset_s: f64,
+    last_us: Option<u64>,
+    last_s: f64,
+}
+
+impl Timeline {
+    fn place(&mut self, timestamp_us: u64) -> f64 {
+        let seconds = timestamp_us as f64 / 1e6;
+        match self.last_us {
+            Some(last_us) if timestamp_us < last_us => self.offset_s = self.last_s - seconds,
+            None => self.offset_s = -seconds,
+            Some(_) => {}
+        }
+        self.last_us = Some(timestamp_us);
+        self.last_s = seconds + self.offset_s;
+        self.last_s
+    }
+}
+
+pub struct PlotApp {
+    events: Receiver<Event>,
+    link: Arc<Mutex<LinkStats>>,
+    history: VecDeque<Point>,
+    timeline: Timeline,
+    /// The time the plots were frozen at.
+    paused_at: Option<f64>,
+    window_s: f64,
+    /// What the source is doing, or the last export's result.
+    status: String,
+}
+
+/// Opens the window and plots `source` in it until it is closed.
+pub fn run(name: String, source: Source, window_s: f64) -> io::Result<()> {
+    let status = format!("Reading {name}");
+    show(&name, source, move |reader| PlotApp {
+        events: reader.events,
+        link: reader.link,
+        history: VecDeque::new(),
+        timeline: Timeline::default(),
+        paused_at: None,
+        window_s,
+        status,
+    })
+}
+
+/// Opens a window named after the source with the app `make` makes in it,
+/// reading `source` for it until it is closed.
+pub fn show<A: eframe::App + 'static>(
+    name: &str,
+    source: Source,
+    make: impl FnOnce(Reader) -> A + 'static,
+) -> io::Result<()> {
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([1000., 760.]),
+        ..Default::default()
+    };
+    eframe::run_native(
+        &format!("Sphere mapping: {name}"),
+        options,
+        Box::new(move |cc| Ok(Box::new(make(spawn_reader(source, cc.egui_ctx.clone()))))),
+    )
+    .map_err(|err| io::Error::other(err.to_string()))
+}
+
+/// Reads `source` on a thread of its own, passing on what it reads and
+/// waking the window for it, and sending the commands it is given, until
+/// it runs out or fails or the window has gone.
+fn spawn_reader(mut source: Source, ctx: egui::Context) -> Reader {
+    let (sender, events) = mpsc::channel();
+    let (commands, received) = mpsc::channel::<String>();
+    let link = Arc::new(Mutex::new(LinkStats::new()));
+    let stats = link.clone();
+    let send = move |event| {
+        let open = sender.send(event).is_ok();
+        ctx.request_repaint();
+        open
+    };
+    thread::spawn(move || {
+        let mut packets = Vec::new();
+        loop {
+            let sent = received
+                .try_iter()
+                .try_for_each(|command| source.send(&command));
+            let more = match sent.and_then(|()| source.read(&mut packets)) {
+                Ok(more) => more,
+                Err(err) => {
+                    send(Event::Failed(err));
+                    return;
+                }
+            };
+            if let Ok(mut stats) = stats.lock() {
+                stats.received(source.received());
+                packets.iter().for_each(|packet| stats.packet(packet));
+            }
+            for packet in packets.drain(..) {
+                if !send(Event::Packet(packet)) {
+                    return;
+                }
+            }
+            if !more {
+                send(Event::Ended);
+                return;
+            }
+        }
+    });
+    Reader {
+        events,
+        commands,
+        link,
+    }
+}
+
+impl PlotApp {
+    fn collect(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                Event::Packet(Packet::Sample(sample)) => {
+                    let t_s = self.timeline.place(sample.timestamp_us);
+                    if self.history.len() == MAX_HISTORY {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(Point {
+                        t_s,
+                        attitude: Attitude::of(&sample),
+                        sample,
+                    });
+                }
+                Event::Packet(_) => {}
+                Event::Ended => self.status = "Replay finished".into(),
+                Event::Failed(err) => self.status = format!("Stopped: {err}"),
+            }
+        }
+    }
+
+    /// The samples received per second over the last second of them.
+    fn rate(&self) -> f64 {
+        let Some(last) = self.history.back() else {
+            return 0.;
+        };
+        let recent = self
+            .history
+            .iter()
+            .rev()
+            .take_while(|point| point.t_s > last.t_s - 1.)
+            .count();
+        recent as f64
+    }
+
+    fn export(&self) -> io::Result<PathBuf> {
+        let path = PathBuf::from(format!(
+            "plot-{}.csv",
+            csv_log::file_time(SystemTime::now())
+        ));
+        let mut out = BufWriter::new(File::create(&path)?);
+        writeln!(out, "{EXPORT_HEADER}")?;
+        for Point {
+            t_s,
+            sample,
+            attitude,
+        } in &self.history
+        {
+            let field = &sample.field;
+            writeln!(
+                out,
+                "{t_s:.6},{},{},{},{},{},{:.2},{:.2},{:.2}",
+                sample.timestamp_us,
+                field.x,
+                field.y,
+                field.z,
+                attitude.magnitude_nt,
+                attitude.heading_deg,
+                attitude.pitch_deg,
+                attitude.roll_deg
+            )?;
+        }
+        out.flush()?;
+        Ok(path)
+    }
+
+    /// A series of one value of each sample shown: those in the window
+    /// while live, or all those up to the pause, which may be scrolled to.
+    fn series(&self, value: impl Fn(&Point) -> f64) -> PlotPoints<'static> {
+        let (start, end) = match self.paused_at {
+            Some(paused_at) => (0, paused_at),
+            None => {
+                let latest = self.history.back().map_or(0., |point| point.t_s);
+                let start = self
+                    .history
+                    .partition_point(|point| point.t_s < latest - self.window_s);
+                // One before the window, so its line runs in from the edge.
+                (start.saturating_sub(1), f64::INFINITY)
+            }
+        };
+        self.history
+            .range(start..)
+            .take_while(|point| point.t_s <= end)
+            .map(|point| [point.t_s, value(point)])
+            .collect()
+    }
+
+    fn controls(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            let label = if self.paused_at.is_some() {
+                "Resume"
+            } else {
+                "Pause"
+            };
+            if ui.button(label).clicked() {
+                self.paused_at = match self.paused_at {
+                    Some(_) => None,
+                    None => Some(self.history.back().map_or(0., |point| point.t_s)),
+                };
+            }
+            let history_s = self
+                .history
+                .front()
+                .zip(self.history.back())
+                .map_or(0., |(first, last)| last.t_s - first.t_s);
+            ui.add_enabled(
+                self.paused_at.is_none(),
+                egui::Slider::new(&mut self.window_s, 1.0..=history_s.max(10.))
+                    .logarithmic(true)
+                    .text("s shown"),
+            );
+            if ui.button("Export CSV").clicked() {
+                self.status = match self.export() {
+                    Ok(path) => format!(
+                        "Exported {} samples to {}",
+                        self.history.len(),
+                        path.display()
+                    ),
+                    Err(err) => format!("Export failed: {err}"),
+                };
+            }
+        });
+        ui.label(format!(
+            "{} samples, {:.0} Hz. {}",
+            self.history.len(),
+            self.rate(),
+            self.status
+        ));
+        if let Ok(link) = self.link.lock() {
+            let text = format!("Link: {}. Lost: {}.", link.rates(), link.losses());
+            match link.lost() > 0 {
+                true => ui.colored_label(egui::Color32::YELLOW, text),
+                false => ui.label(text),
+            };
+        }
+    }
+
+    /// One of the plots, its x axis linked to the others'.
+    fn plot(
+        &self,
+        ui: &mut egui::Ui,
+        id: &str,
+        unit: &str,
+        height: f32,
+        lines: Vec<Line<'static>>,
+        points: Vec<Points<'static>>,
+    ) {
+        let live = self.paused_at.is_none();
+        let latest = self.history.back().map_or(0., |point| point.t_s);
+        let window_s = self.window_s;
+        Plot::new(id)
+            .height(height)
+            .legend(Legend::default())
+            .link_axis("time", [true, false])
+            .link_cursor("time", [true, false])
+            .allow_drag(!live)
+            .allow_zoom(!live)
+            .allow_scroll(!live)
+            .allow_boxed_zoom(!live)
+            .x_axis_label("s")
+            .y_axis_label(unit)
+            .show(ui, |plot_ui| {
+                if live {
+                    plot_ui.set_plot_bounds_x(latest - window_s..=latest);
+                    plot_ui.set_auto_bounds([false, true]);
+                }
+                for line in lines {
+                    plot_ui.line(line);
+                }
+                for points in points {
+                    plot_ui.points(points);
+                }
+            });
+    }
+}
+
+impl eframe::App for PlotApp {
+    fn ui(&mut self, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
+        self.collect();
+        egui::Panel::top("controls").show(ui, |ui| self.controls(ui));
+        egui::CentralPanel::default().show(ui, |ui| {
+            let height = (ui.available_height() - 2. * ui.spacing().item_spacing.y) / 3.;
+            let field = vec![
+                Line::new("X", self.series(|point| point.sample.field.x as f64)),
+                Line::new("Y", self.series(|point| point.sample.field.y as f64)),
+                Line::new("Z", self.series(|point| point.sample.field.z as f64)),
+                Line::new(
+                    "Strength",
+                    self.series(|point| point.attitude.magnitude_nt as f64),
+                ),
+            ];
+            self.plot(ui, "field", "nT", height, field, Vec::new());
+            // Points, so the heading wrapping from 359° to 0° doesn't draw a
+            // line across the plot.
+            let heading = Points::new(
+                "Heading",
+                self.series(|point| point.attitude.heading_deg as f64),
+            )
+            .radius(1.5);
+            self.plot(ui, "heading", "°", height, Vec::new(), vec![heading]);
+            let tilt = vec![
+                Line::new(
+                    "Pitch",
+                    self.series(|point| point.attitude.pitch_deg as f64),
+                ),
+                Line::new("Roll", self.series(|point| point.attitude.roll_deg as f64)),
+            ];
+            self.plot(ui, "tilt", "°", height, tilt, Vec::new());
+        });
+    }
+}
@@ -0,0 +1,19 @@
+//! The compass's host protocol as a library, for applications of their own
+//! to read the board with: [`Connection::open`] finds and opens its serial
+//! port (see [`port`]), and is a stream of the [`Frame`]s it sends, typed
+//! (see [`frame`]), that commands can be sent back on with
+//! [`Connection::send_command`]. The commands are those of the firmware's
+//! serial protocol, listed in the README.
+//!
+//! The stream is decoded as the host tools in this crate's binary decode
+//! it, whichever output format the board is set to (see
+//! [`stream`](sphere_mapping_core::stream)). The types the frames hold are
+//! `sphere_mapping_core`'s, re-exported here.
+
+pub mod connection;
+pub mod frame;
+pub mod port;
+
+pub use connection::Connection;
+pub use frame::Frame;
+pub use sphere_mapping_core;
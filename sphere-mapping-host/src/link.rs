@@ -0,0 +1,234 @@
+//! How the link to the board is doing while it runs, so whether the data
+//! is complete can be seen as it comes in rather than found out from the
+//! log afterwards (see [`analyze`](crate::analyze)): how fast samples,
+//! packets (samples, lines and frames alike) and bytes arrive, over the
+//! last second, and what has been lost on the way, counted three ways:
+//!
+//! - corrupt: bytes that arrived but didn't decode, such as a frame that
+//!   failed its CRC or a line cut short;
+//! - timestamp gaps: samples' timestamps jumping by more than the interval
+//!   between them (see [`TimestampGaps`]), and resets, where they went
+//!   backwards. The frames carry no sequence number, so this is all that
+//!   shows samples went missing, but a board that stalled or changed its
+//!   rate shows the same way;
+//! - the bytes the board couldn't send because the link was behind, as its
+//!   `Status:` lines report them.
+//!
+//! Bytes are counted as they are read from the port, so a replay has no
+//! byte rate.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use sphere_mapping_core::stream::Packet;
+use sphere_mapping_host::frame::parse_status;
+
+/// How many of the latest intervals between samples the expected one is
+/// the shortest of.
+const INTERVALS: usize = 16;
+/// How much longer than expected an interval is before samples count as
+/// missing.
+const GAP_FACTOR: f64 = 1.5;
+/// What the rates are taken over.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// What a sample's timestamp shows went missing before it.
+pub enum TimestampGap {
+    /// The timestamp went back by this much, as after the board reset.
+    Reset { back_us: u64 },
+    /// About `missing` samples are missing from an interval of
+    /// `interval_us`, where `expected_us` was expected.
+    Missing {
+        interval_us: u64,
+        expected_us: u64,
+        missing: u64,
+    },
+}
+
+/// Samples' timestamps jumping by more than the interval between them,
+/// which is found from the stream, since the rate can be changed on the
+/// board: the shortest of the latest intervals. A jump usually means
+/// samples went missing, but can't be told from the board stalling.
+#[derive(Default)]
+pub struct TimestampGaps {
+    last_us: Option<u64>,
+    /// The latest intervals between samples, in µs.
+    intervals: Vec<u64>,
+}
+
+impl TimestampGaps {
+    /// What is missing before the sample taken at `timestamp_us`, if
+    /// anything.
+    pub fn sample(&mut self, timestamp_us: u64) -> Option<TimestampGap> {
+        let last_us = self.last_us.replace(timestamp_us)?;
+        if timestamp_us < last_us {
+            self.intervals.clear();
+            return Some(TimestampGap::Reset {
+                back_us: last_us - timestamp_us,
+            });
+        }
+        let interval_us = timestamp_us - last_us;
+        let expected = self.intervals.iter().copied().filter(|&us| us > 0).min();
+        if self.intervals.len() == INTERVALS {
+            self.intervals.remove(0);
+        }
+        self.intervals.push(interval_us);
+        let expected_us = expected?;
+        if (interval_us as f64) <= expected_us as f64 * GAP_FACTOR {
+            return None;
+        }
+        Some(TimestampGap::Missing {
+            interval_us,
+            expected_us,
+            missing: (interval_us as f64 / expected_us as f64).round() as u64 - 1,
+        })
+    }
+}
+
+/// Counts of what has arrived and what was lost, since the link opened.
+#[derive(Default)]
+pub struct LinkStats {
+    /// When each packet of the last second arrived, and whether it was a
+    /// sample.
+    arrivals: VecDeque<(Instant, bool)>,
+    /// When bytes arrived over the last second, and how many.
+    reads: VecDeque<(Instant, u64)>,
+    gaps: TimestampGaps,
+    pub samples: u64,
+    /// Bytes read from the port, as [`Source::received`] counts them.
+    ///
+    /// [`Source::received`]: crate::source::Source::received
+    bytes: u64,
+    corrupt_packets: u64,
+    corrupt_bytes: u64,
+    timestamp_gaps: u64,
+    missing_samples: u64,
+    resets: u64,
+    /// Bytes the board reported dropping, over all its status lines.
+    board_dropped: u64,
+    /// Status lines that reported dropping any.
+    board_drops: u64,
+}
+
+impl LinkStats {
+    pub fn new() -> LinkStats {
+        LinkStats::default()
+    }
+
+    /// Counts the bytes read since the last call, from `total`, the bytes
+    /// read altogether.
+    pub fn received(&mut self, total: u64) {
+        if total > self.bytes {
+            let now = Instant::now();
+            self.reads.push_back((now, total - self.bytes));
+            self.bytes = total;
+            prune(&mut self.reads, now);
+        }
+    }
+
+    /// Counts `packet`, arrived now.
+    pub fn packet(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        self.arrivals
+            .push_back((now, matches!(packet, Packet::Sample(_))));
+        prune(&mut self.arrivals, now);
+        match packet {
+            Packet::Sample(sample) => {
+                self.samples += 1;
+                match self.gaps.sample(sample.timestamp_us) {
+                    Some(TimestampGap::Reset { .. }) => self.resets += 1,
+                    Some(TimestampGap::Missing { missing, .. }) => {
+                        self.timestamp_gaps += 1;
+                        self.missing_samples += missing;
+                    }
+                    None => {}
+                }
+            }
+            Packet::Line(line) => {
+                if let Some(fields) = line.strip_prefix("Status:") {
+                    let dropped = parse_status(fields)
+                        .into_iter()
+                        .find(|(key, _)| key == "dropped")
+                        .and_then(|(_, value)| value.parse::<u64>().ok())
+                        .unwrap_or(0);
+                    self.board_dropped += dropped;
+                    self.board_drops += u64::from(dropped > 0);
+                }
+            }
+            Packet::Corrupt(bytes) => {
+                self.corrupt_packets += 1;
+                self.corrupt_bytes += *bytes as u64;
+            }
+            Packet::Frame { .. } => {}
+        }
+    }
+
+    /// Samples that arrived over the last second.
+    pub fn sample_rate(&self) -> usize {
+        recent(&self.arrivals).filter(|(_, sample)| *sample).count()
+    }
+
+    /// Packets of every kind that arrived over the last second.
+    pub fn packet_rate(&self) -> usize {
+        recent(&self.arrivals).count()
+    }
+
+    /// Bytes that arrived over the last second, or `None` if none ever
+    /// have, as for a replay.
+    pub fn byte_rate(&self) -> Option<u64> {
+        (self.bytes > 0).then(|| recent(&self.reads).map(|(_, bytes)| bytes).sum())
+    }
+
+    /// How fast things are arriving, as
+    /// `100 samples/s, 102 packets/s, 6.2 kB/s`.
+    pub fn rates(&self) -> String {
+        let mut rates = format!(
+            "{} samples/s, {} packets/s",
+            self.sample_rate(),
+            self.packet_rate()
+        );
+        if let Some(bytes) = self.byte_rate() {
+            rates += &format!(", {:.1} kB/s", bytes as f64 / 1000.);
+        }
+        rates
+    }
+
+    /// What has been lost so far, as
+    /// `corrupt 84 B in 2, 3 timestamp gaps (about 5 samples), 0 resets,
+    /// board dropped 0 B`.
+    pub fn losses(&self) -> String {
+        format!(
+            "corrupt {} B in {}, {} timestamp gaps (about {} samples), {} resets, board dropped {} B",
+            self.corrupt_bytes,
+            self.corrupt_packets,
+            self.timestamp_gaps,
+            self.missing_samples,
+            self.resets,
+            self.board_dropped
+        )
+    }
+
+    /// Losses of any kind so far: corrupt packets, timestamp gaps and times
+    /// the board reported dropping bytes, to see when there are new ones.
+    pub fn lost(&self) -> u64 {
+        self.corrupt_packets + self.timestamp_gaps + self.board_drops
+    }
+}
+
+/// Drops the entries of `window` that are too old to count at `now`.
+fn prune<T>(window: &mut VecDeque<(Instant, T)>, now: Instant) {
+    while window
+        .front()
+        .is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW)
+    {
+        window.pop_front();
+    }
+}
+
+/// The entries of `window` from the last second.
+fn recent<T: Copy>(window: &VecDeque<(Instant, T)>) -> impl Iterator<Item = (Instant, T)> + '_ {
+    window
+        .iter()
+        .copied()
+        .filter(|(at, _)| at.elapsed() <= RATE_WINDOW)
+}
@@ -0,0 +1,523 @@
+//! Host tools for the compass, run against the board over USB serial.
+//!
+//! `log` finds the board's serial port, decodes everything it sends, text
+//! lines and binary frames alike (see
+//! [`stream`](sphere_mapping_core::stream)), and appends each sample to
+//! timestamped CSV files, starting a new file once one has grown too big
+//! or been written to for too long, each with a metadata file of the host
+//! tool's version and the board's boot banner (see [`csv_log`]). It says
+//! when samples have been lost on the way, as it goes, and with `--stats`
+//! how the link is doing all along (see [`link`]). With the
+//! `rerun` feature, `--rerun` logs them to a Rerun viewer as well (see
+//! [`rerun_log`]). With the `sqlite` feature, `--sqlite` writes them to an
+//! SQLite database in place of the CSV files, a session to each run, with
+//! the board's other lines and its calibrations beside them (see
+//! [`sqlite_log`]). Anomalies, new calibrations and the board going away
+//! can be published to an MQTT broker or posted to a webhook as they
+//! happen (see [`notify`]).
+//!
+//! `fit` fits a calibration to raw fields from a file or a burst capture
+//! (see [`raw`]) by least squares (see [`ellipsoid`]), prints it as the
+//! firmware's `CALIBRATION` const and can send it to the board with
+//! `SCAL`, which applies and saves it.
+//!
+//! `config push` sends the settings in a TOML file to the board, checking
+//! the board's reply to each (see [`settings_file`]).
+//!
+//! `analyze` reports how good a recorded log is before it is fitted: gaps
+//! in its samples, the jitter of their timing, what was dropped and which
+//! were at the sensors' limits (see [`analyze`]).
+//!
+//! `accuracy` compares the heading in a recorded log with a reference
+//! heading trace, such as a turntable's, finding the lag between them and
+//! reporting the bias and RMS error (see [`accuracy`]).
+//!
+//! `export` converts a recorded log to a format attitude estimation tools
+//! read, a ROS-style CSV or a generic IMU and magnetometer JSON, in SI
+//! units (see [`export`]).
+//!
+//! `session` logs several boards at once, each on its own port, into one
+//! CSV log whose rows are tagged with the board they came from (see
+//! [`session`]).
+//!
+//! `ports` lists the USB serial ports with their IDs, and which the board
+//! is found on (see [`port`]).
+//!
+//! `sniff` decodes raw bytes from a capture, stdin or the port without
+//! sending anything, printing where each line and frame starts and marking
+//! frames that fail their CRC and gaps in the samples' timestamps (see
+//! [`sniff`]).
+//!
+//! `plot`, built with the `gui` feature, opens a window plotting the field,
+//! heading and tilt live (see [`gui`]), and `cloud` one turning the raw
+//! fields round in 3D with the ellipsoid fitted to them (see [`cloud`]),
+//! and `calibrate` one walking through a calibration, from collecting
+//! fields to uploading the fit (see [`wizard`]); `watch`, built with the
+//! `tui`
+//! feature, shows the latest of them in a terminal dashboard, with the
+//! calibration, sample rate and losses (see [`tui`]), and `repl` is a
+//! console for sending the board commands one at a time, each completed
+//! with Tab and listed with what it does (see [`repl`]).
+//!
+//! Each can read a log of the board replayed in place of the board itself
+//! (see [`source`]), and send the board the declination where it is, from
+//! the World Magnetic Model, on connecting (see [`declination`]).
+
+mod accuracy;
+mod analyze;
+mod attitude;
+#[cfg(feature = "ble")]
+mod ble;
+#[cfg(feature = "gui")]
+mod cloud;
+mod csv_log;
+mod declination;
+mod export;
+#[cfg(feature = "gui")]
+mod gui;
+mod link;
+mod notify;
+mod raw;
+#[cfg(feature = "tui")]
+mod repl;
+#[cfg(feature = "rerun")]
+mod rerun_log;
+mod session;
+mod settings_file;
+mod sniff;
+mod source;
+#[cfg(feature = "sqlite")]
+mod sqlite_log;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(feature = "gui")]
+mod wizard;
+
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use clap::{Args, Parser, Subcommand};
+use sphere_mapping_core::calibration::Calibration;
+use sphere_mapping_core::ellipsoid;
+use sphere_mapping_core::settings::LoggedSample;
+use sphere_mapping_core::stream::Packet;
+use sphere_mapping_host::port;
+
+use csv_log::{CsvLog, Rotation};
+use link::LinkStats;
+use source::{Source, SourceArgs};
+
+#[derive(Parser)]
+#[command(about = "Host tools for the sphere mapping compass")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Log the board's samples to CSV files.
+    Log(LogArgs),
+    /// Log several boards' samples to one set of CSV files.
+    Session(session::SessionArgs),
+    /// Fit a calibration to raw fields.
+    Fit(FitArgs),
+    /// Change the board's settings.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Show what the board sends byte by byte, and what went missing:
+    /// undecodable bytes, timestamp gaps and resets, and dropped bytes.
+    Sniff(sniff::SniffArgs),
+    /// List the USB serial ports, and which the board is on.
+    Ports,
+    /// Report a recorded log's gaps, jitter, drops and saturation.
+    Analyze(analyze::AnalyzeArgs),
+    /// Compare a recorded log's heading with a reference heading trace.
+    Accuracy(accuracy::AccuracyArgs),
+    /// Convert a recorded log to a ROS-style CSV or IMU JSON.
+    Export(export::ExportArgs),
+    /// Plot the field, heading and tilt live in a window.
+    #[cfg(feature = "gui")]
+    Plot(PlotArgs),
+    /// Show the raw fields in 3D with the ellipsoid fitted to them.
+    #[cfg(feature = "gui")]
+    Cloud(CloudArgs),
+    /// Calibrate the board step by step in a window.
+    #[cfg(feature = "gui")]
+    Calibrate(CloudArgs),
+    /// Watch the heading, field, calibration and losses in the terminal.
+    #[cfg(feature = "tui")]
+    Watch(SourceArgs),
+    /// Send the board commands one at a time, with completion and help.
+    #[cfg(feature = "tui")]
+    Repl(SourceArgs),
+}
+
+/// How often `log` says how the link is doing.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Args)]
+struct LogArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// Directory the CSV files are written to.
+    #[arg(long, default_value = ".")]
+    dir: PathBuf,
+    /// Start of each file's name, before its timestamp.
+    #[arg(long, default_value = "samples")]
+    prefix: String,
+    /// Start a new file once this many megabytes have been written; 0
+    /// never does.
+    #[arg(long, default_value_t = 100)]
+    rotate_mb: u64,
+    /// Start a new file after this many minutes; 0 never does.
+    #[arg(long, default_value_t = 60)]
+    rotate_min: u64,
+    /// Also print the board's other lines, such as status and warnings,
+    /// and what was dropped for not decoding.
+    #[arg(long, short)]
+    verbose: bool,
+    /// Print how the link is doing, its rates and what has been lost,
+    /// every ten seconds, not only when something has been.
+    #[arg(long)]
+    stats: bool,
+    #[command(flatten)]
+    notify: notify::NotifyArgs,
+    /// Also log the samples, the board's attitude and its sphere map to a
+    /// Rerun viewer, started if one isn't running.
+    #[cfg(feature = "rerun")]
+    #[arg(long)]
+    rerun: bool,
+    /// Write the samples, the board's other lines and its calibrations to
+    /// this SQLite database, created if it doesn't exist, in place of the
+    /// CSV files.
+    #[cfg(feature = "sqlite")]
+    #[arg(long, conflicts_with_all = ["dir", "prefix", "rotate_mb", "rotate_min"])]
+    sqlite: Option<PathBuf>,
+}
+
+/// Where `log` writes the samples.
+enum Store {
+    Csv(CsvLog),
+    #[cfg(feature = "sqlite")]
+    Sqlite(sqlite_log::SqliteLog),
+}
+
+impl Store {
+    /// The store `args` asks for, logging `name`.
+    fn open(args: &LogArgs, name: &str) -> io::Result<Store> {
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = &args.sqlite {
+            eprintln!("Logging {name} to {}", path.display());
+            return Ok(Store::Sqlite(sqlite_log::SqliteLog::open(path, name)?));
+        }
+        eprintln!("Logging {name} to {}", args.dir.display());
+        let rotation = Rotation {
+            max_bytes: args.rotate_mb * 1024 * 1024,
+            max_age: Duration::from_secs(args.rotate_min * 60),
+        };
+        let mut log = CsvLog::new(args.dir.clone(), args.prefix.clone(), rotation);
+        log.describe(None, &format!("Source: {name}"))?;
+        Ok(Store::Csv(log))
+    }
+
+    fn sample(&mut self, sample: &LoggedSample) -> io::Result<()> {
+        match self {
+            Store::Csv(log) => {
+                if let Some(path) = log.write(sample)? {
+                    eprintln!("Writing {}", path.display());
+                }
+                Ok(())
+            }
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(log) => log.write(sample),
+        }
+    }
+
+    fn line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Store::Csv(log) => log.line(None, line),
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(log) => log.line(line),
+        }
+    }
+}
+
+#[derive(Args)]
+struct FitArgs {
+    /// A serial log with a burst capture's dump, or a CSV of raw fields in
+    /// nT; if left out, a burst capture is taken from the board while it
+    /// is turned.
+    input: Option<PathBuf>,
+    #[command(flatten)]
+    source: SourceArgs,
+    /// How long the burst capture lasts, 1-20 s.
+    #[arg(long, default_value_t = sphere_mapping_core::capture::MAX_CAPTURE_S)]
+    seconds: u8,
+    /// Send the calibration to the board, which applies and saves it.
+    #[arg(long)]
+    upload: bool,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Check a TOML settings file and send its settings to the board,
+    /// which applies and saves each.
+    Push(PushArgs),
+}
+
+#[derive(Args)]
+struct PushArgs {
+    /// The settings file.
+    file: PathBuf,
+    #[command(flatten)]
+    source: SourceArgs,
+}
+
+#[cfg(feature = "gui")]
+#[derive(Args)]
+struct PlotArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// How many seconds of samples the plots show as they follow them.
+    #[arg(long, default_value_t = 30.)]
+    window: f64,
+}
+
+#[cfg(feature = "gui")]
+#[derive(Args)]
+struct CloudArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// The calibration the board is using, as `SCAL` takes it, to undo on
+    /// its samples until it sends a `Calibration:` line.
+    #[arg(long, value_parser = parse_calibration)]
+    calibration: Option<Calibration>,
+}
+
+/// How long the board has to echo an uploaded calibration.
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Log(args) => log(args),
+        Command::Session(args) => session::run(args),
+        Command::Fit(args) => fit(args),
+        Command::Config(ConfigCommand::Push(args)) => push(args),
+        Command::Sniff(args) => sniff::run(args),
+        Command::Ports => ports(),
+        Command::Analyze(args) => analyze::run(args),
+        Command::Accuracy(args) => accuracy::run(args),
+        Command::Export(args) => export::run(args),
+        #[cfg(feature = "gui")]
+        Command::Plot(args) => plot(args),
+        #[cfg(feature = "gui")]
+        Command::Cloud(args) => Source::open(&args.source)
+            .and_then(|(name, source)| cloud::run(name, source, args.calibration)),
+        #[cfg(feature = "gui")]
+        Command::Calibrate(args) => Source::open(&args.source)
+            .and_then(|(name, source)| wizard::run(name, source, args.calibration)),
+        #[cfg(feature = "tui")]
+        Command::Watch(args) => {
+            Source::open(&args).and_then(|(name, source)| tui::run(name, source))
+        }
+        #[cfg(feature = "tui")]
+        Command::Repl(args) => {
+            Source::open(&args).and_then(|(name, source)| repl::run(name, source))
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn log(args: LogArgs) -> io::Result<()> {
+    let (name, mut source) = Source::open(&args.source)?;
+    let mut store = Store::open(&args, &name)?;
+    let mut notifier = notify::Notifier::new(&args.notify);
+    let mut link = LinkStats::new();
+    let mut reported = (Instant::now(), 0);
+    #[cfg(feature = "rerun")]
+    let mut rerun = match args.rerun {
+        true => Some(start_rerun(&mut source)?),
+        false => None,
+    };
+    let mut packets = Vec::new();
+    loop {
+        let more = match source.read(&mut packets) {
+            Ok(more) => more,
+            Err(err) => {
+                notifier.disconnected(&name, &err);
+                return Err(err);
+            }
+        };
+        link.received(source.received());
+        if reported.0.elapsed() >= STATS_INTERVAL {
+            if args.stats || link.lost() > reported.1 {
+                eprintln!("Link: {}; lost {}", link.rates(), link.losses());
+            }
+            reported = (Instant::now(), link.lost());
+        }
+        for packet in packets.drain(..) {
+            link.packet(&packet);
+            #[cfg(feature = "rerun")]
+            if let Some(rerun) = &mut rerun {
+                match &packet {
+                    Packet::Sample(sample) => rerun.sample(sample)?,
+                    Packet::Line(line) => rerun.line(line)?,
+                    Packet::Frame { kind, payload } => rerun.frame(*kind, payload)?,
+                    Packet::Corrupt(_) => {}
+                }
+            }
+            match packet {
+                Packet::Sample(sample) => store.sample(&sample)?,
+                Packet::Line(line) => {
+                    store.line(&line)?;
+                    notifier.line(&name, &line);
+                    if args.verbose {
+                        eprintln!("{line}");
+                    }
+                }
+                Packet::Corrupt(bytes) if args.verbose => {
+                    eprintln!("Dropped {bytes} bytes that didn't decode")
+                }
+                Packet::Frame { .. } | Packet::Corrupt(_) => {}
+            }
+        }
+        if !more {
+            return Ok(());
+        }
+    }
+}
+
+/// Starts logging to Rerun, and has the board send its sphere map and
+/// then each cell as it fills in.
+#[cfg(feature = "rerun")]
+fn start_rerun(source: &mut Source) -> io::Result<rerun_log::RerunLog> {
+    let rerun = rerun_log::RerunLog::spawn()?;
+    source.send("SMAP LIVE 1")?;
+    // The board takes one command at a time.
+    std::thread::sleep(Duration::from_millis(100));
+    source.send("SMAP EXPORT")?;
+    Ok(rerun)
+}
+
+fn fit(args: FitArgs) -> io::Result<()> {
+    if args.upload && args.source.replay.is_some() {
+        return Err(io::Error::other("can't upload to a replay"));
+    }
+    let mut source = None;
+    let raw = match &args.input {
+        Some(path) => raw::read_file(path)?,
+        None => {
+            let (name, mut opened) = Source::open(&args.source)?;
+            eprintln!(
+                "Capturing from {name} for {} s: turn the board through every orientation",
+                args.seconds
+            );
+            let raw = raw::capture(&mut opened, args.seconds)?;
+            source = Some(opened);
+            raw
+        }
+    };
+    let fit = ellipsoid::fit(&raw).map_err(io::Error::other)?;
+    let [rx, ry, rz] = fit.radii;
+    eprintln!(
+        "{} samples, radii {rx:.0}, {ry:.0}, {rz:.0} nT, residual {:.0} nT RMS",
+        fit.samples, fit.residual_nt
+    );
+    println!("{}", ellipsoid::rust_const(&fit.calibration));
+    println!(
+        "// SPHERE_CALIBRATION={}",
+        calibration_numbers(&fit.calibration)
+    );
+    if !args.upload {
+        return Ok(());
+    }
+    let mut source = match source {
+        Some(source) => source,
+        None => Source::open(&args.source)?.1,
+    };
+    upload(&mut source, &fit.calibration)?;
+    eprintln!("Uploaded; the board applied and saved it");
+    Ok(())
+}
+
+fn push(args: PushArgs) -> io::Result<()> {
+    let fields = settings_file::load(&args.file)?;
+    if fields.is_empty() {
+        eprintln!("{} has no settings to push", args.file.display());
+        return Ok(());
+    }
+    let (name, mut source) = Source::open(&args.source)?;
+    eprintln!("Pushing {} settings to {name}", fields.len());
+    match settings_file::push(&mut source, &fields)? {
+        0 => Ok(()),
+        failed => Err(io::Error::other(format!(
+            "{failed} of {} settings weren't acknowledged",
+            fields.len()
+        ))),
+    }
+}
+
+fn ports() -> io::Result<()> {
+    let ports = port::list()?;
+    if ports.is_empty() {
+        eprintln!("No USB serial ports; is the board plugged in?");
+        return Ok(());
+    }
+    for port in &ports {
+        println!("{port}");
+    }
+    match port::detect() {
+        Ok(name) => eprintln!("The board is on {name}"),
+        Err(err) => eprintln!("{err}"),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "gui")]
+fn plot(args: PlotArgs) -> io::Result<()> {
+    let (name, source) = Source::open(&args.source)?;
+    gui::run(name, source, args.window.max(1.))
+}
+
+/// The seven numbers of a `Calibration:` line, as `SCAL` and
+/// `SPHERE_CALIBRATION` take them.
+fn calibration_numbers(calibration: &Calibration) -> String {
+    let line = calibration.to_string();
+    line.trim_start_matches("Calibration: ").replace(' ', "")
+}
+
+#[cfg(feature = "gui")]
+fn parse_calibration(numbers: &str) -> Result<Calibration, String> {
+    Calibration::parse(&format!("Calibration: {numbers}"))
+        .ok_or_else(|| "expected seven numbers, as a `Calibration:` line gives them".into())
+}
+
+/// Sends `calibration` with `SCAL` and waits for the board to echo it.
+fn upload(source: &mut Source, calibration: &Calibration) -> io::Result<()> {
+    source.send(&format!("SCAL {}", calibration_numbers(calibration)))?;
+    let expected = calibration.to_string();
+    let deadline = Instant::now() + UPLOAD_TIMEOUT;
+    let mut packets = Vec::new();
+    while Instant::now() < deadline {
+        source.read(&mut packets)?;
+        if packets
+            .drain(..)
+            .any(|packet| packet == Packet::Line(expected.clone()))
+        {
+            return Ok(());
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the board didn't confirm the calibration",
+    ))
+}
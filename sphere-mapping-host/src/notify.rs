@@ -0,0 +1,305 @@
+//! Events from the board passed on to other systems as they happen, so a
+//! home automation or monitoring setup can act on them without glue code
+//! of its own reading the log: to an MQTT broker, to an HTTP webhook, or
+//! both. The events are:
+//!
+//! - `anomaly`: the field strength is well away from the calibration's, as
+//!   a magnet or steel nearby makes it (an `Anomaly:` line);
+//! - `anomaly_cleared`: it is back to normal;
+//! - `calibration`: a new calibration took effect, from the calibration
+//!   game, `SCAL` or an accepted refit (a `Calibration:` line other than
+//!   the boot banner's);
+//! - `disconnected`: the board stopped answering, unplugged or reset into
+//!   its bootloader.
+//!
+//! Each is a JSON object of the event, the board, when the host saw it in
+//! ISO 8601 UTC and the line it came from, or for `disconnected` the
+//! error:
+//!
+//! `{"event":"anomaly","device":"/dev/ttyACM0","time":"2025-06-01T12:00:00.000Z","line":"Anomaly: 81234 nT"}`
+//!
+//! It is published to `<topic>/<event>` with QoS 0, connecting to the
+//! broker for each, since events are rare, and posted to the webhook. Both
+//! are sent from a thread of their own, so a slow server doesn't hold up
+//! the log, and one that fails is reported and the log carries on.
+//!
+//! Only plain `http://` webhooks are sent to; for an `https://` one, such
+//! as a cloud service's, go through the broker or a local relay.
+
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use clap::Args;
+
+use crate::csv_log::row_time;
+
+/// The MQTT port used when the broker's address doesn't give one.
+const MQTT_PORT: u16 = 1883;
+/// How long connecting, sending and waiting for a reply may take.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Args)]
+pub struct NotifyArgs {
+    /// Post each event as JSON to this `http://` URL.
+    #[arg(long, value_parser = parse_webhook)]
+    webhook: Option<Webhook>,
+    /// Publish each event to the MQTT broker at `<host>[:<port>]`.
+    #[arg(long)]
+    mqtt: Option<String>,
+    /// Topic the events are published under, each to `<topic>/<event>`.
+    #[arg(long, default_value = "sphere-mapping")]
+    mqtt_topic: String,
+}
+
+/// Where a webhook's requests go.
+#[derive(Clone)]
+struct Webhook {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+fn parse_webhook(url: &str) -> Result<Webhook, String> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err("only http:// URLs are supported".into());
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| "bad port")?),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err("no host".into());
+    }
+    Ok(Webhook {
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+/// An event and what it is about, ready to send.
+struct Notification {
+    event: &'static str,
+    json: String,
+}
+
+/// Passes events on to the sending thread, which finishes what it has
+/// been given before the notifier is dropped.
+pub struct Notifier {
+    sender: Option<Sender<Notification>>,
+    thread: Option<JoinHandle<()>>,
+    /// Boards that have reset and have yet to send their banner's
+    /// calibration, which isn't a new one.
+    booting: HashSet<String>,
+}
+
+impl Notifier {
+    /// A notifier for what `args` asks for, which does nothing if it asks
+    /// for neither.
+    pub fn new(args: &NotifyArgs) -> Notifier {
+        let webhook = args.webhook.clone();
+        let mqtt = args.mqtt.clone();
+        let (sender, thread) = match webhook.is_some() || mqtt.is_some() {
+            true => {
+                let topic = args.mqtt_topic.clone();
+                let (sender, receiver) = mpsc::channel::<Notification>();
+                let thread = thread::spawn(move || {
+                    for notification in receiver {
+                        if let Some(webhook) = &webhook {
+                            if let Err(err) = post(webhook, &notification.json) {
+                                eprintln!(
+                                    "Couldn't post {} to the webhook: {err}",
+                                    notification.event
+                                );
+                            }
+                        }
+                        if let Some(broker) = &mqtt {
+                            let topic = format!("{topic}/{}", notification.event);
+                            if let Err(err) = publish(broker, &topic, &notification.json) {
+                                eprintln!(
+                                    "Couldn't publish {} to {broker}: {err}",
+                                    notification.event
+                                );
+                            }
+                        }
+                    }
+                });
+                (Some(sender), Some(thread))
+            }
+            false => (None, None),
+        };
+        Notifier {
+            sender,
+            thread,
+            booting: HashSet::new(),
+        }
+    }
+
+    /// Passes on `line` from `device` if it is an event.
+    pub fn line(&mut self, device: &str, line: &str) {
+        let event = if line.starts_with("Reset:") {
+            self.booting.insert(device.to_string());
+            return;
+        } else if line.starts_with("Calibration:") {
+            match self.booting.remove(device) {
+                true => return,
+                false => "calibration",
+            }
+        } else if line == "Anomaly: cleared" {
+            "anomaly_cleared"
+        } else if line.starts_with("Anomaly:") {
+            "anomaly"
+        } else {
+            return;
+        };
+        self.send(event, device, "line", line);
+    }
+
+    /// Passes on that `device` stopped answering, for `err`.
+    pub fn disconnected(&mut self, device: &str, err: &io::Error) {
+        self.send("disconnected", device, "error", &err.to_string());
+    }
+
+    fn send(&self, event: &'static str, device: &str, key: &str, value: &str) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let json = format!(
+            "{{\"event\":\"{event}\",\"device\":{},\"time\":\"{}\",\"{key}\":{}}}",
+            json_string(device),
+            row_time(SystemTime::now()),
+            json_string(value)
+        );
+        sender.send(Notification { event, json }).ok();
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// `text` as a JSON string, quoted.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            c if c.is_control() => quoted += &format!("\\u{:04x}", c as u32),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let address = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("{host} has no address")))?;
+    let stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Posts `json` to `webhook`, expecting a 2xx status back.
+fn post(webhook: &Webhook, json: &str) -> io::Result<()> {
+    let Webhook { host, port, path } = webhook;
+    let mut stream = connect(host, *port)?;
+    write!(
+        stream,
+        "POST {path} HTTP/1.0\r\nHost: {host}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{json}",
+        json.len()
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("the server replied {status:?}"))),
+    }
+}
+
+/// Publishes `payload` to `topic` on the MQTT 3.1.1 broker at `broker`,
+/// connecting for it, as a client with a name of its own and a clean
+/// session, and disconnecting after.
+fn publish(broker: &str, topic: &str, payload: &str) -> io::Result<()> {
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| io::Error::other(format!("bad port in {broker}")))?,
+        ),
+        None => (broker, MQTT_PORT),
+    };
+    let mut stream = connect(host, port)?;
+    let client = format!("sphere-mapping-{}", std::process::id());
+    // CONNECT: protocol "MQTT" level 4, a clean session, no keep-alive.
+    let mut connect = Vec::new();
+    put_string(&mut connect, "MQTT");
+    connect.extend([4, 0x02, 0, 0]);
+    put_string(&mut connect, &client);
+    stream.write_all(&packet(0x10, &connect))?;
+    // CONNACK: its return code is the last byte.
+    let mut connack = [0u8; 4];
+    stream.read_exact(&mut connack)?;
+    match connack {
+        [0x20, 2, _, 0] => {}
+        [0x20, 2, _, code] => {
+            return Err(io::Error::other(format!(
+                "the broker refused the connection ({code})"
+            )))
+        }
+        _ => return Err(io::Error::other("the broker didn't reply as MQTT")),
+    }
+    // PUBLISH at QoS 0, so nothing comes back.
+    let mut publish = Vec::new();
+    put_string(&mut publish, topic);
+    publish.extend(payload.as_bytes());
+    stream.write_all(&packet(0x30, &publish))?;
+    // DISCONNECT.
+    stream.write_all(&[0xe0, 0])?;
+    Ok(())
+}
+
+/// An MQTT control packet: its type and flags, the length of the rest
+/// seven bits to a byte, low first, and the rest.
+fn packet(kind: u8, rest: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut len = rest.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        match len {
+            0 => {
+                packet.push(byte);
+                break;
+            }
+            _ => packet.push(byte | 0x80),
+        }
+    }
+    packet.extend(rest);
+    packet
+}
+
+/// An MQTT string: its length in two bytes, high first, then its bytes.
+fn put_string(out: &mut Vec<u8>, text: &str) {
+    out.extend((text.len() as u16).to_be_bytes());
+    out.extend(text.as_bytes());
+}
@@ -0,0 +1,151 @@
+//! Finding and opening the board's serial port.
+//!
+//! The board is found by the USB vendor and product IDs of the port it
+//! presents (see [`IDENTITIES`]), so nobody has to work out which COM port
+//! or `/dev/ttyACM` device it was given; `--port` names one instead.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use serialport::{SerialPort, SerialPortType};
+
+/// A USB identity the board's serial port can have.
+pub struct Identity {
+    pub vid: u16,
+    pub pid: u16,
+    pub name: &'static str,
+}
+
+/// The identities the board's port is found by, in order of preference.
+pub const IDENTITIES: [Identity; 2] = [
+    // The micro:bit's interface chip, DAPLink, which presents the nRF's
+    // UART as a CDC serial port.
+    Identity {
+        vid: 0x0d28,
+        pid: 0x0204,
+        name: "micro:bit (DAPLink)",
+    },
+    // The firmware's own USB, for when it drives the nRF's USB peripheral
+    // directly rather than going through DAPLink: pid.codes' test IDs,
+    // until it has IDs of its own.
+    Identity {
+        vid: 0x1209,
+        pid: 0x0001,
+        name: "micro:bit (native USB)",
+    },
+];
+
+/// How long a read waits for data before trying again.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A USB serial port there is.
+pub struct UsbPort {
+    pub name: String,
+    pub vid: u16,
+    pub pid: u16,
+    pub serial_number: Option<String>,
+    pub product: Option<String>,
+    /// Which of [`IDENTITIES`] it has, if any.
+    pub identity: Option<&'static Identity>,
+}
+
+impl fmt::Display for UsbPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:04x}:{:04x}", self.name, self.vid, self.pid)?;
+        match (self.identity, &self.product) {
+            (Some(identity), _) => write!(f, ", {}", identity.name)?,
+            (None, Some(product)) => write!(f, ", {product}")?,
+            (None, None) => {}
+        }
+        if let Some(serial_number) = &self.serial_number {
+            write!(f, ", serial {serial_number}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The USB serial ports there are, boards first. On macOS, where each has
+/// a `/dev/tty.` and a `/dev/cu.` device, only the `cu.` one is listed, as
+/// opening the other waits for a modem's carrier.
+pub fn list() -> io::Result<Vec<UsbPort>> {
+    let ports = serialport::available_ports()?;
+    let names: Vec<&str> = ports.iter().map(|port| port.port_name.as_str()).collect();
+    let mut usb: Vec<UsbPort> = ports
+        .iter()
+        .filter(|port| match port.port_name.strip_prefix("/dev/tty.") {
+            Some(device) => !names.contains(&format!("/dev/cu.{device}").as_str()),
+            None => true,
+        })
+        .filter_map(|port| match &port.port_type {
+            SerialPortType::UsbPort(usb) => Some(UsbPort {
+                name: port.port_name.clone(),
+                vid: usb.vid,
+                pid: usb.pid,
+                serial_number: usb.serial_number.clone(),
+                product: usb.product.clone(),
+                identity: IDENTITIES
+                    .iter()
+                    .find(|identity| identity.vid == usb.vid && identity.pid == usb.pid),
+            }),
+            _ => None,
+        })
+        .collect();
+    usb.sort_by_key(|port| {
+        let rank = IDENTITIES
+            .iter()
+            .position(|identity| identity.vid == port.vid && identity.pid == port.pid);
+        (rank.unwrap_or(IDENTITIES.len()), port.name.clone())
+    });
+    Ok(usb)
+}
+
+/// The name of the board's port: the one with a board's USB IDs, or
+/// failing that the only USB serial port there is.
+pub fn detect() -> io::Result<String> {
+    let usb = list()?;
+    let boards: Vec<&UsbPort> = usb.iter().filter(|port| port.identity.is_some()).collect();
+    let listed = |ports: &[&UsbPort]| {
+        let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+        ports.join(", ")
+    };
+    match (boards.as_slice(), usb.as_slice()) {
+        ([board], _) => Ok(board.name.clone()),
+        ([], [port]) => Ok(port.name.clone()),
+        ([], []) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no USB serial port found; is the board plugged in?",
+        )),
+        ([], _) => Err(io::Error::other(format!(
+            "several USB serial ports and none is a micro:bit: {}; pick one with --port",
+            listed(&usb.iter().collect::<Vec<_>>())
+        ))),
+        (boards, _) => Err(io::Error::other(format!(
+            "several micro:bits: {}; pick one with --port",
+            listed(boards)
+        ))),
+    }
+}
+
+/// Opens `name`, or the board's port if there is none, at `baud`,
+/// returning the port's name with it.
+pub fn connect(name: Option<String>, baud: u32) -> io::Result<(String, Box<dyn SerialPort>)> {
+    let name = match name {
+        Some(name) => name,
+        None => detect()?,
+    };
+    let port = open(&name, baud)?;
+    Ok((name, port))
+}
+
+/// Opens `name` at `baud`, with reads that time out so callers can look
+/// up between them.
+pub fn open(name: &str, baud: u32) -> io::Result<Box<dyn SerialPort>> {
+    serialport::new(name, baud)
+        .timeout(READ_TIMEOUT)
+        .open()
+        .map_err(|err| {
+            let err = io::Error::from(err);
+            io::Error::new(err.kind(), format!("{name}: {err}"))
+        })
+}
@@ -0,0 +1,87 @@
+//! Raw, uncalibrated fields for fitting a calibration to, from a file or
+//! straight from the board.
+//!
+//! Files can be a serial log holding a burst capture's dump, whose
+//! `Captured:` lines are raw, or a CSV of raw fields in nT in the sensor's
+//! own axes, taken from its `x`, `y` and `z` columns if it has a header
+//! naming them, or else its first three. The board's sample lines, and
+//! `log`'s files, hold calibrated fields, which can't be fitted.
+//!
+//! From the board, a burst capture (`SCAP`) is taken while the board is
+//! turned, for up to 20 s at 100 Hz; replaying a log of one does as well.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use sphere_mapping_core::calibration::Measurement;
+use sphere_mapping_core::capture::{parse_captured, MAX_CAPTURE_S};
+use sphere_mapping_core::stream::Packet;
+
+use crate::source::Source;
+
+/// How long after a capture should have finished its dump has to have
+/// ended: 2000 lines take about 8 s at 115200 baud.
+const DUMP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The raw fields in the file at `path`.
+pub fn read_file(path: &Path) -> io::Result<Vec<Measurement>> {
+    let text = String::from_utf8_lossy(&fs::read(path)?).into_owned();
+    let mut columns = [0, 1, 2];
+    let mut fields = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if let Some(field) = parse_captured(line) {
+            fields.push(field);
+            continue;
+        }
+        let cells: Vec<&str> = line.split(',').map(str::trim).collect();
+        let named = ["x", "y", "z"].map(|name| cells.iter().position(|cell| *cell == name));
+        if let [Some(x), Some(y), Some(z)] = named {
+            columns = [x, y, z];
+            continue;
+        }
+        let values =
+            columns.map(|column| cells.get(column).and_then(|cell| cell.parse::<f64>().ok()));
+        if let [Some(x), Some(y), Some(z)] = values {
+            fields.push(Measurement {
+                x: x.round() as i32,
+                y: y.round() as i32,
+                z: z.round() as i32,
+            });
+        }
+    }
+    Ok(fields)
+}
+
+/// Takes a burst capture of `seconds` and returns its raw fields.
+pub fn capture(source: &mut Source, seconds: u8) -> io::Result<Vec<Measurement>> {
+    let seconds = seconds.clamp(1, MAX_CAPTURE_S);
+    source.send(&format!("SCAP {seconds}"))?;
+    let deadline = Instant::now() + Duration::from_secs(seconds as u64) + DUMP_TIMEOUT;
+    let mut packets = Vec::new();
+    let mut fields = Vec::new();
+    let mut more = true;
+    while more && Instant::now() < deadline {
+        more = source.read(&mut packets)?;
+        for packet in packets.drain(..) {
+            let Packet::Line(line) = packet else {
+                continue;
+            };
+            if line == "Capture: done" {
+                return Ok(fields);
+            }
+            if line.starts_with("Warning:") {
+                return Err(io::Error::other(line));
+            }
+            fields.extend(parse_captured(&line));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!(
+            "the capture didn't finish, {} samples received",
+            fields.len()
+        ),
+    ))
+}
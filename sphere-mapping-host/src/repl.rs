@@ -0,0 +1,620 @@
+//! A console for talking to the board a command at a time, for trying the
+//! protocol out and finding out what it can do: every command the firmware
+//! takes is listed with what it does, and completes with Tab (see
+//! [`COMMANDS`]).
+//!
+//! A line is checked with the firmware's own parser before it is sent, so
+//! a typo is caught here rather than ignored by the board. What the board
+//! sends back is laid out to be read: the fields of `Settings:`, `Status:`
+//! and other `key=value` lines one to a row, calibrations with their
+//! units, sphere map frames as a line each, and warnings picked out, with
+//! the status the board sends every so often kept to a faint line.
+//! Samples, which would scroll everything else away, are counted rather
+//! than shown until `samples` turns them on.
+//!
+//! The line can be edited with the arrow keys, Home, End and Ctrl-U, and
+//! Up and Down go through the lines sent before. `help`, or `help
+//! <command>`, lists the commands; `quit`, Ctrl-D or Ctrl-C on an empty
+//! line leaves.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use ratatui::crossterm::cursor::MoveToColumn;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::queue;
+use ratatui::crossterm::style::Stylize;
+use ratatui::crossterm::terminal::{self, Clear, ClearType};
+use sphere_mapping_core::command::{parse_command, SerialCommand};
+use sphere_mapping_core::stream::Packet;
+use sphere_mapping_host::frame::{parse_status, Frame};
+
+use crate::attitude::Attitude;
+use crate::source::Source;
+
+const PROMPT: &str = "> ";
+
+/// A command the console knows: how it is written and what it does.
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+}
+
+/// Every command the firmware takes, then the console's own.
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "SCAL",
+        usage: "SCAL [STOP | <cx>,<cy>,<cz>,<sx>,<sy>,<sz>,<radius>]",
+        help: "Alone, start the calibration game; `SCAL STOP` abandons it, going back to \
+               the mode before. With numbers, apply and save a \
+               calibration fitted elsewhere: the centre in nT, the scales in 1/1024ths and \
+               the radius in nT, in the order `Calibration:` lines give them.",
+    },
+    Command {
+        name: "SROT",
+        usage: "SROT <degrees>",
+        help: "Rotate the LED output clockwise by 0, 90, 180 or 270 degrees.",
+    },
+    Command {
+        name: "SHLD",
+        usage: "SHLD <ms>",
+        help: "Hold each LED frame for at least this many ms.",
+    },
+    Command {
+        name: "SBRT",
+        usage: "SBRT <level>",
+        help: "Fix the LED brightness at 1-9, or 0 to follow the ambient light.",
+    },
+    Command {
+        name: "SMOD",
+        usage: "SMOD <mode>",
+        help: "Pick the display: 0 compass, 1 clock, 2 level.",
+    },
+    Command {
+        name: "SFMT",
+        usage: "SFMT <format>",
+        help: "Pick the sample output: 0 `Measurement:` lines, 1 CSV, 2 binary frames.",
+    },
+    Command {
+        name: "SRPT",
+        usage: "SRPT <samples>",
+        help: "Send every nth sample, 1-255.",
+    },
+    Command {
+        name: "SFLT",
+        usage: "SFLT <weight>",
+        help: "Smooth the field, 0-255, 0 for none.",
+    },
+    Command {
+        name: "SDEC",
+        usage: "SDEC <tenths>",
+        help: "Set the declination in tenths of a degree, East positive, -1800 to 1800.",
+    },
+    Command {
+        name: "SAPP",
+        usage: "SAPP <mode>",
+        help: "Switch mode: 0 compass, 1 calibrate, 2 streaming only, 3 magnitude, 4 sleep, \
+               5 sphere mapping, 6 magnetic survey.",
+    },
+    Command {
+        name: "SPWR",
+        usage: "SPWR <mode>",
+        help: "Pick the power mode: 0 normal, 1 battery, 2 high-rate.",
+    },
+    Command {
+        name: "SIDL",
+        usage: "SIDL <minutes>",
+        help: "Power down after 1-255 minutes without movement, or 0 never.",
+    },
+    Command {
+        name: "SCAP",
+        usage: "SCAP <seconds>",
+        help: "Record 1-20 s of raw samples at 100 Hz, then dump them as `Captured:` lines.",
+    },
+    Command {
+        name: "STRM",
+        usage: "STRM <uT>",
+        help: "Capture once the field's magnitude rises above 1-65535 µT; 0 stops waiting.",
+    },
+    Command {
+        name: "STRD",
+        usage: "STRD <nT>",
+        help: "Capture once any axis changes by more than 1-65535 nT from one sample to the \
+               next; 0 stops waiting.",
+    },
+    Command {
+        name: "SPRE",
+        usage: "SPRE <samples>",
+        help: "Keep 0-1000 samples from before a capture's trigger.",
+    },
+    Command {
+        name: "SPST",
+        usage: "SPST <samples>",
+        help: "Keep 1-1000 samples from a capture's trigger on.",
+    },
+    Command {
+        name: "SBEN",
+        usage: "SBEN <seconds>",
+        help: "Time each stage of handling samples for 1-60 s, then report.",
+    },
+    Command {
+        name: "SPOS",
+        usage: "SPOS <lat>,<lon>",
+        help: "Set the survey's position in decimal degrees, North and East positive.",
+    },
+    Command {
+        name: "SWPT",
+        usage: "SWPT <n>",
+        help: "Set the survey's position as waypoint 0-65535.",
+    },
+    Command {
+        name: "SMAP EXPORT",
+        usage: "SMAP EXPORT",
+        help: "Send every cell of the sphere map as a frame.",
+    },
+    Command {
+        name: "SMAP START",
+        usage: "SMAP START",
+        help: "Add samples to the sphere map, as it does from boot.",
+    },
+    Command {
+        name: "SMAP STOP",
+        usage: "SMAP STOP",
+        help: "Stop adding samples to the sphere map, keeping what it has.",
+    },
+    Command {
+        name: "SMAP RESET",
+        usage: "SMAP RESET",
+        help: "Forget every sample in the sphere map.",
+    },
+    Command {
+        name: "SMAP COV",
+        usage: "SMAP COV [samples]",
+        help: "Report how much of the sphere map is covered, after setting the samples a \
+               cell needs, 1-65535, if given.",
+    },
+    Command {
+        name: "SMAP SAVE",
+        usage: "SMAP SAVE [slot]",
+        help: "Save the sphere map to flash, in slot 0 or 1.",
+    },
+    Command {
+        name: "SMAP LOAD",
+        usage: "SMAP LOAD [slot]",
+        help: "Replace the sphere map with the one saved in slot 0 or 1.",
+    },
+    Command {
+        name: "SMAP MERGE",
+        usage: "SMAP MERGE [slot]",
+        help: "Merge the sphere map saved in slot 0 or 1 into this one.",
+    },
+    Command {
+        name: "SMAP LIVE",
+        usage: "SMAP LIVE <0|1>",
+        help: "Stop or start sending sphere map cells as they change.",
+    },
+    Command {
+        name: "SMAP DIFF",
+        usage: "SMAP DIFF",
+        help: "Send the difference from the map saved in slot 0 to slot 1, a frame per cell.",
+    },
+    Command {
+        name: "SMAP ANOM",
+        usage: "SMAP ANOM",
+        help: "List the cells whose average strength stands out from the rest.",
+    },
+    Command {
+        name: "SMAP FIT",
+        usage: "SMAP FIT",
+        help: "Refit the calibration from the sphere map's samples, and offer it.",
+    },
+    Command {
+        name: "SMAP APPLY",
+        usage: "SMAP APPLY",
+        help: "Apply and save the calibration on offer.",
+    },
+    Command {
+        name: "help",
+        usage: "help [command]",
+        help: "List the commands, or say what one does.",
+    },
+    Command {
+        name: "samples",
+        usage: "samples",
+        help: "Show the samples as they arrive, or stop showing them.",
+    },
+    Command {
+        name: "quit",
+        usage: "quit",
+        help: "Leave the console.",
+    },
+];
+
+/// The line being typed, and those sent before it.
+struct Editor {
+    line: Vec<char>,
+    /// Where in `line` the cursor is.
+    cursor: usize,
+    history: Vec<String>,
+    /// Which of `history` Up and Down have got to, if they have been used.
+    browsing: Option<usize>,
+    /// Whether the last key was a Tab, so another lists the choices.
+    tabbed: bool,
+}
+
+/// What the console does after a key.
+enum Key {
+    Edited,
+    Entered(String),
+    Quit,
+}
+
+impl Editor {
+    fn new() -> Editor {
+        Editor {
+            line: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            browsing: None,
+            tabbed: false,
+        }
+    }
+
+    fn text(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    fn set(&mut self, text: &str) {
+        self.line = text.chars().collect();
+        self.cursor = self.line.len();
+    }
+
+    /// Redraws the prompt and the line, with the cursor where it is.
+    fn draw(&self, out: &mut impl Write) -> io::Result<()> {
+        queue!(out, MoveToColumn(0), Clear(ClearType::CurrentLine))?;
+        write!(out, "{PROMPT}{}", self.text())?;
+        queue!(out, MoveToColumn((PROMPT.len() + self.cursor) as u16))?;
+        out.flush()
+    }
+
+    /// Prints `text` above the line being typed.
+    fn print(&self, out: &mut impl Write, text: &str) -> io::Result<()> {
+        queue!(out, MoveToColumn(0), Clear(ClearType::CurrentLine))?;
+        for line in text.lines() {
+            write!(out, "{line}\r\n")?;
+        }
+        self.draw(out)
+    }
+
+    fn key(
+        &mut self,
+        out: &mut impl Write,
+        code: KeyCode,
+        modifiers: KeyModifiers,
+    ) -> io::Result<Key> {
+        let ctrl = modifiers.contains(KeyModifiers::CONTROL);
+        let tabbed = std::mem::take(&mut self.tabbed);
+        match code {
+            KeyCode::Char('c' | 'd') if ctrl && self.line.is_empty() => return Ok(Key::Quit),
+            KeyCode::Char('c') if ctrl => self.set(""),
+            KeyCode::Char('u') if ctrl => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.line.len(),
+            KeyCode::Up if !self.history.is_empty() => {
+                let at = self
+                    .browsing
+                    .map_or(self.history.len(), |at| at)
+                    .saturating_sub(1);
+                self.browsing = Some(at);
+                let text = self.history[at].clone();
+                self.set(&text);
+            }
+            KeyCode::Down => match self.browsing {
+                Some(at) if at + 1 < self.history.len() => {
+                    self.browsing = Some(at + 1);
+                    let text = self.history[at + 1].clone();
+                    self.set(&text);
+                }
+                Some(_) => {
+                    self.browsing = None;
+                    self.set("");
+                }
+                None => {}
+            },
+            KeyCode::Tab => {
+                self.tabbed = true;
+                self.complete(out, tabbed)?;
+            }
+            KeyCode::Enter => {
+                let text = self.text();
+                queue!(out, MoveToColumn(0), Clear(ClearType::CurrentLine))?;
+                write!(out, "{PROMPT}{text}\r\n")?;
+                self.set("");
+                self.browsing = None;
+                if !text.trim().is_empty() && self.history.last() != Some(&text) {
+                    self.history.push(text.clone());
+                }
+                return Ok(Key::Entered(text));
+            }
+            _ => {}
+        }
+        self.draw(out)?;
+        Ok(Key::Edited)
+    }
+
+    /// Completes the command being typed as far as it can go, listing the
+    /// choices on a second Tab where there is more than one.
+    fn complete(&mut self, out: &mut impl Write, again: bool) -> io::Result<()> {
+        let typed = self.text();
+        let typed = typed.trim_start();
+        let choices: Vec<&Command> = COMMANDS
+            .iter()
+            .filter(|command| {
+                command.name.len() >= typed.len()
+                    && command.name[..typed.len()].eq_ignore_ascii_case(typed)
+            })
+            .collect();
+        match choices[..] {
+            [] => {}
+            [only] => {
+                let arguments = only.usage.len() > only.name.len();
+                self.set(&format!(
+                    "{}{}",
+                    only.name,
+                    if arguments { " " } else { "" }
+                ));
+            }
+            _ => {
+                let first = choices[0].name;
+                let common = choices.iter().fold(first.len(), |common, command| {
+                    first
+                        .bytes()
+                        .zip(command.name.bytes())
+                        .take(common)
+                        .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+                        .count()
+                });
+                if common > typed.len() {
+                    self.set(&first[..common]);
+                } else if again {
+                    let names: Vec<&str> = choices.iter().map(|command| command.name).collect();
+                    self.print(out, &names.join("  "))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the console on `source` until it is left or the source stops.
+pub fn run(name: String, mut source: Source) -> io::Result<()> {
+    println!("Connected to {name}; Tab completes, `help` lists the commands, `quit` leaves");
+    terminal::enable_raw_mode()?;
+    let mut out = io::stdout();
+    let result = run_raw(&mut source, &mut out);
+    terminal::disable_raw_mode()?;
+    println!();
+    result
+}
+
+fn run_raw(source: &mut Source, out: &mut impl Write) -> io::Result<()> {
+    let mut editor = Editor::new();
+    let mut show_samples = false;
+    let mut samples_hidden = 0u64;
+    let mut packets = Vec::new();
+    editor.draw(out)?;
+    loop {
+        if !source.read(&mut packets)? {
+            editor.print(out, "The replay has finished")?;
+            return Ok(());
+        }
+        let mut shown = String::new();
+        for packet in packets.drain(..) {
+            if let Packet::Sample(sample) = &packet {
+                if !show_samples {
+                    samples_hidden += 1;
+                    continue;
+                }
+                let attitude = Attitude::of(sample);
+                writeln!(
+                    shown,
+                    "{} t={} µs  field=({}, {}, {}) nT  accel=({}, {}, {}) mg  heading={:.1}°",
+                    "Sample".dim(),
+                    sample.timestamp_us,
+                    sample.field.x,
+                    sample.field.y,
+                    sample.field.z,
+                    sample.accel.x,
+                    sample.accel.y,
+                    sample.accel.z,
+                    attitude.heading_deg
+                )
+                .ok();
+                continue;
+            }
+            shown += &pretty(Frame::from(packet));
+        }
+        if !shown.is_empty() {
+            editor.print(out, &shown)?;
+        }
+        while event::poll(std::time::Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let line = match editor.key(out, key.code, key.modifiers)? {
+                Key::Edited => continue,
+                Key::Quit => return Ok(()),
+                Key::Entered(line) => line,
+            };
+            let line = line.trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                [] => {}
+                ["quit" | "exit"] => return Ok(()),
+                ["help"] => editor.print(out, &help_all())?,
+                ["help", ..] => editor.print(out, &help_one(&words[1..].join(" ")))?,
+                ["samples"] => {
+                    show_samples = !show_samples;
+                    let said = match show_samples {
+                        true => format!("Showing samples ({samples_hidden} were hidden)"),
+                        false => "Hiding samples".to_string(),
+                    };
+                    samples_hidden = 0;
+                    editor.print(out, &said)?;
+                }
+                _ if parse_command(line.as_bytes()) == SerialCommand::Unknown => {
+                    let said = format!(
+                        "{} the firmware doesn't take {line:?}; `help` lists what it does",
+                        "Not sent:".yellow()
+                    );
+                    editor.print(out, &said)?;
+                }
+                _ => source.send(line)?,
+            }
+            editor.draw(out)?;
+        }
+    }
+}
+
+/// Every command, a line each.
+fn help_all() -> String {
+    let width = COMMANDS
+        .iter()
+        .map(|command| command.usage.len())
+        .max()
+        .unwrap_or(0);
+    let mut text = String::new();
+    for command in COMMANDS {
+        let summary = command.help.split(". ").next().unwrap_or(command.help);
+        let summary = summary.trim_end_matches('.');
+        writeln!(text, "{:width$}  {summary}", command.usage).ok();
+    }
+    text
+}
+
+/// What the command `name` does, or the commands starting with it.
+fn help_one(name: &str) -> String {
+    let matching: Vec<&Command> = COMMANDS
+        .iter()
+        .filter(|command| {
+            command.name.len() >= name.len()
+                && command.name[..name.len()].eq_ignore_ascii_case(name)
+        })
+        .collect();
+    match matching[..] {
+        [] => format!("No command {name:?}; `help` lists them"),
+        [command] => format!("{}\n  {}", command.usage.bold(), command.help),
+        _ => matching
+            .iter()
+            .map(|command| format!("{}\n  {}\n", command.usage.bold(), command.help))
+            .collect(),
+    }
+}
+
+/// `frame` laid out to be read, ending with a newline.
+fn pretty(frame: Frame) -> String {
+    match frame {
+        Frame::Sample(_) => String::new(),
+        Frame::Calibration(calibration) => {
+            let c = calibration.center;
+            let s = calibration.scale;
+            format!(
+                "{}\n  centre  {}, {}, {} nT\n  scale   {}, {}, {} /1024\n  radius  {} nT\n",
+                "Calibration".bold(),
+                c.x,
+                c.y,
+                c.z,
+                s.x,
+                s.y,
+                s.z,
+                calibration.radius
+            )
+        }
+        Frame::Captured(field) => format!("Captured: {}, {}, {} nT\n", field.x, field.y, field.z),
+        // Sent every so often rather than in reply, so kept to a line.
+        Frame::Status(fields) => {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect();
+            format!("{}\n", format!("Status: {}", fields.join(", ")).dim())
+        }
+        Frame::SphereCell(cell) => format!(
+            "{} {}/{}: {} samples, {} ± {} nT ({} to {})\n",
+            "Cell".bold(),
+            cell.index,
+            cell.cells,
+            cell.count,
+            cell.mean_nt,
+            cell.std_dev_nt,
+            cell.min_nt,
+            cell.max_nt
+        ),
+        Frame::SphereDiff(diff) => format!(
+            "{} {}/{}: {} nT ({} samples) to {} nT ({} samples), {:+} nT\n",
+            "Difference".bold(),
+            diff.index,
+            diff.cells,
+            diff.mean_before_nt,
+            diff.count_before,
+            diff.mean_after_nt,
+            diff.count_after,
+            diff.difference_nt
+        ),
+        Frame::Line(line) => {
+            if line.starts_with("Warning:") || line.starts_with("Anomaly:") {
+                return format!("{}\n", line.yellow());
+            }
+            if let Some((name, rest)) = line.split_once(':') {
+                let fields = parse_status(rest);
+                // A line of fields, rather than one that happens to hold an `=`.
+                if fields.len() >= 2 {
+                    return table(name, &fields);
+                }
+            }
+            format!("{line}\n")
+        }
+        Frame::Other { kind, payload } => {
+            format!(
+                "{}\n",
+                format!("Frame of kind {kind}, {} bytes", payload.len()).dim()
+            )
+        }
+        Frame::Corrupt(bytes) => {
+            format!(
+                "{}\n",
+                format!("Dropped {bytes} bytes that didn't decode").dim()
+            )
+        }
+    }
+}
+
+/// `fields` a row each under `name`.
+fn table(name: &str, fields: &[(String, String)]) -> String {
+    let width = fields.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    let mut text = format!("{}\n", name.bold());
+    for (key, value) in fields {
+        writeln!(text, "  {key:width$}  {value}").ok();
+    }
+    text
+}
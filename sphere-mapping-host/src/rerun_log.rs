@@ -0,0 +1,170 @@
+//! Samples, the board's attitude and the sphere map logged to a Rerun
+//! viewer, which can scrub back through them in time and look round them in
+//! 3D, so there is no viewer of our own to keep up for everything.
+//!
+//! Everything is logged against the board's time (`board_time`), from its
+//! timestamps, and when the host received it (`host_time`):
+//!
+//! - `field/x`, `field/y`, `field/z` and `field/strength`, the calibrated
+//!   field in nT, and `attitude/heading`, `attitude/pitch` and
+//!   `attitude/roll` in degrees (see [`attitude`](crate::attitude));
+//! - `world/board`, a box turned as the board is, by its heading, pitch and
+//!   roll, in a world of East, North and Up, with `world/board/field` the
+//!   field on it, a thousand nT to the board's millimetre;
+//! - `sphere_map`, the cells of the board's sphere map that have arrived,
+//!   one point each along its direction, coloured from blue for the weakest
+//!   average strength to red for the strongest, and grey with no samples;
+//! - `log`, the board's other lines.
+
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rerun::{
+    Arrows3D, Boxes3D, Color, Points3D, Quaternion, RecordingStream, RecordingStreamBuilder,
+    Scalars, TextLog, Transform3D, ViewCoordinates,
+};
+use sphere_mapping_core::frame::KIND_SPHERE_CELL;
+use sphere_mapping_core::settings::LoggedSample;
+use sphere_mapping_host::frame::SphereCell;
+
+use crate::attitude::Attitude;
+
+/// The micro:bit's half-sizes in mm.
+const BOARD_HALF_SIZE: [f32; 3] = [26., 20.5, 3.];
+/// nT per mm of the field's arrow.
+const FIELD_SCALE: f32 = 1000.;
+
+pub struct RerunLog {
+    stream: RecordingStream,
+    cells: Vec<Option<SphereCell>>,
+}
+
+impl RerunLog {
+    /// Starts a Rerun viewer, or connects to the one already running.
+    pub fn spawn() -> io::Result<RerunLog> {
+        let stream = RecordingStreamBuilder::new("sphere_mapping")
+            .spawn()
+            .map_err(io::Error::other)?;
+        stream
+            .log_static("world", &ViewCoordinates::RIGHT_HAND_Z_UP())
+            .map_err(io::Error::other)?;
+        stream
+            .log_static("world/board", &Boxes3D::from_half_sizes([BOARD_HALF_SIZE]))
+            .map_err(io::Error::other)?;
+        Ok(RerunLog {
+            stream,
+            cells: Vec::new(),
+        })
+    }
+
+    pub fn sample(&mut self, sample: &LoggedSample) -> io::Result<()> {
+        self.set_time(Some(sample.timestamp_us));
+        let attitude = Attitude::of(sample);
+        let field = sample.field;
+        let scalars = [
+            ("field/x", field.x as f64),
+            ("field/y", field.y as f64),
+            ("field/z", field.z as f64),
+            ("field/strength", attitude.magnitude_nt as f64),
+            ("attitude/heading", attitude.heading_deg as f64),
+            ("attitude/pitch", attitude.pitch_deg as f64),
+            ("attitude/roll", attitude.roll_deg as f64),
+        ];
+        for (path, value) in scalars {
+            self.log(path, &Scalars::single(value))?;
+        }
+        self.log(
+            "world/board",
+            &Transform3D::from_rotation(Quaternion::from_xyzw(rotation(&attitude))),
+        )?;
+        let arrow = [field.x, field.y, field.z].map(|axis| axis as f32 / FIELD_SCALE);
+        self.log("world/board/field", &Arrows3D::from_vectors([arrow]))
+    }
+
+    /// Adds a sphere map cell's frame to the map and logs the map.
+    pub fn frame(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        let Some(cell) = (kind == KIND_SPHERE_CELL)
+            .then(|| SphereCell::decode(payload))
+            .flatten()
+        else {
+            return Ok(());
+        };
+        if self.cells.len() != cell.cells {
+            self.cells = vec![None; cell.cells];
+        }
+        let Some(slot) = self.cells.get_mut(cell.index) else {
+            return Ok(());
+        };
+        *slot = Some(cell);
+        self.set_time(None);
+        let cells: Vec<SphereCell> = self.cells.iter().flatten().copied().collect();
+        let means = cells
+            .iter()
+            .filter(|cell| cell.count > 0)
+            .map(|cell| cell.mean_nt);
+        let (low, high) = (means.clone().min().unwrap_or(0), means.max().unwrap_or(0));
+        let colors = cells.iter().map(|cell| match cell.count {
+            0 => Color::from_rgb(128, 128, 128),
+            _ => {
+                let share = (cell.mean_nt - low) as f32 / (high - low).max(1) as f32;
+                Color::from_rgb((255. * share) as u8, 60, (255. * (1. - share)) as u8)
+            }
+        });
+        let points = Points3D::new(cells.iter().map(|cell| cell.direction))
+            .with_colors(colors)
+            .with_radii([0.03]);
+        self.log("sphere_map", &points)
+    }
+
+    pub fn line(&mut self, line: &str) -> io::Result<()> {
+        self.set_time(None);
+        self.log("log", &TextLog::new(line))
+    }
+
+    /// Sets when what is logged next happened, on the board's clock if
+    /// known, and on the host's.
+    fn set_time(&self, timestamp_us: Option<u64>) {
+        if let Some(timestamp_us) = timestamp_us {
+            self.stream
+                .set_duration_secs("board_time", timestamp_us as f64 / 1e6);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.stream
+            .set_timestamp_secs_since_epoch("host_time", now.as_secs_f64());
+    }
+
+    fn log(&self, path: &str, item: &impl rerun::AsComponents) -> io::Result<()> {
+        self.stream.log(path, item).map_err(io::Error::other)
+    }
+}
+
+/// The board's rotation from lying flat with its top edge to North, as a
+/// quaternion `[x, y, z, w]`: turned to its heading about Up, then pitched
+/// about its X axis, so a raised bottom edge tips its top edge down, then
+/// rolled about its Y axis, so a raised left edge tips its right edge down.
+fn rotation(attitude: &Attitude) -> [f32; 4] {
+    let heading = about([0., 0., 1.], -attitude.heading_deg.to_radians());
+    let pitch = about([1., 0., 0.], -attitude.pitch_deg.to_radians());
+    let roll = about([0., 1., 0.], attitude.roll_deg.to_radians());
+    multiply(multiply(heading, pitch), roll)
+}
+
+/// The rotation by `angle` radians about the unit vector `axis`.
+fn about(axis: [f32; 3], angle: f32) -> [f32; 4] {
+    let (sin, cos) = (angle / 2.).sin_cos();
+    [axis[0] * sin, axis[1] * sin, axis[2] * sin, cos]
+}
+
+/// The rotation `b` then `a`.
+fn multiply(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    let [ax, ay, az, aw] = a;
+    let [bx, by, bz, bw] = b;
+    [
+        aw * bx + ax * bw + ay * bz - az * by,
+        aw * by - ax * bz + ay * bw + az * bx,
+        aw * bz + ax * by - ay * bx + az * bw,
+        aw * bw - ax * bx - ay * by - az * bz,
+    ]
+}
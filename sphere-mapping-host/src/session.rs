@@ -0,0 +1,163 @@
+//! `session`: logs several boards at once into one session log, as for
+//! boards side by side on a jig running different calibrations. Each
+//! board's port is read on a thread of its own, and each sample goes into
+//! the log as it arrives, tagged with the board it came from (see
+//! [`CsvLog::session`]): its USB serial number where the port has one,
+//! which stays the same whichever port it is plugged into, or else the
+//! port's name.
+//!
+//! A board that goes away, unplugged or reset into its bootloader, is
+//! reported and the others carry on; the session ends once none is left.
+//! Events from any of them can be passed on as they happen (see
+//! [`notify`](crate::notify)).
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+use clap::Args;
+use sphere_mapping_core::stream::{Decoder, Packet};
+use sphere_mapping_host::port;
+
+use crate::csv_log::{CsvLog, Rotation};
+use crate::declination::DeclinationArgs;
+use crate::notify::{Notifier, NotifyArgs};
+
+#[derive(Args)]
+pub struct SessionArgs {
+    /// Serial port of a board to log, given once for each; every board
+    /// found by its USB IDs if left out.
+    #[arg(long)]
+    port: Vec<String>,
+    #[arg(long, default_value_t = sphere_mapping_core::config::BAUD_RATE)]
+    baud: u32,
+    /// Directory the CSV files are written to.
+    #[arg(long, default_value = ".")]
+    dir: PathBuf,
+    /// Start of each file's name, before its timestamp.
+    #[arg(long, default_value = "session")]
+    prefix: String,
+    /// Start a new file once this many megabytes have been written; 0
+    /// never does.
+    #[arg(long, default_value_t = 100)]
+    rotate_mb: u64,
+    /// Start a new file after this many minutes; 0 never does.
+    #[arg(long, default_value_t = 60)]
+    rotate_min: u64,
+    /// Also print the boards' other lines, such as status and warnings,
+    /// and what was dropped for not decoding.
+    #[arg(long, short)]
+    verbose: bool,
+    #[command(flatten)]
+    declination: DeclinationArgs,
+    #[command(flatten)]
+    notify: NotifyArgs,
+}
+
+/// What a board's thread hands on: a packet, or why it stopped.
+type Received = (usize, io::Result<Packet>);
+
+pub fn run(args: SessionArgs) -> io::Result<()> {
+    let ports = port::list()?;
+    let names: Vec<String> = match args.port.is_empty() {
+        true => ports
+            .iter()
+            .filter(|port| port.identity.is_some())
+            .map(|port| port.name.clone())
+            .collect(),
+        false => args.port.clone(),
+    };
+    if names.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no micro:bits found; are they plugged in?",
+        ));
+    }
+    // By serial number, for a board whose port has one.
+    let devices: Vec<String> = names
+        .iter()
+        .map(|name| {
+            ports
+                .iter()
+                .find(|port| &port.name == name)
+                .and_then(|port| port.serial_number.clone())
+                .unwrap_or_else(|| name.clone())
+        })
+        .collect();
+
+    let declination = args.declination.command()?;
+    let (sender, receiver) = mpsc::channel();
+    for (index, name) in names.iter().enumerate() {
+        let mut port = port::open(name, args.baud)?;
+        if let Some(command) = &declination {
+            port.write_all(format!("{command}\r").as_bytes())?;
+        }
+        eprintln!("Logging {} from {name}", devices[index]);
+        let sender = sender.clone();
+        thread::spawn(move || read(index, port, sender));
+    }
+    drop(sender);
+
+    eprintln!("Logging to {}", args.dir.display());
+    let rotation = Rotation {
+        max_bytes: args.rotate_mb * 1024 * 1024,
+        max_age: Duration::from_secs(args.rotate_min * 60),
+    };
+    let mut log = CsvLog::session(args.dir, args.prefix, rotation);
+    for (name, device) in names.iter().zip(&devices) {
+        log.describe(Some(device), &format!("Source: {name}"))?;
+    }
+    let mut notifier = Notifier::new(&args.notify);
+    // Until every thread has stopped and dropped its sender.
+    for (index, received) in receiver {
+        let device = &devices[index];
+        match received {
+            Ok(Packet::Sample(sample)) => {
+                if let Some(path) = log.write_device(device, &sample)? {
+                    eprintln!("Writing {}", path.display());
+                }
+            }
+            Ok(Packet::Line(line)) => {
+                log.line(Some(device), &line)?;
+                notifier.line(device, &line);
+                if args.verbose {
+                    eprintln!("{device}: {line}");
+                }
+            }
+            Ok(Packet::Corrupt(bytes)) if args.verbose => {
+                eprintln!("{device}: dropped {bytes} bytes that didn't decode")
+            }
+            Ok(Packet::Frame { .. } | Packet::Corrupt(_)) => {}
+            Err(err) => {
+                eprintln!("{device}: stopped logging: {err}");
+                notifier.disconnected(device, &err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes what the board `index` sends on `port` and hands it on, until
+/// the port fails or nobody is listening.
+fn read(index: usize, mut port: Box<dyn serialport::SerialPort>, sender: Sender<Received>) {
+    let mut decoder = Decoder::new();
+    let mut packets = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        match port.read(&mut buffer) {
+            Ok(len) => decoder.push(&buffer[..len], &mut packets),
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) => {
+                sender.send((index, Err(err))).ok();
+                return;
+            }
+        }
+        for packet in packets.drain(..) {
+            if sender.send((index, Ok(packet))).is_err() {
+                return;
+            }
+        }
+    }
+}
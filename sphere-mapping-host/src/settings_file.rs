@@ -0,0 +1,204 @@
+//! Settings files for `config push`: the board's settings in TOML, named as
+//! its `Settings:` line names them, checked against the serial protocol
+//! before any is sent, then sent a command each, with the `Settings:` line
+//! the board replies with checked for each.
+//!
+//! ```toml
+//! rotation = 90         # SROT: 0, 90, 180 or 270 degrees
+//! hold = 100            # SHLD: ms each LED frame is held at least
+//! brightness = "auto"   # SBRT: 1-9, or "auto" to follow the ambient light
+//! mode = "compass"      # SMOD: "compass", "clock" or "level"
+//! format = "binary"     # SFMT: "text", "csv" or "binary"
+//! every = 1             # SRPT: send every nth sample, 1-255
+//! smoothing = 0         # SFLT: field smoothing weight, 0-255, 0 for none
+//! declination = -1.5    # SDEC: degrees, East positive, to a tenth
+//! power = "normal"      # SPWR: "normal", "battery" or "high-rate"
+//! sleep = "off"         # SIDL: minutes still before powering down, or "off"
+//! ```
+//!
+//! Any can be left out. Each command is checked with the firmware's own
+//! parser (see [`parse_command`]), so what this accepts the board does.
+//! The sample and baud rates are fixed when the firmware is built (see
+//! [`config`](sphere_mapping_core::config)), so can't be pushed.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use sphere_mapping_core::command::{parse_command, SerialCommand};
+use sphere_mapping_core::led::DisplayMode;
+use sphere_mapping_core::settings::{OutputFormat, PowerMode};
+use sphere_mapping_core::stream::Packet;
+use sphere_mapping_host::frame::parse_status;
+use toml::{Table, Value};
+
+use crate::source::Source;
+
+/// How long the board has to reply to each setting.
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The settings a file can hold, in the order they are sent: each one's
+/// name, command and the values it takes.
+const KEYS: [(&str, &str, &str); 10] = [
+    ("rotation", "SROT", "0, 90, 180 or 270"),
+    ("hold", "SHLD", "0-65535 ms"),
+    ("brightness", "SBRT", "1-9 or \"auto\""),
+    ("mode", "SMOD", "\"compass\", \"clock\" or \"level\""),
+    ("format", "SFMT", "\"text\", \"csv\" or \"binary\""),
+    ("every", "SRPT", "1-255"),
+    ("smoothing", "SFLT", "0-255"),
+    ("declination", "SDEC", "-180 to 180 degrees"),
+    ("power", "SPWR", "\"normal\", \"battery\" or \"high-rate\""),
+    ("sleep", "SIDL", "1-255 minutes or \"off\""),
+];
+
+/// One setting to push: its command, and what the board's `Settings:`
+/// line should give it once it is applied.
+pub struct Field {
+    pub key: &'static str,
+    pub command: String,
+    pub expected: String,
+}
+
+/// Reads and checks a settings file, returning every problem with it at
+/// once if there are any.
+pub fn load(path: &Path) -> io::Result<Vec<Field>> {
+    let text = fs::read_to_string(path)?;
+    let table: Table = text
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{err}")))?;
+    let mut problems = Vec::new();
+    for key in table.keys() {
+        if !KEYS.iter().any(|(name, ..)| name == key) {
+            let names: Vec<&str> = KEYS.iter().map(|(name, ..)| *name).collect();
+            problems.push(format!(
+                "unknown setting `{key}`; settings are {}",
+                names.join(", ")
+            ));
+        }
+    }
+    let mut fields = Vec::new();
+    for (key, prefix, takes) in KEYS {
+        let Some(value) = table.get(key) else {
+            continue;
+        };
+        let field = argument(key, value).and_then(|(argument, expected)| {
+            let command = format!("{prefix} {argument}");
+            let accepted = parse_command(command.as_bytes()) != SerialCommand::Unknown;
+            accepted.then_some(Field {
+                key,
+                command,
+                expected,
+            })
+        });
+        match field {
+            Some(field) => fields.push(field),
+            None => problems.push(format!("`{key}` = {value}: takes {takes}")),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            problems.join("\n"),
+        ));
+    }
+    Ok(fields)
+}
+
+/// Sends each of `fields` in turn and checks the board's reply to it,
+/// printing how each went. Returns how many the board didn't acknowledge.
+pub fn push(source: &mut Source, fields: &[Field]) -> io::Result<usize> {
+    let mut failed = 0;
+    let mut packets = Vec::new();
+    for field in fields {
+        source.send(&field.command)?;
+        let reply = settings_reply(source, &mut packets)?;
+        let actual = reply.as_ref().and_then(|reply| {
+            reply
+                .iter()
+                .find(|(key, _)| key == field.key)
+                .map(|(_, value)| value.as_str())
+        });
+        let Field { key, expected, .. } = field;
+        match actual {
+            Some(actual) if actual == expected => println!("{key} = {expected}: acknowledged"),
+            Some(actual) => {
+                println!("{key} = {expected}: the board has {actual}");
+                failed += 1;
+            }
+            None if reply.is_some() => {
+                println!("{key} = {expected}: the board doesn't report it");
+                failed += 1;
+            }
+            None => {
+                println!("{key} = {expected}: no reply");
+                failed += 1;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// The fields of the next `Settings:` line, if one comes in time.
+fn settings_reply(
+    source: &mut Source,
+    packets: &mut Vec<Packet>,
+) -> io::Result<Option<Vec<(String, String)>>> {
+    let deadline = Instant::now() + ACK_TIMEOUT;
+    while Instant::now() < deadline {
+        let more = source.read(packets)?;
+        for packet in packets.drain(..) {
+            if let Packet::Line(line) = packet {
+                if let Some(fields) = line.strip_prefix("Settings:") {
+                    return Ok(Some(parse_status(fields)));
+                }
+            }
+        }
+        if !more {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// The argument of `key`'s command for `value`, and how the `Settings:`
+/// line gives the value, if it is of the right type.
+fn argument(key: &str, value: &Value) -> Option<(String, String)> {
+    let named = |name: &str, names: fn(u8) -> Option<&'static str>| {
+        (0..=u8::MAX)
+            .map_while(|index| Some((index, names(index)?)))
+            .find(|(_, known)| *known == name)
+            .map(|(index, known)| (index.to_string(), known.to_string()))
+    };
+    match (key, value) {
+        ("brightness", Value::String(text)) if text == "auto" => Some(("0".into(), "auto".into())),
+        ("brightness", Value::Integer(0)) => Some(("0".into(), "auto".into())),
+        ("sleep", Value::String(text)) if text == "off" => Some(("0".into(), "off".into())),
+        ("sleep", Value::Integer(0)) => Some(("0".into(), "off".into())),
+        ("mode", Value::String(name)) => named(name, |index| {
+            DisplayMode::from_index(index).map(DisplayMode::name)
+        }),
+        ("format", Value::String(name)) => named(name, |index| {
+            OutputFormat::from_index(index).map(OutputFormat::name)
+        }),
+        ("power", Value::String(name)) => named(name, |index| {
+            PowerMode::from_index(index).map(PowerMode::name)
+        }),
+        ("declination", Value::Float(_) | Value::Integer(_)) => {
+            let degrees = value.as_float().or(value.as_integer().map(|n| n as f64))?;
+            let tenths = (degrees * 10.).round();
+            if !tenths.is_finite() || tenths.abs() > i16::MAX as f64 {
+                return None;
+            }
+            let tenths = tenths as i16;
+            let sign = if tenths < 0 { "-" } else { "" };
+            let magnitude = tenths.unsigned_abs();
+            let shown = format!("{sign}{}.{}", magnitude / 10, magnitude % 10);
+            Some((tenths.to_string(), shown))
+        }
+        ("mode" | "format" | "power" | "declination", _) => None,
+        (_, Value::Integer(number)) => Some((number.to_string(), number.to_string())),
+        _ => None,
+    }
+}
@@ -0,0 +1,329 @@
+//! `sniff`: what the board sent, byte by byte, for debugging the protocol
+//! itself rather than reading the board. Raw bytes, captured to a file or
+//! tee'd from the port into stdin, or read off the port, go through the
+//! same [`Decoder`] as everywhere else, and each piece it decodes is printed
+//! with where it starts in the stream and how long it is: lines as text,
+//! frames by kind, and bytes that didn't decode in hex, with why for a
+//! frame that failed its CRC. Nothing is ever sent, so it can watch a port
+//! another program is using.
+//!
+//! Between them it marks what went missing: timestamp gaps, where samples'
+//! timestamps jump by more than the interval between them (see
+//! [`TimestampGaps`]), timestamps going backwards as after a reset, sphere
+//! map cells skipped in an export, and the bytes the board says it dropped
+//! in its `Status:` lines. Sample frames carry no sequence number, so a
+//! timestamp gap is taken for missing samples, though the board stalling
+//! or changing its rate makes one too.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use clap::Args;
+use sphere_mapping_core::frame::{
+    crc16, KIND_SAMPLE, KIND_SAMPLE_GYRO, KIND_SPHERE_CELL, KIND_SPHERE_DIFF, SYNC,
+};
+use sphere_mapping_core::stream::{Decoder, Packet, Span};
+use sphere_mapping_host::frame::{parse_status, SphereCell, SphereDiff};
+use sphere_mapping_host::port;
+
+use crate::link::{TimestampGap, TimestampGaps};
+
+/// Most bytes of a frame or of what didn't decode shown in hex, unless
+/// `--hex` is given.
+const HEX_LIMIT: usize = 32;
+
+#[derive(Args)]
+pub struct SniffArgs {
+    /// Raw bytes captured from the board, or `-` for stdin; if left out,
+    /// the board's port is read.
+    #[arg(conflicts_with = "port")]
+    input: Option<PathBuf>,
+    /// Serial port the board is on; found by its USB IDs if left out.
+    #[arg(long)]
+    port: Option<String>,
+    #[arg(long, default_value_t = sphere_mapping_core::config::BAUD_RATE)]
+    baud: u32,
+    /// Show every frame's bytes in hex, and all of them.
+    #[arg(long, short = 'x')]
+    hex: bool,
+}
+
+pub fn run(args: SniffArgs) -> io::Result<()> {
+    let (name, mut input): (String, Box<dyn Read>) = match &args.input {
+        Some(path) if path.as_os_str() == "-" => ("stdin".into(), Box::new(io::stdin())),
+        Some(path) => (path.display().to_string(), Box::new(File::open(path)?)),
+        None => {
+            let (name, port) = port::connect(args.port.clone(), args.baud)?;
+            (name, port)
+        }
+    };
+    eprintln!("Sniffing {name}");
+    let mut sniffer = Sniffer::new(args.hex);
+    let mut buffer = [0u8; 1024];
+    loop {
+        match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => sniffer.push(&buffer[..len])?,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    // The last line may not have been ended.
+    sniffer.push(b"\n")?;
+    sniffer.summary();
+    Ok(())
+}
+
+/// The bytes not yet printed, and what has been seen so far.
+struct Sniffer {
+    hex: bool,
+    decoder: Decoder,
+    spans: Vec<Span>,
+    /// Bytes pushed from `held_start` on, kept until their span is printed.
+    held: Vec<u8>,
+    held_start: u64,
+    gaps: TimestampGaps,
+    last_cell: Option<(u8, usize)>,
+    packets: u64,
+    corrupt: u64,
+    corrupt_bytes: u64,
+    crc_failures: u64,
+    missing_samples: u64,
+    resets: u64,
+    missing_cells: u64,
+    board_dropped: u64,
+}
+
+impl Sniffer {
+    fn new(hex: bool) -> Sniffer {
+        Sniffer {
+            hex,
+            decoder: Decoder::new(),
+            spans: Vec::new(),
+            held: Vec::new(),
+            held_start: 0,
+            gaps: TimestampGaps::default(),
+            last_cell: None,
+            packets: 0,
+            corrupt: 0,
+            corrupt_bytes: 0,
+            crc_failures: 0,
+            missing_samples: 0,
+            resets: 0,
+            missing_cells: 0,
+            board_dropped: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut held = std::mem::take(&mut self.held);
+        held.extend_from_slice(bytes);
+        self.decoder.push_spans(bytes, &mut self.spans);
+        let mut stdout = io::stdout().lock();
+        let mut end = self.held_start;
+        for span in std::mem::take(&mut self.spans) {
+            let from = (span.start - self.held_start) as usize;
+            let notes = self.note(&span, &held[from..from + span.len], &held[from..]);
+            writeln!(stdout, "{:08x}  {:>4}  {}", span.start, span.len, notes[0])?;
+            for note in &notes[1..] {
+                writeln!(stdout, "{:16}{note}", "")?;
+            }
+            end = span.start + span.len as u64;
+        }
+        stdout.flush()?;
+        held.drain(..(end - self.held_start) as usize);
+        self.held = held;
+        self.held_start = end;
+        Ok(())
+    }
+
+    /// What `span` was, then anything it shows went missing. `raw` is its
+    /// bytes, and `from` them on everything held, for a failed frame that
+    /// runs on past the next sync.
+    fn note(&mut self, span: &Span, raw: &[u8], from: &[u8]) -> Vec<String> {
+        let framed = raw.starts_with(&SYNC);
+        let Some(packet) = &span.packet else {
+            return vec![format!("line   {}", text(raw))];
+        };
+        self.packets += 1;
+        let mut notes = match packet {
+            Packet::Sample(sample) => {
+                let described = if framed {
+                    format!("frame  kind {} sample", raw[2])
+                } else {
+                    format!("line   {}", text(raw))
+                };
+                let mut notes = vec![described];
+                notes.extend(self.sample(sample.timestamp_us));
+                notes
+            }
+            Packet::Line(line) => {
+                let mut notes = vec![format!("line   {}", text(raw))];
+                if line.starts_with("Map:") {
+                    self.last_cell = None;
+                }
+                if let Some(fields) = line.strip_prefix("Status:") {
+                    let dropped = parse_status(fields)
+                        .into_iter()
+                        .find(|(key, _)| key == "dropped")
+                        .and_then(|(_, value)| value.parse::<u64>().ok())
+                        .unwrap_or(0);
+                    if dropped > 0 {
+                        self.board_dropped += dropped;
+                        notes.push(format!(
+                            "! the board dropped {dropped} bytes it couldn't send"
+                        ));
+                    }
+                }
+                notes
+            }
+            Packet::Frame { kind, payload } => {
+                let mut notes = vec![self.frame(*kind, payload)];
+                notes.extend(self.cell(*kind, payload));
+                notes
+            }
+            Packet::Corrupt(bytes) => {
+                self.corrupt += 1;
+                self.corrupt_bytes += *bytes as u64;
+                let mut notes = vec![format!("bad    {}", self.hex_bytes(raw))];
+                if framed {
+                    self.crc_failures += 1;
+                    notes.push(format!("! {}", crc_failure(from)));
+                } else {
+                    notes.push("! dropped: a line cut short by a frame, or too long".into());
+                }
+                notes
+            }
+        };
+        if framed && self.hex && !matches!(packet, Packet::Corrupt(_)) {
+            notes.insert(1, self.hex_bytes(raw));
+        }
+        notes
+    }
+
+    /// Notes on a timestamp gap before the sample taken at `timestamp_us`.
+    fn sample(&mut self, timestamp_us: u64) -> Option<String> {
+        match self.gaps.sample(timestamp_us)? {
+            TimestampGap::Reset { back_us } => {
+                self.resets += 1;
+                Some(format!(
+                    "! timestamp went back {:.3} s: the board reset?",
+                    back_us as f64 / 1e6
+                ))
+            }
+            TimestampGap::Missing {
+                interval_us,
+                expected_us,
+                missing,
+            } => {
+                self.missing_samples += missing;
+                Some(format!(
+                    "! timestamp gap of {:.3} s, {:.3} s expected: about {missing} samples missing",
+                    interval_us as f64 / 1e6,
+                    expected_us as f64 / 1e6
+                ))
+            }
+        }
+    }
+
+    fn frame(&self, kind: u8, payload: &[u8]) -> String {
+        match kind {
+            KIND_SAMPLE | KIND_SAMPLE_GYRO => {
+                format!(
+                    "frame  kind {kind} sample, {} bytes too short",
+                    payload.len()
+                )
+            }
+            KIND_SPHERE_CELL => match SphereCell::decode(payload) {
+                Some(cell) => format!(
+                    "frame  kind {kind} sphere map cell {} of {}, {} samples",
+                    cell.index, cell.cells, cell.count
+                ),
+                None => format!("frame  kind {kind} sphere map cell, too short"),
+            },
+            KIND_SPHERE_DIFF => match SphereDiff::decode(payload) {
+                Some(cell) => format!(
+                    "frame  kind {kind} sphere map difference cell {} of {}",
+                    cell.index, cell.cells
+                ),
+                None => format!("frame  kind {kind} sphere map difference cell, too short"),
+            },
+            _ => format!("frame  kind {kind}, unknown, {} bytes", payload.len()),
+        }
+    }
+
+    /// Notes on sphere map cells skipped before this frame's, if it is one.
+    fn cell(&mut self, kind: u8, payload: &[u8]) -> Option<String> {
+        let (index, cells) = match kind {
+            KIND_SPHERE_CELL => SphereCell::decode(payload).map(|cell| (cell.index, cell.cells)),
+            KIND_SPHERE_DIFF => SphereDiff::decode(payload).map(|cell| (cell.index, cell.cells)),
+            _ => None,
+        }?;
+        let expected = match self.last_cell.replace((kind, index)) {
+            Some((last_kind, last)) if last_kind == kind && index > last => last + 1,
+            _ => 0,
+        };
+        // An export can start anywhere when cells are sent live as they
+        // fill in, so only skips within one are counted.
+        if expected == 0 || index == expected {
+            return None;
+        }
+        let missing = index - expected;
+        self.missing_cells += missing as u64;
+        Some(format!(
+            "! cells {expected}-{} of {cells} missing",
+            index - 1
+        ))
+    }
+
+    /// `raw` in hex, cut short unless `--hex` was given.
+    fn hex_bytes(&self, raw: &[u8]) -> String {
+        let shown = match self.hex {
+            true => raw,
+            false => &raw[..raw.len().min(HEX_LIMIT)],
+        };
+        let mut hex: Vec<String> = shown.iter().map(|byte| format!("{byte:02x}")).collect();
+        if shown.len() < raw.len() {
+            hex.push("…".into());
+        }
+        hex.join(" ")
+    }
+
+    fn summary(&self) {
+        eprintln!(
+            "{} packets; {} didn't decode ({} bytes), {} of them failing their CRC",
+            self.packets, self.corrupt, self.corrupt_bytes, self.crc_failures
+        );
+        eprintln!(
+            "About {} samples missing by timestamp gaps, {} resets, {} sphere map cells missing; the board dropped {} bytes",
+            self.missing_samples, self.resets, self.missing_cells, self.board_dropped
+        );
+    }
+}
+
+/// Why a frame starting `bytes` failed its CRC, as far as there are bytes
+/// to tell.
+fn crc_failure(bytes: &[u8]) -> String {
+    let (Some(&kind), Some(&len)) = (bytes.get(2), bytes.get(3)) else {
+        return "frame header cut short".into();
+    };
+    let end = 4 + len as usize;
+    match bytes.get(end..end + 2) {
+        Some(crc) => format!(
+            "kind {kind} frame of {len} payload bytes failed its CRC: sent {:04x}, computed {:04x}",
+            u16::from_le_bytes([crc[0], crc[1]]),
+            crc16(&bytes[SYNC.len()..end])
+        ),
+        None => format!("kind {kind} frame of {len} payload bytes cut short by the next one"),
+    }
+}
+
+/// A line as text, without its ending, with anything unprintable escaped.
+fn text(raw: &[u8]) -> String {
+    String::from_utf8_lossy(raw)
+        .trim_end_matches(['\r', '\n'])
+        .escape_debug()
+        .to_string()
+}
@@ -0,0 +1,229 @@
+//! Where packets come from: the board, or a log of it replayed, so
+//! everything downstream of the decoder runs the same either way and can be
+//! worked on without a board attached.
+//!
+//! A replay takes a serial log, whatever the board sent saved to a file,
+//! which goes through the same [`Decoder`] as live data, or one of `log`'s
+//! CSV files. Samples are let out at the pace the board took them, by their
+//! timestamps, sped up by `--speed`, or as fast as they can be read with
+//! `--speed 0`; other lines go out straight after the sample before them.
+//! A timestamp going backwards, as after the board reset, restarts the
+//! pacing there. Commands sent to a replay go nowhere.
+//!
+//! With `--location`, the board is sent the declination where it is as
+//! soon as its port is opened (see [`declination`](crate::declination)).
+//!
+//! With the `ble` feature, `--ble` reads the board over Bluetooth LE
+//! instead of its serial port (see [`ble`](crate::ble)), which is otherwise
+//! the same.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Args;
+use sphere_mapping_core::stream::{Decoder, Packet};
+use sphere_mapping_host::port;
+
+use crate::csv_log;
+use crate::declination::DeclinationArgs;
+
+/// Longest a replay waits before returning, so callers can look up between
+/// packets, as a serial read times out.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Where to read from, shared by every command that reads the board.
+#[derive(Args, Clone)]
+pub struct SourceArgs {
+    /// Serial port the board is on; found by its USB IDs if left out.
+    #[arg(long)]
+    pub port: Option<String>,
+    #[arg(long, default_value_t = sphere_mapping_core::config::BAUD_RATE)]
+    pub baud: u32,
+    /// Replay this serial log or `log` CSV file instead of reading the
+    /// board.
+    #[arg(long, conflicts_with = "port")]
+    pub replay: Option<PathBuf>,
+    /// Read the board over Bluetooth LE, at this address, instead of its
+    /// serial port.
+    #[cfg(feature = "ble")]
+    #[arg(long, conflicts_with_all = ["port", "replay"])]
+    pub ble: Option<String>,
+    /// How many times faster than it was recorded to replay; 0 for as fast
+    /// as possible.
+    #[arg(long, default_value_t = 1.0, requires = "replay")]
+    pub speed: f64,
+    #[command(flatten)]
+    pub declination: DeclinationArgs,
+}
+
+/// What a live board is read from and written to: its serial port, or a
+/// Bluetooth LE connection.
+pub trait Link: io::Read + io::Write + Send {}
+
+impl<T: io::Read + io::Write + Send> Link for T {}
+
+pub enum Source {
+    Live {
+        port: Box<dyn Link>,
+        decoder: Decoder,
+        /// Bytes read from the port.
+        received: u64,
+    },
+    Replay(Replay),
+}
+
+impl Source {
+    /// Opens the board's port or the log to replay, and returns its name
+    /// with it.
+    pub fn open(args: &SourceArgs) -> io::Result<(String, Source)> {
+        match &args.replay {
+            Some(path) => {
+                let replay = Replay::open(path, args.speed)?;
+                Ok((path.display().to_string(), Source::Replay(replay)))
+            }
+            None => {
+                let declination = args.declination.command()?;
+                let (name, port) = connect(args)?;
+                let decoder = Decoder::new();
+                let mut source = Source::Live {
+                    port,
+                    decoder,
+                    received: 0,
+                };
+                if let Some(command) = declination {
+                    source.send(&command)?;
+                }
+                Ok((name, source))
+            }
+        }
+    }
+
+    /// Appends the packets that have arrived to `packets`, waiting a short
+    /// while for some if there are none. Returns `false` once a replay has
+    /// run out, along with its last packets.
+    pub fn read(&mut self, packets: &mut Vec<Packet>) -> io::Result<bool> {
+        match self {
+            Source::Live {
+                port,
+                decoder,
+                received,
+            } => {
+                let mut buffer = [0u8; 1024];
+                match port.read(&mut buffer) {
+                    Ok(len) => {
+                        *received += len as u64;
+                        decoder.push(&buffer[..len], packets);
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+                    Err(err) => return Err(err),
+                }
+                Ok(true)
+            }
+            Source::Replay(replay) => Ok(replay.read(packets)),
+        }
+    }
+
+    /// Bytes read from the board so far; none for a replay, which is read
+    /// as packets.
+    pub fn received(&self) -> u64 {
+        match self {
+            Source::Live { received, .. } => *received,
+            Source::Replay(_) => 0,
+        }
+    }
+
+    /// Sends a command, without its line ending.
+    pub fn send(&mut self, command: &str) -> io::Result<()> {
+        match self {
+            Source::Live { port, .. } => port.write_all(format!("{command}\r").as_bytes()),
+            Source::Replay(_) => Ok(()),
+        }
+    }
+}
+
+/// Opens the link to the board `args` asks for, and returns its name with
+/// it.
+fn connect(args: &SourceArgs) -> io::Result<(String, Box<dyn Link>)> {
+    #[cfg(feature = "ble")]
+    if let Some(address) = &args.ble {
+        let link = crate::ble::Link::connect(address)?;
+        return Ok((format!("ble:{address}"), Box::new(link)));
+    }
+    let (name, port) = port::connect(args.port.clone(), args.baud)?;
+    Ok((name, Box::new(port)))
+}
+
+/// A log's packets, and where the replay has got to.
+pub struct Replay {
+    packets: VecDeque<Packet>,
+    speed: f64,
+    /// When the replay passed a sample taken at this time.
+    started: Option<(Instant, u64)>,
+    last_us: u64,
+}
+
+impl Replay {
+    pub fn open(path: &Path, speed: f64) -> io::Result<Replay> {
+        Ok(Replay {
+            packets: read_log(path)?.into(),
+            speed: if speed.is_finite() { speed.max(0.) } else { 0. },
+            started: None,
+            last_us: 0,
+        })
+    }
+
+    fn read(&mut self, packets: &mut Vec<Packet>) -> bool {
+        while let Some(packet) = self.packets.front() {
+            if let Packet::Sample(sample) = packet {
+                let wait = self.wait_for(sample.timestamp_us);
+                if !wait.is_zero() {
+                    if packets.is_empty() {
+                        thread::sleep(wait.min(MAX_WAIT));
+                    }
+                    return true;
+                }
+            }
+            packets.extend(self.packets.pop_front());
+        }
+        false
+    }
+
+    /// How long until the sample taken at `timestamp_us` is due.
+    fn wait_for(&mut self, timestamp_us: u64) -> Duration {
+        if self.speed == 0. {
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        let (started, first_us) = match self.started {
+            Some(started) if timestamp_us >= self.last_us => started,
+            _ => *self.started.insert((now, timestamp_us)),
+        };
+        self.last_us = timestamp_us;
+        let due = Duration::from_secs_f64((timestamp_us - first_us) as f64 / 1e6 / self.speed);
+        (started + due).saturating_duration_since(now)
+    }
+}
+
+/// Every packet in a serial log or `log` CSV file, in order.
+pub fn read_log(path: &Path) -> io::Result<Vec<Packet>> {
+    let bytes = fs::read(path)?;
+    let mut packets = Vec::new();
+    if bytes.starts_with(csv_log::HEADER.as_bytes()) {
+        let text = String::from_utf8_lossy(&bytes);
+        packets.extend(
+            text.lines()
+                .filter_map(csv_log::parse_row)
+                .map(Packet::Sample),
+        );
+    } else {
+        let mut decoder = Decoder::new();
+        decoder.push(&bytes, &mut packets);
+        // The last line may not have been ended.
+        decoder.push(b"\n", &mut packets);
+    }
+    Ok(packets)
+}
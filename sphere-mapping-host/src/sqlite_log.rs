@@ -0,0 +1,332 @@
+//! Samples logged to an SQLite database, built with the `sqlite` feature,
+//! for monitoring the field over days, where CSV files pile up and have to
+//! be stitched back together to ask anything of them. Each run of `log`
+//! adds a session to the database, so one file can hold every run at a
+//! site, and the tables are:
+//!
+//! - `sessions`: when each run started and, to the second, when it last
+//!   wrote, the source it read and the host tool's version;
+//! - `samples`: a row per sample, as in the CSV log (see [`csv_log`]), with
+//!   the session it belongs to;
+//! - `events`: every line the board sent, such as its boot banner, status,
+//!   warnings and anomalies, with its kind, the part before the `:`;
+//! - `calibrations`: each calibration the board reported, as numbers.
+//!
+//! Times are ISO 8601 in UTC, as in the CSV log, which SQLite's date and
+//! time functions read. For example, the hourly mean field strength:
+//!
+//! ```sql
+//! SELECT strftime('%Y-%m-%d %H:00', host_time) AS hour,
+//!        avg(sqrt(gx * gx + gy * gy + gz * gz)) AS nt
+//! FROM samples GROUP BY hour;
+//! ```
+//!
+//! Rows are written in a transaction committed once a second, with the
+//! database in WAL mode, so a query can be run while the log is being
+//! written and a log cut off loses at most the last second.
+//!
+//! SQLite itself is compiled in, through `rusqlite`, rather than taken
+//! from the system.
+//!
+//! [`csv_log`]: crate::csv_log
+
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+use rusqlite::{params, Connection};
+
+use sphere_mapping_core::calibration::Calibration;
+use sphere_mapping_core::settings::LoggedSample;
+
+use crate::csv_log::row_time;
+
+/// How long rows wait to be committed.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA synchronous = NORMAL;
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    started TEXT NOT NULL,
+    ended TEXT NOT NULL,
+    source TEXT NOT NULL,
+    host TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS samples (
+    session INTEGER NOT NULL REFERENCES sessions (id),
+    host_time TEXT NOT NULL,
+    timestamp_us INTEGER NOT NULL,
+    gx INTEGER NOT NULL,
+    gy INTEGER NOT NULL,
+    gz INTEGER NOT NULL,
+    ax INTEGER NOT NULL,
+    ay INTEGER NOT NULL,
+    az INTEGER NOT NULL,
+    rx REAL,
+    ry REAL,
+    rz REAL
+);
+CREATE INDEX IF NOT EXISTS samples_time ON samples (host_time);
+CREATE TABLE IF NOT EXISTS events (
+    session INTEGER NOT NULL REFERENCES sessions (id),
+    host_time TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    line TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_time ON events (host_time);
+CREATE TABLE IF NOT EXISTS calibrations (
+    session INTEGER NOT NULL REFERENCES sessions (id),
+    host_time TEXT NOT NULL,
+    center_x INTEGER NOT NULL,
+    center_y INTEGER NOT NULL,
+    center_z INTEGER NOT NULL,
+    scale_x INTEGER NOT NULL,
+    scale_y INTEGER NOT NULL,
+    scale_z INTEGER NOT NULL,
+    radius INTEGER NOT NULL
+);
+";
+
+const INSERT_SAMPLE: &str = "INSERT INTO samples VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+const INSERT_EVENT: &str = "INSERT INTO events VALUES (?, ?, ?, ?)";
+const INSERT_CALIBRATION: &str = "INSERT INTO calibrations VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)";
+const END_SESSION: &str = "UPDATE sessions SET ended = ? WHERE id = ?";
+
+/// The database being written, with a session open in it.
+pub struct SqliteLog {
+    database: Connection,
+    session: i64,
+    committed: Instant,
+}
+
+impl SqliteLog {
+    /// Opens the database at `path`, creating it and its tables if they
+    /// don't exist, and starts a session reading `source`.
+    pub fn open(path: &Path, source: &str) -> io::Result<SqliteLog> {
+        let database = Connection::open(path)
+            .map_err(|e| io::Error::other(format!("{}: {e}", path.display())))?;
+        database.execute_batch(SCHEMA).map_err(sql_error)?;
+        let now = row_time(SystemTime::now());
+        let host = format!("sphere-mapping-host {}", env!("CARGO_PKG_VERSION"));
+        database
+            .execute(
+                "INSERT INTO sessions (started, ended, source, host) VALUES (?, ?, ?, ?)",
+                params![now, now, source, host],
+            )
+            .map_err(sql_error)?;
+        let session = database.last_insert_rowid();
+        database.execute_batch("BEGIN").map_err(sql_error)?;
+        Ok(SqliteLog {
+            database,
+            session,
+            committed: Instant::now(),
+        })
+    }
+
+    /// Writes a row for `sample`, received now.
+    pub fn write(&mut self, sample: &LoggedSample) -> io::Result<()> {
+        let LoggedSample {
+            field,
+            accel,
+            timestamp_us,
+            gyro,
+        } = sample;
+        let now = row_time(SystemTime::now());
+        let rate = |axis: usize| gyro.map(|rates| f64::from(rates[axis]));
+        self.run(
+            INSERT_SAMPLE,
+            params![
+                self.session,
+                now,
+                *timestamp_us as i64,
+                field.x,
+                field.y,
+                field.z,
+                accel.x,
+                accel.y,
+                accel.z,
+                rate(0),
+                rate(1),
+                rate(2),
+            ],
+        )?;
+        self.commit_if_due()
+    }
+
+    /// Writes `line` from the board as an event, and as a calibration too
+    /// if it is one.
+    pub fn line(&mut self, line: &str) -> io::Result<()> {
+        let now = row_time(SystemTime::now());
+        let kind = match line.split_once(':') {
+            Some((kind, _)) => kind.trim(),
+            None => "",
+        };
+        self.run(INSERT_EVENT, params![self.session, now, kind, line])?;
+        if let Some(Calibration {
+            center,
+            scale,
+            radius,
+        }) = Calibration::parse(line)
+        {
+            self.run(
+                INSERT_CALIBRATION,
+                params![
+                    self.session,
+                    now,
+                    center.x,
+                    center.y,
+                    center.z,
+                    scale.x,
+                    scale.y,
+                    scale.z,
+                    radius,
+                ],
+            )?;
+        }
+        self.commit_if_due()
+    }
+
+    /// Commits what has been written, and marks the session as ended now.
+    pub fn commit(&mut self) -> io::Result<()> {
+        let now = row_time(SystemTime::now());
+        self.run(END_SESSION, params![now, self.session])?;
+        self.database
+            .execute_batch("COMMIT; BEGIN")
+            .map_err(sql_error)?;
+        self.committed = Instant::now();
+        Ok(())
+    }
+
+    fn commit_if_due(&mut self) -> io::Result<()> {
+        match self.committed.elapsed() >= COMMIT_INTERVAL {
+            true => self.commit(),
+            false => Ok(()),
+        }
+    }
+
+    /// Runs `sql` with `values`, prepared the first time and kept for the
+    /// next.
+    fn run(&self, sql: &str, values: impl rusqlite::Params) -> io::Result<()> {
+        self.database
+            .prepare_cached(sql)
+            .and_then(|mut statement| statement.execute(values))
+            .map(drop)
+            .map_err(sql_error)
+    }
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(format!("SQL: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sphere_mapping_core::calibration::Measurement;
+    use sphere_mapping_core::device::Acceleration;
+
+    fn sample(timestamp_us: u64, gyro: Option<[f32; 3]>) -> LoggedSample {
+        LoggedSample {
+            field: Measurement {
+                x: 12_000,
+                y: -3_400,
+                z: 45_600,
+            },
+            accel: Acceleration {
+                x: 10,
+                y: -20,
+                z: 990,
+            },
+            timestamp_us,
+            gyro,
+        }
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.db");
+        let written = [
+            sample(1_000, None),
+            sample(u32::MAX as u64 + 7, Some([1.5, -2.25, 0.125])),
+        ];
+        let mut log = SqliteLog::open(&path, "/dev/ttyACM0").unwrap();
+        for sample in &written {
+            log.write(sample).unwrap();
+        }
+        log.line("Calibration: 1,2,3,1024,1025,1026,50000").unwrap();
+        log.commit().unwrap();
+        drop(log);
+
+        let database = Connection::open(&path).unwrap();
+        let mut statement = database
+            .prepare(
+                "SELECT session, timestamp_us, gx, gy, gz, ax, ay, az, rx, ry, rz \
+                 FROM samples ORDER BY rowid",
+            )
+            .unwrap();
+        let read: Vec<(i64, LoggedSample)> = statement
+            .query_map([], |row| {
+                let rate = |index| row.get::<_, Option<f64>>(index);
+                let gyro = match (rate(8)?, rate(9)?, rate(10)?) {
+                    (Some(x), Some(y), Some(z)) => Some([x as f32, y as f32, z as f32]),
+                    _ => None,
+                };
+                let sample = LoggedSample {
+                    field: Measurement {
+                        x: row.get(2)?,
+                        y: row.get(3)?,
+                        z: row.get(4)?,
+                    },
+                    accel: Acceleration {
+                        x: row.get(5)?,
+                        y: row.get(6)?,
+                        z: row.get(7)?,
+                    },
+                    timestamp_us: row.get::<_, i64>(1)? as u64,
+                    gyro,
+                };
+                Ok((row.get(0)?, sample))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let session: i64 = database
+            .query_row(
+                "SELECT id FROM sessions WHERE source = '/dev/ttyACM0'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(read, written.map(|sample| (session, sample)));
+
+        let (kind, radius): (String, i64) = database
+            .query_row(
+                "SELECT kind, radius FROM events, calibrations USING (session)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((kind.as_str(), radius), ("Calibration", 50_000));
+    }
+
+    #[test]
+    fn sessions_accumulate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.db");
+        for source in ["first", "second"] {
+            let mut log = SqliteLog::open(&path, source).unwrap();
+            log.write(&sample(0, None)).unwrap();
+            log.commit().unwrap();
+        }
+        let database = Connection::open(&path).unwrap();
+        let counts: i64 = database
+            .query_row("SELECT count(DISTINCT session) FROM samples", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(counts, 2);
+    }
+}
@@ -0,0 +1,212 @@
+//! A dashboard in the terminal, for watching the board from a host with no
+//! display, such as over SSH: the latest heading, field strength and tilt
+//! (see [`attitude`](crate::attitude)), whether the board is calibrated,
+//! how fast samples, packets and bytes are arriving and what has been lost
+//! on the way (see [`link`](crate::link)). The board's sample rate is from
+//! its status lines, beside the rate they arrive at here.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::Constraint;
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Cell, Row, Table};
+use ratatui::Frame;
+use sphere_mapping_core::stream::Packet;
+use sphere_mapping_host::frame::parse_status;
+
+use crate::attitude::Attitude;
+use crate::link::LinkStats;
+use crate::source::Source;
+
+/// Fastest the dashboard is redrawn.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+const POINTS: [&str; 8] = ["N", "NE", "E", "SE", "S", "SW", "W", "NW"];
+
+/// What has been seen of the board so far.
+struct Dashboard {
+    name: String,
+    latest: Option<Attitude>,
+    link: LinkStats,
+    /// The last `Status:` line's fields.
+    status: Vec<(String, String)>,
+    calibration: Option<String>,
+    /// The last warning, anomaly or other notice.
+    notice: Option<String>,
+    /// Why the source stopped, if it has.
+    stopped: Option<String>,
+}
+
+/// Shows the dashboard for `source` until `q` or Esc is pressed.
+pub fn run(name: String, mut source: Source) -> io::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let mut dashboard = Dashboard::new(name);
+    let mut packets = Vec::new();
+    let mut drawn = Instant::now() - REDRAW_INTERVAL;
+    let result = loop {
+        if dashboard.stopped.is_none() {
+            match source.read(&mut packets) {
+                Ok(true) => {}
+                Ok(false) => dashboard.stopped = Some("replay finished".into()),
+                Err(err) => dashboard.stopped = Some(err.to_string()),
+            }
+            dashboard.link.received(source.received());
+            for packet in packets.drain(..) {
+                dashboard.update(packet);
+            }
+        }
+        if drawn.elapsed() >= REDRAW_INTERVAL {
+            if let Err(err) = terminal.draw(|frame| dashboard.draw(frame)) {
+                break Err(err);
+            }
+            drawn = Instant::now();
+        }
+        // Once nothing is being read, the key press is what to wait for.
+        let wait = match dashboard.stopped {
+            Some(_) => REDRAW_INTERVAL,
+            None => Duration::ZERO,
+        };
+        match quit_pressed(wait) {
+            Ok(false) => {}
+            Ok(true) => break Ok(()),
+            Err(err) => break Err(err),
+        }
+    };
+    ratatui::try_restore()?;
+    result
+}
+
+/// Whether `q`, Esc or Ctrl-C was pressed, waiting up to `wait` for a key.
+fn quit_pressed(wait: Duration) -> io::Result<bool> {
+    while event::poll(wait)? {
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let ctrl_c =
+            key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+        if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+impl Dashboard {
+    fn new(name: String) -> Dashboard {
+        Dashboard {
+            name,
+            latest: None,
+            link: LinkStats::new(),
+            status: Vec::new(),
+            calibration: None,
+            notice: None,
+            stopped: None,
+        }
+    }
+
+    fn update(&mut self, packet: Packet) {
+        self.link.packet(&packet);
+        match packet {
+            Packet::Sample(sample) => self.latest = Some(Attitude::of(&sample)),
+            Packet::Line(line) => {
+                if let Some(fields) = line.strip_prefix("Status:") {
+                    self.status = parse_status(fields);
+                } else if line.starts_with("Calibration:") {
+                    self.calibration = Some(line);
+                } else if ["Warning:", "Anomaly:", "Recovered:"]
+                    .iter()
+                    .any(|prefix| line.starts_with(prefix))
+                {
+                    self.notice = Some(line);
+                }
+            }
+            Packet::Frame { .. } | Packet::Corrupt(_) => {}
+        }
+    }
+
+    fn status_field(&self, key: &str) -> Option<&str> {
+        self.status
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let none = || "-".to_string();
+        let (heading, strength, tilt) = match &self.latest {
+            Some(attitude) => {
+                let point = POINTS[((attitude.heading_deg + 22.5) / 45.) as usize % POINTS.len()];
+                (
+                    format!("{:5.1}° {point}", attitude.heading_deg),
+                    format!("{} nT", attitude.magnitude_nt),
+                    format!(
+                        "pitch {:+.1}°, roll {:+.1}°",
+                        attitude.pitch_deg, attitude.roll_deg
+                    ),
+                )
+            }
+            None => (none(), none(), none()),
+        };
+        let calibrated = match self.status_field("calibrated") {
+            Some("yes") => "yes",
+            Some(_) => "no",
+            None => "-",
+        };
+        let calibration = match &self.calibration {
+            Some(line) => format!("{calibrated}, {line}"),
+            None => calibrated.to_string(),
+        };
+        let board_rate = match self.status_field("samples") {
+            Some(samples) => format!(", board {samples} in its last status"),
+            None => String::new(),
+        };
+        let rate = format!(
+            "{} Hz{board_rate}, {} received",
+            self.link.sample_rate(),
+            self.link.samples
+        );
+        let board = match (
+            self.status_field("mode"),
+            self.status_field("uptime"),
+            self.status_field("load"),
+        ) {
+            (Some(mode), Some(uptime), Some(load)) => {
+                format!("{mode}, up {uptime} s, load {load}%")
+            }
+            _ => none(),
+        };
+        let rows = [
+            ("Heading", heading),
+            ("Field", strength),
+            ("Tilt", tilt),
+            ("Calibrated", calibration),
+            ("Rate", rate),
+            ("Link", self.link.rates()),
+            ("Lost", self.link.losses()),
+            ("Board", board),
+            ("Last", self.notice.clone().unwrap_or_else(none)),
+        ]
+        .map(|(label, value)| {
+            Row::new([
+                Cell::from(label),
+                Cell::from(value).style(Style::new().add_modifier(Modifier::BOLD)),
+            ])
+        });
+        let state = match &self.stopped {
+            Some(why) => format!(" stopped: {why} "),
+            None => " live ".to_string(),
+        };
+        let block = Block::bordered()
+            .title(format!(" {} ", self.name))
+            .title(Line::from(state).right_aligned())
+            .title_bottom(" q quit ");
+        let table = Table::new(rows, [Constraint::Length(11), Constraint::Fill(1)]).block(block);
+        frame.render_widget(table, frame.area());
+    }
+}
@@ -0,0 +1,468 @@
+//! A window that walks through calibrating the board: collecting raw
+//! fields while the board is turned, showing how much of the sphere of
+//! directions they cover and which it still needs, fitting a calibration
+//! to them (see [`ellipsoid`]) and, once it is accepted, sending it to the
+//! board, which applies and saves it.
+//!
+//! Fields come in as for [`cloud`](crate::cloud): from burst captures,
+//! started with the Capture button, and from the board's samples with the
+//! calibration the board is using undone. They are drawn as a turning
+//! cloud with the ellipsoid fitted to them, refitted as they arrive, and
+//! dotted with the directions of a geodesic grid, green once enough fields
+//! point that way from the ellipsoid's centre, with the fit's residual
+//! plotted below as it settles (see [`Convergence`]). The directions still
+//! missing are named by the side of the board to point along the Earth's
+//! field to fill them in, in the frame calibrations are fitted in: East,
+//! North and Up are the right edge, the top edge and the front of the
+//! board lying flat with its top edge to North.
+//!
+//! Accepting the fit sends it with `SCAL` and waits for the board to echo
+//! it in a `Calibration:` line, which shows it was applied and saved.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use eframe::egui::{self, Color32};
+use sphere_mapping_core::calibration::{
+    measurement_to_enu, uncalibrated_measurement, Calibration, Measurement,
+};
+use sphere_mapping_core::capture::{parse_captured, MAX_CAPTURE_S};
+use sphere_mapping_core::ellipsoid::{self, Fit, FitError};
+use sphere_mapping_core::geodesic;
+use sphere_mapping_core::gravity;
+use sphere_mapping_core::stream::Packet;
+
+use crate::cloud::{Convergence, Orbit, MAX_POINTS};
+use crate::gui::{self, Event};
+use crate::source::Source;
+use crate::{calibration_numbers, UPLOAD_TIMEOUT};
+
+/// Directions coverage is counted over: the sphere map's default grid.
+const DIRECTION_COUNT: usize = geodesic::vertex_count(4);
+static DIRECTIONS: [[f32; 3]; DIRECTION_COUNT] = geodesic::vertices();
+/// Fields a direction needs to count as covered.
+const COVERED_FIELDS: usize = 3;
+/// Share of directions covered before a fit is worth accepting.
+const GOOD_COVERAGE: f32 = 0.8;
+/// Shortest time between refits as fields arrive.
+const REFIT_INTERVAL: Duration = Duration::from_millis(250);
+/// The sides of the board, as the directions in the fitting frame they
+/// face.
+const SIDES: [([f32; 3], &str); 6] = [
+    ([1., 0., 0.], "right edge"),
+    ([-1., 0., 0.], "left edge"),
+    ([0., 1., 0.], "top edge"),
+    ([0., -1., 0.], "bottom edge"),
+    ([0., 0., 1.], "front"),
+    ([0., 0., -1.], "back"),
+];
+
+const COVERED_COLOR: Color32 = Color32::from_rgb(80, 210, 90);
+const MISSING_COLOR: Color32 = Color32::from_rgb(120, 120, 120);
+
+/// Where the calibration has got to.
+enum Step {
+    /// Collecting fields.
+    Collect,
+    /// Looking over the fit before sending it.
+    Review(Fit),
+    /// Sent it, waiting for the board to echo this line.
+    Upload {
+        fit: Fit,
+        expected: String,
+        sent: Instant,
+    },
+    /// The board has applied and saved it.
+    Done(Fit),
+}
+
+pub struct WizardApp {
+    events: Receiver<Event>,
+    commands: Sender<String>,
+    live: bool,
+    step: Step,
+    /// Raw fields, in the sensor's own axes.
+    raw: VecDeque<Measurement>,
+    /// The calibration the board's samples have had applied.
+    calibration: Option<Calibration>,
+    fit: Option<Result<Fit, FitError>>,
+    fitted: Option<Instant>,
+    stale: bool,
+    /// Fields pointing along each of [`DIRECTIONS`], as of the last fit.
+    coverage: [usize; DIRECTION_COUNT],
+    /// Fields added since the last clear, including any since dropped.
+    added: usize,
+    convergence: Convergence,
+    /// When the burst capture being recorded ends.
+    capturing: Option<Instant>,
+    orbit: Orbit,
+    status: String,
+}
+
+/// Opens the window and calibrates the board from `source` in it until it
+/// is closed, undoing `calibration` on its samples until the board reports
+/// another.
+pub fn run(name: String, source: Source, calibration: Option<Calibration>) -> io::Result<()> {
+    let live = matches!(source, Source::Live { .. });
+    let status =
+        format!("Reading {name}. Capture, then turn the board slowly through every orientation");
+    gui::show(&name, source, move |reader| WizardApp {
+        events: reader.events,
+        commands: reader.commands,
+        live,
+        step: Step::Collect,
+        raw: VecDeque::new(),
+        calibration,
+        fit: None,
+        fitted: None,
+        stale: false,
+        coverage: [0; DIRECTION_COUNT],
+        added: 0,
+        convergence: Convergence::default(),
+        capturing: None,
+        orbit: Orbit::default(),
+        status,
+    })
+}
+
+impl WizardApp {
+    fn collect(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                Event::Packet(Packet::Sample(sample)) => {
+                    if let Some(calibration) = &self.calibration {
+                        self.add(uncalibrated_measurement(sample.field, calibration));
+                    }
+                }
+                Event::Packet(Packet::Line(line)) => {
+                    if let Some(field) = parse_captured(&line) {
+                        self.add(field);
+                    } else if let Some(calibration) = Calibration::parse(&line) {
+                        self.calibration = Some(calibration);
+                        self.confirm(&line);
+                    } else if line == "Capture: done" || line.starts_with("Warning:") {
+                        self.capturing = None;
+                        self.status = line;
+                    }
+                }
+                Event::Packet(_) => {}
+                Event::Ended => self.status = "Replay finished".into(),
+                Event::Failed(err) => self.status = format!("Stopped: {err}"),
+            }
+        }
+        if let Step::Upload { fit, sent, .. } = &self.step {
+            if sent.elapsed() > UPLOAD_TIMEOUT {
+                self.status = "The board didn't confirm the calibration; try again".into();
+                self.step = Step::Review(*fit);
+            }
+        }
+    }
+
+    fn add(&mut self, field: Measurement) {
+        if !matches!(self.step, Step::Collect) {
+            return;
+        }
+        if self.raw.len() == MAX_POINTS {
+            self.raw.pop_front();
+        }
+        self.raw.push_back(field);
+        self.added += 1;
+        self.stale = true;
+    }
+
+    /// Finishes an upload if `line` is the calibration sent.
+    fn confirm(&mut self, line: &str) {
+        if let Step::Upload { fit, expected, .. } = &self.step {
+            if line == expected {
+                self.status = "The board applied and saved the calibration".into();
+                self.step = Step::Done(*fit);
+            }
+        }
+    }
+
+    fn refit(&mut self) {
+        if !self.stale || self.fitted.is_some_and(|at| at.elapsed() < REFIT_INTERVAL) {
+            return;
+        }
+        let fit = ellipsoid::fit(self.raw.make_contiguous());
+        self.coverage = coverage(self.raw.make_contiguous(), fit.as_ref().ok());
+        if let Ok(fit) = fit {
+            self.convergence.push(self.added, fit);
+        }
+        self.fit = Some(fit);
+        self.fitted = Some(Instant::now());
+        self.stale = false;
+    }
+
+    fn covered(&self) -> usize {
+        self.coverage
+            .iter()
+            .filter(|&&fields| fields >= COVERED_FIELDS)
+            .count()
+    }
+
+    /// The sides of the board to point along the field to fill in the
+    /// directions still missing, with how many each would fill, most first.
+    fn missing(&self) -> Vec<(&'static str, usize)> {
+        let mut missing = SIDES.map(|(_, name)| (name, 0));
+        for (direction, &fields) in DIRECTIONS.iter().zip(&self.coverage) {
+            if fields < COVERED_FIELDS {
+                let side = SIDES.map(|(side, _)| side);
+                missing[gravity::nearest(&side, *direction)].1 += 1;
+            }
+        }
+        let mut missing: Vec<_> = missing
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        missing.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        missing
+    }
+
+    fn controls(&mut self, ui: &mut egui::Ui) {
+        match &self.step {
+            Step::Collect => self.collecting(ui),
+            Step::Review(fit) => {
+                let fit = *fit;
+                ui.heading("Review the fit");
+                ui.label(summary(&fit, self.covered()));
+                if (self.covered() as f32) < GOOD_COVERAGE * DIRECTIONS.len() as f32 {
+                    ui.colored_label(
+                        Color32::YELLOW,
+                        "Coverage is low, so the fit may be off in the orientations missing",
+                    );
+                }
+                ui.horizontal(|ui| {
+                    let accept = ui.add_enabled(self.live, egui::Button::new("Accept and upload"));
+                    if accept.clicked() {
+                        self.upload(fit);
+                    }
+                    if ui.button("Collect more").clicked() {
+                        self.step = Step::Collect;
+                    }
+                });
+            }
+            Step::Upload { .. } => {
+                ui.heading("Uploading");
+                ui.label("Waiting for the board to apply and save the calibration");
+            }
+            Step::Done(fit) => {
+                ui.heading("Calibrated");
+                ui.label(summary(fit, self.covered()));
+                if ui.button("Calibrate again").clicked() {
+                    self.clear();
+                    self.step = Step::Collect;
+                }
+            }
+        }
+        ui.label(&self.status);
+    }
+
+    fn collecting(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Collect fields");
+        ui.horizontal(|ui| {
+            let capture = ui.add_enabled(
+                self.live && self.capturing.is_none(),
+                egui::Button::new(format!("Capture {MAX_CAPTURE_S} s")),
+            );
+            if capture.clicked() {
+                self.status = match self.commands.send(format!("SCAP {MAX_CAPTURE_S}")) {
+                    Ok(()) => {
+                        let seconds = Duration::from_secs(MAX_CAPTURE_S as u64);
+                        self.capturing = Some(Instant::now() + seconds);
+                        "Capturing".into()
+                    }
+                    Err(_) => "The board has gone".into(),
+                };
+            }
+            if ui.button("Clear").clicked() {
+                self.clear();
+            }
+            ui.checkbox(&mut self.orbit.spin, "Spin");
+            let fit = match &self.fit {
+                Some(Ok(fit)) => Some(*fit),
+                _ => None,
+            };
+            if ui
+                .add_enabled(fit.is_some(), egui::Button::new("Review fit"))
+                .clicked()
+            {
+                if let Some(fit) = fit {
+                    self.step = Step::Review(fit);
+                }
+            }
+        });
+        if let Some(end) = self.capturing {
+            let left = end.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                ui.label("Receiving the capture");
+            } else {
+                ui.label(format!(
+                    "Recording: {} s left. Keep turning the board",
+                    left.as_secs() + 1
+                ));
+            }
+            ui.ctx().request_repaint_after(Duration::from_millis(200));
+        }
+        let covered = self.covered();
+        ui.label(format!(
+            "{} fields, {covered} of {} directions covered ({:.0}%)",
+            self.raw.len(),
+            DIRECTIONS.len(),
+            covered as f32 * 100. / DIRECTIONS.len() as f32
+        ));
+        let missing = self.missing();
+        if missing.is_empty() {
+            ui.label("Every direction is covered");
+        } else {
+            let sides: Vec<String> = missing
+                .iter()
+                .map(|(side, count)| format!("the {side} ({count})"))
+                .collect();
+            ui.label(format!(
+                "Still missing: point {} along the field, north and dipping down",
+                sides.join(", ")
+            ));
+        }
+        match &self.fit {
+            Some(Ok(fit)) => ui.label(format!(
+                "Residual {:.0} nT RMS, {:.1}% of the field",
+                fit.residual_nt,
+                relative_residual(fit) * 100.
+            )),
+            Some(Err(err)) => ui.label(format!("No fit: {err}")),
+            None => ui.label("No fit yet"),
+        };
+        if let Some((settled, summary)) = self.convergence.summary() {
+            match settled {
+                true => ui.colored_label(COVERED_COLOR, summary),
+                false => ui.label(summary),
+            };
+        }
+        if self.calibration.is_none() {
+            ui.label("Samples are left out until the board's calibration is known");
+        }
+    }
+
+    fn upload(&mut self, fit: Fit) {
+        let command = format!("SCAL {}", calibration_numbers(&fit.calibration));
+        match self.commands.send(command) {
+            Ok(()) => {
+                self.status = "Uploading".into();
+                self.step = Step::Upload {
+                    fit,
+                    expected: fit.calibration.to_string(),
+                    sent: Instant::now(),
+                };
+            }
+            Err(_) => self.status = "The board has gone".into(),
+        }
+        self.orbit.spin = true;
+    }
+
+    fn clear(&mut self) {
+        self.raw.clear();
+        self.fit = None;
+        self.coverage = [0; DIRECTION_COUNT];
+        self.stale = false;
+        self.added = 0;
+        self.convergence.clear();
+    }
+
+    fn draw(&mut self, ui: &mut egui::Ui) {
+        let fit = match &self.step {
+            Step::Collect => self.fit.as_ref().and_then(|fit| fit.as_ref().ok()).copied(),
+            Step::Review(fit) | Step::Upload { fit, .. } | Step::Done(fit) => Some(*fit),
+        };
+        let (painter, view) = self
+            .orbit
+            .draw(ui, self.raw.make_contiguous(), fit.as_ref());
+        let Some(fit) = fit else {
+            return;
+        };
+        let center = fit.calibration.center;
+        let center = [center.x, center.y, center.z].map(|axis| axis as f32);
+        let radii = fit.radii.map(|radius| radius as f32);
+        for (direction, &fields) in DIRECTIONS.iter().zip(&self.coverage) {
+            let point = [0, 1, 2].map(|i| center[i] + radii[i] * direction[i]);
+            let (pos, depth) = view.project(point);
+            let color = match fields >= COVERED_FIELDS {
+                true => COVERED_COLOR,
+                false => MISSING_COLOR,
+            };
+            // The far side fainter, as for the cloud.
+            let alpha = (140. + 110. * depth.clamp(-1., 1.)) as u8;
+            painter.circle_filled(pos, 3.5, color.gamma_multiply(alpha as f32 / 255.));
+        }
+    }
+}
+
+impl eframe::App for WizardApp {
+    fn ui(&mut self, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
+        self.collect();
+        if matches!(self.step, Step::Collect) {
+            self.refit();
+        }
+        egui::Panel::top("controls").show(ui, |ui| self.controls(ui));
+        if matches!(self.step, Step::Collect) {
+            egui::Panel::bottom("convergence").show(ui, |ui| self.convergence.plot(ui));
+        }
+        egui::CentralPanel::default().show(ui, |ui| self.draw(ui));
+    }
+}
+
+/// How many of `raw` point along each of [`DIRECTIONS`] from `fit`'s
+/// centre, its ellipsoid squashed back to a sphere, or from their mean
+/// without one.
+fn coverage(raw: &[Measurement], fit: Option<&Fit>) -> [usize; DIRECTION_COUNT] {
+    let points: Vec<[f64; 3]> = raw
+        .iter()
+        .map(|&field| {
+            let enu = measurement_to_enu(field);
+            [enu.x, enu.y, enu.z].map(f64::from)
+        })
+        .collect();
+    let (center, radii) = match fit {
+        Some(fit) => {
+            let center = fit.calibration.center;
+            ([center.x, center.y, center.z].map(f64::from), fit.radii)
+        }
+        None if !points.is_empty() => {
+            let count = points.len() as f64;
+            let mean = [0, 1, 2].map(|i| points.iter().map(|point| point[i]).sum::<f64>() / count);
+            (mean, [1.; 3])
+        }
+        None => return [0; DIRECTION_COUNT],
+    };
+    let mut coverage = [0; DIRECTION_COUNT];
+    for point in points {
+        let on_sphere = [0, 1, 2].map(|i| ((point[i] - center[i]) / radii[i]) as f32);
+        if let Some(direction) = gravity::unit(on_sphere) {
+            coverage[gravity::nearest(&DIRECTIONS, direction)] += 1;
+        }
+    }
+    coverage
+}
+
+/// The fit's residual as a share of the field's strength.
+fn relative_residual(fit: &Fit) -> f64 {
+    fit.residual_nt / fit.calibration.radius.max(1) as f64
+}
+
+fn summary(fit: &Fit, covered: usize) -> String {
+    let Calibration { center, .. } = fit.calibration;
+    let [rx, ry, rz] = fit.radii;
+    format!(
+        "{} fields covering {covered} of {} directions. Centre {}, {}, {} nT, \
+         radii {rx:.0}, {ry:.0}, {rz:.0} nT, residual {:.0} nT RMS ({:.1}% of the field)",
+        fit.samples,
+        DIRECTIONS.len(),
+        center.x,
+        center.y,
+        center.z,
+        fit.residual_nt,
+        relative_residual(fit) * 100.
+    )
+}
@@ -0,0 +1,286 @@
+//! Python bindings for the host library, as the `sphere_mapping` module,
+//! so the board's logs and stream can be worked with from a notebook: the
+//! same stream decoding and least-squares calibration fit as the host
+//! tools, and a connection to the board to read it and send it commands.
+//!
+//! Frames come out as dicts, which a pandas `DataFrame` takes as rows, with
+//! a `type` saying which they are: `sample` (`timestamp_us`, `field`,
+//! `accel`, `gyro`), `calibration` (`center`, `scale`, `radius`),
+//! `captured` (`field`), `status` (`fields`, a dict), `sphere_cell` and
+//! `sphere_diff` (as in [`frame`](sphere_mapping_host::frame)), `line`
+//! (`text`), `frame` (`kind`, `payload`) or `corrupt` (`bytes`). Vectors
+//! are `(x, y, z)` tuples.
+
+use std::fs;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use futures_core::Stream;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use sphere_mapping_core::calibration::{Calibration, Measurement};
+use sphere_mapping_core::config::BAUD_RATE;
+use sphere_mapping_core::ellipsoid;
+use sphere_mapping_core::stream::{Decoder, Packet};
+use sphere_mapping_host::{Connection as HostConnection, Frame};
+
+/// How often a read waiting on the board looks for Ctrl-C.
+const SIGNAL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Bytes received but not yet decoded.
+#[pyclass]
+#[derive(Default)]
+struct StreamDecoder {
+    decoder: Decoder,
+}
+
+#[pymethods]
+impl StreamDecoder {
+    #[new]
+    fn new() -> StreamDecoder {
+        StreamDecoder::default()
+    }
+
+    /// Decodes `data`, as read from the port, returning the frames it
+    /// completes.
+    fn push<'py>(&mut self, py: Python<'py>, data: &[u8]) -> PyResult<Vec<Bound<'py, PyDict>>> {
+        let mut packets = Vec::new();
+        self.decoder.push(data, &mut packets);
+        to_dicts(py, packets)
+    }
+}
+
+/// Decodes a serial log, whatever the board sent saved to a file.
+#[pyfunction]
+fn read_log<'py>(py: Python<'py>, path: &str) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let bytes = fs::read(path)?;
+    let mut decoder = Decoder::new();
+    let mut packets = Vec::new();
+    decoder.push(&bytes, &mut packets);
+    // The last line may not have been ended.
+    decoder.push(b"\n", &mut packets);
+    to_dicts(py, packets)
+}
+
+/// A calibration fitted to raw fields.
+#[pyclass(frozen, get_all)]
+struct Fit {
+    samples: usize,
+    center: (i32, i32, i32),
+    scale: (i32, i32, i32),
+    radius: u32,
+    /// The ellipsoid's radii, in nT.
+    radii: (f64, f64, f64),
+    /// How far the calibrated strengths still spread, RMS in nT.
+    residual_nt: f64,
+    /// The calibration as its `Calibration:` line.
+    line: String,
+    /// The `SCAL` command that applies and saves it on the board.
+    command: String,
+}
+
+/// Fits a calibration to raw fields in nT, `(x, y, z)` each, as the
+/// `captured` frames of a burst capture give them, by least squares.
+#[pyfunction]
+fn fit(raw: Vec<(i32, i32, i32)>) -> PyResult<Fit> {
+    let raw: Vec<Measurement> = raw
+        .into_iter()
+        .map(|(x, y, z)| Measurement { x, y, z })
+        .collect();
+    let fit = ellipsoid::fit(&raw).map_err(|err| PyValueError::new_err(err.to_string()))?;
+    let calibration = fit.calibration;
+    let line = calibration.to_string();
+    let numbers = line.trim_start_matches("Calibration: ").replace(' ', "");
+    let [rx, ry, rz] = fit.radii;
+    Ok(Fit {
+        samples: fit.samples,
+        center: tuple(calibration.center),
+        scale: tuple(calibration.scale),
+        radius: calibration.radius,
+        radii: (rx, ry, rz),
+        residual_nt: fit.residual_nt,
+        command: format!("SCAL {numbers}"),
+        line,
+    })
+}
+
+/// The board, over its serial port. Iterating over it waits for its next
+/// frame, for as long as it takes, and stops if the port fails.
+#[pyclass]
+struct Connection {
+    name: String,
+    connection: Mutex<HostConnection>,
+}
+
+#[pymethods]
+impl Connection {
+    /// Opens `port`, or the board's port, found by its USB IDs, if it is
+    /// left out.
+    #[new]
+    #[pyo3(signature = (port=None, baud=BAUD_RATE))]
+    fn open(port: Option<&str>, baud: u32) -> PyResult<Connection> {
+        let connection = HostConnection::open(port, baud)?;
+        Ok(Connection {
+            name: connection.name().to_string(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// The name of the port the board is on.
+    #[getter]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sends a command, such as `SCAP 20`, without its line ending.
+    fn send_command(&self, command: &str) -> PyResult<()> {
+        Ok(self.lock().send_command(command)?)
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyDict>>> {
+        loop {
+            let mut guard = self.lock();
+            let connection: &mut HostConnection = &mut guard;
+            let polled = py.detach(|| {
+                let waker = Waker::from(Arc::new(Unpark(thread::current())));
+                match Pin::new(&mut *connection).poll_next(&mut Context::from_waker(&waker)) {
+                    Poll::Ready(frame) => Some(frame),
+                    Poll::Pending => {
+                        thread::park_timeout(SIGNAL_INTERVAL);
+                        None
+                    }
+                }
+            });
+            drop(guard);
+            match polled {
+                Some(Some(frame)) => return to_dict(py, frame?).map(Some),
+                Some(None) => return Ok(None),
+                None => py.check_signals()?,
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HostConnection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Wakes the thread waiting on the connection.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn to_dicts(py: Python<'_>, packets: Vec<Packet>) -> PyResult<Vec<Bound<'_, PyDict>>> {
+    packets
+        .into_iter()
+        .map(|packet| to_dict(py, packet.into()))
+        .collect()
+}
+
+fn to_dict(py: Python<'_>, frame: Frame) -> PyResult<Bound<'_, PyDict>> {
+    let dict = PyDict::new(py);
+    match frame {
+        Frame::Sample(sample) => {
+            dict.set_item("type", "sample")?;
+            dict.set_item("timestamp_us", sample.timestamp_us)?;
+            dict.set_item("field", tuple(sample.field))?;
+            let accel = sample.accel;
+            dict.set_item("accel", (accel.x, accel.y, accel.z))?;
+            dict.set_item("gyro", sample.gyro.map(|[x, y, z]| (x, y, z)))?;
+        }
+        Frame::Calibration(Calibration {
+            center,
+            scale,
+            radius,
+        }) => {
+            dict.set_item("type", "calibration")?;
+            dict.set_item("center", tuple(center))?;
+            dict.set_item("scale", tuple(scale))?;
+            dict.set_item("radius", radius)?;
+        }
+        Frame::Captured(field) => {
+            dict.set_item("type", "captured")?;
+            dict.set_item("field", tuple(field))?;
+        }
+        Frame::Status(fields) => {
+            dict.set_item("type", "status")?;
+            let by_key = PyDict::new(py);
+            for (key, value) in fields {
+                by_key.set_item(key, value)?;
+            }
+            dict.set_item("fields", by_key)?;
+        }
+        Frame::SphereCell(cell) => {
+            dict.set_item("type", "sphere_cell")?;
+            dict.set_item("index", cell.index)?;
+            dict.set_item("cells", cell.cells)?;
+            dict.set_item("direction", direction(cell.direction))?;
+            dict.set_item("count", cell.count)?;
+            dict.set_item("mean_nt", cell.mean_nt)?;
+            dict.set_item("std_dev_nt", cell.std_dev_nt)?;
+            dict.set_item("min_nt", cell.min_nt)?;
+            dict.set_item("max_nt", cell.max_nt)?;
+            dict.set_item("std_error_nt", cell.std_error_nt)?;
+        }
+        Frame::SphereDiff(cell) => {
+            dict.set_item("type", "sphere_diff")?;
+            dict.set_item("index", cell.index)?;
+            dict.set_item("cells", cell.cells)?;
+            dict.set_item("direction", direction(cell.direction))?;
+            dict.set_item("count_before", cell.count_before)?;
+            dict.set_item("mean_before_nt", cell.mean_before_nt)?;
+            dict.set_item("count_after", cell.count_after)?;
+            dict.set_item("mean_after_nt", cell.mean_after_nt)?;
+            dict.set_item("difference_nt", cell.difference_nt)?;
+        }
+        Frame::Line(text) => {
+            dict.set_item("type", "line")?;
+            dict.set_item("text", text)?;
+        }
+        Frame::Other { kind, payload } => {
+            dict.set_item("type", "frame")?;
+            dict.set_item("kind", kind)?;
+            dict.set_item("payload", PyBytes::new(py, &payload))?;
+        }
+        Frame::Corrupt(bytes) => {
+            dict.set_item("type", "corrupt")?;
+            dict.set_item("bytes", bytes)?;
+        }
+    }
+    Ok(dict)
+}
+
+fn tuple(vector: Measurement) -> (i32, i32, i32) {
+    (vector.x, vector.y, vector.z)
+}
+
+fn direction([x, y, z]: [f32; 3]) -> (f32, f32, f32) {
+    (x, y, z)
+}
+
+#[pymodule]
+fn sphere_mapping(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add("BAUD_RATE", BAUD_RATE)?;
+    module.add_class::<StreamDecoder>()?;
+    module.add_class::<Fit>()?;
+    module.add_class::<Connection>()?;
+    module.add_function(wrap_pyfunction!(read_log, module)?)?;
+    module.add_function(wrap_pyfunction!(fit, module)?)?;
+    Ok(())
+}
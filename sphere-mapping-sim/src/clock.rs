@@ -0,0 +1,19 @@
+//! Monotonic microsecond clock for timestamps and timeouts, counting from
+//! boot as the board's does.
+
+use std::time::Instant;
+
+#[derive(Clone, Copy)]
+pub struct Clock(Instant);
+
+impl Clock {
+    /// Starts the clock from 0.
+    pub fn start() -> Clock {
+        Clock(Instant::now())
+    }
+
+    /// Microseconds since the clock started.
+    pub fn now_us(&self) -> u64 {
+        self.0.elapsed().as_micros() as u64
+    }
+}
@@ -0,0 +1,726 @@
+//! The RTIC firmware's tasks, run in turn on one thread: what each does to
+//! the shared state and sends over serial is the same, as far as the host
+//! tools can tell, so they can be tried against it without a board.
+//!
+//! Where the board has hardware the host doesn't, the simulator does
+//! without: there are no external displays or gyro, the brightness is
+//! only ever saved, nothing is written over RTT, and the board is never
+//! powered off however long it is left still, since nothing would move it
+//! again.
+
+use std::fmt::{self, Write};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+use embedded_hal::delay::DelayNs;
+use sphere_mapping_core::benchmark::{Benchmark, Stage, StageTimer};
+use sphere_mapping_core::boot_record::BootRecord;
+use sphere_mapping_core::calibration::{self, Calibration, PRECOMPUTED_CALIBRATION};
+use sphere_mapping_core::capture::{Burst, Capture, Recorded, CAPTURE_RATE_HZ, MAX_DUMP_LINE_LEN};
+use sphere_mapping_core::command::{MapCommand, SerialCommand};
+use sphere_mapping_core::compass::Compass;
+use sphere_mapping_core::config;
+use sphere_mapping_core::device::{CompassDisplay, Sample};
+use sphere_mapping_core::events::{Event, EventBus, FieldWatch, Subscriber};
+use sphere_mapping_core::frame::{Frame, CELL_FRAME_LEN};
+use sphere_mapping_core::led::{self, Celebration};
+use sphere_mapping_core::mode::{AppMode, ModeAction, ModeEvent};
+use sphere_mapping_core::reset::ResetReason;
+use sphere_mapping_core::settings::{OutputFormat, PowerMode, Settings};
+use sphere_mapping_core::sphere_map::{SphereMap, MAP_RAM_BYTES};
+use sphere_mapping_core::status::Status;
+use sphere_mapping_core::storage::{Storage, StorageError};
+use sphere_mapping_core::survey::{Position, Survey};
+
+use crate::clock::Clock;
+use crate::flash::{Flash, RECORDS_START};
+use crate::sensor::Sensor;
+use crate::serial::Serial;
+
+/// How often a status line is sent, in microseconds.
+const STATUS_INTERVAL_US: u64 = 1_000_000;
+const BATTERY_STATUS_INTERVAL_US: u64 = 60_000_000;
+
+/// How often the total uptime is saved, in seconds.
+const UPTIME_SAVE_INTERVAL_S: u32 = 6 * 60 * 60;
+
+/// Room left in the output queue for the live output while the sphere map
+/// is exported, enough for a few samples' lines.
+const EXPORT_HEADROOM: usize = 256;
+
+/// Events the bus holds for outputs that haven't caught up yet.
+const EVENT_QUEUE_LEN: usize = 8;
+
+/// Longest the loop sleeps between looking at the serial port and the
+/// console, in microseconds.
+const POLL_US: u64 = 1_000;
+
+/// What the person at the console does to the board.
+pub enum Console {
+    /// Presses buttons.
+    Button(ModeEvent),
+    /// Presses the reset button.
+    Reset,
+    /// Looks at the LED matrix.
+    Matrix,
+}
+
+/// What outlasts a reset.
+pub struct Board {
+    pub serial: Serial,
+    pub sensor: Sensor,
+    pub flash: Flash,
+    pub matrix: Matrix,
+}
+
+/// The LED matrix, which is only looked at on request.
+pub struct Matrix(led::Frame);
+
+impl Matrix {
+    pub fn new() -> Matrix {
+        Matrix([[0; 5]; 5])
+    }
+}
+
+impl CompassDisplay for Matrix {
+    fn show(&mut self, frame: led::Frame) {
+        self.0 = frame;
+    }
+}
+
+impl fmt::Display for Matrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, row) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            for &pixel in row {
+                f.write_char(match pixel {
+                    0 => '.',
+                    _ => '#',
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waits by sleeping, for the calibration game.
+struct Sleep;
+
+impl DelayNs for Sleep {
+    fn delay_ns(&mut self, ns: u32) {
+        thread::sleep(Duration::from_nanos(ns as u64));
+    }
+}
+
+/// Everything the RTIC firmware shares between its tasks, for one boot.
+pub struct Firmware<'a> {
+    serial: &'a mut Serial,
+    sensor: &'a mut Sensor,
+    matrix: &'a mut Matrix,
+    storage: Storage<&'a mut Flash>,
+    clock: Clock,
+    reset_reason: ResetReason,
+    /// As counted at boot, before this boot's uptime.
+    boot_record: BootRecord,
+    settings: Settings,
+    calibration: Calibration,
+    calibrated: bool,
+    compass: Compass,
+    sphere_map: Box<SphereMap>,
+    survey: Survey,
+    app_mode: AppMode,
+    events: EventBus<EVENT_QUEUE_LEN>,
+    serial_events: Subscriber,
+    /// Full samples handled since the last status line.
+    sample_count: u32,
+    /// Largest gap between the two halves of a sample since the last
+    /// status line, in microseconds.
+    max_skew_us: u32,
+    capture: Box<Capture>,
+    benchmark: Benchmark,
+    watch: FieldWatch,
+    celebration: Celebration,
+    samples_since_report: u8,
+    /// Time spent handling anything since the last status line, in
+    /// microseconds.
+    busy_us: u64,
+    next_status_us: u64,
+    next_save_s: u32,
+}
+
+impl<'a> Firmware<'a> {
+    /// Starts the firmware on `board` after a reset for `reset_reason`,
+    /// queueing the boot banner.
+    pub fn boot(board: &'a mut Board, reset_reason: ResetReason) -> Firmware<'a> {
+        let mut storage = Storage::new(&mut board.flash, RECORDS_START);
+        let settings = Settings::load(&mut storage);
+        let boot_record = BootRecord::count_boot(&mut storage);
+        let boot_saved = boot_record.save(&mut storage);
+        let stored_calibration = Calibration::load(&mut storage);
+        let calibrated = stored_calibration.is_some();
+        let calibration = stored_calibration.unwrap_or(PRECOMPUTED_CALIBRATION);
+        let clock = board.sensor.restart(settings.power.sample_rate_hz());
+        let events = EventBus::new();
+        let serial_events = events.subscribe();
+        let mut firmware = Firmware {
+            serial: &mut board.serial,
+            sensor: &mut board.sensor,
+            matrix: &mut board.matrix,
+            storage,
+            clock,
+            reset_reason,
+            boot_record,
+            settings,
+            calibration,
+            calibrated,
+            compass: Compass::new(),
+            sphere_map: Box::default(),
+            survey: Survey::new(),
+            app_mode: AppMode::default(),
+            events,
+            serial_events,
+            sample_count: 0,
+            max_skew_us: 0,
+            capture: Box::new(Capture::new()),
+            benchmark: Benchmark::new(),
+            watch: FieldWatch::new(),
+            celebration: Celebration::new(),
+            samples_since_report: 0,
+            busy_us: 0,
+            next_status_us: 0,
+            next_save_s: UPTIME_SAVE_INTERVAL_S,
+        };
+        firmware.next_status_us = firmware.status_interval_us();
+
+        let serial = &mut *firmware.serial;
+        write!(serial, "Version: {}", env!("CARGO_PKG_VERSION")).ok();
+        if let Some(hash) = config::GIT_HASH {
+            write!(serial, " ({})", hash).ok();
+        }
+        write!(serial, "\r\n").ok();
+        write!(serial, "Reset: {}\r\n", reset_reason).ok();
+        write!(serial, "{}\r\n", boot_record).ok();
+        write!(serial, "{}\r\n", calibration).ok();
+        if !calibrated {
+            write!(serial, "Warning: no stored calibration, using defaults\r\n").ok();
+        }
+        write!(serial, "{}\r\n", settings).ok();
+        if let Err(e) = boot_saved {
+            firmware.report_storage(e);
+        }
+        firmware
+    }
+
+    /// Runs until the console resets the board, and says why it reset.
+    pub fn run(&mut self, console: &Receiver<Console>) -> ResetReason {
+        loop {
+            let started_us = self.clock.now_us();
+            let mut commands = Vec::new();
+            self.serial.receive(&mut commands);
+            for command in commands {
+                self.command(command);
+            }
+            // Once stdin closes there is nobody at the console.
+            if let Ok(console) = console.try_recv() {
+                match console {
+                    Console::Button(event) => self.mode_event(event),
+                    Console::Reset => return ResetReason::ResetPin,
+                    Console::Matrix => eprintln!("{}", self.matrix),
+                }
+            }
+            if let Some(sample) = self.sensor.take() {
+                self.sample(sample);
+            }
+            self.dispatch();
+            self.transmit();
+            let now_us = self.clock.now_us();
+            self.busy_us += now_us - started_us;
+            if now_us >= self.next_status_us {
+                self.status(now_us);
+            }
+            let wait_us = self.sensor.due_us().saturating_sub(now_us).min(POLL_US);
+            thread::sleep(Duration::from_micros(wait_us));
+        }
+    }
+
+    fn status_interval_us(&self) -> u64 {
+        match self.settings.power {
+            PowerMode::Normal | PowerMode::HighRate => STATUS_INTERVAL_US,
+            PowerMode::Battery => BATTERY_STATUS_INTERVAL_US,
+        }
+    }
+
+    /// Sends a status line with the share of the time spent busy, unless
+    /// the board is asleep, and every few hours saves the total uptime.
+    fn status(&mut self, now_us: u64) {
+        let window_us = self.status_interval_us();
+        self.next_status_us = now_us + window_us;
+        let uptime_s = (now_us / 1_000_000) as u32;
+        let status = Status {
+            uptime_s,
+            load_permille: (std::mem::take(&mut self.busy_us) * 1000 / window_us).min(1000) as u16,
+            samples: std::mem::take(&mut self.sample_count),
+            max_skew_us: std::mem::take(&mut self.max_skew_us),
+            dropped: self.serial.take_dropped(),
+            mode: self.app_mode,
+            calibrated: self.calibrated,
+            boots: self.boot_record.boots,
+            total_uptime_s: self.boot_record.after(uptime_s).uptime_s,
+            reset: self.reset_reason,
+            coverage_permille: self.sphere_map.coverage().permille(),
+            map_bytes: MAP_RAM_BYTES as u32,
+        };
+        if uptime_s >= self.next_save_s {
+            self.next_save_s = uptime_s + UPTIME_SAVE_INTERVAL_S;
+            if let Err(e) = self.boot_record.after(uptime_s).save(&mut self.storage) {
+                self.report_storage(e);
+            }
+        }
+        if status.mode != AppMode::Sleep {
+            write!(self.serial, "{}\r\n", status).ok();
+        }
+    }
+
+    /// Handles a sample: records it for a burst capture, or adds it to the
+    /// sphere map, reports it over serial and updates the matrix.
+    fn sample(&mut self, sample: Sample) {
+        let mut stages = StageTimer::new(self.clock.now_us());
+        stages.lap(Stage::Read, self.clock.now_us());
+        let settings = self.settings;
+
+        // While a burst is being recorded, keep the raw sample and skip the
+        // rest. One waiting for its trigger only keeps a copy.
+        let dumping = self.capture.is_dumping();
+        match self.capture.record(&sample) {
+            Recorded::Ignored | Recorded::BeforeTrigger => {}
+            Recorded::InBurst => {
+                self.sample_count += 1;
+                return;
+            }
+            Recorded::Complete => {
+                self.sample_count += 1;
+                self.end_capture();
+                return;
+            }
+        }
+
+        let heading = self.compass.update(
+            sample,
+            &self.calibration,
+            self.calibrated,
+            self.app_mode,
+            &settings,
+            Some(&*self.sphere_map),
+        );
+        let index = self
+            .sphere_map
+            .add(&sample.accel, &heading.field, &sample.field);
+        let map_update = index.and_then(|index| self.sphere_map.live_update(index));
+        let completed = index.and_then(|index| self.sphere_map.completed_by(index));
+        self.watch
+            .check(&heading.field, &self.calibration, &mut self.events);
+        if let Some(coverage) = completed {
+            self.events.publish(Event::SphereMapComplete(coverage));
+            self.celebration.start(sample.timestamp_us);
+            self.map_command(MapCommand::Fit);
+        }
+        stages.lap(Stage::Math, self.clock.now_us());
+
+        // Send every `report_every`th sample over serial, unless the mode
+        // keeps quiet or a burst is being dumped.
+        self.samples_since_report += 1;
+        if self.samples_since_report >= settings.report_every
+            && self.app_mode.sends_samples()
+            && !dumping
+        {
+            self.samples_since_report = 0;
+            if settings.output_format == OutputFormat::Binary {
+                let frame = Frame::sample(&heading.field, &sample.accel, sample.timestamp_us, None);
+                self.serial.write_bytes(frame.as_bytes()).ok();
+            } else {
+                settings
+                    .output_format
+                    .write_sample(
+                        self.serial,
+                        &heading.field,
+                        &sample.accel,
+                        sample.timestamp_us,
+                        None,
+                    )
+                    .ok();
+            }
+        }
+        // In the survey mode the field goes out averaged, with where it was
+        // measured, instead.
+        if self.app_mode == AppMode::Survey && !dumping {
+            if let Some(point) = self.survey.add(&heading.field, sample.timestamp_us) {
+                write!(self.serial, "{}\r\n", point).ok();
+            }
+        }
+        // A cell that changed enough goes out whatever the output format,
+        // unless a burst is being dumped.
+        if let Some(frame) = map_update.filter(|_| !dumping) {
+            self.serial.write_bytes(frame.as_bytes()).ok();
+        }
+        stages.lap(Stage::Output, self.clock.now_us());
+
+        // The celebration's frames are symmetric but for the tick, which
+        // should read the right way up.
+        let battery = settings.power == PowerMode::Battery;
+        let frame = self
+            .celebration
+            .frame(sample.timestamp_us)
+            .filter(|_| !battery)
+            .map_or(heading.frame, |frame| settings.rotation.apply(frame));
+        self.matrix.show(frame);
+        stages.lap(Stage::Display, self.clock.now_us());
+        if let Some(report) = self.benchmark.record(&stages, self.clock.now_us()) {
+            write!(self.serial, "{}\r\n", report).ok();
+        }
+        self.sample_count += 1;
+        self.max_skew_us = self.max_skew_us.max(sample.skew_us);
+    }
+
+    /// Moves the application mode on for `event`, doing whatever the
+    /// transition needs and reporting the new mode.
+    fn mode_event(&mut self, event: ModeEvent) {
+        let old = self.app_mode;
+        let (new, action) = old.handle(event);
+        match action {
+            // The game runs to the end before another event is handled, so
+            // there is never one to stop.
+            ModeAction::None | ModeAction::StartCalibration | ModeAction::StopCalibration => {}
+            // Button A cycles through the display modes, which are saved
+            // like any other setting.
+            ModeAction::NextDisplayMode => {
+                let mode = self.settings.display_mode.next();
+                self.command(SerialCommand::SetDisplayMode(mode));
+            }
+            ModeAction::NextWaypoint => self.move_survey(None),
+        }
+        if new == old {
+            return;
+        }
+        self.app_mode = new;
+        write!(self.serial, "{}\r\n", new).ok();
+        if action == ModeAction::StartCalibration {
+            self.calibrate();
+        }
+    }
+
+    fn command(&mut self, command: SerialCommand) {
+        let old_power = self.settings.power;
+        if !self.settings.apply(&command) {
+            match command {
+                SerialCommand::ManualCal => {
+                    self.mode_event(ModeEvent::Command(AppMode::Calibrating))
+                }
+                SerialCommand::SetAppMode(mode) => self.mode_event(ModeEvent::Command(mode)),
+                SerialCommand::StopCal => self.mode_event(ModeEvent::StopCalibration),
+                SerialCommand::Capture(_)
+                | SerialCommand::Disarm
+                | SerialCommand::SetPreTrigger(_)
+                | SerialCommand::SetPostTrigger(_) => self.configure_capture(command),
+                SerialCommand::Benchmark(seconds) => self.run_benchmark(seconds),
+                SerialCommand::SetPosition(position) => self.move_survey(Some(position)),
+                SerialCommand::SetCalibration(calibration) => self.set_calibration(calibration),
+                SerialCommand::Map(command) => self.map_command(command),
+                // The settings are applied above.
+                _ => {}
+            }
+            return;
+        }
+        let settings = self.settings;
+        // The sensor only takes new rates when it is set up again.
+        if settings.power != old_power {
+            self.sensor.set_rate(settings.power.sample_rate_hz());
+        }
+        if let Err(e) = settings.save(&mut self.storage) {
+            self.report_storage(e);
+        }
+        write!(self.serial, "{}\r\n", settings).ok();
+    }
+
+    /// Runs the calibration game, then stores and publishes the result, and
+    /// hands back to the compass. Nothing else runs meanwhile but the
+    /// serial output, since the game needs the sensor to itself.
+    fn calibrate(&mut self) {
+        let serial = &mut *self.serial;
+        let Ok(calibration) =
+            calibration::play(&mut *self.sensor, &mut *self.matrix, &mut Sleep, || {
+                serial.transmit()
+            });
+        self.app_mode = self.app_mode.handle(ModeEvent::CalibrationDone).0;
+        write!(self.serial, "{}\r\n", self.app_mode).ok();
+        self.set_calibration(calibration);
+    }
+
+    /// Applies and saves `calibration`, which goes out as a `Calibration:`
+    /// line like any other.
+    fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+        self.calibrated = true;
+        if let Err(e) = calibration.save(&mut self.storage) {
+            self.report_storage(e);
+        }
+        self.events.publish(Event::CalibrationApplied(calibration));
+    }
+
+    /// Applies the capture commands: sets how much of a triggered burst is
+    /// kept either side of the trigger, stops waiting for one, or sets the
+    /// sensor up for 100 Hz and starts a burst or waits for its trigger,
+    /// unless one is already under way.
+    fn configure_capture(&mut self, command: SerialCommand) {
+        let burst = match command {
+            SerialCommand::Capture(burst) => burst,
+            SerialCommand::Disarm => {
+                if self.capture.disarm() {
+                    self.end_capture();
+                }
+                write!(self.serial, "Capture: disarmed\r\n").ok();
+                return;
+            }
+            SerialCommand::SetPreTrigger(samples) => {
+                let (_, post) = self.capture.window();
+                self.capture.set_window(samples, post);
+                let (pre, post) = self.capture.window();
+                write!(self.serial, "Capture: pre={}, post={}\r\n", pre, post).ok();
+                return;
+            }
+            SerialCommand::SetPostTrigger(samples) => {
+                let (pre, _) = self.capture.window();
+                self.capture.set_window(pre, samples);
+                let (pre, post) = self.capture.window();
+                write!(self.serial, "Capture: pre={}, post={}\r\n", pre, post).ok();
+                return;
+            }
+            _ => return,
+        };
+        if self.capture.is_busy() {
+            write!(self.serial, "Warning: a capture is already under way\r\n").ok();
+            return;
+        }
+        if self.settings.power != PowerMode::HighRate {
+            self.sensor.set_rate(CAPTURE_RATE_HZ);
+        }
+        self.capture.begin(burst);
+        let (pre, post) = self.capture.window();
+        match burst {
+            Burst::Timed(seconds) => {
+                write!(self.serial, "Capture: recording, seconds={}\r\n", seconds).ok()
+            }
+            Burst::Triggered(trigger) => write!(
+                self.serial,
+                "Capture: armed, {}, pre={}, post={}\r\n",
+                trigger, pre, post
+            )
+            .ok(),
+        };
+    }
+
+    /// Puts the sensor back to the power mode's rate once a burst has been
+    /// recorded, which `transmit` then dumps, or once one has stopped
+    /// waiting for its trigger.
+    fn end_capture(&mut self) {
+        if self.settings.power != PowerMode::HighRate {
+            self.sensor.set_rate(self.settings.power.sample_rate_hz());
+        }
+    }
+
+    /// Applies the `SMAP` commands, acknowledging each with a `Map:` line.
+    fn map_command(&mut self, command: MapCommand) {
+        let cells = self.sphere_map.cells().len();
+        match command {
+            MapCommand::Start | MapCommand::Stop => {
+                let collecting = command == MapCommand::Start;
+                self.sphere_map.set_collecting(collecting);
+                write!(
+                    self.serial,
+                    "Map: {}, cells={}\r\n",
+                    if collecting { "started" } else { "stopped" },
+                    cells
+                )
+                .ok();
+            }
+            MapCommand::Reset => {
+                self.sphere_map.clear();
+                write!(self.serial, "Map: reset, cells={}\r\n", cells).ok();
+            }
+            MapCommand::Coverage(samples) => {
+                if let Some(samples) = samples {
+                    self.sphere_map.set_covered_samples(samples as u32);
+                }
+                write!(self.serial, "{}\r\n", self.sphere_map.coverage()).ok();
+            }
+            MapCommand::Export => {
+                self.sphere_map.start_export();
+                write!(self.serial, "Map: exporting, cells={}\r\n", cells).ok();
+            }
+            MapCommand::Diff => {
+                if self.sphere_map.start_difference(&mut self.storage) {
+                    write!(self.serial, "Map: diffing, cells={}\r\n", cells).ok();
+                } else {
+                    write!(
+                        self.serial,
+                        "Warning: sphere maps not saved in both slots\r\n"
+                    )
+                    .ok();
+                }
+            }
+            MapCommand::Live(live) => {
+                self.sphere_map.set_live(live);
+                write!(
+                    self.serial,
+                    "Map: live, updates={}\r\n",
+                    if live { "on" } else { "off" }
+                )
+                .ok();
+            }
+            MapCommand::Anomalies => {
+                let anomalies = self.sphere_map.anomalies();
+                write!(self.serial, "{}\r\n", anomalies).ok();
+                for anomaly in anomalies.worst() {
+                    write!(self.serial, "{}\r\n", anomaly).ok();
+                }
+            }
+            MapCommand::Fit => {
+                let refitted = self.sphere_map.refit().clone().fit(&self.calibration);
+                if let Ok(refitted) = refitted {
+                    self.sphere_map.refit().offer(refitted);
+                }
+                match refitted {
+                    Ok(refitted) => write!(self.serial, "{}\r\n", refitted),
+                    Err(directions) => write!(
+                        self.serial,
+                        "Warning: too few field directions to refit, cells={}\r\n",
+                        directions
+                    ),
+                }
+                .ok();
+            }
+            MapCommand::Apply => {
+                let Some(refitted) = self.sphere_map.refit().accept() else {
+                    write!(self.serial, "Warning: no calibration refit offered\r\n").ok();
+                    return;
+                };
+                write!(self.serial, "Map: applied, cells={}\r\n", refitted.cells).ok();
+                self.set_calibration(refitted.calibration);
+            }
+            MapCommand::Save(slot) => match self.sphere_map.save(&mut self.storage, slot) {
+                Ok(()) => {
+                    write!(
+                        self.serial,
+                        "Map: saved, slot={}, cells={}\r\n",
+                        slot, cells
+                    )
+                    .ok();
+                }
+                Err(e) => self.report_storage(e),
+            },
+            MapCommand::Load(slot) => {
+                if self.sphere_map.load(&mut self.storage, slot) {
+                    write!(
+                        self.serial,
+                        "Map: loaded, slot={}, cells={}\r\n",
+                        slot, cells
+                    )
+                    .ok();
+                } else {
+                    write!(
+                        self.serial,
+                        "Warning: no saved sphere map, slot={}\r\n",
+                        slot
+                    )
+                    .ok();
+                }
+            }
+            MapCommand::Merge(slot) => {
+                // Merging a saved map twice would count its samples twice.
+                let merged = match self.sphere_map.holds_saved(slot) {
+                    true => None,
+                    false => Some(self.sphere_map.merge(&mut self.storage, slot)),
+                };
+                match merged {
+                    Some(true) => {
+                        write!(
+                            self.serial,
+                            "Map: merged, slot={}, cells={}\r\n",
+                            slot, cells
+                        )
+                    }
+                    Some(false) => {
+                        write!(
+                            self.serial,
+                            "Warning: no saved sphere map, slot={}\r\n",
+                            slot
+                        )
+                    }
+                    None => write!(
+                        self.serial,
+                        "Warning: saved sphere map already merged, slot={}\r\n",
+                        slot
+                    ),
+                }
+                .ok();
+            }
+        }
+    }
+
+    /// Moves the survey to `position`, or on to its next waypoint, and
+    /// reports where it is.
+    fn move_survey(&mut self, position: Option<Position>) {
+        let position = match position {
+            Some(position) => {
+                self.survey.set_position(position);
+                position
+            }
+            None => self.survey.next_waypoint(),
+        };
+        write!(self.serial, "Position: {}\r\n", position).ok();
+    }
+
+    /// Starts a benchmark run of `seconds`, replacing any run already going.
+    fn run_benchmark(&mut self, seconds: u8) {
+        self.benchmark.start(seconds, self.clock.now_us());
+        write!(self.serial, "Benchmark: running, seconds={}\r\n", seconds).ok();
+    }
+
+    /// Hands the events published since it last ran to the serial port.
+    fn dispatch(&mut self) {
+        while let Some(event) = self.events.next(&mut self.serial_events) {
+            event.write_line(self.serial).ok();
+        }
+    }
+
+    /// Sends what the link has had time for, then tops the queue up with
+    /// whole lines of a burst capture being dumped, and with cells of the
+    /// sphere map, or of the difference between the saved maps, being
+    /// exported, leaving room for the live output, and the closing line
+    /// after the last.
+    fn transmit(&mut self) {
+        self.serial.transmit();
+        while self.capture.is_dumping()
+            && self.serial.space() >= MAX_DUMP_LINE_LEN
+            && self.capture.write_next(self.serial) == Ok(true)
+        {}
+        while self.sphere_map.is_exporting()
+            && self.serial.space() >= CELL_FRAME_LEN + EXPORT_HEADROOM
+        {
+            match self.sphere_map.export_next(&mut self.storage) {
+                Some(frame) => self.serial.write_bytes(frame.as_bytes()).ok(),
+                None => write!(self.serial, "Map: done\r\n").ok(),
+            };
+        }
+    }
+
+    /// Reports a failed flash write on stderr, where the board would use
+    /// RTT, and publishes it for the serial port.
+    fn report_storage(&mut self, error: StorageError) {
+        let error = format!("flash write failed ({:?})", error);
+        eprintln!("{}", error);
+        self.events.publish_error(&error);
+    }
+}
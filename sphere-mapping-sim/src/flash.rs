@@ -0,0 +1,97 @@
+//! The flash pages the firmware keeps its records and the sphere map's
+//! chunks in, held in RAM, and written through to a file if given one, so
+//! settings, the calibration and saved maps outlast the simulator as they
+//! outlast a power cycle on the board.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+use sphere_mapping_core::storage::{CHUNK_STORAGE_LEN, STORAGE_LEN};
+
+/// The chunks and the records after them, as the RTIC firmware maps them.
+const FLASH_LEN: usize = CHUNK_STORAGE_LEN + STORAGE_LEN;
+/// Where the records start, as [`Storage::new`] takes it.
+///
+/// [`Storage::new`]: sphere_mapping_core::storage::Storage::new
+pub const RECORDS_START: u32 = CHUNK_STORAGE_LEN as u32;
+/// The nRF52833's flash page.
+const PAGE_SIZE: usize = 4096;
+
+pub struct Flash {
+    bytes: Vec<u8>,
+    path: Option<PathBuf>,
+}
+
+impl Flash {
+    /// Erased flash, or what was kept in `path` last time.
+    pub fn open(path: Option<PathBuf>) -> io::Result<Flash> {
+        let bytes = match &path {
+            Some(path) if path.exists() => fs::read(path)?,
+            _ => vec![0xff; FLASH_LEN],
+        };
+        if bytes.len() != FLASH_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: not a simulator's flash, which is {FLASH_LEN} bytes",
+                    path.unwrap_or_default().display()
+                ),
+            ));
+        }
+        Ok(Flash { bytes, path })
+    }
+
+    fn keep(&self) -> Result<(), NorFlashErrorKind> {
+        match &self.path {
+            Some(path) => fs::write(path, &self.bytes).map_err(|_| NorFlashErrorKind::Other),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ErrorType for Flash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        FLASH_LEN
+    }
+}
+
+impl NorFlash for Flash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = PAGE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.bytes[from as usize..to as usize].fill(0xff);
+        self.keep()
+    }
+
+    /// Writing can only clear bits, as on the real thing.
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        for (old, new) in self.bytes[offset..offset + bytes.len()]
+            .iter_mut()
+            .zip(bytes)
+        {
+            *old &= new;
+        }
+        self.keep()
+    }
+}
@@ -0,0 +1,135 @@
+//! Runs the firmware on the host, against a simulated sensor, for trying
+//! the host tools and the protocol without a board.
+//!
+//! The board's serial port is a pseudo-terminal (see [`serial`]) the host
+//! tools open with `--port` as they would the board's, at the board's baud
+//! rate, which the output is held to. Samples come from a board turned in
+//! a steady field, or from a log replayed on a loop (see [`sensor`]), and
+//! go through the firmware's tasks from the core crate as on the board
+//! (see [`firmware`]). Settings, the calibration and saved sphere maps are
+//! kept in flash (see [`flash`]), which `--flash` keeps in a file between
+//! runs.
+//!
+//! Lines typed at the console press the board's buttons, `a`, `b` or
+//! `ab`, press its reset button, `reset`, or show the LED matrix, `m`.
+
+#[cfg(not(unix))]
+compile_error!("the simulator's serial port is a pseudo-terminal, which needs a Unix");
+
+mod clock;
+mod firmware;
+mod flash;
+mod sensor;
+mod serial;
+
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use clap::Parser;
+use sphere_mapping_core::config;
+use sphere_mapping_core::mode::ModeEvent;
+use sphere_mapping_core::reset::ResetReason;
+
+use crate::firmware::{Board, Console, Firmware, Matrix};
+use crate::flash::Flash;
+use crate::sensor::{Motion, Sensor};
+use crate::serial::Serial;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Runs the compass firmware on the host with a simulated sensor"
+)]
+struct Cli {
+    /// A serial log to replay as the sensor's samples, on a loop, instead
+    /// of simulating them.
+    #[arg(long)]
+    replay: Option<PathBuf>,
+    /// How the simulated board is moved.
+    #[arg(long, value_enum, default_value_t = Motion::Tumble, conflicts_with = "replay")]
+    motion: Motion,
+    /// File the flash is kept in between runs; erased flash if left out.
+    #[arg(long)]
+    flash: Option<PathBuf>,
+    /// A symlink to make to the serial port, so it has the same name every
+    /// run.
+    #[arg(long)]
+    link: Option<PathBuf>,
+    #[arg(long, default_value_t = config::BAUD_RATE)]
+    baud: u32,
+    /// Seed for the simulated sensor's noise.
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(err) = run(cli) {
+        eprintln!("Error: {err}");
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> io::Result<()> {
+    let sensor = match &cli.replay {
+        Some(path) => Sensor::replay(path)?,
+        None => Sensor::synthetic(cli.motion, cli.seed),
+    };
+    let serial = Serial::open(cli.baud)?;
+    if let Some(link) = &cli.link {
+        // A link left from an earlier run points at a port that has gone.
+        if link.symlink_metadata().is_ok() {
+            std::fs::remove_file(link)?;
+        }
+        std::os::unix::fs::symlink(&serial.name, link)?;
+    }
+    eprintln!(
+        "Serial port: {}{}",
+        serial.name,
+        match &cli.link {
+            Some(link) => format!(" (linked from {})", link.display()),
+            None => String::new(),
+        }
+    );
+    eprintln!("Type a, b or ab to press the buttons, reset to reset, m to see the matrix");
+    let mut board = Board {
+        serial,
+        sensor,
+        flash: Flash::open(cli.flash)?,
+        matrix: Matrix::new(),
+    };
+
+    let (console, events) = mpsc::channel();
+    thread::spawn(move || listen(console));
+    let mut reset = ResetReason::PowerOn;
+    loop {
+        reset = Firmware::boot(&mut board, reset).run(&events);
+        board.serial.reset();
+    }
+}
+
+/// Passes what is typed at the console on to the board, until stdin closes.
+fn listen(console: Sender<Console>) {
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let event = match line.trim() {
+            "a" => Console::Button(ModeEvent::ButtonA),
+            "b" => Console::Button(ModeEvent::ButtonB),
+            "ab" => Console::Button(ModeEvent::BothButtons),
+            "reset" => Console::Reset,
+            "m" => Console::Matrix,
+            "" => continue,
+            other => {
+                eprintln!("Unknown: {other:?}; a, b, ab, reset or m");
+                continue;
+            }
+        };
+        if console.send(event).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! A stand-in for the LSM303AGR: samples of a board being turned in a
+//! steady field, or replayed from a log, at the rate the firmware sets the
+//! sensor up for.
+//!
+//! Synthetic samples are worked out from the Earth's field and gravity as
+//! the board would see them turned by [`Motion`], with the sensors' noise
+//! and the magnetometer's resolution added, and then uncalibrated with the
+//! build's calibration, so the firmware calibrates them back much as it
+//! would a real board's. Replayed ones are the fields and accelerations a
+//! log holds, looped over for as long as the simulator runs; the fields,
+//! which the board had calibrated, are uncalibrated the same way, so they
+//! come back out as logged until a calibration is stored.
+
+use std::convert::Infallible;
+use std::f64::consts::TAU;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use sphere_mapping_core::calibration::{uncalibrated_measurement, Measurement};
+use sphere_mapping_core::config;
+use sphere_mapping_core::device::{Acceleration, Sample, SampleSource};
+use sphere_mapping_core::settings::LoggedSample;
+use sphere_mapping_core::stream::{Decoder, Packet};
+
+use crate::clock::Clock;
+
+/// The field with the board lying flat and pointing North, in nT: about
+/// what it is at mid-northern latitudes, dipping into the ground.
+const FIELD_NT: [f64; 3] = [0., 20_000., 44_000.];
+/// Gravity with the board lying flat, in mg.
+const GRAVITY_MG: [f64; 3] = [0., 0., -1_000.];
+/// The sensors' noise, as a standard deviation.
+const FIELD_NOISE_NT: f64 = 300.;
+const ACCEL_NOISE_MG: f64 = 8.;
+/// The magnetometer's resolution, in nT.
+const FIELD_LSB_NT: f64 = 150.;
+/// Behind the sample that was due by which the sensor skips ahead rather
+/// than catching up, in µs, as after the calibration game held samples up.
+const MAX_BEHIND_US: u64 = 100_000;
+
+/// How the synthetic board is moved.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Motion {
+    /// Turned slowly through every orientation, round three axes at
+    /// unrelated rates, as for mapping the sphere or calibrating.
+    Tumble,
+    /// Flat, turning once round every 20 s, as a compass on a table.
+    Spin,
+    /// Flat and still, pointing North.
+    Still,
+}
+
+impl Motion {
+    /// The board's orientation `t_s` into the run, as a rotation matrix
+    /// taking its axes to the world's.
+    fn orientation(self, t_s: f64) -> [[f64; 3]; 3] {
+        match self {
+            Motion::Tumble => multiply(
+                multiply(rotation_z(TAU * t_s / 20.), rotation_x(TAU * t_s / 13.)),
+                rotation_y(TAU * t_s / 31.4),
+            ),
+            Motion::Spin => rotation_z(TAU * t_s / 20.),
+            Motion::Still => rotation_z(0.),
+        }
+    }
+}
+
+enum Source {
+    Synthetic {
+        motion: Motion,
+        noise: Noise,
+    },
+    Replay {
+        samples: Vec<LoggedSample>,
+        next: usize,
+    },
+}
+
+pub struct Sensor {
+    source: Source,
+    clock: Clock,
+    interval_us: u64,
+    /// When the next sample is ready.
+    due_us: u64,
+}
+
+impl Sensor {
+    /// Samples of a board moved by `motion`, with noise from `seed`.
+    pub fn synthetic(motion: Motion, seed: u64) -> Sensor {
+        Sensor::new(Source::Synthetic {
+            motion,
+            noise: Noise::new(seed),
+        })
+    }
+
+    /// Samples replayed from the serial log at `path`.
+    pub fn replay(path: &Path) -> io::Result<Sensor> {
+        let mut packets = Vec::new();
+        Decoder::new().push(&fs::read(path)?, &mut packets);
+        let samples: Vec<LoggedSample> = packets
+            .into_iter()
+            .filter_map(|packet| match packet {
+                Packet::Sample(sample) => Some(sample),
+                _ => None,
+            })
+            .collect();
+        if samples.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: no samples to replay", path.display()),
+            ));
+        }
+        Ok(Sensor::new(Source::Replay { samples, next: 0 }))
+    }
+
+    fn new(source: Source) -> Sensor {
+        let mut sensor = Sensor {
+            source,
+            clock: Clock::start(),
+            interval_us: 0,
+            due_us: 0,
+        };
+        sensor.set_rate(config::SAMPLE_RATE_HZ);
+        sensor
+    }
+
+    /// Starts the clock the samples are timestamped with over, as a reset
+    /// does, at `hz` samples a second.
+    pub fn restart(&mut self, hz: u32) -> Clock {
+        self.clock = Clock::start();
+        self.set_rate(hz);
+        self.clock
+    }
+
+    /// Sets the sensor up for `hz` samples a second.
+    pub fn set_rate(&mut self, hz: u32) {
+        self.interval_us = 1_000_000 / hz.max(1) as u64;
+        self.due_us = self.clock.now_us() + self.interval_us;
+    }
+
+    /// When the next sample is ready, in µs on the clock.
+    pub fn due_us(&self) -> u64 {
+        self.due_us
+    }
+
+    /// The sample that was due, once it is.
+    pub fn take(&mut self) -> Option<Sample> {
+        let now_us = self.clock.now_us();
+        if now_us < self.due_us {
+            return None;
+        }
+        let at_us = self.due_us;
+        self.due_us += self.interval_us;
+        if now_us > self.due_us + MAX_BEHIND_US {
+            self.due_us = now_us + self.interval_us;
+        }
+        let (field, accel) = match &mut self.source {
+            Source::Synthetic { motion, noise } => {
+                let orientation = motion.orientation(at_us as f64 / 1e6);
+                let field = rotate_back(orientation, FIELD_NT).map(|nt| {
+                    ((nt + noise.normal() * FIELD_NOISE_NT) / FIELD_LSB_NT).round() * FIELD_LSB_NT
+                });
+                let accel = rotate_back(orientation, GRAVITY_MG)
+                    .map(|mg| (mg + noise.normal() * ACCEL_NOISE_MG).round());
+                (
+                    Measurement {
+                        x: field[0] as i32,
+                        y: field[1] as i32,
+                        z: field[2] as i32,
+                    },
+                    Acceleration {
+                        x: accel[0] as i32,
+                        y: accel[1] as i32,
+                        z: accel[2] as i32,
+                    },
+                )
+            }
+            Source::Replay { samples, next } => {
+                let sample = samples[*next];
+                *next = (*next + 1) % samples.len();
+                (sample.field, sample.accel)
+            }
+        };
+        let raw = uncalibrated_measurement(field, &config::CALIBRATION);
+        Some(Sample::pair(raw, at_us, accel, at_us))
+    }
+}
+
+/// For the calibration game, which waits for each sample.
+impl SampleSource for Sensor {
+    type Error = Infallible;
+
+    fn read_sample(&mut self) -> Result<Sample, Infallible> {
+        loop {
+            if let Some(sample) = self.take() {
+                return Ok(sample);
+            }
+            let wait_us = self.due_us.saturating_sub(self.clock.now_us());
+            thread::sleep(Duration::from_micros(wait_us));
+        }
+    }
+}
+
+/// `vector` in the world's axes, in those of a board turned by
+/// `orientation`.
+fn rotate_back(orientation: [[f64; 3]; 3], vector: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|i| (0..3).map(|j| orientation[j][i] * vector[j]).sum())
+}
+
+fn multiply(a: [[f64; 3]; 3], b: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    [0, 1, 2].map(|i| [0, 1, 2].map(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+fn rotation_x(angle: f64) -> [[f64; 3]; 3] {
+    let (sin, cos) = angle.sin_cos();
+    [[1., 0., 0.], [0., cos, -sin], [0., sin, cos]]
+}
+
+fn rotation_y(angle: f64) -> [[f64; 3]; 3] {
+    let (sin, cos) = angle.sin_cos();
+    [[cos, 0., sin], [0., 1., 0.], [-sin, 0., cos]]
+}
+
+fn rotation_z(angle: f64) -> [[f64; 3]; 3] {
+    let (sin, cos) = angle.sin_cos();
+    [[cos, -sin, 0.], [sin, cos, 0.], [0., 0., 1.]]
+}
+
+/// Repeatable noise: xorshift64*, made roughly normal by summing uniforms.
+struct Noise(u64);
+
+impl Noise {
+    fn new(seed: u64) -> Noise {
+        // Xorshift never leaves 0.
+        Noise(seed.max(1))
+    }
+
+    fn uniform(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Mean 0, standard deviation 1. Four uniforms sum to a variance of a
+    /// third.
+    fn normal(&mut self) -> f64 {
+        ((0..4).map(|_| self.uniform()).sum::<f64>() - 2.) * 3f64.sqrt()
+    }
+}
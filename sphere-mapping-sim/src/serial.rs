@@ -0,0 +1,168 @@
+//! The board's UART, as a pseudo-terminal the host tools open as they
+//! would the board's port, with `--port`.
+//!
+//! Output goes through a queue like the RTIC firmware's `TxQueue`, 2048
+//! bytes that a write is dropped from whole, and counted, when it doesn't
+//! fit, and leaves it no faster than the baud rate allows, so output that
+//! would overrun the real link overruns this one too. What nobody is
+//! reading when it leaves is lost, as on a wire. Input is cut into
+//! commands as the firmwares' receive tasks cut it.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::Instant;
+
+use sphere_mapping_core::command::{parse_command, LineReader, SerialCommand};
+
+const TX_QUEUE_LEN: usize = 2048;
+/// Bytes the link can send in one go after being idle, as the RTIC
+/// firmware's EasyDMA transfers.
+const TX_CHUNK_LEN: usize = 64;
+/// The receive buffer's size in both firmwares.
+const COMMAND_LEN: usize = 64;
+
+pub struct Serial {
+    master: File,
+    /// Held open, so the terminal stays raw and the master doesn't report
+    /// an error while no host tool has it open.
+    _slave: File,
+    /// Where the host tools find it.
+    pub name: String,
+    queue: VecDeque<u8>,
+    dropped: u32,
+    bytes_per_s: f64,
+    /// Bytes the link could have sent since it last did.
+    credit: f64,
+    last_sent: Instant,
+    command: LineReader<COMMAND_LEN>,
+}
+
+impl Serial {
+    /// Opens a new pseudo-terminal, raw, for a link at `baud`.
+    pub fn open(baud: u32) -> io::Result<Serial> {
+        // SAFETY: plain libc calls on a descriptor owned by `master` from
+        // the start; `ptsname` is only called from this thread.
+        let (master, name) = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let name = std::ffi::CStr::from_ptr(name)
+                .to_string_lossy()
+                .into_owned();
+            if libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            (master, name)
+        };
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&name)?;
+        // SAFETY: `termios` is filled in by `tcgetattr` before it is used.
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(Serial {
+            master,
+            _slave: slave,
+            name,
+            queue: VecDeque::with_capacity(TX_QUEUE_LEN),
+            dropped: 0,
+            bytes_per_s: baud as f64 / 10.,
+            credit: 0.,
+            last_sent: Instant::now(),
+            command: LineReader::new(),
+        })
+    }
+
+    /// Forgets what was queued and half received, as a reset does.
+    pub fn reset(&mut self) {
+        self.queue.clear();
+        self.dropped = 0;
+        self.command.clear();
+    }
+
+    /// Room left in the queue.
+    pub fn space(&self) -> usize {
+        TX_QUEUE_LEN - self.queue.len()
+    }
+
+    /// Queues `bytes` whole, or drops them whole if they don't fit.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() > self.space() {
+            self.dropped = self.dropped.saturating_add(bytes.len() as u32);
+            return Err(());
+        }
+        self.queue.extend(bytes);
+        Ok(())
+    }
+
+    /// Bytes dropped since this was last called.
+    pub fn take_dropped(&mut self) -> u32 {
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// Sends as much of the queue as the baud rate allows since it was last
+    /// called.
+    pub fn transmit(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_sent).as_secs_f64();
+        self.last_sent = now;
+        self.credit += elapsed * self.bytes_per_s;
+        if self.queue.is_empty() {
+            self.credit = self.credit.min(TX_CHUNK_LEN as f64);
+            return;
+        }
+        let len = (self.credit as usize).min(self.queue.len());
+        self.credit -= len as f64;
+        let bytes: Vec<u8> = self.queue.drain(..len).collect();
+        // Whatever the terminal has no room for is lost, like bytes sent
+        // down a wire nobody is listening on.
+        let _ = self.master.write(&bytes);
+    }
+
+    /// Appends the commands received since this was last called to
+    /// `commands`, cut into lines as the firmwares cut them.
+    pub fn receive(&mut self, commands: &mut Vec<SerialCommand>) {
+        let mut buffer = [0u8; 256];
+        loop {
+            let len = match self.master.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(len) => len,
+            };
+            for &byte in &buffer[..len] {
+                if let Some(line) = self.command.push(byte) {
+                    eprintln!("Received: {:?}", String::from_utf8_lossy(line));
+                    commands.push(parse_command(line));
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes()).map_err(|()| fmt::Error)
+    }
+}
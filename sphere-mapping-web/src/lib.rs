@@ -0,0 +1,146 @@
+//! The protocol and calibration fit compiled to WebAssembly for the page in
+//! `www`, which reads the board over WebSerial, so a classroom can plot its
+//! stream and calibrate it from a browser with nothing installed.
+//!
+//! The page hands over bytes as the port gives them and takes back plain
+//! numbers and strings, so nothing more than `wasm-bindgen` is needed
+//! between them: the same [`Decoder`] as the host tools, so every output
+//! format reads alike, and the same least-squares fit as their `fit`.
+
+use sphere_mapping_core::calibration::{Calibration, Measurement};
+use sphere_mapping_core::capture::parse_captured;
+use sphere_mapping_core::ellipsoid;
+use sphere_mapping_core::fixed;
+use sphere_mapping_core::led::{heading_from_theta, theta_from_field};
+use sphere_mapping_core::stream::{Decoder, Packet};
+use wasm_bindgen::prelude::*;
+
+/// Numbers each sample takes in [`StreamDecoder::take_samples`].
+pub const SAMPLE_FIELDS: usize = 6;
+
+/// [`SAMPLE_FIELDS`], for the page.
+#[wasm_bindgen]
+pub fn sample_fields() -> usize {
+    SAMPLE_FIELDS
+}
+
+/// What the board has sent, decoded and waiting for the page.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct StreamDecoder {
+    decoder: Decoder,
+    packets: Vec<Packet>,
+    samples: Vec<f64>,
+    lines: Vec<String>,
+    captured: Vec<i32>,
+}
+
+#[wasm_bindgen]
+impl StreamDecoder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> StreamDecoder {
+        StreamDecoder::default()
+    }
+
+    /// Decodes `bytes`, as read from the port.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.decoder.push(bytes, &mut self.packets);
+        for packet in self.packets.drain(..) {
+            match packet {
+                Packet::Sample(sample) => {
+                    let field = sample.field;
+                    self.samples.extend([
+                        sample.timestamp_us as f64 / 1e6,
+                        field.x as f64,
+                        field.y as f64,
+                        field.z as f64,
+                        fixed::magnitude(&field) as f64,
+                        heading_from_theta(theta_from_field(field.x, field.y)) as f64,
+                    ]);
+                }
+                Packet::Line(line) => {
+                    if let Some(field) = parse_captured(&line) {
+                        self.captured.extend([field.x, field.y, field.z]);
+                    }
+                    self.lines.push(line);
+                }
+                Packet::Frame { .. } | Packet::Corrupt(_) => {}
+            }
+        }
+    }
+
+    /// The samples decoded since the last call, [`SAMPLE_FIELDS`] numbers
+    /// each: the board's time in seconds, the calibrated field in nT, its
+    /// strength in nT and the heading in degrees.
+    pub fn take_samples(&mut self) -> Vec<f64> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// The other lines decoded since the last call.
+    pub fn take_lines(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.lines)
+    }
+
+    /// The raw fields of the burst capture lines decoded since the last
+    /// call, three numbers each in nT.
+    pub fn take_captured(&mut self) -> Vec<i32> {
+        std::mem::take(&mut self.captured)
+    }
+}
+
+/// A calibration fitted in the browser.
+#[wasm_bindgen]
+pub struct Fit {
+    fit: ellipsoid::Fit,
+}
+
+#[wasm_bindgen]
+impl Fit {
+    pub fn samples(&self) -> usize {
+        self.fit.samples
+    }
+
+    /// The ellipsoid's radii, in nT.
+    pub fn radii(&self) -> Vec<f64> {
+        self.fit.radii.to_vec()
+    }
+
+    /// How far the calibrated strengths still spread, RMS in nT.
+    pub fn residual_nt(&self) -> f64 {
+        self.fit.residual_nt
+    }
+
+    /// The calibration as its `Calibration:` line.
+    pub fn line(&self) -> String {
+        self.fit.calibration.to_string()
+    }
+
+    /// The `SCAL` command that applies and saves it on the board.
+    pub fn command(&self) -> String {
+        let Calibration {
+            center,
+            scale,
+            radius,
+        } = self.fit.calibration;
+        format!(
+            "SCAL {},{},{},{},{},{},{radius}",
+            center.x, center.y, center.z, scale.x, scale.y, scale.z
+        )
+    }
+}
+
+/// Fits a calibration to raw fields, three numbers each in nT, as
+/// [`StreamDecoder::take_captured`] gives them.
+#[wasm_bindgen]
+pub fn fit(raw: &[i32]) -> Result<Fit, JsError> {
+    let raw: Vec<Measurement> = raw
+        .chunks_exact(3)
+        .map(|field| Measurement {
+            x: field[0],
+            y: field[1],
+            z: field[2],
+        })
+        .collect();
+    let fit = ellipsoid::fit(&raw).map_err(|err| JsError::new(&err.to_string()))?;
+    Ok(Fit { fit })
+}
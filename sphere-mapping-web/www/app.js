@@ -0,0 +1,161 @@
+// Reads the board over WebSerial, decoding with the same Rust code as the
+// host tools, plots the field and fits a calibration to a burst capture.
+
+import init, { StreamDecoder, fit, sample_fields } from "./pkg/sphere_mapping_web.js";
+
+const BAUD_RATE = 115200;
+// The USB IDs the board's port can have, as the host tools find it by:
+// DAPLink's, and the firmware's own for native USB.
+const BOARD_FILTERS = [
+  { usbVendorId: 0x0d28, usbProductId: 0x0204 },
+  { usbVendorId: 0x1209, usbProductId: 0x0001 },
+];
+// Seconds of samples plotted.
+const WINDOW_S = 10;
+const CAPTURE_S = 20;
+const COLORS = ["#e55", "#5c5", "#68f", "#ddd"];
+
+const connectButton = document.getElementById("connect");
+const captureButton = document.getElementById("capture");
+const uploadButton = document.getElementById("upload");
+const readout = document.getElementById("readout");
+const fitText = document.getElementById("fit");
+const statusText = document.getElementById("status");
+const canvas = document.getElementById("plot");
+
+let port = null;
+let decoder = null;
+let fields = 0;
+// [time_s, x, y, z, strength] of the samples in the window.
+let history = [];
+// Raw fields of the capture being dumped, three numbers each.
+let captured = [];
+let fitted = null;
+
+await init();
+fields = sample_fields();
+if (!("serial" in navigator)) {
+  connectButton.disabled = true;
+}
+
+connectButton.addEventListener("click", async () => {
+  try {
+    port = await navigator.serial.requestPort({ filters: BOARD_FILTERS });
+    await port.open({ baudRate: BAUD_RATE });
+  } catch (err) {
+    statusText.textContent = `Couldn't open the port: ${err.message}`;
+    return;
+  }
+  decoder = new StreamDecoder();
+  connectButton.disabled = true;
+  captureButton.disabled = false;
+  statusText.textContent = "Connected";
+  read();
+});
+
+captureButton.addEventListener("click", async () => {
+  captured = [];
+  await send(`SCAP ${CAPTURE_S}`);
+  statusText.textContent = "Capturing: turn the board through every orientation";
+});
+
+uploadButton.addEventListener("click", async () => {
+  await send(fitted.command());
+  statusText.textContent = "Calibration sent; waiting for the board to echo it";
+});
+
+async function send(command) {
+  const writer = port.writable.getWriter();
+  await writer.write(new TextEncoder().encode(`${command}\r`));
+  writer.releaseLock();
+}
+
+async function read() {
+  const reader = port.readable.getReader();
+  try {
+    for (;;) {
+      const { value, done } = await reader.read();
+      if (done) {
+        break;
+      }
+      decoder.push(value);
+      receive();
+    }
+  } catch (err) {
+    statusText.textContent = `Disconnected: ${err.message}`;
+  } finally {
+    reader.releaseLock();
+  }
+}
+
+function receive() {
+  const samples = decoder.take_samples();
+  for (let i = 0; i + fields <= samples.length; i += fields) {
+    const [time, x, y, z, strength, heading] = samples.slice(i, i + fields);
+    history.push([time, x, y, z, strength]);
+    readout.textContent = `Heading ${heading.toFixed(0)}°   Field ${strength.toFixed(0)} nT`;
+  }
+  const latest = history.length ? history[history.length - 1][0] : 0;
+  // A reset starts the board's time again; so does the plot.
+  history = history.filter(([time]) => time > latest - WINDOW_S && time <= latest);
+
+  captured.push(...decoder.take_captured());
+  for (const line of decoder.take_lines()) {
+    if (line === "Capture: done") {
+      fitCapture();
+    } else if (line.startsWith("Warning:") || line.startsWith("Calibration:")) {
+      statusText.textContent = line;
+    }
+  }
+}
+
+function fitCapture() {
+  try {
+    fitted = fit(new Int32Array(captured));
+  } catch (err) {
+    fitted = null;
+    uploadButton.disabled = true;
+    fitText.textContent = `No fit: ${err.message}`;
+    return;
+  }
+  const radii = Array.from(fitted.radii(), (radius) => radius.toFixed(0)).join(", ");
+  fitText.textContent =
+    `${fitted.line()}\n${fitted.samples()} samples, radii ${radii} nT, ` +
+    `residual ${fitted.residual_nt().toFixed(0)} nT RMS`;
+  uploadButton.disabled = false;
+  statusText.textContent = "Capture fitted";
+}
+
+function draw() {
+  const width = (canvas.width = canvas.clientWidth);
+  const height = (canvas.height = canvas.clientHeight);
+  const context = canvas.getContext("2d");
+  context.clearRect(0, 0, width, height);
+  if (history.length > 1) {
+    const latest = history[history.length - 1][0];
+    const values = history.flatMap((sample) => sample.slice(1));
+    const top = Math.max(...values.map(Math.abs), 1);
+    const toX = (time) => ((time - latest + WINDOW_S) / WINDOW_S) * width;
+    const toY = (value) => height / 2 - (value / top) * (height / 2 - 4);
+    context.strokeStyle = "#333";
+    context.beginPath();
+    context.moveTo(0, height / 2);
+    context.lineTo(width, height / 2);
+    context.stroke();
+    COLORS.forEach((color, axis) => {
+      context.strokeStyle = color;
+      context.beginPath();
+      history.forEach((sample, i) => {
+        const [x, y] = [toX(sample[0]), toY(sample[axis + 1])];
+        if (i === 0) {
+          context.moveTo(x, y);
+        } else {
+          context.lineTo(x, y);
+        }
+      });
+      context.stroke();
+    });
+  }
+  requestAnimationFrame(draw);
+}
+requestAnimationFrame(draw);
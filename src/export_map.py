@@ -0,0 +1,59 @@
+"""Sphere Map Export
+Asks the device for its sphere map with `SMAP EXPORT` and writes it as a PLY or OBJ file, picked by
+the output's extension, for opening in MeshLab or Blender. Run it while the host app isn't
+holding the serial port.
+
+    python src/export_map.py [output.ply|output.obj] [--port /dev/ttyACM0]
+"""
+import argparse
+import time
+
+import connect_python
+
+from utils.measure import MapCell
+from utils.map_export import write_obj, write_ply
+from utils.serial_parser import SERIAL_PORT, open_serial_port, read_serial, parse_packet
+
+logger = connect_python.get_logger(__name__)
+
+# Give up if the map hasn't all arrived by then; 642 cells take under a second.
+TIMEOUT_S = 10.0
+
+def fetch_map(port: str) -> list[MapCell]:
+    """Ask for the map and collect its cells until the closing line."""
+    ser = open_serial_port(port)
+    cells: dict[int, MapCell] = {}
+    try:
+        ser.write("SMAP EXPORT\r".encode("utf-8"))
+        deadline = time.monotonic() + TIMEOUT_S
+        while time.monotonic() < deadline:
+            for packet in read_serial(ser):
+                if packet == "Map: done":
+                    return [cells[index] for index in sorted(cells)]
+                result = parse_packet(packet)
+                if isinstance(result, MapCell):
+                    cells[result.index] = result
+            time.sleep(0.01)
+    finally:
+        ser.close()
+    logger.error("Timed out with %d cells of the map received", len(cells))
+    return [cells[index] for index in sorted(cells)]
+
+def main():
+    parser = argparse.ArgumentParser(description="Export the device's sphere map.")
+    parser.add_argument("output", nargs="?", default="sphere_map.ply")
+    parser.add_argument("--port", default=SERIAL_PORT)
+    args = parser.parse_args()
+
+    cells = fetch_map(args.port)
+    if not cells:
+        return
+    if args.output.endswith(".obj"):
+        write_obj(cells, args.output)
+    else:
+        write_ply(cells, args.output)
+    logger.info("Wrote %d cells to %s", len(cells), args.output)
+
+
+if __name__ == "__main__":
+    main()
@@ -11,8 +11,8 @@ from vispy import app
 
 from utils.sphere import SphereOrientation, IMAGE_SIZE
 from utils.quaternion import Quaternion
-from utils.measure import Calibration
-from utils.serial_parser import open_serial_port, read_serial, parse_line
+from utils.measure import Calibration, Gyro, Status, MapCell, MapDifference, MapComplete, SurveyPoint
+from utils.serial_parser import open_serial_port, read_serial, parse_packet
 
 # Set up logger
 logger = connect_python.get_logger(__name__)
@@ -41,6 +41,7 @@ def stream_data(client: connect_python.Client):
     ser = open_serial_port()
     sphere = SphereOrientation(render=False)
     quat = Quaternion()
+    gyro = None
 
     def on_timer(event):
         # Handle calibration updates from the client.
@@ -50,35 +51,85 @@ def stream_data(client: connect_python.Client):
             ser.write("SCAL\r".encode("utf-8"))
             client.set_value("calibration_type", "constant")
 
-        # Read serial data.
-        line = read_serial(ser)
-        if not line:
+        # Read serial data. At high rates several samples arrive per tick:
+        # each one goes through the filter, but the sphere is only drawn for
+        # the last.
+        updated = False
+        for packet in read_serial(ser):
+            updated |= handle_packet(packet)
+        if not updated:
             return
 
-        # Parse measurement data or calibration data from serial.
-        result = parse_line(line)
-        if not result:
-            return
-        elif isinstance(result, Calibration):
-            logger.info("Received calibration data from device: %s", result)
-            handle_calibration_data(client, result)
-            return
-
-        # Update quaternion and sphere orientation.
-        quat.update(result)
-        sphere.update(quat)
-
         # Extract Euler angles from quaternion.
         yaw, pitch, roll = quat.to_euler_zyx()
         t_datetime = datetime.now(timezone.utc)
 
         # Stream frame buffer and orientation data.
+        sphere.update(quat)
         pixels = sphere.to_bytes(n_pixels=IMAGE_SIZE * IMAGE_SIZE)
         client.stream_rgb("frame_buffer", 0, IMAGE_SIZE, pixels)
         client.stream("yaw", t_datetime, yaw, name="yaw", unit=Units.RADIAN)
         client.stream("pitch", t_datetime, pitch, name="pitch", unit=Units.RADIAN)
         client.stream("roll", t_datetime, roll, name="roll", unit=Units.RADIAN)
 
+    def handle_packet(packet: str | bytes) -> bool:
+        """Handle a line or frame from the device, returning whether it moved the orientation."""
+        nonlocal gyro
+
+        # Parse measurement data or calibration data from serial.
+        result = parse_packet(packet)
+        if not result:
+            return False
+        elif isinstance(result, Calibration):
+            logger.info("Received calibration data from device: %s", result)
+            handle_calibration_data(client, result)
+            return False
+        elif isinstance(result, Status):
+            # Make a regression in per-sample cost visible without a debugger.
+            logger.debug("Received status from device: %s", result)
+            client.set_value("cpu_load", result.load)
+            client.set_value("sample_rate", result.samples)
+            client.set_value("pairing_skew", result.skew)
+            if result.boots is not None:
+                client.set_value("boot_count", result.boots)
+            if result.dropped is not None:
+                client.set_value("dropped_bytes", result.dropped)
+            if result.coverage is not None:
+                client.set_value("sphere_coverage", result.coverage)
+            if result.map_bytes is not None:
+                client.set_value("sphere_map_bytes", result.map_bytes)
+            return False
+        elif isinstance(result, MapCell):
+            # Sent in answer to `SMAP EXPORT`, or as cells change after `SMAP LIVE 1`, for
+            # tools that render the map.
+            logger.debug("Received sphere map cell from device: %s", result)
+            return False
+        elif isinstance(result, SurveyPoint):
+            # Sent in the survey mode instead of measurements; see survey_log.py.
+            logger.debug("Received survey point from device: %s", result)
+            client.set_value("survey_strength", result.strength)
+            return False
+        elif isinstance(result, MapComplete):
+            logger.info("Sphere map complete: %d cells with %d samples each",
+                        result.cells, result.samples)
+            return False
+        elif isinstance(result, MapDifference):
+            # Sent in answer to `SMAP DIFF`.
+            logger.debug("Received sphere map difference from device: %s", result)
+            return False
+        elif isinstance(result, Gyro):
+            # Hold the rate for the measurement that follows it.
+            gyro = result
+            return False
+
+        if gyro is not None:
+            result.gyr = gyro.rate
+            gyro = None
+
+        # Update quaternion orientation.
+        quat.update(result)
+        return True
+
     timer = app.Timer(interval=0.01, connect=on_timer, start=True)
     try:
         app.run()
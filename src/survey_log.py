@@ -0,0 +1,88 @@
+"""Magnetic Survey Log
+Puts the device in its survey mode with `SAPP 6` and appends each averaged point it sends to a
+CSV file, with its position, until interrupted. Positions come from the device: send `SPOS`
+or `SWPT` to it, or press button B at each waypoint. With `--gps`, NMEA fixes read from a GPS
+receiver on another serial port are relayed to the device as they arrive. Run it while the host
+app isn't holding the serial port.
+
+    python src/survey_log.py [survey.csv] [--port /dev/ttyACM0] [--gps /dev/ttyUSB0]
+"""
+import argparse
+import csv
+import time
+
+import connect_python
+import serial
+
+from utils.measure import SurveyPoint
+from utils.serial_parser import SERIAL_PORT, open_serial_port, read_serial, parse_packet
+
+logger = connect_python.get_logger(__name__)
+
+FIELDS = ["time", "lat", "lon", "waypoint", "x_nt", "y_nt", "z_nt", "strength_nt", "samples"]
+GPS_BAUD_RATE = 9600
+
+def nmea_position(sentence: str) -> tuple[float, float] | None:
+    """The latitude and longitude in a GGA or RMC sentence with a fix, in degrees."""
+    parts = sentence.strip().split('*')[0].split(',')
+    if parts[0][3:] == "GGA" and len(parts) > 6 and parts[6] not in ("", "0"):
+        lat, lat_hemi, lon, lon_hemi = parts[2:6]
+    elif parts[0][3:] == "RMC" and len(parts) > 6 and parts[2] == "A":
+        lat, lat_hemi, lon, lon_hemi = parts[3:7]
+    else:
+        return None
+    try:
+        # ddmm.mmmm and dddmm.mmmm
+        lat_deg = int(lat[:2]) + float(lat[2:]) / 60
+        lon_deg = int(lon[:3]) + float(lon[3:]) / 60
+    except ValueError:
+        return None
+    return (-lat_deg if lat_hemi == "S" else lat_deg, -lon_deg if lon_hemi == "W" else lon_deg)
+
+def main():
+    parser = argparse.ArgumentParser(description="Log the device's magnetic survey.")
+    parser.add_argument("output", nargs="?", default="survey.csv")
+    parser.add_argument("--port", default=SERIAL_PORT)
+    parser.add_argument("--gps", help="serial port of an NMEA GPS receiver to relay fixes from")
+    args = parser.parse_args()
+
+    ser = open_serial_port(args.port)
+    gps = serial.Serial(args.gps, GPS_BAUD_RATE, timeout=0) if args.gps else None
+    gps_pending = b""
+    points = 0
+    try:
+        ser.write("SAPP 6\r".encode("utf-8"))
+        with open(args.output, 'a', newline='', encoding='utf-8') as out:
+            writer = csv.DictWriter(out, fieldnames=FIELDS)
+            if out.tell() == 0:
+                writer.writeheader()
+            while True:
+                if gps is not None and gps.in_waiting > 0:
+                    gps_pending += gps.read(gps.in_waiting)
+                    *sentences, gps_pending = gps_pending.split(b"\n")
+                    for sentence in sentences:
+                        fix = nmea_position(sentence.decode("ascii", errors="ignore"))
+                        if fix is not None:
+                            ser.write(f"SPOS {fix[0]:.7f},{fix[1]:.7f}\r".encode("utf-8"))
+                for packet in read_serial(ser):
+                    result = parse_packet(packet)
+                    if not isinstance(result, SurveyPoint):
+                        continue
+                    x, y, z = result.field
+                    writer.writerow({"time": time.time(), "lat": result.lat, "lon": result.lon,
+                                     "waypoint": result.waypoint, "x_nt": x, "y_nt": y, "z_nt": z,
+                                     "strength_nt": result.strength, "samples": result.samples})
+                    out.flush()
+                    points += 1
+                time.sleep(0.01)
+    except KeyboardInterrupt:
+        pass
+    finally:
+        ser.close()
+        if gps is not None:
+            gps.close()
+    logger.info("Logged %d survey points to %s", points, args.output)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,67 @@
+"""
+Module for writing the device's sphere map as a mesh file.
+
+Each cell becomes a vertex at its direction on the unit sphere, coloured by
+its average field strength from blue (weakest) through green to red
+(strongest), with cells that have no samples yet in grey, so gaps in
+coverage show. PLY files also carry the strength as each vertex's quality,
+which MeshLab can colour and filter by, along with the sample count,
+standard deviation and the standard error of the average, which a fit can
+weight each cell by (-1 for cells with too few samples to tell).
+"""
+from .measure import MapCell
+
+EMPTY_COLOR = (128, 128, 128)
+
+def color_by_magnitude(cells: list[MapCell]) -> list[tuple[int, int, int]]:
+    """An RGB colour per cell, spread over the range of average strengths."""
+    means = [cell.mean for cell in cells if cell.mean is not None]
+    low = min(means, default=0.0)
+    span = max(means, default=0.0) - low
+    colors = []
+    for cell in cells:
+        if cell.mean is None:
+            colors.append(EMPTY_COLOR)
+            continue
+        t = (cell.mean - low) / span if span > 0 else 0.5
+        colors.append(_ramp(t))
+    return colors
+
+def _ramp(t: float) -> tuple[int, int, int]:
+    """Blue at 0, green at 0.5, red at 1."""
+    if t < 0.5:
+        return (0, round(510 * t), round(255 - 510 * t))
+    return (round(510 * (t - 0.5)), round(255 - 510 * (t - 0.5)), 0)
+
+def write_ply(cells: list[MapCell], path: str) -> None:
+    """Write the cells as an ASCII PLY point cloud."""
+    colors = color_by_magnitude(cells)
+    with open(path, 'w', encoding='ascii') as out:
+        out.write("ply\n")
+        out.write("format ascii 1.0\n")
+        out.write(f"comment sphere map, {len(cells)} cells, strengths in nT\n")
+        out.write(f"element vertex {len(cells)}\n")
+        for name in ('x', 'y', 'z'):
+            out.write(f"property float {name}\n")
+        for name in ('red', 'green', 'blue'):
+            out.write(f"property uchar {name}\n")
+        out.write("property float quality\n")
+        out.write("property uint samples\n")
+        out.write("property float std_dev\n")
+        out.write("property float std_error\n")
+        out.write("end_header\n")
+        for cell, (r, g, b) in zip(cells, colors):
+            x, y, z = cell.direction
+            out.write(f"{x:.6f} {y:.6f} {z:.6f} {r} {g} {b} "
+                      f"{cell.mean or 0.0:.1f} {cell.samples} {cell.std_dev or 0.0:.1f} "
+                      f"{-1.0 if cell.std_error is None else cell.std_error:.2f}\n")
+
+def write_obj(cells: list[MapCell], path: str) -> None:
+    """Write the cells as OBJ vertices, with the colour after each position as MeshLab and
+    Blender read it."""
+    colors = color_by_magnitude(cells)
+    with open(path, 'w', encoding='ascii') as out:
+        out.write(f"# sphere map, {len(cells)} cells\n")
+        for cell, (r, g, b) in zip(cells, colors):
+            x, y, z = cell.direction
+            out.write(f"v {x:.6f} {y:.6f} {z:.6f} {r / 255:.4f} {g / 255:.4f} {b / 255:.4f}\n")
@@ -7,12 +7,167 @@ logger = connect_python.get_logger(__name__)
 
 class Measurement:
     """Class to hold measurement data."""
-    def __init__(self, mag: tuple[float, float, float], acc: tuple[float, float, float]):
+    def __init__(self,
+                 mag: tuple[float, float, float],
+                 acc: tuple[float, float, float],
+                 gyr: tuple[float, float, float] | None = None,
+                 time: float | None = None):
         self.mag = mag
         self.acc = acc
+        self.gyr = gyr
+        # seconds since the device booted, when the sensor had the data ready
+        self.time = time
 
     def __repr__(self) -> str:
-        return f"Measurement(mag={self.mag}, acc={self.acc})"
+        return (f"Measurement(mag={self.mag}, acc={self.acc}, gyr={self.gyr}, "
+                f"time={self.time})")
+
+class Gyro:
+    """Class to hold the average angular rate, in deg/s, since the last measurement."""
+    def __init__(self, rate: tuple[float, float, float]):
+        self.rate = rate
+
+    def __repr__(self) -> str:
+        return f"Gyro(rate={self.rate})"
+
+class Status:
+    """Class to hold the device's periodic status frame."""
+    def __init__(self,
+                 uptime: int,
+                 load: float,
+                 idle: float,
+                 samples: int,
+                 skew: int,
+                 mode: str,
+                 calibrated: bool,
+                 boots: int | None = None,
+                 total_uptime: int | None = None,
+                 reset: str | None = None,
+                 dropped: int | None = None,
+                 coverage: float | None = None,
+                 map_bytes: int | None = None):
+        self.uptime = uptime
+        self.load = load
+        self.idle = idle
+        self.samples = samples
+        # largest gap, in microseconds, between the halves of one sample
+        self.skew = skew
+        self.mode = mode
+        self.calibrated = calibrated
+        # boots so far, seconds run over all of them, and why the device last reset
+        self.boots = boots
+        self.total_uptime = total_uptime
+        self.reset = reset
+        # bytes of output the device dropped because the link couldn't keep up
+        self.dropped = dropped
+        # percentage of sphere-map cells with enough samples to count as covered
+        self.coverage = coverage
+        # RAM the sphere map takes on the device, in bytes
+        self.map_bytes = map_bytes
+
+    def __repr__(self) -> str:
+        return (f"Status(uptime={self.uptime}, load={self.load}, idle={self.idle}, "
+                f"samples={self.samples}, skew={self.skew}, mode={self.mode}, calibrated={self.calibrated}, "
+                f"boots={self.boots}, total_uptime={self.total_uptime}, reset={self.reset}, "
+                f"dropped={self.dropped}, coverage={self.coverage}, map_bytes={self.map_bytes})")
+
+class MapCell:
+    """Class to hold one cell of the device's sphere map."""
+    def __init__(self,
+                 index: int,
+                 cells: int,
+                 direction: tuple[float, float, float],
+                 samples: int,
+                 mean: float | None,
+                 std_dev: float | None = None,
+                 minimum: float | None = None,
+                 maximum: float | None = None,
+                 std_error: float | None = None):
+        self.index = index
+        self.cells = cells
+        # unit vector through the middle of the cell, in the board's axes
+        self.direction = direction
+        self.samples = samples
+        # calibrated field strength in nT: average, standard deviation, smallest
+        # and largest, None until there are enough samples
+        self.mean = mean
+        self.std_dev = std_dev
+        self.minimum = minimum
+        self.maximum = maximum
+        # standard error of the average in nT, for weighting the cell in a fit; None until
+        # there are enough samples, or from firmware that doesn't send it
+        self.std_error = std_error
+
+    def __repr__(self) -> str:
+        return (f"MapCell(index={self.index}, cells={self.cells}, direction={self.direction}, "
+                f"samples={self.samples}, mean={self.mean}, std_dev={self.std_dev}, "
+                f"minimum={self.minimum}, maximum={self.maximum}, std_error={self.std_error})")
+
+class SurveyPoint:
+    """Class to hold one averaged point of a magnetic survey."""
+    def __init__(self,
+                 field: tuple[int, int, int],
+                 strength: int,
+                 samples: int,
+                 lat: float | None = None,
+                 lon: float | None = None,
+                 waypoint: int | None = None):
+        # where it was measured: latitude and longitude in degrees, or a waypoint number
+        self.lat = lat
+        self.lon = lon
+        self.waypoint = waypoint
+        # average calibrated field and its strength in nT, over this many samples
+        self.field = field
+        self.strength = strength
+        self.samples = samples
+
+    def __repr__(self) -> str:
+        return (f"SurveyPoint(lat={self.lat}, lon={self.lon}, waypoint={self.waypoint}, "
+                f"field={self.field}, strength={self.strength}, samples={self.samples})")
+
+class MapComplete:
+    """Class to hold the device's announcement that every sphere map cell is covered."""
+    def __init__(self, cells: int, samples: int):
+        self.cells = cells
+        # samples each cell needed to count as covered
+        self.samples = samples
+
+    def __repr__(self) -> str:
+        return f"MapComplete(cells={self.cells}, samples={self.samples})"
+
+class MapDifference:
+    """Class to hold one cell of the difference between two saved sphere maps."""
+    def __init__(self,
+                 index: int,
+                 cells: int,
+                 direction: tuple[float, float, float],
+                 before_samples: int,
+                 before_mean: float | None,
+                 after_samples: int,
+                 after_mean: float | None):
+        self.index = index
+        self.cells = cells
+        # unit vector through the middle of the cell, in the board's axes
+        self.direction = direction
+        # average calibrated field strength in nT in slot 0 (before) and slot 1
+        # (after), None where the cell has no samples
+        self.before_samples = before_samples
+        self.before_mean = before_mean
+        self.after_samples = after_samples
+        self.after_mean = after_mean
+
+    @property
+    def difference(self) -> float | None:
+        """How much the average grew in nT, None unless both maps have samples."""
+        if self.before_mean is None or self.after_mean is None:
+            return None
+        return self.after_mean - self.before_mean
+
+    def __repr__(self) -> str:
+        return (f"MapDifference(index={self.index}, cells={self.cells}, "
+                f"direction={self.direction}, before_samples={self.before_samples}, "
+                f"before_mean={self.before_mean}, after_samples={self.after_samples}, "
+                f"after_mean={self.after_mean}, difference={self.difference})")
 
 class Calibration:
     """Class to hold calibration parameters."""
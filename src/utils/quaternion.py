@@ -2,18 +2,24 @@
 Module for quaternion representation and conversion to rotation matrix.
 """
 import numpy as np
-from ahrs.filters import FQA
+from ahrs.filters import FQA, Madgwick
 
 class Quaternion:
     """Quaternion representation and conversion to rotation matrix."""
     def __init__(self):
         self.q = np.array([1.0, 0.0, 0.0, 0.0])
+        # The device sends a measurement every 100 ms.
+        self.madgwick = Madgwick(frequency=10.0)
+        self.last_time = None
 
     def __repr__(self) -> str:
         return f"Quaternion(q={self.q})"
 
     def update(self, measurement):
-        """Update quaternion from magnetometer and accelerometer data."""
+        """
+        Update quaternion from magnetometer and accelerometer data, and gyro
+        data if the measurement has any.
+        """
         acc = np.array(measurement.acc, dtype=float)
         acc_norm = np.linalg.norm(acc)
         if acc_norm > 0:
@@ -30,7 +36,21 @@ class Quaternion:
             print("Warning: Zero magnetometer reading")
             return
 
-        # use FQA (acc+mag) to estimate orientation
+        # with a gyro, use Madgwick (gyr+acc+mag), which follows fast motion
+        # that FQA would see as a tilted field
+        if measurement.gyr is not None:
+            gyr = np.radians(np.array(measurement.gyr, dtype=float))
+            # integrate over the real interval between acquisitions when the
+            # device timestamps its samples, rather than the nominal 100 ms
+            if measurement.time is not None and self.last_time is not None:
+                dt = measurement.time - self.last_time
+                if dt > 0:
+                    self.madgwick.Dt = dt
+            self.last_time = measurement.time
+            self.q = self.madgwick.updateMARG(self.q, gyr=gyr, acc=acc, mag=mag)
+            return
+
+        # otherwise use FQA (acc+mag) to estimate orientation
         fqa = FQA()
         self.q = fqa.estimate(acc=acc, mag=mag)
 
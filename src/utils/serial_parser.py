@@ -1,13 +1,16 @@
 """
 Module for serial communication and data parsing.
 """
+import math
 import sys
 import re
+import struct
 
 import connect_python
 import serial
 
-from .measure import Measurement, Calibration
+from .measure import (Measurement, Calibration, Gyro, Status, MapCell, MapDifference, MapComplete,
+                      SurveyPoint)
 
 logger = connect_python.get_logger(__name__)
 
@@ -16,8 +19,30 @@ SERIAL_PORT = '/dev/ttyACM0'
 BAUD_RATE = 115200
 
 # Regex pattern to match the expected format
-meas_pattern = re.compile(r'Measurement: (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?)\r?\n?')
+meas_pattern = re.compile(r'Measurement: (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?)(?:, (\d+))?\r?\n?')
 cal_pattern = re.compile(r'Calibration: (-?\d+), (-?\d+), (-?\d+), (-?\d+), (-?\d+), (-?\d+), (\d+)\r?\n?')
+gyro_pattern = re.compile(r'Gyro: (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?), (-?\d+(?:\.\d+)?)\r?\n?')
+status_pattern = re.compile(r'Status: uptime=(\d+), load=(\d+\.\d), idle=(\d+\.\d), samples=(\d+), skew=(\d+), mode=(\w+), calibrated=(yes|no)(?:, boots=(\d+), total=(\d+), reset=([\w -]+?)(?:, dropped=(\d+)(?:, coverage=(\d+\.\d)(?:, map=(\d+))?)?)?)?\r?\n?$')
+map_complete_pattern = re.compile(r'Map: complete, cells=(\d+), samples=(\d+)\r?\n?$')
+survey_pattern = re.compile(r'Survey: (?:lat=(-?\d+\.\d+), lon=(-?\d+\.\d+)|waypoint=(\d+)), x=(-?\d+), y=(-?\d+), z=(-?\d+), strength=(\d+), samples=(\d+)\r?\n?$')
+
+# Binary frames (see sphere-mapping-core/src/frame.rs)
+FRAME_SYNC = b'\xa5\x5a'
+FRAME_KIND_SAMPLE = 1
+FRAME_KIND_SAMPLE_GYRO = 2
+FRAME_KIND_SPHERE_CELL = 3
+FRAME_KIND_SPHERE_DIFF = 4
+FRAME_HEADER_LEN = 4
+FRAME_CRC_LEN = 2
+sample_struct = struct.Struct('<Q3i3h')
+gyro_struct = struct.Struct('<3f')
+cell_struct = struct.Struct('<2H3f5I')
+# Appended to a cell frame by newer firmware
+cell_error_struct = struct.Struct('<f')
+diff_struct = struct.Struct('<2H3f4Ii')
+
+# Bytes read but not yet split into lines and frames
+_pending = bytearray()
 
 def open_serial_port(port: str=SERIAL_PORT, baudrate: int=BAUD_RATE) -> serial.Serial:
     """Open and return a serial port."""
@@ -29,30 +54,133 @@ def open_serial_port(port: str=SERIAL_PORT, baudrate: int=BAUD_RATE) -> serial.S
         logger.error("Could not open serial port %s: %s", port, e)
         sys.exit(1)
 
-def read_serial(uart: serial.Serial | None) -> str | None:
-    """Read a line from the serial port."""
+def read_serial(uart: serial.Serial | None) -> list[str | bytes]:
+    """Read whatever has arrived, as complete text lines and binary frames."""
     try:
         if uart and uart.in_waiting > 0:
-            line_bytes = uart.readline()
-            line_str = line_bytes.decode('utf-8', errors='ignore').strip()
-            return line_str
-        return None
+            _pending.extend(uart.read(uart.in_waiting))
     except Exception as e:
         logger.error("Error reading from serial port: %s", e)
+    packets = []
+    while True:
+        packet = _take_packet()
+        if packet is None:
+            return packets
+        packets.append(packet)
+
+def _take_packet() -> str | bytes | None:
+    """Split the next line or frame off the pending bytes, if one is complete."""
+    sync = _pending.find(FRAME_SYNC)
+    newline = _pending.find(b'\n')
+    if sync == 0:
+        if len(_pending) < FRAME_HEADER_LEN:
+            return None
+        frame_len = FRAME_HEADER_LEN + _pending[3] + FRAME_CRC_LEN
+        if len(_pending) < frame_len:
+            return None
+        frame = bytes(_pending[:frame_len])
+        if crc16(frame[2:-FRAME_CRC_LEN]) != int.from_bytes(frame[-FRAME_CRC_LEN:], 'little'):
+            # Not a frame after all; resync on the next byte.
+            del _pending[0]
+            return b''
+        del _pending[:frame_len]
+        return frame
+    if newline < 0 or (0 < sync < newline):
+        # Text never contains the sync bytes, so anything before them is a
+        # broken line.
+        if sync > 0:
+            del _pending[:sync]
+            return ''
+        return None
+    line = bytes(_pending[:newline + 1])
+    del _pending[:newline + 1]
+    return line.decode('utf-8', errors='ignore').strip()
+
+def crc16(data: bytes) -> int:
+    """CRC-16/CCITT-FALSE, as the firmware computes it."""
+    crc = 0xffff
+    for byte in data:
+        crc ^= byte << 8
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x1021) & 0xffff if crc & 0x8000 else (crc << 1) & 0xffff
+    return crc
+
+def parse_packet(packet: str | bytes) -> (Measurement | Calibration | Gyro | Status | MapCell
+                                          | MapDifference | MapComplete | SurveyPoint | None):
+    """Parse a text line or a binary frame."""
+    if isinstance(packet, bytes):
+        return parse_frame(packet)
+    return parse_line(packet)
+
+def parse_frame(frame: bytes) -> Measurement | MapCell | MapDifference | None:
+    """Parse a binary sample frame, with its gyro rates if it has them, a sphere map cell, or
+    a cell of the difference between two saved sphere maps."""
+    if len(frame) < FRAME_HEADER_LEN + FRAME_CRC_LEN:
+        return None
+    kind = frame[2]
+    payload = frame[FRAME_HEADER_LEN:-FRAME_CRC_LEN]
+    if kind == FRAME_KIND_SPHERE_CELL:
+        if len(payload) < cell_struct.size:
+            return None
+        (index, cells, dx, dy, dz, samples,
+         mean_nt, std_dev_nt, min_nt, max_nt) = cell_struct.unpack_from(payload)
+        std_error = None
+        if len(payload) >= cell_struct.size + cell_error_struct.size:
+            (std_error,) = cell_error_struct.unpack_from(payload, cell_struct.size)
+            if not math.isfinite(std_error):
+                std_error = None
+        return MapCell(index=index, cells=cells, direction=(dx, dy, dz), samples=samples,
+                       mean=float(mean_nt) if samples else None,
+                       std_dev=float(std_dev_nt) if samples > 1 else None,
+                       minimum=float(min_nt) if samples else None,
+                       maximum=float(max_nt) if samples else None,
+                       std_error=std_error)
+    if kind == FRAME_KIND_SPHERE_DIFF:
+        if len(payload) < diff_struct.size:
+            return None
+        # The difference is left for MapDifference to work out from the means.
+        (index, cells, dx, dy, dz, before_samples, before_nt,
+         after_samples, after_nt, _) = diff_struct.unpack_from(payload)
+        return MapDifference(index=index, cells=cells, direction=(dx, dy, dz),
+                             before_samples=before_samples,
+                             before_mean=float(before_nt) if before_samples else None,
+                             after_samples=after_samples,
+                             after_mean=float(after_nt) if after_samples else None)
+    if len(payload) < sample_struct.size:
         return None
+    if kind not in (FRAME_KIND_SAMPLE, FRAME_KIND_SAMPLE_GYRO):
+        return None
+    time_us, gx, gy, gz, ax, ay, az = sample_struct.unpack_from(payload)
+    gyr = None
+    if kind == FRAME_KIND_SAMPLE_GYRO:
+        gyr = gyro_struct.unpack_from(payload, sample_struct.size)
+    return Measurement(mag=(float(gx), float(gy), float(gz)),
+                       acc=(float(ax), float(ay), float(az)),
+                       gyr=gyr,
+                       time=time_us / 1e6)
 
-def parse_line(line: str) -> Measurement | Calibration | None:
+def parse_line(line: str) -> (Measurement | Calibration | Gyro | Status | MapComplete | SurveyPoint
+                             | None):
     """
     Parse of a line of serial data.
-    Expected format: "Measurement: {mag_x}, {mag_y}, {mag_z}, {acc_x}, {acc_y}, {acc_z}"
+    Expected format: "Measurement: {mag_x}, {mag_y}, {mag_z}, {acc_x}, {acc_y}, {acc_z}, {time_us}",
+    where older firmware leaves out the time
     or "Calibration: {center_x}, {center_y}, {center_z}, {scale_x}, {scale_y}, {scale_z}, {radius}"
+    or "Gyro: {rate_x}, {rate_y}, {rate_z}", sent just before the measurement it belongs to
+    or "Status: uptime={s}, load={%}, idle={%}, samples={n}, skew={us}, mode={name}, calibrated={yes|no},
+    boots={n}, total={s}, reset={reason}, dropped={bytes}, coverage={%}, map={bytes}", where older
+    firmware leaves out the boot count, the dropped bytes, the coverage or the map's size onwards
+    or "Map: complete, cells={n}, samples={n}", once every sphere map cell is covered
+    or "Survey: lat={degrees}, lon={degrees}, x={nT}, y={nT}, z={nT}, strength={nT}, samples={n}",
+    with "waypoint={n}" in place of the latitude and longitude for a grid set out by hand
     """
     match = meas_pattern.search(line)
     if match:
         try:
             mag = (float(match.group(1)), float(match.group(2)), float(match.group(3)))
             acc = (float(match.group(4)), float(match.group(5)), float(match.group(6)))
-            return Measurement(mag=mag, acc=acc)
+            time = int(match.group(7)) / 1e6 if match.group(7) else None
+            return Measurement(mag=mag, acc=acc, time=time)
         except ValueError:
             logger.error("Error parsing measurement line: %s", line)
     match = cal_pattern.search(line)
@@ -64,4 +192,40 @@ def parse_line(line: str) -> Measurement | Calibration | None:
             return Calibration(is_constant=True, center=center, scale=scale, radius=radius)
         except ValueError:
             logger.error("Error parsing calibration line: %s", line)
+    match = gyro_pattern.search(line)
+    if match:
+        try:
+            rate = (float(match.group(1)), float(match.group(2)), float(match.group(3)))
+            return Gyro(rate=rate)
+        except ValueError:
+            logger.error("Error parsing gyro line: %s", line)
+    match = status_pattern.search(line)
+    if match:
+        try:
+            return Status(uptime=int(match.group(1)),
+                          load=float(match.group(2)),
+                          idle=float(match.group(3)),
+                          samples=int(match.group(4)),
+                          skew=int(match.group(5)),
+                          mode=match.group(6),
+                          calibrated=match.group(7) == "yes",
+                          boots=int(match.group(8)) if match.group(8) else None,
+                          total_uptime=int(match.group(9)) if match.group(9) else None,
+                          reset=match.group(10),
+                          dropped=int(match.group(11)) if match.group(11) else None,
+                          coverage=float(match.group(12)) if match.group(12) else None,
+                          map_bytes=int(match.group(13)) if match.group(13) else None)
+        except ValueError:
+            logger.error("Error parsing status line: %s", line)
+    match = map_complete_pattern.search(line)
+    if match:
+        return MapComplete(cells=int(match.group(1)), samples=int(match.group(2)))
+    match = survey_pattern.search(line)
+    if match:
+        return SurveyPoint(lat=float(match.group(1)) if match.group(1) else None,
+                           lon=float(match.group(2)) if match.group(2) else None,
+                           waypoint=int(match.group(3)) if match.group(3) else None,
+                           field=(int(match.group(4)), int(match.group(5)), int(match.group(6))),
+                           strength=int(match.group(7)),
+                           samples=int(match.group(8)))
     return None
@@ -0,0 +1,497 @@
+//! Hardware-in-the-loop checks: flashes the firmware onto an attached
+//! micro:bit, drives it over serial as the host tools do and checks what
+//! it says back, so a change that breaks the protocol is caught on the
+//! board itself before a release, not by whoever runs the tools next.
+//!
+//! The port is opened before flashing, which goes through `cargo xtask
+//! flash` with the same `--embassy` and `--features`, so the boot banner is
+//! seen; with `--no-flash` the board is only reset, with `probe-rs reset`,
+//! to run the checks against what is on it already. Each check then runs
+//! in turn:
+//!
+//! - `banner`: the boot banner's `Version:`, `Reset:`, `Calibration:` and
+//!   `Settings:` lines arrive, in that order;
+//! - `status`: a `Status:` line comes within its interval, having dropped
+//!   nothing since boot;
+//! - `text`, `csv`, `binary`: `SFMT` is acknowledged in a `Settings:` line
+//!   and samples then come in that format, binary frames all passing their
+//!   CRC;
+//! - `every`: `SRPT 4` spaces samples four times as far apart as `SRPT 1`;
+//! - `calibration`: a calibration sent with `SCAL` is echoed back, applied
+//!   and saved, then the board's own is sent back the same way;
+//! - `junk`: bytes that aren't a command, and a line too long to be one,
+//!   don't stop the next command being acknowledged.
+//!
+//! The output format and report interval are put back as the banner gave
+//! them afterwards, whether the checks passed or not. Run it with
+//! `cargo run -p tests-hil`; it exits with failure if any check fails.
+
+use std::collections::VecDeque;
+use std::io;
+use std::process::{Command as Process, ExitCode};
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use serialport::SerialPort;
+use sphere_mapping_host::frame::parse_status;
+use sphere_mapping_host::port;
+use sphere_mapping_host::sphere_mapping_core::calibration::{Calibration, Measurement};
+use sphere_mapping_host::sphere_mapping_core::config::BAUD_RATE;
+use sphere_mapping_host::sphere_mapping_core::frame::SYNC;
+use sphere_mapping_host::sphere_mapping_core::settings::OutputFormat;
+use sphere_mapping_host::sphere_mapping_core::stream::{Decoder, Span};
+use sphere_mapping_host::Frame;
+
+/// The micro:bit v2's nRF52833, as probe-rs names it.
+const CHIP: &str = "nRF52833_xxAA";
+/// How long the board has to send its boot banner once started.
+const BANNER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long it has to acknowledge a command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long it has to send a sample or a `Status:` line.
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long binary frames are watched for CRC failures.
+const BINARY_WATCH: Duration = Duration::from_secs(2);
+/// Intervals between samples the report interval is measured over.
+const INTERVALS: usize = 10;
+
+#[derive(Parser)]
+#[command(about = "Check the firmware's serial protocol on an attached board")]
+struct Cli {
+    /// Check the Embassy firmware instead of the RTIC one.
+    #[arg(long)]
+    embassy: bool,
+    /// Cargo features to build the firmware with, comma-separated.
+    #[arg(long)]
+    features: Option<String>,
+    /// Check what is on the board already, only resetting it.
+    #[arg(long)]
+    no_flash: bool,
+    /// Serial port the board is on; found by its USB IDs if left out.
+    #[arg(long)]
+    port: Option<String>,
+    #[arg(long, default_value_t = BAUD_RATE)]
+    baud: u32,
+}
+
+/// A check's name, and the check.
+type Check = (&'static str, fn(&mut Harness) -> Result<(), String>);
+
+const CHECKS: [Check; 7] = [
+    ("status", status),
+    ("text", text),
+    ("csv", csv),
+    ("binary", binary),
+    ("every", every),
+    ("calibration", calibration),
+    ("junk", junk),
+];
+
+fn main() -> ExitCode {
+    match run(&Cli::parse()) {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(failed) => {
+            eprintln!("{failed} checks failed");
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Starts the board and runs every check, returning how many failed.
+fn run(cli: &Cli) -> io::Result<usize> {
+    let (name, port) = port::connect(cli.port.clone(), cli.baud)?;
+    eprintln!("Checking the board on {name}");
+    let board = Board::new(port);
+    if cli.no_flash {
+        run_process(Process::new("probe-rs").args(["reset", "--chip", CHIP]))?;
+    } else {
+        let mut cargo = Process::new(std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()));
+        cargo.current_dir(root()).args(["xtask", "flash"]);
+        if cli.embassy {
+            cargo.arg("--embassy");
+        }
+        if let Some(features) = &cli.features {
+            cargo.args(["--features", features]);
+        }
+        run_process(&mut cargo)?;
+    }
+    let started = Instant::now();
+    let mut harness = match banner(board) {
+        Ok(harness) => {
+            report("banner", started, Ok(()));
+            harness
+        }
+        Err(err) => {
+            report("banner", started, Err(err));
+            eprintln!("Can't go on without the banner's settings");
+            return Ok(1);
+        }
+    };
+    let mut failed = 0;
+    for (name, check) in CHECKS {
+        let started = Instant::now();
+        let result = check(&mut harness);
+        failed += result.is_err() as usize;
+        report(name, started, result);
+    }
+    if let Err(err) = harness.restore() {
+        eprintln!("Couldn't put the board's settings back: {err}");
+        failed += 1;
+    }
+    Ok(failed)
+}
+
+fn report(name: &str, started: Instant, result: Result<(), String>) {
+    let took = started.elapsed().as_secs_f64();
+    match result {
+        Ok(()) => println!("{name:<12} ok ({took:.1} s)"),
+        Err(err) => println!("{name:<12} FAILED ({took:.1} s): {err}"),
+    }
+}
+
+/// The board, and what its boot banner said.
+struct Harness {
+    board: Board,
+    calibration: Calibration,
+    format: String,
+    every: String,
+}
+
+impl Harness {
+    /// Sends `command` and waits for the `Settings:` line acknowledging it
+    /// to give `key` as `value`.
+    fn set(&mut self, command: &str, key: &str, value: &str) -> Result<(), String> {
+        self.board.send(command)?;
+        let settings = self
+            .board
+            .expect("a Settings: line", REPLY_TIMEOUT, settings_fields)?;
+        match field(&settings, key) {
+            Some(actual) if actual == value => Ok(()),
+            Some(actual) => Err(format!("{command} gave {key}={actual}, not {value}")),
+            None => Err(format!("{command}: the Settings: line has no {key}")),
+        }
+    }
+
+    /// Switches to output `format` and checks the next sample comes in it,
+    /// as told by its bytes.
+    fn format(&mut self, format: &str, looks: fn(&[u8]) -> bool) -> Result<(), String> {
+        let index = format_index(format).ok_or(format!("no output format {format}"))?;
+        self.set(&format!("SFMT {index}"), "format", format)?;
+        // Samples already on their way come in the old format.
+        let deadline = Instant::now() + SAMPLE_TIMEOUT;
+        while Instant::now() < deadline {
+            let received =
+                self.board.expect("a sample", SAMPLE_TIMEOUT, |received| {
+                    match received.frame {
+                        Frame::Sample(_) => Some(looks(&received.raw)),
+                        _ => None,
+                    }
+                })?;
+            if received {
+                return Ok(());
+            }
+        }
+        Err(format!("no sample came as {format}"))
+    }
+
+    /// The median interval between samples, in µs.
+    fn interval_us(&mut self) -> Result<u64, String> {
+        let mut timestamps = Vec::new();
+        while timestamps.len() <= INTERVALS {
+            let timestamp_us = self.board.expect("a sample", SAMPLE_TIMEOUT, |received| {
+                match &received.frame {
+                    Frame::Sample(sample) => Some(sample.timestamp_us),
+                    _ => None,
+                }
+            })?;
+            timestamps.push(timestamp_us);
+        }
+        let mut intervals: Vec<u64> = timestamps
+            .windows(2)
+            .map(|pair| pair[1].saturating_sub(pair[0]))
+            .collect();
+        intervals.sort_unstable();
+        Ok(intervals[intervals.len() / 2])
+    }
+
+    /// Puts the output format and report interval back as the banner gave
+    /// them.
+    fn restore(&mut self) -> Result<(), String> {
+        let format = self.format.clone();
+        let index = format_index(&format).ok_or(format!("no output format {format}"))?;
+        self.set(&format!("SFMT {index}"), "format", &format)?;
+        let every = self.every.clone();
+        self.set(&format!("SRPT {every}"), "every", &every)
+    }
+}
+
+/// Checks the boot banner, and keeps what it says the board is using.
+fn banner(mut board: Board) -> Result<Harness, String> {
+    board.expect("a Version: line", BANNER_TIMEOUT, |received| {
+        line(received)
+            .is_some_and(|line| line.starts_with("Version:"))
+            .then_some(())
+    })?;
+    board.expect("a Reset: line", BANNER_TIMEOUT, |received| {
+        line(received)
+            .is_some_and(|line| line.starts_with("Reset:"))
+            .then_some(())
+    })?;
+    let calibration =
+        board.expect(
+            "a Calibration: line",
+            BANNER_TIMEOUT,
+            |received| match &received.frame {
+                Frame::Calibration(calibration) => Some(*calibration),
+                _ => None,
+            },
+        )?;
+    let settings = board.expect("a Settings: line", BANNER_TIMEOUT, settings_fields)?;
+    let setting = |key| {
+        field(&settings, key)
+            .map(str::to_string)
+            .ok_or(format!("the Settings: line has no {key}"))
+    };
+    Ok(Harness {
+        calibration,
+        format: setting("format")?,
+        every: setting("every")?,
+        board,
+    })
+}
+
+fn status(harness: &mut Harness) -> Result<(), String> {
+    let status = harness.board.expect(
+        "a Status: line",
+        SAMPLE_TIMEOUT,
+        |received| match &received.frame {
+            Frame::Status(fields) => Some(fields.clone()),
+            _ => None,
+        },
+    )?;
+    for key in ["uptime", "samples", "dropped"] {
+        if field(&status, key).is_none() {
+            return Err(format!("the Status: line has no {key}"));
+        }
+    }
+    match field(&status, "dropped") {
+        Some("0") => Ok(()),
+        Some(dropped) => Err(format!("dropped {dropped} bytes since boot")),
+        None => unreachable!("checked above"),
+    }
+}
+
+fn text(harness: &mut Harness) -> Result<(), String> {
+    harness.format("text", |raw| raw.starts_with(b"Measurement:"))
+}
+
+fn csv(harness: &mut Harness) -> Result<(), String> {
+    harness.format("csv", |raw| raw.first().is_some_and(u8::is_ascii_digit))
+}
+
+fn binary(harness: &mut Harness) -> Result<(), String> {
+    harness.format("binary", |raw| raw.starts_with(&SYNC))?;
+    let deadline = Instant::now() + BINARY_WATCH;
+    let mut samples = 0;
+    while let Some(received) = harness.board.next(deadline)? {
+        match received.frame {
+            Frame::Sample(_) => samples += 1,
+            Frame::Corrupt(bytes) => return Err(format!("{bytes} bytes failed to decode")),
+            _ => {}
+        }
+    }
+    match samples {
+        0 => Err("no samples while watching the frames".into()),
+        _ => Ok(()),
+    }
+}
+
+fn every(harness: &mut Harness) -> Result<(), String> {
+    harness.set("SRPT 1", "every", "1")?;
+    let each = harness.interval_us()?;
+    harness.set("SRPT 4", "every", "4")?;
+    // The first interval may straddle the change.
+    harness.interval_us()?;
+    let fourth = harness.interval_us()?;
+    let ratio = fourth as f64 / each.max(1) as f64;
+    if !(3.5..=4.5).contains(&ratio) {
+        return Err(format!(
+            "samples every {each} us with SRPT 1 and every {fourth} us with SRPT 4"
+        ));
+    }
+    Ok(())
+}
+
+fn calibration(harness: &mut Harness) -> Result<(), String> {
+    let original = harness.calibration;
+    let center = original.center;
+    let moved = Calibration {
+        center: Measurement {
+            x: center.x + 1,
+            ..center
+        },
+        ..original
+    };
+    let sent = upload(&mut harness.board, &moved);
+    // The board's own goes back whatever happened.
+    let restored = upload(&mut harness.board, &original);
+    sent.and(restored)
+}
+
+/// Sends `calibration` with `SCAL` and waits for the board to echo it.
+fn upload(board: &mut Board, calibration: &Calibration) -> Result<(), String> {
+    let line = calibration.to_string();
+    let numbers = line.trim_start_matches("Calibration: ").replace(' ', "");
+    board.send(&format!("SCAL {numbers}"))?;
+    board.expect("the calibration echoed", REPLY_TIMEOUT, |received| {
+        (received.frame == Frame::Calibration(*calibration)).then_some(())
+    })
+}
+
+fn junk(harness: &mut Harness) -> Result<(), String> {
+    harness.board.write(b"\x00\xff\x7fZZZZ\r")?;
+    harness.board.write(&[b'S'; 300])?;
+    harness.board.write(b"\r")?;
+    let every = harness.every.clone();
+    harness.set(&format!("SRPT {every}"), "every", &every)
+}
+
+/// A decoded packet, with the bytes it came in.
+struct Received {
+    frame: Frame,
+    raw: Vec<u8>,
+}
+
+/// The board's port, read through the same decoder as the host tools.
+struct Board {
+    port: Box<dyn SerialPort>,
+    decoder: Decoder,
+    spans: Vec<Span>,
+    /// Bytes read from `held_start` on, not yet decoded.
+    held: Vec<u8>,
+    held_start: u64,
+    received: VecDeque<Received>,
+}
+
+impl Board {
+    fn new(port: Box<dyn SerialPort>) -> Board {
+        Board {
+            port,
+            decoder: Decoder::new(),
+            spans: Vec::new(),
+            held: Vec::new(),
+            held_start: 0,
+            received: VecDeque::new(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.port
+            .write_all(bytes)
+            .map_err(|err| format!("writing to the port: {err}"))
+    }
+
+    /// Sends a command, without its line ending.
+    fn send(&mut self, command: &str) -> Result<(), String> {
+        self.write(format!("{command}\r").as_bytes())
+    }
+
+    /// The next packet, if one comes before `deadline`.
+    fn next(&mut self, deadline: Instant) -> Result<Option<Received>, String> {
+        while self.received.is_empty() && Instant::now() < deadline {
+            self.read()?;
+        }
+        Ok(self.received.pop_front())
+    }
+
+    /// The first of the packets coming in the next `timeout` that `found`
+    /// finds something in, and what it found, skipping the rest.
+    fn expect<T>(
+        &mut self,
+        what: &str,
+        timeout: Duration,
+        mut found: impl FnMut(&Received) -> Option<T>,
+    ) -> Result<T, String> {
+        let deadline = Instant::now() + timeout;
+        while let Some(received) = self.next(deadline)? {
+            if let Some(found) = found(&received) {
+                return Ok(found);
+            }
+        }
+        Err(format!("no {what} within {} s", timeout.as_secs_f64()))
+    }
+
+    fn read(&mut self) -> Result<(), String> {
+        let mut buffer = [0u8; 1024];
+        let len = match self.port.read(&mut buffer) {
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => return Ok(()),
+            Err(err) => return Err(format!("reading the port: {err}")),
+        };
+        self.held.extend_from_slice(&buffer[..len]);
+        self.decoder.push_spans(&buffer[..len], &mut self.spans);
+        let mut end = self.held_start;
+        for span in self.spans.drain(..) {
+            let from = (span.start - self.held_start) as usize;
+            end = span.start + span.len as u64;
+            if let Some(packet) = span.packet {
+                self.received.push_back(Received {
+                    frame: packet.into(),
+                    raw: self.held[from..from + span.len].to_vec(),
+                });
+            }
+        }
+        self.held.drain(..(end - self.held_start) as usize);
+        self.held_start = end;
+        Ok(())
+    }
+}
+
+fn line(received: &Received) -> Option<&str> {
+    match &received.frame {
+        Frame::Line(line) => Some(line),
+        _ => None,
+    }
+}
+
+fn settings_fields(received: &Received) -> Option<Vec<(String, String)>> {
+    line(received)?.strip_prefix("Settings:").map(parse_status)
+}
+
+fn field<'a>(fields: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.as_str())
+}
+
+/// The index `SFMT` takes for the output format named `name`.
+fn format_index(name: &str) -> Option<u8> {
+    (0..=u8::MAX)
+        .map_while(|index| Some((index, OutputFormat::from_index(index)?)))
+        .find(|(_, format)| format.name() == name)
+        .map(|(index, _)| index)
+}
+
+/// The repository's root, above this crate.
+fn root() -> &'static std::path::Path {
+    std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("tests-hil is in the repository")
+}
+
+fn run_process(process: &mut Process) -> io::Result<()> {
+    let status = process.status().map_err(|err| {
+        let program = process.get_program().to_string_lossy().into_owned();
+        io::Error::new(err.kind(), format!("couldn't run {program}: {err}"))
+    })?;
+    if !status.success() {
+        let program = process.get_program().to_string_lossy();
+        return Err(io::Error::other(format!("{program} failed: {status}")));
+    }
+    Ok(())
+}
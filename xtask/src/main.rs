@@ -0,0 +1,325 @@
+//! `cargo xtask`: builds the firmware, flashes it and shows what it sends,
+//! in one command, in place of the separate `cargo embed` and serial
+//! terminal steps.
+//!
+//! - `build` builds a firmware for release, the RTIC one or with
+//!   `--embassy` the Embassy one, with `SPHERE_GIT_HASH` set to the commit
+//!   it is built from (see
+//!   [`GIT_HASH`](sphere_mapping_host::sphere_mapping_core::config::GIT_HASH))
+//!   so its boot banner's `Version:` line names it. A `fixed-point` build
+//!   is refused if libm is still linked into it, which the default `libm`
+//!   feature does: it needs `--no-default-features --features
+//!   v2,fixed-point`. With the `v1` feature the RTIC firmware is built
+//!   for, and flashed to, a micro:bit v1.5 instead;
+//! - `flash` builds it and writes it to the board with `probe-rs download`,
+//!   then starts it with `probe-rs reset`;
+//! - `monitor` decodes what the board sends over serial, as the host tools
+//!   do (see [`Connection`]), and prints it until Ctrl-C: lines as they
+//!   are, samples, sphere map cells and bytes that didn't decode in words;
+//! - `run` flashes and then monitors, with the port opened before the board
+//!   is started so the boot banner isn't missed.
+//!
+//! `probe-rs` comes from `cargo install probe-rs-tools`.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command as Process, ExitCode};
+
+use clap::{Args, Parser, Subcommand};
+use futures_executor::block_on_stream;
+use sphere_mapping_host::sphere_mapping_core::config::BAUD_RATE;
+use sphere_mapping_host::{Connection, Frame};
+
+const TARGET: &str = "thumbv7em-none-eabihf";
+/// The micro:bit v2's nRF52833, as probe-rs names it.
+const CHIP: &str = "nRF52833_xxAA";
+/// The same for the micro:bit v1.5's nRF51822, built with the `v1` feature.
+const V1_TARGET: &str = "thumbv6m-none-eabi";
+const V1_CHIP: &str = "nRF51822_xxAA";
+
+#[derive(Parser)]
+#[command(about = "Build, flash and monitor the compass firmware")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build the firmware, with the commit for its boot banner.
+    Build(BuildArgs),
+    /// Build the firmware and flash it to the board.
+    Flash(BuildArgs),
+    /// Show what the board sends, decoded.
+    Monitor(MonitorArgs),
+    /// Build, flash and monitor.
+    Run {
+        #[command(flatten)]
+        build: BuildArgs,
+        #[command(flatten)]
+        monitor: MonitorArgs,
+    },
+}
+
+#[derive(Args)]
+struct BuildArgs {
+    /// Build the Embassy firmware instead of the RTIC one.
+    #[arg(long)]
+    embassy: bool,
+    /// Cargo features to build the firmware with, comma-separated.
+    #[arg(long)]
+    features: Option<String>,
+    /// Leave out the firmware's default features.
+    #[arg(long)]
+    no_default_features: bool,
+}
+
+impl BuildArgs {
+    /// The arguments selecting these features, for any cargo command.
+    fn cargo_args(&self) -> Vec<&str> {
+        let mut args = Vec::new();
+        if self.no_default_features {
+            args.push("--no-default-features");
+        }
+        if let Some(features) = &self.features {
+            args.extend(["--features", features]);
+        }
+        args
+    }
+
+    fn has_feature(&self, name: &str) -> bool {
+        self.features
+            .iter()
+            .flat_map(|features| features.split(','))
+            .any(|feature| feature.trim() == name)
+    }
+
+    fn fixed_point(&self) -> bool {
+        self.has_feature("fixed-point")
+    }
+
+    fn target(&self) -> &'static str {
+        if self.has_feature("v1") {
+            V1_TARGET
+        } else {
+            TARGET
+        }
+    }
+
+    fn chip(&self) -> &'static str {
+        if self.has_feature("v1") {
+            V1_CHIP
+        } else {
+            CHIP
+        }
+    }
+}
+
+#[derive(Args)]
+struct MonitorArgs {
+    /// Serial port the board is on; found by its USB IDs if left out.
+    #[arg(long)]
+    port: Option<String>,
+    #[arg(long, default_value_t = BAUD_RATE)]
+    baud: u32,
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Build(args) => build(&args).map(|_| ()),
+        Command::Flash(args) => build(&args).and_then(|elf| {
+            download(&elf, args.chip())?;
+            reset(args.chip())
+        }),
+        Command::Monitor(args) => open(&args).and_then(monitor),
+        Command::Run {
+            build: args,
+            monitor: monitor_args,
+        } => build(&args).and_then(|elf| {
+            download(&elf, args.chip())?;
+            let connection = open(&monitor_args)?;
+            reset(args.chip())?;
+            monitor(connection)
+        }),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Builds the firmware, returning the path of its ELF file.
+fn build(args: &BuildArgs) -> io::Result<PathBuf> {
+    let (crate_dir, binary) = if args.embassy {
+        ("microbit-firmware-embassy", "microbit-firmware-embassy")
+    } else {
+        ("microbit-firmware", "my-app")
+    };
+    let dir = root().join(crate_dir);
+    if args.fixed_point() && links_libm(&dir, args)? {
+        return Err(io::Error::other(
+            "libm is linked into a fixed-point build; \
+             build with --no-default-features --features v2,fixed-point,...",
+        ));
+    }
+    let mut cargo = cargo();
+    cargo
+        .current_dir(&dir)
+        .args(["build", "--release", "--target", args.target()])
+        .args(args.cargo_args());
+    match git_hash() {
+        Some(hash) => {
+            eprintln!("Building {crate_dir} at {hash}");
+            cargo.env("SPHERE_GIT_HASH", hash);
+        }
+        None => eprintln!("Building {crate_dir}, not from a git checkout"),
+    }
+    run(&mut cargo)?;
+    Ok(dir
+        .join("target")
+        .join(args.target())
+        .join("release")
+        .join(binary))
+}
+
+/// Whether anything in the firmware in `dir` built with `args` depends on
+/// libm, going by `cargo tree`.
+fn links_libm(dir: &Path, args: &BuildArgs) -> io::Result<bool> {
+    let output = cargo()
+        .current_dir(dir)
+        .args([
+            "tree",
+            "-e",
+            "features",
+            "-i",
+            "libm",
+            "--target",
+            args.target(),
+        ])
+        .args(args.cargo_args())
+        .output()?;
+    // Without libm in the graph at all, `cargo tree -i` fails saying so.
+    Ok(output.status.success() && String::from_utf8_lossy(&output.stdout).contains("libm v"))
+}
+
+fn cargo() -> Process {
+    Process::new(std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+}
+
+fn download(elf: &Path, chip: &str) -> io::Result<()> {
+    run(Process::new("probe-rs")
+        .args(["download", "--chip", chip])
+        .arg(elf))
+}
+
+fn reset(chip: &str) -> io::Result<()> {
+    run(Process::new("probe-rs").args(["reset", "--chip", chip]))
+}
+
+fn open(args: &MonitorArgs) -> io::Result<Connection> {
+    let connection = Connection::open(args.port.as_deref(), args.baud)?;
+    eprintln!("Monitoring {}, Ctrl-C to stop", connection.name());
+    Ok(connection)
+}
+
+/// Prints what arrives on `connection` until the port fails.
+fn monitor(connection: Connection) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    for frame in block_on_stream(connection) {
+        match frame? {
+            Frame::Sample(sample) => {
+                let (field, accel) = (sample.field, sample.accel);
+                write!(
+                    stdout,
+                    "Sample at {:.3} s: field {}, {}, {} nT, acceleration {}, {}, {} mg",
+                    sample.timestamp_us as f64 / 1e6,
+                    field.x,
+                    field.y,
+                    field.z,
+                    accel.x,
+                    accel.y,
+                    accel.z
+                )?;
+                if let Some([x, y, z]) = sample.gyro {
+                    write!(stdout, ", rate {x:.1}, {y:.1}, {z:.1} deg/s")?;
+                }
+                writeln!(stdout)?;
+            }
+            Frame::SphereCell(cell) => writeln!(
+                stdout,
+                "Sphere map cell {} of {}: {} samples, average {} nT",
+                cell.index, cell.cells, cell.count, cell.mean_nt
+            )?,
+            Frame::SphereDiff(cell) => writeln!(
+                stdout,
+                "Sphere map difference cell {} of {}: {} nT",
+                cell.index, cell.cells, cell.difference_nt
+            )?,
+            Frame::Other { kind, payload } => {
+                writeln!(stdout, "Frame of kind {kind}, {} bytes", payload.len())?
+            }
+            Frame::Corrupt(bytes) => writeln!(stdout, "Dropped {bytes} bytes that didn't decode")?,
+            Frame::Calibration(calibration) => writeln!(stdout, "{calibration}")?,
+            Frame::Captured(field) => writeln!(
+                stdout,
+                "Captured field {}, {}, {} nT",
+                field.x, field.y, field.z
+            )?,
+            Frame::Status(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(key, value)| format!("{key}={value}"))
+                    .collect();
+                writeln!(stdout, "Status: {}", fields.join(", "))?
+            }
+            Frame::Line(line) => writeln!(stdout, "{line}")?,
+        }
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// The commit checked out, as its short hash, with `-dirty` after it if
+/// tracked files have changed since.
+fn git_hash() -> Option<String> {
+    let git = |args: &[&str]| {
+        let output = Process::new("git")
+            .current_dir(root())
+            .args(args)
+            .output()
+            .ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let hash = git(&["rev-parse", "--short", "HEAD"])?;
+    let changed = git(&["status", "--porcelain", "--untracked-files=no"])?;
+    if changed.is_empty() {
+        Some(hash)
+    } else {
+        Some(format!("{hash}-dirty"))
+    }
+}
+
+/// The repository's root, above this crate.
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("xtask is in the repository")
+}
+
+fn run(process: &mut Process) -> io::Result<()> {
+    let status = process.status().map_err(|err| {
+        let program = process.get_program().to_string_lossy().into_owned();
+        io::Error::new(err.kind(), format!("couldn't run {program}: {err}"))
+    })?;
+    if !status.success() {
+        let program = process.get_program().to_string_lossy();
+        return Err(io::Error::other(format!("{program} failed: {status}")));
+    }
+    Ok(())
+}